@@ -0,0 +1,96 @@
+// Parses generated OBJ files of 100k and 1M triangles with load_obj_scene. The time per
+// byte should stay about the same between the two, the parser must not slow down with
+// the number of vertices or faces read so far.
+//
+// cargo bench --bench obj_scene
+#![feature(test)]
+
+extern crate test;
+
+use std::fmt::Write;
+use std::path::PathBuf;
+
+use test::{black_box, Bencher};
+use vulkan_rust::renderer::mesh::loaders::obj::load_obj_scene;
+
+// A grid of columns x rows quads, 2 triangles each, split into 4 groups of rows
+fn grid_obj(columns: u32, rows: u32) -> String {
+    let mut obj = String::new();
+    for y in 0..=rows {
+        for x in 0..=columns {
+            writeln!(obj, "v {} {} 0", x, y).unwrap();
+            writeln!(
+                obj,
+                "vt {} {}",
+                x as f32 / columns as f32,
+                y as f32 / rows as f32
+            )
+            .unwrap();
+        }
+    }
+    writeln!(obj, "vn 0 0 -1").unwrap();
+    for y in 0..rows {
+        if y % (rows / 4).max(1) == 0 {
+            writeln!(obj, "g rows_{}", y).unwrap();
+        }
+        for x in 0..columns {
+            let corner = |x: u32, y: u32| y * (columns + 1) + x + 1;
+            let (a, b, c, d) = (
+                corner(x, y),
+                corner(x + 1, y),
+                corner(x + 1, y + 1),
+                corner(x, y + 1),
+            );
+            writeln!(obj, "f {a}/{a}/1 {b}/{b}/1 {c}/{c}/1").unwrap();
+            writeln!(obj, "f {a}/{a}/1 {c}/{c}/1 {d}/{d}/1").unwrap();
+        }
+    }
+    obj
+}
+
+fn write_grid(name: &str, columns: u32, rows: u32) -> (PathBuf, u64) {
+    let obj = grid_obj(columns, rows);
+    let path = std::env::temp_dir().join(format!("{}_{}.obj", name, std::process::id()));
+    std::fs::write(&path, &obj).unwrap();
+    (path, obj.len() as u64)
+}
+
+fn bench_grid(b: &mut Bencher, name: &str, columns: u32, rows: u32) {
+    let (path, bytes) = write_grid(name, columns, rows);
+    b.bytes = bytes;
+    b.iter(|| black_box(load_obj_scene(&path).unwrap()));
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[bench]
+fn triangles_100k(b: &mut Bencher) {
+    bench_grid(b, "bench_100k", 250, 200);
+}
+
+#[bench]
+fn triangles_1m(b: &mut Bencher) {
+    bench_grid(b, "bench_1m", 1000, 500);
+}
+
+// Not timed, checks that the generated files are what the benchmarks claim
+#[test]
+fn grids_parse_into_their_groups() {
+    let (path, _) = write_grid("grid", 10, 8);
+    let scene = load_obj_scene(&path);
+    std::fs::remove_file(&path).unwrap();
+    let scene = scene.unwrap();
+
+    assert_eq!(
+        scene
+            .groups
+            .iter()
+            .map(|group| group.name.as_str())
+            .collect::<Vec<_>>(),
+        ["rows_0", "rows_2", "rows_4", "rows_6"]
+    );
+    for group in scene.groups.iter() {
+        // 2 rows of 10 quads, sharing the vertices of their corners
+        assert_eq!(group.mesh.indices().len(), 2 * 10 * 2 * 3);
+        assert_eq!(group.mesh.vertices().len(), 3 * 11);
+    }
+}
@@ -0,0 +1,88 @@
+// Compares uploading the dirty slots of the per-object data in coalesced ranges with
+// uploading every slot on its own, on the CPU side: planning the copies and packing
+// them into a staging buffer. Each range is one region of the copy on the transfer
+// queue, so the GPU side scales with the region counts these plans produce.
+//
+// cargo bench --bench object_upload
+#![feature(test)]
+
+extern crate test;
+
+use test::{black_box, Bencher};
+use vulkan_rust::renderer::object_data::{
+    coalesce_dirty_slots, per_slot_plan, UploadPlan, DEFAULT_FULL_UPLOAD_FRACTION,
+};
+
+// The size of InstanceData
+const STRIDE: usize = 212;
+const USED_SLOTS: u32 = 10_000;
+
+// Runs of 8 moving objects every 40 slots, like groups of objects that move together
+fn clustered_dirty_slots() -> Vec<u32> {
+    (0..USED_SLOTS)
+        .step_by(40)
+        .flat_map(|start| start..start + 8)
+        .collect()
+}
+
+// Every 7th slot, so no two dirty slots touch
+fn scattered_dirty_slots() -> Vec<u32> {
+    (0..USED_SLOTS).step_by(7).collect()
+}
+
+fn stage(plan: &UploadPlan, data: &[u8], staging: &mut Vec<u8>) -> usize {
+    staging.clear();
+    for copy in plan.buffer_copies(STRIDE as u64) {
+        let start = copy.src_offset as usize;
+        staging.extend_from_slice(&data[start..start + copy.size as usize]);
+    }
+    plan.ranges.len()
+}
+
+fn bench_plan(b: &mut Bencher, dirty: &[u32], plan: fn(&[u32]) -> UploadPlan) {
+    let data = vec![1u8; USED_SLOTS as usize * STRIDE];
+    let mut staging = Vec::with_capacity(data.len());
+    b.bytes = (plan(dirty).slot_count() as usize * STRIDE) as u64;
+    b.iter(|| black_box(stage(&plan(black_box(dirty)), &data, &mut staging)));
+}
+
+fn coalesced(dirty: &[u32]) -> UploadPlan {
+    coalesce_dirty_slots(dirty, USED_SLOTS, DEFAULT_FULL_UPLOAD_FRACTION)
+}
+
+fn per_slot(dirty: &[u32]) -> UploadPlan {
+    per_slot_plan(dirty, USED_SLOTS)
+}
+
+#[bench]
+fn clustered_coalesced(b: &mut Bencher) {
+    bench_plan(b, &clustered_dirty_slots(), coalesced);
+}
+
+#[bench]
+fn clustered_per_slot(b: &mut Bencher) {
+    bench_plan(b, &clustered_dirty_slots(), per_slot);
+}
+
+#[bench]
+fn scattered_coalesced(b: &mut Bencher) {
+    bench_plan(b, &scattered_dirty_slots(), coalesced);
+}
+
+#[bench]
+fn scattered_per_slot(b: &mut Bencher) {
+    bench_plan(b, &scattered_dirty_slots(), per_slot);
+}
+
+// Not timed, the number of copy regions is what the transfer queue sees
+#[test]
+fn coalescing_cuts_the_regions() {
+    let dirty = clustered_dirty_slots();
+    assert_eq!(per_slot(&dirty).ranges.len(), 2000);
+    assert_eq!(coalesced(&dirty).ranges.len(), 250);
+    let dirty = scattered_dirty_slots();
+    assert_eq!(
+        coalesced(&dirty).ranges.len(),
+        per_slot(&dirty).ranges.len()
+    );
+}
@@ -0,0 +1,83 @@
+// Renders a lit sphere into a window of SDL2 instead of winit, through the raw handles of
+// the window. There is no imgui UI without winit.
+// cargo run --example sdl2 --no-default-features --features sdl2
+use std::collections::HashMap;
+
+use nalgebra as na;
+use nalgebra_glm as glm;
+use sdl2::event::{Event, WindowEvent};
+use sdl2::keyboard::Keycode;
+
+use vulkan_rust::renderer::camera::Camera;
+use vulkan_rust::renderer::config::RendererConfig;
+use vulkan_rust::renderer::light::DirectionalLight;
+use vulkan_rust::renderer::material::{MaterialData, ShaderParameters};
+use vulkan_rust::renderer::utils::InternalWindow;
+use vulkan_rust::renderer::Renderer;
+
+const WIDTH: u32 = 1280;
+const HEIGHT: u32 = 720;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let sdl = sdl2::init()?;
+    let video = sdl.video()?;
+    let window = video
+        .window("SDL2 Example", WIDTH, HEIGHT)
+        .vulkan()
+        .resizable()
+        .build()?;
+    let internal_window = InternalWindow::from_raw_handles(&window)?;
+    let mut renderer = Renderer::new_with_internal_window(
+        "SDL2 Example",
+        WIDTH,
+        HEIGHT,
+        internal_window,
+        RendererConfig::default(),
+    )?;
+
+    let sphere = renderer.resources()?.new_sphere_mesh(3)?;
+    let material = renderer.build_material(
+        "sphere",
+        MaterialData {
+            textures: HashMap::new(),
+            buffers: vec![],
+            parameters: ShaderParameters::default(),
+            base_template: "default".to_string(),
+        },
+    )?;
+    renderer.resources()?.new_object(sphere, material)?;
+    renderer.lights.add_light(DirectionalLight {
+        direction: na::Unit::new_normalize(glm::vec3(-1.0, 1.0, 1.0)),
+        illuminance: glm::vec3(8.0, 8.0, 8.0),
+    });
+    let mut camera = Camera::builder()
+        .position(glm::vec3(0.0, 0.0, -4.0))
+        .aspect(WIDTH as f32 / HEIGHT as f32)
+        .build();
+    camera.look_at(&glm::Vec3::zeros(), &glm::vec3(0.0, 1.0, 0.0));
+
+    let mut event_pump = sdl.event_pump()?;
+    'running: loop {
+        for event in event_pump.poll_iter() {
+            match event {
+                Event::Quit { .. }
+                | Event::KeyDown {
+                    keycode: Some(Keycode::Escape),
+                    ..
+                } => break 'running,
+                Event::Window {
+                    win_event: WindowEvent::SizeChanged(width, height),
+                    ..
+                } => {
+                    let extent = renderer.recreate_swapchain(width as u32, height as u32)?;
+                    if extent.width > 0 && extent.height > 0 {
+                        camera.set_aspect(extent.width as f32 / extent.height as f32);
+                    }
+                }
+                _ => (),
+            }
+        }
+        renderer.render_without_ui(&camera)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,16 @@
+#version 450
+
+layout (location=0) in vec3 in_normal;
+layout (location=1) in float view_depth;
+layout (location=2) flat in uint object_id;
+
+// In the order of AOV_FORMATS
+layout (location=0) out uint out_id;
+layout (location=1) out vec4 out_normal;
+layout (location=2) out float out_depth;
+
+void main() {
+    out_id = object_id;
+    out_normal = vec4(normalize(in_normal), 1.0);
+    out_depth = view_depth;
+}
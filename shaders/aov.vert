@@ -0,0 +1,33 @@
+#version 450
+
+layout (location=0) in vec3 position;
+layout (location=1) in vec3 normal;
+layout (location=3) in mat4 model_matrix;
+layout (location=7) in mat4 inverse_model_matrix;
+
+layout (set=0, binding=0) uniform UniformBufferObject {
+    mat4 view_matrix;
+    mat4 projection_matrix;
+    mat4 previous_view_projection;
+    vec4 fog_color;
+    vec4 fog_params;
+    vec4 fog_height;
+} ubo;
+
+// The id of the handle of the object, see AovImages
+layout (push_constant) uniform Object {
+    uint id;
+} object;
+
+layout (location=0) out vec3 out_normal;
+layout (location=1) out float view_depth;
+layout (location=2) flat out uint object_id;
+
+void main() {
+    vec4 viewpos = ubo.view_matrix*model_matrix*vec4(position, 1.0);
+    gl_Position = ubo.projection_matrix*viewpos;
+    out_normal = vec3(transpose(inverse_model_matrix)*vec4(normalize(normal), 0.0));
+    // The camera looks along +z
+    view_depth = viewpos.z;
+    object_id = object.id;
+}
@@ -0,0 +1,11 @@
+#version 450
+layout (location=0) in vec2 in_tex_coord;
+layout (location=1) in vec3 in_color;
+
+layout (location=0) out vec4 color;
+
+layout(set=0,binding=0) uniform sampler2D albedo_tex;
+
+void main() {
+    color = texture(albedo_tex, in_tex_coord) * vec4(in_color, 1.0);
+}
\ No newline at end of file
@@ -5,25 +5,106 @@ layout (location=1) in vec3 normal;
 layout (location=2) in vec2 uv;
 layout (location=3) in mat4 model_matrix;
 layout (location=7) in mat4 inverse_model_matrix;
+layout (location=11) in mat4 previous_model_matrix;
+// Two 16 bit probe indices in each of x and y, two 16 bit unorm weights in each of z and w
+layout (location=15) in uvec4 probes;
+// SceneObject::flags
+layout (location=16) in uint flags;
 
 layout (set=0, binding=0) uniform UniformBufferObject {
     mat4 view_matrix;
     mat4 projection_matrix;
+    mat4 previous_view_projection;
+    vec4 fog_color; // w is the mode: 0 none, 1 linear, 2 exp, 3 exp2
+    vec4 fog_params; // density, linear start, linear end
+    vec4 fog_height; // falloff, base height
 } ubo;
 
+readonly layout (set=1, binding=0) buffer StorageBufferObject {
+    float num_directional;
+    float num_point;
+    float num_spot;
+    float num_probes;
+    vec3 data[];
+} sbo;
+
+// Two vec4s per vertex of every target, the position delta and the normal delta, see
+// morph.rs
+readonly layout (set=1, binding=2) buffer MorphDeltas {
+    vec4 data[];
+} morph_deltas;
+
+// The morph part starts at offset 32, see MorphPushConstants
+layout (push_constant) uniform DrawConstants {
+    vec2 uv_offset;
+    vec2 uv_scale;
+    float uv_rotation;
+    uvec4 morph_indices;
+    vec4 morph_weights;
+    uint morph_base;
+    uint morph_vertex_count;
+    uint morph_count;
+} draw;
+
 layout (location=0) out vec3 out_normal;
 layout (location=1) out vec4 worldpos;
 layout (location=2) out vec3 camera_pos;
 layout (location=3) out vec2 uv_out;
+layout (location=4) out vec3 ambient_irradiance;
+layout (location=5) flat out uint object_flags;
+
+// The probes follow the lights, with 9 spherical harmonics coefficients each, see
+// probe.rs for their order. Evaluated per vertex, which is plenty for ambient light.
+vec3 probe_irradiance(vec3 n) {
+    if (int(sbo.num_probes) == 0) {
+        return vec3(0);
+    }
+    int start = 2*int(sbo.num_directional) + 2*int(sbo.num_point) + 4*int(sbo.num_spot);
+    uint indices[4] = uint[](probes.x & 0xFFFF, probes.x >> 16, probes.y & 0xFFFF, probes.y >> 16);
+    vec4 weights = vec4(unpackUnorm2x16(probes.z), unpackUnorm2x16(probes.w));
+    vec3 l[9];
+    for (int k = 0; k < 9; k++) {
+        l[k] = vec3(0);
+    }
+    for (int i = 0; i < 4; i++) {
+        if (weights[i] > 0) {
+            int probe = start + 9*int(indices[i]);
+            for (int k = 0; k < 9; k++) {
+                l[k] += weights[i] * sbo.data[probe + k];
+            }
+        }
+    }
+    const float c1 = 0.429043;
+    const float c2 = 0.511664;
+    const float c3 = 0.743125;
+    const float c4 = 0.886227;
+    const float c5 = 0.247708;
+    vec3 irradiance = c1*l[8]*(n.x*n.x - n.y*n.y) + c3*l[6]*n.z*n.z + c4*l[0] - c5*l[6]
+        + 2*c1*(l[4]*n.x*n.y + l[7]*n.x*n.z + l[5]*n.y*n.z)
+        + 2*c2*(l[3]*n.x + l[1]*n.y + l[2]*n.z);
+    return max(irradiance, vec3(0));
+}
 
 void main() {
-    worldpos = model_matrix*vec4(position, 1.0);
+    vec3 morphed_position = position;
+    vec3 morphed_normal = normal;
+    for (uint i = 0; i < draw.morph_count; i++) {
+        uint delta = draw.morph_base + 2*(draw.morph_indices[i]*draw.morph_vertex_count + gl_VertexIndex);
+        morphed_position += draw.morph_weights[i] * morph_deltas.data[delta].xyz;
+        morphed_normal += draw.morph_weights[i] * morph_deltas.data[delta + 1].xyz;
+    }
+
+    worldpos = model_matrix*vec4(morphed_position, 1.0);
     gl_Position = ubo.projection_matrix*ubo.view_matrix*worldpos;
     camera_pos =
 	- ubo.view_matrix[3][0] * vec3 (ubo.view_matrix[0][0],ubo.view_matrix[1][0],ubo.view_matrix[2][0])
 	- ubo.view_matrix[3][1] * vec3 (ubo.view_matrix[0][1],ubo.view_matrix[1][1],ubo.view_matrix[2][1])
 	- ubo.view_matrix[3][2] * vec3 (ubo.view_matrix[0][2],ubo.view_matrix[1][2],ubo.view_matrix[2][2]);
 
-    out_normal = vec3(transpose(inverse_model_matrix)*vec4(normalize(normal), 0.0));
-    uv_out = uv;
+    out_normal = vec3(transpose(inverse_model_matrix)*vec4(normalize(morphed_normal), 0.0));
+    ambient_irradiance = probe_irradiance(normalize(out_normal));
+    float c = cos(draw.uv_rotation);
+    float s = sin(draw.uv_rotation);
+    uv_out = mat2(c, s, -s, c) * (uv * draw.uv_scale) + draw.uv_offset;
+    object_flags = flags;
 }
\ No newline at end of file
@@ -0,0 +1,80 @@
+#version 450
+
+layout (location=0) in vec3 normal_varied;
+layout (location=1) in vec4 worldpos;
+layout (location=2) in vec3 camera_pos;
+layout (location=3) in vec2 uv;
+layout (location=4) in vec3 ambient_irradiance;
+
+layout (location=0) out vec4 outColor;
+
+layout (set=0, binding=0) uniform UniformBufferObject {
+    mat4 view_matrix;
+    mat4 projection_matrix;
+    mat4 previous_view_projection;
+    vec4 fog_color;
+    vec4 fog_params;
+    vec4 fog_height;
+    mat4 light_view_projection;
+    vec4 shadow_params;
+    // x is 1 without an upscale pass, which does the tone mapping otherwise
+    vec4 tone_map_params;
+} ubo;
+
+// Not used, but has to be declared so the pipeline layout matches the default template
+readonly layout (set=1, binding=0) buffer StorageBufferObject {
+    float num_directional;
+    float num_point;
+    float num_spot;
+    float num_probes;
+    vec3 data[];
+} sbo;
+layout (set=1, binding=1) uniform sampler2D light_cookies[8];
+
+layout (set=2, binding=0) uniform sampler2D albedo_tex;
+layout (set=2, binding=1) uniform MaterialParameters {
+    float metallic;
+    float roughness;
+} material_parameters;
+
+// Set per object with Renderer::set_object_params, zero for objects that don't
+layout (set=3, binding=0) uniform ObjectParams {
+    // 0 is whole, 1 is gone
+    float dissolve;
+} object_params;
+
+const float PI = 3.14159265358979323846264;
+// Width of the glowing edge, in noise units
+const float EDGE = 0.05;
+
+float hash(vec2 p) {
+    return fract(sin(dot(p, vec2(12.9898, 78.233))) * 43758.5453);
+}
+
+// Value noise, so the holes grow in blobs instead of single texels
+float noise(vec2 p) {
+    vec2 i = floor(p);
+    vec2 f = fract(p);
+    vec2 u = f * f * (3 - 2 * f);
+    return mix(
+        mix(hash(i), hash(i + vec2(1, 0)), u.x),
+        mix(hash(i + vec2(0, 1)), hash(i + vec2(1, 1)), u.x),
+        u.y);
+}
+
+void main() {
+    float threshold = noise(uv * 16);
+    if (threshold < object_params.dissolve) {
+        discard;
+    }
+    vec3 surface_color = texture(albedo_tex, uv).rgb;
+    vec3 radiance = (1 - material_parameters.metallic) * ambient_irradiance * surface_color / PI;
+    // The edge of the holes glows
+    float edge = 1 - smoothstep(0, EDGE, threshold - object_params.dissolve);
+    radiance += edge * step(0.001, object_params.dissolve) * vec3(4, 1.5, 0.3);
+
+    if (ubo.tone_map_params.x != 0) {
+        radiance /= 1 + radiance;
+    }
+    outColor = vec4(radiance, 1);
+}
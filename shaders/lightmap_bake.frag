@@ -0,0 +1,102 @@
+#version 450
+// Lights a texel of a lightmap with the same diffuse terms as default.frag divided by PI,
+// so lightmapped.frag only multiplies by the albedo. The first directional light is
+// shadowed by a shadow map rendered around the object for the bake. Cookies are not baked.
+
+layout (location=0) in vec3 world_position;
+layout (location=1) in vec3 world_normal;
+layout (location=2) in vec3 shadow_position;
+
+// Alpha is 1 where the mesh covers the texel, the rest is cleared to 0
+layout (location=0) out vec4 outColor;
+
+layout (set=0, binding=0) uniform UniformBufferObject {
+    mat4 view_matrix;
+    mat4 projection_matrix;
+    mat4 previous_view_projection;
+    vec4 fog_color;
+    vec4 fog_params;
+    vec4 fog_height;
+    // Rendered around the object by Renderer::bake_lightmaps
+    mat4 light_view_projection;
+    vec4 shadow_params; // x is 1 with a shadow map, y is the size of a texel
+} ubo;
+
+// The lights of the frames, see default.frag
+readonly layout (set=1, binding=0) buffer StorageBufferObject {
+    float num_directional;
+    float num_point;
+    float num_spot;
+    float num_probes;
+    vec3 data[];
+} sbo;
+
+layout (set=1, binding=3) uniform sampler2DShadow shadow_map;
+
+const float PI = 3.14159265358979323846264;
+
+// Like directional_shadow in default.frag
+float directional_shadow(vec3 position) {
+    if (ubo.shadow_params.x == 0) {
+        return 1.0;
+    }
+    vec4 light_clip = ubo.light_view_projection*vec4(position, 1.0);
+    vec3 coords = light_clip.xyz / light_clip.w;
+    if (coords.z > 1.0) {
+        return 1.0;
+    }
+    vec2 uv = 0.5 * coords.xy + 0.5;
+    float lit = 0.0;
+    for (int x = -1; x <= 1; x++) {
+        for (int y = -1; y <= 1; y++) {
+            lit += texture(shadow_map, vec3(uv + vec2(x, y) * ubo.shadow_params.y, coords.z));
+        }
+    }
+    return lit / 9.0;
+}
+
+void main() {
+    vec3 position = world_position;
+    vec3 normal = normalize(world_normal);
+    int num_dir = int(sbo.num_directional);
+    int num_point = int(sbo.num_point);
+    int num_spot = int(sbo.num_spot);
+
+    vec3 total = vec3(0);
+    for (int i = 0; i < num_dir; i++) {
+        vec3 direction_to_light = normalize(sbo.data[2*i]);
+        vec3 irradiance = sbo.data[2*i+1];
+        if (i == 0) {
+            irradiance *= directional_shadow(shadow_position);
+        }
+        total += irradiance * max(dot(normal, direction_to_light), 0.0);
+    }
+
+    for (int i = 0; i < num_point; i++) {
+        vec3 to_light = sbo.data[2*i + 2*num_dir] - position;
+        float d = length(to_light);
+        if (d <= 0.0) {
+            continue;
+        }
+        vec3 luminous_flux = sbo.data[2*i + 1 + 2*num_dir];
+        total += luminous_flux/(4*PI*d*d) * max(dot(normal, to_light / d), 0.0);
+    }
+
+    int spot_start = 2*num_dir + 2*num_point;
+    for (int i = 0; i < num_spot; i++) {
+        vec3 to_light = sbo.data[4*i + spot_start] - position;
+        float d = length(to_light);
+        if (d <= 0.0) {
+            continue;
+        }
+        vec3 luminous_flux = sbo.data[4*i + 1 + spot_start];
+        vec3 direction = normalize(sbo.data[4*i + 2 + spot_start]);
+        vec3 data3 = sbo.data[4*i + 3 + spot_start];
+        float cone = smoothstep(data3.y, data3.x, dot(direction, -to_light / d));
+        total += cone * luminous_flux/(4*PI*d*d) * max(dot(normal, to_light / d), 0.0);
+    }
+
+    // Encoded as x / (1 + x) per channel, lightmapped.frag decodes it again
+    vec3 irradiance = max(total / PI, vec3(0));
+    outColor = vec4(irradiance / (1 + irradiance), 1);
+}
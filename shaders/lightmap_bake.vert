@@ -0,0 +1,25 @@
+#version 450
+// Draws the mesh in UV space, every fragment is a texel of the lightmap, see lightmap.rs
+
+layout (location=0) in vec3 position;
+layout (location=1) in vec3 normal;
+layout (location=2) in vec2 uv;
+
+// See LightmapPushConstants
+layout (push_constant) uniform Bake {
+    mat4 model_matrix;
+    // How far the position is moved along the normal before the shadow lookup
+    float normal_offset;
+} bake;
+
+layout (location=0) out vec3 world_position;
+layout (location=1) out vec3 world_normal;
+layout (location=2) out vec3 shadow_position;
+
+void main() {
+    // UV (0, 0) is the first texel of the first row
+    gl_Position = vec4(2.0 * uv - 1.0, 0.0, 1.0);
+    world_position = (bake.model_matrix*vec4(position, 1.0)).xyz;
+    world_normal = normalize(transpose(inverse(mat3(bake.model_matrix)))*normal);
+    shadow_position = world_position + bake.normal_offset*world_normal;
+}
@@ -0,0 +1,148 @@
+#version 450
+
+layout (location=0) in vec3 normal_varied;
+layout (location=1) in vec4 worldpos;
+layout (location=2) in vec3 camera_pos;
+layout (location=3) in vec2 uv;
+// See NO_DYNAMIC_LIGHTING in scene.rs
+layout (location=5) flat in uint object_flags;
+
+layout (location=0) out vec4 outColor;
+
+layout (set=0, binding=0) uniform UniformBufferObject {
+    mat4 view_matrix;
+    mat4 projection_matrix;
+    mat4 previous_view_projection;
+    vec4 fog_color; // w is the mode: 0 none, 1 linear, 2 exp, 3 exp2
+    vec4 fog_params; // density, linear start, linear end
+    vec4 fog_height; // falloff, base height
+    mat4 light_view_projection;
+    vec4 shadow_params;
+    // x is 1 without an upscale pass, which does the tone mapping otherwise
+    vec4 tone_map_params;
+} ubo;
+
+readonly layout (set=1, binding=0) buffer StorageBufferObject {
+    float num_directional;
+    float num_point;
+    float num_spot;
+    float num_probes;
+    vec3 data[];
+} sbo;
+// Not used, but has to be declared so the pipeline layout matches the default template
+layout (set=1, binding=1) uniform sampler2D light_cookies[8];
+layout (set=1, binding=3) uniform sampler2DShadow shadow_map;
+
+layout (set=2, binding=0) uniform sampler2D albedo_tex;
+// Baked irradiance / PI, encoded as x / (1 + x) so it fits in an 8 bit texture
+layout (set=2, binding=1) uniform sampler2D lightmap_tex;
+
+const float PI = 3.14159265358979323846264;
+const uint NO_DYNAMIC_LIGHTING = 1u << 31;
+
+// Like directional_shadow in default.frag
+float directional_shadow(vec3 position) {
+    if (ubo.shadow_params.x == 0) {
+        return 1.0;
+    }
+    vec4 light_clip = ubo.light_view_projection*vec4(position, 1.0);
+    vec3 coords = light_clip.xyz / light_clip.w;
+    if (coords.z > 1.0) {
+        return 1.0;
+    }
+    vec2 uv = 0.5 * coords.xy + 0.5;
+    float lit = 0.0;
+    for (int x = -1; x <= 1; x++) {
+        for (int y = -1; y <= 1; y++) {
+            lit += texture(shadow_map, vec3(uv + vec2(x, y) * ubo.shadow_params.y, coords.z));
+        }
+    }
+    return lit / 9.0;
+}
+
+// The diffuse irradiance of the lights, the same terms lightmap_bake.frag bakes
+vec3 direct_irradiance(vec3 position, vec3 normal) {
+    int num_dir = int(sbo.num_directional);
+    int num_point = int(sbo.num_point);
+    int num_spot = int(sbo.num_spot);
+
+    vec3 total = vec3(0);
+    for (int i = 0; i < num_dir; i++) {
+        vec3 direction_to_light = normalize(sbo.data[2*i]);
+        vec3 irradiance = sbo.data[2*i+1];
+        if (i == 0) {
+            irradiance *= directional_shadow(position);
+        }
+        total += irradiance * max(dot(normal, direction_to_light), 0.0);
+    }
+
+    for (int i = 0; i < num_point; i++) {
+        vec3 to_light = sbo.data[2*i + 2*num_dir] - position;
+        float d = length(to_light);
+        if (d <= 0.0) {
+            continue;
+        }
+        vec3 luminous_flux = sbo.data[2*i + 1 + 2*num_dir];
+        total += luminous_flux/(4*PI*d*d) * max(dot(normal, to_light / d), 0.0);
+    }
+
+    int spot_start = 2*num_dir + 2*num_point;
+    for (int i = 0; i < num_spot; i++) {
+        vec3 to_light = sbo.data[4*i + spot_start] - position;
+        float d = length(to_light);
+        if (d <= 0.0) {
+            continue;
+        }
+        vec3 luminous_flux = sbo.data[4*i + 1 + spot_start];
+        vec3 direction = normalize(sbo.data[4*i + 2 + spot_start]);
+        vec3 data3 = sbo.data[4*i + 3 + spot_start];
+        float cone = smoothstep(data3.y, data3.x, dot(direction, -to_light / d));
+        total += cone * luminous_flux/(4*PI*d*d) * max(dot(normal, to_light / d), 0.0);
+    }
+    return total;
+}
+
+// Radiance stays as it is for the upscale pass, see ToneMapping in config.rs
+vec3 tone_map(vec3 total_radiance) {
+    if (ubo.tone_map_params.x == 0) {
+        return total_radiance;
+    }
+    return total_radiance / (1 + total_radiance);
+}
+
+// Applied to linear radiance, before tone mapping
+vec3 apply_fog(vec3 radiance, vec3 position, vec3 camera) {
+    int mode = int(ubo.fog_color.w);
+    if (mode == 0) {
+        return radiance;
+    }
+    float distance = length(position - camera);
+    float visibility = 1.0;
+    if (mode == 1) {
+        visibility = clamp((ubo.fog_params.z - distance) / max(ubo.fog_params.z - ubo.fog_params.y, 0.0001), 0.0, 1.0);
+    } else if (mode == 2) {
+        visibility = exp(-ubo.fog_params.x * distance);
+    } else if (mode == 3) {
+        visibility = exp(-(ubo.fog_params.x * distance) * (ubo.fog_params.x * distance));
+    }
+    if (ubo.fog_height.x > 0) {
+        // -y is up
+        float height = -position.y - ubo.fog_height.y;
+        visibility = mix(1.0, visibility, exp(-ubo.fog_height.x * max(height, 0.0)));
+    }
+    return mix(ubo.fog_color.rgb, radiance, visibility);
+}
+
+void main() {
+    vec3 surface_color = texture(albedo_tex, uv).rgb;
+    vec3 encoded = min(texture(lightmap_tex, uv).rgb, vec3(0.999));
+    vec3 irradiance = encoded / (1 - encoded);
+    // The lights are added on top of the baked ones, unless the object turned them off
+    if ((object_flags & NO_DYNAMIC_LIGHTING) == 0) {
+        irradiance += direct_irradiance(worldpos.xyz, normalize(normal_varied)) / PI;
+    }
+
+    vec3 radiance = apply_fog(surface_color * irradiance, worldpos.xyz, camera_pos);
+
+    outColor = vec4(tone_map(radiance), 1);
+}
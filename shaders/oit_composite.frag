@@ -0,0 +1,18 @@
+#version 450
+// Puts the transparent objects accumulated by transparent_oit.frag over the opaque scene,
+// with a fullscreen triangle from upscale.vert
+layout (location=0) out vec4 color;
+
+// The depth is input attachment 0, see Renderer::create_scene_subpasses
+layout (set=0, binding=0, input_attachment_index=1) uniform subpassInput accumulation_input;
+layout (set=0, binding=1, input_attachment_index=2) uniform subpassInput revealage_input;
+
+void main() {
+    float revealage = subpassLoad(revealage_input).r;
+    // Nothing transparent covers the pixel
+    if (revealage == 1.0) {
+        discard;
+    }
+    vec4 accumulation = subpassLoad(accumulation_input);
+    color = vec4(accumulation.rgb / max(accumulation.a, 1e-5), 1.0 - revealage);
+}
@@ -0,0 +1,18 @@
+#version 450
+// oit_composite.frag for multisampled scenes, only the first sample of the accumulated
+// objects is put over the opaque scene
+layout (location=0) out vec4 color;
+
+// The depth is input attachment 0, see Renderer::create_scene_subpasses
+layout (set=0, binding=0, input_attachment_index=1) uniform subpassInputMS accumulation_input;
+layout (set=0, binding=1, input_attachment_index=2) uniform subpassInputMS revealage_input;
+
+void main() {
+    float revealage = subpassLoad(revealage_input, 0).r;
+    // Nothing transparent covers the pixel
+    if (revealage == 1.0) {
+        discard;
+    }
+    vec4 accumulation = subpassLoad(accumulation_input, 0);
+    color = vec4(accumulation.rgb / max(accumulation.a, 1e-5), 1.0 - revealage);
+}
@@ -0,0 +1,50 @@
+#version 450
+// default.vert without morph targets and light probes, moved by the offset orbit.comp
+// writes into the user managed set 3
+
+layout (location=0) in vec3 position;
+layout (location=1) in vec3 normal;
+layout (location=2) in vec2 uv;
+layout (location=3) in mat4 model_matrix;
+layout (location=7) in mat4 inverse_model_matrix;
+
+layout (set=0, binding=0) uniform UniformBufferObject {
+    mat4 view_matrix;
+    mat4 projection_matrix;
+    mat4 previous_view_projection;
+    vec4 fog_color;
+    vec4 fog_params;
+    vec4 fog_height;
+} ubo;
+
+readonly layout (set=3, binding=0) buffer Orbit {
+    vec4 offset;
+    float angle;
+} orbit;
+
+layout (push_constant) uniform DrawConstants {
+    vec2 uv_offset;
+    vec2 uv_scale;
+    float uv_rotation;
+} draw;
+
+layout (location=0) out vec3 out_normal;
+layout (location=1) out vec4 worldpos;
+layout (location=2) out vec3 camera_pos;
+layout (location=3) out vec2 uv_out;
+layout (location=4) out vec3 ambient_irradiance;
+
+void main() {
+    worldpos = model_matrix*vec4(position, 1.0) + vec4(orbit.offset.xyz, 0.0);
+    gl_Position = ubo.projection_matrix*ubo.view_matrix*worldpos;
+    camera_pos =
+	- ubo.view_matrix[3][0] * vec3 (ubo.view_matrix[0][0],ubo.view_matrix[1][0],ubo.view_matrix[2][0])
+	- ubo.view_matrix[3][1] * vec3 (ubo.view_matrix[0][1],ubo.view_matrix[1][1],ubo.view_matrix[2][1])
+	- ubo.view_matrix[3][2] * vec3 (ubo.view_matrix[0][2],ubo.view_matrix[1][2],ubo.view_matrix[2][2]);
+
+    out_normal = vec3(transpose(inverse_model_matrix)*vec4(normalize(normal), 0.0));
+    ambient_irradiance = vec3(0.03);
+    float c = cos(draw.uv_rotation);
+    float s = sin(draw.uv_rotation);
+    uv_out = mat2(c, s, -s, c) * (uv * draw.uv_scale) + draw.uv_offset;
+}
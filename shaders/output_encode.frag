@@ -0,0 +1,53 @@
+#version 450
+// Encodes the composited frame for the swapchain, the same math as OutputEncoding in
+// color.rs. 1 is SDR white.
+layout (location=0) in vec2 in_tex_coord;
+
+layout (location=0) out vec4 color;
+
+layout(set=0,binding=0) uniform sampler2D albedo_tex;
+
+layout (push_constant) uniform Output {
+    // 0 sRGB, 1 PQ, 2 scRGB
+    uint encoding;
+    float paper_white_nits;
+    float peak_nits;
+} pc;
+
+const float PQ_M1 = 2610.0 / 16384.0;
+const float PQ_M2 = 2523.0 / 4096.0 * 128.0;
+const float PQ_C1 = 3424.0 / 4096.0;
+const float PQ_C2 = 2413.0 / 4096.0 * 32.0;
+const float PQ_C3 = 2392.0 / 4096.0 * 32.0;
+const float SCRGB_NITS = 80.0;
+
+// Column major, see rec709_to_rec2020
+const mat3 REC709_TO_REC2020 = mat3(
+    0.6274, 0.0691, 0.0164,
+    0.3293, 0.9195, 0.0880,
+    0.0433, 0.0114, 0.8956
+);
+
+vec3 linear_to_srgb(vec3 c) {
+    return mix(c * 12.92, 1.055 * pow(c, vec3(1.0 / 2.4)) - 0.055, greaterThan(c, vec3(0.0031308)));
+}
+
+vec3 nits_to_pq(vec3 nits) {
+    vec3 y = pow(clamp(nits / 10000.0, 0.0, 1.0), vec3(PQ_M1));
+    return pow((PQ_C1 + PQ_C2 * y) / (1.0 + PQ_C3 * y), vec3(PQ_M2));
+}
+
+void main() {
+    vec4 composite = texture(albedo_tex, in_tex_coord);
+    vec3 rgb = max(composite.rgb, vec3(0.0));
+    vec3 encoded;
+    if (pc.encoding == 1) {
+        vec3 nits = min(REC709_TO_REC2020 * rgb * pc.paper_white_nits, vec3(pc.peak_nits));
+        encoded = nits_to_pq(nits);
+    } else if (pc.encoding == 2) {
+        encoded = min(rgb * pc.paper_white_nits, vec3(pc.peak_nits)) / SCRGB_NITS;
+    } else {
+        encoded = linear_to_srgb(min(rgb, vec3(1.0)));
+    }
+    color = vec4(encoded, composite.a);
+}
@@ -0,0 +1,37 @@
+#version 450
+layout (location=0) in vec2 in_tex_coord;
+layout (location=1) in vec4 in_color;
+
+layout (location=0) out vec4 color;
+
+layout (set=0, binding=0) uniform UniformBufferObject {
+    mat4 view_matrix;
+    mat4 projection_matrix;
+} ubo;
+
+layout (set=2, binding=0) uniform sampler2D albedo_tex;
+
+// The depth of the opaque scene, see Renderer::draw_emitter. particle_msaa.frag reads it
+// with multisampling.
+layout (set=3, binding=0, input_attachment_index=0) uniform subpassInput scene_depth;
+
+// EmitterConfig::softness
+layout (push_constant) uniform Constants {
+    float softness;
+} constants;
+
+// The distance from the camera plane of a value in the depth buffer, cameras are
+// perspective projections
+float view_depth(float depth) {
+    mat4 p = ubo.projection_matrix;
+    return abs(p[3][2] / (depth * p[2][3] - p[2][2]));
+}
+
+void main() {
+    color = texture(albedo_tex, in_tex_coord) * in_color;
+    if (constants.softness > 0.0) {
+        float scene = view_depth(subpassLoad(scene_depth).r);
+        float particle = view_depth(gl_FragCoord.z);
+        color.a *= clamp((scene - particle) / constants.softness, 0.0, 1.0);
+    }
+}
@@ -0,0 +1,34 @@
+#version 450
+
+// One instance per particle, the six vertices of the quad come from gl_VertexIndex
+layout (location=0) in vec3 position;
+layout (location=1) in float size;
+layout (location=2) in vec4 color;
+
+layout (set=0, binding=0) uniform UniformBufferObject {
+    mat4 view_matrix;
+    mat4 projection_matrix;
+    mat4 previous_view_projection;
+    vec4 fog_color;
+    vec4 fog_params;
+    vec4 fog_height;
+} ubo;
+
+layout (location=0) out vec2 out_tex_coord;
+layout (location=1) out vec4 out_color;
+
+const vec2 CORNERS[6] = vec2[](
+    vec2(-0.5, -0.5), vec2(-0.5, 0.5), vec2(0.5, -0.5),
+    vec2(0.5, -0.5), vec2(-0.5, 0.5), vec2(0.5, 0.5)
+);
+
+void main() {
+    vec2 corner = CORNERS[gl_VertexIndex];
+    // The rows of the view matrix are the camera's right and down directions
+    vec3 right = vec3(ubo.view_matrix[0][0], ubo.view_matrix[1][0], ubo.view_matrix[2][0]);
+    vec3 down = vec3(ubo.view_matrix[0][1], ubo.view_matrix[1][1], ubo.view_matrix[2][1]);
+    vec3 world = position + (right * corner.x + down * corner.y) * size;
+    gl_Position = ubo.projection_matrix * ubo.view_matrix * vec4(world, 1.0);
+    out_tex_coord = corner + vec2(0.5);
+    out_color = color;
+}
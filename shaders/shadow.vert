@@ -0,0 +1,22 @@
+#version 450
+
+layout (location=0) in vec3 position;
+layout (location=3) in mat4 model_matrix;
+
+layout (set=0, binding=0) uniform UniformBufferObject {
+    mat4 view_matrix;
+    mat4 projection_matrix;
+    mat4 previous_view_projection;
+    vec4 fog_color;
+    vec4 fog_params;
+    vec4 fog_height;
+    // Of the directional light casting shadows, see shadow.rs
+    mat4 light_view_projection;
+    vec4 shadow_params; // x is 1 with a shadow map, y is the size of a texel
+} ubo;
+
+// Only the depth is written. Morph targets are not applied, objects cast the shadow of
+// their base mesh.
+void main() {
+    gl_Position = ubo.light_view_projection*model_matrix*vec4(position, 1.0);
+}
@@ -0,0 +1,10 @@
+#version 450
+layout (location=0) in vec3 in_direction;
+
+layout (location=0) out vec4 color;
+
+layout (set=2, binding=0) uniform samplerCube albedo_tex;
+
+void main() {
+    color = vec4(texture(albedo_tex, in_direction).rgb, 1.0);
+}
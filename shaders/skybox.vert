@@ -0,0 +1,37 @@
+#version 450
+layout (set=0, binding=0) uniform UniformBufferObject {
+    mat4 view_matrix;
+    mat4 projection_matrix;
+    mat4 previous_view_projection;
+    vec4 fog_color;
+    vec4 fog_params;
+    vec4 fog_height;
+} ubo;
+
+layout (location=0) out vec3 out_direction;
+
+const vec3 CORNERS[8] = vec3[](
+    vec3(-1.0, -1.0, -1.0), vec3(1.0, -1.0, -1.0), vec3(-1.0, 1.0, -1.0), vec3(1.0, 1.0, -1.0),
+    vec3(-1.0, -1.0, 1.0), vec3(1.0, -1.0, 1.0), vec3(-1.0, 1.0, 1.0), vec3(1.0, 1.0, 1.0)
+);
+
+// Two triangles for each face, nothing is culled so the winding doesn't matter
+const int INDICES[36] = int[](
+    1, 3, 5, 5, 3, 7, // +X
+    0, 4, 2, 2, 4, 6, // -X
+    2, 6, 3, 3, 6, 7, // +Y
+    0, 1, 4, 4, 1, 5, // -Y
+    4, 5, 6, 6, 5, 7, // +Z
+    0, 2, 1, 1, 2, 3  // -Z
+);
+
+// A unit cube around the camera, no vertex buffer needed
+void main() {
+    vec3 position = CORNERS[INDICES[gl_VertexIndex]];
+    // Without the translation the cube moves with the camera and only turns
+    mat4 rotation = mat4(mat3(ubo.view_matrix));
+    vec4 clip = ubo.projection_matrix * rotation * vec4(position, 1.0);
+    // Depth 1, the sky is behind everything
+    gl_Position = clip.xyww;
+    out_direction = position;
+}
@@ -4,8 +4,9 @@ layout (location=1) in vec3 in_color;
 
 layout (location=0) out vec4 color;
 
-layout(set=0,binding=0) uniform sampler2D font_atlas;
+// The glyph coverage of the font atlas
+layout(set=0,binding=0) uniform sampler2D albedo_tex;
 
 void main() {
-    color = vec4(in_color, texture(font_atlas, in_tex_coord));
+    color = vec4(in_color, texture(albedo_tex, in_tex_coord));
 }
\ No newline at end of file
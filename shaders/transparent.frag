@@ -0,0 +1,264 @@
+#version 450
+#extension GL_EXT_nonuniform_qualifier : require
+
+layout (location=0) in vec3 normal_varied;
+layout (location=1) in vec4 worldpos;
+layout (location=2) in vec3 camera_pos;
+layout (location=3) in vec2 uv;
+// From the light probes, already evaluated for the normal
+layout (location=4) in vec3 ambient_irradiance;
+// See NO_DYNAMIC_LIGHTING in scene.rs
+layout (location=5) flat in uint object_flags;
+
+layout (location=0) out vec4 outColor;
+
+layout (set=0, binding=0) uniform UniformBufferObject {
+    mat4 view_matrix;
+    mat4 projection_matrix;
+    mat4 previous_view_projection;
+    vec4 fog_color; // w is the mode: 0 none, 1 linear, 2 exp, 3 exp2
+    vec4 fog_params; // density, linear start, linear end
+    vec4 fog_height; // falloff, base height
+    // Of the first directional light, see shadow.rs
+    mat4 light_view_projection;
+    vec4 shadow_params; // x is 1 with a shadow map, y is the size of a texel
+    // x is 1 without an upscale pass, which does the tone mapping otherwise
+    vec4 tone_map_params;
+} ubo;
+
+readonly layout (set=1, binding=0) buffer StorageBufferObject {
+    float num_directional;
+    float num_point;
+    float num_spot;
+    float num_probes;
+    vec3 data[];
+} sbo;
+
+// Clamped to a black border, unused slots hold a white texture
+layout (set=1, binding=1) uniform sampler2D light_cookies[8];
+
+// The depth seen by the first directional light, compared against with a border that
+// is lit
+layout (set=1, binding=3) uniform sampler2DShadow shadow_map;
+
+layout (set=2, binding=0) uniform sampler2D albedo_tex;
+
+layout (set=2, binding=1) uniform MaterialParameters {
+    float metallic;
+    float roughness;
+    // Multiplies the alpha of the albedo texture
+    float opacity;
+} material_parameters;
+
+const float PI = 3.14159265358979323846264;
+const uint NO_DYNAMIC_LIGHTING = 1u << 31;
+
+struct DirectionalLight {
+    vec3 direction_to_light;
+    vec3 irradiance;
+};
+
+struct PointLight {
+    vec3 position;
+    vec3 luminous_flux;
+};
+
+struct SpotLight {
+    vec3 position;
+    vec3 luminous_flux;
+    vec3 direction;
+    float cos_inner;
+    float cos_outer;
+    int cookie; // -1 if there is none
+};
+
+float distribution(vec3 normal, vec3 halfvector, float roughness) {
+    float NdotH = dot(halfvector, normal);
+    if (NdotH > 0) {
+        float r = roughness * roughness;
+        return r / (PI * (1 + NdotH*NdotH*(r-1))*(1 + NdotH*NdotH*(r-1)));
+    } else {
+        return 0.0;
+    }
+}
+
+float geometry(vec3 light, vec3 normal, vec3 view, float roughness) {
+    float NdotL = abs(dot(normal, light));
+    float NdotV = abs(dot(normal, view));
+    return 0.5 / max(0.01, mix(2*NdotL*NdotV, NdotL+NdotV, roughness));
+}
+
+vec3 compute_radiance(vec3 irradiance, vec3 light_dir, vec3 normal, vec3 camera_dir, vec3 surface_color, float metallic, float roughness) {
+    float NdotL = max(dot(normal, light_dir), 0);
+
+    vec3 irradiance_on_surface = irradiance*NdotL;
+
+    roughness = roughness * roughness;
+
+    vec3 F0 = mix(vec3(0.03), surface_color, vec3(metallic));
+    vec3 reflected_irradiance = (F0 + (1 - F0)*(1-NdotL)*(1-NdotL)*(1-NdotL)*(1-NdotL)*(1-NdotL)) * irradiance_on_surface;
+    vec3 refracted_irradiance = irradiance_on_surface - reflected_irradiance;
+    vec3 refracted_not_absorbed_irradiance = refracted_irradiance * (1-metallic);
+
+    vec3 halfvector = normalize(0.5*(camera_dir + light_dir));
+    float NdotH = max(dot(normal, halfvector), 0);
+    vec3 F = (F0 + (1 - F0)*(1 - NdotH)*(1 - NdotH)*(1 - NdotH)*(1 - NdotH)*(1 - NdotH));
+    vec3 relevant_reflection = reflected_irradiance*F*geometry(light_dir, normal, camera_dir, roughness) * distribution(normal, halfvector, roughness);
+
+    return refracted_not_absorbed_irradiance*surface_color/PI + relevant_reflection;
+}
+
+// Projects the position onto a plane in front of the light, the cookie covers the outer cone
+vec3 sample_cookie(SpotLight light, vec3 light_to_surface) {
+    // -y is up
+    vec3 up = abs(light.direction.y) < 0.99 ? vec3(0, -1, 0) : vec3(1, 0, 0);
+    vec3 right = normalize(cross(up, light.direction));
+    up = cross(light.direction, right);
+    float depth = dot(light_to_surface, light.direction);
+    float tan_outer = sqrt(1 - light.cos_outer*light.cos_outer) / light.cos_outer;
+    vec2 projected = vec2(dot(light_to_surface, right), dot(light_to_surface, up)) / (depth * tan_outer);
+    return texture(light_cookies[light.cookie], 0.5 * projected + 0.5).rgb;
+}
+
+// 1 where the first directional light reaches the position, 0 in its shadow. Averages
+// 3x3 comparisons to soften the edges.
+float directional_shadow(vec3 position) {
+    if (ubo.shadow_params.x == 0) {
+        return 1.0;
+    }
+    vec4 light_clip = ubo.light_view_projection*vec4(position, 1.0);
+    vec3 coords = light_clip.xyz / light_clip.w;
+    if (coords.z > 1.0) {
+        return 1.0;
+    }
+    vec2 uv = 0.5 * coords.xy + 0.5;
+    float lit = 0.0;
+    for (int x = -1; x <= 1; x++) {
+        for (int y = -1; y <= 1; y++) {
+            lit += texture(shadow_map, vec3(uv + vec2(x, y) * ubo.shadow_params.y, coords.z));
+        }
+    }
+    return lit / 9.0;
+}
+
+// Radiance stays as it is for the upscale pass, see ToneMapping in config.rs
+vec3 tone_map(vec3 total_radiance) {
+    if (ubo.tone_map_params.x == 0) {
+        return total_radiance;
+    }
+    return total_radiance / (1 + total_radiance);
+}
+
+// Applied to linear radiance, before tone mapping
+vec3 apply_fog(vec3 radiance, vec3 position, vec3 camera) {
+    int mode = int(ubo.fog_color.w);
+    if (mode == 0) {
+        return radiance;
+    }
+    float distance = length(position - camera);
+    float visibility = 1.0;
+    if (mode == 1) {
+        visibility = clamp((ubo.fog_params.z - distance) / max(ubo.fog_params.z - ubo.fog_params.y, 0.0001), 0.0, 1.0);
+    } else if (mode == 2) {
+        visibility = exp(-ubo.fog_params.x * distance);
+    } else if (mode == 3) {
+        visibility = exp(-(ubo.fog_params.x * distance) * (ubo.fog_params.x * distance));
+    }
+    if (ubo.fog_height.x > 0) {
+        // -y is up
+        float height = -position.y - ubo.fog_height.y;
+        visibility = mix(1.0, visibility, exp(-ubo.fog_height.x * max(height, 0.0)));
+    }
+    return mix(ubo.fog_color.rgb, radiance, visibility);
+}
+
+void main() {
+    vec3 total_radiance = vec3(0);
+    vec3 normal = normalize(normal_varied);
+    vec3 direction_to_camera = normalize(camera_pos - worldpos.xyz);
+    // Objects without dynamic lighting skip the lights, only the probes light them
+    bool dynamic_lighting = (object_flags & NO_DYNAMIC_LIGHTING) == 0;
+    int num_dir = dynamic_lighting ? int(sbo.num_directional) : 0;
+    int num_point = dynamic_lighting ? int(sbo.num_point) : 0;
+    int num_spot = dynamic_lighting ? int(sbo.num_spot) : 0;
+
+    vec4 albedo = texture(albedo_tex, uv);
+    vec3 surface_color = albedo.rgb;
+
+    for (int i = 0; i < num_dir; i++) {
+        vec3 data1 = sbo.data[2*i];
+        vec3 data2 = sbo.data[2*i+1];
+        DirectionalLight d_light = DirectionalLight(normalize(data1), data2);
+        if (i == 0) {
+            d_light.irradiance *= directional_shadow(worldpos.xyz);
+        }
+
+        total_radiance += compute_radiance(
+            d_light.irradiance,
+            d_light.direction_to_light,
+            normal,
+            direction_to_camera,
+            surface_color,
+            material_parameters.metallic,
+            material_parameters.roughness);
+    }
+
+    for (int i = 0; i < num_point; i++) {
+        vec3 data1 = sbo.data[2*i + 2*num_dir];
+        vec3 data2 = sbo.data[2*i + 1 + 2*num_dir];
+        PointLight light = PointLight(data1, data2);
+
+        vec3 direction_to_light = normalize(light.position - worldpos.xyz);
+        float d = length(worldpos.xyz - light.position);
+        vec3 irradiance = light.luminous_flux/(4*PI*d*d);
+
+        total_radiance += compute_radiance(
+            irradiance,
+            direction_to_light,
+            normal,
+            direction_to_camera,
+            surface_color,
+            material_parameters.metallic,
+            material_parameters.roughness);
+    }
+
+    int spot_start = 2*num_dir + 2*num_point;
+    for (int i = 0; i < num_spot; i++) {
+        vec3 data3 = sbo.data[4*i + 3 + spot_start];
+        SpotLight light = SpotLight(
+            sbo.data[4*i + spot_start],
+            sbo.data[4*i + 1 + spot_start],
+            normalize(sbo.data[4*i + 2 + spot_start]),
+            data3.x,
+            data3.y,
+            int(data3.z));
+
+        vec3 light_to_surface = worldpos.xyz - light.position;
+        float d = length(light_to_surface);
+        vec3 direction_to_light = -light_to_surface / d;
+        float cone = smoothstep(light.cos_outer, light.cos_inner, dot(light.direction, -direction_to_light));
+        if (cone <= 0) {
+            continue;
+        }
+        vec3 irradiance = cone * light.luminous_flux/(4*PI*d*d);
+        if (light.cookie >= 0) {
+            irradiance *= sample_cookie(light, light_to_surface);
+        }
+
+        total_radiance += compute_radiance(
+            irradiance,
+            direction_to_light,
+            normal,
+            direction_to_camera,
+            surface_color,
+            material_parameters.metallic,
+            material_parameters.roughness);
+    }
+
+    // Diffuse only, metals reflect their surroundings instead
+    total_radiance += (1 - material_parameters.metallic) * ambient_irradiance * surface_color / PI;
+
+    total_radiance = apply_fog(total_radiance, worldpos.xyz, camera_pos);
+
+    outColor = vec4(tone_map(total_radiance), albedo.a * material_parameters.opacity);
+}
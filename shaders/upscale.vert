@@ -0,0 +1,9 @@
+#version 450
+layout (location=0) out vec2 out_tex_coord;
+
+// One triangle covering the whole screen, no vertex buffer needed
+void main() {
+    vec2 position = vec2((gl_VertexIndex << 1) & 2, gl_VertexIndex & 2);
+    gl_Position = vec4(position * 2.0 - 1.0, 0.0, 1.0);
+    out_tex_coord = position;
+}
@@ -0,0 +1,38 @@
+#version 450
+// Adds the bloom to the HDR scene and tone maps it, see ToneMapping in config.rs
+layout (location=0) in vec2 in_tex_coord;
+
+layout (location=0) out vec4 color;
+
+layout(set=0,binding=0) uniform sampler2D albedo_tex;
+// The first level of the bloom chain, see bloom.rs
+layout(set=0,binding=1) uniform sampler2D emissive_tex;
+
+layout (push_constant) uniform ToneMapping {
+    // 1 unless auto exposure is on
+    float exposure;
+    // 0 Reinhard, 1 ACES
+    uint operator;
+    // 0 while bloom is off
+    float bloom_intensity;
+} pc;
+
+vec3 aces(vec3 x) {
+    return clamp((x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14), 0.0, 1.0);
+}
+
+void main() {
+    vec4 scene = texture(albedo_tex, in_tex_coord);
+    vec3 radiance = scene.rgb;
+    if (pc.bloom_intensity > 0.0) {
+        radiance += texture(emissive_tex, in_tex_coord).rgb * pc.bloom_intensity;
+    }
+    radiance = max(radiance * pc.exposure, vec3(0.0));
+    vec3 mapped;
+    if (pc.operator == 1) {
+        mapped = aces(radiance);
+    } else {
+        mapped = radiance / (1.0 + radiance);
+    }
+    color = vec4(mapped, scene.a);
+}
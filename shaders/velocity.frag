@@ -0,0 +1,14 @@
+#version 450
+
+layout (location=0) in vec4 current_clip;
+layout (location=1) in vec4 previous_clip;
+
+// See VELOCITY_FORMAT
+layout (location=0) out vec2 out_velocity;
+
+void main() {
+    // Normalized device coordinates span 2 UV units
+    vec2 current = current_clip.xy/current_clip.w;
+    vec2 previous = previous_clip.xy/previous_clip.w;
+    out_velocity = (current - previous)*0.5;
+}
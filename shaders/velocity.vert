@@ -0,0 +1,59 @@
+#version 450
+
+layout (location=0) in vec3 position;
+layout (location=3) in mat4 model_matrix;
+layout (location=11) in mat4 previous_model_matrix;
+
+layout (set=0, binding=0) uniform UniformBufferObject {
+    mat4 view_matrix;
+    mat4 projection_matrix;
+    mat4 previous_view_projection;
+    vec4 fog_color;
+    vec4 fog_params;
+    vec4 fog_height;
+} ubo;
+
+// See default.vert
+readonly layout (set=1, binding=2) buffer MorphDeltas {
+    vec4 data[];
+} morph_deltas;
+
+// Laid out like the one of default.vert, followed by the targets of the previous frame at
+// offset 80, see PREVIOUS_MORPH_PUSH_CONSTANT_OFFSET. The UV transform isn't used.
+layout (push_constant) uniform DrawConstants {
+    vec2 uv_offset;
+    vec2 uv_scale;
+    float uv_rotation;
+    uvec4 morph_indices;
+    vec4 morph_weights;
+    uint morph_base;
+    uint morph_vertex_count;
+    uint morph_count;
+    uvec4 previous_morph_indices;
+    vec4 previous_morph_weights;
+    uint previous_morph_base;
+    uint previous_morph_vertex_count;
+    uint previous_morph_count;
+} draw;
+
+layout (location=0) out vec4 current_clip;
+layout (location=1) out vec4 previous_clip;
+
+vec3 morph(uvec4 indices, vec4 weights, uint base, uint vertex_count, uint count) {
+    vec3 morphed_position = position;
+    for (uint i = 0; i < count; i++) {
+        uint delta = base + 2*(indices[i]*vertex_count + gl_VertexIndex);
+        morphed_position += weights[i] * morph_deltas.data[delta].xyz;
+    }
+    return morphed_position;
+}
+
+void main() {
+    vec3 current_position = morph(draw.morph_indices, draw.morph_weights, draw.morph_base,
+        draw.morph_vertex_count, draw.morph_count);
+    vec3 previous_position = morph(draw.previous_morph_indices, draw.previous_morph_weights,
+        draw.previous_morph_base, draw.previous_morph_vertex_count, draw.previous_morph_count);
+    current_clip = ubo.projection_matrix*ubo.view_matrix*model_matrix*vec4(current_position, 1.0);
+    previous_clip = ubo.previous_view_projection*previous_model_matrix*vec4(previous_position, 1.0);
+    gl_Position = current_clip;
+}
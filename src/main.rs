@@ -1,48 +1,118 @@
-use std::ops::DerefMut;
+use std::collections::HashMap;
 
 use ash::vk;
 use gpu_allocator::MemoryLocation;
-use log::info;
-use vulkan_rust::renderer::buffer::BufferManager;
-use vulkan_rust::renderer::material::{MaterialData, ShaderParameters};
+use log::{error, info, warn};
+use vulkan_rust::renderer::material::{MaterialData, MaterialTextureSlot, ShaderParameters};
 use vulkan_rust::renderer::utils::create_render_window;
-use winit::event::{Event, WindowEvent};
+use winit::event::{ElementState, Event, MouseButton, WindowEvent};
+use winit::window::CursorGrabMode;
 
 use nalgebra as na;
 use nalgebra_glm as glm;
 
-use vulkan_rust::renderer::camera::Camera;
-use vulkan_rust::renderer::light::{DirectionalLight, LightManager, PointLight};
-use vulkan_rust::renderer::{error::RendererError, Renderer};
+use vulkan_rust::renderer::camera::{Camera, CameraController, FlyController};
+use vulkan_rust::renderer::color::Color;
+use vulkan_rust::renderer::compute::ComputeBufferBinding;
+use vulkan_rust::renderer::config::{
+    BackgroundBehavior, DeviceSelection, OpaqueSort, PresentModePreference, RedrawPolicy,
+    RendererConfig, ToneMapping,
+};
+use vulkan_rust::renderer::cubemap::{direction_test_pattern, CUBEMAP_FACES};
+use vulkan_rust::renderer::culling::CullingMode;
+use vulkan_rust::renderer::cursor::CursorConfig;
+use vulkan_rust::renderer::fog::{FogConfig, FogMode};
+use vulkan_rust::renderer::gizmo::GizmoMode;
+use vulkan_rust::renderer::light::{DirectionalLight, LightHandle, PointLight, SpotLight};
+use vulkan_rust::renderer::loading::{
+    MaterialDescription, MeshDescription, MeshSource, ObjectDescription, SceneDescription,
+    TextureDescription,
+};
+use vulkan_rust::renderer::morph::MorphTarget;
+use vulkan_rust::renderer::particles::{Curve, EmitterConfig, VelocityRange};
+use vulkan_rust::renderer::probe::{LightProbe, ProbeData};
+use vulkan_rust::renderer::scene::NO_DYNAMIC_LIGHTING;
+use vulkan_rust::renderer::settings::{settings_path, RendererSettings};
+use vulkan_rust::renderer::text_input::{TextInput, TextInputEvent};
+use vulkan_rust::renderer::turntable::TurntableSubject;
+use vulkan_rust::renderer::{error::RendererError, Anchor, Renderer};
+
+// Text colors, in sRGB like in any color picker
+const GREETING_COLOR: &str = "#00FF00";
+const GREETING_SHADOW_COLOR: &str = "#999999";
+const FPS_COLOR: &str = "#FFFFFF";
+const PROGRESS_COLOR: &str = "#FFD700";
+
+const APP_NAME: &str = "My Game Engine";
+
+// The object_params block of shaders/dissolve.frag
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct DissolveParams {
+    dissolve: f32,
+}
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     log4rs::init_file("log4rs.yml", Default::default()).unwrap();
+    if std::env::args().any(|arg| arg == "--headless") {
+        return render_headless();
+    }
     let (event_loop, window, internal_window) = create_render_window()?;
     let window_size = window.inner_size();
-    let mut renderer = Renderer::new(
-        "My Game Engine",
+    let mut config = RendererConfig {
+        background_behavior: BackgroundBehavior::ThrottleTo(5.0),
+        opaque_sort: OpaqueSort::FrontToBack,
+        msaa_samples: 4,
+        ..Default::default()
+    };
+    let settings_path = settings_path(APP_NAME);
+    if let Some(path) = settings_path.as_ref().filter(|path| path.exists()) {
+        match RendererSettings::load(path) {
+            Ok(settings) => settings.configure(&mut config),
+            Err(e) => warn!("Unable to load settings from {}: {}", path.display(), e),
+        }
+    }
+    // --device takes an index or part of a name, the log lists the devices
+    if let Some(device) = std::env::args().skip_while(|arg| arg != "--device").nth(1) {
+        config.device_selection = match device.parse() {
+            Ok(index) => DeviceSelection::Index(index),
+            Err(_) => DeviceSelection::NameContains(device),
+        };
+    }
+    let mut renderer = Renderer::new_with_config(
+        APP_NAME,
         &window,
         window_size.width,
         window_size.height,
         internal_window,
+        config,
     )?;
 
+    // --skybox takes the prefix of six faces written by save_cubemap, e.g. the
+    // cubemap_test the K key saves
+    if let Some(prefix) = std::env::args().skip_while(|arg| arg != "--skybox").nth(1) {
+        let faces = CUBEMAP_FACES.map(|face| format!("{}_{}.png", prefix, face.suffix()));
+        if let Err(e) = renderer.set_skybox_from_files(faces) {
+            warn!("Unable to load the skybox {}: {}", prefix, e);
+        }
+    }
+
+    // --scene adds a scene written by save_scene, e.g. the scene.ron the F10 key saves
+    if let Some(path) = std::env::args().skip_while(|arg| arg != "--scene").nth(1) {
+        match renderer.load_scene_file(&path) {
+            Ok(scene) => info!("Loaded {} objects from {}", scene.objects.len(), path),
+            Err(e) => warn!("Unable to load the scene {}: {}", path, e),
+        }
+    }
+
     let tex1_handle = renderer.new_texture_from_file("texture.png")?;
     let tex2_handle = renderer.new_texture_from_file("texture2.jpg")?;
     let tex3_handle = renderer.new_texture_from_file("texture3.jpg")?;
-    let tex4_handle = renderer.new_texture_from_file("plain_white.jpg")?;
 
-    let sphere = if let Ok(mut allo) = renderer.allocator.lock() {
-        renderer.meshs.new_sphere_mesh(
-            3,
-            &renderer.context.device,
-            allo.deref_mut(),
-            renderer.buffer_manager.clone(),
-        )?
-    } else {
-        panic!("No allocator!");
-    };
+    let sphere = renderer.resources()?.new_sphere_mesh(3)?;
 
+    // The roughness of the first sphere is animated
+    let mut pulsing_material = None;
     for i in 0..10 {
         for j in 0..10 {
             let translation = glm::Vec3::new(i as f32 - 5., j as f32 + 5., 10.0);
@@ -58,144 +128,291 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 _ => unreachable!(),
             };
 
-            if let Ok(mut allo) = renderer.allocator.lock() {
-                // allocate uniform buffer
-                let mut buffer = BufferManager::new_buffer(
-                    renderer.buffer_manager.clone(),
-                    &renderer.context.device,
-                    allo.deref_mut(),
-                    2 * 4,
-                    vk::BufferUsageFlags::UNIFORM_BUFFER,
-                    MemoryLocation::CpuToGpu,
-                    format!("uniforms-sphere-{}-{}", i, j).as_str(),
-                )?;
-                let material_data = [metallic, roughness];
-                buffer.fill(allo.deref_mut(), &material_data)?;
-                let mat_data = MaterialData {
-                    textures: vec![tex_handle],
-                    buffers: vec![buffer.get_handle()],
-                    parameters: ShaderParameters::default(),
-                    base_template: "default".to_string(),
-                };
-                let mat_name = format!("mat_{}_{}", metallic, roughness);
-                let material_handle = renderer.material_system.build_material(
-                    &renderer.context.device,
-                    &renderer.texture_storage,
-                    renderer.buffer_manager.clone(),
-                    &mut renderer.descriptor_layout_cache,
-                    &mut renderer.descriptor_allocator,
-                    mat_name.as_str(),
-                    mat_data,
-                )?;
-                renderer.material_uniform_buffers.push(buffer);
-
-                let new_object = renderer.scene_tree.new_object(
-                    sphere,
-                    material_handle,
-                    &renderer.context.device,
-                    allo.deref_mut(),
-                    renderer.buffer_manager.clone(),
-                )?;
-                {
-                    let obj_ref = renderer
-                        .scene_tree
-                        .get_object_mut(new_object, allo.deref_mut())
-                        .expect("We were given an invalid handle");
-                    obj_ref.object.position = translation;
-                    obj_ref.object.scaling = glm::Vec3::new(scale, scale, scale);
-                }
-            } else {
-                panic!("No allocator!");
+            // Written to the material_parameters block by the material system
+            let mut parameters = ShaderParameters::default();
+            parameters.set("metallic", metallic);
+            parameters.set("roughness", roughness);
+            let mat_data = MaterialData {
+                textures: HashMap::from([(MaterialTextureSlot::Albedo, tex_handle)]),
+                buffers: vec![],
+                parameters,
+                base_template: "default".to_string(),
+            };
+            let mat_name = format!("mat_{}_{}", metallic, roughness);
+            let material_handle = renderer.build_material(mat_name.as_str(), mat_data)?;
+            if i == 0 && j == 0 {
+                pulsing_material = Some(material_handle);
             }
+
+            let mut resources = renderer.resources()?;
+            let new_object = resources.new_object(sphere, material_handle)?;
+            let obj_ref = resources
+                .get_object_mut(new_object)
+                .expect("We were given an invalid handle");
+            obj_ref.object.position = translation;
+            obj_ref.object.scaling = glm::Vec3::new(scale, scale, scale);
         }
     }
 
-    // Try loading an obj model
-    let car_model = if let Ok(mut allo) = renderer.allocator.lock() {
-        renderer.meshs.new_mesh_from_obj(
-            "models/alfa147.obj",
-            &renderer.context.device,
-            allo.deref_mut(),
-            renderer.buffer_manager.clone(),
-        )?
-    } else {
-        panic!("No allocator!");
+    // A flat box with a scrolling texture, like a conveyor belt
+    let conveyor = renderer.load_scene(SceneDescription {
+        textures: vec![TextureDescription {
+            name: "conveyor".to_string(),
+            path: "texture.png".into(),
+        }],
+        meshes: vec![MeshDescription {
+            name: "conveyor".to_string(),
+            source: MeshSource::Cube,
+        }],
+        materials: vec![MaterialDescription {
+            name: "conveyor_material".to_string(),
+            base_template: "default".to_string(),
+            textures: vec!["conveyor".to_string()],
+            uniform_data: vec![0.0, 0.9],
+        }],
+        objects: vec![ObjectDescription {
+            position: glm::Vec3::new(0f32, 16f32, 10f32),
+            scaling: glm::Vec3::new(5.0f32, 0.1f32, 5.0f32),
+            ..ObjectDescription::new("conveyor", "conveyor_material")
+        }],
+        // Warm ambient light above the conveyor and cool light below it, -y is up
+        probes: vec![
+            LightProbe {
+                position: na::Point3::new(0.0, 14.0, 10.0),
+                data: ProbeData::Ambient(glm::Vec3::new(0.08, 0.05, 0.03)),
+            },
+            LightProbe {
+                position: na::Point3::new(0.0, 18.0, 10.0),
+                data: ProbeData::Ambient(glm::Vec3::new(0.02, 0.03, 0.06)),
+            },
+        ],
+    })?;
+    let conveyor_material = conveyor.materials["conveyor_material"];
+
+    // Two cubes that dissolve out of phase, with one material for both
+    renderer.register_effect_template(
+        "dissolve",
+        vk_shader_macros::include_glsl!("./shaders/default.vert", kind: vert),
+        vk_shader_macros::include_glsl!("./shaders/dissolve.frag", kind: frag),
+        &[],
+    )?;
+    let dissolving = renderer.load_scene(SceneDescription {
+        textures: vec![TextureDescription {
+            name: "dissolve".to_string(),
+            path: "texture.png".into(),
+        }],
+        meshes: vec![MeshDescription {
+            name: "dissolve_cube".to_string(),
+            source: MeshSource::Cube,
+        }],
+        materials: vec![MaterialDescription {
+            name: "dissolve_material".to_string(),
+            base_template: "dissolve".to_string(),
+            textures: vec!["dissolve".to_string()],
+            uniform_data: vec![0.0, 0.5],
+        }],
+        objects: vec![
+            ObjectDescription {
+                position: glm::Vec3::new(-8f32, 10f32, 10f32),
+                ..ObjectDescription::new("dissolve_cube", "dissolve_material")
+            },
+            ObjectDescription {
+                position: glm::Vec3::new(8f32, 10f32, 10f32),
+                ..ObjectDescription::new("dissolve_cube", "dissolve_material")
+            },
+        ],
+        probes: vec![],
+    })?;
+    let dissolve_cubes = dissolving.objects.clone();
+
+    // A sphere that bulges and squashes with two morph targets
+    let blob = renderer.load_scene(SceneDescription {
+        textures: vec![TextureDescription {
+            name: "blob".to_string(),
+            path: "texture2.jpg".into(),
+        }],
+        meshes: vec![MeshDescription {
+            name: "blob".to_string(),
+            source: MeshSource::Sphere(3),
+        }],
+        materials: vec![MaterialDescription {
+            name: "blob_material".to_string(),
+            base_template: "default".to_string(),
+            textures: vec!["blob".to_string()],
+            uniform_data: vec![0.2, 0.4],
+        }],
+        objects: vec![ObjectDescription {
+            position: glm::Vec3::new(-4f32, 14f32, 10f32),
+            ..ObjectDescription::new("blob", "blob_material")
+        }],
+        probes: vec![],
+    })?;
+    let blob_mesh = blob.meshes["blob"];
+    let blob_targets = {
+        let vertices = renderer
+            .meshs
+            .get_mesh(blob_mesh)
+            .expect("Blob mesh not loaded?")
+            .vertices();
+        vec![
+            MorphTarget {
+                name: "bulge".to_string(),
+                position_deltas: vertices.iter().map(|v| v.normal * 0.3).collect(),
+                normal_deltas: vec![],
+            },
+            MorphTarget {
+                name: "squash".to_string(),
+                position_deltas: vertices
+                    .iter()
+                    .map(|v| {
+                        // Vertex is packed, so its fields can't be borrowed
+                        let pos = v.pos;
+                        glm::Vec3::new(0.3 * pos.x, -0.4 * pos.y, 0.3 * pos.z)
+                    })
+                    .collect(),
+                normal_deltas: vec![],
+            },
+        ]
+    };
+    renderer.set_morph_targets(blob_mesh, blob_targets)?;
+    let blob_object = blob.objects[0];
+
+    // A sphere moved by a compute shader, its vertex shader reads the offset from a
+    // storage buffer in a user managed set
+    let orbit_pass = renderer.register_compute_pass(
+        "orbit",
+        vk_shader_macros::include_glsl!("./shaders/orbit.comp", kind: comp),
+    )?;
+    let orbit_template = renderer.register_effect_template(
+        "orbit",
+        vk_shader_macros::include_glsl!("./shaders/orbit.vert", kind: vert),
+        vk_shader_macros::include_glsl!("./shaders/default.frag", kind: frag),
+        &[3],
+    )?;
+    let orbit_buffer = {
+        let mut resources = renderer.resources()?;
+        let mut buffer = resources.new_buffer(
+            32,
+            vk::BufferUsageFlags::STORAGE_BUFFER,
+            MemoryLocation::CpuToGpu,
+            "orbit",
+        )?;
+        buffer.fill(resources.allocator(), &[0f32; 8])?;
+        buffer
     };
+    let orbit_set = renderer.allocate_user_set(orbit_template, 3)?;
+    let orbit_info = [vk::DescriptorBufferInfo {
+        buffer: orbit_buffer.get_buffer().buffer,
+        offset: 0,
+        range: vk::WHOLE_SIZE,
+    }];
+    let orbit_write = vk::WriteDescriptorSet::builder()
+        .dst_set(orbit_set)
+        .dst_binding(0)
+        .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+        .buffer_info(&orbit_info)
+        .build();
+    unsafe {
+        renderer
+            .context
+            .device
+            .update_descriptor_sets(&[orbit_write], &[]);
+    }
+    renderer.set_descriptor_hook(orbit_template, move |device, cmd_buf, layout, _| unsafe {
+        device.cmd_bind_descriptor_sets(
+            cmd_buf,
+            vk::PipelineBindPoint::GRAPHICS,
+            layout,
+            3,
+            &[orbit_set],
+            &[],
+        );
+    });
+    let orbiter = renderer.load_scene(SceneDescription {
+        textures: vec![TextureDescription {
+            name: "orbiter".to_string(),
+            path: "texture2.jpg".into(),
+        }],
+        meshes: vec![MeshDescription {
+            name: "orbiter".to_string(),
+            source: MeshSource::Sphere(2),
+        }],
+        materials: vec![MaterialDescription {
+            name: "orbiter_material".to_string(),
+            base_template: "orbit".to_string(),
+            textures: vec!["orbiter".to_string()],
+            uniform_data: vec![0.5, 0.0],
+        }],
+        objects: vec![ObjectDescription {
+            position: glm::Vec3::new(-4f32, 20f32, 10f32),
+            ..ObjectDescription::new("orbiter", "orbiter_material")
+        }],
+        ..Default::default()
+    })?;
+    // The bounds don't know about the offset
+    if let Some(obj_ref) = renderer.resources()?.get_object_mut(orbiter.objects[0]) {
+        obj_ref.object.culling = CullingMode::Never;
+    }
+
+    // Stream the car in while the rest of the scene is already rendering
     let car_base_position = glm::Vec3::new(0f32, 15f32, 20f32);
-    let car_handle = {
-        if let Ok(mut allo) = renderer.allocator.lock() {
-            // allocate uniform buffer
-            let mut buffer = BufferManager::new_buffer(
-                renderer.buffer_manager.clone(),
-                &renderer.context.device,
-                allo.deref_mut(),
-                2 * 4,
-                vk::BufferUsageFlags::UNIFORM_BUFFER,
-                MemoryLocation::CpuToGpu,
-                "uniforms-car",
-            )?;
-            let material_data = [0.8f32, 0.1f32];
-            buffer.fill(allo.deref_mut(), &material_data)?;
-            let mat_data = MaterialData {
-                textures: vec![tex4_handle],
-                buffers: vec![buffer.get_handle()],
-                parameters: ShaderParameters::default(),
-                base_template: "default".to_string(),
-            };
-            let material_handle = renderer.material_system.build_material(
-                &renderer.context.device,
-                &renderer.texture_storage,
-                renderer.buffer_manager.clone(),
-                &mut renderer.descriptor_layout_cache,
-                &mut renderer.descriptor_allocator,
-                "car_material",
-                mat_data,
-            )?;
-            renderer.material_uniform_buffers.push(buffer);
-            let child_object = {
-                let h = renderer.scene_tree.new_object(
-                    sphere,
-                    material_handle,
-                    &renderer.context.device,
-                    allo.deref_mut(),
-                    renderer.buffer_manager.clone(),
-                )?;
-                let obj_ref = renderer
-                    .scene_tree
-                    .get_object_mut(h, allo.deref_mut())
-                    .expect("We were given an invalid handle");
-                obj_ref.object.position = glm::Vec3::new(0f32, 0f32, 65f32);
-                obj_ref.object.scaling = glm::Vec3::new(10.0f32, 10.0f32, 10.0f32);
-                h
-            };
-            let new_object = renderer.scene_tree.new_object(
-                car_model,
-                material_handle,
-                &renderer.context.device,
-                allo.deref_mut(),
-                renderer.buffer_manager.clone(),
-            )?;
-            {
-                let mut obj_ref = renderer
-                    .scene_tree
-                    .get_object_mut(new_object, allo.deref_mut())
-                    .expect("We were given an invalid handle");
-                obj_ref.object.position = car_base_position;
-                obj_ref.object.scaling = glm::Vec3::new(0.1f32, 0.1f32, 0.1f32);
-                obj_ref.object.rotation = glm::Quat::from_polar_decomposition(
+    let car_scene = SceneDescription {
+        textures: vec![TextureDescription {
+            name: "plain_white".to_string(),
+            path: "plain_white.jpg".into(),
+        }],
+        meshes: vec![
+            MeshDescription {
+                name: "car".to_string(),
+                source: MeshSource::Obj("models/alfa147.obj".into()),
+            },
+            MeshDescription {
+                name: "sphere".to_string(),
+                source: MeshSource::Sphere(3),
+            },
+        ],
+        materials: vec![MaterialDescription {
+            name: "car_material".to_string(),
+            base_template: "default".to_string(),
+            textures: vec!["plain_white".to_string()],
+            uniform_data: vec![0.8, 0.1],
+        }],
+        objects: vec![
+            ObjectDescription {
+                position: car_base_position,
+                scaling: glm::Vec3::new(0.1f32, 0.1f32, 0.1f32),
+                rotation: glm::Quat::from_polar_decomposition(
                     1.0f32,
                     std::f32::consts::FRAC_2_PI,
                     na::Unit::<glm::Vec3>::new_normalize(glm::Vec3::new(1.0f32, 0.0f32, 0.0f32)),
-                );
-                obj_ref.add_child(child_object)?;
-            }
-            new_object
-        } else {
-            panic!("No allocator!");
-        }
+                ),
+                ..ObjectDescription::new("car", "car_material")
+            },
+            ObjectDescription {
+                position: glm::Vec3::new(0f32, 0f32, 65f32),
+                scaling: glm::Vec3::new(10.0f32, 10.0f32, 10.0f32),
+                parent: Some(0),
+                ..ObjectDescription::new("sphere", "car_material")
+            },
+        ],
+        ..Default::default()
     };
+    let mut car_ticket = Some(renderer.load_scene_incremental(car_scene));
+    let mut car_handle = None;
+    // Toggled with B, hides the sphere parented to the car as well
+    let mut car_visible = true;
+    let mut progress_text: Option<usize> = None;
+    // Taken with F12 and saved once the copy is done, without stalling the frame
+    let mut pending_screenshot = None;
 
-    let mut lights = LightManager::default();
+    // A flashlight shining through a window onto the sphere grid
+    let window_cookie = renderer.new_texture_from_u8(
+        &window_frame_cookie(64),
+        64,
+        64,
+        vk::Format::R8G8B8A8_UNORM,
+    )?;
+    let lights = &mut renderer.lights;
     lights.add_light(DirectionalLight {
         direction: na::Unit::new_normalize(glm::Vec3::new(-1., -1., 0.)),
         illuminance: glm::Vec3::new(10.1, 10.1, 10.1),
@@ -212,68 +429,202 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         position: na::Point3::new(1.5, 0.2, 0.0),
         luminous_flux: glm::Vec3::new(5.0, 5.0, 5.0),
     });
-    renderer.update_storage_from_lights(&lights)?;
-
-    let mut camera = Camera::builder().build();
-
-    let mut speed_factor = 1.0f32;
-    let mut move_up_pressed = false;
-    let mut move_down_pressed = false;
-    let mut move_forward_pressed = false;
-    let mut move_backward_pressed = false;
-    let mut move_right_pressed = false;
-    let mut move_left_pressed = false;
-    let mut turn_up_pressed = false;
-    let mut turn_down_pressed = false;
-    let mut turn_right_pressed = false;
-    let mut turn_left_pressed = false;
+    // Circles the car, moved every frame
+    let orbiting_light = match lights.add_light(PointLight {
+        position: na::Point3::new(0.0, 0.0, 0.0),
+        luminous_flux: glm::Vec3::new(300.0, 120.0, 40.0),
+    }) {
+        LightHandle::Point(handle) => handle,
+        _ => unreachable!(),
+    };
+    lights.add_light(SpotLight {
+        position: na::Point3::new(-0.5, 9.5, 2.0),
+        direction: na::Unit::new_normalize(glm::Vec3::new(0.0, 0.0, 1.0)),
+        inner_angle: 20f32.to_radians(),
+        outer_angle: 25f32.to_radians(),
+        luminous_flux: glm::Vec3::new(800.0, 750.0, 600.0),
+        cookie: Some(window_cookie),
+    });
+    for probe in conveyor.probes.iter() {
+        lights.add_probe(probe.position, probe.data);
+    }
+
+    // Shown instead of the system cursor while mouse look is active
+    let crosshair = CursorConfig {
+        texture: renderer.new_texture_from_u8(
+            &crosshair_cursor(32),
+            32,
+            32,
+            vk::Format::R8G8B8A8_UNORM,
+        )?,
+        hotspot_px: glm::vec2(16.0, 16.0),
+        size_px: glm::vec2(32.0, 32.0),
+    };
+    let mut mouse_look = false;
+
+    // Smoke puffing out of the car once it is loaded
+    let smoke_texture =
+        renderer.new_texture_from_u8(&smoke_puff(32), 32, 32, vk::Format::R8G8B8A8_UNORM)?;
+
+    // A sphere whose lighting is baked once instead of being computed every frame
+    let baked = renderer.load_scene(SceneDescription {
+        textures: vec![TextureDescription {
+            name: "baked_albedo".to_string(),
+            path: "texture2.jpg".into(),
+        }],
+        meshes: vec![MeshDescription {
+            name: "baked_sphere".to_string(),
+            source: MeshSource::Sphere(3),
+        }],
+        materials: vec![MaterialDescription {
+            name: "baked_dynamic".to_string(),
+            base_template: "default".to_string(),
+            textures: vec!["baked_albedo".to_string()],
+            uniform_data: vec![0.0, 0.9],
+        }],
+        objects: vec![ObjectDescription {
+            position: glm::Vec3::new(-8f32, 10f32, 10f32),
+            ..ObjectDescription::new("baked_sphere", "baked_dynamic")
+        }],
+        ..Default::default()
+    })?;
+    let baked_sphere = baked.objects[0];
+    let lightmaps = renderer.bake_lightmaps(&[baked_sphere], 256)?;
+    let baked_material = renderer.build_material(
+        "baked_static",
+        MaterialData {
+            textures: HashMap::from([
+                (MaterialTextureSlot::Albedo, baked.textures["baked_albedo"]),
+                (MaterialTextureSlot::Lightmap, lightmaps[0]),
+            ]),
+            buffers: vec![],
+            parameters: ShaderParameters::default(),
+            base_template: "lightmapped".to_string(),
+        },
+    )?;
+    {
+        // The lightmap holds all of the lights, so they aren't added again
+        let mut resources = renderer.resources()?;
+        let guard = resources
+            .get_object_mut(baked_sphere)
+            .expect("We were given an invalid handle");
+        guard.object.material = baked_material;
+        guard.object.flags |= NO_DYNAMIC_LIGHTING;
+    }
+
+    // The demo props can be hidden together with H
+    let props_group = renderer.scene_tree.create_group("demo_props");
+    renderer
+        .scene_tree
+        .add_to_group(props_group, conveyor.objects[0])?;
+    renderer
+        .scene_tree
+        .add_to_group(props_group, baked_sphere)?;
+    let mut props_visible = true;
+
+    // Subtle fog, toggled with G
+    let demo_fog = FogConfig {
+        mode: FogMode::Exponential { density: 0.02 },
+        color: glm::Vec3::new(0.05, 0.05, 0.06),
+        apply_to_background: true,
+        ..Default::default()
+    };
+    let mut fog_enabled = true;
+    renderer.set_fog(demo_fog);
+
+    // T cycles through the gizmos on the baked sphere, dragged with the left mouse button
+    let mut gizmo_mode: Option<GizmoMode> = None;
+    let mut primary_down = false;
+
+    let extent = renderer.get_extent();
+    let mut camera = Camera::builder()
+        .aspect(extent.width as f32 / extent.height as f32)
+        .build();
+
+    // WASD, Space and C move, Q, E, R and F turn, shift and control change the speed
+    let mut camera_controller = FlyController::default();
 
     // Create some text
     renderer.add_text(
-        &window,
         (100, 200),
+        Anchor::TopLeft,
         &[
             &fontdue::layout::TextStyle::new("Hello ", 35.0, 0),
             &fontdue::layout::TextStyle::new("world!", 40.0, 0),
             &fontdue::layout::TextStyle::new("(and smaller)", 8.0, 0),
         ],
-        [0., 1., 0.],
+        Color::from_srgb_hex(GREETING_COLOR).expect("Invalid color"),
     )?;
     renderer.add_text(
-        &window,
         (100, 300),
+        Anchor::TopLeft,
         &[
             &fontdue::layout::TextStyle::new("Hello ", 35.0, 0),
             &fontdue::layout::TextStyle::new("world!", 40.0, 0),
             &fontdue::layout::TextStyle::new("(and smaller)", 8.0, 0),
         ],
-        [0.6, 0.6, 0.6],
+        Color::from_srgb_hex(GREETING_SHADOW_COLOR).expect("Invalid color"),
     )?;
 
     // Run event loop
     let mut running = true;
     let mut now = std::time::SystemTime::now();
-    let mut fps_id = renderer.add_text(
-        &window,
-        (0, 50),
+    // Stays in the top right corner when the window is resized
+    let fps_id = renderer.add_text(
+        (10, 10),
+        Anchor::TopRight,
         &[&fontdue::layout::TextStyle::new("FPS: 0000.00", 20.0, 0)],
-        [1.0, 1.0, 1.0],
+        Color::from_srgb_hex(FPS_COLOR).expect("Invalid color"),
     )?;
+    // Click it to rename the baked sphere
+    let mut name_input = TextInput::new((0, 150), (300, 30), 20.0, "baked sphere");
     let start_time = std::time::SystemTime::now();
+    // The statistics of the last frame are logged once a second
+    let mut last_stats_log = std::time::Instant::now();
     event_loop.run(move |event, _, controlflow| {
+        // A focused text input gets the keyboard before any key binding
+        let text_response = name_input.handle_event(&window, &event);
+        match &text_response.event {
+            Some(TextInputEvent::Submitted(name)) => info!("Renamed the baked sphere to {}", name),
+            Some(TextInputEvent::Cancelled) => info!("Renaming cancelled"),
+            None => {}
+        }
         renderer.handle_event(&window, &event);
+        if !text_response.consumed {
+            camera_controller.handle_event(&event);
+        }
+        if let Event::WindowEvent {
+            event:
+                WindowEvent::MouseInput {
+                    state,
+                    button: MouseButton::Left,
+                    ..
+                },
+            ..
+        } = &event
+        {
+            primary_down = *state == ElementState::Pressed;
+        }
+        if let Event::WindowEvent {
+            event: WindowEvent::CursorMoved { .. } | WindowEvent::MouseInput { .. },
+            ..
+        } = &event
+        {
+            renderer
+                .gizmo_handle_event(renderer.get_cursor_position(), primary_down, &camera)
+                .expect("Could not apply the gizmo");
+        }
         match event {
             Event::WindowEvent {
                 event: WindowEvent::Resized(size),
                 ..
             } => {
-                renderer
+                let extent = renderer
                     .recreate_swapchain(size.width, size.height)
                     .expect("Recreate Swapchain");
-                camera.set_aspect(size.width as f32 / size.height as f32);
-                renderer
-                    .update_storage_from_lights(&lights)
-                    .expect("light update");
+                if extent.width > 0 && extent.height > 0 {
+                    camera.set_aspect(extent.width as f32 / extent.height as f32);
+                }
             }
             Event::WindowEvent {
                 event: WindowEvent::CloseRequested,
@@ -294,77 +645,237 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                         ..
                     },
                 ..
-            } => match keycode {
-                winit::event::VirtualKeyCode::Space => {
-                    move_up_pressed = match pressed {
-                        winit::event::ElementState::Pressed => true,
-                        winit::event::ElementState::Released => false,
-                    };
-                }
-                winit::event::VirtualKeyCode::C => {
-                    move_down_pressed = match pressed {
-                        winit::event::ElementState::Pressed => true,
-                        winit::event::ElementState::Released => false,
-                    };
+            } if !text_response.consumed => match keycode {
+                winit::event::VirtualKeyCode::G => {
+                    if matches!(pressed, winit::event::ElementState::Pressed) {
+                        fog_enabled = !fog_enabled;
+                        renderer.set_fog(if fog_enabled {
+                            demo_fog
+                        } else {
+                            FogConfig::default()
+                        });
+                    }
                 }
-                winit::event::VirtualKeyCode::W => {
-                    move_forward_pressed = match pressed {
-                        winit::event::ElementState::Pressed => true,
-                        winit::event::ElementState::Released => false,
-                    };
+                winit::event::VirtualKeyCode::H => {
+                    if matches!(pressed, winit::event::ElementState::Pressed) {
+                        props_visible = !props_visible;
+                        renderer
+                            .scene_tree
+                            .set_group_visible(props_group, props_visible)
+                            .expect("Invalid group handle");
+                    }
                 }
-                winit::event::VirtualKeyCode::S => {
-                    move_backward_pressed = match pressed {
-                        winit::event::ElementState::Pressed => true,
-                        winit::event::ElementState::Released => false,
-                    };
+                winit::event::VirtualKeyCode::T => {
+                    if matches!(pressed, winit::event::ElementState::Pressed) {
+                        gizmo_mode = match gizmo_mode {
+                            None => Some(GizmoMode::Translate),
+                            Some(GizmoMode::Translate) => Some(GizmoMode::Rotate),
+                            Some(GizmoMode::Rotate) => Some(GizmoMode::Scale),
+                            Some(GizmoMode::Scale) => None,
+                        };
+                        renderer
+                            .set_gizmo(gizmo_mode, baked_sphere)
+                            .expect("Could not set the gizmo");
+                    }
                 }
-                winit::event::VirtualKeyCode::A => {
-                    move_left_pressed = match pressed {
-                        winit::event::ElementState::Pressed => true,
-                        winit::event::ElementState::Released => false,
-                    };
+                winit::event::VirtualKeyCode::P => {
+                    if matches!(pressed, winit::event::ElementState::Pressed) {
+                        renderer
+                            .render_material_preview(conveyor_material, 128)
+                            .expect("Could not render material preview")
+                            .save("material_preview.png")
+                            .expect("Could not save material preview");
+                        info!("Saved material preview");
+                    }
                 }
-                winit::event::VirtualKeyCode::D => {
-                    move_right_pressed = match pressed {
-                        winit::event::ElementState::Pressed => true,
-                        winit::event::ElementState::Released => false,
-                    };
+                winit::event::VirtualKeyCode::B => {
+                    if matches!(pressed, winit::event::ElementState::Pressed) {
+                        if let Some(car_handle) = car_handle {
+                            car_visible = !car_visible;
+                            renderer
+                                .scene_tree
+                                .set_visible(car_handle, car_visible, true)
+                                .expect("Invalid car handle");
+                        }
+                    }
                 }
-                winit::event::VirtualKeyCode::E => {
-                    turn_right_pressed = match pressed {
-                        winit::event::ElementState::Pressed => true,
-                        winit::event::ElementState::Released => false,
-                    };
+                winit::event::VirtualKeyCode::K => {
+                    if matches!(pressed, winit::event::ElementState::Pressed) {
+                        // Every saved face should be a single color with a darker top
+                        // left quarter, see direction_test_pattern
+                        let pattern = renderer
+                            .new_texture_from_u8(
+                                &direction_test_pattern(512, 256),
+                                512,
+                                256,
+                                vk::Format::R8G8B8A8_SRGB,
+                            )
+                            .expect("Could not create the test pattern");
+                        let cubemap = renderer
+                            .equirect_to_cubemap(pattern, 128)
+                            .expect("Could not convert the test pattern");
+                        renderer
+                            .save_cubemap(cubemap, "cubemap_test")
+                            .expect("Could not save the cubemap");
+                        renderer
+                            .cubemap_to_equirect(cubemap, 512)
+                            .expect("Could not convert the cubemap back")
+                            .save("cubemap_test_equirect.png")
+                            .expect("Could not save the equirectangular image");
+                        for texture in [pattern, cubemap] {
+                            renderer
+                                .destroy_texture(texture, false)
+                                .expect("Invalid texture handle");
+                        }
+                        info!("Saved the cubemap test pattern");
+                    }
                 }
-                winit::event::VirtualKeyCode::Q => {
-                    turn_left_pressed = match pressed {
-                        winit::event::ElementState::Pressed => true,
-                        winit::event::ElementState::Released => false,
-                    };
+                winit::event::VirtualKeyCode::L => {
+                    if matches!(pressed, winit::event::ElementState::Pressed) {
+                        renderer
+                            .bake_light_probes(32)
+                            .expect("Could not bake the light probes");
+                        info!("Baked {} light probes", renderer.lights.probes().len());
+                    }
                 }
-                winit::event::VirtualKeyCode::R => {
-                    turn_up_pressed = match pressed {
-                        winit::event::ElementState::Pressed => true,
-                        winit::event::ElementState::Released => false,
-                    };
+                winit::event::VirtualKeyCode::O => {
+                    if matches!(pressed, winit::event::ElementState::Pressed) {
+                        let tone_mapping = match renderer.get_tone_mapping() {
+                            ToneMapping::Reinhard => ToneMapping::Aces,
+                            ToneMapping::Aces => ToneMapping::Reinhard,
+                        };
+                        renderer.set_tone_mapping(tone_mapping);
+                        info!("Tone mapping: {:?}", tone_mapping);
+                    }
                 }
-                winit::event::VirtualKeyCode::F => {
-                    turn_down_pressed = match pressed {
-                        winit::event::ElementState::Pressed => true,
-                        winit::event::ElementState::Released => false,
-                    };
+                winit::event::VirtualKeyCode::N => {
+                    if matches!(pressed, winit::event::ElementState::Pressed) {
+                        let intensity = if renderer.get_bloom().intensity > 0.0 {
+                            0.0
+                        } else {
+                            0.3
+                        };
+                        renderer
+                            .set_bloom_intensity(intensity)
+                            .expect("Could not change the bloom");
+                        info!("Bloom intensity: {}", intensity);
+                    }
                 }
-                winit::event::VirtualKeyCode::LShift => {
-                    speed_factor = match pressed {
-                        winit::event::ElementState::Pressed => 2.0f32,
-                        winit::event::ElementState::Released => 1.0f32,
-                    };
+                winit::event::VirtualKeyCode::F10 => {
+                    if matches!(pressed, winit::event::ElementState::Pressed) {
+                        match renderer.save_scene("scene.ron") {
+                            Ok(()) => info!("Saved the scene to scene.ron"),
+                            Err(e) => warn!("Unable to save the scene: {}", e),
+                        }
+                    }
                 }
                 winit::event::VirtualKeyCode::F12 => {
                     if matches!(pressed, winit::event::ElementState::Pressed) {
-                        renderer.screenshot().expect("Could not take screenshot");
-                        info!("Screenshotted!");
+                        pending_screenshot = Some(
+                            renderer
+                                .screenshot_async()
+                                .expect("Could not take screenshot"),
+                        );
+                    }
+                }
+                winit::event::VirtualKeyCode::M => {
+                    if matches!(pressed, winit::event::ElementState::Pressed) {
+                        mouse_look = !mouse_look;
+                        let grab = if mouse_look {
+                            // Not every platform can lock the cursor in place
+                            window
+                                .set_cursor_grab(CursorGrabMode::Locked)
+                                .or_else(|_| window.set_cursor_grab(CursorGrabMode::Confined))
+                        } else {
+                            window.set_cursor_grab(CursorGrabMode::None)
+                        };
+                        if let Err(e) = grab {
+                            info!("Could not change the cursor grab: {}", e);
+                        }
+                        window.set_cursor_visible(!mouse_look);
+                        camera_controller.set_mouse_look(mouse_look);
+                        renderer.set_cursor_grabbed(mouse_look);
+                        renderer
+                            .set_software_cursor(mouse_look.then_some(crosshair))
+                            .expect("Could not set the software cursor");
+                    }
+                }
+                #[cfg(feature = "shader-reload")]
+                winit::event::VirtualKeyCode::F5 => {
+                    if matches!(pressed, winit::event::ElementState::Pressed) {
+                        match renderer.reload_shaders() {
+                            Ok(changed) if changed.is_empty() => info!("No shader changed"),
+                            Ok(changed) => info!("Reloaded {}", changed.join(", ")),
+                            Err(RendererError::ShaderReloadError { source, .. }) => {
+                                warn!("Kept the old shaders, {}", source)
+                            }
+                            Err(e) => warn!("Unable to reload the shaders: {}", e),
+                        }
+                    }
+                }
+                winit::event::VirtualKeyCode::F6 => {
+                    if matches!(pressed, winit::event::ElementState::Pressed) {
+                        // Switches between full and half resolution
+                        let mut settings = renderer.get_settings();
+                        settings.render_scale = if settings.render_scale < 1.0 {
+                            1.0
+                        } else {
+                            0.5
+                        };
+                        let report = renderer
+                            .apply_settings(&settings)
+                            .expect("Could not apply the settings");
+                        info!("Render scale {}: {:?}", settings.render_scale, report);
+                    }
+                }
+                winit::event::VirtualKeyCode::F7 => {
+                    if matches!(pressed, winit::event::ElementState::Pressed) {
+                        // Only draws when something changes, the animations pause meanwhile
+                        let mut settings = renderer.get_settings();
+                        settings.redraw_policy = match settings.redraw_policy {
+                            RedrawPolicy::Continuous => RedrawPolicy::OnDemand,
+                            RedrawPolicy::OnDemand => RedrawPolicy::Continuous,
+                        };
+                        renderer
+                            .apply_settings(&settings)
+                            .expect("Could not apply the settings");
+                        info!("Redraw policy {:?}", settings.redraw_policy);
+                    }
+                }
+                winit::event::VirtualKeyCode::F8 => {
+                    if matches!(pressed, winit::event::ElementState::Pressed) {
+                        let aovs = renderer
+                            .capture_aovs(&camera)
+                            .expect("Could not capture the AOVs");
+                        aovs.save("aovs").expect("Could not save the AOVs");
+                        info!(
+                            "Captured AOVs with {} objects",
+                            aovs.unique_object_ids().len()
+                        );
+                    }
+                }
+                winit::event::VirtualKeyCode::F9 => {
+                    if matches!(pressed, winit::event::ElementState::Pressed) {
+                        let frames = renderer
+                            .capture_turntable(TurntableSubject::Object(baked_sphere), 8, 256)
+                            .expect("Could not capture the turntable");
+                        for (i, frame) in frames.iter().enumerate() {
+                            frame
+                                .save(format!("turntable_{}.png", i))
+                                .expect("Could not save the turntable frame");
+                        }
+                        info!("Saved {} turntable frames", frames.len());
+                    }
+                }
+                winit::event::VirtualKeyCode::V => {
+                    if matches!(pressed, winit::event::ElementState::Pressed) {
+                        // Toggles vsync, the FPS text shows the mode the surface allowed
+                        let mode = match renderer.get_present_mode() {
+                            PresentModePreference::Fifo => PresentModePreference::Immediate,
+                            _ => PresentModePreference::Fifo,
+                        };
+                        renderer.set_present_mode(mode);
+                        info!("Present mode {:?}", mode);
                     }
                 }
                 winit::event::VirtualKeyCode::Escape => {
@@ -373,9 +884,23 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 }
                 _ => {}
             },
+            Event::LoopDestroyed => {
+                if let Some(path) = &settings_path {
+                    if let Err(e) = renderer.get_settings().save(path) {
+                        warn!("Unable to save settings to {}: {}", path.display(), e);
+                    }
+                }
+            }
             Event::MainEventsCleared => {
                 // doing the work here (later)
                 window.request_redraw();
+                // Unchanged frames return right away, so don't spin on them
+                *controlflow = match renderer.get_settings().redraw_policy {
+                    RedrawPolicy::Continuous => winit::event_loop::ControlFlow::Poll,
+                    RedrawPolicy::OnDemand => winit::event_loop::ControlFlow::WaitUntil(
+                        std::time::Instant::now() + std::time::Duration::from_millis(16),
+                    ),
+                };
             }
             Event::RedrawRequested(_) => {
                 if !running {
@@ -383,69 +908,174 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 }
                 let temp = now;
                 now = std::time::SystemTime::now();
-                let diff = 1.0 / now.duration_since(temp).unwrap_or_default().as_secs_f32();
-                let text = format!("FPS: {:.02}", diff);
-                renderer
-                    .remove_text(fps_id[0])
-                    .expect("Could not remove old fps text");
-                fps_id = renderer
-                    .add_text(
-                        &window,
-                        (0, 100),
-                        &[&fontdue::layout::TextStyle::new(&text, 20.0, 0)],
-                        [1.0, 1.0, 1.0],
-                    )
-                    .expect("Could not add fps text");
-                let move_speed: f32 = 0.05f32 * speed_factor;
-                let turn_speed: f32 = 0.005f32 * speed_factor;
-                if move_up_pressed {
-                    camera.move_up(move_speed);
+                let frame_time = now.duration_since(temp).unwrap_or_default().as_secs_f32();
+                let animate = renderer.get_settings().redraw_policy == RedrawPolicy::Continuous;
+                if animate {
+                    let diff = 1.0 / frame_time;
+                    let text = format!("FPS: {:.02} {:?}", diff, renderer.present_mode());
+                    renderer
+                        .update_text(
+                            fps_id,
+                            (10, 10),
+                            Anchor::TopRight,
+                            &[&fontdue::layout::TextStyle::new(&text, 20.0, 0)],
+                            Color::from_srgb_hex(FPS_COLOR).expect("Invalid color"),
+                        )
+                        .expect("Could not update fps text");
                 }
-                if move_down_pressed {
-                    camera.move_down(move_speed);
-                }
-                if move_forward_pressed {
-                    camera.move_forward(move_speed);
-                }
-                if move_backward_pressed {
-                    camera.move_backward(move_speed);
-                }
-                if move_right_pressed {
-                    camera.move_right(move_speed);
-                }
-                if move_left_pressed {
-                    camera.move_left(move_speed);
+                if last_stats_log.elapsed() >= std::time::Duration::from_secs(1) {
+                    last_stats_log = std::time::Instant::now();
+                    info!("{}", renderer.frame_stats());
+                    let timings = renderer
+                        .frame_timings()
+                        .iter()
+                        .map(|timing| format!("{} {:.2} ms", timing.name, timing.gpu_ms))
+                        .collect::<Vec<_>>();
+                    if !timings.is_empty() {
+                        info!("GPU passes: {}", timings.join(", "));
+                    }
                 }
-                if turn_up_pressed {
-                    camera.turn_up(turn_speed);
+                renderer
+                    .update_text_input(&mut name_input)
+                    .expect("Could not draw the text input");
+                if let Some(token) = pending_screenshot {
+                    if let Some(screen) = renderer
+                        .poll_screenshot(token)
+                        .expect("Could not read the screenshot")
+                    {
+                        pending_screenshot = None;
+                        match screen.save("screenshot.jpg") {
+                            Ok(()) => info!("Screenshotted!"),
+                            Err(e) => error!("Could not save the screenshot: {}", e),
+                        }
+                    }
                 }
-                if turn_down_pressed {
-                    camera.turn_down(turn_speed);
+                camera_controller.update(&mut camera, frame_time);
+                let mut car_loaded = false;
+                if let Some(ticket) = car_ticket.as_mut() {
+                    let progress = renderer
+                        .advance_loading(ticket, std::time::Duration::from_millis(4))
+                        .expect("Could not load car scene");
+                    if ticket.is_finished() {
+                        if let Some(id) = progress_text.take() {
+                            renderer
+                                .remove_text(id)
+                                .expect("Could not remove progress text");
+                        }
+                        let result = ticket.take_result().expect("Car scene has no result");
+                        car_handle = result.objects.first().copied();
+                        car_loaded = true;
+                    } else {
+                        let filled = progress.done * 20 / progress.total.max(1);
+                        let text = format!(
+                            "Loading [{}{}] {}/{} {}",
+                            "#".repeat(filled),
+                            ".".repeat(20 - filled),
+                            progress.done,
+                            progress.total,
+                            progress.current_item
+                        );
+                        let styles = [&fontdue::layout::TextStyle::new(&text, 20.0, 0)];
+                        let color = Color::from_srgb_hex(PROGRESS_COLOR).expect("Invalid color");
+                        match progress_text {
+                            Some(id) => renderer
+                                .update_text(id, (10, 10), Anchor::BottomLeft, &styles, color)
+                                .expect("Could not update progress text"),
+                            None => {
+                                progress_text = Some(
+                                    renderer
+                                        .add_text((10, 10), Anchor::BottomLeft, &styles, color)
+                                        .expect("Could not add progress text"),
+                                )
+                            }
+                        }
+                    }
                 }
-                if turn_right_pressed {
-                    camera.turn_right(turn_speed);
+                if car_loaded {
+                    car_ticket = None;
+                    renderer
+                        .add_emitter(EmitterConfig {
+                            spawn_rate: 20.0,
+                            lifetime: 2.5,
+                            velocity: VelocityRange {
+                                min: glm::vec3(-0.3, -1.5, -0.3),
+                                max: glm::vec3(0.3, -0.8, 0.3),
+                            },
+                            size_over_lifetime: Curve::linear(0.5, 2.0),
+                            alpha_over_lifetime: Curve::linear(0.6, 0.0),
+                            color: Color::from_srgb_hex("#A0A0A0").expect("Invalid color"),
+                            seed: 7,
+                            attached_to: car_handle,
+                            offset: glm::vec3(0.0, -3.0, 0.0),
+                            ..EmitterConfig::new(smoke_texture)
+                        })
+                        .expect("Could not add smoke emitter");
                 }
-                if turn_left_pressed {
-                    camera.turn_left(turn_speed);
+                if let Some(car_handle) = car_handle.filter(|_| animate) {
+                    let mut resources = renderer.resources().expect("Could not lock the allocator");
+                    let obj_ref = resources
+                        .get_object_mut(car_handle)
+                        .expect("Could not get car obj mut ref");
+                    obj_ref.object.position = glm::Vec3::new(
+                        car_base_position.x,
+                        car_base_position.y,
+                        car_base_position.z
+                            + start_time
+                                .elapsed()
+                                .expect("Could not get elapsed time")
+                                .as_secs_f32()
+                                .sin()
+                                * 5.0f32,
+                    );
                 }
-                {
-                    if let Ok(mut allo) = renderer.allocator.lock() {
-                        let obj_ref = renderer
-                            .scene_tree
-                            .get_object_mut(car_handle, allo.deref_mut())
-                            .expect("Could not get car obj mut ref");
-                        obj_ref.object.position = glm::Vec3::new(
-                            car_base_position.x,
-                            car_base_position.y,
-                            car_base_position.z
-                                + start_time
-                                    .elapsed()
-                                    .expect("Could not get elapsed time")
-                                    .as_secs_f32()
-                                    .sin()
-                                    * 5.0f32,
+                if animate {
+                    renderer
+                        .animate_material_uv(conveyor_material, glm::Vec2::new(0.25, 0.0))
+                        .expect("Could not animate conveyor");
+                    let seconds = start_time
+                        .elapsed()
+                        .expect("Could not get elapsed time")
+                        .as_secs_f32();
+                    if let Some(material) = pulsing_material {
+                        renderer
+                            .set_material_parameter(
+                                material,
+                                "roughness",
+                                0.5 + 0.5 * seconds.sin(),
+                            )
+                            .expect("Could not animate the roughness");
+                    }
+                    if let Some(light) = renderer.lights.point_light_mut(orbiting_light) {
+                        light.position = na::Point3::new(
+                            car_base_position.x + 6.0 * seconds.cos(),
+                            car_base_position.y - 2.0,
+                            car_base_position.z + seconds.sin() * 5.0 + 6.0 * seconds.sin(),
                         );
                     }
+                    renderer
+                        .dispatch_compute(
+                            orbit_pass,
+                            [1, 1, 1],
+                            &[ComputeBufferBinding::whole(0, 0, &orbit_buffer)],
+                        )
+                        .expect("Could not move the orbiter");
+                    for (i, cube) in dissolve_cubes.iter().enumerate() {
+                        let params = DissolveParams {
+                            dissolve: 0.5 + 0.5 * (seconds + i as f32 * std::f32::consts::PI).sin(),
+                        };
+                        renderer
+                            .set_object_params(*cube, &params)
+                            .expect("Could not dissolve cube");
+                    }
+                    renderer
+                        .set_morph_weights(
+                            blob_object,
+                            &[
+                                ("bulge", (0.5 + 0.5 * (2.0 * seconds).sin()).powi(2)),
+                                ("squash", 0.5 + 0.5 * (1.3 * seconds).cos()),
+                            ],
+                        )
+                        .expect("Could not animate blob");
                 }
                 let result = renderer.render(&camera, &window, |_| {});
                 match result {
@@ -454,10 +1084,100 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                         source: ash::vk::Result::ERROR_OUT_OF_DATE_KHR,
                         ..
                     }) => {} // Resize request will update swapchain
-                    _ => result.expect("render error"),
+                    // Something panicked while holding the allocator, nothing can be drawn
+                    Err(RendererError::AllocatorUnavailable { .. }) => {
+                        error!("The allocator is unavailable, exiting");
+                        running = false;
+                        *controlflow = winit::event_loop::ControlFlow::Exit;
+                    }
+                    _ => {
+                        result.expect("render error");
+                    }
                 }
             }
             _ => {}
         }
     });
 }
+
+// Renders a lit sphere without a window into headless.png, see tests/headless.rs for
+// the checks of the frames
+fn render_headless() -> Result<(), Box<dyn std::error::Error>> {
+    let mut renderer = Renderer::new_headless(APP_NAME, 256, 256)?;
+    let sphere = renderer.resources()?.new_sphere_mesh(3)?;
+    let material = renderer.build_material(
+        "headless",
+        MaterialData {
+            textures: HashMap::new(),
+            buffers: vec![],
+            parameters: ShaderParameters::default(),
+            base_template: "default".to_string(),
+        },
+    )?;
+    renderer.resources()?.new_object(sphere, material)?;
+    renderer.lights.add_light(DirectionalLight {
+        direction: na::Unit::new_normalize(glm::Vec3::new(-1.0, 1.0, 1.0)),
+        illuminance: glm::Vec3::new(8.0, 8.0, 8.0),
+    });
+    let mut camera = Camera::builder()
+        .position(glm::Vec3::new(0.0, 0.0, -4.0))
+        .aspect(1.0)
+        .build();
+    camera.look_at(&glm::Vec3::zeros(), &glm::Vec3::new(0.0, 1.0, 0.0));
+
+    renderer.render_to_image(&camera)?.save("headless.png")?;
+    info!("Rendered headless.png");
+    Ok(())
+}
+
+// White panes with a black frame and cross bars, RGBA8
+fn window_frame_cookie(size: usize) -> Vec<u8> {
+    let bar = size / 16;
+    let mut data = Vec::with_capacity(size * size * 4);
+    for y in 0..size {
+        for x in 0..size {
+            let on_bar = |p: usize| p < bar || p >= size - bar || p.abs_diff(size / 2) < bar / 2;
+            let value = if on_bar(x) || on_bar(y) { 0 } else { 255 };
+            data.extend_from_slice(&[value, value, value, 255]);
+        }
+    }
+    data
+}
+
+// A white cross with a black outline and a gap in the middle, RGBA8
+fn crosshair_cursor(size: usize) -> Vec<u8> {
+    let center = size as i32 / 2;
+    let mut data = Vec::with_capacity(size * size * 4);
+    for y in 0..size as i32 {
+        for x in 0..size as i32 {
+            let (dx, dy) = ((x - center).abs(), (y - center).abs());
+            let arm = |along: i32, across: i32| (3..center - 1).contains(&along) && across <= 2;
+            let pixel = if arm(dx, dy) || arm(dy, dx) {
+                if dx <= 1 || dy <= 1 {
+                    [255, 255, 255, 255]
+                } else {
+                    [0, 0, 0, 255]
+                }
+            } else {
+                [0, 0, 0, 0]
+            };
+            data.extend_from_slice(&pixel);
+        }
+    }
+    data
+}
+
+// White, with an alpha that fades out towards the edge
+fn smoke_puff(size: usize) -> Vec<u8> {
+    let center = (size as f32 - 1.0) / 2.0;
+    let mut data = Vec::with_capacity(size * size * 4);
+    for y in 0..size {
+        for x in 0..size {
+            let (dx, dy) = (x as f32 - center, y as f32 - center);
+            let distance = (dx * dx + dy * dy).sqrt() / (size as f32 / 2.0);
+            let alpha = (1.0 - distance).clamp(0.0, 1.0).powi(2);
+            data.extend_from_slice(&[255, 255, 255, (alpha * 255.0) as u8]);
+        }
+    }
+    data
+}
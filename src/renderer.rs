@@ -1,7 +1,9 @@
-use std::ops::DerefMut;
-use std::path::Path;
+use std::collections::{BTreeSet, HashMap};
+use std::ops::{ControlFlow, Deref, DerefMut, Range};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use ash::vk;
 
@@ -11,62 +13,203 @@ use gpu_allocator::vulkan::{
 use gpu_allocator::MemoryLocation;
 use imgui::{Condition, Context, FontConfig, FontSource, Ui};
 use imgui_rs_vulkan_renderer::{Options, Renderer as ImguiRenderer};
+#[cfg(feature = "winit")]
 use imgui_winit_support::{HiDpiMode, WinitPlatform};
-use log::info;
+use log::{error, info, warn};
 use nalgebra_glm as glm;
 
+pub mod aov;
+mod async_compute;
+pub mod bloom;
 pub mod buffer;
 pub mod camera;
+pub mod capabilities;
+pub mod color;
+mod command_reuse;
+pub mod compute;
+pub mod config;
 mod context;
+pub mod cubemap;
+pub mod culling;
+pub mod cursor;
+mod debug_namer;
+#[cfg(feature = "defrag")]
+pub mod defrag;
 mod descriptor;
+pub mod draw_list;
 pub mod error;
+pub mod exposure;
+pub mod fog;
+pub mod gizmo;
+mod gpu_timer;
+pub mod instancing;
 pub mod light;
+mod lightmap;
+pub mod loading;
 pub mod material;
+pub mod material_file;
 pub mod mesh;
+pub mod morph;
+pub mod object_data;
+pub mod particles;
+mod pipeline_cache;
+mod preview;
+pub mod probe;
+pub mod profiler;
 mod queue;
+mod readback;
 mod render_target;
+pub mod resources;
 pub mod scene;
+pub mod scene_file;
+pub mod schedule;
+pub mod selection;
+pub mod settings;
 mod shaders;
+mod shadow;
+pub mod stats;
 mod swapchain;
 mod text;
+#[cfg(feature = "winit")]
+pub mod text_input;
 mod texture;
+mod timeline;
+pub mod turntable;
 pub mod utils;
+pub mod velocity;
 pub mod vertex;
+mod watcher;
 
-use buffer::Buffer;
-use camera::Camera;
+use aov::{AovImages, AovPass, AovRegion, AovTarget, AOV_BACKGROUND_ID};
+use async_compute::AsyncCompute;
+use bloom::{BloomConfig, BloomPass};
+use buffer::{lock_allocator, Buffer};
+use camera::{Camera, CameraUniformData};
+use capabilities::RendererCapabilities;
+use color::Color;
+use command_reuse::{RecordKey, RecordedFrames};
+use compute::{ComputeBufferBinding, ComputeDispatcher, ComputePass};
+use config::{
+    BackgroundBehavior, OpaqueSort, PresentModePreference, RedrawPolicy, RendererConfig,
+    ToneMapping, TransparencyTechnique, MIN_RENDER_SCALE,
+};
+use cubemap::{CubemapConversion, CubemapConverter, CUBEMAP_FACES};
+use culling::{CullingMode, CullingStats, Frustum};
+use cursor::{CursorConfig, CursorTracker, SoftwareCursor};
+#[cfg(feature = "defrag")]
+use defrag::{DefragCandidate, DefragReport, DefragState};
+use draw_list::{DrawKey, DrawSortStats, TransparentDraw};
+use exposure::{adapt_exposure, AutoExposureConfig, LuminancePass};
+use fog::{FogConfig, FogUniformData};
+use gizmo::{Gizmo, GizmoDrag, GizmoMode, GizmoResources, GizmoView, GIZMO_MAX_VERTICES};
+use gpu_timer::GpuFrameTimer;
+use instancing::{InstanceBatchBuffer, InstanceGroups};
+use lightmap::{LightmapBaker, LightmapPushConstants, LIGHTMAP_FORMAT};
+use morph::{
+    MorphPushConstants, MorphTarget, MORPH_PUSH_CONSTANT_OFFSET,
+    PREVIOUS_MORPH_PUSH_CONSTANT_OFFSET,
+};
+use object_data::{ObjectUploadStats, MAX_OBJECT_PARAMS_SIZE};
+use particles::{EmitterConfig, ParticleEmitter, ParticleInstance};
+use pipeline_cache::PipelineCache;
+use probe::{LightProbe, ProbeData};
+use profiler::{GpuProfiler, PassTiming};
+use resources::ResourceContext;
+use schedule::{
+    PassDescription, PassKind, PassQueue, RenderSchedule, ScheduleOptions, ScheduleResource,
+};
+use selection::{SelectionMode, SelectionRect};
+use settings::{plan_settings, ApplyReport, RendererSettings, SettingsOperation};
+use shadow::{ShadowMap, ShadowUniformData};
+use stats::{FrameEvents, FrameStats, FrameTimeHistory, RendererStats, StatsReader};
 use swapchain::Swapchain;
-use winit::event::{ElementState, Event, VirtualKeyCode, WindowEvent};
+#[cfg(feature = "winit")]
+use text_input::TextInput;
+use turntable::{TurntableConfig, TurntableLighting, TurntableSubject};
+use velocity::{PreviousFrame, VelocityImage, VelocityPass, VelocityTarget, VELOCITY_FORMAT};
+#[cfg(feature = "winit")]
+use winit::event::{DeviceEvent, ElementState, Event, VirtualKeyCode, WindowEvent};
+#[cfg(feature = "winit")]
 use winit::window::Window;
 
 use self::buffer::BufferManager;
 use self::context::VulkanContext;
-use self::descriptor::{DescriptorAllocator, DescriptorLayoutCache};
-use self::error::{InvalidHandle, RendererError};
-use self::light::LightManager;
-use self::material::{MaterialSystem, MeshPassType};
-use self::mesh::MeshManager;
-use self::scene::SceneTree;
-use self::shaders::ShaderCache;
-use self::text::TextHandler;
-use self::texture::{Texture, TextureStorage};
-use self::utils::{Handle, InternalWindow};
+use self::descriptor::{DescriptorAllocator, DescriptorBuilder, DescriptorLayoutCache};
+use self::error::{
+    AllocatorUnavailable, CubemapError, FrameError, IncompatibleMaterial, InvalidHandle,
+    MaterialFileError, MorphTargetError, ObjectParamsError, RendererError, SceneLoadError,
+    TextureInUse,
+};
+use self::light::{LightHandle, LightManager, LightStorage};
+use self::loading::{
+    LoadProgress, LoadStep, MeshSource, SceneDescription, SceneLoadResult, SceneLoadTicket,
+    SpawnedGltf, SpawnedObj,
+};
+use self::material::{
+    DefaultTextures, EffectTemplate, Material, MaterialData, MaterialSystem, MaterialTextureSlot,
+    MeshPassType, ParameterValue, ShaderParameters, TransparencyMode, OIT_COMPOSITE_TEMPLATE,
+};
+use self::material_file::MaterialFile;
+use self::mesh::loaders::gltf::load_gltf;
+use self::mesh::{Mesh, MeshManager};
+use self::preview::{PreviewScene, PREVIEW_BACKGROUND};
+use self::readback::PendingImageReadback;
+use self::render_target::{RenderTarget, OIT_ACCUMULATION_FORMAT, OIT_REVEALAGE_FORMAT};
+use self::scene::{ChunkEvent, InstanceData, SceneChunk, SceneObject, SceneTree, ALL_LAYERS};
+use self::scene_file::{SceneFile, SceneFileLight, SceneFileMaterial, SceneFileObject};
+use self::shaders::{
+    BindingContract, ShaderCache, OBJECT_PARAMS_BLOCK, OBJECT_PARAMS_SET, VERTEX_AND_FRAGMENT,
+};
+use self::text::{TextHandler, TextVertexData};
+use self::texture::{Texture, TextureCreateOptions, TextureStorage};
+use self::timeline::FrameTimeline;
+use self::utils::{fnv1a, fnv1a_start, Handle, HandleArray, InternalWindow};
+use self::watcher::AssetWatcher;
 
 pub use error::RendererResult;
+pub use shaders::{GlobalBinding, GLOBAL_DESCRIPTORS};
+pub use text::{Anchor, FontHandle};
+
+// Without winit there is no window the imgui UI could be drawn for, so the frames never
+// get one
+#[cfg(not(feature = "winit"))]
+enum Window {}
 
 const FRAMES_IN_FLIGHT: usize = 2;
 
+// Headless frames are waited for right after they are submitted, so one image is enough
+const HEADLESS_IMAGE_COUNT: u32 = 1;
+
+// Smaller changes of the view projection don't wake RedrawPolicy::OnDemand
+const REDRAW_CAMERA_EPSILON: f32 = 1e-5;
+
+// Camera matrices followed by the fog, shadow and tone mapping settings, see
+// UniformBufferObject in the shaders
+const GLOBAL_UNIFORM_SIZE: usize =
+    TONE_MAP_UNIFORM_OFFSET + std::mem::size_of::<ToneMapUniformData>();
+const SHADOW_UNIFORM_OFFSET: usize =
+    std::mem::size_of::<CameraUniformData>() + std::mem::size_of::<FogUniformData>();
+const TONE_MAP_UNIFORM_OFFSET: usize =
+    SHADOW_UNIFORM_OFFSET + std::mem::size_of::<ShadowUniformData>();
+
+// Visible transparent objects back to front, see Renderer::record_scene_objects
+type TransparentObjects = Vec<(DrawKey, Handle<SceneObject>)>;
+
+// x is 1 when the forward shaders tone map with x / (1 + x) themselves. Views that are
+// read back, like light probes and previews, have no upscale pass to do it.
+type ToneMapUniformData = [f32; 4];
+const SHADER_TONE_MAPPING: ToneMapUniformData = [1.0, 0.0, 0.0, 0.0];
+const PASS_TONE_MAPPING: ToneMapUniformData = [0.0; 4];
+
 struct FrameData {
     device: ash::Device,
     image_available_semaphore: vk::Semaphore,
     render_finished_semaphore: vk::Semaphore,
-    in_flight_fence: vk::Fence,
 }
 
 impl Drop for FrameData {
     fn drop(&mut self) {
         unsafe {
-            self.device.destroy_fence(self.in_flight_fence, None);
             self.device
                 .destroy_semaphore(self.render_finished_semaphore, None);
             self.device
@@ -75,59 +218,648 @@ impl Drop for FrameData {
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameStatus {
+    Rendered,
+    // The window is in the background and BackgroundBehavior::SkipRendering is set
+    Skipped,
+    // RedrawPolicy::OnDemand and nothing changed since the last frame, the last
+    // presented image is still on screen
+    Unchanged,
+    // The surface has no size yet (minimized, or a Wayland surface before its first
+    // configure), rendering resumes after recreate_swapchain with a non-zero size
+    NotReady,
+}
+
+// The frame a FrameRecorder records, ended by Renderer::end_frame. Dropping it instead
+// abandons the frame, its swapchain image stays acquired and is used by the next
+// begin_frame.
+#[derive(Debug)]
+struct FrameToken {
+    image_index: u32,
+    frame_number: u64,
+    abandoned: Arc<AtomicBool>,
+    ended: bool,
+}
+
+impl Drop for FrameToken {
+    fn drop(&mut self) {
+        if !self.ended {
+            self.abandoned.store(true, Ordering::Release);
+        }
+    }
+}
+
+// A frame between Renderer::begin_frame and FrameRecorder::end. It borrows the renderer
+// mutably, so nothing that must not happen in the middle of a frame compiles while it is
+// alive, e.g. destroying a texture, changing a mesh or recreating the swapchain. What is
+// safe mid-frame is available on the recorder itself, and shows up in the frame.
+// Dropping the recorder without ending it abandons the frame.
+#[must_use]
+pub struct FrameRecorder<'a> {
+    renderer: &'a mut Renderer,
+    token: FrameToken,
+}
+
+impl<'a> FrameRecorder<'a> {
+    pub fn image_index(&self) -> u32 {
+        self.token.image_index
+    }
+
+    pub fn frame_number(&self) -> u64 {
+        self.token.frame_number
+    }
+
+    pub fn renderer(&self) -> &Renderer {
+        self.renderer
+    }
+
+    pub fn set_fog(&mut self, fog: FogConfig) {
+        self.renderer.set_fog(fog);
+    }
+
+    pub fn set_cursor_grabbed(&mut self, grabbed: bool) {
+        self.renderer.set_cursor_grabbed(grabbed);
+    }
+
+    pub fn get_emitter_mut(
+        &mut self,
+        handle: Handle<ParticleEmitter>,
+    ) -> Option<&mut ParticleEmitter> {
+        self.renderer.get_emitter_mut(handle)
+    }
+
+    // Uploads everything the frame needs, records and submits it and presents the image
+    #[cfg(feature = "winit")]
+    pub fn end<F: FnOnce(&mut Ui)>(
+        self,
+        camera: &Camera,
+        window: &Window,
+        ui_func: F,
+    ) -> RendererResult<FrameStatus> {
+        self.finish(camera, Some(window), ui_func)
+    }
+
+    // Like end, without the imgui UI, for windows that don't come from winit
+    pub fn end_without_ui(self, camera: &Camera) -> RendererResult<FrameStatus> {
+        self.renderer.advance_frame_clock();
+        self.finish(camera, None, |_| {})
+    }
+
+    fn finish<F: FnOnce(&mut Ui)>(
+        self,
+        camera: &Camera,
+        window: Option<&Window>,
+        ui_func: F,
+    ) -> RendererResult<FrameStatus> {
+        let FrameRecorder { renderer, token } = self;
+        renderer.end_frame(token, camera, window, ui_func)
+    }
+}
+
+// The renderer side of the FrameToken of a FrameRecorder
+struct PendingFrame {
+    image_index: u32,
+    abandoned: Arc<AtomicBool>,
+}
+
+// A color target sampled by a fullscreen pass, the scene at the render scale for the
+// upscale or the composite for the output pass. Its color image is a texture, so the
+// material of the pass can use it like any other.
+struct OffscreenTarget {
+    texture: Handle<Texture>,
+    target: RenderTarget,
+    material: Handle<Material>,
+}
+
+// A cubemap the renderer owns and the material of the skybox template sampling it
+struct Skybox {
+    cubemap: Handle<Texture>,
+    material: Handle<Material>,
+}
+
+// What capture_frame reads back
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CaptureResolution {
+    // The swapchain image, with text and the UI. With an output pass the composite
+    // before encoding, as sRGB.
+    Output,
+    // Only the scene, at the render scale and before tone mapping
+    Internal,
+}
+
+// A screenshot that is still being copied, see Renderer::screenshot_async
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ScreenshotToken(u64);
+
 struct UiState {
     opened: bool,
     show_demo_window: bool,
+    show_light_probes: bool,
+}
+
+// Binds the user managed sets of a template before an object using it is drawn, the
+// pipeline and the renderer's sets are already bound
+pub type DescriptorHook =
+    Box<dyn FnMut(&ash::Device, vk::CommandBuffer, vk::PipelineLayout, Handle<SceneObject>) + Send>;
+
+// Records draws over the text, inside the render pass of overlay_render_pass. Gets the
+// extent of the swapchain, the viewport and scissor are left as the text set them.
+pub type OverlayRecorder = Box<dyn FnMut(&ash::Device, vk::CommandBuffer, vk::Extent2D) + Send>;
+
+// Told about every chunk change, e.g. to stream gameplay data in and out with the chunk
+pub type ChunkCallback = Box<dyn FnMut(Handle<SceneChunk>, ChunkEvent) + Send>;
+
+// Wraps imgui::Context to make it Send, see below
+struct ImguiContext(Context);
+
+// SAFETY: imgui::Context holds raw pointers into the Dear ImGui state, which is all that
+// keeps it from being Send. That state isn't tied to the thread that created it:
+// - Dear ImGui finds the context through one process-wide pointer, not thread-local
+//   storage. imgui-rs sets it when the context is created and clears it when it is dropped
+// - creating a second context while this one is alive panics, so no other thread can
+//   make another context current in the meantime
+// - the context is only reached through &mut Renderer, so it is never used from two
+//   threads at once
+// Moving it to another thread with the renderer can't leave anything dangling or shared.
+unsafe impl Send for ImguiContext {}
+
+impl Deref for ImguiContext {
+    type Target = Context;
+
+    fn deref(&self) -> &Context {
+        &self.0
+    }
+}
+
+impl DerefMut for ImguiContext {
+    fn deref_mut(&mut self) -> &mut Context {
+        &mut self.0
+    }
 }
 
+// The renderer is Send, it can be moved to a render thread that calls render or
+// begin_frame while the thread of the event loop forwards the window events to it, e.g.
+// through a channel to handle_event. The window is shared with an Arc, winit windows are
+// Send and Sync. The renderer isn't Sync, it is used from one thread at a time.
+// Methods taking &mut self may wait for the GPU, free resources or rewrite descriptor
+// sets, so none of them can be called while a FrameRecorder is alive. Methods taking
+// &self only read. Other threads get stats through stats_reader and poll
+// SceneLoadTicket::progress.
 pub struct Renderer {
     dropped: bool,
     // This has to be first, so that it is dropped first
     // TODO do this better?
     imgui_renderer: ImguiRenderer,
+    #[cfg(feature = "winit")]
     platform: WinitPlatform,
-    imgui: Context,
+    imgui: ImguiContext,
     ui_state: UiState,
     pub allocator: Arc<Mutex<Allocator>>,
     pub context: VulkanContext,
     pub buffer_manager: Arc<Mutex<BufferManager>>,
     swapchain: Swapchain,
+    schedule: RenderSchedule,
     render_pass: vk::RenderPass,
+    // Single sampled, the text, gizmo, imgui UI and software cursor are drawn in it over
+    // what render_pass left
+    overlay_render_pass: vk::RenderPass,
+    shadow_map: ShadowMap,
+    // Of the frame being recorded, None without a directional light
+    shadow_view_projection: Option<glm::Mat4>,
+    // Renders the scene into scaled_target in HDR_WORKING_FORMAT, which the upscale pass
+    // tone maps
+    scene_render_pass: vk::RenderPass,
+    scaled_target: Option<OffscreenTarget>,
+    // Only for swapchains that need an output pass, which encodes composite_target into
+    // the swapchain image
+    output_render_pass: Option<vk::RenderPass>,
+    composite_target: Option<OffscreenTarget>,
+    capabilities: RendererCapabilities,
+    luminance_pass: Option<LuminancePass>,
+    bloom_pass: Option<BloomPass>,
+    // The levels the bloom pass blurs into, which the upscale material samples as its
+    // emissive texture. Only resized while bloom is on.
+    bloom_chain: Option<Handle<Texture>>,
+    // Only with RendererConfig::async_compute on a device with a compute queue
+    async_compute: Option<AsyncCompute>,
+    aov_pass: Option<AovPass>,
+    // Only with RendererConfig::velocity_target, the target follows the scaled target
+    velocity_pass: Option<VelocityPass>,
+    velocity_target: Option<VelocityTarget>,
+    // Passes registered with register_compute_pass and what dispatch_compute queued
+    compute: ComputeDispatcher,
+    // Measured a few frames ago, None until the first result arrives
+    scene_luminance: Option<f32>,
+    exposure: f32,
+    // The exposure still moved noticeably in the last frame
+    exposure_adapting: bool,
     shader_cache: ShaderCache,
     pub scene_tree: SceneTree,
+    // The set of the object parameters, with the buffer it was written for
+    object_params_set: Option<(vk::Buffer, vk::DescriptorSet)>,
     pub descriptor_layout_cache: DescriptorLayoutCache,
     pub descriptor_allocator: DescriptorAllocator,
     pub material_system: MaterialSystem,
     graphics_command_pool: vk::CommandPool,
     command_buffers: Vec<vk::CommandBuffer>,
+    // Which of command_buffers can be submitted again without recording them
+    recorded_frames: RecordedFrames,
+    // The overlay render pass and what follows it, recorded every frame, per image
+    overlay_command_buffers: Vec<vk::CommandBuffer>,
     frame_data: Vec<FrameData>,
-    images_in_flight: Vec<vk::Fence>,
+    timeline: FrameTimeline,
     current_image: usize,
     uniform_buffer: Buffer,
+    global_uniform_stride: usize,
+    fog: FogConfig,
+    // Behind the skybox, and behind nothing when fog covers the background
+    clear_color: Color,
+    skybox: Option<Skybox>,
+    view_projection_history: PreviousFrame<glm::Mat4>,
     descriptor_set_camera: vk::DescriptorSet,
+    global_set_hashes: [u64; 2],
     descriptor_set_lights: vk::DescriptorSet,
-    light_buffer: Buffer,
+    light_storage: LightStorage,
+    // The morph target deltas of every mesh, see morph::pack_deltas
+    morph_deltas: Buffer,
+    // A copy of the probes of the last light upload, for the debug view
+    light_probes: Vec<LightProbe>,
+    cookie_sampler: vk::Sampler,
+    fallback_texture: Handle<Texture>,
     pub texture_storage: TextureStorage,
     pub text: TextHandler,
+    // Changes are uploaded with the next frame
+    pub lights: LightManager,
     pub meshs: MeshManager,
     pub material_uniform_buffers: Vec<Buffer>,
     last_frame: Instant,
+    frame_delta: Duration,
+    config: RendererConfig,
+    focused: bool,
+    occluded: bool,
+    last_render: Instant,
+    surface_ready: bool,
+    // The swapchain is recreated with the new present mode by the next begin_frame
+    present_mode_changed: bool,
+    preview_scene: Option<PreviewScene>,
+    frustum: Frustum,
+    // The layer mask and position of the camera of the frame being recorded
+    view_layer_mask: u32,
+    view_position: glm::Vec3,
+    culling_stats: CullingStats,
+    // Objects left out when the instance groups were last rebuilt, see skipped_draws
+    skipped_draws: Vec<(Handle<SceneObject>, String)>,
+    draw_sort_stats: DrawSortStats,
+    object_upload_stats: ObjectUploadStats,
+    frame_times: FrameTimeHistory,
+    gpu_frame_times: FrameTimeHistory,
+    // None when the graphics queue has no timestamps
+    gpu_timer: Option<GpuFrameTimer>,
+    // None when the graphics queue has no timestamps, like gpu_timer
+    profiler: Option<GpuProfiler>,
+    // Of the last frame the GPU finished
+    pass_timings: Vec<PassTiming>,
+    // Counted while the current frame is rendered, and a copy of the last finished one
+    frame_stats: FrameStats,
+    last_frame_stats: FrameStats,
+    frame_number: u64,
+    frame_events: FrameEvents,
+    // See RedrawPolicy::OnDemand
+    redraw_requested: bool,
+    // Bumped by every request_redraw, the recorded frames are only reused without one
+    redraw_generation: u64,
+    last_presented_view: Option<glm::Mat4>,
+    stats_reader: StatsReader,
+    pending_frame: Option<PendingFrame>,
+    // Files of loaded textures, reloaded at the start of a frame when they change
+    asset_watcher: AssetWatcher,
+    descriptor_hooks: HashMap<Handle<EffectTemplate>, DescriptorHook>,
+    // See screenshot_async
+    pending_screenshots: HashMap<ScreenshotToken, PendingImageReadback>,
+    next_screenshot: u64,
+    chunk_callback: Option<ChunkCallback>,
+    overlay_recorder: Option<OverlayRecorder>,
+    cursor_tracker: CursorTracker,
+    software_cursor: Option<SoftwareCursor>,
+    // Six vertices per swapchain image
+    cursor_vertex_buffer: Option<Buffer>,
+    cubemap_converter: Option<CubemapConverter>,
+    lightmap_baker: Option<LightmapBaker>,
+    gizmo: Option<Gizmo>,
+    gizmo_resources: Option<GizmoResources>,
+    emitters: HandleArray<ParticleEmitter>,
+    // Farthest from the camera first, updated every frame
+    emitter_draw_order: Vec<(DrawKey, Handle<ParticleEmitter>)>,
+    // Per swapchain image, see particle_depth_set
+    particle_depth_sets: Vec<vk::DescriptorSet>,
+    // Per swapchain image, see oit_composite_set. Empty without weighted blended
+    // transparency.
+    oit_composite_sets: Vec<vk::DescriptorSet>,
+    // Written before every probe view and preview, which are waited for
+    oit_offscreen_composite_set: Option<vk::DescriptorSet>,
+    instance_batches: InstanceBatchBuffer,
+    instance_groups: InstanceGroups,
+    #[cfg(feature = "defrag")]
+    defrag: DefragState,
 }
 
+// Fails to compile when a field stops being Send
+const _: fn() = || {
+    fn assert_send<T: Send>() {}
+    assert_send::<Renderer>();
+};
+
 impl Renderer {
+    // Turns the color and depth attachments of a render pass into multisampled ones. The
+    // samples are resolved into a third attachment that takes over the color's image and
+    // final layout, only the resolved color is stored.
+    fn multisample_attachments(
+        attachments: &mut Vec<vk::AttachmentDescription>,
+        samples: vk::SampleCountFlags,
+    ) {
+        if samples == vk::SampleCountFlags::TYPE_1 {
+            return;
+        }
+        let mut resolve = attachments[0];
+        resolve.load_op = vk::AttachmentLoadOp::DONT_CARE;
+        attachments[0].samples = samples;
+        attachments[0].store_op = vk::AttachmentStoreOp::DONT_CARE;
+        attachments[0].final_layout = vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL;
+        attachments[1].samples = samples;
+        attachments.push(resolve);
+    }
+
+    // The subpasses after the forward one blend over its color and read its depth
+    fn forward_subpass_dependency(dst_subpass: u32) -> vk::SubpassDependency {
+        vk::SubpassDependency::builder()
+            .src_subpass(0)
+            .src_stage_mask(
+                vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT
+                    | vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS
+                    | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
+            )
+            .src_access_mask(
+                vk::AccessFlags::COLOR_ATTACHMENT_WRITE
+                    | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+            )
+            .dst_subpass(dst_subpass)
+            .dst_stage_mask(
+                vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT
+                    | vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS
+                    | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS
+                    | vk::PipelineStageFlags::FRAGMENT_SHADER,
+            )
+            .dst_access_mask(
+                vk::AccessFlags::COLOR_ATTACHMENT_READ
+                    | vk::AccessFlags::COLOR_ATTACHMENT_WRITE
+                    | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ
+                    | vk::AccessFlags::INPUT_ATTACHMENT_READ,
+            )
+            .dependency_flags(vk::DependencyFlags::BY_REGION)
+            .build()
+    }
+
+    // The subpasses of the main and scene render passes, which have to be compatible. The
+    // forward pass draws in the first subpass and the transparency pass in the second,
+    // where the depth is read only and can be read by the shaders. Weighted blended
+    // transparency accumulates into two more attachments in the second subpass instead,
+    // which a third one composites over the color. The particles are drawn in the last
+    // subpass, which also resolves the samples. The layouts are the ones of the color and
+    // depth in the forward and transparency passes.
+    fn create_scene_subpasses(
+        device: &ash::Device,
+        mut attachments: Vec<vk::AttachmentDescription>,
+        forward_layouts: [vk::ImageLayout; 2],
+        transparency_layouts: [vk::ImageLayout; 2],
+        transparency: TransparencyTechnique,
+        mut subpass_dependencies: Vec<vk::SubpassDependency>,
+    ) -> RendererResult<vk::RenderPass> {
+        let [forward_color_layout, forward_depth_layout] = forward_layouts;
+        let [color_layout, depth_layout] = transparency_layouts;
+        let multisampled = attachments.len() > 2;
+        let forward_color_references = [vk::AttachmentReference {
+            attachment: 0,
+            layout: forward_color_layout,
+        }];
+        let forward_depth_reference = vk::AttachmentReference {
+            attachment: 1,
+            layout: forward_depth_layout,
+        };
+        let color_references = [vk::AttachmentReference {
+            attachment: 0,
+            layout: color_layout,
+        }];
+        let resolve_attachment_references = [vk::AttachmentReference {
+            attachment: 2,
+            layout: color_layout,
+        }];
+        let depth_references = [vk::AttachmentReference {
+            attachment: 1,
+            layout: depth_layout,
+        }];
+
+        // After the color, depth and resolve attachments, see RenderTarget::new_from_image
+        let accumulation = attachments.len() as u32;
+        let revealage = accumulation + 1;
+        let oit_color_references = [
+            vk::AttachmentReference {
+                attachment: accumulation,
+                layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+            },
+            vk::AttachmentReference {
+                attachment: revealage,
+                layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+            },
+        ];
+        // The depth stays the first input attachment, for the particles
+        let composite_input_references = [
+            depth_references[0],
+            vk::AttachmentReference {
+                attachment: accumulation,
+                layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            },
+            vk::AttachmentReference {
+                attachment: revealage,
+                layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            },
+        ];
+        let preserved_color = [0];
+
+        let mut subpasses = vec![vk::SubpassDescription::builder()
+            .color_attachments(&forward_color_references)
+            .depth_stencil_attachment(&forward_depth_reference)
+            .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+            .build()];
+        subpass_dependencies.push(Self::forward_subpass_dependency(1));
+        match transparency {
+            TransparencyTechnique::SortedAlpha => {
+                subpasses.push(
+                    vk::SubpassDescription::builder()
+                        .color_attachments(&color_references)
+                        .depth_stencil_attachment(&depth_references[0])
+                        .input_attachments(&depth_references)
+                        .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+                        .build(),
+                );
+            }
+            TransparencyTechnique::WeightedBlended => {
+                let samples = attachments[0].samples;
+                for format in [OIT_ACCUMULATION_FORMAT, OIT_REVEALAGE_FORMAT] {
+                    attachments.push(
+                        vk::AttachmentDescription::builder()
+                            .format(format)
+                            .load_op(vk::AttachmentLoadOp::CLEAR)
+                            .store_op(vk::AttachmentStoreOp::DONT_CARE)
+                            .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+                            .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+                            .initial_layout(vk::ImageLayout::UNDEFINED)
+                            .final_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+                            .samples(samples)
+                            .build(),
+                    );
+                }
+                subpasses.push(
+                    vk::SubpassDescription::builder()
+                        .color_attachments(&oit_color_references)
+                        .depth_stencil_attachment(&depth_references[0])
+                        .preserve_attachments(&preserved_color)
+                        .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+                        .build(),
+                );
+                subpasses.push(
+                    vk::SubpassDescription::builder()
+                        .color_attachments(&color_references)
+                        .depth_stencil_attachment(&depth_references[0])
+                        .input_attachments(&composite_input_references)
+                        .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+                        .build(),
+                );
+                subpass_dependencies.push(Self::forward_subpass_dependency(2));
+                // The composite reads what the transparent objects accumulated
+                subpass_dependencies.push(
+                    vk::SubpassDependency::builder()
+                        .src_subpass(1)
+                        .src_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
+                        .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+                        .dst_subpass(2)
+                        .dst_stage_mask(vk::PipelineStageFlags::FRAGMENT_SHADER)
+                        .dst_access_mask(vk::AccessFlags::INPUT_ATTACHMENT_READ)
+                        .dependency_flags(vk::DependencyFlags::BY_REGION)
+                        .build(),
+                );
+            }
+        }
+        if multisampled {
+            if let Some(last) = subpasses.last_mut() {
+                last.p_resolve_attachments = resolve_attachment_references.as_ptr();
+            }
+        }
+
+        let renderpass_info = vk::RenderPassCreateInfo::builder()
+            .attachments(&attachments)
+            .subpasses(&subpasses)
+            .dependencies(&subpass_dependencies);
+        unsafe { Ok(device.create_render_pass(&renderpass_info, None)?) }
+    }
+
+    // The attachment layouts come from the schedule. With output encoding the color
+    // attachment is the composite target instead of the swapchain image. The scene passes
+    // draw in the subpasses of create_scene_subpasses. The color is left for the overlay
+    // render pass.
     fn create_render_pass(
         device: &ash::Device,
-        format: &vk::SurfaceFormatKHR,
+        format: vk::Format,
+        samples: vk::SampleCountFlags,
+        transparency: TransparencyTechnique,
+        schedule: &RenderSchedule,
+    ) -> RendererResult<vk::RenderPass> {
+        let output_encode = schedule.options().output_encode;
+        let color = if output_encode {
+            ScheduleResource::CompositeColor
+        } else {
+            ScheduleResource::SwapchainColor
+        };
+        let color_final_layout = vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL;
+        let mut attachments = vec![
+            vk::AttachmentDescription::builder()
+                .format(format)
+                .load_op(vk::AttachmentLoadOp::CLEAR)
+                .store_op(vk::AttachmentStoreOp::STORE)
+                .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+                .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+                .initial_layout(vk::ImageLayout::UNDEFINED)
+                .final_layout(color_final_layout)
+                .samples(vk::SampleCountFlags::TYPE_1)
+                .build(),
+            vk::AttachmentDescription::builder()
+                .format(vk::Format::D32_SFLOAT)
+                .load_op(vk::AttachmentLoadOp::CLEAR)
+                .store_op(vk::AttachmentStoreOp::DONT_CARE)
+                .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+                .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+                .initial_layout(vk::ImageLayout::UNDEFINED)
+                .final_layout(schedule.final_layout(ScheduleResource::Depth))
+                .samples(vk::SampleCountFlags::TYPE_1)
+                .build(),
+        ];
+        Self::multisample_attachments(&mut attachments, samples);
+
+        let mut subpass_dependencies = vec![vk::SubpassDependency::builder()
+            .src_subpass(vk::SUBPASS_EXTERNAL)
+            .src_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
+            .dst_subpass(0)
+            .dst_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
+            .dst_access_mask(
+                vk::AccessFlags::COLOR_ATTACHMENT_READ | vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
+            )
+            .build()];
+        if output_encode {
+            // The composite target and its depth are shared by the frames in flight, so
+            // the previous frame's output pass and depth writes have to be done first
+            subpass_dependencies[0].src_stage_mask |= vk::PipelineStageFlags::FRAGMENT_SHADER
+                | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS;
+            subpass_dependencies[0].dst_stage_mask |= vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS;
+            subpass_dependencies[0].src_access_mask |=
+                vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE;
+            subpass_dependencies[0].dst_access_mask |=
+                vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ
+                    | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE;
+        }
+
+        Self::create_scene_subpasses(
+            device,
+            attachments,
+            [
+                schedule.layout_in(PassKind::Forward, color),
+                schedule.layout_in(PassKind::Forward, ScheduleResource::Depth),
+            ],
+            [
+                schedule.layout_in(PassKind::Transparency, color),
+                schedule.layout_in(PassKind::Transparency, ScheduleResource::Depth),
+            ],
+            transparency,
+            subpass_dependencies,
+        )
+    }
+
+    // The scene templates are built for it, the color is left ready to be sampled by the
+    // upscale pass. It has the subpasses of the main render pass.
+    fn create_scene_render_pass(
+        device: &ash::Device,
+        format: vk::Format,
+        samples: vk::SampleCountFlags,
+        transparency: TransparencyTechnique,
     ) -> RendererResult<vk::RenderPass> {
-        let attachments = [
+        let mut attachments = vec![
             vk::AttachmentDescription::builder()
-                .format(format.format)
+                .format(format)
                 .load_op(vk::AttachmentLoadOp::CLEAR)
                 .store_op(vk::AttachmentStoreOp::STORE)
                 .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
                 .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
                 .initial_layout(vk::ImageLayout::UNDEFINED)
-                .final_layout(vk::ImageLayout::PRESENT_SRC_KHR)
+                .final_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
                 .samples(vk::SampleCountFlags::TYPE_1)
                 .build(),
             vk::AttachmentDescription::builder()
@@ -137,36 +869,164 @@ impl Renderer {
                 .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
                 .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
                 .initial_layout(vk::ImageLayout::UNDEFINED)
-                .final_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
+                .final_layout(vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL)
                 .samples(vk::SampleCountFlags::TYPE_1)
                 .build(),
         ];
+        Self::multisample_attachments(&mut attachments, samples);
+
+        let subpass_dependencies = vec![
+            // The previous frame's upscale, luminance and bloom passes have to be done
+            // reading the color
+            vk::SubpassDependency::builder()
+                .src_subpass(vk::SUBPASS_EXTERNAL)
+                .src_stage_mask(
+                    vk::PipelineStageFlags::FRAGMENT_SHADER
+                        | vk::PipelineStageFlags::COMPUTE_SHADER,
+                )
+                .src_access_mask(vk::AccessFlags::SHADER_READ)
+                .dst_subpass(0)
+                .dst_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
+                .dst_access_mask(
+                    vk::AccessFlags::COLOR_ATTACHMENT_READ
+                        | vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
+                )
+                .build(),
+            vk::SubpassDependency::builder()
+                .src_subpass(transparency.scene_subpasses() - 1)
+                .src_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
+                .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+                .dst_subpass(vk::SUBPASS_EXTERNAL)
+                .dst_stage_mask(
+                    vk::PipelineStageFlags::FRAGMENT_SHADER
+                        | vk::PipelineStageFlags::COMPUTE_SHADER,
+                )
+                .dst_access_mask(vk::AccessFlags::SHADER_READ)
+                .build(),
+        ];
+
+        Self::create_scene_subpasses(
+            device,
+            attachments,
+            [
+                vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+                vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+            ],
+            [
+                vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+                vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL,
+            ],
+            transparency,
+            subpass_dependencies,
+        )
+    }
+
+    // Only the swapchain image, which the output pass covers completely
+    fn create_output_render_pass(
+        device: &ash::Device,
+        format: vk::Format,
+        schedule: &RenderSchedule,
+    ) -> RendererResult<vk::RenderPass> {
+        let attachments = [vk::AttachmentDescription::builder()
+            .format(format)
+            .load_op(vk::AttachmentLoadOp::DONT_CARE)
+            .store_op(vk::AttachmentStoreOp::STORE)
+            .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+            .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+            .initial_layout(vk::ImageLayout::UNDEFINED)
+            .final_layout(schedule.final_layout(ScheduleResource::SwapchainColor))
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .build()];
 
         let color_attachment_references = [vk::AttachmentReference {
             attachment: 0,
-            layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+            layout: schedule.subpass_layout(ScheduleResource::SwapchainColor),
         }];
 
-        let depth_attachment_reference = vk::AttachmentReference {
-            attachment: 1,
-            layout: vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+        let subpasses = [vk::SubpassDescription::builder()
+            .color_attachments(&color_attachment_references)
+            .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+            .build()];
+
+        let subpass_dependencies = [vk::SubpassDependency::builder()
+            .src_subpass(vk::SUBPASS_EXTERNAL)
+            .src_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
+            .dst_subpass(0)
+            .dst_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
+            .dst_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+            .build()];
+
+        let renderpass_info = vk::RenderPassCreateInfo::builder()
+            .attachments(&attachments)
+            .subpasses(&subpasses)
+            .dependencies(&subpass_dependencies);
+        unsafe { Ok(device.create_render_pass(&renderpass_info, None)?) }
+    }
+
+    // Single sampled with only the color the main render pass leaves, which it loads.
+    // The overlay passes of the schedule are drawn in it, see PassKind::is_overlay.
+    fn create_overlay_render_pass(
+        device: &ash::Device,
+        format: vk::Format,
+        schedule: &RenderSchedule,
+    ) -> RendererResult<vk::RenderPass> {
+        let output_encode = schedule.options().output_encode;
+        let (color, color_final_layout) = if output_encode {
+            // Sampled by the output pass
+            (
+                ScheduleResource::CompositeColor,
+                vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            )
+        } else {
+            (
+                ScheduleResource::SwapchainColor,
+                schedule.final_layout(ScheduleResource::SwapchainColor),
+            )
         };
+        let attachments = [vk::AttachmentDescription::builder()
+            .format(format)
+            .load_op(vk::AttachmentLoadOp::LOAD)
+            .store_op(vk::AttachmentStoreOp::STORE)
+            .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+            .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+            .initial_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+            .final_layout(color_final_layout)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .build()];
+
+        let color_attachment_references = [vk::AttachmentReference {
+            attachment: 0,
+            layout: schedule.subpass_layout(color),
+        }];
 
         let subpasses = [vk::SubpassDescription::builder()
             .color_attachments(&color_attachment_references)
-            .depth_stencil_attachment(&depth_attachment_reference)
             .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
             .build()];
 
-        let subpass_dependencies = [vk::SubpassDependency::builder()
+        // After the resolve of the main render pass
+        let mut subpass_dependencies = vec![vk::SubpassDependency::builder()
             .src_subpass(vk::SUBPASS_EXTERNAL)
             .src_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
+            .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
             .dst_subpass(0)
             .dst_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
             .dst_access_mask(
                 vk::AccessFlags::COLOR_ATTACHMENT_READ | vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
             )
             .build()];
+        if output_encode {
+            subpass_dependencies.push(
+                vk::SubpassDependency::builder()
+                    .src_subpass(0)
+                    .src_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
+                    .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+                    .dst_subpass(vk::SUBPASS_EXTERNAL)
+                    .dst_stage_mask(vk::PipelineStageFlags::FRAGMENT_SHADER)
+                    .dst_access_mask(vk::AccessFlags::SHADER_READ)
+                    .build(),
+            );
+        }
 
         let renderpass_info = vk::RenderPassCreateInfo::builder()
             .attachments(&attachments)
@@ -177,24 +1037,22 @@ impl Renderer {
 
     fn create_frame_data(device: &ash::Device, num: usize) -> RendererResult<Vec<FrameData>> {
         let semaphore_info = vk::SemaphoreCreateInfo::builder();
-        let fence_info = vk::FenceCreateInfo::builder().flags(vk::FenceCreateFlags::SIGNALED);
         (0..num)
             .map(|_| {
                 let image_available_semaphore =
                     unsafe { device.create_semaphore(&semaphore_info, None)? };
                 let render_finished_semaphore =
                     unsafe { device.create_semaphore(&semaphore_info, None)? };
-                let in_flight_fence = unsafe { device.create_fence(&fence_info, None)? };
                 Ok(FrameData {
                     device: device.clone(),
                     image_available_semaphore,
                     render_finished_semaphore,
-                    in_flight_fence,
                 })
             })
             .collect()
     }
 
+    #[cfg(feature = "winit")]
     pub fn new(
         name: &str,
         window: &Window,
@@ -202,7 +1060,81 @@ impl Renderer {
         window_height: u32,
         internal_window: InternalWindow,
     ) -> RendererResult<Self> {
-        let context = VulkanContext::new(name, internal_window)?;
+        Self::new_with_config(
+            name,
+            window,
+            window_width,
+            window_height,
+            internal_window,
+            RendererConfig::default(),
+        )
+    }
+
+    #[cfg(feature = "winit")]
+    pub fn new_with_config(
+        name: &str,
+        window: &Window,
+        window_width: u32,
+        window_height: u32,
+        internal_window: InternalWindow,
+        config: RendererConfig,
+    ) -> RendererResult<Self> {
+        let mut renderer = Self::create(
+            name,
+            Some(internal_window),
+            window_width,
+            window_height,
+            config,
+        )?;
+        renderer
+            .platform
+            .attach_window(renderer.imgui.io_mut(), window, HiDpiMode::Rounded);
+        Ok(renderer)
+    }
+
+    // Renders into a window of any windowing library, e.g. one of SDL2 through
+    // InternalWindow::from_raw_handles. There is no imgui UI, the frames are rendered with
+    // render_without_ui or FrameRecorder::end_without_ui. The window has to outlive the
+    // renderer.
+    pub fn new_with_internal_window(
+        name: &str,
+        window_width: u32,
+        window_height: u32,
+        internal_window: InternalWindow,
+        config: RendererConfig,
+    ) -> RendererResult<Self> {
+        Self::create(
+            name,
+            Some(internal_window),
+            window_width,
+            window_height,
+            config,
+        )
+    }
+
+    // Renders into offscreen targets instead of a window, see render_to_image. There is
+    // no UI and nothing is presented.
+    pub fn new_headless(name: &str, width: u32, height: u32) -> RendererResult<Self> {
+        Self::new_headless_with_config(name, width, height, RendererConfig::default())
+    }
+
+    pub fn new_headless_with_config(
+        name: &str,
+        width: u32,
+        height: u32,
+        config: RendererConfig,
+    ) -> RendererResult<Self> {
+        Self::create(name, None, width, height, config)
+    }
+
+    fn create(
+        name: &str,
+        window: Option<InternalWindow>,
+        window_width: u32,
+        window_height: u32,
+        config: RendererConfig,
+    ) -> RendererResult<Self> {
+        let context = VulkanContext::new(name, window, &config.device_selection)?;
 
         // Allocator
         let mut allocator = Allocator::new(&AllocatorCreateDesc {
@@ -219,25 +1151,123 @@ impl Renderer {
             },
             buffer_device_address: false,
         })?;
-        let format = context
-            .surface_formats
-            .iter()
-            .find(|format| {
-                format.format == vk::Format::B8G8R8A8_SRGB
-                    && format.color_space == vk::ColorSpaceKHR::SRGB_NONLINEAR
-            })
-            .ok_or(vk::Result::ERROR_FORMAT_NOT_SUPPORTED)?;
-
-        let render_pass = Self::create_render_pass(&context.device, format)?;
+        let surface_formats = match window {
+            Some(_) => context.surface_formats.clone(),
+            None => vec![swapchain::HEADLESS_FORMAT],
+        };
+        let capabilities = RendererCapabilities::new(
+            surface_formats,
+            context.swapchain_colorspace,
+            &context.physical_device_properties.limits,
+            context.independent_blend,
+            &config,
+        )
+        .ok_or(vk::Result::ERROR_FORMAT_NOT_SUPPORTED)?;
+        if capabilities.color_space != config.preferred_color_space {
+            info!(
+                "{:?} is not supported by the surface, using {:?}",
+                config.preferred_color_space, capabilities.color_space
+            );
+        }
+        info!(
+            "Swapchain format {:?} in {:?}, working format {:?}",
+            capabilities.surface_format.format,
+            capabilities.surface_format.color_space,
+            capabilities.working_format
+        );
+        if capabilities.msaa_samples.as_raw() != config.msaa_samples.max(1) {
+            info!(
+                "{} samples per pixel are not supported, using {}",
+                config.msaa_samples,
+                capabilities.msaa_samples.as_raw()
+            );
+        }
+        if capabilities.transparency != config.transparency_mode {
+            info!(
+                "{:?} transparency is not supported, using {:?}",
+                config.transparency_mode, capabilities.transparency
+            );
+        }
+        let format = &capabilities.surface_format;
+        let output_encode = capabilities.output_encoding.is_some();
 
-        let swapchain = Swapchain::new(
-            &context,
-            &mut allocator,
-            *format,
-            window_width,
-            window_height,
-            &render_pass,
+        let schedule = RenderSchedule::new(ScheduleOptions {
+            output_encode,
+            ..Default::default()
+        })?;
+        let render_pass = Self::create_render_pass(
+            &context.device,
+            capabilities.working_format,
+            capabilities.msaa_samples,
+            capabilities.transparency,
+            &schedule,
+        )?;
+        let overlay_render_pass = Self::create_overlay_render_pass(
+            &context.device,
+            capabilities.working_format,
+            &schedule,
+        )?;
+        let scene_render_pass = Self::create_scene_render_pass(
+            &context.device,
+            capabilities::HDR_WORKING_FORMAT,
+            capabilities.msaa_samples,
+            capabilities.transparency,
         )?;
+        let output_render_pass = if output_encode {
+            Some(Self::create_output_render_pass(
+                &context.device,
+                format.format,
+                &schedule,
+            )?)
+        } else {
+            None
+        };
+        let debug_namer = &context.debug_namer;
+        debug_namer.name(render_pass, "main");
+        debug_namer.name(overlay_render_pass, "overlay");
+        debug_namer.name(scene_render_pass, "scaled scene");
+        if let Some(output_render_pass) = output_render_pass {
+            debug_namer.name(output_render_pass, "output");
+        }
+
+        // Vulkan can't create a swapchain without a size, so a surface that has none yet
+        // gets a placeholder until the first resize
+        let surface_ready = window.is_none()
+            || !swapchain::is_zero_extent(swapchain::choose_extent(
+                &context.surface_capabilities,
+                window_width,
+                window_height,
+            ));
+        let mut swapchain = if window.is_some() {
+            Swapchain::new(
+                &context,
+                &mut allocator,
+                *format,
+                window_width.max(1),
+                window_height.max(1),
+                output_render_pass.as_ref().unwrap_or(&render_pass),
+                output_render_pass
+                    .is_none()
+                    .then_some(capabilities.msaa_samples),
+                capabilities.transparency,
+                config.present_mode,
+            )?
+        } else {
+            // The headless format is sRGB, so there is no output pass
+            Swapchain::new_headless(
+                &context,
+                &mut allocator,
+                window_width.max(1),
+                window_height.max(1),
+                &render_pass,
+                capabilities.msaa_samples,
+                capabilities.transparency,
+                HEADLESS_IMAGE_COUNT,
+            )?
+        };
+        if !output_encode {
+            swapchain.add_overlay_framebuffers(&context, &overlay_render_pass)?;
+        }
 
         // Create command pools
         let graphics_commandpool_info = vk::CommandPoolCreateInfo::builder()
@@ -257,51 +1287,231 @@ impl Renderer {
                 .device
                 .allocate_command_buffers(&command_buffer_allocate_info)?
         };
+        let overlay_command_buffers = unsafe {
+            context
+                .device
+                .allocate_command_buffers(&command_buffer_allocate_info)?
+        };
+        debug_namer.name(graphics_command_pool, "graphics");
+        for (i, command_buffer) in command_buffers.iter().enumerate() {
+            debug_namer.name(*command_buffer, &format!("graphics cb image {}", i));
+        }
+        for (i, command_buffer) in overlay_command_buffers.iter().enumerate() {
+            debug_namer.name(*command_buffer, &format!("overlay cb image {}", i));
+        }
+
+        let async_compute = if config.async_compute {
+            AsyncCompute::new(
+                &context.device,
+                &context.graphics_queue,
+                context.compute_queue.as_ref(),
+                graphics_command_pool,
+                FRAMES_IN_FLIGHT,
+                &debug_namer,
+            )?
+        } else {
+            None
+        };
+        if config.async_compute && async_compute.is_none() {
+            info!("No separate compute queue, compute passes run on the graphics queue");
+        }
+
+        let gpu_timer = GpuFrameTimer::new(&context, FRAMES_IN_FLIGHT)?;
+        if gpu_timer.is_none() {
+            info!("The graphics queue has no timestamps, GPU frame times are not measured");
+        }
+        let profiler = GpuProfiler::new(&context, FRAMES_IN_FLIGHT)?;
 
         let frame_data = Self::create_frame_data(&context.device, FRAMES_IN_FLIGHT)?;
-        let images_in_flight = vec![vk::Fence::null(); swapchain.get_actual_image_count() as usize];
+        for (i, frame) in frame_data.iter().enumerate() {
+            debug_namer.name(
+                frame.image_available_semaphore,
+                &format!("frame {} image-available", i),
+            );
+            debug_namer.name(
+                frame.render_finished_semaphore,
+                &format!("frame {} render-finished", i),
+            );
+        }
+        let timeline = FrameTimeline::new(
+            &context.device,
+            FRAMES_IN_FLIGHT,
+            swapchain.get_actual_image_count() as usize,
+            context.timeline_semaphores,
+        )?;
+        if !timeline.uses_timeline_semaphore() {
+            warn!("Timeline semaphores are not supported, falling back to fences");
+        }
+        timeline.set_debug_names(debug_namer);
 
         // Create buffer manager
         let buffer_manager = BufferManager::new();
+        // Buffers are named after the names they are created with
+        buffer_manager
+            .lock()
+            .unwrap()
+            .set_debug_namer(debug_namer.clone());
+        // gpu-allocator doesn't say which memory type an allocation is in, so mapped writes
+        // are flushed if any host visible type isn't coherent
+        let memory_properties = unsafe {
+            context
+                .instance
+                .get_physical_device_memory_properties(context.physical_device)
+        };
+        let all_coherent = memory_properties.memory_types
+            [..memory_properties.memory_type_count as usize]
+            .iter()
+            .filter(|t| {
+                t.property_flags
+                    .contains(vk::MemoryPropertyFlags::HOST_VISIBLE)
+            })
+            .all(|t| {
+                t.property_flags
+                    .contains(vk::MemoryPropertyFlags::HOST_COHERENT)
+            });
+        buffer_manager.lock().unwrap().set_non_coherent_atom_size(
+            (!all_coherent).then_some(
+                context
+                    .physical_device_properties
+                    .limits
+                    .non_coherent_atom_size,
+            ),
+        );
+        buffer_manager.lock().unwrap().set_transfer_queue(
+            &context.device,
+            &context.transfer_queue,
+            &context.graphics_queue,
+        )?;
         // Create uniform buffer
-        let camera_transforms: [[[f32; 4]; 4]; 2] =
-            [glm::Mat4::identity().into(), glm::Mat4::identity().into()];
+        let camera_transforms: CameraUniformData = [glm::Mat4::identity().into(); 3];
+        let fog = FogConfig::default();
+        let global_uniform_stride = context.pad_uniform_buffer_size(GLOBAL_UNIFORM_SIZE);
+        // Object parameters are bound with dynamic offsets, which have the same alignment
+        let mut scene_tree = SceneTree::default();
+        scene_tree
+            .set_object_params_stride(context.pad_uniform_buffer_size(MAX_OBJECT_PARAMS_SIZE));
+        scene_tree.set_device_local_object_data(config.device_local_object_data);
         let mut uniform_buffer = BufferManager::new_buffer(
             buffer_manager.clone(),
             &context.device,
             &mut allocator,
-            (std::mem::size_of::<[[[f32; 4]; 4]; 2]>()
-                * swapchain.get_actual_image_count() as usize) as u64,
+            (global_uniform_stride * swapchain.get_actual_image_count() as usize) as u64,
             vk::BufferUsageFlags::UNIFORM_BUFFER,
             MemoryLocation::CpuToGpu,
             "main-uniforms",
         )?;
         for i in 0..swapchain.get_actual_image_count() as usize {
-            let offset = i * std::mem::size_of::<[[[f32; 4]; 4]; 2]>();
+            let offset = i * global_uniform_stride;
             uniform_buffer.copy_to_offset(&mut allocator, &camera_transforms, offset)?;
+            uniform_buffer.copy_to_offset(
+                &mut allocator,
+                &fog.uniform_data(),
+                offset + std::mem::size_of::<CameraUniformData>(),
+            )?;
+            uniform_buffer.copy_to_offset(
+                &mut allocator,
+                &shadow::NO_SHADOW,
+                offset + SHADOW_UNIFORM_OFFSET,
+            )?;
+            uniform_buffer.copy_to_offset(
+                &mut allocator,
+                &PASS_TONE_MAPPING,
+                offset + TONE_MAP_UNIFORM_OFFSET,
+            )?;
         }
+        let shadow_map = ShadowMap::new(
+            &context,
+            &mut allocator,
+            graphics_command_pool,
+            config.shadow_map_resolution,
+        )?;
+        shadow_map.set_debug_names(debug_namer);
+
+        // Bound with the first frame
+        let light_storage =
+            LightStorage::new(&context.device, &mut allocator, buffer_manager.clone())?;
 
-        // Create storage buffer for lights
-        let mut light_buffer = BufferManager::new_buffer(
+        // Empty storage buffers aren't allowed, so this starts with a vec4 nothing reads
+        let mut morph_deltas = BufferManager::new_buffer(
             buffer_manager.clone(),
             &context.device,
             &mut allocator,
-            8,
+            16,
             vk::BufferUsageFlags::STORAGE_BUFFER,
             MemoryLocation::CpuToGpu,
-            "lights",
+            "morph deltas",
         )?;
-        light_buffer.fill(&mut allocator, &[0.0f32; 2])?;
+        morph_deltas.fill(&mut allocator, &[0.0f32; 4])?;
 
-        let mut shader_cache = ShaderCache::new(&context.device)?;
-        let material_system = MaterialSystem::new(&context.device, render_pass, &mut shader_cache)?;
+        let pipeline_cache = PipelineCache::new(&context, config.pipeline_cache_path.clone())?;
+        let mut shader_cache = ShaderCache::new(&context.device, pipeline_cache)?;
 
         let descriptor_layout_cache = DescriptorLayoutCache::default();
         let mut descriptor_allocator = DescriptorAllocator::default();
 
-        let text = TextHandler::new("Roboto-Regular.ttf")?;
+        let mut text = TextHandler::new("Roboto-Regular.ttf", config.deterministic_ids)?;
+        text.resize(swapchain.get_extent());
 
-        let texture_storage = TextureStorage::default();
+        let mut texture_storage = TextureStorage::default();
+        texture_storage.set_debug_namer(debug_namer.clone());
+        // Bound to the light cookie slots that have no cookie
+        let fallback_texture = texture_storage.get_or_create_fallback_texture(
+            &context.device,
+            &mut allocator,
+            buffer_manager.clone(),
+            &graphics_command_pool,
+            &context.graphics_queue.queue,
+        )?;
+        // Bound to the texture slots materials leave empty
+        let mut default_texture = |pixel: [u8; 4], name: &str| -> RendererResult<Handle<Texture>> {
+            let handle = texture_storage.new_texture_from_u8(
+                &pixel,
+                1,
+                1,
+                vk::Format::R8G8B8A8_UNORM,
+                &context.device,
+                &mut allocator,
+                buffer_manager.clone(),
+                &graphics_command_pool,
+                &context.graphics_queue.queue,
+            )?;
+            texture_storage.set_debug_name(handle, name);
+            Ok(handle)
+        };
+        let default_textures = DefaultTextures {
+            white: fallback_texture,
+            black: default_texture([0, 0, 0, 255], "default black")?,
+            flat_normal: default_texture([128, 128, 255, 255], "default flat normal")?,
+        };
+        let mut material_system = MaterialSystem::new(
+            &context.device,
+            render_pass,
+            overlay_render_pass,
+            scene_render_pass,
+            shadow_map.render_pass,
+            capabilities.msaa_samples,
+            capabilities.transparency,
+            &mut shader_cache,
+            default_textures,
+        )?;
+        if let Some(output_render_pass) = output_render_pass {
+            material_system.build_output_template(
+                &context.device,
+                output_render_pass,
+                &mut shader_cache,
+            )?;
+        }
+        // Cookies must not tile outside of the cone
+        let cookie_sampler_info = vk::SamplerCreateInfo::builder()
+            .mag_filter(vk::Filter::LINEAR)
+            .min_filter(vk::Filter::LINEAR)
+            .address_mode_u(vk::SamplerAddressMode::CLAMP_TO_BORDER)
+            .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_BORDER)
+            .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_BORDER)
+            .border_color(vk::BorderColor::FLOAT_OPAQUE_BLACK);
+        let cookie_sampler = unsafe { context.device.create_sampler(&cookie_sampler_info, None)? };
+        debug_namer.name(cookie_sampler, "light cookie sampler");
+        material_system.set_debug_names(debug_namer);
 
         let default_template_handle = material_system.get_effect_template_handle("default")?;
         let default_template =
@@ -312,13 +1522,15 @@ impl Renderer {
             .expect("No effect handle?");
         let effect = shader_cache.get_shader_effect_by_handle(effect_handle)?;
 
+        // Every template has to use the same layouts for the globals and lights sets
+        let global_set_hashes = [effect.get_set_hash(0), effect.get_set_hash(1)];
         let descriptor_set_camera =
             descriptor_allocator.allocate(&context.device, effect.set_layouts[0])?;
         // Update camera descriptor sets
         unsafe {
             let buffer_info = [vk::DescriptorBufferInfo::builder()
                 .buffer(uniform_buffer.get_buffer().buffer)
-                .range(std::mem::size_of::<[[[f32; 4]; 4]; 2]>() as u64)
+                .range(GLOBAL_UNIFORM_SIZE as u64)
                 .build()];
             let descriptor_write = vk::WriteDescriptorSet::builder()
                 .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC)
@@ -331,22 +1543,33 @@ impl Renderer {
         }
         let descriptor_set_lights =
             descriptor_allocator.allocate(&context.device, effect.set_layouts[1])?;
+        debug_namer.name(effect.set_layouts[0], "set0 globals");
+        debug_namer.name(effect.set_layouts[1], "set1 lights");
+        debug_namer.name(descriptor_set_camera, "globals");
+        debug_namer.name(descriptor_set_lights, "lights");
+        morph::update_descriptor(&context.device, descriptor_set_lights, &morph_deltas);
+        shadow_map.update_descriptor(&context.device, descriptor_set_lights);
+        let oit_offscreen_composite_set =
+            if capabilities.transparency == TransparencyTechnique::WeightedBlended {
+                let layout = Self::oit_composite_layout(&material_system, &shader_cache)?;
+                Some(descriptor_allocator.allocate(&context.device, layout)?)
+            } else {
+                None
+            };
 
         let mut imgui = Context::create();
         imgui.set_ini_filename(None);
 
-        let mut platform = WinitPlatform::init(&mut imgui);
+        #[cfg(feature = "winit")]
+        let platform = WinitPlatform::init(&mut imgui);
 
-        let hidpi_factor = platform.hidpi_factor();
-        let font_size = (13.0 * hidpi_factor) as f32;
+        // The fonts are added before a window is attached, at a hidpi factor of 1
         imgui.fonts().add_font(&[FontSource::DefaultFontData {
             config: Some(FontConfig {
-                size_pixels: font_size,
+                size_pixels: 13.0,
                 ..FontConfig::default()
             }),
         }]);
-        imgui.io_mut().font_global_scale = (1.0 / hidpi_factor) as f32;
-        platform.attach_window(imgui.io_mut(), window, HiDpiMode::Rounded);
 
         let allocator = Arc::new(Mutex::new(allocator));
 
@@ -355,58 +1578,191 @@ impl Renderer {
             context.device.clone(),
             context.graphics_queue.queue,
             graphics_command_pool,
-            render_pass,
+            overlay_render_pass,
             &mut imgui,
             Some(Options {
                 in_flight_frames: FRAMES_IN_FLIGHT,
                 ..Default::default()
             }),
         )?;
+        let instance_batches =
+            InstanceBatchBuffer::new(swapchain.get_actual_image_count() as usize);
 
-        Ok(Renderer {
+        let mut renderer = Renderer {
             dropped: false,
             allocator,
             context,
-            imgui,
+            imgui: ImguiContext(imgui),
             ui_state: UiState {
                 opened: true,
                 show_demo_window: false,
+                show_light_probes: false,
             },
+            #[cfg(feature = "winit")]
             platform,
             imgui_renderer,
             buffer_manager,
             swapchain,
             graphics_command_pool,
+            recorded_frames: RecordedFrames::new(command_buffers.len()),
             command_buffers,
+            overlay_command_buffers,
+            schedule,
             render_pass,
+            overlay_render_pass,
+            shadow_map,
+            shadow_view_projection: None,
+            scene_render_pass,
+            scaled_target: None,
+            output_render_pass,
+            composite_target: None,
+            capabilities,
+            luminance_pass: None,
+            bloom_pass: None,
+            bloom_chain: None,
+            async_compute,
+            aov_pass: None,
+            velocity_pass: None,
+            velocity_target: None,
+            compute: ComputeDispatcher::default(),
+            scene_luminance: None,
+            exposure: 1.0,
+            exposure_adapting: false,
             shader_cache,
-            scene_tree: Default::default(),
+            scene_tree,
+            object_params_set: None,
             descriptor_layout_cache,
             descriptor_allocator,
             material_system,
             frame_data,
-            images_in_flight,
+            timeline,
             current_image: 0,
             uniform_buffer,
+            global_uniform_stride,
+            fog,
+            clear_color: Color::linear(0.0, 0.0, 0.0),
+            skybox: None,
+            view_projection_history: PreviousFrame::default(),
             descriptor_set_camera,
+            global_set_hashes,
             descriptor_set_lights,
-            light_buffer,
+            light_storage,
+            morph_deltas,
+            light_probes: vec![],
+            cookie_sampler,
+            fallback_texture,
             texture_storage,
             text,
+            lights: LightManager::default(),
             meshs: Default::default(),
             material_uniform_buffers: Default::default(),
             last_frame: Instant::now(),
-        })
+            frame_delta: Duration::ZERO,
+            frame_times: FrameTimeHistory::new(config.frame_time_history),
+            gpu_frame_times: FrameTimeHistory::new(config.frame_time_history),
+            gpu_timer,
+            profiler,
+            pass_timings: vec![],
+            frame_stats: FrameStats::default(),
+            last_frame_stats: FrameStats::default(),
+            frame_number: 0,
+            frame_events: FrameEvents::empty(),
+            redraw_requested: true,
+            redraw_generation: 0,
+            last_presented_view: None,
+            stats_reader: StatsReader::default(),
+            pending_frame: None,
+            asset_watcher: AssetWatcher::new(config.asset_reload_debounce, config.watch_assets),
+            config,
+            focused: true,
+            occluded: false,
+            last_render: Instant::now(),
+            surface_ready,
+            present_mode_changed: false,
+            preview_scene: None,
+            frustum: Frustum::default(),
+            view_layer_mask: ALL_LAYERS,
+            view_position: glm::Vec3::zeros(),
+            culling_stats: CullingStats::default(),
+            skipped_draws: vec![],
+            draw_sort_stats: DrawSortStats::default(),
+            object_upload_stats: ObjectUploadStats::default(),
+            descriptor_hooks: HashMap::new(),
+            pending_screenshots: HashMap::new(),
+            next_screenshot: 0,
+            chunk_callback: None,
+            overlay_recorder: None,
+            cursor_tracker: CursorTracker::default(),
+            software_cursor: None,
+            cursor_vertex_buffer: None,
+            gizmo: None,
+            gizmo_resources: None,
+            emitters: HandleArray::new(),
+            emitter_draw_order: vec![],
+            particle_depth_sets: vec![],
+            oit_composite_sets: vec![],
+            oit_offscreen_composite_set,
+            instance_batches,
+            instance_groups: InstanceGroups::default(),
+            cubemap_converter: None,
+            lightmap_baker: None,
+            #[cfg(feature = "defrag")]
+            defrag: DefragState::default(),
+        };
+        renderer.update_composite_target()?;
+        renderer.update_scaled_target()?;
+        Ok(renderer)
     }
 
+    #[cfg(feature = "winit")]
     pub fn handle_event(&mut self, window: &Window, event: &winit::event::Event<()>) {
         self.platform
             .handle_event(self.imgui.io_mut(), window, event);
+        // The UI and the cursor follow the input
+        if matches!(
+            event,
+            Event::WindowEvent { .. }
+                | Event::DeviceEvent {
+                    event: DeviceEvent::MouseMotion { .. },
+                    ..
+                }
+        ) {
+            self.request_redraw();
+        }
         match event {
-            Event::NewEvents(_) => {
-                let now = Instant::now();
-                self.imgui.io_mut().update_delta_time(now - self.last_frame);
-                self.last_frame = now;
+            Event::NewEvents(_) => self.advance_frame_clock(),
+            Event::WindowEvent {
+                event: WindowEvent::Focused(focused),
+                ..
+            } => {
+                let was_in_background = self.is_in_background();
+                self.focused = *focused;
+                self.log_background_change(was_in_background);
+            }
+            Event::WindowEvent {
+                event: WindowEvent::Occluded(occluded),
+                ..
+            } => {
+                let was_in_background = self.is_in_background();
+                self.occluded = *occluded;
+                self.log_background_change(was_in_background);
+            }
+            Event::WindowEvent {
+                event: WindowEvent::CursorMoved { position, .. },
+                ..
+            } => {
+                self.cursor_tracker
+                    .cursor_moved(glm::vec2(position.x as f32, position.y as f32));
+            }
+            Event::DeviceEvent {
+                event: DeviceEvent::MouseMotion { delta },
+                ..
+            } => {
+                let extent = self.swapchain.get_extent();
+                self.cursor_tracker.mouse_motion(
+                    glm::vec2(delta.0 as f32, delta.1 as f32),
+                    glm::vec2(extent.width as f32, extent.height as f32),
+                );
             }
             Event::WindowEvent {
                 event:
@@ -432,368 +1788,7293 @@ impl Renderer {
         }
     }
 
-    pub fn recreate_swapchain(&mut self, width: u32, height: u32) -> RendererResult<()> {
-        unsafe {
-            self.context.device.device_wait_idle()?;
+    // Drawn on top of everything, including the UI, at the cursor position. Only the
+    // swapchain gets it, material previews and other offscreen renders don't.
+    pub fn set_software_cursor(&mut self, config: Option<CursorConfig>) -> RendererResult<()> {
+        self.request_redraw();
+        let config = match config {
+            Some(config) => config,
+            None => {
+                self.software_cursor = None;
+                return self.rebuild_schedule();
+            }
+        };
+        let material = self.build_material(
+            "software cursor",
+            MaterialData {
+                textures: HashMap::from([(MaterialTextureSlot::Albedo, config.texture)]),
+                buffers: vec![],
+                parameters: ShaderParameters::default(),
+                base_template: "cursor".to_string(),
+            },
+        )?;
+        if self.cursor_vertex_buffer.is_none() {
+            if let Ok(mut allo) = lock_allocator(&self.allocator) {
+                let bytes = 6
+                    * self.swapchain.get_actual_image_count() as usize
+                    * std::mem::size_of::<TextVertexData>();
+                self.cursor_vertex_buffer = Some(BufferManager::new_buffer(
+                    self.buffer_manager.clone(),
+                    &self.context.device,
+                    allo.deref_mut(),
+                    bytes as u64,
+                    vk::BufferUsageFlags::VERTEX_BUFFER,
+                    MemoryLocation::CpuToGpu,
+                    "cursor-vertex-buffer",
+                )?);
+            } else {
+                return Err(AllocatorUnavailable.into());
+            }
         }
-        self.context.refresh_surface_data()?;
-        if let Ok(mut allo) = self.allocator.lock() {
-            let old_image_count = self.swapchain.get_actual_image_count();
-            self.swapchain.destroy(&self.context, allo.deref_mut());
-            self.swapchain = Swapchain::new(
-                &self.context,
-                allo.deref_mut(),
-                self.swapchain.get_image_format(),
-                width,
-                height,
-                &self.render_pass,
-            )?;
-            assert!(old_image_count == self.swapchain.get_actual_image_count());
+        self.software_cursor = Some(SoftwareCursor { config, material });
+        self.rebuild_schedule()
+    }
+
+    // Called whenever one of the toggles in ScheduleOptions changes. None of them change
+    // the attachment layouts so far, so the render pass can be kept.
+    fn rebuild_schedule(&mut self) -> RendererResult<()> {
+        let options = ScheduleOptions {
+            gizmo: self.gizmo.is_some(),
+            software_cursor: self.software_cursor.is_some(),
+            upscale: self.scaled_target.is_some(),
+            luminance: self.config.auto_exposure.is_some() && self.luminance_pass.is_some(),
+            bloom: self.config.bloom.enabled() && self.bloom_pass.is_some(),
+            async_compute: self.async_compute.is_some(),
+            output_encode: self.output_render_pass.is_some(),
+        };
+        if options != self.schedule.options() {
+            self.schedule = RenderSchedule::new(options)?;
+            info!("Render schedule: {:?}", self.schedule.pass_kinds());
         }
         Ok(())
     }
 
-    fn wait_for_next_frame_fence(&self) -> RendererResult<()> {
-        unsafe {
-            self.context.device.wait_for_fences(
-                &[self.frame_data[self.current_image].in_flight_fence],
-                true,
-                std::u64::MAX,
+    // The passes of a frame in the order they are recorded, for debugging
+    pub fn describe_schedule(&self) -> Vec<PassDescription> {
+        self.schedule.passes().to_vec()
+    }
+
+    // While grabbed, the cursor position follows the raw mouse motion instead of the
+    // reported cursor position, which is stuck while the cursor is grabbed
+    pub fn set_cursor_grabbed(&mut self, grabbed: bool) {
+        self.cursor_tracker.set_grabbed(grabbed);
+    }
+
+    // In pixels from the top left corner of the window
+    pub fn get_cursor_position(&self) -> glm::Vec2 {
+        self.cursor_tracker.position()
+    }
+
+    fn update_software_cursor(
+        &mut self,
+        allocator: &mut Allocator,
+        image_index: usize,
+    ) -> RendererResult<()> {
+        if let (Some(software_cursor), Some(buffer)) =
+            (&self.software_cursor, &mut self.cursor_vertex_buffer)
+        {
+            let extent = self.swapchain.get_extent();
+            let vertices = cursor::cursor_vertices(
+                &self.cursor_tracker.position(),
+                &software_cursor.config,
+                &glm::vec2(extent.width as f32, extent.height as f32),
+            );
+            buffer.copy_to_offset(
+                allocator,
+                &vertices,
+                image_index * std::mem::size_of_val(&vertices),
             )?;
         }
         Ok(())
     }
 
-    fn wait_for_image_fence_and_set_new_fence(&mut self, image_index: usize) -> RendererResult<()> {
-        if self.images_in_flight[image_index] != vk::Fence::null() {
-            unsafe {
-                self.context.device.wait_for_fences(
-                    &[self.images_in_flight[image_index]],
-                    true,
-                    std::u64::MAX,
-                )?;
+    fn draw_upscale(&self, cmd_buf: vk::CommandBuffer) -> RendererResult<()> {
+        match self.scaled_target.as_ref() {
+            Some(scaled) => {
+                let bloom_intensity = if self.schedule.options().bloom {
+                    self.config.bloom.intensity
+                } else {
+                    0.0
+                };
+                let constants = [
+                    self.exposure.to_bits(),
+                    self.config.tone_mapping.shader_index(),
+                    bloom_intensity.to_bits(),
+                ];
+                let bytes = unsafe {
+                    std::slice::from_raw_parts(
+                        constants.as_ptr() as *const u8,
+                        std::mem::size_of_val(&constants),
+                    )
+                };
+                self.draw_fullscreen(cmd_buf, scaled.material, bytes)
             }
+            None => Ok(()),
         }
+    }
 
-        self.images_in_flight[image_index] = self.frame_data[self.current_image].in_flight_fence;
-        Ok(())
+    fn draw_output(&self, cmd_buf: vk::CommandBuffer) -> RendererResult<()> {
+        let (composite, encoding) = match (
+            self.composite_target.as_ref(),
+            self.capabilities.output_encoding,
+        ) {
+            (Some(composite), Some(encoding)) => (composite, encoding),
+            _ => return Ok(()),
+        };
+        let constants = encoding.push_constants();
+        let bytes = unsafe {
+            std::slice::from_raw_parts(
+                constants.as_ptr() as *const u8,
+                std::mem::size_of_val(&constants),
+            )
+        };
+        self.draw_fullscreen(cmd_buf, composite.material, bytes)
     }
 
-    fn update_command_buffer<F: FnOnce(&mut Ui)>(
-        &mut self,
-        image_index: usize,
-        window: &Window,
-        ui_func: F,
+    // A fullscreen triangle over the swapchain extent with the forward pass of the
+    // material, the push constants go to its fragment shader
+    fn draw_fullscreen(
+        &self,
+        cmd_buf: vk::CommandBuffer,
+        material: Handle<Material>,
+        push_constants: &[u8],
     ) -> RendererResult<()> {
-        let command_buffer_begin_info = vk::CommandBufferBeginInfo::builder();
-        let cmd_buf = &self.command_buffers[image_index];
-        let framebuffer = &self.swapchain.get_render_targets()[image_index].framebuffer;
+        let material = self.material_system.get_material_by_handle(material)?;
+        let template = self
+            .material_system
+            .get_effect_template_by_handle(material.original)?;
+        let pass = &template.pass_shaders[MeshPassType::Forward];
+        let extent = self.swapchain.get_extent();
+        let viewports = [vk::Viewport {
+            x: 0.,
+            y: 0.,
+            width: extent.width as f32,
+            height: extent.height as f32,
+            min_depth: 0.,
+            max_depth: 1.,
+        }];
+        let scissors = [vk::Rect2D {
+            offset: vk::Offset2D { x: 0, y: 0 },
+            extent,
+        }];
+        let device = &self.context.device;
         unsafe {
-            self.context
-                .device
-                .begin_command_buffer(*cmd_buf, &command_buffer_begin_info)?;
+            device.cmd_bind_pipeline(cmd_buf, vk::PipelineBindPoint::GRAPHICS, pass.pipeline);
+            device.cmd_set_viewport(cmd_buf, 0, &viewports);
+            device.cmd_set_scissor(cmd_buf, 0, &scissors);
+            device.cmd_bind_descriptor_sets(
+                cmd_buf,
+                vk::PipelineBindPoint::GRAPHICS,
+                pass.layout,
+                0,
+                &[material.pass_sets[MeshPassType::Forward]],
+                &[],
+            );
+            device.cmd_push_constants(
+                cmd_buf,
+                pass.layout,
+                vk::ShaderStageFlags::FRAGMENT,
+                0,
+                push_constants,
+            );
+            device.cmd_draw(cmd_buf, 3, 1, 0, 0);
         }
-        let clear_values = [
-            vk::ClearValue {
-                color: vk::ClearColorValue {
-                    float32: [0.0, 0.0, 0.00, 1.0],
-                },
-            },
-            vk::ClearValue {
-                depth_stencil: vk::ClearDepthStencilValue {
-                    depth: 1.0,
-                    stencil: 0,
-                },
-            },
-        ];
-        let render_pass_begin_info = vk::RenderPassBeginInfo::builder()
-            .render_pass(self.render_pass)
-            .framebuffer(*framebuffer)
-            .render_area(vk::Rect2D {
-                offset: vk::Offset2D { x: 0, y: 0 },
-                extent: self.swapchain.get_extent(),
-            })
-            .clear_values(&clear_values);
+        Ok(())
+    }
+
+    fn draw_software_cursor(
+        &self,
+        cmd_buf: vk::CommandBuffer,
+        image_index: usize,
+    ) -> RendererResult<()> {
+        let (cursor, buffer) = match (&self.software_cursor, &self.cursor_vertex_buffer) {
+            (Some(cursor), Some(buffer)) => (cursor, buffer),
+            _ => return Ok(()),
+        };
+        let material = self
+            .material_system
+            .get_material_by_handle(cursor.material)?;
+        let template = self
+            .material_system
+            .get_effect_template_by_handle(material.original)?;
+        let pass = &template.pass_shaders[MeshPassType::Forward];
+        let extent = self.swapchain.get_extent();
+        let viewports = [vk::Viewport {
+            x: 0.,
+            y: 0.,
+            width: extent.width as f32,
+            height: extent.height as f32,
+            min_depth: 0.,
+            max_depth: 1.,
+        }];
+        let scissors = [vk::Rect2D {
+            offset: vk::Offset2D { x: 0, y: 0 },
+            extent,
+        }];
+        let device = &self.context.device;
         unsafe {
-            self.context.device.cmd_begin_render_pass(
-                *cmd_buf,
-                &render_pass_begin_info,
-                vk::SubpassContents::INLINE,
+            device.cmd_bind_pipeline(cmd_buf, vk::PipelineBindPoint::GRAPHICS, pass.pipeline);
+            device.cmd_set_viewport(cmd_buf, 0, &viewports);
+            device.cmd_set_scissor(cmd_buf, 0, &scissors);
+            device.cmd_bind_descriptor_sets(
+                cmd_buf,
+                vk::PipelineBindPoint::GRAPHICS,
+                pass.layout,
+                0,
+                &[material.pass_sets[MeshPassType::Forward]],
+                &[],
             );
+            let offset = (image_index * 6 * std::mem::size_of::<TextVertexData>()) as u64;
+            device.cmd_bind_vertex_buffers(cmd_buf, 0, &[buffer.get_buffer().buffer], &[offset]);
+            device.cmd_draw(cmd_buf, 6, 1, 0, 0);
+        }
+        Ok(())
+    }
 
-            let viewports = [vk::Viewport {
-                x: 0.,
-                y: 0.,
-                width: self.swapchain.get_extent().width as f32,
-                height: self.swapchain.get_extent().height as f32,
-                min_depth: 0.,
-                max_depth: 1.,
-            }];
-            let scissors = [vk::Rect2D {
-                offset: vk::Offset2D { x: 0, y: 0 },
-                extent: self.swapchain.get_extent(),
-            }];
+    // Shows the handles of the mode over the selected object, None hides them
+    pub fn set_gizmo(
+        &mut self,
+        mode: Option<GizmoMode>,
+        selected: Handle<SceneObject>,
+    ) -> RendererResult<()> {
+        self.request_redraw();
+        let mode = match mode {
+            Some(mode) => mode,
+            None => {
+                self.gizmo = None;
+                return self.rebuild_schedule();
+            }
+        };
+        if self.scene_tree.get_object(selected).is_none() {
+            return Err(InvalidHandle.into());
+        }
+        if self.gizmo_resources.is_none() {
+            // The cursor pipeline with the default white albedo draws plain colored triangles
+            let material = self.build_material(
+                "gizmo",
+                MaterialData {
+                    textures: HashMap::new(),
+                    buffers: vec![],
+                    parameters: ShaderParameters::default(),
+                    base_template: "cursor".to_string(),
+                },
+            )?;
+            let image_count = self.swapchain.get_actual_image_count() as usize;
+            let bytes = GIZMO_MAX_VERTICES * image_count * std::mem::size_of::<TextVertexData>();
+            if let Ok(mut allo) = lock_allocator(&self.allocator) {
+                let vertex_buffer = BufferManager::new_buffer(
+                    self.buffer_manager.clone(),
+                    &self.context.device,
+                    allo.deref_mut(),
+                    bytes as u64,
+                    vk::BufferUsageFlags::VERTEX_BUFFER,
+                    MemoryLocation::CpuToGpu,
+                    "gizmo-vertex-buffer",
+                )?;
+                self.gizmo_resources = Some(GizmoResources {
+                    material,
+                    vertex_buffer,
+                    vertex_counts: vec![0; image_count],
+                });
+            } else {
+                return Err(AllocatorUnavailable.into());
+            }
+        }
+        match self.gizmo.as_mut() {
+            // Switching modes on the same object keeps the screen scale
+            Some(gizmo) if gizmo.target == selected => gizmo.mode = mode,
+            _ => self.gizmo = Some(Gizmo::new(mode, selected)),
+        }
+        self.rebuild_schedule()
+    }
 
-            let camera_buffer_offset = image_index * std::mem::size_of::<[[[f32; 4]; 4]; 2]>();
-            let mut cur_pipeline = vk::Pipeline::null();
-            let mut cur_layout = vk::PipelineLayout::null(); // shouldn't change but we will need it
-                                                             // TODO sort by pipeline
-            for m in self.scene_tree.iter() {
-                let mat_handle = m.material;
-                let mat = self.material_system.get_material_by_handle(mat_handle)?;
-                let effect = self
-                    .material_system
-                    .get_effect_template_by_handle(mat.original)?;
-                if cur_pipeline != effect.pass_shaders[MeshPassType::Forward].pipeline {
-                    cur_pipeline = effect.pass_shaders[MeshPassType::Forward].pipeline;
-                    cur_layout = effect.pass_shaders[MeshPassType::Forward].layout;
+    pub fn get_gizmo(&self) -> Option<&Gizmo> {
+        self.gizmo.as_ref()
+    }
 
-                    self.context.device.cmd_bind_pipeline(
-                        *cmd_buf,
-                        vk::PipelineBindPoint::GRAPHICS,
-                        cur_pipeline,
-                    );
+    fn gizmo_view(&self, gizmo: &Gizmo, camera: &Camera) -> Option<GizmoView> {
+        let object = self.scene_tree.get_object(gizmo.target)?;
+        let transform = object.get_global_transform();
+        let center = glm::vec3(transform[(0, 3)], transform[(1, 3)], transform[(2, 3)]);
+        let orientation = match gizmo.mode {
+            GizmoMode::Scale => object.rotation,
+            GizmoMode::Translate | GizmoMode::Rotate => glm::Quat::identity(),
+        };
+        let extent = self.swapchain.get_extent();
+        Some(GizmoView {
+            center,
+            orientation,
+            size: gizmo.size(&center, &camera.get_position()),
+            view_projection: camera.view_projection_matrix(),
+            screen_size: glm::vec2(extent.width as f32, extent.height as f32),
+        })
+    }
 
-                    self.context.device.cmd_bind_descriptor_sets(
-                        *cmd_buf,
-                        vk::PipelineBindPoint::GRAPHICS,
-                        cur_layout,
-                        0,
-                        &[self.descriptor_set_camera, self.descriptor_set_lights],
-                        // Only the camera offset changes
-                        &[camera_buffer_offset as u32],
-                    );
+    // Call with every cursor move and primary button change, cursor_position in pixels
+    // from the top left corner. Drags are applied to the selected object right away.
+    // Returns whether the gizmo used the input, in which case nothing else should.
+    pub fn gizmo_handle_event(
+        &mut self,
+        cursor_position: glm::Vec2,
+        primary_down: bool,
+        camera: &Camera,
+    ) -> RendererResult<bool> {
+        let view = match self.gizmo.as_ref() {
+            Some(gizmo) => self.gizmo_view(gizmo, camera),
+            None => return Ok(false),
+        };
+        let view = match view {
+            Some(view) => view,
+            None => {
+                // The selected object was removed
+                self.gizmo = None;
+                self.rebuild_schedule()?;
+                return Ok(false);
+            }
+        };
+        let (target, response) = match self.gizmo.as_mut() {
+            Some(gizmo) => (
+                gizmo.target,
+                gizmo.handle_input(&cursor_position, primary_down, &view),
+            ),
+            None => return Ok(false),
+        };
+        if let Some(drag) = response.drag {
+            self.apply_gizmo_drag(target, drag)?;
+        }
+        Ok(response.consumed)
+    }
 
-                    self.context
-                        .device
-                        .cmd_set_viewport(*cmd_buf, 0, &viewports);
-                    self.context.device.cmd_set_scissor(*cmd_buf, 0, &scissors);
+    // Drags are in world space while the object's transform is relative to its parent
+    fn apply_gizmo_drag(
+        &mut self,
+        target: Handle<SceneObject>,
+        drag: GizmoDrag,
+    ) -> RendererResult<()> {
+        if let Ok(mut allo) = lock_allocator(&self.allocator) {
+            let mut guard = self
+                .scene_tree
+                .get_object_mut(target, allo.deref_mut())
+                .ok_or(InvalidHandle)?;
+            let object = &mut guard.object;
+            let local = glm::Mat4::new_translation(&object.position)
+                * glm::quat_to_mat4(&object.rotation)
+                * glm::scaling(&object.scaling);
+            let world_to_parent = local * glm::inverse(&object.get_global_transform());
+            let to_parent = |v: &glm::Vec3| (world_to_parent * glm::vec4(v.x, v.y, v.z, 0.0)).xyz();
+            match drag {
+                GizmoDrag::Translate(offset) => object.position += to_parent(&offset),
+                GizmoDrag::Rotate { axis, delta, .. } => {
+                    let axis = to_parent(&axis);
+                    if glm::length(&axis) > f32::EPSILON {
+                        object.rotation =
+                            glm::quat_angle_axis(delta, &glm::normalize(&axis)) * object.rotation;
+                    }
+                }
+                GizmoDrag::Scale(factors) => {
+                    object.scaling = object.scaling.component_mul(&factors);
                 }
+            }
+        } else {
+            return Err(AllocatorUnavailable.into());
+        }
+        Ok(())
+    }
 
-                self.context.device.cmd_bind_descriptor_sets(
-                    *cmd_buf,
-                    vk::PipelineBindPoint::GRAPHICS,
-                    cur_layout,
-                    2,
-                    &[mat.pass_sets[MeshPassType::Forward]],
-                    &[],
-                );
-                let buf = m.get_buffer();
-                let inner_buf = buf.get_buffer();
-                self.context
-                    .device
-                    .cmd_bind_vertex_buffers(*cmd_buf, 1, &[inner_buf.buffer], &[0]);
-                let mesh = self
-                    .meshs
-                    .get_mesh(m.mesh)
-                    .ok_or::<RendererError>(InvalidHandle.into())?;
-                mesh.draw(&self.context.device, *cmd_buf);
+    fn update_gizmo(
+        &mut self,
+        allocator: &mut Allocator,
+        image_index: usize,
+        camera: &Camera,
+    ) -> RendererResult<()> {
+        let vertices = match self.gizmo.as_ref() {
+            Some(gizmo) => self
+                .gizmo_view(gizmo, camera)
+                .map(|view| gizmo.vertices(&view))
+                .unwrap_or_default(),
+            None => return Ok(()),
+        };
+        if let Some(resources) = self.gizmo_resources.as_mut() {
+            if !vertices.is_empty() {
+                resources.vertex_buffer.copy_to_offset(
+                    allocator,
+                    &vertices,
+                    image_index * GIZMO_MAX_VERTICES * std::mem::size_of::<TextVertexData>(),
+                )?;
             }
-            self.text.draw(
-                &self.context.device,
-                *cmd_buf,
-                image_index,
-                self.swapchain.get_extent(),
-                &self.material_system,
+            resources.vertex_counts[image_index] = vertices.len() as u32;
+        }
+        Ok(())
+    }
+
+    fn draw_gizmo(&self, cmd_buf: vk::CommandBuffer, image_index: usize) -> RendererResult<()> {
+        let resources = match &self.gizmo_resources {
+            Some(resources) if resources.vertex_counts[image_index] > 0 => resources,
+            _ => return Ok(()),
+        };
+        let material = self
+            .material_system
+            .get_material_by_handle(resources.material)?;
+        let template = self
+            .material_system
+            .get_effect_template_by_handle(material.original)?;
+        let pass = &template.pass_shaders[MeshPassType::Forward];
+        let extent = self.swapchain.get_extent();
+        let viewports = [vk::Viewport {
+            x: 0.,
+            y: 0.,
+            width: extent.width as f32,
+            height: extent.height as f32,
+            min_depth: 0.,
+            max_depth: 1.,
+        }];
+        let scissors = [vk::Rect2D {
+            offset: vk::Offset2D { x: 0, y: 0 },
+            extent,
+        }];
+        let device = &self.context.device;
+        unsafe {
+            device.cmd_bind_pipeline(cmd_buf, vk::PipelineBindPoint::GRAPHICS, pass.pipeline);
+            device.cmd_set_viewport(cmd_buf, 0, &viewports);
+            device.cmd_set_scissor(cmd_buf, 0, &scissors);
+            device.cmd_bind_descriptor_sets(
+                cmd_buf,
+                vk::PipelineBindPoint::GRAPHICS,
+                pass.layout,
+                0,
+                &[material.pass_sets[MeshPassType::Forward]],
+                &[],
+            );
+            let offset =
+                (image_index * GIZMO_MAX_VERTICES * std::mem::size_of::<TextVertexData>()) as u64;
+            device.cmd_bind_vertex_buffers(
+                cmd_buf,
+                0,
+                &[resources.vertex_buffer.get_buffer().buffer],
+                &[offset],
+            );
+            device.cmd_draw(cmd_buf, resources.vertex_counts[image_index], 1, 0, 0);
+        }
+        Ok(())
+    }
+
+    pub fn add_emitter(
+        &mut self,
+        config: EmitterConfig,
+    ) -> RendererResult<Handle<ParticleEmitter>> {
+        self.request_redraw();
+        if let Some(object) = config.attached_to {
+            if self.scene_tree.get_object(object).is_none() {
+                return Err(InvalidHandle.into());
+            }
+        }
+        let material = self.build_material(
+            &format!("particles {:?}", config.texture),
+            MaterialData {
+                textures: HashMap::from([(MaterialTextureSlot::Albedo, config.texture)]),
+                buffers: vec![],
+                parameters: ShaderParameters::default(),
+                base_template: "particle".to_string(),
+            },
+        )?;
+        let image_count = self.swapchain.get_actual_image_count() as usize;
+        let bytes = config.capacity.max(1) * image_count * std::mem::size_of::<ParticleInstance>();
+        let instance_buffer = if let Ok(mut allo) = lock_allocator(&self.allocator) {
+            BufferManager::new_buffer(
+                self.buffer_manager.clone(),
+                &self.context.device,
+                allo.deref_mut(),
+                bytes as u64,
+                vk::BufferUsageFlags::VERTEX_BUFFER,
+                MemoryLocation::CpuToGpu,
+                "particle-instance-buffer",
+            )?
+        } else {
+            return Err(AllocatorUnavailable.into());
+        };
+        let handle = self.emitters.insert(ParticleEmitter::new(
+            config,
+            material,
+            instance_buffer,
+            image_count,
+        ));
+        self.rebuild_schedule()?;
+        Ok(handle)
+    }
+
+    pub fn remove_emitter(&mut self, handle: Handle<ParticleEmitter>) -> RendererResult<()> {
+        self.request_redraw();
+        let mut emitter = self.emitters.remove(handle)?;
+        emitter.instance_buffer.queue_free()?;
+        self.emitter_draw_order.retain(|(_, h)| *h != handle);
+        self.rebuild_schedule()
+    }
+
+    pub fn get_emitter(&self, handle: Handle<ParticleEmitter>) -> Option<&ParticleEmitter> {
+        self.emitters.get(handle)
+    }
+
+    pub fn get_emitter_mut(
+        &mut self,
+        handle: Handle<ParticleEmitter>,
+    ) -> Option<&mut ParticleEmitter> {
+        self.request_redraw();
+        self.emitters.get_mut(handle)
+    }
+
+    // Simulates every emitter with the frame time and uploads the particles of the image
+    fn update_emitters(
+        &mut self,
+        allocator: &mut Allocator,
+        image_index: usize,
+        camera: &Camera,
+    ) -> RendererResult<()> {
+        let dt = self.frame_delta.as_secs_f32();
+        let camera_position = camera.get_position();
+        let mut draw_order = vec![];
+        for (handle, emitter) in self.emitters.iter_mut_with_handles() {
+            let config = emitter.config();
+            // Emitters of removed objects stay where the object was
+            let origin = match config
+                .attached_to
+                .and_then(|object| self.scene_tree.get_object(object))
+            {
+                Some(object) => {
+                    let transform = object.get_global_transform();
+                    glm::vec3(transform[(0, 3)], transform[(1, 3)], transform[(2, 3)])
+                        + config.offset
+                }
+                None if config.attached_to.is_some() => emitter.origin(),
+                None => config.offset,
+            };
+            emitter.simulate(dt, origin);
+            let instances = emitter.instances(&camera_position);
+            if !instances.is_empty() {
+                let capacity = emitter.config().capacity.max(1);
+                emitter.instance_buffer.copy_to_offset(
+                    allocator,
+                    &instances,
+                    image_index * capacity * std::mem::size_of::<ParticleInstance>(),
+                )?;
+            }
+            emitter.instance_counts[image_index] = instances.len() as u32;
+            let distance = glm::distance(&origin, &camera_position);
+            draw_order.push((DrawKey::transparent(distance), handle));
+        }
+        draw_order.sort_by_key(|(key, _)| *key);
+        self.emitter_draw_order = draw_order;
+        Ok(())
+    }
+
+    // The set 3 of the particle pipelines for the image, the depth its scene render pass
+    // draws with. It is written while the command buffer of the image is recorded, when no
+    // submitted frame uses it.
+    fn particle_depth_set(
+        &mut self,
+        image_index: usize,
+        material: Handle<Material>,
+    ) -> RendererResult<vk::DescriptorSet> {
+        let template = self.material_system.get_effect_template_by_handle(
+            self.material_system
+                .get_material_by_handle(material)?
+                .original,
+        )?;
+        let effect_handle = template.pass_shaders[MeshPassType::Transparency]
+            .effect_handle
+            .ok_or(InvalidHandle)?;
+        let layout = self
+            .shader_cache
+            .get_shader_effect_by_handle(effect_handle)?
+            .set_layouts[3];
+        while self.particle_depth_sets.len() <= image_index {
+            let set = self
+                .descriptor_allocator
+                .allocate(&self.context.device, layout)?;
+            self.particle_depth_sets.push(set);
+        }
+        let set = self.particle_depth_sets[image_index];
+        let image_info = [vk::DescriptorImageInfo {
+            sampler: vk::Sampler::null(),
+            image_view: self
+                .scene_target(image_index)
+                .depth_image_view
+                .expect("Scene targets have a depth image"),
+            image_layout: vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL,
+        }];
+        let write = vk::WriteDescriptorSet::builder()
+            .dst_set(set)
+            .dst_binding(0)
+            .descriptor_type(vk::DescriptorType::INPUT_ATTACHMENT)
+            .image_info(&image_info)
+            .build();
+        unsafe {
+            self.context.device.update_descriptor_sets(&[write], &[]);
+        }
+        Ok(set)
+    }
+
+    // Set 0 of the composite of weighted blended transparency, see
+    // MaterialSystem::build_oit_composite_template
+    fn oit_composite_layout(
+        material_system: &MaterialSystem,
+        shader_cache: &ShaderCache,
+    ) -> RendererResult<vk::DescriptorSetLayout> {
+        let template = material_system.get_effect_template_by_handle(
+            material_system.get_effect_template_handle(OIT_COMPOSITE_TEMPLATE)?,
+        )?;
+        let effect_handle = template.pass_shaders[MeshPassType::Transparency]
+            .effect_handle
+            .ok_or(InvalidHandle)?;
+        Ok(shader_cache
+            .get_shader_effect_by_handle(effect_handle)?
+            .set_layouts[0])
+    }
+
+    // The composite set for the image, with the accumulation and revealage its scene
+    // render pass draws with. Written like particle_depth_set.
+    fn oit_composite_set(&mut self, image_index: usize) -> RendererResult<vk::DescriptorSet> {
+        while self.oit_composite_sets.len() <= image_index {
+            let layout = Self::oit_composite_layout(&self.material_system, &self.shader_cache)?;
+            let set = self
+                .descriptor_allocator
+                .allocate(&self.context.device, layout)?;
+            self.oit_composite_sets.push(set);
+        }
+        let set = self.oit_composite_sets[image_index];
+        self.write_oit_composite_set(set, self.scene_target(image_index));
+        Ok(set)
+    }
+
+    fn write_oit_composite_set(&self, set: vk::DescriptorSet, target: &RenderTarget) {
+        let image_infos = target
+            .oit_image_views
+            .iter()
+            .map(|view| vk::DescriptorImageInfo {
+                sampler: vk::Sampler::null(),
+                image_view: *view,
+                image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            })
+            .collect::<Vec<_>>();
+        let writes = image_infos
+            .iter()
+            .enumerate()
+            .map(|(binding, info)| {
+                vk::WriteDescriptorSet::builder()
+                    .dst_set(set)
+                    .dst_binding(binding as u32)
+                    .descriptor_type(vk::DescriptorType::INPUT_ATTACHMENT)
+                    .image_info(std::slice::from_ref(info))
+                    .build()
+            })
+            .collect::<Vec<_>>();
+        unsafe {
+            self.context.device.update_descriptor_sets(&writes, &[]);
+        }
+    }
+
+    // The transparent objects accumulated into the attachments of set over the color, in
+    // the last subpass of a scene render pass
+    fn draw_oit_composite(
+        &self,
+        cmd_buf: vk::CommandBuffer,
+        extent: vk::Extent2D,
+        set: vk::DescriptorSet,
+    ) -> RendererResult<()> {
+        let template = self.material_system.get_effect_template_by_handle(
+            self.material_system
+                .get_effect_template_handle(OIT_COMPOSITE_TEMPLATE)?,
+        )?;
+        let pass = &template.pass_shaders[MeshPassType::Transparency];
+        let viewports = [vk::Viewport {
+            x: 0.,
+            y: 0.,
+            width: extent.width as f32,
+            height: extent.height as f32,
+            min_depth: 0.,
+            max_depth: 1.,
+        }];
+        let scissors = [vk::Rect2D {
+            offset: vk::Offset2D { x: 0, y: 0 },
+            extent,
+        }];
+        let device = &self.context.device;
+        unsafe {
+            device.cmd_bind_pipeline(cmd_buf, vk::PipelineBindPoint::GRAPHICS, pass.pipeline);
+            device.cmd_set_viewport(cmd_buf, 0, &viewports);
+            device.cmd_set_scissor(cmd_buf, 0, &scissors);
+            device.cmd_bind_descriptor_sets(
+                cmd_buf,
+                vk::PipelineBindPoint::GRAPHICS,
+                pass.layout,
+                0,
+                &[set],
+                &[],
+            );
+            device.cmd_draw(cmd_buf, 3, 1, 0, 0);
+        }
+        Ok(())
+    }
+
+    // The billboards of the emitter in the transparency subpass, faded out in front of
+    // the opaque scene in depth_set, see EmitterConfig::softness
+    fn draw_emitter(
+        &mut self,
+        cmd_buf: vk::CommandBuffer,
+        image_index: usize,
+        handle: Handle<ParticleEmitter>,
+        depth_set: vk::DescriptorSet,
+    ) -> RendererResult<()> {
+        let camera_buffer_offset = image_index * self.global_uniform_stride;
+        let extent = self.get_internal_extent();
+        let viewports = [vk::Viewport {
+            x: 0.,
+            y: 0.,
+            width: extent.width as f32,
+            height: extent.height as f32,
+            min_depth: 0.,
+            max_depth: 1.,
+        }];
+        let scissors = [vk::Rect2D {
+            offset: vk::Offset2D { x: 0, y: 0 },
+            extent,
+        }];
+        let device = &self.context.device;
+        let emitter = match self.emitters.get(handle) {
+            Some(emitter) if emitter.instance_counts[image_index] > 0 => emitter,
+            _ => return Ok(()),
+        };
+        let material = self
+            .material_system
+            .get_material_by_handle(emitter.material)?;
+        let template = self
+            .material_system
+            .get_effect_template_by_handle(material.original)?;
+        let pass = &template.pass_shaders[MeshPassType::Transparency];
+        let capacity = emitter.config().capacity.max(1);
+        let offset = (image_index * capacity * std::mem::size_of::<ParticleInstance>()) as u64;
+        let softness = emitter.config().softness.max(0.0);
+        unsafe {
+            device.cmd_bind_pipeline(cmd_buf, vk::PipelineBindPoint::GRAPHICS, pass.pipeline);
+            device.cmd_set_viewport(cmd_buf, 0, &viewports);
+            device.cmd_set_scissor(cmd_buf, 0, &scissors);
+            device.cmd_bind_descriptor_sets(
+                cmd_buf,
+                vk::PipelineBindPoint::GRAPHICS,
+                pass.layout,
+                0,
+                &[
+                    self.descriptor_set_camera,
+                    self.descriptor_set_lights,
+                    material.pass_sets[MeshPassType::Transparency],
+                    depth_set,
+                ],
+                &[camera_buffer_offset as u32],
+            );
+            device.cmd_push_constants(
+                cmd_buf,
+                pass.layout,
+                vk::ShaderStageFlags::FRAGMENT,
+                0,
+                &softness.to_ne_bytes(),
+            );
+            device.cmd_bind_vertex_buffers(
+                cmd_buf,
+                0,
+                &[emitter.instance_buffer.get_buffer().buffer],
+                &[offset],
+            );
+            device.cmd_draw(cmd_buf, 6, emitter.instance_counts[image_index], 0, 0);
+        }
+        self.frame_stats.pipeline_binds += 1;
+        self.frame_stats.draw_calls += 1;
+        self.frame_stats.triangles += 2 * emitter.instance_counts[image_index] as u64;
+        Ok(())
+    }
+
+    // Only updates the uniform data, takes effect on the next frame
+    pub fn set_fog(&mut self, fog: FogConfig) {
+        self.request_redraw();
+        self.fog = fog;
+    }
+
+    pub fn get_fog(&self) -> &FogConfig {
+        &self.fog
+    }
+
+    // The radiance behind the scene, tone mapped like the rest of it. Fog applied to the
+    // background takes precedence.
+    pub fn set_clear_color(&mut self, color: Color) {
+        self.request_redraw();
+        self.clear_color = color;
+    }
+
+    pub fn get_clear_color(&self) -> Color {
+        self.clear_color
+    }
+
+    fn background_color(&self) -> [f32; 4] {
+        self.fog
+            .background_color()
+            .unwrap_or_else(|| self.clear_color.to_linear_rgba())
+    }
+
+    // For views with SHADER_TONE_MAPPING, same as the shaders
+    fn tone_mapped_background_color(&self) -> [f32; 4] {
+        let [r, g, b, a] = self.background_color();
+        let tone_map = |c: f32| c / (1.0 + c);
+        [tone_map(r), tone_map(g), tone_map(b), a]
+    }
+
+    // Draws the six files around the scene, in the order +X, -X, +Y, -Y, +Z, -Z, see
+    // cubemap.rs. A skybox set before is replaced and its cubemap destroyed once the
+    // frames using it are done.
+    pub fn set_skybox_from_files<P: AsRef<Path>>(&mut self, paths: [P; 6]) -> RendererResult<()> {
+        let cubemap = self.resources()?.new_cubemap_from_files(&paths)?;
+        self.clear_skybox()?;
+        let material = self.build_material(
+            "skybox",
+            MaterialData {
+                textures: HashMap::from([(MaterialTextureSlot::Albedo, cubemap)]),
+                buffers: vec![],
+                parameters: ShaderParameters::default(),
+                base_template: "skybox".to_string(),
+            },
+        )?;
+        self.skybox = Some(Skybox { cubemap, material });
+        Ok(())
+    }
+
+    // Back to the clear color
+    pub fn clear_skybox(&mut self) -> RendererResult<()> {
+        self.request_redraw();
+        if let Some(skybox) = self.skybox.take() {
+            self.material_system.remove_material("skybox")?;
+            self.texture_storage
+                .queue_destroy(skybox.cubemap, self.timeline.last_submitted())?;
+        }
+        Ok(())
+    }
+
+    pub fn has_skybox(&self) -> bool {
+        self.skybox.is_some()
+    }
+
+    // First in the forward pass, with the camera at camera_buffer_offset
+    fn draw_skybox(
+        &mut self,
+        cmd_buf: vk::CommandBuffer,
+        extent: vk::Extent2D,
+        camera_buffer_offset: usize,
+    ) -> RendererResult<()> {
+        let skybox = match self.skybox.as_ref() {
+            Some(skybox) => skybox,
+            None => return Ok(()),
+        };
+        let material = self
+            .material_system
+            .get_material_by_handle(skybox.material)?;
+        let template = self
+            .material_system
+            .get_effect_template_by_handle(material.original)?;
+        let pass = &template.pass_shaders[MeshPassType::Forward];
+        let viewports = [vk::Viewport {
+            x: 0.,
+            y: 0.,
+            width: extent.width as f32,
+            height: extent.height as f32,
+            min_depth: 0.,
+            max_depth: 1.,
+        }];
+        let scissors = [vk::Rect2D {
+            offset: vk::Offset2D { x: 0, y: 0 },
+            extent,
+        }];
+        let device = &self.context.device;
+        unsafe {
+            device.cmd_bind_pipeline(cmd_buf, vk::PipelineBindPoint::GRAPHICS, pass.pipeline);
+            device.cmd_set_viewport(cmd_buf, 0, &viewports);
+            device.cmd_set_scissor(cmd_buf, 0, &scissors);
+            device.cmd_bind_descriptor_sets(
+                cmd_buf,
+                vk::PipelineBindPoint::GRAPHICS,
+                pass.layout,
+                0,
+                &[
+                    self.descriptor_set_camera,
+                    self.descriptor_set_lights,
+                    material.pass_sets[MeshPassType::Forward],
+                ],
+                &[camera_buffer_offset as u32],
+            );
+            device.cmd_draw(cmd_buf, 36, 1, 0, 0);
+        }
+        self.frame_stats.pipeline_binds += 1;
+        self.frame_stats.draw_calls += 1;
+        self.frame_stats.triangles += 12;
+        Ok(())
+    }
+
+    // The time since the last frame, which particles and exposure advance by. With winit
+    // every batch of window events starts a frame, without it every frame rendered with
+    // render_without_ui or FrameRecorder::end_without_ui does.
+    fn advance_frame_clock(&mut self) {
+        let now = Instant::now();
+        self.frame_delta = now - self.last_frame;
+        self.imgui.io_mut().update_delta_time(self.frame_delta);
+        self.last_frame = now;
+    }
+
+    // Unfocused or occluded (occlusion is only reported on some platforms)
+    pub fn is_in_background(&self) -> bool {
+        !self.focused || self.occluded
+    }
+
+    #[cfg(feature = "winit")]
+    fn log_background_change(&self, was_in_background: bool) {
+        match (was_in_background, self.is_in_background()) {
+            (false, true) => info!(
+                "Entering background mode: {:?}",
+                self.config.background_behavior
+            ),
+            (true, false) => info!("Leaving background mode"),
+            _ => {}
+        }
+    }
+
+    // The extent is chosen by the surface and can differ from the requested size
+    // (e.g. with fractional scaling), so use the returned one for the camera aspect.
+    // A zero sized extent keeps the old swapchain and stops rendering until the next resize.
+    pub fn recreate_swapchain(&mut self, width: u32, height: u32) -> RendererResult<vk::Extent2D> {
+        self.recreate_swapchain_with(width, height, None)
+    }
+
+    // Recreates a headless swapchain with image_count images, like a window's swapchain
+    // can come back with another count after the surface changed. Exercises the resizing
+    // of everything that has a part per image.
+    pub fn set_headless_image_count(&mut self, image_count: u32) -> RendererResult<()> {
+        if !self.swapchain.is_headless() {
+            return Err(FrameError::from(
+                "The image count of a window's swapchain is up to the driver".to_string(),
+            )
+            .into());
+        }
+        let extent = self.swapchain.get_extent();
+        self.recreate_swapchain_with(extent.width, extent.height, Some(image_count.max(1)))?;
+        Ok(())
+    }
+
+    // Headless swapchains keep their image count unless headless_image_count is given
+    fn recreate_swapchain_with(
+        &mut self,
+        width: u32,
+        height: u32,
+        headless_image_count: Option<u32>,
+    ) -> RendererResult<vk::Extent2D> {
+        self.drop_pending_frame()?;
+        unsafe {
+            self.context.device.device_wait_idle()?;
+        }
+        // The framebuffers and targets the recorded frames use are replaced
+        self.recorded_frames.invalidate();
+        let headless = self.swapchain.is_headless();
+        let extent = if headless {
+            vk::Extent2D { width, height }
+        } else {
+            self.context.refresh_surface_data()?;
+            swapchain::choose_extent(&self.context.surface_capabilities, width, height)
+        };
+        if swapchain::is_zero_extent(extent) {
+            self.surface_ready = false;
+            return Ok(extent);
+        }
+        let old_image_count = self.swapchain.get_actual_image_count();
+        if let Ok(mut allo) = lock_allocator(&self.allocator) {
+            self.swapchain.destroy(&self.context, allo.deref_mut());
+            self.swapchain = if headless {
+                Swapchain::new_headless(
+                    &self.context,
+                    allo.deref_mut(),
+                    width,
+                    height,
+                    &self.render_pass,
+                    self.capabilities.msaa_samples,
+                    self.capabilities.transparency,
+                    headless_image_count.unwrap_or(old_image_count),
+                )?
+            } else {
+                Swapchain::new(
+                    &self.context,
+                    allo.deref_mut(),
+                    self.swapchain.get_image_format(),
+                    width,
+                    height,
+                    self.output_render_pass
+                        .as_ref()
+                        .unwrap_or(&self.render_pass),
+                    self.output_render_pass
+                        .is_none()
+                        .then_some(self.capabilities.msaa_samples),
+                    self.capabilities.transparency,
+                    self.config.present_mode,
+                )?
+            };
+        } else {
+            return Err(AllocatorUnavailable.into());
+        }
+        if self.output_render_pass.is_none() {
+            self.swapchain
+                .add_overlay_framebuffers(&self.context, &self.overlay_render_pass)?;
+        }
+        let image_count = self.swapchain.get_actual_image_count();
+        if image_count != old_image_count {
+            info!(
+                "The swapchain has {} images instead of {}",
+                image_count, old_image_count
+            );
+            self.resize_per_image_resources(image_count as usize)?;
+        }
+        self.frame_events |= FrameEvents::SWAPCHAIN_RECREATED;
+        self.present_mode_changed = false;
+        self.surface_ready = true;
+        self.update_composite_target()?;
+        self.update_scaled_target()?;
+        let extent = self.swapchain.get_extent();
+        self.cursor_tracker
+            .resized(glm::vec2(extent.width as f32, extent.height as f32));
+        Ok(extent)
+    }
+
+    // Everything with a part per swapchain image follows a new image count. Nothing may be
+    // in flight. Buffers only grow, with fewer images their end stays unused.
+    fn resize_per_image_resources(&mut self, image_count: usize) -> RendererResult<()> {
+        unsafe {
+            self.context
+                .device
+                .free_command_buffers(self.graphics_command_pool, &self.command_buffers);
+            self.context
+                .device
+                .free_command_buffers(self.graphics_command_pool, &self.overlay_command_buffers);
+        }
+        let command_buffer_allocate_info = vk::CommandBufferAllocateInfo::builder()
+            .command_pool(self.graphics_command_pool)
+            .command_buffer_count(image_count as u32);
+        self.command_buffers = unsafe {
+            self.context
+                .device
+                .allocate_command_buffers(&command_buffer_allocate_info)?
+        };
+        self.overlay_command_buffers = unsafe {
+            self.context
+                .device
+                .allocate_command_buffers(&command_buffer_allocate_info)?
+        };
+        for (i, command_buffer) in self.command_buffers.iter().enumerate() {
+            self.context
+                .debug_namer
+                .name(*command_buffer, &format!("graphics cb image {}", i));
+        }
+        for (i, command_buffer) in self.overlay_command_buffers.iter().enumerate() {
+            self.context
+                .debug_namer
+                .name(*command_buffer, &format!("overlay cb image {}", i));
+        }
+        self.timeline.set_image_count(image_count);
+        self.recorded_frames.set_image_count(image_count);
+        self.instance_batches.set_frames(image_count)?;
+
+        if let Ok(mut allo) = lock_allocator(&self.allocator) {
+            let allo = allo.deref_mut();
+            let uniform_size = (self.global_uniform_stride * image_count) as u64;
+            if self.uniform_buffer.ensure_capacity(allo, uniform_size)? {
+                let buffer_info = [vk::DescriptorBufferInfo::builder()
+                    .buffer(self.uniform_buffer.get_buffer().buffer)
+                    .range(GLOBAL_UNIFORM_SIZE as u64)
+                    .build()];
+                let descriptor_write = vk::WriteDescriptorSet::builder()
+                    .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC)
+                    .dst_binding(0)
+                    .dst_set(self.descriptor_set_camera)
+                    .buffer_info(&buffer_info[..]);
+                unsafe {
+                    self.context
+                        .device
+                        .update_descriptor_sets(&[*descriptor_write], &[]);
+                }
+            }
+            if let Some(buffer) = self.cursor_vertex_buffer.as_mut() {
+                let bytes = 6 * image_count * std::mem::size_of::<TextVertexData>();
+                buffer.ensure_capacity(allo, bytes as u64)?;
+            }
+            if let Some(resources) = self.gizmo_resources.as_mut() {
+                let bytes =
+                    GIZMO_MAX_VERTICES * image_count * std::mem::size_of::<TextVertexData>();
+                resources
+                    .vertex_buffer
+                    .ensure_capacity(allo, bytes as u64)?;
+                resources.vertex_counts = vec![0; image_count];
+            }
+            for emitter in self.emitters.iter_mut() {
+                let bytes = emitter.config().capacity.max(1)
+                    * image_count
+                    * std::mem::size_of::<ParticleInstance>();
+                emitter
+                    .instance_buffer
+                    .ensure_capacity(allo, bytes as u64)?;
+                emitter.instance_counts = vec![0; image_count];
+            }
+        } else {
+            return Err(AllocatorUnavailable.into());
+        }
+        Ok(())
+    }
+
+    // The image of an abandoned frame can't be carried over to a new swapchain, so the
+    // wait on its acquire semaphore is submitted without any work, which resets the
+    // semaphore. Headless images are acquired without it.
+    fn drop_pending_frame(&mut self) -> RendererResult<()> {
+        if self.pending_frame.take().is_some() && !self.swapchain.is_headless() {
+            let semaphores = [self.frame_data[self.current_image].image_available_semaphore];
+            let stages = [vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT];
+            let submit_info = vk::SubmitInfo::builder()
+                .wait_semaphores(&semaphores)
+                .wait_dst_stage_mask(&stages)
+                .build();
+            unsafe {
+                self.context.device.queue_submit(
+                    self.context.graphics_queue.queue,
+                    &[submit_info],
+                    vk::Fence::null(),
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    // Takes effect with the next frame, the swapchain is recreated for it. Kept across
+    // resizes.
+    pub fn set_present_mode(&mut self, mode: PresentModePreference) {
+        if self.config.present_mode != mode {
+            self.config.present_mode = mode;
+            self.present_mode_changed = true;
+            self.request_redraw();
+        }
+    }
+
+    pub fn get_present_mode(&self) -> PresentModePreference {
+        self.config.present_mode
+    }
+
+    // The mode the swapchain presents with, after falling back from the preference
+    pub fn present_mode(&self) -> vk::PresentModeKHR {
+        self.swapchain.get_present_mode()
+    }
+
+    // Only the scaled target is reallocated, the swapchain stays as it is
+    pub fn set_render_scale(&mut self, scale: f32) -> RendererResult<()> {
+        self.request_redraw();
+        self.config.render_scale = scale.clamp(MIN_RENDER_SCALE, 1.0);
+        self.update_scaled_target()
+    }
+
+    pub fn get_render_scale(&self) -> f32 {
+        self.config.render_scale
+    }
+
+    // The resolution the scene is rendered at, the swapchain extent times the render scale
+    pub fn get_internal_extent(&self) -> vk::Extent2D {
+        match self.scaled_target.as_ref() {
+            Some(scaled) => vk::Extent2D {
+                width: scaled.target.extent.width,
+                height: scaled.target.extent.height,
+            },
+            None => self.swapchain.get_extent(),
+        }
+    }
+
+    // Creates the scaled target for the current render scale and swapchain extent, or
+    // replaces it when either changed. The scene is always rendered to it, also at full
+    // render scale, since the upscale pass does the tone mapping.
+    fn update_scaled_target(&mut self) -> RendererResult<()> {
+        let scale = self.config.render_scale.clamp(MIN_RENDER_SCALE, 1.0);
+        let swapchain_extent = self.swapchain.get_extent();
+        let wanted = vk::Extent2D {
+            width: ((swapchain_extent.width as f32 * scale).round() as u32).max(1),
+            height: ((swapchain_extent.height as f32 * scale).round() as u32).max(1),
+        };
+        let current = self
+            .scaled_target
+            .as_ref()
+            .map(|_| self.get_internal_extent());
+        if current == Some(wanted) {
+            self.update_luminance_pass()?;
+            self.update_bloom_pass()?;
+            return self.rebuild_schedule();
+        }
+
+        // Frames in flight may still render to the old target
+        unsafe {
+            self.context.device.device_wait_idle()?;
+        }
+        let old = self.scaled_target.take();
+        let template = self.config.upscale_filter.template_name();
+        self.scaled_target = Some(self.new_offscreen_target(
+            wanted,
+            self.scene_render_pass,
+            "scaled scene",
+            old.as_ref(),
+            "upscale",
+            template,
+        )?);
+        if let Some(old) = old {
+            self.destroy_offscreen_target(old)?;
+        }
+        self.update_velocity_target()?;
+        self.update_luminance_pass()?;
+        self.update_bloom_pass()?;
+        self.rebuild_schedule()
+    }
+
+    // Replaces the velocity target with one at the internal extent, only with
+    // RendererConfig::velocity_target. Materials sampling the old one get the new one.
+    // Nothing may be in flight.
+    fn update_velocity_target(&mut self) -> RendererResult<()> {
+        if !self.config.velocity_target {
+            return Ok(());
+        }
+        if self.velocity_pass.is_none() {
+            self.velocity_pass = Some(VelocityPass::new(
+                &self.context.device,
+                &mut self.material_system,
+                &mut self.shader_cache,
+            )?);
+        }
+        let render_pass = self
+            .velocity_pass
+            .as_ref()
+            .map(|pass| pass.render_pass)
+            .expect("The velocity pass was just created");
+        let extent = self.get_internal_extent();
+        let (texture, target) = if let Ok(mut allo) = lock_allocator(&self.allocator) {
+            let texture = self.texture_storage.new_color_target(
+                extent,
+                VELOCITY_FORMAT,
+                &[],
+                &self.context.device,
+                allo.deref_mut(),
+            )?;
+            let image = self
+                .texture_storage
+                .get_texture(texture)
+                .ok_or::<RendererError>(InvalidHandle.into())?
+                .image();
+            let target = RenderTarget::new_from_image(
+                &self.context,
+                allo.deref_mut(),
+                image,
+                VELOCITY_FORMAT,
+                extent,
+                &render_pass,
+                vk::SampleCountFlags::TYPE_1,
+                TransparencyTechnique::SortedAlpha,
+            )?;
+            (texture, target)
+        } else {
+            return Err(AllocatorUnavailable.into());
+        };
+        target.set_debug_name(&self.context, "velocity");
+        if let Some(mut old) = self.velocity_target.take() {
+            self.material_system.replace_texture(
+                &self.context.device,
+                &self.texture_storage,
+                old.texture,
+                texture,
+            )?;
+            if let Ok(mut allo) = lock_allocator(&self.allocator) {
+                old.target.destroy(&self.context, allo.deref_mut());
+                self.texture_storage.remove_texture(
+                    old.texture,
+                    &self.context.device,
+                    allo.deref_mut(),
+                )?;
+            }
+        }
+        self.velocity_target = Some(VelocityTarget {
+            texture,
+            target,
+            rendered: false,
+        });
+        Ok(())
+    }
+
+    // The screen space motion of the last rendered frame in VELOCITY_FORMAT, see
+    // velocity.rs. None without RendererConfig::velocity_target. The handle changes
+    // with the internal extent, materials built with it follow on their own.
+    pub fn velocity_texture(&self) -> Option<Handle<Texture>> {
+        self.velocity_target.as_ref().map(|target| target.texture)
+    }
+
+    // The composite target has the swapchain extent and only exists with an output pass.
+    // Nothing may be in flight.
+    fn update_composite_target(&mut self) -> RendererResult<()> {
+        if self.output_render_pass.is_none() {
+            return Ok(());
+        }
+        let old = self.composite_target.take();
+        let mut target = self.new_offscreen_target(
+            self.swapchain.get_extent(),
+            self.render_pass,
+            "composite",
+            old.as_ref(),
+            "output",
+            "output_encode",
+        )?;
+        target
+            .target
+            .add_overlay_framebuffer(&self.context, &self.overlay_render_pass)?;
+        if let Some(framebuffer) = target.target.overlay_framebuffer {
+            self.context
+                .debug_namer
+                .name(framebuffer, "composite overlay framebuffer");
+        }
+        self.composite_target = Some(target);
+        if let Some(old) = old {
+            self.destroy_offscreen_target(old)?;
+        }
+        Ok(())
+    }
+
+    // A target for render_pass, in HDR_WORKING_FORMAT for the scene render pass and the
+    // working format otherwise. The material replacing old keeps its handle and gets the
+    // new texture, without old it is built from the template.
+    fn new_offscreen_target(
+        &mut self,
+        extent: vk::Extent2D,
+        render_pass: vk::RenderPass,
+        debug_name: &str,
+        old: Option<&OffscreenTarget>,
+        material_name: &str,
+        template: &str,
+    ) -> RendererResult<OffscreenTarget> {
+        let format = if render_pass == self.scene_render_pass {
+            capabilities::HDR_WORKING_FORMAT
+        } else {
+            self.capabilities.working_format
+        };
+        // The scene color is read by the async compute passes while the graphics queue
+        // upscales it
+        let queue_families = match self.async_compute.as_ref() {
+            Some(async_compute) if render_pass == self.scene_render_pass => {
+                async_compute.sharing_families().to_vec()
+            }
+            _ => vec![],
+        };
+        let (texture, target) = if let Ok(mut allo) = lock_allocator(&self.allocator) {
+            let texture = self.texture_storage.new_color_target(
+                extent,
+                format,
+                &queue_families,
+                &self.context.device,
+                allo.deref_mut(),
+            )?;
+            let image = self
+                .texture_storage
+                .get_texture(texture)
+                .ok_or::<RendererError>(InvalidHandle.into())?
+                .image();
+            let target = RenderTarget::new_from_image(
+                &self.context,
+                allo.deref_mut(),
+                image,
+                format,
+                extent,
+                &render_pass,
+                self.capabilities.msaa_samples,
+                self.capabilities.transparency,
+            )?;
+            (texture, target)
+        } else {
+            return Err(AllocatorUnavailable.into());
+        };
+        target.set_debug_name(&self.context, debug_name);
+        let material = match old {
+            Some(old) => {
+                self.material_system.replace_texture(
+                    &self.context.device,
+                    &self.texture_storage,
+                    old.texture,
+                    texture,
+                )?;
+                old.material
+            }
+            None => self.build_material(
+                material_name,
+                MaterialData {
+                    textures: HashMap::from([(MaterialTextureSlot::Albedo, texture)]),
+                    buffers: vec![],
+                    parameters: ShaderParameters::default(),
+                    base_template: template.to_string(),
+                },
+            )?,
+        };
+        Ok(OffscreenTarget {
+            texture,
+            target,
+            material,
+        })
+    }
+
+    fn destroy_offscreen_target(&mut self, mut target: OffscreenTarget) -> RendererResult<()> {
+        if let Ok(mut allo) = lock_allocator(&self.allocator) {
+            target.target.destroy(&self.context, allo.deref_mut());
+            self.texture_storage.remove_texture(
+                target.texture,
+                &self.context.device,
+                allo.deref_mut(),
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn get_capabilities(&self) -> &RendererCapabilities {
+        &self.capabilities
+    }
+
+    // The samples per pixel actually used, RendererConfig::msaa_samples can ask for more
+    // than the device supports
+    pub fn msaa_samples(&self) -> u32 {
+        self.capabilities.msaa_samples.as_raw()
+    }
+
+    // Whether RendererConfig::async_compute found a compute queue to use
+    pub fn uses_async_compute(&self) -> bool {
+        self.async_compute.is_some()
+    }
+
+    // Brightness of SDR white and the display's peak for HDR swapchains, takes effect
+    // with the next frame
+    pub fn get_settings(&self) -> RendererSettings {
+        RendererSettings::from_config(&self.config)
+    }
+
+    // Clamps the settings and changes only what differs from the current ones, with
+    // every operation done once, see plan_settings
+    pub fn apply_settings(&mut self, settings: &RendererSettings) -> RendererResult<ApplyReport> {
+        let (settings, warnings) = settings.clamped();
+        for warning in warnings.iter() {
+            warn!("{}", warning);
+        }
+        let operations = plan_settings(&self.get_settings(), &settings);
+        self.request_redraw();
+        for operation in operations.iter() {
+            match operation {
+                SettingsOperation::SetBackgroundBehavior => {
+                    self.config.background_behavior = settings.background_behavior;
+                }
+                SettingsOperation::SetRedrawPolicy => {
+                    self.set_redraw_policy(settings.redraw_policy);
+                }
+                SettingsOperation::ReblendProbes => {
+                    self.config.probe_blend = settings.probe_blend;
+                    self.scene_tree.set_light_probes(
+                        self.light_probes
+                            .iter()
+                            .map(|probe| probe.position.coords)
+                            .collect(),
+                        settings.probe_blend,
+                    );
+                }
+                SettingsOperation::UpdateOutputEncoding => {
+                    self.set_hdr_nits(settings.hdr_paper_white_nits, settings.hdr_peak_nits);
+                }
+                SettingsOperation::RebuildScaledTarget => {
+                    self.config.render_scale = settings.render_scale;
+                    self.config.auto_exposure =
+                        settings.auto_exposure_config(self.config.auto_exposure);
+                    self.update_scaled_target()?;
+                }
+            }
+        }
+        Ok(ApplyReport {
+            operations,
+            warnings,
+        })
+    }
+
+    pub fn set_hdr_nits(&mut self, paper_white_nits: f32, peak_nits: f32) {
+        self.request_redraw();
+        self.config.hdr_paper_white_nits = paper_white_nits;
+        self.config.hdr_peak_nits = peak_nits;
+        if self.capabilities.output_encoding.is_some() {
+            self.capabilities.output_encoding = Some(capabilities::output_encoding(
+                self.capabilities.color_space,
+                &self.config,
+            ));
+        }
+    }
+
+    // Creates the luminance pass the first time auto exposure is on and points it at the
+    // scaled target
+    fn update_luminance_pass(&mut self) -> RendererResult<()> {
+        let texture = match (&self.config.auto_exposure, &self.scaled_target) {
+            (Some(_), Some(scaled)) => scaled.texture,
+            _ => {
+                if let Some(pass) = self.luminance_pass.as_mut() {
+                    pass.clear();
+                }
+                self.scene_luminance = None;
+                self.exposure = 1.0;
+                return Ok(());
+            }
+        };
+        if self.luminance_pass.is_none() {
+            let pass = if let Ok(mut allo) = lock_allocator(&self.allocator) {
+                LuminancePass::new(
+                    &self.context.device,
+                    allo.deref_mut(),
+                    self.buffer_manager.clone(),
+                    &mut self.shader_cache,
+                    &mut self.descriptor_allocator,
+                    FRAMES_IN_FLIGHT,
+                )?
+            } else {
+                return Err(AllocatorUnavailable.into());
+            };
+            self.luminance_pass = Some(pass);
+        }
+        let view = self
+            .texture_storage
+            .get_texture(texture)
+            .ok_or::<RendererError>(InvalidHandle.into())?
+            .image_view;
+        // The descriptor sets are rewritten in place
+        self.timeline.wait_for(self.timeline.last_submitted())?;
+        if let Some(pass) = self.luminance_pass.as_mut() {
+            pass.set_source(&self.context.device, view);
+        }
+        Ok(())
+    }
+
+    pub fn set_auto_exposure(&mut self, config: Option<AutoExposureConfig>) -> RendererResult<()> {
+        self.request_redraw();
+        self.config.auto_exposure = config;
+        self.update_scaled_target()
+    }
+
+    // The average luminance of the scene before exposure, measured FRAMES_IN_FLIGHT
+    // frames ago. None while auto exposure is off and until the first measurement is
+    // read back.
+    pub fn scene_luminance(&self) -> Option<f32> {
+        self.scene_luminance
+    }
+
+    // What the scene color is multiplied by before it is shown, 1 without auto exposure
+    pub fn get_exposure(&self) -> f32 {
+        self.exposure
+    }
+
+    // Takes effect with the next frame, the pipelines stay as they are
+    pub fn set_tone_mapping(&mut self, tone_mapping: ToneMapping) {
+        self.request_redraw();
+        self.config.tone_mapping = tone_mapping;
+    }
+
+    pub fn get_tone_mapping(&self) -> ToneMapping {
+        self.config.tone_mapping
+    }
+
+    // Creates the bloom pass the first time bloom is on, and the chain for the scaled
+    // target whenever its size or the number of levels changed
+    fn update_bloom_pass(&mut self) -> RendererResult<()> {
+        let scene_texture = match (self.config.bloom.enabled(), &self.scaled_target) {
+            (true, Some(scaled)) => scaled.texture,
+            _ => return Ok(()),
+        };
+        if self.bloom_pass.is_none() {
+            self.bloom_pass = Some(BloomPass::new(
+                &self.context.device,
+                &mut self.shader_cache,
+                &mut self.descriptor_allocator,
+            )?);
+        }
+        let scene_extent = self.get_internal_extent();
+        let extent = bloom::bloom_extent(scene_extent);
+        let levels = bloom::bloom_level_count(extent, self.config.bloom.levels);
+        let scene_view = self
+            .texture_storage
+            .get_texture(scene_texture)
+            .ok_or::<RendererError>(InvalidHandle.into())?
+            .image_view;
+        let current = self
+            .bloom_pass
+            .as_ref()
+            .map(|pass| pass.is_target(scene_view, extent, levels));
+        if self.bloom_chain.is_some() && current == Some(true) {
+            return Ok(());
+        }
+
+        // Frames in flight may still blur into the old chain
+        unsafe {
+            self.context.device.device_wait_idle()?;
+        }
+        let format = capabilities::HDR_WORKING_FORMAT;
+        let chain = if let Ok(mut allo) = lock_allocator(&self.allocator) {
+            self.texture_storage.new_storage_chain(
+                extent,
+                format,
+                levels,
+                &self.context.device,
+                allo.deref_mut(),
+            )?
+        } else {
+            return Err(AllocatorUnavailable.into());
+        };
+        let chain_image = self
+            .texture_storage
+            .get_texture(chain)
+            .ok_or::<RendererError>(InvalidHandle.into())?
+            .image();
+        if let Some(pass) = self.bloom_pass.as_mut() {
+            pass.set_target(
+                &self.context.device,
+                scene_view,
+                chain_image,
+                format,
+                extent,
+                levels,
+            )?;
+        }
+        match self.bloom_chain.replace(chain) {
+            Some(old) => {
+                self.material_system.replace_texture(
+                    &self.context.device,
+                    &self.texture_storage,
+                    old,
+                    chain,
+                )?;
+                if let Ok(mut allo) = lock_allocator(&self.allocator) {
+                    self.texture_storage.remove_texture(
+                        old,
+                        &self.context.device,
+                        allo.deref_mut(),
+                    )?;
+                }
+            }
+            None => {
+                let material =
+                    self.set_material_texture("upscale", MaterialTextureSlot::Emissive, chain)?;
+                if let Some(scaled) = self.scaled_target.as_mut() {
+                    scaled.material = material;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Bright parts of the scene glow, in the upscale pass before the exposure and tone
+    // mapping. An intensity of 0 turns it off.
+    pub fn set_bloom(&mut self, config: BloomConfig) -> RendererResult<()> {
+        self.request_redraw();
+        self.config.bloom = config;
+        self.update_bloom_pass()?;
+        self.rebuild_schedule()
+    }
+
+    pub fn set_bloom_intensity(&mut self, intensity: f32) -> RendererResult<()> {
+        self.set_bloom(BloomConfig {
+            intensity: intensity.max(0.0),
+            ..self.config.bloom
+        })
+    }
+
+    pub fn get_bloom(&self) -> BloomConfig {
+        self.config.bloom
+    }
+
+    // Reads the luminance measured by the last frame that used this frame's slot, which
+    // begin_frame already waited for, and moves the exposure toward it
+    fn update_exposure(&mut self) -> RendererResult<()> {
+        let config = match self.config.auto_exposure {
+            Some(config) => config,
+            None => return Ok(()),
+        };
+        if let Some(pass) = self.luminance_pass.as_mut() {
+            if let Some(luminance) = pass.read(self.current_image)? {
+                self.scene_luminance = Some(luminance);
+            }
+        }
+        if let Some(luminance) = self.scene_luminance {
+            let previous = self.exposure;
+            self.exposure = adapt_exposure(
+                self.exposure,
+                luminance,
+                &config,
+                self.frame_delta.as_secs_f32(),
+            );
+            self.exposure_adapting = (self.exposure - previous).abs() > 1e-3 * previous;
+        }
+        Ok(())
+    }
+
+    pub fn get_extent(&self) -> vk::Extent2D {
+        self.swapchain.get_extent()
+    }
+
+    // Counts from the last rendered frame
+    pub fn get_culling_stats(&self) -> CullingStats {
+        self.culling_stats
+    }
+
+    // The objects that can't be drawn with their material and why, as logged when the
+    // scene was last sorted into draws, see check_draw_compatibility
+    pub fn skipped_draws(&self) -> &[(Handle<SceneObject>, String)] {
+        &self.skipped_draws
+    }
+
+    // With culling off every object in the camera's layers is drawn, the shadow pass still
+    // culls against the light
+    pub fn set_frustum_culling(&mut self, enabled: bool) {
+        if self.config.frustum_culling != enabled {
+            self.config.frustum_culling = enabled;
+            self.request_redraw();
+        }
+    }
+
+    pub fn get_frustum_culling(&self) -> bool {
+        self.config.frustum_culling
+    }
+
+    pub fn get_draw_sort_stats(&self) -> DrawSortStats {
+        self.draw_sort_stats
+    }
+
+    // Can be cloned and sent to other threads, which see the stats of the last frame
+    pub fn stats_reader(&self) -> StatsReader {
+        self.stats_reader.clone()
+    }
+
+    pub fn get_stats(&self) -> RendererStats {
+        RendererStats {
+            frame_number: self.frame_number,
+            cpu_frame_time: self.frame_times.percentiles(),
+            gpu_frame_time: self.gpu_frame_times.percentiles(),
+            culling: self.culling_stats,
+            draw_sort: self.draw_sort_stats,
+            object_uploads: self.object_upload_stats,
+            frame: self.last_frame_stats,
+        }
+    }
+
+    // Of the last rendered frame
+    pub fn frame_stats(&self) -> FrameStats {
+        self.last_frame_stats
+    }
+
+    // The GPU time of every pass of the last frame the GPU finished, in the order they
+    // were recorded. That frame lags behind by the frames in flight. Empty without
+    // timestamps on the graphics queue. Passes on the async compute queue are only timed
+    // if it has the same timestamps, see profiler::overlap_ms for how much they overlap.
+    pub fn frame_timings(&self) -> &[PassTiming] {
+        &self.pass_timings
+    }
+
+    pub fn is_surface_ready(&self) -> bool {
+        self.surface_ready
+    }
+
+    // Frees everything that was queued for deletion before the last frame the GPU finished
+    fn free_retired_resources(&mut self) -> RendererResult<()> {
+        let completed = self.timeline.completed()?;
+        if let Ok(mut allo) = lock_allocator(&self.allocator) {
+            self.buffer_manager
+                .lock()
+                .unwrap()
+                .free_queued(allo.deref_mut(), completed);
+            self.texture_storage
+                .destroy_queued(&self.context.device, allo.deref_mut(), completed);
+        }
+        #[cfg(feature = "shader-reload")]
+        self.shader_cache
+            .destroy_retired(&self.context.device, completed);
+        Ok(())
+    }
+
+    // The generation check is cheap and always done, the layout checks only in debug builds
+    fn check_draw_compatibility(
+        &self,
+        material: &Material,
+        template: &EffectTemplate,
+    ) -> Option<String> {
+        if material.template_generation != template.generation {
+            return Some(format!(
+                "material was built for generation {} of the template, which is at {}",
+                material.template_generation, template.generation
+            ));
+        }
+        if !template.user_managed_sets.is_empty()
+            && !self.descriptor_hooks.contains_key(&material.original)
+        {
+            return Some(format!(
+                "sets {:?} are user managed, but the template has no descriptor hook",
+                template.user_managed_sets
+            ));
+        }
+        if cfg!(debug_assertions) {
+            let effect = match template.pass_shaders[template.mesh_pass()]
+                .effect_handle
+                .and_then(|h| self.shader_cache.get_shader_effect_by_handle(h).ok())
+            {
+                Some(effect) => effect,
+                None => return Some("template has no effect for its pass".to_string()),
+            };
+            for (set, hash) in self.global_set_hashes.iter().enumerate() {
+                if effect.get_set_hash(set) != *hash {
+                    return Some(format!(
+                        "set {} layout does not match the bound global set",
+                        set
+                    ));
+                }
+            }
+            if effect.get_set_hash(2) != material.pass_set_hashes[template.mesh_pass()] {
+                return Some("set 2 layout does not match the material".to_string());
+            }
+        }
+        None
+    }
+
+    // Records the passes of the frame before the overlays into the command buffer of the
+    // swapchain image, see record_overlay_commands for the rest. With passes on the async
+    // compute queue the graphics passes after them go into a second command buffer of the
+    // frame, the graphics command buffers are returned in submission order.
+    fn update_command_buffer(
+        &mut self,
+        image_index: usize,
+    ) -> RendererResult<Vec<vk::CommandBuffer>> {
+        let command_buffer_begin_info = vk::CommandBufferBeginInfo::builder();
+        let mut cmd_buf = self.command_buffers[image_index];
+        let mut graphics_command_buffers = vec![cmd_buf];
+        // With an output pass the main render pass draws to the composite target
+        let framebuffer = match self.composite_target.as_ref() {
+            Some(composite) => composite.target.framebuffer,
+            None => self.swapchain.get_render_targets()[image_index].framebuffer,
+        };
+        unsafe {
+            self.context
+                .device
+                .begin_command_buffer(cmd_buf, &command_buffer_begin_info)?;
+        }
+        if let Some(timer) = self.gpu_timer.as_ref() {
+            timer.begin(&self.context.device, cmd_buf, self.current_image);
+        }
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.begin_frame(&self.context.device, cmd_buf, self.current_image);
+        }
+        // Has a render pass of its own, before the ones of the schedule
+        let debug_namer = self.context.debug_namer.clone();
+        debug_namer.begin_label(cmd_buf, "uploads");
+        self.begin_profiler_scope(cmd_buf, "uploads");
+        self.sync_lights(Some(cmd_buf))?;
+        self.material_system
+            .record_parameter_uploads(&self.context.device, cmd_buf)?;
+        self.text.resize(self.swapchain.get_extent());
+        self.text.record_uploads(&self.context.device, cmd_buf);
+        self.end_profiler_scope(cmd_buf);
+        debug_namer.end_label(cmd_buf);
+        // Before every pass that could read what they write
+        debug_namer.begin_label(cmd_buf, "compute");
+        self.begin_profiler_scope(cmd_buf, "compute");
+        self.compute
+            .record(&self.context.device, cmd_buf, image_index)?;
+        self.end_profiler_scope(cmd_buf);
+        debug_namer.end_label(cmd_buf);
+        debug_namer.begin_label(cmd_buf, "shadow");
+        self.begin_profiler_scope(cmd_buf, "shadow");
+        self.record_shadow_pass(cmd_buf, image_index)?;
+        self.end_profiler_scope(cmd_buf);
+        debug_namer.end_label(cmd_buf);
+        if self.velocity_target.is_some() {
+            debug_namer.begin_label(cmd_buf, "velocity");
+            self.begin_profiler_scope(cmd_buf, "velocity");
+            self.record_velocity_pass(cmd_buf, image_index)?;
+            self.end_profiler_scope(cmd_buf);
+            debug_namer.end_label(cmd_buf);
+            if let Some(target) = self.velocity_target.as_mut() {
+                target.rendered = true;
+            }
+        }
+        let clear_values = self.scene_clear_values(self.background_color());
+        let main_pass_begin_info = vk::RenderPassBeginInfo::builder()
+            .render_pass(self.render_pass)
+            .framebuffer(framebuffer)
+            .render_area(vk::Rect2D {
+                offset: vk::Offset2D { x: 0, y: 0 },
+                extent: self.swapchain.get_extent(),
+            })
+            .clear_values(&clear_values)
+            .build();
+        // With upscaling the scene passes render to the scaled target first
+        let render_pass_begin_info = match self.scaled_target.as_ref() {
+            Some(scaled) if self.schedule.options().upscale => vk::RenderPassBeginInfo::builder()
+                .render_pass(self.scene_render_pass)
+                .framebuffer(scaled.target.framebuffer)
+                .render_area(vk::Rect2D {
+                    offset: vk::Offset2D { x: 0, y: 0 },
+                    extent: self.get_internal_extent(),
+                })
+                .clear_values(&clear_values)
+                .build(),
+            _ => main_pass_begin_info,
+        };
+        unsafe {
+            self.context.device.cmd_begin_render_pass(
+                cmd_buf,
+                &render_pass_begin_info,
+                vk::SubpassContents::INLINE,
+            );
+        }
+
+        // The transitions between the passes are done by the render passes, see
+        // RenderSchedule
+        let mut scene_pass_ended = false;
+        let mut subpass = 0;
+        // Recorded by the forward pass, drawn by the transparency pass
+        let mut transparent_draws = vec![];
+        let passes = self
+            .schedule
+            .passes()
+            .iter()
+            .filter(|pass| !pass.kind.is_overlay() && pass.kind != PassKind::Output)
+            .map(|pass| (pass.kind, pass.queue))
+            .collect::<Vec<_>>();
+        for (kind, queue) in passes {
+            // Passes that switch render passes or command buffers label themselves, or
+            // not at all, a label can't span those
+            let labeled = !matches!(
+                kind,
+                PassKind::Luminance | PassKind::Bloom | PassKind::Upscale
+            );
+            if kind == PassKind::Transparency {
+                unsafe {
+                    self.context
+                        .device
+                        .cmd_next_subpass(cmd_buf, vk::SubpassContents::INLINE);
+                }
+                subpass += 1;
+            }
+            if labeled {
+                debug_namer.begin_label(cmd_buf, kind.name());
+            }
+            // Passes on the compute queue are timed in the compute command buffer
+            let profiled = !(queue == PassQueue::AsyncCompute && self.async_compute.is_some());
+            if profiled {
+                self.begin_profiler_scope(cmd_buf, kind.name());
+            }
+            match kind {
+                PassKind::Forward => {
+                    transparent_draws = self.record_forward_pass(cmd_buf, image_index)?
+                }
+                PassKind::Transparency => {
+                    self.record_transparency_pass(cmd_buf, image_index, &transparent_draws)?;
+                    // Weighted blended transparency composites in a subpass of its own
+                    subpass = self.capabilities.transparency.scene_subpasses() - 1;
+                }
+                PassKind::Luminance => {
+                    self.end_scene_render_pass(cmd_buf, subpass);
+                    scene_pass_ended = true;
+                    let split = self
+                        .async_compute
+                        .as_ref()
+                        .filter(|_| queue == PassQueue::AsyncCompute)
+                        .map(|async_compute| {
+                            (
+                                async_compute.compute_command_buffer(self.current_image),
+                                async_compute.graphics_command_buffer(self.current_image),
+                            )
+                        });
+                    match split {
+                        Some((compute_cmd_buf, next_cmd_buf)) => {
+                            unsafe {
+                                self.context.device.end_command_buffer(cmd_buf)?;
+                                self.context.device.begin_command_buffer(
+                                    compute_cmd_buf,
+                                    &command_buffer_begin_info,
+                                )?;
+                            }
+                            let timed = self
+                                .profiler
+                                .as_ref()
+                                .map_or(false, |profiler| profiler.times_compute());
+                            if timed {
+                                self.begin_profiler_scope(compute_cmd_buf, kind.name());
+                            }
+                            self.record_luminance(compute_cmd_buf);
+                            if timed {
+                                self.end_profiler_scope(compute_cmd_buf);
+                            }
+                            unsafe {
+                                self.context.device.end_command_buffer(compute_cmd_buf)?;
+                                self.context.device.begin_command_buffer(
+                                    next_cmd_buf,
+                                    &command_buffer_begin_info,
+                                )?;
+                            }
+                            cmd_buf = next_cmd_buf;
+                            graphics_command_buffers.push(cmd_buf);
+                        }
+                        None => self.record_luminance(cmd_buf),
+                    }
+                }
+                PassKind::Bloom => {
+                    if !scene_pass_ended {
+                        self.end_scene_render_pass(cmd_buf, subpass);
+                        scene_pass_ended = true;
+                    }
+                    if let Some(pass) = self.bloom_pass.as_ref() {
+                        debug_namer.begin_label(cmd_buf, kind.name());
+                        pass.record(&self.context.device, cmd_buf, &self.config.bloom);
+                        debug_namer.end_label(cmd_buf);
+                    }
+                }
+                PassKind::Upscale => {
+                    if !scene_pass_ended {
+                        self.end_scene_render_pass(cmd_buf, subpass);
+                    }
+                    unsafe {
+                        self.context.device.cmd_begin_render_pass(
+                            cmd_buf,
+                            &main_pass_begin_info,
+                            vk::SubpassContents::INLINE,
+                        );
+                    }
+                    subpass = 0;
+                    debug_namer.begin_label(cmd_buf, kind.name());
+                    self.draw_upscale(cmd_buf)?;
+                    debug_namer.end_label(cmd_buf);
+                }
+                PassKind::Text
+                | PassKind::Gizmo
+                | PassKind::Overlay
+                | PassKind::SoftwareCursor
+                | PassKind::Output => {
+                    unreachable!("{} is recorded with the overlays", kind.name())
+                }
+            }
+            if profiled {
+                self.end_profiler_scope(cmd_buf);
+            }
+            if labeled {
+                debug_namer.end_label(cmd_buf);
+            }
+        }
+
+        self.end_scene_render_pass(cmd_buf, subpass);
+        unsafe {
+            self.context.device.end_command_buffer(cmd_buf)?;
+        }
+        Ok(graphics_command_buffers)
+    }
+
+    // The target the scene passes of the image draw to
+    fn scene_target(&self, image_index: usize) -> &RenderTarget {
+        match (self.scaled_target.as_ref(), self.composite_target.as_ref()) {
+            (Some(scaled), _) if self.schedule.options().upscale => &scaled.target,
+            (_, Some(composite)) => &composite.target,
+            _ => &self.swapchain.get_render_targets()[image_index],
+        }
+    }
+
+    // With weighted blended transparency, moves on from the transparency subpass of an
+    // offscreen target and composites it. The command buffer has to be waited for before
+    // the next one is recorded.
+    fn record_offscreen_oit_composite(
+        &self,
+        cmd_buf: vk::CommandBuffer,
+        extent: vk::Extent2D,
+        target: &RenderTarget,
+    ) -> RendererResult<()> {
+        let set = match self.oit_offscreen_composite_set {
+            Some(set) => set,
+            None => return Ok(()),
+        };
+        self.write_oit_composite_set(set, target);
+        unsafe {
+            self.context
+                .device
+                .cmd_next_subpass(cmd_buf, vk::SubpassContents::INLINE);
+        }
+        self.draw_oit_composite(cmd_buf, extent, set)
+    }
+
+    // Of the main and scene render passes: the color, the depth, nothing for the resolve
+    // when multisampling, then the empty accumulation and the fully revealing revealage of
+    // weighted blended transparency
+    fn scene_clear_values(&self, color: [f32; 4]) -> Vec<vk::ClearValue> {
+        let mut clear_values = vec![
+            vk::ClearValue {
+                color: vk::ClearColorValue { float32: color },
+            },
+            vk::ClearValue {
+                depth_stencil: vk::ClearDepthStencilValue {
+                    depth: 1.0,
+                    stencil: 0,
+                },
+            },
+        ];
+        if self.capabilities.msaa_samples != vk::SampleCountFlags::TYPE_1 {
+            clear_values.push(vk::ClearValue::default());
+        }
+        if self.capabilities.transparency == TransparencyTechnique::WeightedBlended {
+            clear_values.push(vk::ClearValue {
+                color: vk::ClearColorValue { float32: [0.0; 4] },
+            });
+            clear_values.push(vk::ClearValue {
+                color: vk::ClearColorValue {
+                    float32: [1.0, 0.0, 0.0, 0.0],
+                },
+            });
+        }
+        clear_values
+    }
+
+    // Ends the main or scene render pass from the subpass it is in, skipping the
+    // subpasses after it
+    fn end_scene_render_pass(&self, cmd_buf: vk::CommandBuffer, subpass: u32) {
+        unsafe {
+            for _ in subpass + 1..self.capabilities.transparency.scene_subpasses() {
+                self.context
+                    .device
+                    .cmd_next_subpass(cmd_buf, vk::SubpassContents::INLINE);
+            }
+            self.context.device.cmd_end_render_pass(cmd_buf);
+        }
+    }
+
+    // Records the overlay render pass and the output pass into the overlay command buffer
+    // of the swapchain image. It is recorded every frame, so that the command buffers of
+    // the scene can be submitted again while the UI, gizmo or cursor change.
+    fn record_overlay_commands<F: FnOnce(&mut Ui)>(
+        &mut self,
+        image_index: usize,
+        window: Option<&Window>,
+        ui_func: F,
+    ) -> RendererResult<vk::CommandBuffer> {
+        let cmd_buf = self.overlay_command_buffers[image_index];
+        let swapchain_target = &self.swapchain.get_render_targets()[image_index];
+        let swapchain_framebuffer = swapchain_target.framebuffer;
+        let overlay_framebuffer = self
+            .composite_target
+            .as_ref()
+            .map_or(swapchain_target, |composite| &composite.target)
+            .overlay_framebuffer
+            .expect("The overlay framebuffers are added with the targets");
+        let command_buffer_begin_info = vk::CommandBufferBeginInfo::builder()
+            .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+        let overlay_pass_begin_info = vk::RenderPassBeginInfo::builder()
+            .render_pass(self.overlay_render_pass)
+            .framebuffer(overlay_framebuffer)
+            .render_area(vk::Rect2D {
+                offset: vk::Offset2D { x: 0, y: 0 },
+                extent: self.swapchain.get_extent(),
+            });
+        unsafe {
+            self.context
+                .device
+                .begin_command_buffer(cmd_buf, &command_buffer_begin_info)?;
+            self.context.device.cmd_begin_render_pass(
+                cmd_buf,
+                &overlay_pass_begin_info,
+                vk::SubpassContents::INLINE,
+            );
+        }
+
+        let debug_namer = self.context.debug_namer.clone();
+        let mut ui_func = Some(ui_func);
+        let passes = self
+            .schedule
+            .passes()
+            .iter()
+            .filter(|pass| pass.kind.is_overlay() || pass.kind == PassKind::Output)
+            .map(|pass| pass.kind)
+            .collect::<Vec<_>>();
+        for kind in passes {
+            // The output pass switches render passes and labels itself
+            let labeled = kind != PassKind::Output;
+            if labeled {
+                debug_namer.begin_label(cmd_buf, kind.name());
+            }
+            self.begin_profiler_scope(cmd_buf, kind.name());
+            match kind {
+                PassKind::Text => {
+                    self.text.draw(
+                        &self.context.device,
+                        cmd_buf,
+                        self.swapchain.get_extent(),
+                        &self.material_system,
+                    )?;
+                    if let Some(recorder) = self.overlay_recorder.as_mut() {
+                        recorder(&self.context.device, cmd_buf, self.swapchain.get_extent());
+                    }
+                }
+                // Headless there is no UI
+                PassKind::Overlay => {
+                    if let (Some(window), Some(ui_func)) = (window, ui_func.take()) {
+                        self.record_overlay(cmd_buf, window, ui_func)?;
+                    }
+                }
+                PassKind::Gizmo => self.draw_gizmo(cmd_buf, image_index)?,
+                PassKind::SoftwareCursor => self.draw_software_cursor(cmd_buf, image_index)?,
+                PassKind::Output => {
+                    if let Some(output_render_pass) = self.output_render_pass {
+                        let output_pass_begin_info = vk::RenderPassBeginInfo::builder()
+                            .render_pass(output_render_pass)
+                            .framebuffer(swapchain_framebuffer)
+                            .render_area(vk::Rect2D {
+                                offset: vk::Offset2D { x: 0, y: 0 },
+                                extent: self.swapchain.get_extent(),
+                            });
+                        unsafe {
+                            self.context.device.cmd_end_render_pass(cmd_buf);
+                            self.context.device.cmd_begin_render_pass(
+                                cmd_buf,
+                                &output_pass_begin_info,
+                                vk::SubpassContents::INLINE,
+                            );
+                        }
+                        debug_namer.begin_label(cmd_buf, kind.name());
+                        self.draw_output(cmd_buf)?;
+                        debug_namer.end_label(cmd_buf);
+                    }
+                }
+                _ => unreachable!("{} is recorded with the scene", kind.name()),
+            }
+            self.end_profiler_scope(cmd_buf);
+            if labeled {
+                debug_namer.end_label(cmd_buf);
+            }
+        }
+
+        unsafe {
+            self.context.device.cmd_end_render_pass(cmd_buf);
+        }
+        if let Some(timer) = self.gpu_timer.as_mut() {
+            timer.end(&self.context.device, cmd_buf, self.current_image);
+        }
+        unsafe {
+            self.context.device.end_command_buffer(cmd_buf)?;
+        }
+        Ok(cmd_buf)
+    }
+
+    // Whether the command buffer of the image was recorded for a frame like this one, then
+    // it is submitted again as it is. Its draws count for this frame too.
+    fn reuse_recorded_frame(&mut self, image_index: usize) -> bool {
+        // Instance batches are copied from the object data while recording, and the events
+        // cover what doesn't go through a generation, e.g. uploaded textures
+        self.collect_frame_events();
+        if self.object_upload_stats.dirty_slots > 0 || !self.frame_events.is_empty() {
+            self.recorded_frames.invalidate();
+        }
+        let key = RecordKey {
+            scene: self.scene_tree.generation(),
+            materials: self.material_system.generation(),
+            text: self.text.generation(),
+            lights: self.lights.generation(),
+            renderer: self.redraw_generation,
+            view_projection: self
+                .view_projection_history
+                .last_rendered()
+                .unwrap_or_else(glm::identity),
+            layer_mask: self.view_layer_mask,
+        };
+        if !self.recorded_frames.reusable(image_index, key) {
+            return false;
+        }
+        let recorded = self.last_frame_stats;
+        self.frame_stats.draw_calls = recorded.draw_calls;
+        self.frame_stats.pipeline_binds = recorded.pipeline_binds;
+        self.frame_stats.triangles = recorded.triangles;
+        self.frame_stats.objects_drawn = recorded.objects_drawn;
+        self.frame_stats.objects_total = recorded.objects_total;
+        true
+    }
+
+    // Frames with anything that is written into the scene command buffers every frame
+    // are recorded every frame: the GPU timestamps, particles, compute dispatches and the
+    // luminance pass. The overlays are in a command buffer of their own.
+    fn can_keep_recording(&self) -> bool {
+        self.gpu_timer.is_none()
+            && self.profiler.is_none()
+            && self.emitters.is_empty()
+            && !self.compute.has_pending()
+            && self.config.auto_exposure.is_none()
+    }
+
+    // On whichever queue the schedule put the luminance pass
+    fn record_luminance(&mut self, cmd_buf: vk::CommandBuffer) {
+        if self.config.auto_exposure.is_none() {
+            return;
+        }
+        if let Some(pass) = self.luminance_pass.as_mut() {
+            pass.record(
+                &self.context.device,
+                cmd_buf,
+                self.current_image,
+                self.frame_number,
+            );
+        }
+    }
+
+    // Renders the depth of the opaque objects in front of the first directional light
+    // into the shadow map, nothing without one. Objects are culled against the light's
+    // view and drawn with the DirectionalShadow pass of their template, templates
+    // without one cast no shadows.
+    fn record_shadow_pass(
+        &self,
+        cmd_buf: vk::CommandBuffer,
+        image_index: usize,
+    ) -> RendererResult<()> {
+        match self.shadow_view_projection {
+            Some(matrix) => {
+                self.record_shadow_map(cmd_buf, image_index, &matrix, self.view_layer_mask)
+            }
+            None => Ok(()),
+        }
+    }
+
+    // The matrix has to be in the shadow uniform of the image's camera slot as well,
+    // shadow.vert reads it from there
+    fn record_shadow_map(
+        &self,
+        cmd_buf: vk::CommandBuffer,
+        image_index: usize,
+        light_view_projection: &glm::Mat4,
+        layer_mask: u32,
+    ) -> RendererResult<()> {
+        let instance_buffer = match self.scene_tree.get_instance_buffer() {
+            Some(buffer) => buffer.get_buffer().buffer,
+            None => return Ok(()),
+        };
+        let frustum = Frustum::from_view_projection(light_view_projection);
+        let extent = self.shadow_map.extent();
+        let clear_values = [vk::ClearValue {
+            depth_stencil: vk::ClearDepthStencilValue {
+                depth: 1.0,
+                stencil: 0,
+            },
+        }];
+        let render_pass_begin_info = vk::RenderPassBeginInfo::builder()
+            .render_pass(self.shadow_map.render_pass)
+            .framebuffer(self.shadow_map.framebuffer)
+            .render_area(vk::Rect2D {
+                offset: vk::Offset2D { x: 0, y: 0 },
+                extent,
+            })
+            .clear_values(&clear_values);
+        let viewports = [vk::Viewport {
+            x: 0.,
+            y: 0.,
+            width: extent.width as f32,
+            height: extent.height as f32,
+            min_depth: 0.,
+            max_depth: 1.,
+        }];
+        let scissors = [vk::Rect2D {
+            offset: vk::Offset2D { x: 0, y: 0 },
+            extent,
+        }];
+        let device = &self.context.device;
+        unsafe {
+            device.cmd_begin_render_pass(
+                cmd_buf,
+                &render_pass_begin_info,
+                vk::SubpassContents::INLINE,
+            );
+        }
+
+        let mut cur_pipeline = vk::Pipeline::null();
+        for (_, m) in self.scene_tree.iter_visible(layer_mask) {
+            let mat = self.material_system.get_material_by_handle(m.material)?;
+            let effect = self
+                .material_system
+                .get_effect_template_by_handle(mat.original)?;
+            let pass = &effect.pass_shaders[MeshPassType::DirectionalShadow];
+            if pass.pipeline == vk::Pipeline::null()
+                || matches!(effect.transparency_mode, TransparencyMode::Transparent)
+            {
+                continue;
+            }
+            let mesh = self
+                .meshs
+                .get_mesh(m.mesh)
+                .ok_or::<RendererError>(InvalidHandle.into())?;
+            if !culling::is_visible(
+                &m.culling,
+                mesh.bounds(),
+                &m.get_global_transform(),
+                &frustum,
+            ) {
+                continue;
+            }
+            // Objects without a slot are in inactive chunks, which aren't visible
+            let slot = match m.get_instance_slot() {
+                Some(slot) => slot,
+                None => continue,
+            };
+            unsafe {
+                if cur_pipeline != pass.pipeline {
+                    cur_pipeline = pass.pipeline;
+                    device.cmd_bind_pipeline(
+                        cmd_buf,
+                        vk::PipelineBindPoint::GRAPHICS,
+                        cur_pipeline,
+                    );
+                    device.cmd_bind_descriptor_sets(
+                        cmd_buf,
+                        vk::PipelineBindPoint::GRAPHICS,
+                        pass.layout,
+                        0,
+                        &[self.descriptor_set_camera, self.descriptor_set_lights],
+                        &[(image_index * self.global_uniform_stride) as u32],
+                    );
+                    device.cmd_set_viewport(cmd_buf, 0, &viewports);
+                    device.cmd_set_scissor(cmd_buf, 0, &scissors);
+                }
+                device.cmd_bind_vertex_buffers(
+                    cmd_buf,
+                    1,
+                    &[instance_buffer],
+                    &[self.scene_tree.get_instance_offset(slot)],
+                );
+            }
+            mesh.draw(device, cmd_buf);
+        }
+
+        unsafe {
+            device.cmd_end_render_pass(cmd_buf);
+        }
+        Ok(())
+    }
+
+    // Renders the screen space motion of the opaque objects the camera sees into the
+    // velocity target, from their previous transforms under the previous view projection
+    // to the current ones, with the morph weights of both frames. Skinning is not applied,
+    // and transparent objects and particles leave no motion behind.
+    fn record_velocity_pass(
+        &self,
+        cmd_buf: vk::CommandBuffer,
+        image_index: usize,
+    ) -> RendererResult<()> {
+        let (render_pass, template) = match self.velocity_pass.as_ref() {
+            Some(pass) => (pass.render_pass, pass.template),
+            None => return Ok(()),
+        };
+        let target = match self.velocity_target.as_ref() {
+            Some(target) => &target.target,
+            None => return Ok(()),
+        };
+        let pass = &self
+            .material_system
+            .get_effect_template_by_handle(template)?
+            .pass_shaders[MeshPassType::Forward];
+        let extent = self.get_internal_extent();
+        let clear_values = [
+            vk::ClearValue {
+                color: vk::ClearColorValue {
+                    float32: [0.0, 0.0, 0.0, 0.0],
+                },
+            },
+            vk::ClearValue {
+                depth_stencil: vk::ClearDepthStencilValue {
+                    depth: 1.0,
+                    stencil: 0,
+                },
+            },
+        ];
+        let render_pass_begin_info = vk::RenderPassBeginInfo::builder()
+            .render_pass(render_pass)
+            .framebuffer(target.framebuffer)
+            .render_area(vk::Rect2D {
+                offset: vk::Offset2D { x: 0, y: 0 },
+                extent,
+            })
+            .clear_values(&clear_values);
+        let device = &self.context.device;
+        unsafe {
+            device.cmd_begin_render_pass(
+                cmd_buf,
+                &render_pass_begin_info,
+                vk::SubpassContents::INLINE,
+            );
+        }
+        // Cleared even without objects, so nothing of an earlier frame is left
+        let instance_buffer = match self.scene_tree.get_instance_buffer() {
+            Some(buffer) => buffer.get_buffer().buffer,
+            None => {
+                unsafe {
+                    device.cmd_end_render_pass(cmd_buf);
+                }
+                return Ok(());
+            }
+        };
+        unsafe {
+            device.cmd_bind_pipeline(cmd_buf, vk::PipelineBindPoint::GRAPHICS, pass.pipeline);
+            device.cmd_bind_descriptor_sets(
+                cmd_buf,
+                vk::PipelineBindPoint::GRAPHICS,
+                pass.layout,
+                0,
+                &[self.descriptor_set_camera, self.descriptor_set_lights],
+                &[(image_index * self.global_uniform_stride) as u32],
+            );
+            device.cmd_set_viewport(
+                cmd_buf,
+                0,
+                &[vk::Viewport {
+                    x: 0.,
+                    y: 0.,
+                    width: extent.width as f32,
+                    height: extent.height as f32,
+                    min_depth: 0.,
+                    max_depth: 1.,
+                }],
+            );
+            device.cmd_set_scissor(
+                cmd_buf,
+                0,
+                &[vk::Rect2D {
+                    offset: vk::Offset2D { x: 0, y: 0 },
+                    extent,
+                }],
+            );
+        }
+        for (_, m) in self.scene_tree.iter_visible(self.view_layer_mask) {
+            let mat = self.material_system.get_material_by_handle(m.material)?;
+            let effect = self
+                .material_system
+                .get_effect_template_by_handle(mat.original)?;
+            if matches!(effect.transparency_mode, TransparencyMode::Transparent) {
+                continue;
+            }
+            let mesh = self
+                .meshs
+                .get_mesh(m.mesh)
+                .ok_or::<RendererError>(InvalidHandle.into())?;
+            if !culling::is_visible(
+                &m.culling,
+                mesh.bounds(),
+                &m.get_global_transform(),
+                &self.frustum,
+            ) {
+                continue;
+            }
+            // Objects without a slot are in inactive chunks, which aren't visible
+            let slot = match m.get_instance_slot() {
+                Some(slot) => slot,
+                None => continue,
+            };
+            // Pushed for every draw, so no targets are left over from the one before
+            let morph = MorphPushConstants::for_draw(mesh, m.get_morph_weights());
+            let previous_morph = MorphPushConstants::for_draw(mesh, m.get_previous_morph_weights());
+            unsafe {
+                device.cmd_push_constants(
+                    cmd_buf,
+                    pass.layout,
+                    vk::ShaderStageFlags::VERTEX,
+                    MORPH_PUSH_CONSTANT_OFFSET,
+                    morph.as_bytes(),
+                );
+                device.cmd_push_constants(
+                    cmd_buf,
+                    pass.layout,
+                    vk::ShaderStageFlags::VERTEX,
+                    PREVIOUS_MORPH_PUSH_CONSTANT_OFFSET,
+                    previous_morph.as_bytes(),
+                );
+                device.cmd_bind_vertex_buffers(
+                    cmd_buf,
+                    1,
+                    &[instance_buffer],
+                    &[self.scene_tree.get_instance_offset(slot)],
+                );
+            }
+            mesh.draw(device, cmd_buf);
+        }
+
+        unsafe {
+            device.cmd_end_render_pass(cmd_buf);
+        }
+        Ok(())
+    }
+
+    // Returns the transparent objects for the transparency pass
+    fn record_forward_pass(
+        &mut self,
+        cmd_buf: vk::CommandBuffer,
+        image_index: usize,
+    ) -> RendererResult<TransparentObjects> {
+        let extent = self.get_internal_extent();
+        self.draw_skybox(cmd_buf, extent, image_index * self.global_uniform_stride)?;
+        let frustum = self.frustum;
+        let view_position = self.view_position;
+        let transparent;
+        (self.culling_stats, self.draw_sort_stats, transparent) = self.record_scene_objects(
+            cmd_buf,
+            extent,
+            image_index,
+            image_index * self.global_uniform_stride,
+            &frustum,
+            &view_position,
+            self.view_layer_mask,
+        )?;
+        self.frame_stats.objects_drawn = self.culling_stats.drawn as u32;
+        self.frame_stats.objects_total = self.scene_tree.iter_with_handles().count() as u32;
+        Ok(transparent)
+    }
+
+    // The transparent objects and the particle emitters, blended back to front together
+    fn record_transparency_pass(
+        &mut self,
+        cmd_buf: vk::CommandBuffer,
+        image_index: usize,
+        objects: &[(DrawKey, Handle<SceneObject>)],
+    ) -> RendererResult<()> {
+        let extent = self.get_internal_extent();
+        let camera_buffer_offset = image_index * self.global_uniform_stride;
+        // Written once before it is bound, the set can't change while it is recorded
+        let drawn_emitter = self.emitter_draw_order.iter().find_map(|(_, handle)| {
+            self.emitters
+                .get(*handle)
+                .filter(|emitter| emitter.instance_counts[image_index] > 0)
+                .map(|emitter| emitter.material)
+        });
+        let depth_set = match drawn_emitter {
+            Some(material) => self.particle_depth_set(image_index, material)?,
+            None => vk::DescriptorSet::null(),
+        };
+        if self.capabilities.transparency == TransparencyTechnique::WeightedBlended {
+            return self.record_weighted_blended_transparency(
+                cmd_buf,
+                image_index,
+                objects,
+                depth_set,
+            );
+        }
+        let draws = draw_list::merge_transparent(objects, &self.emitter_draw_order);
+        // Objects between two emitters are recorded together
+        let mut run = vec![];
+        for draw in draws {
+            match draw {
+                TransparentDraw::Object(handle) => run.push(handle),
+                TransparentDraw::Emitter(handle) => {
+                    self.record_transparent_objects(
+                        cmd_buf,
+                        extent,
+                        camera_buffer_offset,
+                        &std::mem::take(&mut run),
+                    )?;
+                    self.draw_emitter(cmd_buf, image_index, handle, depth_set)?;
+                }
+            }
+        }
+        self.record_transparent_objects(cmd_buf, extent, camera_buffer_offset, &run)
+    }
+
+    // The transparent objects in any order, then their composite and the emitters back to
+    // front over it in the next subpass
+    fn record_weighted_blended_transparency(
+        &mut self,
+        cmd_buf: vk::CommandBuffer,
+        image_index: usize,
+        objects: &[(DrawKey, Handle<SceneObject>)],
+        depth_set: vk::DescriptorSet,
+    ) -> RendererResult<()> {
+        let extent = self.get_internal_extent();
+        let camera_buffer_offset = image_index * self.global_uniform_stride;
+        let objects = objects
+            .iter()
+            .map(|(_, handle)| *handle)
+            .collect::<Vec<_>>();
+        self.record_transparent_objects(cmd_buf, extent, camera_buffer_offset, &objects)?;
+        let set = self.oit_composite_set(image_index)?;
+        unsafe {
+            self.context
+                .device
+                .cmd_next_subpass(cmd_buf, vk::SubpassContents::INLINE);
+        }
+        self.draw_oit_composite(cmd_buf, extent, set)?;
+        for (_, handle) in self.emitter_draw_order.clone() {
+            self.draw_emitter(cmd_buf, image_index, handle, depth_set)?;
+        }
+        Ok(())
+    }
+
+    // In the transparency subpass, one draw per object in the given order
+    fn record_transparent_objects(
+        &mut self,
+        cmd_buf: vk::CommandBuffer,
+        extent: vk::Extent2D,
+        camera_buffer_offset: usize,
+        objects: &[Handle<SceneObject>],
+    ) -> RendererResult<()> {
+        let runs = (0..objects.len())
+            .map(|index| index..index + 1)
+            .collect::<Vec<_>>();
+        self.record_draws(cmd_buf, extent, camera_buffer_offset, objects, &runs, 0)
+    }
+
+    // Sorts every object into the instance groups, logging the ones that can't be drawn
+    // with their material. Objects only share a draw when nothing is bound per object.
+    fn rebuild_instance_groups(&mut self) -> RendererResult<()> {
+        let mut batchable = vec![];
+        let mut singles = vec![];
+        let mut skipped = vec![];
+        for (handle, m) in self.scene_tree.iter_with_handles() {
+            let mat = self.material_system.get_material_by_handle(m.material)?;
+            let effect = self
+                .material_system
+                .get_effect_template_by_handle(mat.original)?;
+            if let Some(reason) = self.check_draw_compatibility(mat, effect) {
+                error!(
+                    "Skipping draw of {:?} with material {:?} (template {:?}): {}",
+                    handle,
+                    self.material_system.get_material_name(m.material),
+                    self.material_system.get_effect_template_name(mat.original),
+                    reason
+                );
+                skipped.push((handle, reason));
+                continue;
+            }
+            // Object params and morph weights are bound per object, and descriptor hooks
+            // are called per object
+            if !matches!(effect.transparency_mode, TransparencyMode::Transparent)
+                && effect.object_params_size.is_none()
+                && !effect.morph_targets
+                && !self.descriptor_hooks.contains_key(&mat.original)
+                && m.get_instance_slot().is_some()
+            {
+                let pipeline =
+                    vk::Handle::as_raw(effect.pass_shaders[MeshPassType::Forward].pipeline);
+                batchable.push(((pipeline, m.material.id(), m.mesh.id()), handle));
+            } else {
+                singles.push(handle);
+            }
+        }
+        self.instance_groups.rebuild(batchable, singles);
+        self.skipped_draws = skipped;
+        Ok(())
+    }
+
+    // The object and its mesh, None when it is hidden, outside of layer_mask or culled.
+    // Counts it in stats either way.
+    fn cull_object(
+        &self,
+        handle: Handle<SceneObject>,
+        frustum: &Frustum,
+        layer_mask: u32,
+        stats: &mut CullingStats,
+    ) -> RendererResult<Option<(&SceneObject, &Mesh)>> {
+        let m = match self.scene_tree.get_object(handle) {
+            Some(m) if self.scene_tree.is_object_visible(handle) => m,
+            _ => return Ok(None),
+        };
+        if m.layer_mask & layer_mask == 0 {
+            stats.masked += 1;
+            return Ok(None);
+        }
+        let mesh = self
+            .meshs
+            .get_mesh(m.mesh)
+            .ok_or::<RendererError>(InvalidHandle.into())?;
+        if !culling::is_visible(
+            &m.culling,
+            mesh.bounds(),
+            &m.get_global_transform(),
+            frustum,
+        ) {
+            stats.culled += 1;
+            return Ok(None);
+        }
+        stats.drawn += 1;
+        if m.culling == CullingMode::Never {
+            stats.never_culled += 1;
+        }
+        Ok(Some((m, mesh)))
+    }
+
+    // Draws every visible opaque object seen through frustum with the camera at
+    // camera_buffer_offset in the uniform buffer, in the forward subpass of a render pass
+    // compatible with the main one. The visible objects of every instance group are drawn
+    // with one instanced draw, with their instance data in the region of image_index. The
+    // groups are rebuilt when the scene changed since the last draw. The visible
+    // transparent objects are returned back to front for the transparency subpass, see
+    // record_transparent_objects.
+    #[allow(clippy::too_many_arguments)]
+    fn record_scene_objects(
+        &mut self,
+        cmd_buf: vk::CommandBuffer,
+        extent: vk::Extent2D,
+        image_index: usize,
+        camera_buffer_offset: usize,
+        frustum: &Frustum,
+        camera_position: &glm::Vec3,
+        layer_mask: u32,
+    ) -> RendererResult<(CullingStats, DrawSortStats, TransparentObjects)> {
+        self.collect_frame_events();
+        if !self.instance_groups.is_valid() {
+            self.rebuild_instance_groups()?;
+        }
+        let mut culling_stats = CullingStats::default();
+        // Every draw covers the objects of its range in drawn
+        let mut drawn = vec![];
+        let mut draws = vec![];
+        for group in self.instance_groups.groups() {
+            let mut members = vec![];
+            let mut nearest = f32::INFINITY;
+            for handle in &group.objects {
+                if let Some((m, mesh)) =
+                    self.cull_object(*handle, frustum, layer_mask, &mut culling_stats)?
+                {
+                    // The closest point of the bounds, large objects around the camera
+                    // come first
+                    let distance = mesh
+                        .bounds()
+                        .transformed(&m.get_global_transform())
+                        .distance_to_point(camera_position);
+                    nearest = nearest.min(distance);
+                    let bucket = match self.config.opaque_sort {
+                        OpaqueSort::None => 0,
+                        OpaqueSort::FrontToBack => draw_list::distance_bucket(distance),
+                    };
+                    members.push((bucket, *handle));
+                }
+            }
+            if members.is_empty() {
+                continue;
+            }
+            members.sort_by_key(|(bucket, _)| *bucket);
+            let start = drawn.len();
+            drawn.extend(members.into_iter().map(|(_, handle)| handle));
+            let key = DrawKey::opaque(
+                group.pipeline,
+                group.material,
+                group.mesh,
+                nearest,
+                self.config.opaque_sort,
+            );
+            draws.push((key, start..drawn.len()));
+        }
+        for handle in self.instance_groups.singles() {
+            let (m, mesh) =
+                match self.cull_object(*handle, frustum, layer_mask, &mut culling_stats)? {
+                    Some(visible) => visible,
+                    None => continue,
+                };
+            let mat = self.material_system.get_material_by_handle(m.material)?;
+            let effect = self
+                .material_system
+                .get_effect_template_by_handle(mat.original)?;
+            let key = if matches!(effect.transparency_mode, TransparencyMode::Transparent) {
+                let center = m.get_global_transform() * mesh.bounds().center().push(1.0);
+                DrawKey::transparent(glm::distance(&center.xyz(), camera_position))
+            } else {
+                let distance = mesh
+                    .bounds()
+                    .transformed(&m.get_global_transform())
+                    .distance_to_point(camera_position);
+                DrawKey::opaque(
+                    vk::Handle::as_raw(effect.pass_shaders[MeshPassType::Forward].pipeline),
+                    m.material.id(),
+                    m.mesh.id(),
+                    distance,
+                    self.config.opaque_sort,
+                )
+            };
+            draws.push((key, drawn.len()..drawn.len() + 1));
+            drawn.push(*handle);
+        }
+        let mut sort_stats = draw_list::sort_draws(&mut draws);
+        // The transparent draws are sorted after the opaque ones and cover one object each
+        let first_transparent = draws
+            .iter()
+            .position(|(key, _)| key.transparent)
+            .unwrap_or(draws.len());
+        let transparent = draws[first_transparent..]
+            .iter()
+            .map(|(key, run)| (*key, drawn[run.start]))
+            .collect::<Vec<_>>();
+        draws.truncate(first_transparent);
+
+        let mut batched_instances = vec![];
+        for (_, run) in draws.iter().filter(|(_, run)| run.len() > 1) {
+            for handle in &drawn[run.clone()] {
+                if let Some(m) = self.scene_tree.get_object(*handle) {
+                    batched_instances.extend_from_slice(m.get_instance_data().as_slice());
+                }
+            }
+            sort_stats.instanced_draws += 1;
+            sort_stats.instanced_objects += run.len();
+        }
+        let batch_offset = if batched_instances.is_empty() {
+            0
+        } else if let Ok(mut allo) = lock_allocator(&self.allocator) {
+            self.instance_batches.write(
+                image_index,
+                &batched_instances,
+                &self.context.device,
+                allo.deref_mut(),
+                self.buffer_manager.clone(),
+            )?
+        } else {
+            return Err(AllocatorUnavailable.into());
+        };
+        let runs = draws.into_iter().map(|(_, run)| run).collect::<Vec<_>>();
+        self.record_draws(
+            cmd_buf,
+            extent,
+            camera_buffer_offset,
+            &drawn,
+            &runs,
+            batch_offset,
+        )?;
+        Ok((culling_stats, sort_stats, transparent))
+    }
+
+    // Every run of drawn is one draw of its objects, instanced when it covers several.
+    // Their instance data is in the instance batches from batch_offset on, in the order
+    // of the runs.
+    fn record_draws(
+        &mut self,
+        cmd_buf: vk::CommandBuffer,
+        extent: vk::Extent2D,
+        camera_buffer_offset: usize,
+        drawn: &[Handle<SceneObject>],
+        runs: &[Range<usize>],
+        mut batch_offset: vk::DeviceSize,
+    ) -> RendererResult<()> {
+        unsafe {
+            let viewports = [vk::Viewport {
+                x: 0.,
+                y: 0.,
+                width: extent.width as f32,
+                height: extent.height as f32,
+                min_depth: 0.,
+                max_depth: 1.,
+            }];
+            let scissors = [vk::Rect2D {
+                offset: vk::Offset2D { x: 0, y: 0 },
+                extent,
+            }];
+
+            let mut cur_pipeline = vk::Pipeline::null();
+            let mut cur_layout = vk::PipelineLayout::null(); // shouldn't change but we will need it
+            for run in runs {
+                // Everything but the instance data is the same for the whole run
+                let handle = drawn[run.start];
+                let instance_count = run.len() as u32;
+                let m = self
+                    .scene_tree
+                    .get_object(handle)
+                    .ok_or::<RendererError>(InvalidHandle.into())?;
+                let mesh = self
+                    .meshs
+                    .get_mesh(m.mesh)
+                    .ok_or::<RendererError>(InvalidHandle.into())?;
+                let mat = self.material_system.get_material_by_handle(m.material)?;
+                let effect = self
+                    .material_system
+                    .get_effect_template_by_handle(mat.original)?;
+                // Transparent objects are drawn in the transparency subpass, which their
+                // pipelines are built for
+                let pass = &effect.pass_shaders[effect.mesh_pass()];
+                if cur_pipeline != pass.pipeline {
+                    cur_pipeline = pass.pipeline;
+                    cur_layout = pass.layout;
+
+                    self.context.device.cmd_bind_pipeline(
+                        cmd_buf,
+                        vk::PipelineBindPoint::GRAPHICS,
+                        cur_pipeline,
+                    );
+                    self.frame_stats.pipeline_binds += 1;
+
+                    self.context.device.cmd_bind_descriptor_sets(
+                        cmd_buf,
+                        vk::PipelineBindPoint::GRAPHICS,
+                        cur_layout,
+                        0,
+                        &[self.descriptor_set_camera, self.descriptor_set_lights],
+                        // Only the camera offset changes
+                        &[camera_buffer_offset as u32],
+                    );
+
+                    self.context.device.cmd_set_viewport(cmd_buf, 0, &viewports);
+                    self.context.device.cmd_set_scissor(cmd_buf, 0, &scissors);
+                }
+
+                self.context.device.cmd_bind_descriptor_sets(
+                    cmd_buf,
+                    vk::PipelineBindPoint::GRAPHICS,
+                    cur_layout,
+                    2,
+                    &[mat.pass_sets[effect.mesh_pass()]],
+                    &[],
+                );
+                if let Some(hook) = self.descriptor_hooks.get_mut(&mat.original) {
+                    hook(&self.context.device, cmd_buf, cur_layout, handle);
+                }
+                let uv_transform = mat.parameters.uv_transform_push_constants();
+                self.context.device.cmd_push_constants(
+                    cmd_buf,
+                    cur_layout,
+                    vk::ShaderStageFlags::VERTEX,
+                    0,
+                    std::slice::from_raw_parts(
+                        uv_transform.as_ptr() as *const u8,
+                        std::mem::size_of_val(&uv_transform),
+                    ),
+                );
+                if effect.morph_targets {
+                    let morph = MorphPushConstants::for_draw(mesh, m.get_morph_weights());
+                    self.context.device.cmd_push_constants(
+                        cmd_buf,
+                        cur_layout,
+                        vk::ShaderStageFlags::VERTEX,
+                        MORPH_PUSH_CONSTANT_OFFSET,
+                        morph.as_bytes(),
+                    );
+                }
+                if instance_count > 1 {
+                    if let Some(buffer) = self.instance_batches.buffer() {
+                        self.context.device.cmd_bind_vertex_buffers(
+                            cmd_buf,
+                            1,
+                            &[buffer.get_buffer().buffer],
+                            &[batch_offset],
+                        );
+                    }
+                    batch_offset +=
+                        (run.len() * std::mem::size_of::<InstanceData>()) as vk::DeviceSize;
+                    mesh.draw_instanced(&self.context.device, cmd_buf, instance_count);
+                    self.frame_stats.draw_calls += 1;
+                    self.frame_stats.triangles +=
+                        (mesh.indices().len() / 3) as u64 * instance_count as u64;
+                    continue;
+                }
+                // Objects without a slot are in inactive chunks, which aren't visible
+                let (slot, instance_buffer) =
+                    match (m.get_instance_slot(), self.scene_tree.get_instance_buffer()) {
+                        (Some(slot), Some(buffer)) => (slot, buffer.get_buffer()),
+                        _ => continue,
+                    };
+                self.context.device.cmd_bind_vertex_buffers(
+                    cmd_buf,
+                    1,
+                    &[instance_buffer.buffer],
+                    &[self.scene_tree.get_instance_offset(slot)],
+                );
+                if let (Some(_), Some((_, set))) =
+                    (effect.object_params_size, self.object_params_set)
+                {
+                    self.context.device.cmd_bind_descriptor_sets(
+                        cmd_buf,
+                        vk::PipelineBindPoint::GRAPHICS,
+                        cur_layout,
+                        OBJECT_PARAMS_SET,
+                        &[set],
+                        &[self.scene_tree.get_object_params_offset(slot)],
+                    );
+                }
+                mesh.draw(&self.context.device, cmd_buf);
+                self.frame_stats.draw_calls += 1;
+                self.frame_stats.triangles += (mesh.indices().len() / 3) as u64;
+            }
+        }
+        Ok(())
+    }
+
+    fn record_overlay<F: FnOnce(&mut Ui)>(
+        &mut self,
+        cmd_buf: vk::CommandBuffer,
+        window: &Window,
+        ui_func: F,
+    ) -> RendererResult<()> {
+        #[cfg(feature = "winit")]
+        self.platform
+            .prepare_frame(self.imgui.io_mut(), window)
+            .expect("Failed to prepare frame");
+        // Never called without winit, there is no Window
+        #[cfg(not(feature = "winit"))]
+        let _ = window;
+        let internal_extent = self.get_internal_extent();
+        let ui = self.imgui.frame();
+
+        if self.ui_state.opened {
+            let w = ui
+                .window("Vulkan Renderer")
+                .opened(&mut self.ui_state.opened)
+                .position([20.0, 20.0], Condition::FirstUseEver)
+                .size([600.0, 400.0], Condition::FirstUseEver)
+                .resizable(true)
+                .movable(true);
+            w.build(|| {
+                ui.checkbox("Show Demo Window", &mut self.ui_state.show_demo_window);
+                ui.checkbox("Show Light Probes", &mut self.ui_state.show_light_probes);
+                ui.text(format!(
+                    "Objects drawn: {} (never culled: {}), culled: {}, masked: {}",
+                    self.culling_stats.drawn,
+                    self.culling_stats.never_culled,
+                    self.culling_stats.culled,
+                    self.culling_stats.masked
+                ));
+                ui.text(format!(
+                    "Draw sort: {} material buckets, {:.2} distance buckets on average",
+                    self.draw_sort_stats.material_buckets,
+                    self.draw_sort_stats.average_distance_buckets()
+                ));
+                ui.text(format!(
+                    "Instancing: {} objects in {} draws",
+                    self.draw_sort_stats.instanced_objects, self.draw_sort_stats.instanced_draws
+                ));
+                ui.text(format!(
+                    "Object data: {} dirty slots in {} copies, {} bytes{}",
+                    self.object_upload_stats.dirty_slots,
+                    self.object_upload_stats.ranges,
+                    self.object_upload_stats.bytes_copied,
+                    if self.object_upload_stats.full_upload {
+                        " (full upload)"
+                    } else {
+                        ""
+                    }
+                ));
+                ui.text(format!(
+                    "Internal resolution: {}x{} (scale {:.2})",
+                    internal_extent.width, internal_extent.height, self.config.render_scale
+                ));
+                ui.text(format!(
+                    "Output: {:?} {:?}",
+                    self.capabilities.color_space, self.capabilities.surface_format.format
+                ));
+                if let Some(luminance) = self.scene_luminance {
+                    ui.text(format!(
+                        "Scene luminance: {:.3}, exposure: {:.2}",
+                        luminance, self.exposure
+                    ));
+                }
+                if let Some(p) = self.frame_times.percentiles() {
+                    ui.text(format!(
+                        "Frame time p50: {:.2} ms, p95: {:.2} ms, p99: {:.2} ms, max: {:.2} ms",
+                        p.p50, p.p95, p.p99, p.max
+                    ));
+                    let samples = self.frame_times.iter().collect::<Vec<_>>();
+                    ui.plot_lines("Frame time (ms)", &samples)
+                        .scale_min(0.0)
+                        .graph_size([0.0, 60.0])
+                        .build();
+                }
+                if let Some(_tree_root) = ui.tree_node("Scene Objects") {
+                    for (i, object) in self.scene_tree.iter().enumerate() {
+                        let name = format!("Object {i}");
+                        if let Some(_tree_node) = ui.tree_node(name) {
+                            ui.text(format!(
+                                "Position: {} {} {}",
+                                object.position.x, object.position.y, object.position.z
+                            ));
+
+                            let angles = glm::quat_euler_angles(&object.rotation);
+                            let (pitch, yaw, roll) = (angles.x, angles.y, angles.z);
+                            ui.text(format!("Rotation: {} {} {}", pitch, yaw, roll));
+
+                            ui.text(format!(
+                                "Scaling: {} {} {}",
+                                object.scaling.x, object.scaling.y, object.scaling.z
+                            ));
+                        };
+                    }
+                };
+            });
+        }
+
+        if self.ui_state.show_demo_window {
+            ui.show_demo_window(&mut self.ui_state.show_demo_window);
+        }
+
+        // Updated with this frame's camera by now
+        if let (true, Some(view_projection)) = (
+            self.ui_state.show_light_probes,
+            self.view_projection_history.last_rendered(),
+        ) {
+            let screen_size = glm::Vec2::from(ui.io().display_size);
+            let draw_list = ui.get_background_draw_list();
+            for probe in self.light_probes.iter() {
+                let center = match gizmo::project_to_screen(
+                    &probe.position.coords,
+                    &view_projection,
+                    &screen_size,
+                ) {
+                    Some(center) => center,
+                    None => continue,
+                };
+                // Tone mapped like the scene
+                let radiance = probe.data.to_spherical_harmonics().average_radiance();
+                let color = radiance.map(|c| c / (1.0 + c));
+                draw_list
+                    .add_circle([center.x, center.y], 7.0, [color.x, color.y, color.z, 1.0])
+                    .filled(true)
+                    .build();
+                draw_list
+                    .add_circle([center.x, center.y], 7.0, [1.0, 1.0, 1.0, 1.0])
+                    .build();
+            }
+        }
+
+        ui_func(ui);
+
+        #[cfg(feature = "winit")]
+        self.platform.prepare_render(ui, window);
+        let draw_data = self.imgui.render();
+        self.imgui_renderer.cmd_draw(cmd_buf, draw_data)?;
+        Ok(())
+    }
+
+    fn begin_profiler_scope(&mut self, cmd_buf: vk::CommandBuffer, name: &'static str) {
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.begin_scope(&self.context.device, cmd_buf, self.current_image, name);
+        }
+    }
+
+    fn end_profiler_scope(&mut self, cmd_buf: vk::CommandBuffer) {
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.end_scope(&self.context.device, cmd_buf, self.current_image);
+        }
+    }
+
+    fn submit_commands<F: FnOnce(&mut Ui)>(
+        &mut self,
+        image_index: usize,
+        window: Option<&Window>,
+        ui_func: F,
+    ) -> RendererResult<()> {
+        let record_start = Instant::now();
+        let command_bufs = if self.reuse_recorded_frame(image_index) {
+            vec![self.command_buffers[image_index]]
+        } else {
+            let keep = self.can_keep_recording();
+            let command_bufs = self.update_command_buffer(image_index)?;
+            // A frame split for async compute also uses command buffers of the frame in
+            // flight, which are recorded again for other images
+            self.recorded_frames
+                .recorded(image_index, keep && command_bufs.len() == 1);
+            self.frame_stats.command_buffers_recorded = command_bufs.len() as u32;
+            command_bufs
+        };
+        let overlay_buf = self.record_overlay_commands(image_index, window, ui_func)?;
+        self.frame_stats.record_time = record_start.elapsed();
+        let this_frame_data = &self.frame_data[self.current_image];
+        // A headless image is not acquired and not presented, so neither semaphore is used
+        let headless = self.swapchain.is_headless();
+        let image_available = (!headless).then_some(this_frame_data.image_available_semaphore);
+        // Presenting can only wait on a binary semaphore
+        let semaphores_finished = if headless {
+            vec![]
+        } else {
+            vec![this_frame_data.render_finished_semaphore]
+        };
+        // The compute passes of the last frame may still read the scene color this frame
+        // writes
+        let scene_color_released = self
+            .async_compute
+            .as_mut()
+            .and_then(|async_compute| async_compute.take_pending_release());
+        let (uploads, upload_stages) = self.take_upload_waits();
+        let frame = match (command_bufs.as_slice(), self.async_compute.as_mut()) {
+            ([before_compute, after_compute], Some(async_compute)) => {
+                let device = &self.context.device;
+                let queue = self.context.graphics_queue.queue;
+                let before_compute = [*before_compute];
+                let after_compute = [*after_compute, overlay_buf];
+                let mut scene_waiting = scene_color_released.into_iter().collect::<Vec<_>>();
+                let mut scene_stages =
+                    vec![vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT; scene_waiting.len()];
+                scene_waiting.extend(uploads);
+                scene_stages.extend(upload_stages);
+                let graphics_done = [async_compute.graphics_done(self.current_image)];
+                let scene_submit = [vk::SubmitInfo::builder()
+                    .wait_semaphores(&scene_waiting)
+                    .wait_dst_stage_mask(&scene_stages)
+                    .command_buffers(&before_compute)
+                    .signal_semaphores(&graphics_done)
+                    .build()];
+                unsafe { device.queue_submit(queue, &scene_submit, vk::Fence::null())? };
+                async_compute.submit(self.current_image)?;
+                // Only what draws to the swapchain image has to wait for it
+                let semaphores_available = image_available.into_iter().collect::<Vec<_>>();
+                let waiting_stages = vec![
+                    vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT;
+                    semaphores_available.len()
+                ];
+                let rest_submit = [vk::SubmitInfo::builder()
+                    .wait_semaphores(&semaphores_available)
+                    .wait_dst_stage_mask(&waiting_stages)
+                    .command_buffers(&after_compute)
+                    .signal_semaphores(&semaphores_finished)
+                    .build()];
+                unsafe { device.queue_submit(queue, &rest_submit, vk::Fence::null())? };
+                // Presenting doesn't wait for the compute passes, but the frame only
+                // counts as done once they are
+                let compute_done = [async_compute.compute_done(self.current_image)];
+                let compute_stages = [vk::PipelineStageFlags::ALL_COMMANDS];
+                let submit_info = vk::SubmitInfo::builder()
+                    .wait_semaphores(&compute_done)
+                    .wait_dst_stage_mask(&compute_stages);
+                self.timeline
+                    .submit(queue, self.current_image, image_index, submit_info, &[])?
+            }
+            _ => {
+                let mut semaphores_waiting = image_available.into_iter().collect::<Vec<_>>();
+                semaphores_waiting.extend(scene_color_released);
+                let mut waiting_stages =
+                    vec![vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT; semaphores_waiting.len()];
+                semaphores_waiting.extend(uploads);
+                waiting_stages.extend(upload_stages);
+                let command_bufs = command_bufs
+                    .iter()
+                    .copied()
+                    .chain(std::iter::once(overlay_buf))
+                    .collect::<Vec<_>>();
+                let submit_info = vk::SubmitInfo::builder()
+                    .wait_semaphores(&semaphores_waiting)
+                    .wait_dst_stage_mask(&waiting_stages)
+                    .command_buffers(&command_bufs[..]);
+                self.timeline.submit(
+                    self.context.graphics_queue.queue,
+                    self.current_image,
+                    image_index,
+                    submit_info,
+                    &semaphores_finished,
+                )?
+            }
+        };
+        self.buffer_manager
+            .lock()
+            .unwrap()
+            .set_last_submitted_frame(frame);
+        Ok(())
+    }
+
+    // The meshes uploaded through the transfer queue since the last graphics submission,
+    // whatever draws next has to wait for them
+    fn take_upload_waits(&self) -> (Vec<vk::Semaphore>, Vec<vk::PipelineStageFlags>) {
+        let uploads = self.buffer_manager.lock().unwrap().take_upload_semaphores();
+        let stages = vec![vk::PipelineStageFlags::VERTEX_INPUT; uploads.len()];
+        (uploads, stages)
+    }
+
+    fn present(&self, image_index: u32) -> RendererResult<()> {
+        self.swapchain.present(
+            &self.context.graphics_queue.queue,
+            &self.frame_data[self.current_image].render_finished_semaphore,
+            image_index,
+        )?;
+        Ok(())
+    }
+
+    // begin_frame followed by end_frame
+    #[cfg(feature = "winit")]
+    pub fn render<F: FnOnce(&mut Ui)>(
+        &mut self,
+        camera: &Camera,
+        window: &Window,
+        ui_func: F,
+    ) -> RendererResult<FrameStatus> {
+        self.render_frame(camera, Some(window), ui_func)
+    }
+
+    // Like render, without the imgui UI, see new_with_internal_window
+    pub fn render_without_ui(&mut self, camera: &Camera) -> RendererResult<FrameStatus> {
+        self.advance_frame_clock();
+        self.render_frame(camera, None, |_| {})
+    }
+
+    fn render_frame<F: FnOnce(&mut Ui)>(
+        &mut self,
+        camera: &Camera,
+        window: Option<&Window>,
+        ui_func: F,
+    ) -> RendererResult<FrameStatus> {
+        if self.config.redraw_policy == RedrawPolicy::OnDemand
+            && self.surface_ready
+            && self.pending_frame.is_none()
+            && !self.needs_redraw(camera)
+        {
+            self.idle_tick()?;
+            return Ok(FrameStatus::Unchanged);
+        }
+        if let Some(recorder) = self.begin_frame()? {
+            return recorder.finish(camera, window, ui_func);
+        }
+        if self.surface_ready {
+            Ok(FrameStatus::Skipped)
+        } else {
+            Ok(FrameStatus::NotReady)
+        }
+    }
+
+    // Forces the next render to draw a frame with RedrawPolicy::OnDemand, for changes the
+    // renderer can't see, e.g. to meshs or material_system
+    pub fn request_redraw(&mut self) {
+        self.redraw_requested = true;
+        self.redraw_generation += 1;
+    }
+
+    // Records the command buffers of the next frames again, even if nothing the renderer
+    // tracks changed, e.g. after writing to a buffer a recorded frame binds through
+    // buffer_manager
+    pub fn force_rerecord(&mut self) {
+        self.recorded_frames.invalidate();
+    }
+
+    pub fn set_redraw_policy(&mut self, policy: RedrawPolicy) {
+        self.config.redraw_policy = policy;
+        self.request_redraw();
+    }
+
+    // Whether anything that shows up on screen changed since the last presented frame.
+    // The events of the subsystems are kept for the frame that is drawn.
+    fn needs_redraw(&mut self, camera: &Camera) -> bool {
+        self.collect_frame_events();
+        let camera_moved = self.last_presented_view.map_or(true, |view| {
+            view.iter()
+                .zip(camera.view_projection_matrix().iter())
+                .any(|(a, b)| (a - b).abs() > REDRAW_CAMERA_EPSILON)
+        });
+        let particles_alive = self
+            .emitters
+            .iter()
+            .any(|emitter| !emitter.particles().is_empty() || emitter.config().spawn_rate > 0.0);
+        self.redraw_requested
+            || !self.frame_events.is_empty()
+            || !self.light_storage.is_current(&self.lights)
+            || camera_moved
+            || particles_alive
+            || self.exposure_adapting
+            || camera.get_layer_mask() != self.view_layer_mask
+    }
+
+    // What still has to happen while no frames are drawn: the resources queued for
+    // deletion are freed and changed assets are reloaded, which wakes the next render
+    fn idle_tick(&mut self) -> RendererResult<()> {
+        self.free_deferred_while_skipping()?;
+        self.reload_changed_assets()
+    }
+
+    // Waits until the next frame can be recorded and acquires its swapchain image. None
+    // when there is nothing to render to, or the frame is skipped in the background.
+    pub fn begin_frame(&mut self) -> RendererResult<Option<FrameRecorder<'_>>> {
+        if let Some(pending) = self.pending_frame.take() {
+            if !pending.abandoned.load(Ordering::Acquire) {
+                self.pending_frame = Some(pending);
+                return Err(FrameError::from(
+                    "begin_frame called again before end_frame".to_string(),
+                )
+                .into());
+            }
+            // The image of the abandoned frame is still acquired, and its semaphore
+            // signaled, so this frame takes it over
+            let token = self.start_pending_frame(pending.image_index);
+            return Ok(Some(FrameRecorder {
+                renderer: self,
+                token,
+            }));
+        }
+        if !self.surface_ready {
+            self.free_deferred_while_skipping()?;
+            return Ok(None);
+        }
+        if self.is_in_background() {
+            match self.config.background_behavior {
+                BackgroundBehavior::ContinueFullRate => {}
+                BackgroundBehavior::ThrottleTo(fps) => {
+                    let frame_time = Duration::from_secs_f32(1.0 / fps.max(0.001));
+                    let elapsed = self.last_render.elapsed();
+                    if elapsed < frame_time {
+                        std::thread::sleep(frame_time - elapsed);
+                    }
+                }
+                BackgroundBehavior::SkipRendering => {
+                    self.free_deferred_while_skipping()?;
+                    return Ok(None);
+                }
+            }
+        }
+
+        if self.present_mode_changed {
+            let extent = self.swapchain.get_extent();
+            let extent = self.recreate_swapchain(extent.width, extent.height)?;
+            if swapchain::is_zero_extent(extent) {
+                return Ok(None);
+            }
+        }
+
+        self.reload_changed_assets()?;
+
+        self.frame_stats = FrameStats::default();
+        let wait_start = Instant::now();
+        self.timeline.wait_for_frame(self.current_image)?;
+        let mut fence_wait_time = wait_start.elapsed();
+        // The last frame recorded with this frame in flight is done now
+        if let Some(gpu_frame_ms) = self
+            .gpu_timer
+            .as_mut()
+            .and_then(|timer| timer.read(&self.context.device, self.current_image))
+        {
+            self.gpu_frame_times.push(gpu_frame_ms);
+            self.frame_stats.gpu_frame_ms = Some(gpu_frame_ms);
+        }
+        if let Some(timings) = self
+            .profiler
+            .as_mut()
+            .and_then(|profiler| profiler.read(&self.context.device, self.current_image))
+        {
+            self.pass_timings = timings;
+        }
+        let image_index = self.swapchain.get_next_image(
+            std::u64::MAX,
+            &self.frame_data[self.current_image].image_available_semaphore,
+            vk::Fence::null(),
+        )?;
+        // The uniforms and command buffer of the image can't be touched before this
+        let wait_start = Instant::now();
+        self.timeline.wait_for_image(image_index as usize)?;
+        fence_wait_time += wait_start.elapsed();
+        self.frame_stats.fence_wait_time = fence_wait_time;
+        let token = self.start_pending_frame(image_index);
+        Ok(Some(FrameRecorder {
+            renderer: self,
+            token,
+        }))
+    }
+
+    fn start_pending_frame(&mut self, image_index: u32) -> FrameToken {
+        let abandoned = Arc::new(AtomicBool::new(false));
+        self.pending_frame = Some(PendingFrame {
+            image_index,
+            abandoned: abandoned.clone(),
+        });
+        FrameToken {
+            image_index,
+            frame_number: self.frame_number,
+            abandoned,
+            ended: false,
+        }
+    }
+
+    fn end_frame<F: FnOnce(&mut Ui)>(
+        &mut self,
+        mut token: FrameToken,
+        camera: &Camera,
+        window: Option<&Window>,
+        ui_func: F,
+    ) -> RendererResult<FrameStatus> {
+        let image_index = match &self.pending_frame {
+            Some(pending) if Arc::ptr_eq(&pending.abandoned, &token.abandoned) => {
+                pending.image_index
+            }
+            _ => {
+                return Err(FrameError::from(
+                    "end_frame called with a token of a frame that is no longer pending"
+                        .to_string(),
+                )
+                .into())
+            }
+        };
+        token.ended = true;
+        self.pending_frame = None;
+
+        let chunk_changes;
+        let view_projection = camera.view_projection_matrix();
+        // The uniform buffer has room for every image, so writing it needs no allocator
+        let offset = image_index as usize * self.global_uniform_stride;
+        self.frustum = if self.config.frustum_culling {
+            Frustum::from_view_projection(&view_projection)
+        } else {
+            Frustum::default()
+        };
+        self.view_layer_mask = camera.get_layer_mask();
+        self.view_position = camera.get_position();
+        // On the first frame there is no previous frame, so the current one is used
+        let previous_view_projection = self.view_projection_history.advance(view_projection);
+        camera.update_buffer(&mut self.uniform_buffer, offset, &previous_view_projection)?;
+        self.uniform_buffer.write(
+            &self.fog.uniform_data(),
+            offset + std::mem::size_of::<CameraUniformData>(),
+        )?;
+        // The shadow map follows the camera
+        self.shadow_view_projection = self.lights.directional_lights().first().map(|light| {
+            shadow::light_view_projection(
+                &light.direction,
+                &camera.get_position(),
+                self.config.shadow_distance,
+                self.shadow_map.resolution,
+            )
+        });
+        self.uniform_buffer.write(
+            &match self.shadow_view_projection.as_ref() {
+                Some(matrix) => self.shadow_map.uniform_data(matrix),
+                None => shadow::NO_SHADOW,
+            },
+            offset + SHADOW_UNIFORM_OFFSET,
+        )?;
+        self.uniform_buffer
+            .write(&PASS_TONE_MAPPING, offset + TONE_MAP_UNIFORM_OFFSET)?;
+        // A clone, so the methods below can borrow all of self while the lock is held
+        let allocator = self.allocator.clone();
+        if let Ok(mut alloc) = lock_allocator(&allocator) {
+            chunk_changes = self.scene_tree.update_chunks(
+                &camera.get_position(),
+                self.config.chunk_activation_radius,
+                Instant::now(),
+                &self.context.device,
+                alloc.deref_mut(),
+                self.buffer_manager.clone(),
+            )?;
+            self.object_upload_stats = self.scene_tree.prepare_frame(alloc.deref_mut())?;
+            self.update_software_cursor(alloc.deref_mut(), image_index as usize)?;
+            self.update_gizmo(alloc.deref_mut(), image_index as usize, camera)?;
+            self.update_emitters(alloc.deref_mut(), image_index as usize, camera)?;
+        } else {
+            return Err(AllocatorUnavailable.into());
+        }
+        self.update_object_params_set()?;
+        if let Some(callback) = &mut self.chunk_callback {
+            for (chunk, event) in chunk_changes {
+                callback(chunk, event);
+            }
+        }
+
+        self.free_retired_resources()?;
+        self.update_exposure()?;
+
+        self.submit_commands(image_index as usize, window, ui_func)?;
+
+        self.present(image_index)?;
+        // Headless frames are not in flight, they are done when end_frame returns
+        if self.swapchain.is_headless() {
+            self.timeline.wait_for(self.timeline.last_submitted())?;
+        }
+        self.current_image = (self.current_image + 1) % FRAMES_IN_FLIGHT;
+        self.finish_frame_timing();
+        self.last_render = Instant::now();
+        self.last_presented_view = Some(view_projection);
+        self.redraw_requested = false;
+        Ok(FrameStatus::Rendered)
+    }
+
+    // Adds what the subsystems did since the last call to frame_events. Anything that can
+    // change which objects share a draw invalidates the instance groups.
+    fn collect_frame_events(&mut self) {
+        let events = self.scene_tree.take_frame_events()
+            | self.material_system.take_frame_events()
+            | self.text.take_frame_events()
+            | self.texture_storage.take_frame_events();
+        if events.intersects(
+            FrameEvents::SCENE_CHANGED | FrameEvents::MATERIAL_BUILT | FrameEvents::PIPELINE_BUILT,
+        ) {
+            self.instance_groups.invalidate();
+        }
+        self.frame_events |= events;
+    }
+
+    // Records the frame time and logs spikes together with everything expensive that
+    // happened since the last rendered frame
+    fn finish_frame_timing(&mut self) {
+        self.collect_frame_events();
+        // The first frame includes startup, and throttled background frames are slow on purpose
+        if self.frame_number > 0 && !self.is_in_background() {
+            let milliseconds = self.frame_delta.as_secs_f32() * 1000.0;
+            self.frame_times.push(milliseconds);
+            if let Some(threshold) = self.config.frame_spike_threshold {
+                if self.frame_delta > threshold {
+                    warn!(
+                        "Frame {} took {:.2} ms (dirty: {})",
+                        self.frame_number, milliseconds, self.frame_events
+                    );
+                }
+            }
+        }
+        self.frame_events = FrameEvents::empty();
+        (
+            self.frame_stats.buffer_bytes_allocated,
+            self.frame_stats.buffer_bytes_freed,
+        ) = self.buffer_manager.lock().unwrap().take_byte_counts();
+        self.last_frame_stats = self.frame_stats;
+        self.frame_number += 1;
+        self.stats_reader.set(self.get_stats());
+    }
+
+    // Nothing gets submitted while skipping, so once the frames in flight are done
+    // everything that was queued for deletion can go
+    fn free_deferred_while_skipping(&mut self) -> RendererResult<()> {
+        self.timeline.wait_idle()?;
+        self.free_retired_resources()
+    }
+
+    // Uploads the lights if they changed since the last upload. With a command buffer
+    // only the changed part is written by it, without one, or when the buffer has to
+    // grow or the cookies changed, everything is written once the frames in flight are
+    // done.
+    fn sync_lights(&mut self, cmd_buf: Option<vk::CommandBuffer>) -> RendererResult<()> {
+        if self.light_storage.is_current(&self.lights) {
+            return Ok(());
+        }
+        let probes = self.lights.probes();
+        if probes.len() != self.light_probes.len()
+            || probes
+                .iter()
+                .zip(self.light_probes.iter())
+                .any(|(a, b)| a.position != b.position)
+        {
+            self.scene_tree.set_light_probes(
+                probes.iter().map(|probe| probe.position.coords).collect(),
+                self.config.probe_blend,
+            );
+        }
+        self.light_probes = self.lights.probes().to_vec();
+
+        let data = self.lights.storage_data();
+        let cookies = self.lights.cookie_textures();
+        match cmd_buf {
+            Some(cmd_buf) if self.light_storage.can_record(&data, &cookies) => {
+                self.light_storage.record_upload(
+                    &self.context.device,
+                    cmd_buf,
+                    data,
+                    self.lights.generation(),
+                );
+            }
+            _ => {
+                self.timeline.wait_for(self.timeline.last_submitted())?;
+                if let Ok(mut allo) = lock_allocator(&self.allocator) {
+                    self.light_storage.write(
+                        &self.context.device,
+                        allo.deref_mut(),
+                        self.buffer_manager.clone(),
+                        &self.lights,
+                        self.descriptor_set_lights,
+                        &self.texture_storage,
+                        self.cookie_sampler,
+                        self.fallback_texture,
+                    )?;
+                } else {
+                    return Err(AllocatorUnavailable.into());
+                }
+                #[cfg(feature = "defrag")]
+                self.defrag.set_pinned(cookies);
+            }
+        }
+        Ok(())
+    }
+
+    // See MaterialSystem::build_material
+    pub fn build_material(
+        &mut self,
+        material_name: &str,
+        info: MaterialData,
+    ) -> RendererResult<Handle<Material>> {
+        self.request_redraw();
+        if let Ok(mut allo) = lock_allocator(&self.allocator) {
+            self.material_system.build_material(
+                &self.context.device,
+                allo.deref_mut(),
+                &self.texture_storage,
+                self.buffer_manager.clone(),
+                &mut self.descriptor_layout_cache,
+                &mut self.descriptor_allocator,
+                material_name,
+                info,
+            )
+        } else {
+            Err(AllocatorUnavailable.into())
+        }
+    }
+
+    // Copy on write, see MaterialSystem::set_material_texture
+    pub fn set_material_texture(
+        &mut self,
+        material_name: &str,
+        slot: MaterialTextureSlot,
+        texture: Handle<Texture>,
+    ) -> RendererResult<Handle<Material>> {
+        self.request_redraw();
+        let handle = self.material_system.get_material_handle(material_name)?;
+        if !self.material_system.is_shared(handle) {
+            // The descriptor set gets rewritten, which is not allowed while it is in use
+            self.timeline.wait_for(self.timeline.last_submitted())?;
+        }
+        if let Ok(mut allo) = lock_allocator(&self.allocator) {
+            self.material_system.set_material_texture(
+                &self.context.device,
+                allo.deref_mut(),
+                &self.texture_storage,
+                self.buffer_manager.clone(),
+                &mut self.descriptor_layout_cache,
+                &mut self.descriptor_allocator,
+                material_name,
+                slot,
+                texture,
+            )
+        } else {
+            Err(AllocatorUnavailable.into())
+        }
+    }
+
+    pub fn set_material_parameters(
+        &mut self,
+        material_name: &str,
+        parameters: ShaderParameters,
+    ) -> RendererResult<Handle<Material>> {
+        self.request_redraw();
+        if let Ok(mut allo) = lock_allocator(&self.allocator) {
+            self.material_system.set_parameters(
+                &self.context.device,
+                allo.deref_mut(),
+                &self.texture_storage,
+                self.buffer_manager.clone(),
+                &mut self.descriptor_layout_cache,
+                &mut self.descriptor_allocator,
+                material_name,
+                parameters,
+            )
+        } else {
+            Err(AllocatorUnavailable.into())
+        }
+    }
+
+    // Changes one value of the material's parameter block in place, see
+    // MaterialSystem::set_parameter. Cheap enough to animate parameters every frame.
+    pub fn set_material_parameter<V: Into<ParameterValue>>(
+        &mut self,
+        handle: Handle<Material>,
+        name: &str,
+        value: V,
+    ) -> RendererResult<()> {
+        self.request_redraw();
+        self.material_system.set_parameter(handle, name, value)
+    }
+
+    // Scrolls the texture coordinates of a material by velocity (in UV units per second).
+    // Only touches push constant data, so no descriptor sets are recreated.
+    pub fn animate_material_uv(
+        &mut self,
+        handle: Handle<Material>,
+        velocity: glm::Vec2,
+    ) -> RendererResult<()> {
+        self.request_redraw();
+        let dt = self.frame_delta.as_secs_f32();
+        let material = self.material_system.get_material_by_handle_mut(handle)?;
+        let offset = &mut material.parameters.uv_offset;
+        // Keep the offset in [0, 1) so it doesn't lose precision over time
+        offset.x = (offset.x + velocity.x * dt).rem_euclid(1.0);
+        offset.y = (offset.y + velocity.y * dt).rem_euclid(1.0);
+        Ok(())
+    }
+
+    // Sets the object_params block of the object's template for this object alone,
+    // T has to be laid out like the block, see SceneTree::set_object_params. Objects
+    // without parameters of their own draw with zeroes.
+    pub fn set_object_params<T: Copy>(
+        &mut self,
+        object: Handle<SceneObject>,
+        params: &T,
+    ) -> RendererResult<()> {
+        self.request_redraw();
+        let material = self
+            .scene_tree
+            .get_object(object)
+            .ok_or::<RendererError>(InvalidHandle.into())?
+            .material;
+        let template = self
+            .material_system
+            .get_material_by_handle(material)?
+            .original;
+        let expected = self
+            .material_system
+            .get_effect_template_by_handle(template)?
+            .object_params_size;
+        let size = std::mem::size_of::<T>();
+        match expected {
+            None => Err(ObjectParamsError(format!(
+                "template {:?} has no {} block",
+                self.material_system.get_effect_template_name(template),
+                OBJECT_PARAMS_BLOCK
+            ))
+            .into()),
+            Some(expected) if expected as usize != size => Err(ObjectParamsError(format!(
+                "{} bytes of parameters for a {} byte block",
+                size, expected
+            ))
+            .into()),
+            Some(_) => self.scene_tree.set_object_params(object, params),
+        }
+    }
+
+    // Replaces the morph targets of the mesh, see Mesh::set_morph_targets
+    pub fn set_morph_targets(
+        &mut self,
+        mesh: Handle<Mesh>,
+        targets: Vec<MorphTarget>,
+    ) -> RendererResult<()> {
+        self.meshs
+            .get_mesh_mut(mesh)
+            .ok_or::<RendererError>(InvalidHandle.into())?
+            .set_morph_targets(targets)?;
+        self.update_morph_deltas()
+    }
+
+    // Uploads the deltas of every mesh again, needed after changing the vertices of a
+    // mesh with morph targets through meshs, e.g. with Mesh::subdivide
+    pub fn update_morph_deltas(&mut self) -> RendererResult<()> {
+        self.request_redraw();
+        let data = morph::pack_deltas(&mut self.meshs);
+        // The buffer may be reallocated, and draws in flight read the old deltas
+        self.timeline.wait_idle()?;
+        if let Ok(mut allo) = lock_allocator(&self.allocator) {
+            if data.is_empty() {
+                self.morph_deltas.fill(allo.deref_mut(), &[0.0f32; 4])?;
+            } else {
+                self.morph_deltas.fill(allo.deref_mut(), &data)?;
+            }
+        } else {
+            return Err(AllocatorUnavailable.into());
+        }
+        morph::update_descriptor(
+            &self.context.device,
+            self.descriptor_set_lights,
+            &self.morph_deltas,
+        );
+        if let Some(preview_scene) = self.preview_scene.as_ref() {
+            morph::update_descriptor(
+                &self.context.device,
+                preview_scene.descriptor_set_lights,
+                &self.morph_deltas,
+            );
+        }
+        Ok(())
+    }
+
+    // Sets the weights of the named morph targets of the object's mesh, the others keep
+    // their weights. Only the MAX_ACTIVE_MORPH_TARGETS largest ones are applied.
+    pub fn set_morph_weights(
+        &mut self,
+        object: Handle<SceneObject>,
+        weights: &[(&str, f32)],
+    ) -> RendererResult<()> {
+        self.request_redraw();
+        let mesh = self
+            .scene_tree
+            .get_object(object)
+            .ok_or::<RendererError>(InvalidHandle.into())?
+            .mesh;
+        let mesh = self
+            .meshs
+            .get_mesh(mesh)
+            .ok_or::<RendererError>(InvalidHandle.into())?;
+        if let Some((name, _)) = weights
+            .iter()
+            .find(|(name, _)| mesh.morph_target_index(name).is_none())
+        {
+            return Err(MorphTargetError(format!("the mesh has no target {:?}", name)).into());
+        }
+        self.scene_tree.set_morph_weights(object, weights)
+    }
+
+    // Rewrites the object parameter set when the scene tree grew its buffer
+    fn update_object_params_set(&mut self) -> RendererResult<()> {
+        let buffer = match self.scene_tree.get_object_params_buffer() {
+            Some(buffer) => buffer.get_buffer().buffer,
+            None => return Ok(()),
+        };
+        if matches!(self.object_params_set, Some((current, _)) if current == buffer) {
+            return Ok(());
+        }
+        let (set, _) = DescriptorBuilder::begin(
+            &mut self.descriptor_layout_cache,
+            &mut self.descriptor_allocator,
+        )
+        .bind_buffer(
+            0,
+            &[vk::DescriptorBufferInfo {
+                buffer,
+                offset: 0,
+                range: MAX_OBJECT_PARAMS_SIZE as u64,
+            }],
+            vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC,
+            VERTEX_AND_FRAGMENT,
+        )
+        .build(&self.context.device)?;
+        self.object_params_set = Some((buffer, set));
+        self.recorded_frames.invalidate();
+        Ok(())
+    }
+
+    // Loads an OBJ file with its MTL files and adds one object per material. They are
+    // siblings under a hidden parent that only carries their transform, to place the
+    // whole model. Each gets a material of base_template with the diffuse texture of its
+    // MTL material, or a 1x1 texture of the diffuse color without one, and a roughness
+    // from the specular exponent.
+    pub fn spawn_obj<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        base_template: &str,
+    ) -> RendererResult<SpawnedObj> {
+        let path = path.as_ref();
+        let submeshes = self.resources()?.new_meshes_from_obj(path)?;
+        if submeshes.is_empty() {
+            return Err(SceneLoadError(format!("{} has no faces", path.display())).into());
+        }
+        let mut textures = Vec::with_capacity(submeshes.len());
+        let mut materials = Vec::with_capacity(submeshes.len());
+        for (_, obj_material) in submeshes.iter() {
+            let texture = match &obj_material.diffuse_texture {
+                Some(texture_path) => match self.new_texture_from_file(texture_path) {
+                    Ok(texture) => Some(texture),
+                    Err(e) => {
+                        warn!(
+                            "{}: could not load {} of material {}, using its diffuse color: {}",
+                            path.display(),
+                            texture_path.display(),
+                            obj_material.name,
+                            e
+                        );
+                        None
+                    }
+                },
+                None => None,
+            };
+            let texture = match texture {
+                Some(texture) => texture,
+                None => {
+                    let [r, g, b] = obj_material.diffuse_color;
+                    self.new_color_texture([r, g, b, 1.0])?
+                }
+            };
+            // The usual conversion of a Blinn-Phong exponent to a GGX roughness
+            let roughness = (2.0 / (obj_material.specular_exponent.max(0.0) + 2.0)).sqrt();
+            let material = self.build_loaded_material(
+                &format!("{}:{}", path.display(), obj_material.name),
+                base_template,
+                texture,
+                0.0,
+                roughness,
+            )?;
+            textures.push(texture);
+            materials.push(material);
+        }
+        let mut resources = self.resources()?;
+        // Never drawn, so any mesh does
+        let parent = resources.new_object(submeshes[0].0, materials[0])?;
+        resources.scene_tree.set_visible(parent, false, false)?;
+        let mut objects = Vec::with_capacity(submeshes.len());
+        for ((mesh, _), material) in submeshes.iter().zip(materials.iter()) {
+            let object = resources.new_object(*mesh, *material)?;
+            resources
+                .get_object_mut(parent)
+                .ok_or::<RendererError>(InvalidHandle.into())?
+                .add_child(object)?;
+            objects.push(object);
+        }
+        Ok(SpawnedObj {
+            parent,
+            objects,
+            meshes: submeshes.into_iter().map(|(mesh, _)| mesh).collect(),
+            materials,
+            textures,
+        })
+    }
+
+    // Loads the default scene of a .gltf or .glb file, or its first one without a default.
+    // Every node becomes a hidden object carrying its transform, with one child object
+    // per primitive of its mesh. The root nodes are children of a hidden root, to place
+    // the whole asset. Each glTF material becomes a material of base_template with the
+    // base color image, or a 1x1 texture of the base color factor without one, and the
+    // metallic and roughness factors. The primitive objects start with the default morph
+    // weights of their node.
+    pub fn spawn_gltf<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        base_template: &str,
+    ) -> RendererResult<SpawnedGltf> {
+        let path = path.as_ref();
+        let scene = load_gltf(path)?;
+        let mut textures = vec![];
+        let mut image_textures = Vec::with_capacity(scene.images.len());
+        for image in scene.images.iter() {
+            let texture = match image {
+                Some(image) => {
+                    let texture = self.new_texture_from_u8(
+                        &image.pixels,
+                        image.width,
+                        image.height,
+                        vk::Format::R8G8B8A8_SRGB,
+                    )?;
+                    textures.push(texture);
+                    Some(texture)
+                }
+                None => None,
+            };
+            image_textures.push(texture);
+        }
+        let mut materials = Vec::with_capacity(scene.materials.len() + 1);
+        for gltf_material in scene.materials.iter() {
+            let image_texture = gltf_material
+                .base_color_image
+                .and_then(|image| image_textures.get(image).copied().flatten());
+            let texture = match image_texture {
+                Some(texture) => texture,
+                None => {
+                    let texture = self.new_color_texture(gltf_material.base_color_factor)?;
+                    textures.push(texture);
+                    texture
+                }
+            };
+            let material = self.build_loaded_material(
+                &format!("{}:{}", path.display(), gltf_material.name),
+                base_template,
+                texture,
+                gltf_material.metallic_factor,
+                gltf_material.roughness_factor,
+            )?;
+            materials.push(material);
+        }
+        // For primitives without a material, the glTF default is white and fully rough
+        let default_material = {
+            let texture = self.new_color_texture([1.0; 4])?;
+            textures.push(texture);
+            self.build_loaded_material(
+                &format!("{}:default", path.display()),
+                base_template,
+                texture,
+                1.0,
+                1.0,
+            )?
+        };
+        materials.push(default_material);
+
+        let mut resources = self.resources()?;
+        let mut mesh_handles = Vec::with_capacity(scene.meshes.len());
+        let mut meshes = vec![];
+        let mut has_morph_targets = false;
+        for primitives in scene.meshes {
+            let mut handles = Vec::with_capacity(primitives.len());
+            for primitive in primitives {
+                let material = primitive
+                    .material
+                    .and_then(|material| materials.get(material).copied())
+                    .unwrap_or(default_material);
+                // The names are the same for every primitive of the mesh
+                let target_names: Vec<String> = primitive
+                    .mesh
+                    .morph_targets()
+                    .iter()
+                    .map(|target| target.name.clone())
+                    .collect();
+                has_morph_targets |= !target_names.is_empty();
+                let mesh = resources.add_mesh(primitive.mesh)?;
+                meshes.push(mesh);
+                handles.push((mesh, material, target_names));
+            }
+            mesh_handles.push(handles);
+        }
+        // The hidden objects are never drawn, so any mesh does
+        let hidden_mesh = *meshes
+            .first()
+            .ok_or_else(|| SceneLoadError(format!("{} has no meshes", path.display())))?;
+        let root = resources.new_object(hidden_mesh, default_material)?;
+        resources.scene_tree.set_visible(root, false, false)?;
+        let mut spawned = SpawnedGltf {
+            root,
+            nodes: vec![],
+            objects: vec![],
+            meshes,
+            materials,
+            textures,
+        };
+        let mut stack: Vec<(usize, Handle<SceneObject>)> =
+            scene.roots.iter().rev().map(|node| (*node, root)).collect();
+        while let Some((index, parent)) = stack.pop() {
+            let node = match scene.nodes.get(index) {
+                Some(node) => node,
+                None => continue,
+            };
+            let object = resources.new_object(hidden_mesh, default_material)?;
+            resources.scene_tree.set_visible(object, false, false)?;
+            {
+                let object_ref = resources
+                    .get_object_mut(object)
+                    .ok_or::<RendererError>(InvalidHandle.into())?;
+                object_ref.object.position = node.translation;
+                object_ref.object.rotation = node.rotation;
+                object_ref.object.scaling = node.scale;
+            }
+            resources
+                .get_object_mut(parent)
+                .ok_or::<RendererError>(InvalidHandle.into())?
+                .add_child(object)?;
+            for (mesh, material, target_names) in node
+                .mesh
+                .and_then(|mesh| mesh_handles.get(mesh))
+                .into_iter()
+                .flatten()
+            {
+                let primitive = resources.new_object(*mesh, *material)?;
+                let weights: Vec<(&str, f32)> = target_names
+                    .iter()
+                    .map(String::as_str)
+                    .zip(node.morph_weights.iter().copied())
+                    .filter(|(_, weight)| *weight != 0.0)
+                    .collect();
+                resources
+                    .scene_tree
+                    .set_morph_weights(primitive, &weights)?;
+                resources
+                    .get_object_mut(object)
+                    .ok_or::<RendererError>(InvalidHandle.into())?
+                    .add_child(primitive)?;
+                spawned.objects.push(primitive);
+            }
+            spawned.nodes.push((node.name.clone(), object));
+            stack.extend(node.children.iter().rev().map(|child| (*child, object)));
+        }
+        drop(resources);
+        if has_morph_targets {
+            self.update_morph_deltas()?;
+        }
+        Ok(spawned)
+    }
+
+    // A 1x1 texture of a linear color, for materials loaded without an image
+    fn new_color_texture(&mut self, color: [f32; 4]) -> RendererResult<Handle<Texture>> {
+        let pixel = color.map(|c| (c.clamp(0.0, 1.0) * 255.0).round() as u8);
+        self.new_texture_from_u8(&pixel, 1, 1, vk::Format::R8G8B8A8_UNORM)
+    }
+
+    // A material of base_template for the loaders, with the parameters of default.frag
+    fn build_loaded_material(
+        &mut self,
+        name: &str,
+        base_template: &str,
+        texture: Handle<Texture>,
+        metallic: f32,
+        roughness: f32,
+    ) -> RendererResult<Handle<Material>> {
+        let mut parameters = ShaderParameters::default();
+        parameters.set("metallic", metallic);
+        parameters.set("roughness", roughness);
+        self.build_material(
+            name,
+            MaterialData {
+                textures: HashMap::from([(MaterialTextureSlot::Albedo, texture)]),
+                buffers: vec![],
+                parameters,
+                base_template: base_template.to_string(),
+            },
+        )
+    }
+
+    // Locks the allocator until the context is dropped
+    pub fn resources(&mut self) -> RendererResult<ResourceContext<'_>> {
+        self.request_redraw();
+        let allocator = lock_allocator(&self.allocator).map_err(|_| AllocatorUnavailable)?;
+        Ok(ResourceContext {
+            allocator,
+            device: &self.context.device,
+            buffer_manager: &self.buffer_manager,
+            command_pool: self.graphics_command_pool,
+            queue: self.context.graphics_queue.queue,
+            meshs: &mut self.meshs,
+            texture_storage: &mut self.texture_storage,
+            scene_tree: &mut self.scene_tree,
+        })
+    }
+
+    pub fn new_texture_from_file<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+    ) -> RendererResult<Handle<Texture>> {
+        self.resources()?.new_texture_from_file(path)
+    }
+
+    pub fn new_texture_from_file_with_options<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        options: TextureCreateOptions,
+    ) -> RendererResult<Handle<Texture>> {
+        self.resources()?
+            .new_texture_from_file_with_options(path, options)
+    }
+
+    pub fn new_texture_from_u8(
+        &mut self,
+        data: &[u8],
+        width: u32,
+        height: u32,
+        format: vk::Format,
+    ) -> RendererResult<Handle<Texture>> {
+        self.resources()?
+            .new_texture_from_u8(data, width, height, format)
+    }
+
+    // Decodes the file of a texture again and puts the new image in under the same
+    // handle, so materials keep using it. Fails for textures not loaded from a file.
+    pub fn reload_texture(&mut self, texture: Handle<Texture>) -> RendererResult<()> {
+        let path = self
+            .texture_storage
+            .get_texture_path(texture)
+            .ok_or::<RendererError>(InvalidHandle.into())?
+            .to_path_buf();
+        // Keeps a texture loaded without mipmaps that way, the new size may need more
+        // levels
+        let options = TextureCreateOptions {
+            generate_mipmaps: self
+                .texture_storage
+                .get_texture(texture)
+                .map_or(true, |texture| texture.mip_levels() > 1),
+        };
+        let new_texture = if let Ok(mut allo) = lock_allocator(&self.allocator) {
+            Texture::from_file(
+                &path,
+                options,
+                &self.context.device,
+                allo.deref_mut(),
+                self.buffer_manager.clone(),
+                self.graphics_command_pool,
+                self.context.graphics_queue.queue,
+            )?
+        } else {
+            return Err(AllocatorUnavailable.into());
+        };
+        // The descriptor sets get rewritten, which is not allowed while they are in use
+        self.timeline.wait_for(self.timeline.last_submitted())?;
+        self.texture_storage
+            .replace_image(texture, new_texture, self.timeline.last_submitted())?;
+        let materials = self.material_system.rewrite_texture_descriptors(
+            &self.context.device,
+            &self.texture_storage,
+            texture,
+        )?;
+        info!(
+            "Reloaded texture {} ({} materials updated)",
+            path.display(),
+            materials
+        );
+        Ok(())
+    }
+
+    // Files that can't be decoded yet are usually still being written, so they are
+    // tried again later instead of failing the frame
+    fn reload_changed_assets(&mut self) -> RendererResult<()> {
+        for path in self.texture_storage.file_paths() {
+            self.asset_watcher.watch(path);
+        }
+        let now = Instant::now();
+        for path in self.asset_watcher.poll(now) {
+            match self.texture_storage.find_texture_by_path(&path) {
+                Some(texture) => {
+                    if let Err(e) = self.reload_texture(texture) {
+                        warn!("Unable to reload {}, retrying: {}", path.display(), e);
+                        self.asset_watcher.retry(&path, now);
+                    }
+                }
+                None => self.asset_watcher.unwatch(&path),
+            }
+        }
+        Ok(())
+    }
+
+    // Fails with TextureInUse while materials use the texture, unless force is set, in
+    // which case those materials get the fallback texture instead. The texture itself is
+    // destroyed once the frames using it are done.
+    pub fn destroy_texture(&mut self, texture: Handle<Texture>, force: bool) -> RendererResult<()> {
+        self.request_redraw();
+        if self.texture_storage.get_texture(texture).is_none() {
+            return Err(InvalidHandle.into());
+        }
+        if self.material_system.default_textures().contains(texture) {
+            return Err(TextureInUse::from(vec!["<default texture>".to_string()]).into());
+        }
+        if self.material_system.texture_reference_count(texture) > 0 {
+            if !force {
+                return Err(TextureInUse::from(
+                    self.material_system.material_names_using_texture(texture),
+                )
+                .into());
+            }
+            // The descriptor sets get rewritten, which is not allowed while they are in use
+            self.timeline.wait_for(self.timeline.last_submitted())?;
+            self.material_system.replace_texture(
+                &self.context.device,
+                &self.texture_storage,
+                texture,
+                self.fallback_texture,
+            )?;
+        }
+        self.texture_storage
+            .queue_destroy(texture, self.timeline.last_submitted())
+    }
+
+    // Fails with TextureInUse while materials use the texture. Once it is gone the handle
+    // finds nothing, also after its slot is reused.
+    pub fn remove_texture(&mut self, texture: Handle<Texture>) -> RendererResult<()> {
+        self.destroy_texture(texture, false)
+    }
+
+    // Moves mesh buffers and textures into new allocations, largest first, so they fill
+    // the holes left in the blocks of the allocator and the blocks they leave empty get
+    // freed. Waits for the GPU, so it is meant for loading screens and other idle moments.
+    // Stops after the first move that exceeds budget, so every call makes progress, and
+    // continues where it stopped on the next call. Both copies of a resource exist until it is moved, so there has to
+    // be room for the largest one.
+    #[cfg(feature = "defrag")]
+    pub fn defragment(&mut self, budget: Duration) -> RendererResult<DefragReport> {
+        let start = Instant::now();
+        // Buffers and descriptor sets are swapped in place, which is not allowed while
+        // they are in use, and the recorded frames still refer to the old ones
+        self.timeline.wait_idle()?;
+        self.recorded_frames.invalidate();
+        self.free_retired_resources()?;
+        if !self.defrag.in_pass() {
+            let candidates = self.defrag_candidates();
+            self.defrag.start(candidates);
+        }
+        let mut report = DefragReport::default();
+        loop {
+            match self.defrag.next_candidate() {
+                Some(DefragCandidate::Buffer(handle, _)) => {
+                    if let Some(bytes) = self.relocate_buffer(handle)? {
+                        report.moved_buffers += 1;
+                        report.bytes_moved += bytes;
+                    }
+                }
+                Some(DefragCandidate::Texture(handle, _)) => {
+                    if let Some(bytes) = self.relocate_texture(handle)? {
+                        report.moved_textures += 1;
+                        report.bytes_moved += bytes;
+                    }
+                }
+                None => {
+                    report.finished = true;
+                    break;
+                }
+            }
+            // Nothing was submitted since waiting, so the old allocation goes right away
+            // and the next resource can take its place
+            self.free_retired_resources()?;
+            if start.elapsed() >= budget {
+                break;
+            }
+        }
+        info!(
+            "Defragmented {} buffers and {} textures, {} bytes moved{}",
+            report.moved_buffers,
+            report.moved_textures,
+            report.bytes_moved,
+            if report.finished {
+                ", pass finished"
+            } else {
+                ""
+            }
+        );
+        Ok(report)
+    }
+
+    // The vertex and index buffers of every mesh and every texture Texture::is_movable
+    // that is only bound through materials
+    #[cfg(feature = "defrag")]
+    fn defrag_candidates(&self) -> Vec<DefragCandidate> {
+        let mut candidates = vec![];
+        let buffer_manager = self.buffer_manager.lock().unwrap();
+        for mesh in self.meshs.iter() {
+            for buffer in [&mesh.vertex_buffer, &mesh.index_buffer]
+                .into_iter()
+                .flatten()
+            {
+                if let Some(details) = buffer_manager.get_buffer(buffer.get_handle()) {
+                    candidates.push(DefragCandidate::Buffer(buffer.get_handle(), details.size));
+                }
+            }
+        }
+        for (handle, size) in self.texture_storage.movable_textures() {
+            if !self.is_texture_pinned(handle) {
+                candidates.push(DefragCandidate::Texture(handle, size));
+            }
+        }
+        candidates
+    }
+
+    #[cfg(feature = "defrag")]
+    fn is_texture_pinned(&self, texture: Handle<Texture>) -> bool {
+        texture == self.fallback_texture
+            || self.material_system.default_textures().contains(texture)
+            || self.defrag.is_pinned(texture)
+    }
+
+    // None for buffers freed since the pass started
+    #[cfg(feature = "defrag")]
+    fn relocate_buffer(
+        &self,
+        handle: Handle<buffer::InternalBuffer>,
+    ) -> RendererResult<Option<u64>> {
+        if let Ok(mut allo) = lock_allocator(&self.allocator) {
+            let mut buffer_manager = self.buffer_manager.lock().unwrap();
+            if buffer_manager.get_buffer(handle).is_none() {
+                return Ok(None);
+            }
+            buffer_manager.relocate(handle, allo.deref_mut()).map(Some)
+        } else {
+            Err(AllocatorUnavailable.into())
+        }
+    }
+
+    // None for textures removed or pinned since the pass started
+    #[cfg(feature = "defrag")]
+    fn relocate_texture(&mut self, handle: Handle<Texture>) -> RendererResult<Option<u64>> {
+        if self.is_texture_pinned(handle) {
+            return Ok(None);
+        }
+        let texture = match self.texture_storage.get_texture_mut(handle) {
+            Some(texture) if texture.is_movable() => texture,
+            _ => return Ok(None),
+        };
+        let bytes = texture.allocation_size();
+        let new_texture = if let Ok(mut allo) = lock_allocator(&self.allocator) {
+            texture.relocate(
+                &self.context.device,
+                allo.deref_mut(),
+                self.graphics_command_pool,
+                self.context.graphics_queue.queue,
+            )?
+        } else {
+            return Err(AllocatorUnavailable.into());
+        };
+        self.texture_storage
+            .replace_image(handle, new_texture, self.timeline.last_submitted())?;
+        self.material_system.rewrite_texture_descriptors(
+            &self.context.device,
+            &self.texture_storage,
+            handle,
+        )?;
+        Ok(Some(bytes))
+    }
+
+    // A texture named by a material or scene file, relative to it. Textures already loaded
+    // from the same file are reused, missing ones are replaced by a white texture.
+    fn load_texture_of_file(
+        &mut self,
+        path: &Path,
+        texture: &Path,
+    ) -> RendererResult<Handle<Texture>> {
+        let texture_path = MaterialFile::resolve_texture_path(path, texture);
+        if let Ok(mut allo) = lock_allocator(&self.allocator) {
+            if texture_path.is_file() {
+                self.texture_storage.get_or_load_texture_from_file(
+                    &texture_path,
+                    &self.context.device,
+                    allo.deref_mut(),
+                    self.buffer_manager.clone(),
+                    self.graphics_command_pool,
+                    self.context.graphics_queue.queue,
+                )
+            } else {
+                warn!(
+                    "{}: texture {} not found, using a white texture instead",
+                    path.display(),
+                    texture_path.display()
+                );
+                self.texture_storage.get_or_create_fallback_texture(
+                    &self.context.device,
+                    allo.deref_mut(),
+                    self.buffer_manager.clone(),
+                    &self.graphics_command_pool,
+                    &self.context.graphics_queue.queue,
+                )
+            }
+        } else {
+            Err(AllocatorUnavailable.into())
+        }
+    }
+
+    // Builds the material described by a .material file, see MaterialFile.
+    // Textures already loaded from the same file are reused, missing ones are
+    // replaced by a white texture. The material is registered under the path.
+    pub fn load_material_file<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+    ) -> RendererResult<Handle<Material>> {
+        self.request_redraw();
+        let path = path.as_ref();
+        let file = MaterialFile::load(path)?;
+        let textures = file
+            .textures
+            .iter()
+            .map(|texture| self.load_texture_of_file(path, texture))
+            .collect::<RendererResult<Vec<_>>>()?;
+        let textures = self
+            .material_system
+            .textures_in_template_order(&file.base_template, textures)?;
+        self.build_material(
+            &path.to_string_lossy(),
+            MaterialData {
+                textures,
+                buffers: vec![],
+                parameters: file.shader_parameters(),
+                base_template: file.base_template,
+            },
+        )
+    }
+
+    // Writes a material to a .material file that load_material_file turns back into
+    // the same material. Fails if one of its textures was not loaded from a file.
+    pub fn save_material_file<P: AsRef<Path>>(
+        &self,
+        handle: Handle<Material>,
+        path: P,
+    ) -> RendererResult<()> {
+        let path = path.as_ref();
+        let material = self.material_system.get_material_by_handle(handle)?;
+        let base_template = self
+            .material_system
+            .get_effect_template_name(material.original)
+            .ok_or::<RendererError>(InvalidHandle.into())?;
+        let textures = self
+            .material_system
+            .textures_to_template_order(handle)?
+            .iter()
+            .map(|texture| {
+                self.texture_storage
+                    .get_texture_path(*texture)
+                    .map(|texture_path| MaterialFile::relative_texture_path(path, texture_path))
+                    .ok_or_else(|| {
+                        MaterialFileError(format!(
+                            "{}: texture {:?} was not loaded from a file",
+                            path.display(),
+                            texture
+                        ))
+                        .into()
+                    })
+            })
+            .collect::<RendererResult<Vec<_>>>()?;
+        MaterialFile::new(base_template, textures, &material.parameters).save(path)
+    }
+
+    // Writes the objects of the scene tree with their meshes and materials, and the lights,
+    // to a file that load_scene_file builds the same scene from, see SceneFile. Objects
+    // whose mesh was not built from an OBJ file or a primitive are skipped with their
+    // children, textures that were not loaded from a file are left to the slot's default.
+    // Light probes, groups and chunks are not written.
+    pub fn save_scene<P: AsRef<Path>>(&self, path: P) -> RendererResult<()> {
+        let path = path.as_ref();
+        let mut file = SceneFile::default();
+        let mut mesh_names = HashMap::new();
+        let mut material_names: HashMap<Handle<Material>, String> = HashMap::new();
+        let mut indices = HashMap::new();
+        // Depth first from the roots, so parents are written before their children
+        let mut pending = self
+            .scene_tree
+            .iter_with_handles()
+            .filter(|(_, object)| object.get_parent().is_none())
+            .map(|(handle, _)| handle)
+            .collect::<Vec<_>>();
+        pending.reverse();
+        while let Some(handle) = pending.pop() {
+            let object = self
+                .scene_tree
+                .get_object(handle)
+                .ok_or::<RendererError>(InvalidHandle.into())?;
+            let source = match self.meshs.get_mesh_source(object.mesh) {
+                Some(source) => source,
+                None => {
+                    warn!(
+                        "{}: the mesh of object {} can't be saved, skipping it and its children",
+                        path.display(),
+                        handle.id()
+                    );
+                    continue;
+                }
+            };
+            let mesh = mesh_names
+                .entry(object.mesh)
+                .or_insert_with(|| {
+                    let name = format!("mesh{}", object.mesh.id());
+                    let source = match source {
+                        MeshSource::Obj(obj) => {
+                            MeshSource::Obj(MaterialFile::relative_texture_path(path, obj))
+                        }
+                        other => other.clone(),
+                    };
+                    file.meshes.insert(name.clone(), source);
+                    name
+                })
+                .clone();
+            let material = match material_names.get(&object.material) {
+                Some(name) => name.clone(),
+                None => {
+                    let name = self
+                        .material_system
+                        .get_material_name(object.material)
+                        .map(str::to_string)
+                        .unwrap_or_else(|| format!("material{}", object.material.id()));
+                    let material = self.scene_file_material(object.material, path)?;
+                    file.materials.insert(name.clone(), material);
+                    material_names.insert(object.material, name.clone());
+                    name
+                }
+            };
+            indices.insert(handle, file.objects.len());
+            file.objects.push(SceneFileObject {
+                mesh,
+                material,
+                position: object.position.into(),
+                rotation: object.rotation.coords.into(),
+                scaling: object.scaling.into(),
+                parent: object
+                    .get_parent()
+                    .and_then(|parent| indices.get(&parent).copied()),
+                visible: object.visible,
+            });
+            pending.extend(object.get_children().iter().rev().copied());
+        }
+
+        file.lights.extend(
+            self.lights
+                .directional_lights()
+                .iter()
+                .map(SceneFileLight::from),
+        );
+        file.lights
+            .extend(self.lights.point_lights().iter().map(SceneFileLight::from));
+        for light in self.lights.spot_lights() {
+            let cookie = light.cookie.and_then(|cookie| {
+                let cookie_path = self
+                    .texture_storage
+                    .get_texture_path(cookie)
+                    .map(|cookie_path| MaterialFile::relative_texture_path(path, cookie_path));
+                if cookie_path.is_none() {
+                    warn!(
+                        "{}: a spot light cookie was not loaded from a file, leaving it out",
+                        path.display()
+                    );
+                }
+                cookie_path
+            });
+            file.lights
+                .push(SceneFileLight::from_spot_light(light, cookie));
+        }
+        file.save(path)
+    }
+
+    fn scene_file_material(
+        &self,
+        handle: Handle<Material>,
+        path: &Path,
+    ) -> RendererResult<SceneFileMaterial> {
+        let material = self.material_system.get_material_by_handle(handle)?;
+        let base_template = self
+            .material_system
+            .get_effect_template_name(material.original)
+            .ok_or::<RendererError>(InvalidHandle.into())?;
+        if !material.buffers.is_empty() {
+            warn!(
+                "{}: the uniform buffers of material {:?} are not saved",
+                path.display(),
+                handle
+            );
+        }
+        let textures = self
+            .material_system
+            .optional_textures_to_template_order(handle)?
+            .into_iter()
+            .map(|texture| {
+                let texture = texture?;
+                let texture_path = self.texture_storage.get_texture_path(texture);
+                if texture_path.is_none() {
+                    warn!(
+                        "{}: texture {:?} was not loaded from a file, saving its slot empty",
+                        path.display(),
+                        texture
+                    );
+                }
+                texture_path
+                    .map(|texture_path| MaterialFile::relative_texture_path(path, texture_path))
+            })
+            .collect();
+        Ok(SceneFileMaterial::new(
+            base_template,
+            textures,
+            &material.parameters,
+        ))
+    }
+
+    // Adds everything a file written by save_scene describes to the current scene. The
+    // materials are registered under their names in the file, the textures under their
+    // paths in SceneLoadResult::textures.
+    pub fn load_scene_file<P: AsRef<Path>>(&mut self, path: P) -> RendererResult<SceneLoadResult> {
+        self.request_redraw();
+        let mut ticket = self.load_scene_file_incremental(path);
+        self.advance_loading(&mut ticket, Duration::MAX)?;
+        Ok(ticket
+            .take_result()
+            .expect("Scene finished loading without a result?"))
+    }
+
+    // Like load_scene_file, in the steps of advance_loading. The file is read and parsed
+    // on a loader thread, the first step waits for it as long as the budget allows. The
+    // total of the progress is only known once the file is parsed.
+    pub fn load_scene_file_incremental<P: AsRef<Path>>(&self, path: P) -> SceneLoadTicket {
+        SceneLoadTicket::from_file(path.as_ref().to_path_buf())
+    }
+
+    // One material of a scene file, with the textures it uses
+    fn load_file_material(
+        &mut self,
+        path: &Path,
+        name: &str,
+        material: &SceneFileMaterial,
+        result: &mut SceneLoadResult,
+    ) -> RendererResult<()> {
+        let mut textures = Vec::with_capacity(material.textures.len());
+        for texture in material.textures.iter() {
+            textures.push(match texture {
+                Some(texture) => {
+                    let handle = self.load_texture_of_file(path, texture)?;
+                    result
+                        .textures
+                        .insert(texture.to_string_lossy().to_string(), handle);
+                    Some(handle)
+                }
+                None => None,
+            });
+        }
+        let textures = self
+            .material_system
+            .optional_textures_in_template_order(&material.base_template, textures)?;
+        let handle = self.build_material(
+            name,
+            MaterialData {
+                textures,
+                buffers: vec![],
+                parameters: material.shader_parameters(),
+                base_template: material.base_template.clone(),
+            },
+        )?;
+        result.materials.insert(name.to_string(), handle);
+        Ok(())
+    }
+
+    // The objects and lights of a scene file, after its meshes and materials
+    fn add_file_objects(
+        &mut self,
+        path: &Path,
+        file: &SceneFile,
+        result: &mut SceneLoadResult,
+    ) -> RendererResult<()> {
+        // SceneFile::load checked the references
+        if let Ok(mut allo) = lock_allocator(&self.allocator) {
+            for object in file.objects.iter() {
+                let handle = self.scene_tree.new_object(
+                    result.meshes[&object.mesh],
+                    result.materials[&object.material],
+                    &self.context.device,
+                    allo.deref_mut(),
+                    self.buffer_manager.clone(),
+                )?;
+                {
+                    let obj_ref = self
+                        .scene_tree
+                        .get_object_mut(handle, allo.deref_mut())
+                        .ok_or::<RendererError>(InvalidHandle.into())?;
+                    obj_ref.object.position = object.position.into();
+                    obj_ref.object.rotation = object.rotation();
+                    obj_ref.object.scaling = object.scaling.into();
+                    obj_ref.object.visible = object.visible;
+                }
+                if let Some(parent) = object.parent {
+                    let mut parent_ref = self
+                        .scene_tree
+                        .get_object_mut(result.objects[parent], allo.deref_mut())
+                        .ok_or::<RendererError>(InvalidHandle.into())?;
+                    parent_ref.add_child(handle)?;
+                }
+                result.objects.push(handle);
+            }
+        } else {
+            return Err(AllocatorUnavailable.into());
+        }
+
+        for light in file.lights.iter() {
+            let cookie = match light {
+                SceneFileLight::Spot {
+                    cookie: Some(cookie),
+                    ..
+                } => Some(self.load_texture_of_file(path, cookie)?),
+                _ => None,
+            };
+            let handle = self.lights.add_light(light.to_light());
+            if let (LightHandle::Spot(spot), Some(cookie)) = (handle, cookie) {
+                self.lights.attach_cookie(spot, cookie)?;
+            }
+            result.lights.push(handle);
+        }
+        Ok(())
+    }
+
+    // Builds a forward template from SPIR-V compiled by the caller. Sets 0 and 1 must
+    // only declare bindings from GLOBAL_DESCRIPTORS, set 2 is the material and set 3 can
+    // hold the object_params block, anything else is rejected with a list of the offending
+    // bindings. Sets in user_managed_sets
+    // are skipped by the check and have to be bound with set_descriptor_hook instead.
+    pub fn register_effect_template(
+        &mut self,
+        name: &str,
+        vertex_spirv: &[u32],
+        fragment_spirv: &[u32],
+        user_managed_sets: &[u32],
+    ) -> RendererResult<Handle<EffectTemplate>> {
+        self.request_redraw();
+        let vertex_shader = format!("{}.vert", name);
+        let fragment_shader = format!("{}.frag", name);
+        self.shader_cache.add_shader_module(
+            &self.context.device,
+            &vertex_shader,
+            vertex_spirv.to_vec(),
+        )?;
+        self.shader_cache.add_shader_module(
+            &self.context.device,
+            &fragment_shader,
+            fragment_spirv.to_vec(),
+        )?;
+        let contract = user_managed_sets
+            .iter()
+            .fold(BindingContract::scene(), |contract, set| {
+                contract.with_user_managed_set(*set)
+            });
+        let handle = self.material_system.register_template(
+            &self.context.device,
+            self.scene_render_pass,
+            &mut self.shader_cache,
+            name,
+            &vertex_shader,
+            &fragment_shader,
+            &contract,
+        )?;
+        if let Ok(template) = self.material_system.get_effect_template_by_handle(handle) {
+            self.context.debug_namer.name(
+                template.pass_shaders[MeshPassType::Forward].pipeline,
+                &format!("{}/forward", name),
+            );
+        }
+        Ok(handle)
+    }
+
+    // Recompiles the shaders in ./shaders whose files changed and rebuilds the templates
+    // using them, returns the shaders that changed. On an error, like a shader that
+    // doesn't compile, everything stays as it was. The replaced pipelines are destroyed
+    // once the frames in flight are done with them. Shaders can't change the layouts of
+    // their descriptor sets, their texture slots or their object_params block, that
+    // needs a restart. Compute shaders are not reloaded.
+    #[cfg(feature = "shader-reload")]
+    pub fn reload_shaders(&mut self) -> RendererResult<Vec<String>> {
+        let reload = self.shader_cache.reload_from_disk(&self.context.device)?;
+        if reload.changed().is_empty() {
+            return Ok(vec![]);
+        }
+        let changed = reload.changed().to_vec();
+        match self.material_system.rebuild_templates(
+            &self.context.device,
+            &mut self.shader_cache,
+            &changed,
+        ) {
+            Ok(retired) => {
+                self.shader_cache
+                    .retire(retired, self.timeline.last_submitted());
+                self.material_system
+                    .set_debug_names(&self.context.debug_namer);
+                self.request_redraw();
+                Ok(self
+                    .shader_cache
+                    .finish_reload(&self.context.device, reload))
+            }
+            Err(e) => {
+                self.shader_cache
+                    .revert_reload(&self.context.device, reload);
+                Err(e)
+            }
+        }
+    }
+
+    // Objects whose template has user managed sets are skipped until it has a hook
+    pub fn set_descriptor_hook<F>(&mut self, template: Handle<EffectTemplate>, hook: F)
+    where
+        F: FnMut(&ash::Device, vk::CommandBuffer, vk::PipelineLayout, Handle<SceneObject>)
+            + Send
+            + 'static,
+    {
+        self.request_redraw();
+        self.instance_groups.invalidate();
+        self.descriptor_hooks.insert(template, Box::new(hook));
+    }
+
+    pub fn remove_descriptor_hook(&mut self, template: Handle<EffectTemplate>) {
+        self.request_redraw();
+        self.instance_groups.invalidate();
+        self.descriptor_hooks.remove(&template);
+    }
+
+    // A set with the layout of a user managed set of the template, for its descriptor
+    // hook. The caller writes it and it lives as long as the renderer.
+    pub fn allocate_user_set(
+        &mut self,
+        template: Handle<EffectTemplate>,
+        set: u32,
+    ) -> RendererResult<vk::DescriptorSet> {
+        let template = self
+            .material_system
+            .get_effect_template_by_handle(template)?;
+        if !template.user_managed_sets.contains(&set) {
+            return Err(InvalidHandle.into());
+        }
+        let effect_handle = template.pass_shaders[MeshPassType::Forward]
+            .effect_handle
+            .ok_or(InvalidHandle)?;
+        let effect = self
+            .shader_cache
+            .get_shader_effect_by_handle(effect_handle)?;
+        self.descriptor_allocator
+            .allocate(&self.context.device, effect.set_layouts[set as usize])
+    }
+
+    // Builds a compute pipeline from SPIR-V compiled by the caller, its descriptor sets
+    // are reflected from the shader. Compute shaders get nothing from the renderer, every
+    // set they declare is bound by dispatch_compute.
+    pub fn register_compute_pass(
+        &mut self,
+        name: &str,
+        compute_spirv: &[u32],
+    ) -> RendererResult<Handle<ComputePass>> {
+        let compute_shader = format!("{}.comp", name);
+        self.shader_cache.add_shader_module(
+            &self.context.device,
+            &compute_shader,
+            compute_spirv.to_vec(),
+        )?;
+        self.compute.register(
+            &self.context.device,
+            &mut self.shader_cache,
+            &compute_shader,
+        )
+    }
+
+    // Runs the pass at the start of the next rendered frame, with every set and binding
+    // the shader declares bound to a buffer. Its writes are visible to the vertex and
+    // fragment shaders of that frame. Dispatches run once, in the order they were queued.
+    pub fn dispatch_compute(
+        &mut self,
+        pass: Handle<ComputePass>,
+        group_counts: [u32; 3],
+        bindings: &[ComputeBufferBinding],
+    ) -> RendererResult<()> {
+        self.compute.queue(pass, group_counts, bindings)?;
+        self.request_redraw();
+        Ok(())
+    }
+
+    // Called during render, after the chunks were updated from the camera position
+    pub fn set_chunk_callback<F>(&mut self, callback: F)
+    where
+        F: FnMut(Handle<SceneChunk>, ChunkEvent) + Send + 'static,
+    {
+        self.chunk_callback = Some(Box::new(callback));
+    }
+
+    // Called every time a frame is recorded, after the text and below the gizmo and the
+    // imgui UI. With RedrawPolicy::OnDemand, request_redraw when what it draws changes. It
+    // is dropped while the device is still alive, so what it owns can destroy its Vulkan
+    // objects in its own Drop.
+    pub fn set_overlay_recorder<F>(&mut self, recorder: F)
+    where
+        F: FnMut(&ash::Device, vk::CommandBuffer, vk::Extent2D) + Send + 'static,
+    {
+        self.overlay_recorder = Some(Box::new(recorder));
+        self.request_redraw();
+    }
+
+    pub fn clear_overlay_recorder(&mut self) {
+        self.overlay_recorder = None;
+        self.request_redraw();
+    }
+
+    // The render pass the upscale pass draws the scene to the output in, with
+    // msaa_samples samples. It lives as long as the renderer.
+    pub fn main_render_pass(&self) -> vk::RenderPass {
+        self.render_pass
+    }
+
+    // The render pass text and the overlay recorder draw in, pipelines for it are single
+    // sampled and have no depth. It lives as long as the renderer.
+    pub fn overlay_render_pass(&self) -> vk::RenderPass {
+        self.overlay_render_pass
+    }
+
+    // Bakes Renderer::lights into one texture per object, by drawing its mesh in UV
+    // space with lightmap_bake.frag. The UVs have to be inside [0, 1] and must not
+    // overlap. The first directional light is shadowed by every object that casts
+    // shadows, up to RendererConfig::shadow_distance towards the light. Use the result
+    // with the "lightmapped" template and the textures [albedo, lightmap], and set
+    // NO_DYNAMIC_LIGHTING in the object's flags so the lights aren't added on top of the
+    // baked ones. Can be called between frames.
+    pub fn bake_lightmaps(
+        &mut self,
+        objects: &[Handle<SceneObject>],
+        resolution: u32,
+    ) -> RendererResult<Vec<Handle<Texture>>> {
+        self.request_redraw();
+        let resolution = resolution.max(1);
+        // The shadow map is drawn with the camera slot and instance data of the frames
+        self.timeline.wait_idle()?;
+        self.sync_lights(None)?;
+        if let Ok(mut allo) = lock_allocator(&self.allocator) {
+            self.scene_tree.flush_object_data(allo.deref_mut())?;
+        } else {
+            return Err(AllocatorUnavailable.into());
+        }
+        if self.lightmap_baker.is_none() {
+            self.lightmap_baker = Some(LightmapBaker::new(
+                &self.context.device,
+                &mut self.material_system,
+                &mut self.shader_cache,
+            )?);
+        }
+        let mut lightmaps = vec![];
+        for handle in objects {
+            let object = self
+                .scene_tree
+                .get_object(*handle)
+                .ok_or::<RendererError>(InvalidHandle.into())?;
+            let mesh = object.mesh;
+            let transform = object.get_global_transform();
+            let mut data = self.bake_lightmap(mesh, &transform, resolution)?;
+            lightmap::grow_coverage(&mut data, resolution);
+            if let Ok(mut allo) = lock_allocator(&self.allocator) {
+                lightmaps.push(self.texture_storage.new_texture_from_u8(
+                    &data,
+                    resolution,
+                    resolution,
+                    LIGHTMAP_FORMAT,
+                    &self.context.device,
+                    allo.deref_mut(),
+                    self.buffer_manager.clone(),
+                    &self.graphics_command_pool,
+                    &self.context.graphics_queue.queue,
+                )?);
+            } else {
+                return Err(AllocatorUnavailable.into());
+            }
+        }
+        Ok(lightmaps)
+    }
+
+    // Draws the mesh into a resolution x resolution target in UV space and reads back
+    // the RGBA8 texels, with an alpha of 0 where the mesh isn't. With a directional light
+    // the shadow map is rendered around the object first, the next frame renders it
+    // again.
+    fn bake_lightmap(
+        &mut self,
+        mesh: Handle<Mesh>,
+        transform: &glm::Mat4,
+        resolution: u32,
+    ) -> RendererResult<Vec<u8>> {
+        let bounds = self
+            .meshs
+            .get_mesh(mesh)
+            .ok_or::<RendererError>(InvalidHandle.into())?
+            .bounds()
+            .transformed(transform);
+        let radius = glm::length(&bounds.half_extents()).max(f32::EPSILON);
+        let light_view_projection = self.lights.directional_lights().first().map(|light| {
+            shadow::bake_view_projection(
+                &light.direction,
+                &bounds.center(),
+                radius,
+                self.config.shadow_distance,
+            )
+        });
+        let shadow = match light_view_projection.as_ref() {
+            Some(matrix) => self.shadow_map.uniform_data(matrix),
+            None => shadow::NO_SHADOW,
+        };
+        // The next frame writes its camera again
+        let camera_offset = self.current_image * self.global_uniform_stride;
+        self.uniform_buffer
+            .write(&shadow, camera_offset + SHADOW_UNIFORM_OFFSET)?;
+        // A texel of the map, so surfaces don't shadow themselves
+        let normal_offset = 2.0 * radius / self.shadow_map.resolution as f32;
+        let push_constants = LightmapPushConstants::new(transform, normal_offset);
+
+        let render_pass = self
+            .lightmap_baker
+            .as_ref()
+            .map(|baker| baker.render_pass)
+            .expect("Created by bake_lightmaps");
+        let extent = vk::Extent2D {
+            width: resolution,
+            height: resolution,
+        };
+        let mut target = if let Ok(mut allo) = lock_allocator(&self.allocator) {
+            RenderTarget::new_offscreen(
+                &self.context,
+                allo.deref_mut(),
+                LIGHTMAP_FORMAT,
+                extent,
+                &render_pass,
+                vk::SampleCountFlags::TYPE_1,
+                TransparencyTechnique::SortedAlpha,
+            )?
+        } else {
+            return Err(AllocatorUnavailable.into());
+        };
+        target.set_debug_name(&self.context, "lightmap bake");
+        let result = self
+            .draw_lightmap(
+                mesh,
+                light_view_projection.as_ref(),
+                &push_constants,
+                &target,
+                extent,
+            )
+            .and_then(|_| {
+                self.read_back_texels(
+                    target.image,
+                    extent,
+                    0,
+                    vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                    LIGHTMAP_FORMAT,
+                )
+            });
+        if let Ok(mut allo) = lock_allocator(&self.allocator) {
+            target.destroy(&self.context, allo.deref_mut());
+        }
+        result
+    }
+
+    fn draw_lightmap(
+        &self,
+        mesh: Handle<Mesh>,
+        light_view_projection: Option<&glm::Mat4>,
+        push_constants: &LightmapPushConstants,
+        target: &RenderTarget,
+        extent: vk::Extent2D,
+    ) -> RendererResult<()> {
+        let baker = self
+            .lightmap_baker
+            .as_ref()
+            .expect("Created by bake_lightmaps");
+        let pass = &self
+            .material_system
+            .get_effect_template_by_handle(baker.template)?
+            .pass_shaders[MeshPassType::Forward];
+        let mesh = self
+            .meshs
+            .get_mesh(mesh)
+            .ok_or::<RendererError>(InvalidHandle.into())?;
+        let device = &self.context.device;
+        let command_buffer_alloc_info = vk::CommandBufferAllocateInfo::builder()
+            .command_pool(self.graphics_command_pool)
+            .command_buffer_count(1);
+        let cmd_buf = unsafe { device.allocate_command_buffers(&command_buffer_alloc_info) }?[0];
+        let cmd_begin_info = vk::CommandBufferBeginInfo::builder()
+            .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+        unsafe {
+            device.begin_command_buffer(cmd_buf, &cmd_begin_info)?;
+        }
+        // Layer masks are for cameras, every object casts its shadow into the lightmaps.
+        // The shadow render pass makes the map visible to the fragment shaders after it.
+        if let Some(matrix) = light_view_projection {
+            self.record_shadow_map(cmd_buf, self.current_image, matrix, ALL_LAYERS)?;
+        }
+        // Texels the mesh doesn't cover keep an alpha of 0, see lightmap::grow_coverage
+        let clear_values = [
+            vk::ClearValue {
+                color: vk::ClearColorValue {
+                    float32: [0.0, 0.0, 0.0, 0.0],
+                },
+            },
+            vk::ClearValue {
+                depth_stencil: vk::ClearDepthStencilValue {
+                    depth: 1.0,
+                    stencil: 0,
+                },
+            },
+        ];
+        let render_pass_begin_info = vk::RenderPassBeginInfo::builder()
+            .render_pass(baker.render_pass)
+            .framebuffer(target.framebuffer)
+            .render_area(vk::Rect2D {
+                offset: vk::Offset2D { x: 0, y: 0 },
+                extent,
+            })
+            .clear_values(&clear_values);
+        unsafe {
+            device.cmd_begin_render_pass(
+                cmd_buf,
+                &render_pass_begin_info,
+                vk::SubpassContents::INLINE,
+            );
+            device.cmd_bind_pipeline(cmd_buf, vk::PipelineBindPoint::GRAPHICS, pass.pipeline);
+            device.cmd_bind_descriptor_sets(
+                cmd_buf,
+                vk::PipelineBindPoint::GRAPHICS,
+                pass.layout,
+                0,
+                &[self.descriptor_set_camera, self.descriptor_set_lights],
+                &[(self.current_image * self.global_uniform_stride) as u32],
+            );
+            device.cmd_set_viewport(
+                cmd_buf,
+                0,
+                &[vk::Viewport {
+                    x: 0.,
+                    y: 0.,
+                    width: extent.width as f32,
+                    height: extent.height as f32,
+                    min_depth: 0.,
+                    max_depth: 1.,
+                }],
+            );
+            device.cmd_set_scissor(
+                cmd_buf,
+                0,
+                &[vk::Rect2D {
+                    offset: vk::Offset2D { x: 0, y: 0 },
+                    extent,
+                }],
+            );
+            device.cmd_push_constants(
+                cmd_buf,
+                pass.layout,
+                vk::ShaderStageFlags::VERTEX,
+                0,
+                push_constants.as_bytes(),
+            );
+        }
+        mesh.draw(device, cmd_buf);
+        unsafe {
+            device.cmd_end_render_pass(cmd_buf);
+            device.end_command_buffer(cmd_buf)?;
+        }
+
+        let command_buffers = [cmd_buf];
+        let (uploads, upload_stages) = self.take_upload_waits();
+        let submit_infos = [vk::SubmitInfo::builder()
+            .wait_semaphores(&uploads)
+            .wait_dst_stage_mask(&upload_stages)
+            .command_buffers(&command_buffers)
+            .build()];
+        unsafe {
+            let fence = device.create_fence(&vk::FenceCreateInfo::default(), None)?;
+            device.queue_submit(self.context.graphics_queue.queue, &submit_infos, fence)?;
+            device.wait_for_fences(&[fence], true, std::u64::MAX)?;
+            device.destroy_fence(fence, None);
+            device.free_command_buffers(self.graphics_command_pool, &command_buffers);
+        }
+        Ok(())
+    }
+
+    // Renders the scene around every probe of Renderer::lights into six face_size x
+    // face_size views and replaces the probe's data with their projection, see
+    // probe::project_views.
+    // Objects are lit by the probes as they were before the bake, so baking again adds
+    // another bounce of ambient light. Can be called between frames, the lights are
+    // uploaded afterwards.
+    pub fn bake_light_probes(&mut self, face_size: u32) -> RendererResult<()> {
+        self.request_redraw();
+        if self.lights.probes().is_empty() {
+            return Ok(());
+        }
+        // The views are drawn with the camera slot and instance data of the frames
+        self.timeline.wait_idle()?;
+        self.sync_lights(None)?;
+        if let Ok(mut allo) = lock_allocator(&self.allocator) {
+            self.scene_tree.flush_object_data(allo.deref_mut())?;
+        } else {
+            return Err(AllocatorUnavailable.into());
+        }
+        self.update_object_params_set()?;
+        let extent = vk::Extent2D {
+            width: face_size.max(1),
+            height: face_size.max(1),
+        };
+        let mut target = if let Ok(mut allo) = lock_allocator(&self.allocator) {
+            RenderTarget::new_offscreen(
+                &self.context,
+                allo.deref_mut(),
+                capabilities::HDR_WORKING_FORMAT,
+                extent,
+                &self.scene_render_pass,
+                self.capabilities.msaa_samples,
+                self.capabilities.transparency,
+            )?
+        } else {
+            return Err(AllocatorUnavailable.into());
+        };
+        target.set_debug_name(&self.context, "light probe");
+
+        let mut lights = std::mem::take(&mut self.lights);
+        let result = self.bake_probes_into(&mut lights, &target);
+        self.lights = lights;
+        if let Ok(mut allo) = lock_allocator(&self.allocator) {
+            target.destroy(&self.context, allo.deref_mut());
+        }
+        result?;
+        self.sync_lights(None)
+    }
+
+    fn bake_probes_into(
+        &mut self,
+        lights: &mut LightManager,
+        target: &RenderTarget,
+    ) -> RendererResult<()> {
+        let extent = vk::Extent2D {
+            width: target.extent.width,
+            height: target.extent.height,
+        };
+        let layout = vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL;
+        let format = capabilities::HDR_WORKING_FORMAT;
+        // The next frame writes its camera again
+        let camera_offset = self.current_image * self.global_uniform_stride;
+        for index in 0..lights.probes().len() {
+            let position = lights.probes()[index].position.coords;
+            let mut views = Vec::with_capacity(6);
+            for view in probe::probe_views() {
+                let camera = Camera::builder()
+                    .position(position)
+                    .view_direction(view.view)
+                    .down_direction(view.down)
+                    .fovy(std::f32::consts::FRAC_PI_2)
+                    .aspect(1.0)
+                    .build();
+                let view_projection = camera.view_projection_matrix();
+                camera.update_buffer(&mut self.uniform_buffer, camera_offset, &view_projection)?;
+                self.uniform_buffer.write(
+                    &self.fog.uniform_data(),
+                    camera_offset + std::mem::size_of::<CameraUniformData>(),
+                )?;
+                // The shadow map only covers the surroundings of the main camera
+                self.uniform_buffer
+                    .write(&shadow::NO_SHADOW, camera_offset + SHADOW_UNIFORM_OFFSET)?;
+                self.uniform_buffer.write(
+                    &SHADER_TONE_MAPPING,
+                    camera_offset + TONE_MAP_UNIFORM_OFFSET,
+                )?;
+                let frustum = Frustum::from_view_projection(&view_projection);
+                self.draw_probe_view(target, camera_offset, &frustum, &position)?;
+                views.push((
+                    view,
+                    self.read_back_image_layer(target.image, extent, 0, layout, format)?,
+                ));
+            }
+            lights.set_probe_data(
+                index,
+                ProbeData::SphericalHarmonics(probe::project_views(&views)),
+            )?;
+        }
+        Ok(())
+    }
+
+    fn draw_probe_view(
+        &mut self,
+        target: &RenderTarget,
+        camera_offset: usize,
+        frustum: &Frustum,
+        position: &glm::Vec3,
+    ) -> RendererResult<()> {
+        let extent = vk::Extent2D {
+            width: target.extent.width,
+            height: target.extent.height,
+        };
+        let command_buffer_alloc_info = vk::CommandBufferAllocateInfo::builder()
+            .command_pool(self.graphics_command_pool)
+            .command_buffer_count(1);
+        let cmd_buf = unsafe {
+            self.context
+                .device
+                .allocate_command_buffers(&command_buffer_alloc_info)
+        }?[0];
+        let cmd_begin_info = vk::CommandBufferBeginInfo::builder()
+            .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+        let clear_values = self.scene_clear_values(self.tone_mapped_background_color());
+        let render_pass_begin_info = vk::RenderPassBeginInfo::builder()
+            .render_pass(self.scene_render_pass)
+            .framebuffer(target.framebuffer)
+            .render_area(vk::Rect2D {
+                offset: vk::Offset2D { x: 0, y: 0 },
+                extent,
+            })
+            .clear_values(&clear_values);
+        unsafe {
+            self.context
+                .device
+                .begin_command_buffer(cmd_buf, &cmd_begin_info)?;
+            self.context.device.cmd_begin_render_pass(
+                cmd_buf,
+                &render_pass_begin_info,
+                vk::SubpassContents::INLINE,
+            );
+        }
+        self.draw_skybox(cmd_buf, extent, camera_offset)?;
+        // Layer masks are for cameras, probes see every object. Particles are left out.
+        let (_, _, transparent) = self.record_scene_objects(
+            cmd_buf,
+            extent,
+            self.current_image,
+            camera_offset,
+            frustum,
+            position,
+            ALL_LAYERS,
+        )?;
+        unsafe {
+            self.context
+                .device
+                .cmd_next_subpass(cmd_buf, vk::SubpassContents::INLINE);
+        }
+        let transparent = transparent
+            .into_iter()
+            .map(|(_, handle)| handle)
+            .collect::<Vec<_>>();
+        self.record_transparent_objects(cmd_buf, extent, camera_offset, &transparent)?;
+        self.record_offscreen_oit_composite(cmd_buf, extent, target)?;
+        self.end_scene_render_pass(cmd_buf, self.capabilities.transparency.particle_subpass());
+        unsafe {
+            self.context.device.end_command_buffer(cmd_buf)?;
+        }
+
+        let command_buffers = [cmd_buf];
+        let (uploads, upload_stages) = self.take_upload_waits();
+        let submit_infos = [vk::SubmitInfo::builder()
+            .wait_semaphores(&uploads)
+            .wait_dst_stage_mask(&upload_stages)
+            .command_buffers(&command_buffers)
+            .build()];
+        unsafe {
+            let device = &self.context.device;
+            let fence = device.create_fence(&vk::FenceCreateInfo::default(), None)?;
+            device.queue_submit(self.context.graphics_queue.queue, &submit_infos, fence)?;
+            device.wait_for_fences(&[fence], true, std::u64::MAX)?;
+            device.destroy_fence(fence, None);
+            device.free_command_buffers(self.graphics_command_pool, &command_buffers);
+        }
+        Ok(())
+    }
+
+    pub fn load_scene(&mut self, description: SceneDescription) -> RendererResult<SceneLoadResult> {
+        self.request_redraw();
+        let mut ticket = self.load_scene_incremental(description);
+        self.advance_loading(&mut ticket, Duration::MAX)?;
+        Ok(ticket
+            .take_result()
+            .expect("Scene finished loading without a result?"))
+    }
+
+    pub fn load_scene_incremental(&self, description: SceneDescription) -> SceneLoadTicket {
+        SceneLoadTicket::new(description)
+    }
+
+    // Processes load steps until the budget is used up, but always at least one. Waiting
+    // for the loader thread of a scene file is the exception, it gives up with the budget.
+    pub fn advance_loading(
+        &mut self,
+        ticket: &mut SceneLoadTicket,
+        budget: Duration,
+    ) -> RendererResult<LoadProgress> {
+        self.request_redraw();
+        let start = Instant::now();
+        while !ticket.is_finished() {
+            if !self.run_load_step(ticket, budget.saturating_sub(start.elapsed()))? {
+                break;
+            }
+            ticket.next_step += 1;
+            if ticket.is_finished() {
+                self.material_uniform_buffers
+                    .append(&mut ticket.uniform_buffers);
+            }
+            if start.elapsed() >= budget {
+                break;
+            }
+        }
+        Ok(ticket.progress())
+    }
+
+    // Removes everything an unfinished ticket has created so far
+    pub fn cancel_loading(&mut self, mut ticket: SceneLoadTicket) -> RendererResult<()> {
+        self.request_redraw();
+        if ticket.is_finished() {
+            // The loaded scene belongs to the caller now
+            return Ok(());
+        }
+        // Objects and lights are only created by the last step, so there are none to
+        // remove here
+        for name in ticket.result.materials.keys() {
+            self.material_system.remove_material(name)?;
+        }
+        for buffer in ticket.uniform_buffers.iter_mut() {
+            buffer.queue_free()?;
+        }
+        for handle in ticket.result.meshes.values() {
+            self.meshs.remove_mesh(*handle)?;
+        }
+        // The textures of scene files are shared with every other user of their file
+        if ticket.file.is_some() {
+            return Ok(());
+        }
+        if let Ok(mut allo) = lock_allocator(&self.allocator) {
+            // Uploads wait for completion and nothing has drawn these yet
+            for handle in ticket.result.textures.values() {
+                self.texture_storage.remove_texture(
+                    *handle,
+                    &self.context.device,
+                    allo.deref_mut(),
+                )?;
+            }
+        } else {
+            return Err(AllocatorUnavailable.into());
+        }
+        Ok(())
+    }
+
+    fn new_mesh_from_source(&mut self, source: &MeshSource) -> RendererResult<Handle<Mesh>> {
+        if let Ok(mut allo) = lock_allocator(&self.allocator) {
+            let device = &self.context.device;
+            let buffer_manager = self.buffer_manager.clone();
+            match source {
+                MeshSource::Obj(path) => {
+                    self.meshs
+                        .new_mesh_from_obj(path, device, allo.deref_mut(), buffer_manager)
+                }
+                MeshSource::Sphere(refinements) => self.meshs.new_sphere_mesh(
+                    *refinements,
+                    device,
+                    allo.deref_mut(),
+                    buffer_manager,
+                ),
+                MeshSource::Cube => {
+                    self.meshs
+                        .new_cube_mesh(device, allo.deref_mut(), buffer_manager)
+                }
+                MeshSource::Icosahedron => {
+                    self.meshs
+                        .new_icosahedron_mesh(device, allo.deref_mut(), buffer_manager)
+                }
+            }
+        } else {
+            Err(AllocatorUnavailable.into())
+        }
+    }
+
+    // Returns whether the step is done, only the parse step can run out of time
+    fn run_load_step(
+        &mut self,
+        ticket: &mut SceneLoadTicket,
+        timeout: Duration,
+    ) -> RendererResult<bool> {
+        let step = match ticket.step(ticket.next_step) {
+            Some(step) => step,
+            None => return Ok(true),
+        };
+        match step {
+            LoadStep::Parse => return ticket.wait_parsed(timeout),
+            LoadStep::Texture(i) => {
+                let desc = &ticket.description.textures[i];
+                let handle = self.new_texture_from_file(&desc.path)?;
+                ticket.result.textures.insert(desc.name.clone(), handle);
+            }
+            LoadStep::Mesh(i) => {
+                let desc = &ticket.description.meshes[i];
+                let handle = self.new_mesh_from_source(&desc.source)?;
+                ticket.result.meshes.insert(desc.name.clone(), handle);
+            }
+            LoadStep::Material(i) => {
+                if let Some((path, file)) = ticket.parsed_file() {
+                    let path = path.to_path_buf();
+                    let (name, material) = file
+                        .materials
+                        .iter()
+                        .nth(i)
+                        .map(|(name, material)| (name.clone(), material.clone()))
+                        .ok_or::<RendererError>(InvalidHandle.into())?;
+                    self.load_file_material(&path, &name, &material, &mut ticket.result)?;
+                    return Ok(true);
+                }
+                let desc = &ticket.description.materials[i];
+                let textures = desc
+                    .textures
+                    .iter()
+                    .map(|name| {
+                        ticket.result.textures.get(name).copied().ok_or_else(|| {
+                            SceneLoadError(format!(
+                                "material {} uses unknown texture {}",
+                                desc.name, name
+                            ))
+                            .into()
+                        })
+                    })
+                    .collect::<RendererResult<Vec<_>>>()?;
+                let mut buffers = vec![];
+                if !desc.uniform_data.is_empty() {
+                    if let Ok(mut allo) = lock_allocator(&self.allocator) {
+                        let mut buffer = BufferManager::new_buffer(
+                            self.buffer_manager.clone(),
+                            &self.context.device,
+                            allo.deref_mut(),
+                            (desc.uniform_data.len() * std::mem::size_of::<f32>()) as u64,
+                            vk::BufferUsageFlags::UNIFORM_BUFFER,
+                            MemoryLocation::CpuToGpu,
+                            format!("uniforms-{}", desc.name).as_str(),
+                        )?;
+                        buffer.fill(allo.deref_mut(), &desc.uniform_data)?;
+                        buffers.push(buffer.get_handle());
+                        ticket.uniform_buffers.push(buffer);
+                    } else {
+                        return Err(AllocatorUnavailable.into());
+                    }
+                }
+                let textures = self
+                    .material_system
+                    .textures_in_template_order(&desc.base_template, textures)?;
+                let handle = self.build_material(
+                    &desc.name,
+                    MaterialData {
+                        textures,
+                        buffers,
+                        parameters: ShaderParameters::default(),
+                        base_template: desc.base_template.clone(),
+                    },
+                )?;
+                ticket.result.materials.insert(desc.name.clone(), handle);
+            }
+            LoadStep::Objects => {
+                if let Some((path, file)) = ticket.parsed_file() {
+                    let path = path.to_path_buf();
+                    let file = file.clone();
+                    self.add_file_objects(&path, &file, &mut ticket.result)?;
+                    return Ok(true);
+                }
+                // Resolve everything first, so a bad description does not leave half a scene behind
+                let mut resolved = Vec::with_capacity(ticket.description.objects.len());
+                for (i, desc) in ticket.description.objects.iter().enumerate() {
+                    let mesh = ticket
+                        .result
+                        .meshes
+                        .get(&desc.mesh)
+                        .copied()
+                        .ok_or_else(|| {
+                            SceneLoadError(format!("object {} uses unknown mesh {}", i, desc.mesh))
+                        })?;
+                    let material = ticket
+                        .result
+                        .materials
+                        .get(&desc.material)
+                        .copied()
+                        .ok_or_else(|| {
+                            SceneLoadError(format!(
+                                "object {} uses unknown material {}",
+                                i, desc.material
+                            ))
+                        })?;
+                    if let Some(parent) = desc.parent {
+                        if parent >= i {
+                            return Err(SceneLoadError(format!(
+                                "object {} has to come after its parent {}",
+                                i, parent
+                            ))
+                            .into());
+                        }
+                    }
+                    resolved.push((mesh, material));
+                }
+
+                if let Ok(mut allo) = lock_allocator(&self.allocator) {
+                    for (desc, (mesh, material)) in
+                        ticket.description.objects.iter().zip(resolved.into_iter())
+                    {
+                        let handle = self.scene_tree.new_object(
+                            mesh,
+                            material,
+                            &self.context.device,
+                            allo.deref_mut(),
+                            self.buffer_manager.clone(),
+                        )?;
+                        {
+                            let obj_ref = self
+                                .scene_tree
+                                .get_object_mut(handle, allo.deref_mut())
+                                .ok_or::<RendererError>(InvalidHandle.into())?;
+                            obj_ref.object.position = desc.position;
+                            obj_ref.object.rotation = desc.rotation;
+                            obj_ref.object.scaling = desc.scaling;
+                        }
+                        if let Some(parent) = desc.parent {
+                            let mut parent_ref = self
+                                .scene_tree
+                                .get_object_mut(ticket.result.objects[parent], allo.deref_mut())
+                                .ok_or::<RendererError>(InvalidHandle.into())?;
+                            parent_ref.add_child(handle)?;
+                        }
+                        ticket.result.objects.push(handle);
+                    }
+                } else {
+                    return Err(AllocatorUnavailable.into());
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    // screen_size is the size of the surface in pixels, the text is positioned relative to it
+    pub fn get_config(&self) -> &RendererConfig {
+        &self.config
+    }
+
+    // Hash over every handle and text id handed out so far. Two renderers that
+    // performed the same operations in deterministic mode end up with the same hash.
+    pub fn debug_handle_hash(&self) -> u64 {
+        let buffer_hash = self.buffer_manager.lock().unwrap().allocation_hash();
+        [
+            self.scene_tree.allocation_hash(),
+            self.material_system.allocation_hash(),
+            self.texture_storage.allocation_hash(),
+            self.meshs.allocation_hash(),
+            buffer_hash,
+            self.text.allocation_hash(),
+        ]
+        .iter()
+        .fold(fnv1a_start(), |hash, h| fnv1a(hash, &h.to_le_bytes()))
+    }
+
+    // Loads another font for add_text. A style uses it with the index of the handle as
+    // its font_index, chars the font of a style doesn't have are taken from the first
+    // font that has them.
+    pub fn add_font<P: AsRef<Path>>(&mut self, font_path: P) -> RendererResult<FontHandle> {
+        self.text.add_font(font_path)
+    }
+
+    // Arrays still work as colors and are taken as linear, use Color::from_srgb_hex
+    // for colors from a picker or mockup. The position is in pixels from the anchor and
+    // the text keeps its place relative to it when the window is resized. The
+    // font_index of each style is the index of a FontHandle, 0 is the font the renderer
+    // was created with.
+    pub fn add_text(
+        &mut self,
+        position: (u32, u32),
+        anchor: Anchor,
+        styles: &[&fontdue::layout::TextStyle],
+        color: impl Into<Color>,
+    ) -> RendererResult<usize> {
+        self.request_redraw();
+        if let Ok(mut allo) = lock_allocator(&self.allocator) {
+            self.text.add_text(
+                styles,
+                color.into().to_linear(),
+                position,
+                anchor,
+                &self.context.max_texture_extent,
+                &self.context.device,
+                &mut self.texture_storage,
+                allo.deref_mut(),
+                self.buffer_manager.clone(),
+                &self.graphics_command_pool,
+                &self.context.graphics_queue.queue,
+                &mut self.descriptor_layout_cache,
+                &mut self.descriptor_allocator,
+                &mut self.material_system,
+            )
+        } else {
+            Err(AllocatorUnavailable.into())
+        }
+    }
+
+    // Replaces the text of id, like removing it and adding the new text without
+    // allocating while the text fits into the old buffers
+    pub fn update_text(
+        &mut self,
+        id: usize,
+        position: (u32, u32),
+        anchor: Anchor,
+        styles: &[&fontdue::layout::TextStyle],
+        color: impl Into<Color>,
+    ) -> RendererResult<()> {
+        self.request_redraw();
+        if let Ok(mut allo) = lock_allocator(&self.allocator) {
+            self.text.update_text(
+                id,
+                styles,
+                color.into().to_linear(),
+                position,
+                anchor,
+                &self.context.max_texture_extent,
+                &self.context.device,
+                &mut self.texture_storage,
+                allo.deref_mut(),
+                self.buffer_manager.clone(),
+                &self.graphics_command_pool,
+                &self.context.graphics_queue.queue,
+                &mut self.descriptor_layout_cache,
+                &mut self.descriptor_allocator,
+                &mut self.material_system,
+            )
+        } else {
+            Err(AllocatorUnavailable.into())
+        }
+    }
+
+    pub fn remove_text(&mut self, id: usize) -> RendererResult<()> {
+        self.request_redraw();
+        self.text.remove_text_by_id(id)
+    }
+
+    // Redraws a text input if it changed since the last call, e.g. once per frame after
+    // it handled the events
+    #[cfg(feature = "winit")]
+    pub fn update_text_input(&mut self, input: &mut TextInput) -> RendererResult<()> {
+        if !input.dirty {
+            return Ok(());
+        }
+        self.remove_text_input(input)?;
+        let px = input.px;
+        let text = &self.text;
+        let runs = input.runs(|c| text.advance(c, px));
+        for run in runs {
+            let position = (input.position.0 + run.x.round() as u32, input.position.1);
+            let id = self.add_text(
+                position,
+                Anchor::TopLeft,
+                &[&fontdue::layout::TextStyle::new(&run.text, px, 0)],
+                run.color,
+            )?;
+            input.text_ids.push(id);
+        }
+        input.dirty = false;
+        Ok(())
+    }
+
+    // Removes what update_text_input drew, it is drawn again with the next update
+    #[cfg(feature = "winit")]
+    pub fn remove_text_input(&mut self, input: &mut TextInput) -> RendererResult<()> {
+        for id in input.text_ids.drain(..) {
+            self.text.remove_text_by_id(id)?;
+        }
+        input.dirty = true;
+        Ok(())
+    }
+
+    // Draws a frame and reads it back like screenshot. Meant for headless renderers, with
+    // a window the frame is also presented, without the UI.
+    pub fn render_to_image(&mut self, camera: &Camera) -> RendererResult<image::RgbaImage> {
+        match self.begin_frame()? {
+            Some(FrameRecorder { renderer, token }) => {
+                renderer.end_frame(token, camera, None, |_| {})?;
+            }
+            None => {
+                return Err(FrameError::from("There is nothing to render to".to_string()).into())
+            }
+        }
+        self.capture_frame(CaptureResolution::Output)
+    }
+
+    // The last presented frame, see capture_frame
+    pub fn screenshot(&mut self) -> RendererResult<image::RgbaImage> {
+        self.capture_frame(CaptureResolution::Output)
+    }
+
+    // The format is picked by the extension of path
+    pub fn save_screenshot<P: AsRef<Path>>(&mut self, path: P) -> RendererResult<()> {
+        self.screenshot()?.save(path)?;
+        Ok(())
+    }
+
+    // Like screenshot, without waiting for the GPU. The copy runs after the last
+    // submitted frame, poll_screenshot returns the image once it is done.
+    pub fn screenshot_async(&mut self) -> RendererResult<ScreenshotToken> {
+        let (image, extent, layout, format) = self.capture_source(CaptureResolution::Output)?;
+        let pending = self.start_texel_readback(image, extent, 0, layout, format)?;
+        let token = ScreenshotToken(self.next_screenshot);
+        self.next_screenshot += 1;
+        self.pending_screenshots.insert(token, pending);
+        Ok(token)
+    }
+
+    // None while the copy is still running. The image is returned once, the token is
+    // invalid afterwards.
+    pub fn poll_screenshot(
+        &mut self,
+        token: ScreenshotToken,
+    ) -> RendererResult<Option<image::RgbaImage>> {
+        let pending = self
+            .pending_screenshots
+            .get(&token)
+            .ok_or::<RendererError>(InvalidHandle.into())?;
+        if !pending.is_done(&self.context.device)? {
+            return Ok(None);
+        }
+        let pending = self
+            .pending_screenshots
+            .remove(&token)
+            .expect("The screenshot was just found");
+        let (extent, format) = (pending.extent, pending.format);
+        let data = if let Ok(mut allo) = lock_allocator(&self.allocator) {
+            pending.finish(
+                &self.context.device,
+                self.graphics_command_pool,
+                allo.deref_mut(),
+            )?
+        } else {
+            return Err(AllocatorUnavailable.into());
+        };
+        Self::texels_to_rgba(data, extent, format).map(Some)
+    }
+
+    // Reads back the last rendered frame. Without upscaling both resolutions are the same.
+    pub fn capture_frame(
+        &mut self,
+        resolution: CaptureResolution,
+    ) -> RendererResult<image::RgbaImage> {
+        let (image, extent, layout, format) = self.capture_source(resolution)?;
+        // The image is rendered to again by the next frame
+        self.timeline.wait_for(self.timeline.last_submitted())?;
+        self.read_back_image_layer(image, extent, 0, layout, format)
+    }
+
+    // The image capture_frame reads, with its extent, layout and format
+    fn capture_source(
+        &self,
+        resolution: CaptureResolution,
+    ) -> RendererResult<(vk::Image, vk::Extent2D, vk::ImageLayout, vk::Format)> {
+        let offscreen_texture = match resolution {
+            CaptureResolution::Internal => self
+                .scaled_target
+                .as_ref()
+                .or(self.composite_target.as_ref())
+                .map(|t| t.texture),
+            CaptureResolution::Output => self.composite_target.as_ref().map(|t| t.texture),
+        };
+        match offscreen_texture {
+            Some(texture) => {
+                let texture = self
+                    .texture_storage
+                    .get_texture(texture)
+                    .ok_or::<RendererError>(InvalidHandle.into())?;
+                Ok((
+                    texture.image(),
+                    texture.extent(),
+                    vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                    texture.format(),
+                ))
+            }
+            None => Ok((
+                self.swapchain.get_render_targets()[self.current_image].image,
+                self.swapchain.get_extent(),
+                vk::ImageLayout::PRESENT_SRC_KHR,
+                self.swapchain.get_image_format().format,
+            )),
+        }
+    }
+
+    // Draws the scene objects as the camera sees them into the AOV attachments at the
+    // render scale and reads them back, see aov.rs. Particles, text and the UI are not
+    // part of it. Can be called between frames.
+    pub fn capture_aovs(&mut self, camera: &Camera) -> RendererResult<AovImages> {
+        if self.aov_pass.is_none() {
+            self.aov_pass = Some(AovPass::new(
+                &self.context.device,
+                &mut self.material_system,
+                &mut self.shader_cache,
+            )?);
+        }
+        let render_pass = self
+            .aov_pass
+            .as_ref()
+            .map(|pass| pass.render_pass)
+            .expect("The AOV pass was just created");
+        // Drawn with the camera slot and instance data of the frames
+        self.timeline.wait_idle()?;
+        self.sync_lights(None)?;
+        let extent = self.get_internal_extent();
+        let target = if let Ok(mut allo) = lock_allocator(&self.allocator) {
+            self.scene_tree.flush_object_data(allo.deref_mut())?;
+            AovTarget::new(&self.context, allo.deref_mut(), extent, render_pass)?
+        } else {
+            return Err(AllocatorUnavailable.into());
+        };
+        let result = self
+            .draw_aovs(camera, &target, render_pass, &AovRegion::full(extent))
+            .and_then(|_| {
+                let images = target.images().collect::<Vec<_>>();
+                let mut texels = vec![];
+                for (image, format) in images.into_iter().zip(aov::AOV_FORMATS) {
+                    texels.push(self.read_back_texels(
+                        image,
+                        extent,
+                        0,
+                        vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                        format,
+                    )?);
+                }
+                Ok(texels)
+            });
+        if let Ok(mut allo) = lock_allocator(&self.allocator) {
+            target.destroy(&self.context, allo.deref_mut());
+        }
+        let texels = result?.try_into().expect("One readback per AOV format");
+        Ok(AovImages::from_texels(extent.width, extent.height, texels))
+    }
+
+    // Reads back the velocity of the last rendered frame, see velocity.rs. None without
+    // RendererConfig::velocity_target, or before a frame was rendered with the current
+    // internal extent. Can be called between frames.
+    pub fn capture_velocity(&mut self) -> RendererResult<Option<VelocityImage>> {
+        let (image, extent) = match self.velocity_target.as_ref() {
+            Some(velocity) if velocity.rendered => (
+                velocity.target.image,
+                vk::Extent2D {
+                    width: velocity.target.extent.width,
+                    height: velocity.target.extent.height,
+                },
+            ),
+            _ => return Ok(None),
+        };
+        self.timeline.wait_idle()?;
+        let texels = self.read_back_texels(
+            image,
+            extent,
+            0,
+            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            VELOCITY_FORMAT,
+        )?;
+        Ok(Some(VelocityImage::from_texels(
+            extent.width,
+            extent.height,
+            &texels,
+        )))
+    }
+
+    // The objects in a rectangle of the window, for drag selection, see selection.rs.
+    // Objects that are hidden or not in the camera's layer mask are never selected, and a
+    // click selects at most one object. The precise mode draws the objects and can be
+    // called between frames.
+    pub fn select_in_rect(
+        &mut self,
+        rect: SelectionRect,
+        camera: &Camera,
+        mode: SelectionMode,
+    ) -> RendererResult<Vec<Handle<SceneObject>>> {
+        let extent = self.swapchain.get_extent();
+        let view_projection = camera.view_projection_matrix();
+        match mode {
+            SelectionMode::ContainedBounds | SelectionMode::IntersectsBounds => {
+                let frustum = Frustum::from_view_projection(&view_projection);
+                let mut candidates = vec![];
+                for (handle, m) in self.scene_tree.iter_visible(camera.get_layer_mask()) {
+                    if m.get_instance_slot().is_none() {
+                        continue;
+                    }
+                    let bounds = self
+                        .meshs
+                        .get_mesh(m.mesh)
+                        .ok_or::<RendererError>(InvalidHandle.into())?
+                        .bounds()
+                        .transformed(&m.get_global_transform());
+                    if frustum.intersects_aabb(&bounds) {
+                        candidates.push((handle, bounds));
+                    }
+                }
+                Ok(selection::select_by_bounds(
+                    &rect,
+                    mode,
+                    &view_projection,
+                    &glm::vec2(extent.width as f32, extent.height as f32),
+                    &camera.get_position(),
+                    candidates,
+                ))
+            }
+            // A click picks what is visible under the cursor
+            SelectionMode::PreciseStencil { occlusion } => {
+                self.select_in_rect_precise(rect, camera, occlusion || rect.is_click())
+            }
+        }
+    }
+
+    fn select_in_rect_precise(
+        &mut self,
+        rect: SelectionRect,
+        camera: &Camera,
+        occlusion: bool,
+    ) -> RendererResult<Vec<Handle<SceneObject>>> {
+        let view_extent = self.swapchain.get_extent();
+        let (offset, size) =
+            match selection::pixel_region(&rect, view_extent.width, view_extent.height) {
+                Some(region) => region,
+                None => return Ok(vec![]),
+            };
+        if self.aov_pass.is_none() {
+            self.aov_pass = Some(AovPass::new(
+                &self.context.device,
+                &mut self.material_system,
+                &mut self.shader_cache,
+            )?);
+        }
+        let render_pass = self
+            .aov_pass
+            .as_ref()
+            .map(|pass| pass.render_pass)
+            .expect("The AOV pass was just created");
+        let object_count = self.scene_tree.iter_with_handles().count().max(1) as u32;
+        let queries = if occlusion {
+            None
+        } else {
+            let pool_info = vk::QueryPoolCreateInfo::builder()
+                .query_type(vk::QueryType::OCCLUSION)
+                .query_count(object_count);
+            Some(unsafe { self.context.device.create_query_pool(&pool_info, None)? })
+        };
+        let region = AovRegion {
+            view_extent,
+            offset,
+            occlusion_queries: queries.map(|pool| (pool, object_count)),
+        };
+        let extent = vk::Extent2D {
+            width: size[0],
+            height: size[1],
+        };
+        // Drawn with the camera slot and instance data of the frames
+        self.timeline.wait_idle()?;
+        let target = if let Ok(mut allo) = lock_allocator(&self.allocator) {
+            self.scene_tree.flush_object_data(allo.deref_mut())?;
+            AovTarget::new(&self.context, allo.deref_mut(), extent, render_pass)?
+        } else {
+            return Err(AllocatorUnavailable.into());
+        };
+        let result = self
+            .draw_aovs(camera, &target, render_pass, &region)
+            .and_then(|drawn| match queries {
+                // Every object with a pixel in the region, hidden or not
+                Some(pool) => {
+                    let mut samples = vec![0u64; drawn.len()];
+                    if !drawn.is_empty() {
+                        unsafe {
+                            self.context.device.get_query_pool_results(
+                                pool,
+                                0,
+                                drawn.len() as u32,
+                                &mut samples,
+                                vk::QueryResultFlags::TYPE_64 | vk::QueryResultFlags::WAIT,
+                            )?;
+                        }
+                    }
+                    Ok(drawn
+                        .into_iter()
+                        .zip(samples)
+                        .filter(|(_, samples)| *samples > 0)
+                        .map(|(handle, _)| handle)
+                        .collect())
+                }
+                // The ids that are left in the region after the depth test
+                None => {
+                    let image = target.images().next().expect("The object id attachment");
+                    let texels = self.read_back_texels(
+                        image,
+                        extent,
+                        0,
+                        vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                        aov::AOV_FORMATS[0],
+                    )?;
+                    let ids = texels
+                        .chunks_exact(4)
+                        .map(|id| u32::from_le_bytes([id[0], id[1], id[2], id[3]]))
+                        .filter(|id| *id != AOV_BACKGROUND_ID)
+                        .collect::<BTreeSet<_>>();
+                    Ok(drawn
+                        .into_iter()
+                        .filter(|handle| ids.contains(&(handle.id() as u32)))
+                        .collect())
+                }
+            });
+        if let Ok(mut allo) = lock_allocator(&self.allocator) {
+            target.destroy(&self.context, allo.deref_mut());
+        }
+        if let Some(pool) = queries {
+            unsafe { self.context.device.destroy_query_pool(pool, None) };
+        }
+        result
+    }
+
+    // Returns the objects drawn, in the order of their occlusion queries
+    fn draw_aovs(
+        &mut self,
+        camera: &Camera,
+        target: &AovTarget,
+        render_pass: vk::RenderPass,
+        region: &AovRegion,
+    ) -> RendererResult<Vec<Handle<SceneObject>>> {
+        let view_projection = camera.view_projection_matrix();
+        // The next frame writes its camera again
+        let camera_offset = self.current_image * self.global_uniform_stride;
+        camera.update_buffer(&mut self.uniform_buffer, camera_offset, &view_projection)?;
+        let frustum = Frustum::from_view_projection(&view_projection);
+
+        let command_buffer_alloc_info = vk::CommandBufferAllocateInfo::builder()
+            .command_pool(self.graphics_command_pool)
+            .command_buffer_count(1);
+        let cmd_buf = unsafe {
+            self.context
+                .device
+                .allocate_command_buffers(&command_buffer_alloc_info)
+        }?[0];
+        let cmd_begin_info = vk::CommandBufferBeginInfo::builder()
+            .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+        let clear_values = [
+            vk::ClearValue {
+                color: vk::ClearColorValue {
+                    uint32: [AOV_BACKGROUND_ID, 0, 0, 0],
+                },
+            },
+            vk::ClearValue {
+                color: vk::ClearColorValue {
+                    float32: [0.0, 0.0, 0.0, 0.0],
+                },
+            },
+            vk::ClearValue {
+                color: vk::ClearColorValue {
+                    float32: [0.0, 0.0, 0.0, 0.0],
+                },
+            },
+            vk::ClearValue {
+                depth_stencil: vk::ClearDepthStencilValue {
+                    depth: 1.0,
+                    stencil: 0,
+                },
+            },
+        ];
+        let render_pass_begin_info = vk::RenderPassBeginInfo::builder()
+            .render_pass(render_pass)
+            .framebuffer(target.framebuffer)
+            .render_area(vk::Rect2D {
+                offset: vk::Offset2D { x: 0, y: 0 },
+                extent: target.extent,
+            })
+            .clear_values(&clear_values);
+        unsafe {
+            self.context
+                .device
+                .begin_command_buffer(cmd_buf, &cmd_begin_info)?;
+            if let Some((queries, count)) = region.occlusion_queries {
+                self.context
+                    .device
+                    .cmd_reset_query_pool(cmd_buf, queries, 0, count);
+            }
+            self.context.device.cmd_begin_render_pass(
+                cmd_buf,
+                &render_pass_begin_info,
+                vk::SubpassContents::INLINE,
+            );
+        }
+        let drawn = self.record_aov_objects(
+            cmd_buf,
+            target.extent,
+            camera_offset,
+            &frustum,
+            camera.get_layer_mask(),
+            region,
+        )?;
+        unsafe {
+            self.context.device.cmd_end_render_pass(cmd_buf);
+            self.context.device.end_command_buffer(cmd_buf)?;
+        }
+
+        let command_buffers = [cmd_buf];
+        let (uploads, upload_stages) = self.take_upload_waits();
+        let submit_infos = [vk::SubmitInfo::builder()
+            .wait_semaphores(&uploads)
+            .wait_dst_stage_mask(&upload_stages)
+            .command_buffers(&command_buffers)
+            .build()];
+        unsafe {
+            let device = &self.context.device;
+            let fence = device.create_fence(&vk::FenceCreateInfo::default(), None)?;
+            device.queue_submit(self.context.graphics_queue.queue, &submit_infos, fence)?;
+            device.wait_for_fences(&[fence], true, std::u64::MAX)?;
+            device.destroy_fence(fence, None);
+            device.free_command_buffers(self.graphics_command_pool, &command_buffers);
+        }
+        Ok(drawn)
+    }
+
+    // Like record_scene_objects, with one pipeline for every object and its id instead of
+    // its material. extent is the size of the target.
+    fn record_aov_objects(
+        &self,
+        cmd_buf: vk::CommandBuffer,
+        extent: vk::Extent2D,
+        camera_buffer_offset: usize,
+        frustum: &Frustum,
+        layer_mask: u32,
+        region: &AovRegion,
+    ) -> RendererResult<Vec<Handle<SceneObject>>> {
+        let template = self
+            .aov_pass
+            .as_ref()
+            .ok_or::<RendererError>(InvalidHandle.into())?
+            .template;
+        let pass = &self
+            .material_system
+            .get_effect_template_by_handle(template)?
+            .pass_shaders[MeshPassType::Forward];
+        let instance_buffer = match self.scene_tree.get_instance_buffer() {
+            Some(buffer) => buffer.get_buffer().buffer,
+            None => return Ok(vec![]),
+        };
+        let device = &self.context.device;
+        unsafe {
+            device.cmd_bind_pipeline(cmd_buf, vk::PipelineBindPoint::GRAPHICS, pass.pipeline);
+            device.cmd_bind_descriptor_sets(
+                cmd_buf,
+                vk::PipelineBindPoint::GRAPHICS,
+                pass.layout,
+                0,
+                &[self.descriptor_set_camera, self.descriptor_set_lights],
+                &[camera_buffer_offset as u32],
+            );
+            device.cmd_set_viewport(
+                cmd_buf,
+                0,
+                &[vk::Viewport {
+                    x: -(region.offset[0] as f32),
+                    y: -(region.offset[1] as f32),
+                    width: region.view_extent.width as f32,
+                    height: region.view_extent.height as f32,
+                    min_depth: 0.,
+                    max_depth: 1.,
+                }],
+            );
+            device.cmd_set_scissor(
+                cmd_buf,
+                0,
+                &[vk::Rect2D {
+                    offset: vk::Offset2D { x: 0, y: 0 },
+                    extent,
+                }],
+            );
+        }
+        let mut drawn = vec![];
+        for (handle, m) in self.scene_tree.iter_visible(layer_mask) {
+            let mesh = self
+                .meshs
+                .get_mesh(m.mesh)
+                .ok_or::<RendererError>(InvalidHandle.into())?;
+            if !culling::is_visible(
+                &m.culling,
+                mesh.bounds(),
+                &m.get_global_transform(),
+                frustum,
+            ) {
+                continue;
+            }
+            // Objects without a slot are in inactive chunks, which aren't visible
+            let slot = match m.get_instance_slot() {
+                Some(slot) => slot,
+                None => continue,
+            };
+            let id = handle.id() as u32;
+            unsafe {
+                device.cmd_push_constants(
+                    cmd_buf,
+                    pass.layout,
+                    vk::ShaderStageFlags::VERTEX,
+                    0,
+                    &id.to_le_bytes(),
+                );
+                device.cmd_bind_vertex_buffers(
+                    cmd_buf,
+                    1,
+                    &[instance_buffer],
+                    &[self.scene_tree.get_instance_offset(slot)],
+                );
+            }
+            match region.occlusion_queries {
+                Some((queries, _)) => unsafe {
+                    device.cmd_clear_attachments(
+                        cmd_buf,
+                        &[vk::ClearAttachment {
+                            aspect_mask: vk::ImageAspectFlags::DEPTH,
+                            color_attachment: 0,
+                            clear_value: vk::ClearValue {
+                                depth_stencil: vk::ClearDepthStencilValue {
+                                    depth: 1.0,
+                                    stencil: 0,
+                                },
+                            },
+                        }],
+                        &[vk::ClearRect {
+                            rect: vk::Rect2D {
+                                offset: vk::Offset2D { x: 0, y: 0 },
+                                extent,
+                            },
+                            base_array_layer: 0,
+                            layer_count: 1,
+                        }],
+                    );
+                    let query = drawn.len() as u32;
+                    device.cmd_begin_query(cmd_buf, queries, query, vk::QueryControlFlags::empty());
+                    mesh.draw(device, cmd_buf);
+                    device.cmd_end_query(cmd_buf, queries, query);
+                },
+                None => mesh.draw(device, cmd_buf),
+            }
+            drawn.push(handle);
+        }
+        Ok(drawn)
+    }
+
+    // Converts an equirectangular texture into a cubemap with square faces of face_size
+    // texels, see cubemap.rs for the conventions. The faces are sRGB like textures loaded
+    // from files. Can be called between frames.
+    pub fn equirect_to_cubemap(
+        &mut self,
+        equirect: Handle<Texture>,
+        face_size: u32,
+    ) -> RendererResult<Handle<Texture>> {
+        let extent = vk::Extent2D {
+            width: face_size.max(1),
+            height: face_size.max(1),
+        };
+        self.convert_cubemap(equirect, extent, CubemapConversion::EquirectToCubemap)
+    }
+
+    // The inverse of equirect_to_cubemap, for exporting and looking at cubemaps. The
+    // image is width x width / 2.
+    pub fn cubemap_to_equirect(
+        &mut self,
+        cubemap: Handle<Texture>,
+        width: u32,
+    ) -> RendererResult<image::RgbaImage> {
+        let extent = vk::Extent2D {
+            width: width.max(2),
+            height: (width / 2).max(1),
+        };
+        let equirect =
+            self.convert_cubemap(cubemap, extent, CubemapConversion::CubemapToEquirect)?;
+        let image = self.read_back_texture_layer(equirect, 0);
+        if let Ok(mut allo) = lock_allocator(&self.allocator) {
+            self.texture_storage.remove_texture(
+                equirect,
+                &self.context.device,
+                allo.deref_mut(),
+            )?;
+        }
+        image
+    }
+
+    // Writes every face to <path_prefix>_<suffix>.png, with the suffixes of CubemapFace
+    pub fn save_cubemap<P: AsRef<Path>>(
+        &mut self,
+        cubemap: Handle<Texture>,
+        path_prefix: P,
+    ) -> RendererResult<()> {
+        let texture = self
+            .texture_storage
+            .get_texture(cubemap)
+            .ok_or(InvalidHandle)?;
+        if !texture.is_cubemap() {
+            return Err(CubemapError("save_cubemap needs a cubemap".to_string()).into());
+        }
+        for face in CUBEMAP_FACES {
+            let image = self.read_back_texture_layer(cubemap, face.layer())?;
+            let mut path = path_prefix.as_ref().as_os_str().to_owned();
+            path.push(format!("_{}.png", face.suffix()));
+            let path = PathBuf::from(path);
+            image.save(&path).map_err(|e| {
+                RendererError::from(CubemapError(format!("{}: {}", path.display(), e)))
+            })?;
+        }
+        Ok(())
+    }
+
+    fn convert_cubemap(
+        &mut self,
+        source: Handle<Texture>,
+        extent: vk::Extent2D,
+        conversion: CubemapConversion,
+    ) -> RendererResult<Handle<Texture>> {
+        let to_cubemap = conversion == CubemapConversion::EquirectToCubemap;
+        let source_texture = self
+            .texture_storage
+            .get_texture(source)
+            .ok_or(InvalidHandle)?;
+        if source_texture.is_cubemap() == to_cubemap {
+            let message = if to_cubemap {
+                "equirect_to_cubemap was given a cubemap"
+            } else {
+                "cubemap_to_equirect was given a texture that is not a cubemap"
+            };
+            return Err(CubemapError(message.to_string()).into());
+        }
+        let source_view = source_texture.image_view;
+
+        if self.cubemap_converter.is_none() {
+            self.cubemap_converter = Some(CubemapConverter::new(
+                &self.context.device,
+                &mut self.shader_cache,
+                &mut self.descriptor_allocator,
+            )?);
+        }
+        let target = if let Ok(mut allo) = lock_allocator(&self.allocator) {
+            self.texture_storage.new_storage_texture(
+                extent,
+                to_cubemap,
+                &self.context.device,
+                allo.deref_mut(),
+            )?
+        } else {
+            return Err(AllocatorUnavailable.into());
+        };
+        let target_texture = self
+            .texture_storage
+            .get_texture(target)
+            .ok_or(InvalidHandle)?;
+        let target_view = target_texture
+            .storage_view()
+            .expect("Storage textures have a storage view");
+        let target_range = vk::ImageSubresourceRange {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            base_mip_level: 0,
+            level_count: 1,
+            base_array_layer: 0,
+            layer_count: target_texture.layer_count(),
+        };
+        let target_image = target_texture.image();
+
+        let device = &self.context.device;
+        let command_buffer_alloc_info = vk::CommandBufferAllocateInfo::builder()
+            .command_pool(self.graphics_command_pool)
+            .command_buffer_count(1);
+        let command_buffer =
+            unsafe { device.allocate_command_buffers(&command_buffer_alloc_info) }?[0];
+        let cmd_begin_info = vk::CommandBufferBeginInfo::builder()
+            .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+        unsafe { device.begin_command_buffer(command_buffer, &cmd_begin_info) }?;
+
+        let barrier = vk::ImageMemoryBarrier::builder()
+            .image(target_image)
+            .src_access_mask(vk::AccessFlags::empty())
+            .dst_access_mask(vk::AccessFlags::SHADER_WRITE)
+            .old_layout(vk::ImageLayout::UNDEFINED)
+            .new_layout(vk::ImageLayout::GENERAL)
+            .subresource_range(target_range)
+            .build();
+        unsafe {
+            device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::TOP_OF_PIPE,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &[barrier],
+            )
+        };
+        self.cubemap_converter
+            .as_ref()
+            .expect("Converter was just created")
+            .record(
+                device,
+                command_buffer,
+                conversion,
+                source_view,
+                target_view,
+                extent,
+            );
+        let barrier = vk::ImageMemoryBarrier::builder()
+            .image(target_image)
+            .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+            .dst_access_mask(vk::AccessFlags::SHADER_READ)
+            .old_layout(vk::ImageLayout::GENERAL)
+            .new_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+            .subresource_range(target_range)
+            .build();
+        unsafe {
+            device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::PipelineStageFlags::FRAGMENT_SHADER,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &[barrier],
+            )
+        };
+        unsafe { device.end_command_buffer(command_buffer) }?;
+
+        // TODO the graphics queue family is not checked for compute support, though every
+        // desktop driver has it
+        let submit_infos = [vk::SubmitInfo::builder()
+            .command_buffers(&[command_buffer])
+            .build()];
+        let fence = unsafe { device.create_fence(&vk::FenceCreateInfo::default(), None) }?;
+        unsafe { device.queue_submit(self.context.graphics_queue.queue, &submit_infos, fence) }?;
+        unsafe { device.wait_for_fences(&[fence], true, std::u64::MAX) }?;
+        unsafe {
+            device.destroy_fence(fence, None);
+            device.free_command_buffers(self.graphics_command_pool, &[command_buffer]);
+        }
+
+        Ok(target)
+    }
+
+    fn read_back_texture_layer(
+        &mut self,
+        handle: Handle<Texture>,
+        layer: u32,
+    ) -> RendererResult<image::RgbaImage> {
+        let texture = self
+            .texture_storage
+            .get_texture(handle)
+            .ok_or(InvalidHandle)?;
+        let (image, extent, format) = (texture.image(), texture.extent(), texture.format());
+        self.read_back_image_layer(
+            image,
+            extent,
+            layer,
+            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            format,
+        )
+    }
+
+    // Renders a sphere with the material under fixed lighting on a gray background.
+    // Independent from the scene, lights and camera, and can be called between frames.
+    pub fn render_material_preview(
+        &mut self,
+        material: Handle<Material>,
+        size: u32,
+    ) -> RendererResult<image::RgbaImage> {
+        Ok(self.render_material_previews(&[material], size)?.remove(0))
+    }
+
+    // Same as render_material_preview, but all of the previews share one render target
+    pub fn render_material_previews(
+        &mut self,
+        materials: &[Handle<Material>],
+        size: u32,
+    ) -> RendererResult<Vec<image::RgbaImage>> {
+        self.ensure_preview_scene()?;
+
+        let extent = vk::Extent2D {
+            width: size.max(1),
+            height: size.max(1),
+        };
+        let mut target = if let Ok(mut allo) = lock_allocator(&self.allocator) {
+            RenderTarget::new_offscreen(
+                &self.context,
+                allo.deref_mut(),
+                capabilities::HDR_WORKING_FORMAT,
+                extent,
+                &self.scene_render_pass,
+                self.capabilities.msaa_samples,
+                self.capabilities.transparency,
+            )?
+        } else {
+            return Err(AllocatorUnavailable.into());
+        };
+        target.set_debug_name(&self.context, "material preview");
+
+        let layout = vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL;
+        let format = capabilities::HDR_WORKING_FORMAT;
+        let previews = materials
+            .iter()
+            .map(|material| {
+                self.draw_material_preview(*material, &target)?;
+                self.read_back_image_layer(target.image, extent, 0, layout, format)
+            })
+            .collect::<RendererResult<Vec<_>>>();
+
+        if let Ok(mut allo) = lock_allocator(&self.allocator) {
+            target.destroy(&self.context, allo.deref_mut());
+        }
+        previews
+    }
+
+    fn ensure_preview_scene(&mut self) -> RendererResult<()> {
+        // Previews bind the default object parameters
+        self.update_object_params_set()?;
+        if self.preview_scene.is_some() {
+            return Ok(());
+        }
+        let default_template = self.material_system.get_effect_template_by_handle(
+            self.material_system.get_effect_template_handle("default")?,
+        )?;
+        let effect_handle = default_template.pass_shaders[MeshPassType::Forward]
+            .effect_handle
+            .expect("No effect handle?");
+        let effect = self
+            .shader_cache
+            .get_shader_effect_by_handle(effect_handle)?;
+        let (camera_layout, lights_layout) = (effect.set_layouts[0], effect.set_layouts[1]);
+        if let Ok(mut allo) = lock_allocator(&self.allocator) {
+            self.preview_scene = Some(PreviewScene::new(
+                &self.context.device,
+                allo.deref_mut(),
+                self.buffer_manager.clone(),
+                &mut self.descriptor_allocator,
+                &mut self.meshs,
+                camera_layout,
+                lights_layout,
+            )?);
+        } else {
+            return Err(AllocatorUnavailable.into());
+        }
+        // The preview lights have no cookies and cast no shadows, but the slots still have
+        // to be bound
+        if let Some(preview_scene) = self.preview_scene.as_ref() {
+            morph::update_descriptor(
+                &self.context.device,
+                preview_scene.descriptor_set_lights,
+                &self.morph_deltas,
+            );
+            self.shadow_map
+                .update_descriptor(&self.context.device, preview_scene.descriptor_set_lights);
+            LightManager::default().update_cookie_descriptors(
+                &self.context.device,
+                preview_scene.descriptor_set_lights,
+                &self.texture_storage,
+                self.cookie_sampler,
+                self.fallback_texture,
             )?;
+        }
+        Ok(())
+    }
 
-            // Draw UI
-            self.platform
-                .prepare_frame(self.imgui.io_mut(), window)
-                .expect("Failed to prepare frame");
-            let ui = self.imgui.frame();
-
-            if self.ui_state.opened {
-                let w = ui
-                    .window("Vulkan Renderer")
-                    .opened(&mut self.ui_state.opened)
-                    .position([20.0, 20.0], Condition::FirstUseEver)
-                    .size([600.0, 400.0], Condition::FirstUseEver)
-                    .resizable(true)
-                    .movable(true);
-                w.build(|| {
-                    ui.checkbox("Show Demo Window", &mut self.ui_state.show_demo_window);
-                    if let Some(_tree_root) = ui.tree_node("Scene Objects") {
-                        for (i, object) in self.scene_tree.iter_mut().enumerate() {
-                            let name = format!("Object {i}");
-                            if let Some(_tree_node) = ui.tree_node(name) {
-                                ui.text(format!(
-                                    "Position: {} {} {}",
-                                    object.position.x, object.position.y, object.position.z
-                                ));
-
-                                let angles = glm::quat_euler_angles(&object.rotation);
-                                let (pitch, yaw, roll) = (angles.x, angles.y, angles.z);
-                                ui.text(format!("Rotation: {} {} {}", pitch, yaw, roll));
-
-                                ui.text(format!(
-                                    "Scaling: {} {} {}",
-                                    object.scaling.x, object.scaling.y, object.scaling.z
-                                ));
-                            };
-                        }
-                    };
-                });
-            }
+    // Renders subject from config.frames directions around it, see turntable_camera. The
+    // scene, its camera and its objects are left untouched.
+    pub fn capture_turntable(
+        &mut self,
+        subject: TurntableSubject,
+        frames: u32,
+        size: u32,
+    ) -> RendererResult<Vec<image::RgbaImage>> {
+        let config = TurntableConfig {
+            frames,
+            size,
+            ..Default::default()
+        };
+        let mut images = Vec::with_capacity(frames as usize);
+        self.capture_turntable_with(subject, &config, |_, image| {
+            images.push(image);
+            ControlFlow::Continue(())
+        })?;
+        Ok(images)
+    }
 
-            if self.ui_state.show_demo_window {
-                ui.show_demo_window(&mut self.ui_state.show_demo_window);
+    // Hands every frame to on_frame as soon as it is read back, so only one frame is in
+    // memory at a time. Returning ControlFlow::Break stops the capture. Returns the number
+    // of frames captured.
+    pub fn capture_turntable_with<F>(
+        &mut self,
+        subject: TurntableSubject,
+        config: &TurntableConfig,
+        mut on_frame: F,
+    ) -> RendererResult<u32>
+    where
+        F: FnMut(u32, image::RgbaImage) -> ControlFlow<()>,
+    {
+        let (mesh, material, object, transform) = match subject {
+            TurntableSubject::Object(handle) => {
+                let object = self
+                    .scene_tree
+                    .get_object(handle)
+                    .ok_or::<RendererError>(InvalidHandle.into())?;
+                (
+                    object.mesh,
+                    object.material,
+                    Some(handle),
+                    object.get_global_transform(),
+                )
+            }
+            TurntableSubject::Mesh(mesh, material) => (mesh, material, None, glm::Mat4::identity()),
+        };
+        let bounds = self
+            .meshs
+            .get_mesh(mesh)
+            .ok_or::<RendererError>(InvalidHandle.into())?
+            .bounds()
+            .transformed(&transform);
+        self.ensure_preview_scene()?;
+        let lights = match config.lighting {
+            TurntableLighting::Preview => {
+                self.preview_scene
+                    .as_ref()
+                    .expect("Preview scene not created?")
+                    .descriptor_set_lights
             }
+            TurntableLighting::Scene => self.descriptor_set_lights,
+        };
 
-            ui_func(ui);
+        let extent = vk::Extent2D {
+            width: config.size.max(1),
+            height: config.size.max(1),
+        };
+        let mut target = if let Ok(mut allo) = lock_allocator(&self.allocator) {
+            RenderTarget::new_offscreen(
+                &self.context,
+                allo.deref_mut(),
+                capabilities::HDR_WORKING_FORMAT,
+                extent,
+                &self.scene_render_pass,
+                self.capabilities.msaa_samples,
+                self.capabilities.transparency,
+            )?
+        } else {
+            return Err(AllocatorUnavailable.into());
+        };
+        target.set_debug_name(&self.context, "turntable");
 
-            self.platform.prepare_render(ui, window);
-            let draw_data = self.imgui.render();
-            self.imgui_renderer.cmd_draw(*cmd_buf, draw_data)?;
+        let layout = vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL;
+        let format = capabilities::HDR_WORKING_FORMAT;
+        let background = config.background.to_linear_rgba();
+        let mut captured = 0;
+        let result = (|| -> RendererResult<()> {
+            for frame in 0..config.frames {
+                let camera = turntable::turntable_camera(&bounds, frame, config);
+                if let Ok(mut allo) = lock_allocator(&self.allocator) {
+                    self.preview_scene
+                        .as_mut()
+                        .expect("Preview scene not created?")
+                        .set_view(allo.deref_mut(), &camera, &transform)?;
+                } else {
+                    return Err(AllocatorUnavailable.into());
+                }
+                self.draw_preview(mesh, material, object, lights, background, &target)?;
+                let image = self.read_back_image_layer(target.image, extent, 0, layout, format)?;
+                captured += 1;
+                if on_frame(frame, image).is_break() {
+                    break;
+                }
+            }
+            Ok(())
+        })();
 
-            self.context.device.cmd_end_render_pass(*cmd_buf);
-            self.context.device.end_command_buffer(*cmd_buf)?;
+        if let Ok(mut allo) = lock_allocator(&self.allocator) {
+            target.destroy(&self.context, allo.deref_mut());
+            if let Some(preview_scene) = self.preview_scene.as_mut() {
+                preview_scene.reset_view(allo.deref_mut())?;
+            }
         }
-        Ok(())
+        result.map(|_| captured)
     }
 
-    fn submit_commands<F: FnOnce(&mut Ui)>(
-        &mut self,
-        image_index: usize,
-        window: &Window,
-        ui_func: F,
+    fn draw_material_preview(
+        &self,
+        material: Handle<Material>,
+        target: &RenderTarget,
     ) -> RendererResult<()> {
-        self.update_command_buffer(image_index, window, ui_func)?;
-        let cmd_buf = &self.command_buffers[image_index];
-        let this_frame_data = &self.frame_data[self.current_image];
-        let semaphores_available = [this_frame_data.image_available_semaphore];
-        let waiting_stages = [vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT];
-        let semaphores_finished = [this_frame_data.render_finished_semaphore];
-        let command_bufs = [*cmd_buf];
-        let submit_info = [vk::SubmitInfo::builder()
-            .wait_semaphores(&semaphores_available)
-            .wait_dst_stage_mask(&waiting_stages)
-            .command_buffers(&command_bufs[..])
-            .signal_semaphores(&semaphores_finished)
-            .build()];
-        unsafe {
-            self.context
-                .device
-                .reset_fences(&[this_frame_data.in_flight_fence])?;
-            self.context.device.queue_submit(
-                self.context.graphics_queue.queue,
-                &submit_info,
-                this_frame_data.in_flight_fence,
-            )?;
-        }
-        Ok(())
-    }
-
-    fn present(&self, image_index: u32) -> RendererResult<()> {
-        self.swapchain.present(
-            &self.context.graphics_queue.queue,
-            &self.frame_data[self.current_image].render_finished_semaphore,
-            image_index,
-        )?;
-        Ok(())
+        let preview_scene = self
+            .preview_scene
+            .as_ref()
+            .expect("Preview scene not created?");
+        self.draw_preview(
+            preview_scene.sphere,
+            material,
+            None,
+            preview_scene.descriptor_set_lights,
+            PREVIEW_BACKGROUND,
+            target,
+        )
     }
 
-    pub fn render<F: FnOnce(&mut Ui)>(
-        &mut self,
-        camera: &Camera,
-        window: &Window,
-        ui_func: F,
+    // Draws a single mesh with the camera and instance of the preview scene, and the object
+    // parameters of params_slot or the zeroed default ones
+    fn draw_preview(
+        &self,
+        mesh: Handle<Mesh>,
+        material: Handle<Material>,
+        object: Option<Handle<SceneObject>>,
+        lights: vk::DescriptorSet,
+        background: [f32; 4],
+        target: &RenderTarget,
     ) -> RendererResult<()> {
-        self.wait_for_next_frame_fence()?;
-        let image_index = self.swapchain.get_next_image(
-            std::u64::MAX,
-            &self.frame_data[self.current_image].image_available_semaphore,
-            vk::Fence::null(),
-        )?;
-
-        if let Ok(mut alloc) = self.allocator.lock() {
-            let offset = image_index as usize * std::mem::size_of::<[[[f32; 4]; 4]; 2]>();
-            camera.update_buffer(alloc.deref_mut(), &mut self.uniform_buffer, offset)?;
-        } else {
-            panic!("No allocator!");
+        let mat = self.material_system.get_material_by_handle(material)?;
+        let effect = self
+            .material_system
+            .get_effect_template_by_handle(mat.original)?;
+        if let Some(reason) = self.check_draw_compatibility(mat, effect) {
+            return Err(IncompatibleMaterial(reason).into());
         }
-
-        self.wait_for_image_fence_and_set_new_fence(image_index as usize)?;
-
-        if let Ok(mut allo) = self.allocator.lock() {
-            self.buffer_manager
-                .lock()
-                .unwrap()
-                .free_queued(allo.deref_mut(), image_index);
+        if !effect.user_managed_sets.is_empty() {
+            return Err(
+                IncompatibleMaterial("previews can't bind user managed sets".to_string()).into(),
+            );
         }
+        let object = match object {
+            Some(handle) => Some(
+                self.scene_tree
+                    .get_object(handle)
+                    .ok_or::<RendererError>(InvalidHandle.into())?,
+            ),
+            None => None,
+        };
+        let params = match effect.object_params_size {
+            Some(_) => {
+                let offset = match object.and_then(|object| object.get_instance_slot()) {
+                    Some(slot) => Some(self.scene_tree.get_object_params_offset(slot)),
+                    None => self.scene_tree.get_default_object_params_offset(),
+                };
+                match (offset, self.object_params_set) {
+                    (Some(offset), Some((_, set))) => Some((set, offset)),
+                    _ => return Err(IncompatibleMaterial(
+                        "previews of templates with object parameters need an object in the scene"
+                            .to_string(),
+                    )
+                    .into()),
+                }
+            }
+            None => None,
+        };
+        let preview_scene = self
+            .preview_scene
+            .as_ref()
+            .expect("Preview scene not created?");
+        let mesh = self
+            .meshs
+            .get_mesh(mesh)
+            .ok_or::<RendererError>(InvalidHandle.into())?;
+        let pass = &effect.pass_shaders[effect.mesh_pass()];
+        let extent = vk::Extent2D {
+            width: target.extent.width,
+            height: target.extent.height,
+        };
+        let device = &self.context.device;
 
-        self.submit_commands(image_index as usize, window, ui_func)?;
+        let command_buffer_alloc_info = vk::CommandBufferAllocateInfo::builder()
+            .command_pool(self.graphics_command_pool)
+            .command_buffer_count(1);
+        let cmd_buf = unsafe { device.allocate_command_buffers(&command_buffer_alloc_info) }?[0];
+        let cmd_begin_info = vk::CommandBufferBeginInfo::builder()
+            .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+        let clear_values = self.scene_clear_values(background);
+        let render_pass_begin_info = vk::RenderPassBeginInfo::builder()
+            .render_pass(self.scene_render_pass)
+            .framebuffer(target.framebuffer)
+            .render_area(vk::Rect2D {
+                offset: vk::Offset2D { x: 0, y: 0 },
+                extent,
+            })
+            .clear_values(&clear_values);
+        let viewports = [vk::Viewport {
+            x: 0.,
+            y: 0.,
+            width: extent.width as f32,
+            height: extent.height as f32,
+            min_depth: 0.,
+            max_depth: 1.,
+        }];
+        let scissors = [vk::Rect2D {
+            offset: vk::Offset2D { x: 0, y: 0 },
+            extent,
+        }];
+        let uv_transform = mat.parameters.uv_transform_push_constants();
+        let morph = MorphPushConstants::for_draw(
+            mesh,
+            object
+                .map(|object| object.get_morph_weights())
+                .unwrap_or_default(),
+        );
+        // Transparent materials are drawn in the subpass their pipelines are built for
+        let subpass = match effect.mesh_pass() {
+            MeshPassType::Transparency => 1,
+            _ => 0,
+        };
+        unsafe {
+            device.begin_command_buffer(cmd_buf, &cmd_begin_info)?;
+            device.cmd_begin_render_pass(
+                cmd_buf,
+                &render_pass_begin_info,
+                vk::SubpassContents::INLINE,
+            );
+            for _ in 0..subpass {
+                device.cmd_next_subpass(cmd_buf, vk::SubpassContents::INLINE);
+            }
+            device.cmd_bind_pipeline(cmd_buf, vk::PipelineBindPoint::GRAPHICS, pass.pipeline);
+            device.cmd_bind_descriptor_sets(
+                cmd_buf,
+                vk::PipelineBindPoint::GRAPHICS,
+                pass.layout,
+                0,
+                &[
+                    preview_scene.descriptor_set_camera,
+                    lights,
+                    mat.pass_sets[effect.mesh_pass()],
+                ],
+                &[0],
+            );
+            if let Some((set, offset)) = params {
+                device.cmd_bind_descriptor_sets(
+                    cmd_buf,
+                    vk::PipelineBindPoint::GRAPHICS,
+                    pass.layout,
+                    OBJECT_PARAMS_SET,
+                    &[set],
+                    &[offset],
+                );
+            }
+            device.cmd_set_viewport(cmd_buf, 0, &viewports);
+            device.cmd_set_scissor(cmd_buf, 0, &scissors);
+            device.cmd_push_constants(
+                cmd_buf,
+                pass.layout,
+                vk::ShaderStageFlags::VERTEX,
+                0,
+                std::slice::from_raw_parts(
+                    uv_transform.as_ptr() as *const u8,
+                    std::mem::size_of_val(&uv_transform),
+                ),
+            );
+            if effect.morph_targets {
+                device.cmd_push_constants(
+                    cmd_buf,
+                    pass.layout,
+                    vk::ShaderStageFlags::VERTEX,
+                    MORPH_PUSH_CONSTANT_OFFSET,
+                    morph.as_bytes(),
+                );
+            }
+            device.cmd_bind_vertex_buffers(
+                cmd_buf,
+                1,
+                &[preview_scene.instance_buffer().get_buffer().buffer],
+                &[0],
+            );
+            mesh.draw(device, cmd_buf);
+        }
+        let subpass = match effect.mesh_pass() {
+            MeshPassType::Transparency => {
+                self.record_offscreen_oit_composite(cmd_buf, extent, target)?;
+                self.capabilities.transparency.particle_subpass()
+            }
+            _ => subpass,
+        };
+        self.end_scene_render_pass(cmd_buf, subpass);
+        unsafe {
+            device.end_command_buffer(cmd_buf)?;
+        }
 
-        self.present(image_index)?;
-        self.current_image = (self.current_image + 1) % FRAMES_IN_FLIGHT;
+        let command_buffers = [cmd_buf];
+        let (uploads, upload_stages) = self.take_upload_waits();
+        let submit_infos = [vk::SubmitInfo::builder()
+            .wait_semaphores(&uploads)
+            .wait_dst_stage_mask(&upload_stages)
+            .command_buffers(&command_buffers)
+            .build()];
+        unsafe {
+            let fence = device.create_fence(&vk::FenceCreateInfo::default(), None)?;
+            device.queue_submit(self.context.graphics_queue.queue, &submit_infos, fence)?;
+            device.wait_for_fences(&[fence], true, std::u64::MAX)?;
+            device.destroy_fence(fence, None);
+            device.free_command_buffers(self.graphics_command_pool, &command_buffers);
+        }
         Ok(())
     }
 
-    pub fn update_storage_from_lights(&mut self, lights: &LightManager) -> RendererResult<()> {
-        if let Ok(mut allo) = self.allocator.lock() {
-            Ok(lights.update_buffer(
-                &self.context.device,
-                allo.deref_mut(),
-                &mut self.light_buffer,
-                self.descriptor_set_lights,
-            )?)
-        } else {
-            panic!("No allocator!");
-        }
+    // Copies one array layer of an image back to the CPU as RGBA8, it is in layout again
+    // afterwards
+    fn read_back_image_layer(
+        &mut self,
+        source_image: vk::Image,
+        extent: vk::Extent2D,
+        layer: u32,
+        layout: vk::ImageLayout,
+        format: vk::Format,
+    ) -> RendererResult<image::RgbaImage> {
+        let data = self.read_back_texels(source_image, extent, layer, layout, format)?;
+        Self::texels_to_rgba(data, extent, format)
     }
 
-    pub fn new_texture_from_file<P: AsRef<Path>>(
-        &mut self,
-        path: P,
-    ) -> RendererResult<Handle<Texture>> {
-        if let Ok(mut allo) = self.allocator.lock() {
-            Ok(self.texture_storage.new_texture_from_file(
-                path,
-                &self.context.device,
-                allo.deref_mut(),
-                self.buffer_manager.clone(),
-                self.graphics_command_pool,
-                self.context.graphics_queue.queue,
-            )?)
-        } else {
-            panic!("No allocator!");
+    // Texels from read_back_texels of an image with format
+    fn texels_to_rgba(
+        mut data: Vec<u8>,
+        extent: vk::Extent2D,
+        format: vk::Format,
+    ) -> RendererResult<image::RgbaImage> {
+        // The data that comes out might not be in RGBA8 format, so we have to convert it.
+        match format {
+            vk::Format::B8G8R8A8_UNORM | vk::Format::B8G8R8A8_SRGB => {
+                for v in data.chunks_mut(4) {
+                    // BGRA -> RGBA involves swapping B and R (0 and 2)
+                    v.swap(0, 2);
+                }
+            }
+            vk::Format::R8G8B8A8_UNORM | vk::Format::R8G8B8A8_SRGB => {} // Nothing to do
+            capabilities::HDR_WORKING_FORMAT => {
+                // Linear half floats, encoded as sRGB and clipped like a sRGB swapchain would
+                data = data
+                    .chunks(8)
+                    .flat_map(|texel| {
+                        let channel = |i: usize| {
+                            color::f16_to_f32(u16::from_le_bytes([texel[2 * i], texel[2 * i + 1]]))
+                                .clamp(0.0, 1.0)
+                        };
+                        let encode = |c: f32| (color::linear_to_srgb(c) * 255.0).round() as u8;
+                        [
+                            encode(channel(0)),
+                            encode(channel(1)),
+                            encode(channel(2)),
+                            (channel(3) * 255.0).round() as u8,
+                        ]
+                    })
+                    .collect();
+            }
+            _ => panic!("No way to convert this format! {:?}", format),
         }
+
+        let screen = image::RgbaImage::from_raw(extent.width, extent.height, data)
+            .expect("ImageBuffer creation");
+        Ok(screen)
     }
 
-    pub fn add_text(
+    // The texels of one array layer as they are stored, row after row without padding.
+    // 8 bit formats come back as RGBA8 or BGRA8 like the image.
+    fn read_back_texels(
         &mut self,
-        window: &winit::window::Window,
-        position: (u32, u32),
-        styles: &[&fontdue::layout::TextStyle],
-        color: [f32; 3],
-    ) -> RendererResult<Vec<usize>> {
-        if let Ok(mut allo) = self.allocator.lock() {
-            self.text.add_text(
-                styles,
-                color,
-                position,
-                window,
-                &self.context.max_texture_extent,
+        source_image: vk::Image,
+        extent: vk::Extent2D,
+        layer: u32,
+        layout: vk::ImageLayout,
+        format: vk::Format,
+    ) -> RendererResult<Vec<u8>> {
+        let pending = self.start_texel_readback(source_image, extent, layer, layout, format)?;
+        if let Ok(mut allo) = lock_allocator(&self.allocator) {
+            pending.finish(
                 &self.context.device,
-                &mut self.texture_storage,
+                self.graphics_command_pool,
                 allo.deref_mut(),
-                self.buffer_manager.clone(),
-                &self.graphics_command_pool,
-                &self.context.graphics_queue.queue,
-                &mut self.descriptor_layout_cache,
-                &mut self.descriptor_allocator,
-                &mut self.material_system,
             )
         } else {
-            panic!("No allocator!");
+            Err(AllocatorUnavailable.into())
         }
     }
 
-    pub fn remove_text(&mut self, id: usize) -> RendererResult<()> {
-        self.text.remove_text_by_id(id)
-    }
-
-    pub fn screenshot(&mut self) -> RendererResult<()> {
+    // Submits the copy of read_back_texels without waiting for it. It waits for
+    // everything submitted before it, and everything submitted after it waits for the
+    // copy, so the image can be rendered to again right away.
+    fn start_texel_readback(
+        &mut self,
+        source_image: vk::Image,
+        extent: vk::Extent2D,
+        layer: u32,
+        layout: vk::ImageLayout,
+        format: vk::Format,
+    ) -> RendererResult<PendingImageReadback> {
         let command_buffer_alloc_info = vk::CommandBufferAllocateInfo::builder()
             .command_pool(self.graphics_command_pool)
             .command_buffer_count(1);
@@ -811,12 +9092,18 @@ impl Renderer {
                 .begin_command_buffer(copy_buffer, &cmd_begin_info)
         }?;
 
+        // Copies need texels of the same size, 8 bit formats all go to RGBA8
+        let (dest_format, texel_size) = match format {
+            capabilities::HDR_WORKING_FORMAT => (capabilities::HDR_WORKING_FORMAT, 8),
+            vk::Format::R32_UINT | vk::Format::R32_SFLOAT | VELOCITY_FORMAT => (format, 4),
+            _ => (vk::Format::R8G8B8A8_UNORM, 4),
+        };
         let image_create_info = vk::ImageCreateInfo::builder()
-            .format(vk::Format::R8G8B8A8_UNORM)
+            .format(dest_format)
             .image_type(vk::ImageType::TYPE_2D)
             .extent(vk::Extent3D {
-                width: self.swapchain.get_extent().width,
-                height: self.swapchain.get_extent().height,
+                width: extent.width,
+                height: extent.height,
                 depth: 1,
             })
             .array_layers(1)
@@ -833,7 +9120,7 @@ impl Renderer {
                 .get_image_memory_requirements(dest_image)
         };
 
-        let dest_image_allocation = if let Ok(mut allo) = self.allocator.lock() {
+        let dest_image_allocation = if let Ok(mut allo) = lock_allocator(&self.allocator) {
             allo.allocate(&AllocationCreateDesc {
                 name: "dest_image",
                 requirements: reqs,
@@ -842,7 +9129,7 @@ impl Renderer {
                 allocation_scheme: AllocationScheme::GpuAllocatorManaged,
             })?
         } else {
-            panic!("No allocator!");
+            return Err(AllocatorUnavailable.into());
         };
         unsafe {
             self.context.device.bind_image_memory(
@@ -880,26 +9167,25 @@ impl Renderer {
                 );
             }
         }
-        let source_image = self.swapchain.get_render_targets()[self.current_image].image;
         {
             let barrier = vk::ImageMemoryBarrier::builder()
                 .image(source_image)
-                .src_access_mask(vk::AccessFlags::MEMORY_READ)
+                .src_access_mask(vk::AccessFlags::MEMORY_WRITE)
                 .dst_access_mask(vk::AccessFlags::TRANSFER_READ)
-                .old_layout(vk::ImageLayout::PRESENT_SRC_KHR)
+                .old_layout(layout)
                 .new_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
                 .subresource_range(vk::ImageSubresourceRange {
                     aspect_mask: vk::ImageAspectFlags::COLOR,
                     base_mip_level: 0,
                     level_count: 1,
-                    base_array_layer: 0,
+                    base_array_layer: layer,
                     layer_count: 1,
                 })
                 .build();
             unsafe {
                 self.context.device.cmd_pipeline_barrier(
                     copy_buffer,
-                    vk::PipelineStageFlags::TRANSFER,
+                    vk::PipelineStageFlags::ALL_COMMANDS,
                     vk::PipelineStageFlags::TRANSFER,
                     vk::DependencyFlags::empty(),
                     &[],
@@ -915,7 +9201,7 @@ impl Renderer {
             .src_subresource(vk::ImageSubresourceLayers {
                 aspect_mask: vk::ImageAspectFlags::COLOR,
                 mip_level: 0,
-                base_array_layer: 0,
+                base_array_layer: layer,
                 layer_count: 1,
             })
             .src_offset(zero_offset)
@@ -927,8 +9213,8 @@ impl Renderer {
             })
             .dst_offset(zero_offset)
             .extent(vk::Extent3D {
-                width: self.swapchain.get_extent().width,
-                height: self.swapchain.get_extent().height,
+                width: extent.width,
+                height: extent.height,
                 depth: 1,
             })
             .build();
@@ -947,7 +9233,7 @@ impl Renderer {
             let barrier = vk::ImageMemoryBarrier::builder()
                 .image(dest_image)
                 .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
-                .dst_access_mask(vk::AccessFlags::MEMORY_READ)
+                .dst_access_mask(vk::AccessFlags::HOST_READ)
                 .old_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
                 .new_layout(vk::ImageLayout::GENERAL)
                 .subresource_range(vk::ImageSubresourceRange {
@@ -962,7 +9248,7 @@ impl Renderer {
                 self.context.device.cmd_pipeline_barrier(
                     copy_buffer,
                     vk::PipelineStageFlags::TRANSFER,
-                    vk::PipelineStageFlags::TRANSFER,
+                    vk::PipelineStageFlags::HOST,
                     vk::DependencyFlags::empty(),
                     &[],
                     &[],
@@ -974,14 +9260,14 @@ impl Renderer {
             let barrier = vk::ImageMemoryBarrier::builder()
                 .image(source_image)
                 .src_access_mask(vk::AccessFlags::TRANSFER_READ)
-                .dst_access_mask(vk::AccessFlags::MEMORY_READ)
+                .dst_access_mask(vk::AccessFlags::MEMORY_READ | vk::AccessFlags::MEMORY_WRITE)
                 .old_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
-                .new_layout(vk::ImageLayout::PRESENT_SRC_KHR)
+                .new_layout(layout)
                 .subresource_range(vk::ImageSubresourceRange {
                     aspect_mask: vk::ImageAspectFlags::COLOR,
                     base_mip_level: 0,
                     level_count: 1,
-                    base_array_layer: 0,
+                    base_array_layer: layer,
                     layer_count: 1,
                 })
                 .build();
@@ -989,7 +9275,7 @@ impl Renderer {
                 self.context.device.cmd_pipeline_barrier(
                     copy_buffer,
                     vk::PipelineStageFlags::TRANSFER,
-                    vk::PipelineStageFlags::TRANSFER,
+                    vk::PipelineStageFlags::ALL_COMMANDS,
                     vk::DependencyFlags::empty(),
                     &[],
                     &[],
@@ -1018,83 +9304,15 @@ impl Renderer {
             )
         }?;
 
-        unsafe {
-            self.context
-                .device
-                .wait_for_fences(&[fence], true, std::u64::MAX)
-        }?;
-
-        unsafe { self.context.device.destroy_fence(fence, None) };
-        unsafe {
-            self.context
-                .device
-                .free_command_buffers(self.graphics_command_pool, &[copy_buffer])
-        };
-
-        let mut data = {
-            let source_ptr = dest_image_allocation
-                .mapped_ptr()
-                .expect("No mapped memory for image")
-                .as_ptr() as *mut u8;
-
-            let subresource_layout = unsafe {
-                self.context.device.get_image_subresource_layout(
-                    dest_image,
-                    vk::ImageSubresource {
-                        aspect_mask: vk::ImageAspectFlags::COLOR,
-                        mip_level: 0,
-                        array_layer: 0,
-                    },
-                )
-            };
-
-            let mut data = Vec::<u8>::with_capacity(subresource_layout.size as usize);
-            unsafe {
-                std::ptr::copy(
-                    source_ptr,
-                    data.as_mut_ptr(),
-                    subresource_layout.size as usize,
-                );
-                data.set_len(subresource_layout.size as usize);
-            }
-            if let Ok(mut allo) = self.allocator.lock() {
-                allo.free(dest_image_allocation)
-                    .expect("Could not free dest image");
-            }
-            unsafe {
-                self.context.device.destroy_image(dest_image, None);
-            }
-            data
-        };
-
-        // The data that comes out might not be in RGBA8 format, so we have to convert it.
-        match self.swapchain.get_image_format().format {
-            vk::Format::B8G8R8A8_UNORM | vk::Format::B8G8R8A8_SRGB => {
-                for v in data.chunks_mut(4) {
-                    // BGRA -> RGBA involves swapping B and R (0 and 2)
-                    v.swap(0, 2);
-                }
-            }
-            vk::Format::R8G8B8A8_UNORM => {} // Nothing to do
-            _ => panic!(
-                "No way to convert this format! {:?}",
-                self.swapchain.get_image_format().format
-            ),
-        }
-
-        let screen: image::ImageBuffer<image::Rgba<u8>, _> = image::ImageBuffer::from_raw(
-            self.swapchain.get_extent().width,
-            self.swapchain.get_extent().height,
-            data,
-        )
-        .expect("ImageBuffer creation");
-
-        let screen_image = image::DynamicImage::ImageRgba8(screen);
-        screen_image
-            .save("screenshot.jpg")
-            .expect("Could not save screenshot");
-
-        Ok(())
+        Ok(PendingImageReadback {
+            command_buffer: copy_buffer,
+            fence,
+            image: dest_image,
+            allocation: dest_image_allocation,
+            extent,
+            texel_size,
+            format,
+        })
     }
 }
 
@@ -1108,29 +9326,105 @@ impl Drop for Renderer {
                 .device
                 .device_wait_idle()
                 .expect("Something wrong while waiting for idle");
+            self.overlay_recorder = None;
             self.meshs.destroy();
-            self.uniform_buffer
-                .queue_free(None)
-                .expect("Invalid Handle?!");
-            self.light_buffer
-                .queue_free(None)
-                .expect("Invalid Handle?!");
-
-            if let Ok(mut allo) = self.allocator.lock() {
+            self.uniform_buffer.queue_free().expect("Invalid Handle?!");
+            self.light_storage.destroy().expect("Invalid Handle?!");
+            self.morph_deltas.queue_free().expect("Invalid Handle?!");
+            if let Some(buffer) = self.cursor_vertex_buffer.as_mut() {
+                buffer.queue_free().expect("Invalid Handle?!");
+            }
+            if let Some(resources) = self.gizmo_resources.as_mut() {
+                resources
+                    .vertex_buffer
+                    .queue_free()
+                    .expect("Invalid Handle?!");
+            }
+            for emitter in self.emitters.iter_mut() {
+                emitter
+                    .instance_buffer
+                    .queue_free()
+                    .expect("Invalid Handle?!");
+            }
+            self.instance_batches.destroy().expect("Invalid Handle?!");
+
+            if let Ok(mut allo) = lock_allocator(&self.allocator) {
                 let allo = allo.deref_mut();
+                for (_, pending) in self.pending_screenshots.drain() {
+                    pending
+                        .destroy(&self.context.device, self.graphics_command_pool, allo)
+                        .expect("Could not free a screenshot");
+                }
+                // Before the textures, the target uses the image of one
+                if let Some(scaled) = self.scaled_target.as_mut() {
+                    scaled.target.destroy(&self.context, allo);
+                }
+                if let Some(composite) = self.composite_target.as_mut() {
+                    composite.target.destroy(&self.context, allo);
+                }
+                if let Some(velocity) = self.velocity_target.as_mut() {
+                    velocity.target.destroy(&self.context, allo);
+                }
+                // Its views are of the chain
+                if let Some(pass) = self.bloom_pass.as_mut() {
+                    pass.destroy(&self.context.device);
+                }
                 self.texture_storage.clean_up(&self.context.device, allo);
+                self.context
+                    .device
+                    .destroy_sampler(self.cookie_sampler, None);
+                self.shadow_map.destroy(&self.context.device, allo);
 
                 self.frame_data.clear();
+                self.timeline.destroy();
+                if let Some(timer) = self.gpu_timer.as_mut() {
+                    timer.destroy(&self.context.device);
+                }
+                if let Some(profiler) = self.profiler.as_mut() {
+                    profiler.destroy(&self.context.device);
+                }
+                if let Some(async_compute) = self.async_compute.as_mut() {
+                    async_compute.destroy();
+                }
                 self.context
                     .device
                     .destroy_command_pool(self.graphics_command_pool, None);
-                // device.destroy_command_pool(command_pool_transfer, None);
                 self.text.destroy();
                 self.context
                     .device
                     .destroy_render_pass(self.render_pass, None);
-                let num_images = self.swapchain.get_actual_image_count();
+                self.context
+                    .device
+                    .destroy_render_pass(self.overlay_render_pass, None);
+                self.context
+                    .device
+                    .destroy_render_pass(self.scene_render_pass, None);
+                if let Some(output_render_pass) = self.output_render_pass {
+                    self.context
+                        .device
+                        .destroy_render_pass(output_render_pass, None);
+                }
                 self.material_system.destroy(&self.context.device);
+                if let Some(converter) = self.cubemap_converter.as_mut() {
+                    converter.destroy(&self.context.device);
+                }
+                if let Some(baker) = self.lightmap_baker.as_mut() {
+                    baker.destroy(&self.context.device);
+                }
+                if let Some(pass) = self.luminance_pass.as_mut() {
+                    pass.destroy(&self.context.device)
+                        .expect("Invalid Handle?!");
+                }
+                if let Some(pass) = self.aov_pass.as_mut() {
+                    pass.destroy(&self.context.device);
+                }
+                if let Some(pass) = self.velocity_pass.as_mut() {
+                    pass.destroy(&self.context.device);
+                }
+                self.compute.destroy(&self.context.device);
+                if let Err(e) = self.shader_cache.save_pipeline_cache(&self.context.device) {
+                    warn!("Could not save the pipeline cache: {}", e);
+                }
                 self.shader_cache.destroy(&self.context.device);
                 self.swapchain.destroy(&self.context, allo);
 
@@ -1140,18 +9434,104 @@ impl Drop for Renderer {
                 self.descriptor_allocator.destroy(&self.context.device);
 
                 for buf in self.material_uniform_buffers.iter_mut() {
-                    buf.queue_free(None).expect("Invalid Handle?!");
+                    buf.queue_free().expect("Invalid Handle?!");
                 }
-
-                {
-                    let mut guard = self.buffer_manager.lock().unwrap();
-                    for i in 0..num_images {
-                        guard.free_queued(allo, i);
-                    }
+                if let Some(preview_scene) = self.preview_scene.as_mut() {
+                    preview_scene.destroy().expect("Invalid Handle?!");
                 }
+
+                // The device is idle, so nothing is in use anymore
+                self.buffer_manager.lock().unwrap().free_all_queued(allo);
                 log::logger().flush();
             }
         }
         self.dropped = true;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::buffer::ALLOCATOR_LOCKS;
+    use super::*;
+
+    fn headless_renderer() -> Option<Renderer> {
+        match Renderer::new_headless("renderer test", 64, 64) {
+            Ok(renderer) => Some(renderer),
+            Err(
+                e @ (RendererError::LoadError { .. }
+                | RendererError::NoSuitableDevice { .. }
+                | RendererError::VulkanError {
+                    source: vk::Result::ERROR_INCOMPATIBLE_DRIVER,
+                    ..
+                }),
+            ) => {
+                eprintln!("Skipped, there is no Vulkan device: {}", e);
+                None
+            }
+            Err(e) => panic!("Unable to create a headless renderer: {:?}", e),
+        }
+    }
+
+    // Records the frame again and renders it, counting the locks of the allocator
+    fn allocator_locks_of_frame(renderer: &mut Renderer, camera: &Camera) -> usize {
+        renderer.force_rerecord();
+        ALLOCATOR_LOCKS.with(|locks| locks.set(0));
+        renderer.render_to_image(camera).unwrap();
+        ALLOCATOR_LOCKS.with(|locks| locks.get())
+    }
+
+    #[test]
+    fn camera_and_material_uniforms_are_written_without_the_allocator() {
+        let mut renderer = match headless_renderer() {
+            Some(renderer) => renderer,
+            None => return,
+        };
+        let sphere = renderer.resources().unwrap().new_sphere_mesh(1).unwrap();
+        let mut materials = vec![];
+        for i in 0..8 {
+            let mut parameters = ShaderParameters::default();
+            parameters.set("roughness", i as f32 / 8.0);
+            let material = renderer
+                .build_material(
+                    &format!("material {}", i),
+                    MaterialData {
+                        textures: HashMap::new(),
+                        buffers: vec![],
+                        parameters,
+                        base_template: "default".to_string(),
+                    },
+                )
+                .unwrap();
+            let object = renderer
+                .resources()
+                .unwrap()
+                .new_object(sphere, material)
+                .unwrap();
+            renderer
+                .resources()
+                .unwrap()
+                .get_object_mut(object)
+                .unwrap()
+                .object
+                .position = glm::vec3(i as f32 - 3.5, 0.0, 0.0);
+            materials.push(material);
+        }
+        let mut camera = Camera::builder()
+            .position(glm::vec3(0.0, 0.0, -8.0))
+            .aspect(1.0)
+            .build();
+        camera.look_at(&glm::Vec3::zeros(), &glm::vec3(0.0, 1.0, 0.0));
+        for _ in 0..FRAMES_IN_FLIGHT + 1 {
+            renderer.render_to_image(&camera).unwrap();
+        }
+        let unchanged = allocator_locks_of_frame(&mut renderer, &camera);
+
+        camera.set_position(glm::vec3(1.0, 0.0, -8.0));
+        for (i, material) in materials.iter().enumerate() {
+            renderer
+                .set_material_parameter(*material, "roughness", 1.0 - i as f32 / 8.0)
+                .unwrap();
+        }
+        assert_eq!(allocator_locks_of_frame(&mut renderer, &camera), unchanged);
+    }
+}
@@ -0,0 +1,383 @@
+use std::collections::BTreeSet;
+use std::io::Write;
+use std::path::Path;
+
+use ash::vk;
+use gpu_allocator::{
+    vulkan::{Allocation, AllocationCreateDesc, AllocationScheme, Allocator},
+    MemoryLocation,
+};
+use nalgebra_glm as glm;
+
+use super::{
+    color,
+    context::VulkanContext,
+    material::{EffectTemplate, MaterialSystem},
+    shaders::ShaderCache,
+    utils::Handle,
+    RendererResult,
+};
+
+// Arbitrary output variables, images of the scene beside its color for compositing. The
+// AOV pass draws the scene objects again into attachments of these formats, in this
+// order, only when Renderer::capture_aovs asks for it. The forward pass is unchanged.
+pub const AOV_FORMATS: [vk::Format; 3] = [
+    // The id of the handle of the object, see Handle::id
+    vk::Format::R32_UINT,
+    // The world space normal
+    vk::Format::R16G16B16A16_SFLOAT,
+    // The distance from the camera along its view direction
+    vk::Format::R32_SFLOAT,
+];
+
+// Handle ids are never 0, so this is where no object was drawn
+pub const AOV_BACKGROUND_ID: u32 = 0;
+
+pub type ObjectIdImage = image::ImageBuffer<image::Luma<u16>, Vec<u16>>;
+
+// Rows from top to bottom, every pixel of the background is zero
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AovImages {
+    pub width: u32,
+    pub height: u32,
+    pub object_ids: Vec<u32>,
+    pub normals: Vec<glm::Vec3>,
+    // In m
+    pub linear_depth: Vec<f32>,
+}
+
+impl AovImages {
+    // From the texels read back from the attachments, in the order of AOV_FORMATS
+    pub(crate) fn from_texels(width: u32, height: u32, texels: [Vec<u8>; 3]) -> Self {
+        let [ids, normals, depth] = texels;
+        let to_f32 = |bytes: &[u8]| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        let half = |bytes: &[u8], i: usize| {
+            color::f16_to_f32(u16::from_le_bytes([bytes[2 * i], bytes[2 * i + 1]]))
+        };
+        Self {
+            width,
+            height,
+            object_ids: ids
+                .chunks_exact(4)
+                .map(|id| u32::from_le_bytes([id[0], id[1], id[2], id[3]]))
+                .collect(),
+            normals: normals
+                .chunks_exact(8)
+                .map(|texel| glm::vec3(half(texel, 0), half(texel, 1), half(texel, 2)))
+                .collect(),
+            linear_depth: depth.chunks_exact(4).map(to_f32).collect(),
+        }
+    }
+
+    pub fn object_id(&self, x: u32, y: u32) -> u32 {
+        self.object_ids[(y * self.width + x) as usize]
+    }
+
+    // Every object that covers at least one pixel
+    pub fn unique_object_ids(&self) -> BTreeSet<u32> {
+        self.object_ids
+            .iter()
+            .copied()
+            .filter(|id| *id != AOV_BACKGROUND_ID)
+            .collect()
+    }
+
+    // 16 bit PNGs can't hold the ids, so they are split into their low and high halves
+    pub fn object_id_images(&self) -> (ObjectIdImage, ObjectIdImage) {
+        let split = |shift: u32| {
+            image::ImageBuffer::from_raw(
+                self.width,
+                self.height,
+                self.object_ids
+                    .iter()
+                    .map(|id| (id >> shift) as u16)
+                    .collect(),
+            )
+            .expect("ImageBuffer creation")
+        };
+        (split(0), split(16))
+    }
+
+    // Writes object_ids_low.png and object_ids_high.png, and normals.f32 and depth.f32
+    // with the raw little endian floats, three per pixel for the normals, row by row
+    pub fn save<P: AsRef<Path>>(&self, directory: P) -> RendererResult<()> {
+        let directory = directory.as_ref();
+        std::fs::create_dir_all(directory)?;
+        let (low, high) = self.object_id_images();
+        low.save(directory.join("object_ids_low.png"))?;
+        high.save(directory.join("object_ids_high.png"))?;
+        let mut normals =
+            std::io::BufWriter::new(std::fs::File::create(directory.join("normals.f32"))?);
+        for normal in self.normals.iter() {
+            for c in normal.iter() {
+                normals.write_all(&c.to_le_bytes())?;
+            }
+        }
+        normals.flush()?;
+        let mut depth =
+            std::io::BufWriter::new(std::fs::File::create(directory.join("depth.f32"))?);
+        for d in self.linear_depth.iter() {
+            depth.write_all(&d.to_le_bytes())?;
+        }
+        depth.flush()?;
+        Ok(())
+    }
+}
+
+// The part of a view the AOV pass draws. The target only covers the region, the viewport
+// moves the rest of the view off it.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct AovRegion {
+    pub view_extent: vk::Extent2D,
+    // Where the target starts in the view
+    pub offset: [u32; 2],
+    // Depth is cleared before every object, so none hides another, and each object
+    // gets a query counting its pixels. The pool has room for this many objects.
+    pub occlusion_queries: Option<(vk::QueryPool, u32)>,
+}
+
+impl AovRegion {
+    pub fn full(view_extent: vk::Extent2D) -> Self {
+        Self {
+            view_extent,
+            offset: [0, 0],
+            occlusion_queries: None,
+        }
+    }
+}
+
+// Created by the first capture_aovs and kept until the renderer is dropped
+pub(crate) struct AovPass {
+    pub render_pass: vk::RenderPass,
+    pub template: Handle<EffectTemplate>,
+}
+
+impl AovPass {
+    pub fn new(
+        device: &ash::Device,
+        material_system: &mut MaterialSystem,
+        shader_cache: &mut ShaderCache,
+    ) -> RendererResult<Self> {
+        let render_pass = Self::create_render_pass(device)?;
+        let template = material_system.build_aov_template(device, render_pass, shader_cache)?;
+        Ok(Self {
+            render_pass,
+            template,
+        })
+    }
+
+    // The attachments end up ready to be copied from
+    fn create_render_pass(device: &ash::Device) -> RendererResult<vk::RenderPass> {
+        let mut attachments = AOV_FORMATS
+            .iter()
+            .map(|format| {
+                vk::AttachmentDescription::builder()
+                    .format(*format)
+                    .load_op(vk::AttachmentLoadOp::CLEAR)
+                    .store_op(vk::AttachmentStoreOp::STORE)
+                    .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+                    .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+                    .initial_layout(vk::ImageLayout::UNDEFINED)
+                    .final_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+                    .samples(vk::SampleCountFlags::TYPE_1)
+                    .build()
+            })
+            .collect::<Vec<_>>();
+        attachments.push(
+            vk::AttachmentDescription::builder()
+                .format(vk::Format::D32_SFLOAT)
+                .load_op(vk::AttachmentLoadOp::CLEAR)
+                .store_op(vk::AttachmentStoreOp::DONT_CARE)
+                .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+                .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+                .initial_layout(vk::ImageLayout::UNDEFINED)
+                .final_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
+                .samples(vk::SampleCountFlags::TYPE_1)
+                .build(),
+        );
+
+        let color_attachment_references = (0..AOV_FORMATS.len() as u32)
+            .map(|attachment| vk::AttachmentReference {
+                attachment,
+                layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+            })
+            .collect::<Vec<_>>();
+        let depth_attachment_reference = vk::AttachmentReference {
+            attachment: AOV_FORMATS.len() as u32,
+            layout: vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+        };
+
+        let subpasses = [vk::SubpassDescription::builder()
+            .color_attachments(&color_attachment_references)
+            .depth_stencil_attachment(&depth_attachment_reference)
+            .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+            .build()];
+
+        let subpass_dependencies = [vk::SubpassDependency::builder()
+            .src_subpass(0)
+            .src_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
+            .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+            .dst_subpass(vk::SUBPASS_EXTERNAL)
+            .dst_stage_mask(vk::PipelineStageFlags::TRANSFER)
+            .dst_access_mask(vk::AccessFlags::TRANSFER_READ)
+            .build()];
+
+        let renderpass_info = vk::RenderPassCreateInfo::builder()
+            .attachments(&attachments)
+            .subpasses(&subpasses)
+            .dependencies(&subpass_dependencies);
+        unsafe { Ok(device.create_render_pass(&renderpass_info, None)?) }
+    }
+
+    // The pipeline belongs to the template
+    pub fn destroy(&mut self, device: &ash::Device) {
+        unsafe {
+            device.destroy_render_pass(self.render_pass, None);
+        }
+    }
+}
+
+struct Attachment {
+    image: vk::Image,
+    allocation: Allocation,
+    view: vk::ImageView,
+}
+
+impl Attachment {
+    fn new(
+        context: &VulkanContext,
+        allocator: &mut Allocator,
+        format: vk::Format,
+        extent: vk::Extent2D,
+        usage: vk::ImageUsageFlags,
+        aspect_mask: vk::ImageAspectFlags,
+    ) -> RendererResult<Self> {
+        let queue_family_indices = [context.graphics_queue.index];
+        let image_info = vk::ImageCreateInfo::builder()
+            .image_type(vk::ImageType::TYPE_2D)
+            .format(format)
+            .extent(vk::Extent3D {
+                width: extent.width,
+                height: extent.height,
+                depth: 1,
+            })
+            .mip_levels(1)
+            .array_layers(1)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .tiling(vk::ImageTiling::OPTIMAL)
+            .usage(usage)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE)
+            .queue_family_indices(&queue_family_indices);
+        let image = unsafe { context.device.create_image(&image_info, None) }?;
+        let reqs = unsafe { context.device.get_image_memory_requirements(image) };
+        let allocation = allocator.allocate(&AllocationCreateDesc {
+            name: "aov_image",
+            requirements: reqs,
+            location: MemoryLocation::GpuOnly,
+            linear: false,
+            allocation_scheme: AllocationScheme::GpuAllocatorManaged,
+        })?;
+        unsafe {
+            context
+                .device
+                .bind_image_memory(image, allocation.memory(), allocation.offset())?;
+        }
+        let subresource_range = vk::ImageSubresourceRange::builder()
+            .aspect_mask(aspect_mask)
+            .base_mip_level(0)
+            .level_count(1)
+            .base_array_layer(0)
+            .layer_count(1);
+        let view_info = vk::ImageViewCreateInfo::builder()
+            .image(image)
+            .view_type(vk::ImageViewType::TYPE_2D)
+            .format(format)
+            .subresource_range(*subresource_range);
+        let view = unsafe { context.device.create_image_view(&view_info, None) }?;
+        Ok(Self {
+            image,
+            allocation,
+            view,
+        })
+    }
+
+    fn destroy(self, context: &VulkanContext, allocator: &mut Allocator) {
+        unsafe {
+            context.device.destroy_image_view(self.view, None);
+            context.device.destroy_image(self.image, None);
+        }
+        allocator
+            .free(self.allocation)
+            .expect("Could not free memory");
+    }
+}
+
+// The attachments of one capture, destroyed right after they are read back
+pub(crate) struct AovTarget {
+    pub extent: vk::Extent2D,
+    // In the order of AOV_FORMATS
+    attachments: Vec<Attachment>,
+    depth: Attachment,
+    pub framebuffer: vk::Framebuffer,
+}
+
+impl AovTarget {
+    pub fn new(
+        context: &VulkanContext,
+        allocator: &mut Allocator,
+        extent: vk::Extent2D,
+        render_pass: vk::RenderPass,
+    ) -> RendererResult<Self> {
+        let mut attachments = Vec::with_capacity(AOV_FORMATS.len());
+        for format in AOV_FORMATS {
+            attachments.push(Attachment::new(
+                context,
+                allocator,
+                format,
+                extent,
+                vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSFER_SRC,
+                vk::ImageAspectFlags::COLOR,
+            )?);
+        }
+        let depth = Attachment::new(
+            context,
+            allocator,
+            vk::Format::D32_SFLOAT,
+            extent,
+            vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT,
+            vk::ImageAspectFlags::DEPTH,
+        )?;
+        let views = attachments
+            .iter()
+            .chain(std::iter::once(&depth))
+            .map(|attachment| attachment.view)
+            .collect::<Vec<_>>();
+        let framebuffer_info = vk::FramebufferCreateInfo::builder()
+            .render_pass(render_pass)
+            .attachments(&views)
+            .width(extent.width)
+            .height(extent.height)
+            .layers(1);
+        let framebuffer = unsafe { context.device.create_framebuffer(&framebuffer_info, None) }?;
+        Ok(Self {
+            extent,
+            attachments,
+            depth,
+            framebuffer,
+        })
+    }
+
+    pub fn images(&self) -> impl Iterator<Item = vk::Image> + '_ {
+        self.attachments.iter().map(|attachment| attachment.image)
+    }
+
+    pub fn destroy(self, context: &VulkanContext, allocator: &mut Allocator) {
+        unsafe {
+            context.device.destroy_framebuffer(self.framebuffer, None);
+        }
+        for attachment in self.attachments {
+            attachment.destroy(context, allocator);
+        }
+        self.depth.destroy(context, allocator);
+    }
+}
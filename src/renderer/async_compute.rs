@@ -0,0 +1,176 @@
+use ash::vk;
+
+use super::{debug_namer::DebugNamer, queue::Queue, RendererResult};
+
+// The semaphores and command buffers of one frame in flight
+struct AsyncComputeFrame {
+    compute_command_buffer: vk::CommandBuffer,
+    // The graphics passes after the compute passes, the ones before them go into the
+    // command buffer of the swapchain image
+    graphics_command_buffer: vk::CommandBuffer,
+    // Signaled by the graphics passes the compute passes depend on
+    graphics_done: vk::Semaphore,
+    // Waited on by the frame's last submission, so the frame timeline covers the
+    // compute passes too
+    compute_done: vk::Semaphore,
+    // Waited on by the next frame before it writes the scene color again
+    scene_color_released: vk::Semaphore,
+}
+
+// Runs the passes the schedule puts on PassQueue::AsyncCompute on a compute queue of its
+// own. Resources both queues read at the same time, like the scene color, are created
+// with CONCURRENT sharing between sharing_families. The ones only the compute passes
+// touch stay EXCLUSIVE and are never transferred, their contents are rewritten every
+// frame before they are read.
+pub(crate) struct AsyncCompute {
+    device: ash::Device,
+    queue: vk::Queue,
+    sharing_families: [u32; 2],
+    command_pool: vk::CommandPool,
+    frames: Vec<AsyncComputeFrame>,
+    // The scene color release of the last submitted compute work, the next graphics
+    // submission that writes the scene color waits on it
+    pending_release: Option<vk::Semaphore>,
+}
+
+impl AsyncCompute {
+    // None without a compute queue, the compute passes are recorded inline then
+    pub fn new(
+        device: &ash::Device,
+        graphics_queue: &Queue,
+        compute_queue: Option<&Queue>,
+        graphics_command_pool: vk::CommandPool,
+        frames_in_flight: usize,
+        debug_namer: &DebugNamer,
+    ) -> RendererResult<Option<Self>> {
+        let compute_queue = match compute_queue {
+            Some(queue) if queue.index != graphics_queue.index => queue,
+            _ => return Ok(None),
+        };
+        let pool_info = vk::CommandPoolCreateInfo::builder()
+            .queue_family_index(compute_queue.index)
+            .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER);
+        let command_pool = unsafe { device.create_command_pool(&pool_info, None)? };
+        debug_namer.name(command_pool, "async compute");
+        let count = frames_in_flight as u32;
+        let compute_command_buffers = unsafe {
+            device.allocate_command_buffers(
+                &vk::CommandBufferAllocateInfo::builder()
+                    .command_pool(command_pool)
+                    .command_buffer_count(count),
+            )?
+        };
+        let graphics_command_buffers = unsafe {
+            device.allocate_command_buffers(
+                &vk::CommandBufferAllocateInfo::builder()
+                    .command_pool(graphics_command_pool)
+                    .command_buffer_count(count),
+            )?
+        };
+        let semaphore_info = vk::SemaphoreCreateInfo::builder();
+        let mut frames = Vec::with_capacity(frames_in_flight);
+        for (i, (compute_command_buffer, graphics_command_buffer)) in compute_command_buffers
+            .into_iter()
+            .zip(graphics_command_buffers.into_iter())
+            .enumerate()
+        {
+            let frame = unsafe {
+                AsyncComputeFrame {
+                    compute_command_buffer,
+                    graphics_command_buffer,
+                    graphics_done: device.create_semaphore(&semaphore_info, None)?,
+                    compute_done: device.create_semaphore(&semaphore_info, None)?,
+                    scene_color_released: device.create_semaphore(&semaphore_info, None)?,
+                }
+            };
+            debug_namer.name(
+                frame.compute_command_buffer,
+                &format!("async compute cb frame {}", i),
+            );
+            debug_namer.name(
+                frame.graphics_command_buffer,
+                &format!("graphics after compute cb frame {}", i),
+            );
+            debug_namer.name(
+                frame.graphics_done,
+                &format!("frame {} graphics-before-compute", i),
+            );
+            debug_namer.name(frame.compute_done, &format!("frame {} compute-done", i));
+            debug_namer.name(
+                frame.scene_color_released,
+                &format!("frame {} scene-color-released", i),
+            );
+            frames.push(frame);
+        }
+        Ok(Some(Self {
+            device: device.clone(),
+            queue: compute_queue.queue,
+            sharing_families: [graphics_queue.index, compute_queue.index],
+            command_pool,
+            frames,
+            pending_release: None,
+        }))
+    }
+
+    // The queue families of resources both queues use
+    pub fn sharing_families(&self) -> &[u32] {
+        &self.sharing_families
+    }
+
+    pub fn compute_command_buffer(&self, frame: usize) -> vk::CommandBuffer {
+        self.frames[frame].compute_command_buffer
+    }
+
+    pub fn graphics_command_buffer(&self, frame: usize) -> vk::CommandBuffer {
+        self.frames[frame].graphics_command_buffer
+    }
+
+    pub fn graphics_done(&self, frame: usize) -> vk::Semaphore {
+        self.frames[frame].graphics_done
+    }
+
+    pub fn compute_done(&self, frame: usize) -> vk::Semaphore {
+        self.frames[frame].compute_done
+    }
+
+    // A binary semaphore has to be waited on before it is signaled again, so the wait
+    // is handed to exactly one submission
+    pub fn take_pending_release(&mut self) -> Option<vk::Semaphore> {
+        self.pending_release.take()
+    }
+
+    // Submits the recorded compute command buffer of the frame, after the graphics
+    // submission that signals graphics_done
+    pub fn submit(&mut self, frame: usize) -> RendererResult<()> {
+        let frame_data = &self.frames[frame];
+        let wait_semaphores = [frame_data.graphics_done];
+        let wait_stages = [vk::PipelineStageFlags::COMPUTE_SHADER];
+        let command_buffers = [frame_data.compute_command_buffer];
+        let signal_semaphores = [frame_data.compute_done, frame_data.scene_color_released];
+        let submit_infos = [vk::SubmitInfo::builder()
+            .wait_semaphores(&wait_semaphores)
+            .wait_dst_stage_mask(&wait_stages)
+            .command_buffers(&command_buffers)
+            .signal_semaphores(&signal_semaphores)
+            .build()];
+        unsafe {
+            self.device
+                .queue_submit(self.queue, &submit_infos, vk::Fence::null())?
+        };
+        self.pending_release = Some(frame_data.scene_color_released);
+        Ok(())
+    }
+
+    // Nothing may be in flight
+    pub fn destroy(&mut self) {
+        unsafe {
+            for frame in self.frames.drain(..) {
+                self.device.destroy_semaphore(frame.graphics_done, None);
+                self.device.destroy_semaphore(frame.compute_done, None);
+                self.device
+                    .destroy_semaphore(frame.scene_color_released, None);
+            }
+            self.device.destroy_command_pool(self.command_pool, None);
+        }
+    }
+}
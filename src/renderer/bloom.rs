@@ -0,0 +1,425 @@
+use ash::vk;
+
+use super::{
+    descriptor::DescriptorAllocator, error::InvalidHandle, material::ComputePipelineBuilder,
+    shaders::ShaderCache, RendererResult,
+};
+
+// The most levels the bloom chain gets, whatever BloomConfig::levels asks for
+pub const MAX_BLOOM_LEVELS: u32 = 8;
+
+// How the bright parts of the scene glow, see Renderer::set_bloom
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BloomConfig {
+    // How much of the blurred bright parts is added to the scene, 0 turns bloom off
+    pub intensity: f32,
+    // Radiance above it glows, before the exposure is applied
+    pub threshold: f32,
+    // Radiance up to this much below the threshold glows a little, so there is no hard
+    // edge where it starts
+    pub knee: f32,
+    // Every level halves the size of the one before it, more of them spread the glow
+    // further. At most MAX_BLOOM_LEVELS, and fewer on small targets.
+    pub levels: u32,
+}
+
+impl Default for BloomConfig {
+    fn default() -> Self {
+        Self {
+            intensity: 0.0,
+            threshold: 1.0,
+            knee: 0.5,
+            levels: 6,
+        }
+    }
+}
+
+impl BloomConfig {
+    pub fn enabled(&self) -> bool {
+        self.intensity > 0.0
+    }
+}
+
+// The first level of the chain is half the size of the scene
+pub fn bloom_extent(scene_extent: vk::Extent2D) -> vk::Extent2D {
+    vk::Extent2D {
+        width: (scene_extent.width / 2).max(1),
+        height: (scene_extent.height / 2).max(1),
+    }
+}
+
+// The levels of a chain starting at extent, it stops before a level would be smaller
+// than a texel
+pub fn bloom_level_count(extent: vk::Extent2D, levels: u32) -> u32 {
+    let fitting = u32::BITS - extent.width.min(extent.height).max(1).leading_zeros();
+    levels.clamp(1, MAX_BLOOM_LEVELS).min(fitting)
+}
+
+struct BloomPipeline {
+    pipeline: vk::Pipeline,
+    pipeline_layout: vk::PipelineLayout,
+    // MAX_BLOOM_LEVELS of them, rewritten for every chain
+    descriptor_sets: Vec<vk::DescriptorSet>,
+}
+
+impl BloomPipeline {
+    fn new(
+        device: &ash::Device,
+        shader_cache: &mut ShaderCache,
+        descriptor_allocator: &mut DescriptorAllocator,
+        name: &str,
+        code: Vec<u32>,
+    ) -> RendererResult<Self> {
+        shader_cache.add_shader_module(device, name, code)?;
+        let effect_handle = shader_cache.build_compute_effect(device, name)?;
+        let effect = shader_cache.get_shader_effect_by_handle(effect_handle)?;
+        let stage = *effect
+            .get_stages(shader_cache)?
+            .first()
+            .ok_or(InvalidHandle)?;
+        let pipeline = ComputePipelineBuilder::new(stage, effect.pipeline_layout)
+            .build_pipeline(device, shader_cache.pipeline_cache())?;
+        let descriptor_sets = (0..MAX_BLOOM_LEVELS)
+            .map(|_| descriptor_allocator.allocate(device, effect.set_layouts[0]))
+            .collect::<RendererResult<Vec<_>>>()?;
+        Ok(Self {
+            pipeline,
+            pipeline_layout: effect.pipeline_layout,
+            descriptor_sets,
+        })
+    }
+}
+
+// Blurs what is brighter than the threshold for the upscale pass, which adds it to the
+// scene. The scene is downsampled into a chain of levels, each half the size of the one
+// before, then the levels are blurred and added back up from the smallest one, which
+// leaves the glow in the first level. All of it runs on the graphics queue, frames in
+// flight share the chain. Created when bloom is first turned on and kept until the
+// renderer is dropped.
+pub(crate) struct BloomPass {
+    downsample: BloomPipeline,
+    upsample: BloomPipeline,
+    sampler: vk::Sampler,
+    scene_view: vk::ImageView,
+    chain: vk::Image,
+    // Of the first level
+    extent: vk::Extent2D,
+    // One per level, for sampling and storing alike
+    level_views: Vec<vk::ImageView>,
+}
+
+impl BloomPass {
+    pub fn new(
+        device: &ash::Device,
+        shader_cache: &mut ShaderCache,
+        descriptor_allocator: &mut DescriptorAllocator,
+    ) -> RendererResult<Self> {
+        let downsample = BloomPipeline::new(
+            device,
+            shader_cache,
+            descriptor_allocator,
+            "./shaders/bloom_downsample.comp",
+            vk_shader_macros::include_glsl!("./shaders/bloom_downsample.comp", kind: comp).to_vec(),
+        )?;
+        let upsample = BloomPipeline::new(
+            device,
+            shader_cache,
+            descriptor_allocator,
+            "./shaders/bloom_upsample.comp",
+            vk_shader_macros::include_glsl!("./shaders/bloom_upsample.comp", kind: comp).to_vec(),
+        )?;
+        let sampler_info = vk::SamplerCreateInfo::builder()
+            .mag_filter(vk::Filter::LINEAR)
+            .min_filter(vk::Filter::LINEAR)
+            .address_mode_u(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_EDGE);
+        let sampler = unsafe { device.create_sampler(&sampler_info, None) }?;
+        Ok(Self {
+            downsample,
+            upsample,
+            sampler,
+            scene_view: vk::ImageView::null(),
+            chain: vk::Image::null(),
+            extent: vk::Extent2D::default(),
+            level_views: vec![],
+        })
+    }
+
+    pub fn level_count(&self) -> u32 {
+        self.level_views.len() as u32
+    }
+
+    // Whether the passes already blur scene_view into a chain like that
+    pub fn is_target(&self, scene_view: vk::ImageView, extent: vk::Extent2D, levels: u32) -> bool {
+        self.scene_view == scene_view && self.extent == extent && self.level_count() == levels
+    }
+
+    // Points the passes at a new scene color and chain, the chain has levels levels and
+    // extent is the size of the first one. None of the frames in flight may use the old
+    // ones anymore.
+    pub fn set_target(
+        &mut self,
+        device: &ash::Device,
+        scene_view: vk::ImageView,
+        chain: vk::Image,
+        format: vk::Format,
+        extent: vk::Extent2D,
+        levels: u32,
+    ) -> RendererResult<()> {
+        self.destroy_level_views(device);
+        self.scene_view = scene_view;
+        self.chain = chain;
+        self.extent = extent;
+        for level in 0..levels {
+            let view_create_info = vk::ImageViewCreateInfo::builder()
+                .image(chain)
+                .view_type(vk::ImageViewType::TYPE_2D)
+                .format(format)
+                .subresource_range(vk::ImageSubresourceRange {
+                    aspect_mask: vk::ImageAspectFlags::COLOR,
+                    base_mip_level: level,
+                    level_count: 1,
+                    base_array_layer: 0,
+                    layer_count: 1,
+                });
+            self.level_views
+                .push(unsafe { device.create_image_view(&view_create_info, None) }?);
+        }
+
+        let source_info = |view: vk::ImageView, layout: vk::ImageLayout| {
+            [vk::DescriptorImageInfo {
+                sampler: self.sampler,
+                image_view: view,
+                image_layout: layout,
+            }]
+        };
+        let level_info = |level: usize| {
+            [vk::DescriptorImageInfo {
+                sampler: vk::Sampler::null(),
+                image_view: self.level_views[level],
+                image_layout: vk::ImageLayout::GENERAL,
+            }]
+        };
+        for level in 0..levels as usize {
+            // The chain stays in GENERAL while the passes run
+            let downsample_source = if level == 0 {
+                source_info(scene_view, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+            } else {
+                source_info(self.level_views[level - 1], vk::ImageLayout::GENERAL)
+            };
+            let downsample_target = level_info(level);
+            let mut writes = vec![
+                vk::WriteDescriptorSet::builder()
+                    .dst_set(self.downsample.descriptor_sets[level])
+                    .dst_binding(0)
+                    .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                    .image_info(&downsample_source)
+                    .build(),
+                vk::WriteDescriptorSet::builder()
+                    .dst_set(self.downsample.descriptor_sets[level])
+                    .dst_binding(1)
+                    .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
+                    .image_info(&downsample_target)
+                    .build(),
+            ];
+            // The smallest level has nothing below it to add
+            let upsample_source = (level + 1 < levels as usize)
+                .then(|| source_info(self.level_views[level + 1], vk::ImageLayout::GENERAL));
+            if let Some(upsample_source) = upsample_source.as_ref() {
+                writes.push(
+                    vk::WriteDescriptorSet::builder()
+                        .dst_set(self.upsample.descriptor_sets[level])
+                        .dst_binding(0)
+                        .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                        .image_info(upsample_source)
+                        .build(),
+                );
+                writes.push(
+                    vk::WriteDescriptorSet::builder()
+                        .dst_set(self.upsample.descriptor_sets[level])
+                        .dst_binding(1)
+                        .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
+                        .image_info(&downsample_target)
+                        .build(),
+                );
+            }
+            unsafe { device.update_descriptor_sets(&writes, &[]) };
+        }
+        Ok(())
+    }
+
+    // The scene color has to be in SHADER_READ_ONLY_OPTIMAL and visible to compute
+    // shaders, the scene render pass does both. Leaves the chain in
+    // SHADER_READ_ONLY_OPTIMAL for the upscale pass.
+    pub fn record(
+        &self,
+        device: &ash::Device,
+        command_buffer: vk::CommandBuffer,
+        config: &BloomConfig,
+    ) {
+        let levels = self.level_count();
+        if levels == 0 {
+            return;
+        }
+        let level_extent = |level: u32| vk::Extent2D {
+            width: (self.extent.width >> level).max(1),
+            height: (self.extent.height >> level).max(1),
+        };
+        let level_range = |base_mip_level: u32, level_count: u32| vk::ImageSubresourceRange {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            base_mip_level,
+            level_count,
+            base_array_layer: 0,
+            layer_count: 1,
+        };
+        let barrier = |range: vk::ImageSubresourceRange,
+                       old_layout: vk::ImageLayout,
+                       new_layout: vk::ImageLayout,
+                       src_access: vk::AccessFlags,
+                       dst_access: vk::AccessFlags| {
+            vk::ImageMemoryBarrier::builder()
+                .image(self.chain)
+                .subresource_range(range)
+                .old_layout(old_layout)
+                .new_layout(new_layout)
+                .src_access_mask(src_access)
+                .dst_access_mask(dst_access)
+                .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .build()
+        };
+        // A level written by one dispatch is read by the next
+        let level_written = |level: u32| {
+            barrier(
+                level_range(level, 1),
+                vk::ImageLayout::GENERAL,
+                vk::ImageLayout::GENERAL,
+                vk::AccessFlags::SHADER_WRITE,
+                vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE,
+            )
+        };
+        let dispatch = |pipeline: &BloomPipeline, level: u32, push_constants: &[u8]| {
+            let extent = level_extent(level);
+            unsafe {
+                device.cmd_bind_pipeline(
+                    command_buffer,
+                    vk::PipelineBindPoint::COMPUTE,
+                    pipeline.pipeline,
+                );
+                device.cmd_bind_descriptor_sets(
+                    command_buffer,
+                    vk::PipelineBindPoint::COMPUTE,
+                    pipeline.pipeline_layout,
+                    0,
+                    &[pipeline.descriptor_sets[level as usize]],
+                    &[],
+                );
+                if !push_constants.is_empty() {
+                    device.cmd_push_constants(
+                        command_buffer,
+                        pipeline.pipeline_layout,
+                        vk::ShaderStageFlags::COMPUTE,
+                        0,
+                        push_constants,
+                    );
+                }
+                // 8x8 work groups, see the shaders
+                device.cmd_dispatch(
+                    command_buffer,
+                    (extent.width + 7) / 8,
+                    (extent.height + 7) / 8,
+                    1,
+                );
+            }
+        };
+        let compute_barrier = |barriers: &[vk::ImageMemoryBarrier]| unsafe {
+            device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                barriers,
+            );
+        };
+
+        // Everything is written again, the previous frame's upscale only has to be done
+        // reading the first level
+        unsafe {
+            device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::FRAGMENT_SHADER,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &[barrier(
+                    level_range(0, levels),
+                    vk::ImageLayout::UNDEFINED,
+                    vk::ImageLayout::GENERAL,
+                    vk::AccessFlags::empty(),
+                    vk::AccessFlags::SHADER_WRITE,
+                )],
+            );
+        }
+        for level in 0..levels {
+            let constants = [
+                config.threshold.to_bits(),
+                config.knee.max(0.0).to_bits(),
+                (level == 0) as u32,
+            ];
+            let bytes = unsafe {
+                std::slice::from_raw_parts(
+                    constants.as_ptr() as *const u8,
+                    std::mem::size_of_val(&constants),
+                )
+            };
+            dispatch(&self.downsample, level, bytes);
+            compute_barrier(&[level_written(level)]);
+        }
+        for level in (0..levels - 1).rev() {
+            dispatch(&self.upsample, level, &[]);
+            if level > 0 {
+                compute_barrier(&[level_written(level)]);
+            }
+        }
+        unsafe {
+            device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::PipelineStageFlags::FRAGMENT_SHADER,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &[barrier(
+                    level_range(0, levels),
+                    vk::ImageLayout::GENERAL,
+                    vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                    vk::AccessFlags::SHADER_WRITE,
+                    vk::AccessFlags::SHADER_READ,
+                )],
+            );
+        }
+    }
+
+    fn destroy_level_views(&mut self, device: &ash::Device) {
+        for view in self.level_views.drain(..) {
+            unsafe {
+                device.destroy_image_view(view, None);
+            }
+        }
+    }
+
+    // The chain is a texture of the texture storage, the effects and shader modules
+    // belong to the shader cache and the descriptor sets to the descriptor allocator
+    pub fn destroy(&mut self, device: &ash::Device) {
+        self.destroy_level_views(device);
+        unsafe {
+            device.destroy_pipeline(self.downsample.pipeline, None);
+            device.destroy_pipeline(self.upsample.pipeline, None);
+            device.destroy_sampler(self.sampler, None);
+        }
+    }
+}
@@ -1,14 +1,41 @@
+#[cfg(test)]
+use std::cell::Cell;
+use std::collections::VecDeque;
 use std::fmt::Debug;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, LockResult, Mutex, MutexGuard};
 
 use ash::vk;
 use gpu_allocator::vulkan::{Allocation, AllocationCreateDesc, AllocationScheme, Allocator};
 use gpu_allocator::MemoryLocation;
 
-use super::error::InvalidHandle;
+use super::debug_namer::DebugNamer;
+use super::error::{
+    BufferAccessError, InvalidHandle, OutOfBoundsWrite, RendererError, TransferError,
+};
+use super::queue::Queue;
+use super::timeline::retired;
 use super::utils::{Handle, HandleArray};
 use super::RendererResult;
 
+// The most vkCmdUpdateBuffer writes at once
+pub(crate) const MAX_UPDATE_SIZE: usize = 65536;
+
+// Locks of the allocator by this thread, see lock_allocator
+#[cfg(test)]
+thread_local! {
+    pub(crate) static ALLOCATOR_LOCKS: Cell<usize> = Cell::new(0);
+}
+
+// The renderer locks its allocator only through here, so tests can count the locks.
+// Writes to buffers that are already allocated don't need it, see Buffer::write.
+pub(crate) fn lock_allocator(
+    allocator: &Mutex<Allocator>,
+) -> LockResult<MutexGuard<'_, Allocator>> {
+    #[cfg(test)]
+    ALLOCATOR_LOCKS.with(|locks| locks.set(locks.get() + 1));
+    allocator.lock()
+}
+
 pub struct InternalBuffer {
     device: ash::Device,
     allocation: Option<Allocation>,
@@ -16,7 +43,12 @@ pub struct InternalBuffer {
     size: u64,
     buffer_usage: vk::BufferUsageFlags,
     location: MemoryLocation,
+    // Shared with CONCURRENT sharing between these queue families
+    sharing_families: Option<[u32; 2]>,
     name: String,
+    // The first frame that can use the vk::Buffer, the frames before it were submitted
+    // before it existed
+    first_frame: u64,
 }
 
 impl Debug for InternalBuffer {
@@ -28,21 +60,32 @@ impl Debug for InternalBuffer {
             .field("size", &self.size)
             .field("buffer_usage", &self.buffer_usage)
             .field("location", &self.location)
+            .field("first_frame", &self.first_frame)
             .finish()
     }
 }
 
 impl InternalBuffer {
+    #[allow(clippy::too_many_arguments)]
     fn new(
         device: &ash::Device,
         allocator: &mut Allocator,
         size: u64,
         buffer_usage: vk::BufferUsageFlags,
         location: MemoryLocation,
+        sharing_families: Option<[u32; 2]>,
         name: &str,
+        first_frame: u64,
     ) -> RendererResult<InternalBuffer> {
-        let (buffer, allocation) =
-            Self::allocate_buffer(device, allocator, size, buffer_usage, location, name)?;
+        let (buffer, allocation) = Self::allocate_buffer(
+            device,
+            allocator,
+            size,
+            buffer_usage,
+            location,
+            sharing_families,
+            name,
+        )?;
         Ok(InternalBuffer {
             device: device.clone(),
             allocation: Some(allocation),
@@ -50,7 +93,9 @@ impl InternalBuffer {
             size,
             buffer_usage,
             location,
+            sharing_families,
             name: name.to_string(),
+            first_frame,
         })
     }
 
@@ -60,12 +105,17 @@ impl InternalBuffer {
         size: u64,
         buffer_usage: vk::BufferUsageFlags,
         location: MemoryLocation,
+        sharing_families: Option<[u32; 2]>,
         name: &str,
     ) -> RendererResult<(vk::Buffer, Allocation)> {
-        let buffer_create_info = vk::BufferCreateInfo::builder()
+        let mut buffer_create_info = vk::BufferCreateInfo::builder()
             .size(size)
             .usage(buffer_usage);
-        // TODO: sharing mode?
+        if let Some(families) = sharing_families.as_ref() {
+            buffer_create_info = buffer_create_info
+                .sharing_mode(vk::SharingMode::CONCURRENT)
+                .queue_family_indices(families);
+        }
         let buffer = unsafe { device.create_buffer(&buffer_create_info, None)? };
         let reqs = unsafe { device.get_buffer_memory_requirements(buffer) };
         let allocation = allocator.allocate(&AllocationCreateDesc {
@@ -82,68 +132,108 @@ impl InternalBuffer {
         Ok((buffer, allocation))
     }
 
-    fn fill<T>(&mut self, allocator: &mut Allocator, data: &[T]) -> RendererResult<()> {
-        let data_len = data.len() * std::mem::size_of::<T>();
-        if data_len > self.size as usize {
-            let (buffer, allocation) = Self::allocate_buffer(
-                &self.device,
-                allocator,
-                data_len as u64,
-                self.buffer_usage,
-                self.location,
-                &self.name,
-            )?;
-            let old_allocation = self.allocation.take().expect("Buffer had no allocation!");
+    // Moves to a new buffer of size bytes and hands back the old one, which frames in
+    // flight may still use. Host visible contents are carried over, device local ones
+    // have to be copied on the GPU, see BufferManager::transfer_by_handle.
+    fn reallocate(
+        &mut self,
+        allocator: &mut Allocator,
+        size: u64,
+        first_frame: u64,
+    ) -> RendererResult<InternalBuffer> {
+        let (buffer, allocation) = Self::allocate_buffer(
+            &self.device,
+            allocator,
+            size,
+            self.buffer_usage,
+            self.location,
+            self.sharing_families,
+            &self.name,
+        )?;
+        let old_ptr = self.allocation.as_ref().and_then(|a| a.mapped_ptr());
+        if let (Some(src), Some(dst)) = (old_ptr, allocation.mapped_ptr()) {
+            let len = self.size.min(size) as usize;
             unsafe {
-                allocator.free(old_allocation)?;
-                self.device.destroy_buffer(self.buffer, None);
+                (dst.as_ptr() as *mut u8).copy_from_nonoverlapping(src.as_ptr() as *const u8, len)
+            };
+        }
+        Ok(InternalBuffer {
+            device: self.device.clone(),
+            allocation: self.allocation.replace(allocation),
+            buffer: std::mem::replace(&mut self.buffer, buffer),
+            size: std::mem::replace(&mut self.size, size),
+            buffer_usage: self.buffer_usage,
+            location: self.location,
+            sharing_families: self.sharing_families,
+            name: self.name.clone(),
+            first_frame: std::mem::replace(&mut self.first_frame, first_frame),
+        })
+    }
+
+    // Data that doesn't fit is an OutOfBoundsWrite, a buffer that isn't host visible a
+    // BufferAccessError.
+    // The allocation stays mapped for its whole life, so this never needs the
+    // allocator. With an atom_size the write is flushed, in whole atoms of atom_size
+    // bytes, as the memory may not be host coherent.
+    fn write<T>(&self, data: &[T], offset: usize, atom_size: Option<u64>) -> RendererResult<()> {
+        let data_len = std::mem::size_of_val(data);
+        if data_len as u64 + offset as u64 > self.size {
+            return Err(OutOfBoundsWrite {
+                offset,
+                len: data_len,
+                size: self.size,
             }
-            self.buffer = buffer;
-            self.allocation = Some(allocation);
-            self.size = data_len as u64;
+            .into());
         }
-        if let Some(allocation) = &self.allocation {
-            let data_ptr = allocation.mapped_ptr().unwrap().as_ptr() as *mut u8;
-            unsafe { data_ptr.copy_from_nonoverlapping(data.as_ptr() as *const u8, data_len) };
-        } else {
-            panic!("Buffer had no allocation!");
+        let data_ptr = self.mapped_ptr()? as *mut u8;
+        let data_ptr = unsafe { data_ptr.add(offset) };
+        unsafe { data_ptr.copy_from_nonoverlapping(data.as_ptr() as *const u8, data_len) };
+        if let (Some(atom_size), Some(allocation)) = (atom_size, &self.allocation) {
+            let (flush_offset, flush_size) = flush_range(
+                allocation.offset(),
+                allocation.size(),
+                offset as u64,
+                data_len as u64,
+                atom_size,
+            );
+            let ranges = [vk::MappedMemoryRange::builder()
+                .memory(unsafe { allocation.memory() })
+                .offset(flush_offset)
+                .size(flush_size)
+                .build()];
+            unsafe { self.device.flush_mapped_memory_ranges(&ranges)? };
         }
         Ok(())
     }
 
-    pub fn copy_to_offset<T>(
-        &mut self,
-        allocator: &mut Allocator,
-        data: &[T],
-        offset: usize,
-    ) -> RendererResult<()> {
-        let data_len = data.len() * std::mem::size_of::<T>();
-        if (data_len + offset) > self.size as usize {
-            let (buffer, allocation) = Self::allocate_buffer(
-                &self.device,
-                allocator,
-                (data_len + offset) as u64,
-                self.buffer_usage,
-                self.location,
-                &self.name,
-            )?;
-            let old_allocation = self.allocation.take().expect("Buffer had no allocation!");
-            unsafe {
-                allocator.free(old_allocation)?;
-                self.device.destroy_buffer(self.buffer, None);
-            }
-            self.buffer = buffer;
-            self.allocation = Some(allocation);
-            self.size = (data_len + offset) as u64;
+    // Only for host visible buffers the GPU is done writing to
+    fn read<T: Copy>(&self, count: usize) -> RendererResult<Vec<T>> {
+        let data_len = count * std::mem::size_of::<T>();
+        if data_len as u64 > self.size {
+            return Err(BufferAccessError(format!(
+                "Tried to read {} bytes from {}, which only has {}",
+                data_len, self.name, self.size
+            ))
+            .into());
         }
-        if let Some(allocation) = &self.allocation {
-            let data_ptr = allocation.mapped_ptr().unwrap().as_ptr() as *mut u8;
-            let data_ptr = unsafe { data_ptr.add(offset) };
-            unsafe { data_ptr.copy_from_nonoverlapping(data.as_ptr() as *const u8, data_len) };
-        } else {
-            panic!("Buffer had no allocation!");
+        let data_ptr = self.mapped_ptr()? as *const T;
+        let mut data = Vec::with_capacity(count);
+        unsafe {
+            data_ptr.copy_to_nonoverlapping(data.as_mut_ptr(), count);
+            data.set_len(count);
         }
-        Ok(())
+        Ok(data)
+    }
+
+    fn mapped_ptr(&self) -> RendererResult<*mut std::ffi::c_void> {
+        let allocation = self
+            .allocation
+            .as_ref()
+            .ok_or_else(|| BufferAccessError(format!("{} has no allocation", self.name)))?;
+        let ptr = allocation
+            .mapped_ptr()
+            .ok_or_else(|| BufferAccessError(format!("{} is not host visible", self.name)))?;
+        Ok(ptr.as_ptr())
     }
 
     fn destroy(&mut self, allocator: &mut Allocator) {
@@ -156,20 +246,297 @@ impl InternalBuffer {
     }
 }
 
+// The (offset, size) to flush after writing len bytes at offset into an allocation,
+// rounded out to whole atoms. When the last atom reaches past the allocation it may
+// also reach past the memory object, so the flush goes to its end with WHOLE_SIZE
+// instead (VUID-VkMappedMemoryRange-size-01390).
+fn flush_range(
+    allocation_offset: u64,
+    allocation_size: u64,
+    offset: u64,
+    len: u64,
+    atom_size: u64,
+) -> (u64, u64) {
+    let atom_size = atom_size.max(1);
+    let start = allocation_offset + offset;
+    let aligned_start = start / atom_size * atom_size;
+    let aligned_end = (start + len + atom_size - 1) / atom_size * atom_size;
+    if aligned_end > allocation_offset + allocation_size {
+        (aligned_start, vk::WHOLE_SIZE)
+    } else {
+        (aligned_start, aligned_end - aligned_start)
+    }
+}
+
+// Copies into a device local buffer on the transfer queue. Everything it holds is
+// destroyed once fence is signaled, retired only once its frame is done as well.
+#[derive(Debug)]
+struct PendingUpload {
+    command_buffer: vk::CommandBuffer,
+    fence: vk::Fence,
+    // None when nothing comes from the CPU, like when a buffer is moved
+    staging: Option<InternalBuffer>,
+    // The buffer the copies moved away from, with the last frame that may use it
+    retired: Option<(InternalBuffer, u64)>,
+}
+
+// The regions copied from one buffer to another
+type BufferCopies = (vk::Buffer, vk::Buffer, Vec<vk::BufferCopy>);
+
+// Fills device local buffers from staging buffers on the transfer queue. The graphics
+// queue waits for the copies with one semaphore per upload, see
+// BufferManager::take_upload_semaphores. The buffers are shared between both families
+// with CONCURRENT sharing when they differ, so they are never transferred.
+struct TransferContext {
+    device: ash::Device,
+    queue: vk::Queue,
+    command_pool: vk::CommandPool,
+    sharing_families: Option<[u32; 2]>,
+    pending: Vec<PendingUpload>,
+    // Signaled by uploads no graphics submission waits on yet
+    signaled: Vec<vk::Semaphore>,
+    // Ordered by the frame after which each semaphore is no longer waited on
+    waited: VecDeque<(vk::Semaphore, u64)>,
+}
+
+impl Debug for TransferContext {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TransferContext")
+            .field("device", &self.device.handle())
+            .field("queue", &self.queue)
+            .field("command_pool", &self.command_pool)
+            .field("sharing_families", &self.sharing_families)
+            .field("pending", &self.pending)
+            .field("signaled", &self.signaled)
+            .field("waited", &self.waited)
+            .finish()
+    }
+}
+
+impl TransferContext {
+    // The copies are recorded in order, after a barrier that makes them wait for the
+    // copies submitted to the queue before, which may write the same buffers
+    fn submit_copies(
+        &mut self,
+        copies: &[BufferCopies],
+        staging: Option<InternalBuffer>,
+        retired: Option<(InternalBuffer, u64)>,
+    ) -> RendererResult<vk::Fence> {
+        let device = &self.device;
+        let command_buffer = unsafe {
+            device.allocate_command_buffers(
+                &vk::CommandBufferAllocateInfo::builder()
+                    .command_pool(self.command_pool)
+                    .command_buffer_count(1),
+            )?
+        }[0];
+        let begin_info = vk::CommandBufferBeginInfo::builder()
+            .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+        let barriers = [vk::MemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .dst_access_mask(vk::AccessFlags::TRANSFER_READ | vk::AccessFlags::TRANSFER_WRITE)
+            .build()];
+        unsafe {
+            device.begin_command_buffer(command_buffer, &begin_info)?;
+            device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::DependencyFlags::empty(),
+                &barriers,
+                &[],
+                &[],
+            );
+            for (src, dst, regions) in copies {
+                device.cmd_copy_buffer(command_buffer, *src, *dst, regions);
+            }
+            device.end_command_buffer(command_buffer)?;
+        }
+        let fence = unsafe { device.create_fence(&vk::FenceCreateInfo::default(), None)? };
+        let semaphore =
+            unsafe { device.create_semaphore(&vk::SemaphoreCreateInfo::default(), None)? };
+        let command_buffers = [command_buffer];
+        let signal_semaphores = [semaphore];
+        let submit_infos = [vk::SubmitInfo::builder()
+            .command_buffers(&command_buffers)
+            .signal_semaphores(&signal_semaphores)
+            .build()];
+        unsafe { device.queue_submit(self.queue, &submit_infos, fence)? };
+        self.pending.push(PendingUpload {
+            command_buffer,
+            fence,
+            staging,
+            retired,
+        });
+        self.signaled.push(semaphore);
+        Ok(fence)
+    }
+
+    #[cfg(feature = "defrag")]
+    fn wait(&self, fence: vk::Fence) -> RendererResult<()> {
+        unsafe { self.device.wait_for_fences(&[fence], true, u64::MAX)? };
+        Ok(())
+    }
+
+    // Returns the bytes of staging and retired buffers freed
+    fn free_finished(&mut self, allocator: &mut Allocator, completed_frame: u64) -> u64 {
+        let mut freed = 0;
+        let device = &self.device;
+        let command_pool = self.command_pool;
+        self.pending.retain_mut(|upload| {
+            if !unsafe { device.get_fence_status(upload.fence) }.unwrap_or(false)
+                || upload
+                    .retired
+                    .as_ref()
+                    .map_or(false, |(_, frame)| *frame > completed_frame)
+            {
+                return true;
+            }
+            for buffer in upload
+                .staging
+                .iter_mut()
+                .chain(upload.retired.as_mut().map(|(buffer, _)| buffer))
+            {
+                freed += buffer.size;
+                buffer.destroy(allocator);
+            }
+            unsafe {
+                device.destroy_fence(upload.fence, None);
+                device.free_command_buffers(command_pool, &[upload.command_buffer]);
+            }
+            false
+        });
+        while let Some((_, frame)) = self.waited.front() {
+            if *frame > completed_frame {
+                break;
+            }
+            if let Some((semaphore, _)) = self.waited.pop_front() {
+                unsafe { device.destroy_semaphore(semaphore, None) };
+            }
+        }
+        freed
+    }
+
+    // Only safe once the device is idle
+    fn destroy(&mut self, allocator: &mut Allocator) -> u64 {
+        let freed = self.free_finished(allocator, u64::MAX);
+        let device = &self.device;
+        unsafe {
+            for semaphore in self.signaled.drain(..) {
+                device.destroy_semaphore(semaphore, None);
+            }
+            device.destroy_command_pool(self.command_pool, None);
+        }
+        freed
+    }
+}
+
 #[derive(Debug)]
 pub struct BufferManager {
     handle_array: HandleArray<InternalBuffer>,
-    to_free: Vec<(InternalBuffer, Option<u32>)>,
+    // Ordered by the frame after which each buffer can be freed
+    to_free: VecDeque<(InternalBuffer, u64)>,
+    last_submitted_frame: u64,
+    debug_namer: Option<DebugNamer>,
+    // nonCoherentAtomSize of the device, writes to mapped memory are flushed in multiples
+    // of it. None if all host visible memory of the device is host coherent, which needs
+    // no flushes.
+    non_coherent_atom_size: Option<u64>,
+    // Since the last take_byte_counts
+    bytes_allocated: u64,
+    bytes_freed: u64,
+    // Device local buffers fall back to host visible memory without it
+    transfer: Option<TransferContext>,
 }
 
 impl BufferManager {
+    pub fn allocation_hash(&self) -> u64 {
+        self.handle_array.allocation_hash()
+    }
+
     pub fn new() -> Arc<Mutex<BufferManager>> {
         Arc::new(Mutex::new(BufferManager {
             handle_array: HandleArray::new(),
-            to_free: vec![],
+            to_free: VecDeque::new(),
+            last_submitted_frame: 0,
+            debug_namer: None,
+            non_coherent_atom_size: None,
+            bytes_allocated: 0,
+            bytes_freed: 0,
+            transfer: None,
         }))
     }
 
+    // Buffers are named after the name passed to new_buffer from then on
+    pub(crate) fn set_debug_namer(&mut self, debug_namer: DebugNamer) {
+        self.debug_namer = Some(debug_namer);
+    }
+
+    pub(crate) fn set_non_coherent_atom_size(&mut self, size: Option<u64>) {
+        self.non_coherent_atom_size = size;
+    }
+
+    // Device local buffers are filled through the transfer queue from then on
+    pub(crate) fn set_transfer_queue(
+        &mut self,
+        device: &ash::Device,
+        transfer_queue: &Queue,
+        graphics_queue: &Queue,
+    ) -> RendererResult<()> {
+        let pool_info = vk::CommandPoolCreateInfo::builder()
+            .queue_family_index(transfer_queue.index)
+            .flags(vk::CommandPoolCreateFlags::TRANSIENT);
+        let command_pool = unsafe { device.create_command_pool(&pool_info, None)? };
+        if let Some(debug_namer) = &self.debug_namer {
+            debug_namer.name(command_pool, "transfer");
+        }
+        self.transfer = Some(TransferContext {
+            device: device.clone(),
+            queue: transfer_queue.queue,
+            command_pool,
+            sharing_families: (transfer_queue.index != graphics_queue.index)
+                .then_some([graphics_queue.index, transfer_queue.index]),
+            pending: vec![],
+            signaled: vec![],
+            waited: VecDeque::new(),
+        });
+        Ok(())
+    }
+
+    // Signaled by the uploads since the last call. The next graphics submission that may
+    // use the uploaded buffers has to wait on them, at VERTEX_INPUT for vertex and index
+    // data. They are destroyed once the frame after the last submitted one is done.
+    pub(crate) fn take_upload_semaphores(&mut self) -> Vec<vk::Semaphore> {
+        let last_submitted_frame = self.last_submitted_frame;
+        match self.transfer.as_mut() {
+            Some(transfer) => {
+                let semaphores = std::mem::take(&mut transfer.signaled);
+                transfer.waited.extend(
+                    semaphores
+                        .iter()
+                        .map(|semaphore| (*semaphore, last_submitted_frame + 1)),
+                );
+                semaphores
+            }
+            None => vec![],
+        }
+    }
+
+    // The bytes allocated and freed since the last call
+    pub(crate) fn take_byte_counts(&mut self) -> (u64, u64) {
+        (
+            std::mem::take(&mut self.bytes_allocated),
+            std::mem::take(&mut self.bytes_freed),
+        )
+    }
+
+    fn name_buffer(&self, int_buf: &InternalBuffer) {
+        if let Some(debug_namer) = &self.debug_namer {
+            debug_namer.name(int_buf.buffer, &int_buf.name);
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
     fn allocate_new_buffer(
         &mut self,
         device: &ash::Device,
@@ -177,10 +544,21 @@ impl BufferManager {
         size: u64,
         buffer_usage: vk::BufferUsageFlags,
         location: MemoryLocation,
+        sharing_families: Option<[u32; 2]>,
         name: &str,
     ) -> RendererResult<Handle<InternalBuffer>> {
-        let internal_buffer =
-            InternalBuffer::new(device, allocator, size, buffer_usage, location, name)?;
+        let internal_buffer = InternalBuffer::new(
+            device,
+            allocator,
+            size,
+            buffer_usage,
+            location,
+            sharing_families,
+            name,
+            self.last_submitted_frame + 1,
+        )?;
+        self.name_buffer(&internal_buffer);
+        self.bytes_allocated += size;
         Ok(self.handle_array.insert(internal_buffer))
     }
 
@@ -199,6 +577,7 @@ impl BufferManager {
             size,
             buffer_usage,
             location,
+            None,
             name,
         )?;
         let buffer = Buffer {
@@ -209,20 +588,81 @@ impl BufferManager {
         Ok(buffer)
     }
 
+    // A GpuOnly buffer holding data, copied in through the transfer queue. Falls back to
+    // a CpuToGpu buffer without one. Writing to it later goes through the transfer queue
+    // as well, see Buffer::fill, and so does moving it, see relocate.
+    pub fn new_device_local_buffer<T>(
+        manager: Arc<Mutex<BufferManager>>,
+        device: &ash::Device,
+        allocator: &mut Allocator,
+        data: &[T],
+        buffer_usage: vk::BufferUsageFlags,
+        name: &str,
+    ) -> RendererResult<Buffer> {
+        if data.is_empty() {
+            return Err(BufferAccessError(format!("{} would be an empty buffer", name)).into());
+        }
+        let mut locked = manager.lock().unwrap();
+        let (buffer_usage, location, sharing_families) = match &locked.transfer {
+            Some(transfer) => (
+                buffer_usage
+                    | vk::BufferUsageFlags::TRANSFER_SRC
+                    | vk::BufferUsageFlags::TRANSFER_DST,
+                MemoryLocation::GpuOnly,
+                transfer.sharing_families,
+            ),
+            None => (buffer_usage, MemoryLocation::CpuToGpu, None),
+        };
+        let handle = locked.allocate_new_buffer(
+            device,
+            allocator,
+            std::mem::size_of_val(data) as u64,
+            buffer_usage,
+            location,
+            sharing_families,
+            name,
+        )?;
+        locked.copy_to_offset_by_handle(handle, allocator, data, 0)?;
+        drop(locked);
+        Ok(Buffer {
+            manager,
+            handle,
+            active: true,
+        })
+    }
+
     pub fn get_buffer(&self, handle: Handle<InternalBuffer>) -> Option<BufferDetails> {
         self.handle_array.get(handle).map(|int_buf| int_buf.into())
     }
 
-    fn fill_buffer_by_handle<T>(
+    // Makes room for size bytes and returns whether that moved the handle to a new
+    // vk::Buffer. The old one is freed once every frame submitted so far is done.
+    fn ensure_capacity_by_handle(
         &mut self,
         handle: Handle<InternalBuffer>,
         allocator: &mut Allocator,
-        data: &[T],
-    ) -> RendererResult<()> {
-        self.handle_array
+        size: u64,
+    ) -> RendererResult<bool> {
+        let int_buf = self
+            .handle_array
             .get_mut(handle)
-            .ok_or_else(|| InvalidHandle.into())
-            .and_then(|int_buf| int_buf.fill(allocator, data))
+            .ok_or::<RendererError>(InvalidHandle.into())?;
+        match resize(size, int_buf.size, int_buf.location) {
+            Resize::Keep => return Ok(false),
+            Resize::Transfer => {
+                self.check_transfer_target(handle)?;
+                self.transfer_by_handle(handle, allocator, size, None)?;
+                return Ok(true);
+            }
+            Resize::Reallocate => {}
+        }
+        let old = int_buf.reallocate(allocator, size, self.last_submitted_frame + 1)?;
+        if let Some(debug_namer) = &self.debug_namer {
+            debug_namer.name(int_buf.buffer, &int_buf.name);
+        }
+        self.bytes_allocated += size;
+        self.to_free.push_back((old, self.last_submitted_frame));
+        Ok(true)
     }
 
     fn copy_to_offset_by_handle<T>(
@@ -232,32 +672,375 @@ impl BufferManager {
         data: &[T],
         offset: usize,
     ) -> RendererResult<()> {
-        self.handle_array
-            .get_mut(handle)
-            .ok_or_else(|| InvalidHandle.into())
-            .and_then(|int_buf| int_buf.copy_to_offset(allocator, data, offset))
+        let location = self
+            .handle_array
+            .get(handle)
+            .ok_or::<RendererError>(InvalidHandle.into())?
+            .location;
+        if location == MemoryLocation::GpuOnly {
+            return self.upload_by_handle(handle, allocator, data, offset);
+        }
+        let required = (std::mem::size_of_val(data) + offset) as u64;
+        self.ensure_capacity_by_handle(handle, allocator, required)?;
+        self.write_by_handle(handle, data, offset)
+    }
+
+    // Device local buffers are written on the transfer queue, which needs them to be
+    // created by new_device_local_buffer
+    fn check_transfer_target(&self, handle: Handle<InternalBuffer>) -> RendererResult<()> {
+        let int_buf = self
+            .handle_array
+            .get(handle)
+            .ok_or::<RendererError>(InvalidHandle.into())?;
+        let transfer = self.transfer.as_ref().ok_or_else(no_transfer_queue)?;
+        let usage = vk::BufferUsageFlags::TRANSFER_SRC | vk::BufferUsageFlags::TRANSFER_DST;
+        if !int_buf.buffer_usage.contains(usage)
+            || int_buf.sharing_families != transfer.sharing_families
+        {
+            return Err(TransferError(format!(
+                "{} can't be written on the transfer queue, device local buffers have to be \
+                 created with new_device_local_buffer",
+                int_buf.name
+            ))
+            .into());
+        }
+        Ok(())
     }
 
-    fn queue_free(
+    // Copies data into a device local buffer through a staging buffer, see
+    // transfer_by_handle
+    fn upload_by_handle<T>(
         &mut self,
         handle: Handle<InternalBuffer>,
-        last_frame_index: Option<u32>,
+        allocator: &mut Allocator,
+        data: &[T],
+        offset: usize,
     ) -> RendererResult<()> {
-        let int_buf = self.handle_array.remove(handle)?;
-        self.to_free.push((int_buf, last_frame_index));
+        let size = std::mem::size_of_val(data) as u64;
+        if size == 0 {
+            return Ok(());
+        }
+        self.check_transfer_target(handle)?;
+        let transfer = self.transfer.as_ref().ok_or_else(no_transfer_queue)?;
+        let mut staging = InternalBuffer::new(
+            &transfer.device,
+            allocator,
+            size,
+            vk::BufferUsageFlags::TRANSFER_SRC,
+            MemoryLocation::CpuToGpu,
+            None,
+            "staging",
+            self.last_submitted_frame + 1,
+        )?;
+        if let Err(e) = staging.write(data, 0, self.non_coherent_atom_size) {
+            staging.destroy(allocator);
+            return Err(e);
+        }
+        self.bytes_allocated += size;
+        let required = offset as u64 + size;
+        let region = vk::BufferCopy {
+            src_offset: 0,
+            dst_offset: offset as u64,
+            size,
+        };
+        self.transfer_by_handle(handle, allocator, required, Some((staging, vec![region])))?;
         Ok(())
     }
 
-    pub fn free_queued(&mut self, allocator: &mut Allocator, last_frame_index: u32) {
-        self.to_free.retain_mut(|(int_buf, i)| {
-            if i.is_none() || i.unwrap() == last_frame_index {
-                int_buf.destroy(allocator);
-                false
-            } else {
-                true
+    // Copies the regions of data into the buffer, src_offset is where a region starts in
+    // data and dst_offset where it goes. The regions must not overlap. A device local
+    // buffer gets all of them from one staging buffer in a single submission, see
+    // transfer_by_handle, a host visible one is written region by region.
+    fn copy_regions_by_handle(
+        &mut self,
+        handle: Handle<InternalBuffer>,
+        allocator: &mut Allocator,
+        data: &[u8],
+        regions: &[vk::BufferCopy],
+    ) -> RendererResult<()> {
+        let required = regions
+            .iter()
+            .map(|region| region.dst_offset + region.size)
+            .max()
+            .unwrap_or(0);
+        let region_data = |region: &vk::BufferCopy| {
+            data.get(region.src_offset as usize..(region.src_offset + region.size) as usize)
+                .ok_or_else(|| {
+                    RendererError::from(OutOfBoundsWrite {
+                        offset: region.src_offset as usize,
+                        len: region.size as usize,
+                        size: data.len() as u64,
+                    })
+                })
+        };
+        let location = self
+            .handle_array
+            .get(handle)
+            .ok_or::<RendererError>(InvalidHandle.into())?
+            .location;
+        if location != MemoryLocation::GpuOnly {
+            self.ensure_capacity_by_handle(handle, allocator, required)?;
+            for region in regions {
+                self.write_by_handle(handle, region_data(region)?, region.dst_offset as usize)?;
             }
-        });
+            return Ok(());
+        }
+        let size = regions.iter().map(|region| region.size).sum::<u64>();
+        if size == 0 {
+            return Ok(());
+        }
+        self.check_transfer_target(handle)?;
+        let transfer = self.transfer.as_ref().ok_or_else(no_transfer_queue)?;
+        let mut staging = InternalBuffer::new(
+            &transfer.device,
+            allocator,
+            size,
+            vk::BufferUsageFlags::TRANSFER_SRC,
+            MemoryLocation::CpuToGpu,
+            None,
+            "staging",
+            self.last_submitted_frame + 1,
+        )?;
+        // Packed one after the other
+        let mut staged = Vec::with_capacity(regions.len());
+        let mut staging_offset = 0;
+        for region in regions {
+            let written = region_data(region).and_then(|bytes| {
+                staging.write(bytes, staging_offset as usize, self.non_coherent_atom_size)
+            });
+            if let Err(e) = written {
+                staging.destroy(allocator);
+                return Err(e);
+            }
+            staged.push(vk::BufferCopy {
+                src_offset: staging_offset,
+                dst_offset: region.dst_offset,
+                size: region.size,
+            });
+            staging_offset += region.size;
+        }
+        self.bytes_allocated += size;
+        self.transfer_by_handle(handle, allocator, required, Some((staging, staged)))?;
+        Ok(())
+    }
+
+    // Writes a device local buffer on the transfer queue, see take_upload_semaphores.
+    // upload is a staging buffer and the regions copied from it, which must not overlap.
+    // Frames in flight may still read the buffer, so it is only written in place when it
+    // takes an upload that fits and no frame was submitted since it was created.
+    // Otherwise it moves to a new buffer of at least size bytes, which gets the old
+    // contents the upload doesn't overwrite. The old one is freed once the copy and the frames using it are done.
+    // The caller checks the buffer with check_transfer_target.
+    fn transfer_by_handle(
+        &mut self,
+        handle: Handle<InternalBuffer>,
+        allocator: &mut Allocator,
+        size: u64,
+        upload: Option<(InternalBuffer, Vec<vk::BufferCopy>)>,
+    ) -> RendererResult<vk::Fence> {
+        let last_submitted_frame = self.last_submitted_frame;
+        let int_buf = self
+            .handle_array
+            .get_mut(handle)
+            .ok_or::<RendererError>(InvalidHandle.into())?;
+        let in_place = upload.is_some()
+            && writes_in_place(
+                size,
+                int_buf.size,
+                int_buf.first_frame,
+                last_submitted_frame,
+            );
+        let mut copies = vec![];
+        let mut retired = None;
+        if !in_place {
+            let new_size = size.max(int_buf.size);
+            let old = match int_buf.reallocate(allocator, new_size, last_submitted_frame + 1) {
+                Ok(old) => old,
+                Err(e) => {
+                    if let Some((mut staging, _)) = upload {
+                        staging.destroy(allocator);
+                    }
+                    return Err(e);
+                }
+            };
+            if let Some(debug_namer) = &self.debug_namer {
+                debug_namer.name(int_buf.buffer, &int_buf.name);
+            }
+            self.bytes_allocated += new_size;
+            let mut overwritten = upload
+                .as_ref()
+                .map(|(_, regions)| {
+                    regions
+                        .iter()
+                        .map(|region| (region.dst_offset, region.dst_offset + region.size))
+                        .collect::<Vec<_>>()
+                })
+                .unwrap_or_default();
+            overwritten.sort_unstable();
+            let regions = kept_regions(old.size, &overwritten);
+            if !regions.is_empty() {
+                copies.push((old.buffer, int_buf.buffer, regions));
+            }
+            retired = Some((old, last_submitted_frame));
+        }
+        if let Some((staging, regions)) = &upload {
+            copies.push((staging.buffer, int_buf.buffer, regions.clone()));
+        }
+        self.transfer
+            .as_mut()
+            .ok_or_else(no_transfer_queue)?
+            .submit_copies(&copies, upload.map(|(staging, _)| staging), retired)
+    }
+
+    fn write_by_handle<T>(
+        &self,
+        handle: Handle<InternalBuffer>,
+        data: &[T],
+        offset: usize,
+    ) -> RendererResult<()> {
+        self.handle_array
+            .get(handle)
+            .ok_or::<RendererError>(InvalidHandle.into())?
+            .write(data, offset, self.non_coherent_atom_size)
+    }
+
+    fn read_by_handle<T: Copy>(
+        &self,
+        handle: Handle<InternalBuffer>,
+        count: usize,
+    ) -> RendererResult<Vec<T>> {
+        self.handle_array
+            .get(handle)
+            .ok_or::<RendererError>(InvalidHandle.into())?
+            .read(count)
+    }
+
+    // Moves a buffer into a new allocation, which the allocator puts into the first
+    // block with room for it. The handle stays the same, the old buffer is freed once the
+    // GPU is done with every frame submitted so far. Device local buffers are copied on
+    // the transfer queue, which this waits for. Returns the bytes moved.
+    #[cfg(feature = "defrag")]
+    pub(crate) fn relocate(
+        &mut self,
+        handle: Handle<InternalBuffer>,
+        allocator: &mut Allocator,
+    ) -> RendererResult<u64> {
+        let int_buf = self
+            .handle_array
+            .get_mut(handle)
+            .ok_or::<RendererError>(InvalidHandle.into())?;
+        let size = int_buf.size;
+        if int_buf.location == MemoryLocation::GpuOnly {
+            self.check_transfer_target(handle)?;
+            let fence = self.transfer_by_handle(handle, allocator, size, None)?;
+            self.transfer
+                .as_ref()
+                .ok_or_else(no_transfer_queue)?
+                .wait(fence)?;
+            return Ok(size);
+        }
+        if int_buf
+            .allocation
+            .as_ref()
+            .and_then(|a| a.mapped_ptr())
+            .is_none()
+        {
+            panic!("Only host visible and device local buffers can be relocated");
+        }
+        let old = int_buf.reallocate(allocator, size, self.last_submitted_frame + 1)?;
+        if let Some(debug_namer) = &self.debug_namer {
+            debug_namer.name(int_buf.buffer, &int_buf.name);
+        }
+        self.bytes_allocated += size;
+        self.to_free.push_back((old, self.last_submitted_frame));
+        Ok(size)
+    }
+
+    // Anything submitted so far might still use the buffer
+    fn queue_free(&mut self, handle: Handle<InternalBuffer>) -> RendererResult<()> {
+        let int_buf = self.handle_array.remove(handle)?;
+        self.to_free.push_back((int_buf, self.last_submitted_frame));
+        Ok(())
+    }
+
+    pub(crate) fn set_last_submitted_frame(&mut self, frame: u64) {
+        self.last_submitted_frame = frame;
+    }
+
+    // Only safe once the GPU is done with every submitted frame
+    pub fn free_all_queued(&mut self, allocator: &mut Allocator) {
+        for (mut int_buf, _) in self.to_free.drain(..) {
+            self.bytes_freed += int_buf.size;
+            int_buf.destroy(allocator);
+        }
+        if let Some(mut transfer) = self.transfer.take() {
+            self.bytes_freed += transfer.destroy(allocator);
+        }
+    }
+
+    // Frees every buffer whose last user is at or before the completed frame
+    pub fn free_queued(&mut self, allocator: &mut Allocator, completed_frame: u64) {
+        if let Some(transfer) = self.transfer.as_mut() {
+            self.bytes_freed += transfer.free_finished(allocator, completed_frame);
+        }
+        for mut int_buf in retired(&mut self.to_free, completed_frame) {
+            self.bytes_freed += int_buf.size;
+            int_buf.destroy(allocator);
+        }
+    }
+}
+
+// How a buffer makes room for size bytes
+#[derive(Debug, PartialEq, Eq)]
+enum Resize {
+    Keep,
+    // Host visible buffers move to a new allocation right away
+    Reallocate,
+    // Device local buffers move on the transfer queue
+    Transfer,
+}
+
+fn resize(size: u64, capacity: u64, location: MemoryLocation) -> Resize {
+    if size <= capacity {
+        Resize::Keep
+    } else if location == MemoryLocation::GpuOnly {
+        Resize::Transfer
+    } else {
+        Resize::Reallocate
+    }
+}
+
+// Whether size bytes can go into the current vk::Buffer, which frames submitted since it
+// was created might still read
+fn writes_in_place(size: u64, capacity: u64, first_frame: u64, last_submitted_frame: u64) -> bool {
+    size <= capacity && first_frame > last_submitted_frame
+}
+
+fn no_transfer_queue() -> RendererError {
+    TransferError(
+        "device local buffers are written on the transfer queue, which isn't set up yet"
+            .to_string(),
+    )
+    .into()
+}
+
+// The parts of the first old_size bytes of a buffer outside of the overwritten ranges,
+// which carry over when it moves. The ranges are sorted and don't overlap.
+fn kept_regions(old_size: u64, overwritten: &[(u64, u64)]) -> Vec<vk::BufferCopy> {
+    let mut kept = vec![];
+    let mut from = 0;
+    for (start, end) in overwritten.iter().chain([(old_size, old_size)].iter()) {
+        let to = (*start).min(old_size);
+        if to > from {
+            kept.push(vk::BufferCopy {
+                src_offset: from,
+                dst_offset: from,
+                size: to - from,
+            });
+        }
+        from = from.max((*end).min(old_size));
     }
+    kept
 }
 
 pub struct BufferDetails {
@@ -290,6 +1073,7 @@ impl Buffer {
         self.handle
     }
 
+    // Grows the buffer when the data doesn't fit, see ensure_capacity
     pub fn fill<T>(&mut self, allocator: &mut Allocator, data: &[T]) -> RendererResult<()> {
         if !self.active {
             panic!("Tried to fill inactive buffer!");
@@ -297,7 +1081,24 @@ impl Buffer {
         self.manager
             .lock()
             .unwrap()
-            .fill_buffer_by_handle(self.handle, allocator, data)
+            .copy_to_offset_by_handle(self.handle, allocator, data, 0)
+    }
+
+    // Grows the buffer to at least size bytes, it never shrinks. Returns true when that
+    // moved it to a new vk::Buffer, descriptor sets pointing at the old one have to be
+    // written again. Frames in flight keep using the old one until they are done.
+    pub fn ensure_capacity(
+        &mut self,
+        allocator: &mut Allocator,
+        size: u64,
+    ) -> RendererResult<bool> {
+        if !self.active {
+            panic!("Tried to grow inactive buffer!");
+        }
+        self.manager
+            .lock()
+            .unwrap()
+            .ensure_capacity_by_handle(self.handle, allocator, size)
     }
 
     pub fn copy_to_offset<T>(
@@ -315,6 +1116,48 @@ impl Buffer {
             .copy_to_offset_by_handle(self.handle, allocator, data, offset)
     }
 
+    // Copies several regions of data at once, see BufferManager::copy_regions_by_handle.
+    // Grows the buffer when they don't fit.
+    pub fn copy_regions(
+        &mut self,
+        allocator: &mut Allocator,
+        data: &[u8],
+        regions: &[vk::BufferCopy],
+    ) -> RendererResult<()> {
+        if !self.active {
+            panic!("Tried to copy to inactive buffer!");
+        }
+        self.manager
+            .lock()
+            .unwrap()
+            .copy_regions_by_handle(self.handle, allocator, data, regions)
+    }
+
+    // Writes through the persistent mapping without touching the allocator, for data
+    // that is known to fit, like per frame uniforms. Data that doesn't fit is an
+    // OutOfBoundsWrite and nothing is written, use copy_to_offset to grow the buffer
+    // instead.
+    pub fn write<T>(&mut self, data: &[T], offset: usize) -> RendererResult<()> {
+        if !self.active {
+            panic!("Tried to write to inactive buffer!");
+        }
+        self.manager
+            .lock()
+            .unwrap()
+            .write_by_handle(self.handle, data, offset)
+    }
+
+    // The first count elements, for buffers the GPU writes to and the CPU reads back
+    pub fn read<T: Copy>(&self, count: usize) -> RendererResult<Vec<T>> {
+        if !self.active {
+            panic!("Tried to read inactive buffer!");
+        }
+        self.manager
+            .lock()
+            .unwrap()
+            .read_by_handle(self.handle, count)
+    }
+
     pub fn is_active(&self) -> bool {
         self.active
     }
@@ -330,14 +1173,113 @@ impl Buffer {
             .unwrap()
     }
 
-    pub fn queue_free(&mut self, last_frame_index: Option<u32>) -> RendererResult<()> {
+    pub fn queue_free(&mut self) -> RendererResult<()> {
         if !self.active {
             panic!("Tried to free inactive buffer!");
         }
         self.active = false;
-        self.manager
-            .lock()
-            .unwrap()
-            .queue_free(self.handle, last_frame_index)
+        self.manager.lock().unwrap().queue_free(self.handle)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // (offset, size) of every region, which is copied to the same offset
+    fn offsets_and_sizes(regions: Vec<vk::BufferCopy>) -> Vec<(u64, u64)> {
+        regions
+            .iter()
+            .inspect(|region| assert_eq!(region.src_offset, region.dst_offset))
+            .map(|region| (region.src_offset, region.size))
+            .collect()
+    }
+
+    #[test]
+    fn moving_keeps_everything() {
+        assert_eq!(offsets_and_sizes(kept_regions(64, &[])), vec![(0, 64)]);
+    }
+
+    #[test]
+    fn upload_in_the_middle_keeps_both_sides() {
+        assert_eq!(
+            offsets_and_sizes(kept_regions(64, &[(16, 32)])),
+            vec![(0, 16), (32, 32)]
+        );
+    }
+
+    #[test]
+    fn upload_past_the_end_keeps_the_start() {
+        assert_eq!(
+            offsets_and_sizes(kept_regions(64, &[(32, 96)])),
+            vec![(0, 32)]
+        );
+        assert_eq!(
+            offsets_and_sizes(kept_regions(64, &[(64, 96)])),
+            vec![(0, 64)]
+        );
+    }
+
+    #[test]
+    fn several_uploads_keep_the_gaps() {
+        assert_eq!(
+            offsets_and_sizes(kept_regions(64, &[(8, 16), (16, 24), (40, 48)])),
+            vec![(0, 8), (24, 16), (48, 16)]
+        );
+        assert_eq!(
+            offsets_and_sizes(kept_regions(64, &[(0, 8), (56, 96)])),
+            vec![(8, 48)]
+        );
+    }
+
+    #[test]
+    fn upload_over_everything_keeps_nothing() {
+        assert!(kept_regions(64, &[(0, 64)]).is_empty());
+        assert!(kept_regions(64, &[(0, 128)]).is_empty());
+    }
+
+    #[test]
+    fn shrinking_keeps_the_buffer() {
+        for location in [MemoryLocation::CpuToGpu, MemoryLocation::GpuOnly] {
+            assert_eq!(resize(32, 64, location), Resize::Keep);
+            assert_eq!(resize(64, 64, location), Resize::Keep);
+        }
+    }
+
+    #[test]
+    fn growing_moves_the_buffer() {
+        assert_eq!(resize(65, 64, MemoryLocation::CpuToGpu), Resize::Reallocate);
+        assert_eq!(resize(65, 64, MemoryLocation::GpuToCpu), Resize::Reallocate);
+        assert_eq!(resize(65, 64, MemoryLocation::GpuOnly), Resize::Transfer);
+    }
+
+    #[test]
+    fn growing_in_flight_frees_the_old_buffer_once_its_frames_are_done() {
+        // Created for frame 3, frames 3 to 5 were submitted since
+        assert!(writes_in_place(64, 64, 3, 2));
+        assert!(!writes_in_place(64, 64, 3, 5));
+        assert_eq!(resize(128, 64, MemoryLocation::GpuOnly), Resize::Transfer);
+        // The old buffer is queued for the last submitted frame, the new one is used from
+        // the next
+        let mut queue = VecDeque::from([("older", 4), ("old", 5)]);
+        assert!(writes_in_place(128, 128, 6, 5));
+        assert_eq!(retired(&mut queue, 3), Vec::<&str>::new());
+        assert_eq!(retired(&mut queue, 4), vec!["older"]);
+        assert_eq!(queue.len(), 1);
+        assert_eq!(retired(&mut queue, 6), vec!["old"]);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn flush_covers_whole_atoms() {
+        assert_eq!(flush_range(256, 1024, 10, 20, 64), (256, 64));
+        assert_eq!(flush_range(256, 1024, 60, 8, 64), (256, 128));
+        assert_eq!(flush_range(0, 1024, 0, 1024, 64), (0, 1024));
+    }
+
+    #[test]
+    fn flush_past_the_allocation_goes_to_the_end_of_the_memory() {
+        assert_eq!(flush_range(0, 100, 90, 10, 64), (64, vk::WHOLE_SIZE));
+        assert_eq!(flush_range(32, 40, 0, 40, 64), (0, vk::WHOLE_SIZE));
     }
 }
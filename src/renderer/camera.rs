@@ -1,8 +1,16 @@
-use gpu_allocator::vulkan::Allocator;
 use nalgebra as na;
 use nalgebra_glm as glm;
 
-use super::{buffer::Buffer, RendererResult};
+use super::{buffer::Buffer, culling::Frustum, scene::ALL_LAYERS, RendererResult};
+
+#[cfg(feature = "winit")]
+pub mod controller;
+
+#[cfg(feature = "winit")]
+pub use controller::{CameraController, FlyBindings, FlyController, OrbitController};
+
+// view, projection and the previous frame's view-projection
+pub(crate) type CameraUniformData = [[[f32; 4]; 4]; 3];
 
 pub struct CameraBuilder {
     position: glm::Vec3,
@@ -12,6 +20,7 @@ pub struct CameraBuilder {
     aspect: f32,
     near: f32,
     far: f32,
+    layer_mask: u32,
 }
 
 impl CameraBuilder {
@@ -50,6 +59,12 @@ impl CameraBuilder {
         self
     }
 
+    // Only objects sharing a bit with this are drawn through the camera
+    pub fn layer_mask(mut self, mask: u32) -> CameraBuilder {
+        self.layer_mask = mask;
+        self
+    }
+
     pub fn build(self) -> Camera {
         if self.far < self.near {
             // TODO return error
@@ -73,6 +88,7 @@ impl CameraBuilder {
             aspect: self.aspect,
             near: self.near,
             far: self.far,
+            layer_mask: self.layer_mask,
             view_matrix: glm::Mat4::identity(),
             projection_matrix: glm::Mat4::identity(),
         };
@@ -91,6 +107,7 @@ pub struct Camera {
     aspect: f32,
     near: f32,
     far: f32,
+    layer_mask: u32,
     projection_matrix: glm::Mat4,
 }
 
@@ -104,6 +121,7 @@ impl Camera {
             aspect: 800.0 / 600.0,
             near: 0.1,
             far: 100.0,
+            layer_mask: ALL_LAYERS,
         }
     }
 
@@ -200,19 +218,76 @@ impl Camera {
         self.turn_up(-angle);
     }
 
+    pub fn get_position(&self) -> glm::Vec3 {
+        self.position
+    }
+
+    pub fn set_position(&mut self, position: glm::Vec3) {
+        self.position = position;
+        self.update_view_matrix();
+    }
+
+    pub fn get_layer_mask(&self) -> u32 {
+        self.layer_mask
+    }
+
+    pub fn set_layer_mask(&mut self, mask: u32) {
+        self.layer_mask = mask;
+    }
+
+    pub fn get_view_direction(&self) -> glm::Vec3 {
+        self.view_direction.into_inner()
+    }
+
+    // Looks at target with down as close to the given direction as possible. A down
+    // direction along the view direction keeps the current one.
+    pub fn look_at(&mut self, target: &glm::Vec3, down: &glm::Vec3) {
+        let view = target - self.position;
+        if view.norm() <= f32::EPSILON {
+            return;
+        }
+        let view = na::Unit::new_normalize(view);
+        let orthogonal = |d: &glm::Vec3| d - d.dot(&view) * view.as_ref();
+        let mut new_down = orthogonal(down);
+        if new_down.norm() <= 1e-4 {
+            new_down = orthogonal(self.down_direction.as_ref());
+        }
+        if new_down.norm() <= 1e-4 {
+            return;
+        }
+        self.view_direction = view;
+        self.down_direction = na::Unit::new_normalize(new_down);
+        self.update_view_matrix();
+    }
+
     pub fn set_aspect(&mut self, ratio: f32) {
         self.aspect = ratio;
         self.update_projection_matrix();
     }
 
+    pub fn view_projection_matrix(&self) -> glm::Mat4 {
+        self.projection_matrix * self.view_matrix
+    }
+
+    // Normalized world space planes with the normals pointing inside, in the order left,
+    // right, top, bottom, near, far. A point p is inside a plane when
+    // dot(plane.xyz, p) + plane.w >= 0.
+    pub fn frustum_planes(&self) -> [glm::Vec4; 6] {
+        Frustum::from_view_projection(&self.view_projection_matrix()).planes()
+    }
+
+    // The buffer has to have room for the data at offset
     pub(crate) fn update_buffer(
         &self,
-        allocator: &mut Allocator,
         buffer: &mut Buffer,
         offset: usize,
+        previous_view_projection: &glm::Mat4,
     ) -> RendererResult<()> {
-        let data_array: [[[f32; 4]; 4]; 2] =
-            [self.view_matrix.into(), self.projection_matrix.into()];
-        buffer.copy_to_offset(allocator, &data_array, offset)
+        let data_array: CameraUniformData = [
+            self.view_matrix.into(),
+            self.projection_matrix.into(),
+            (*previous_view_projection).into(),
+        ];
+        buffer.write(&data_array, offset)
     }
 }
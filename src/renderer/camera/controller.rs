@@ -0,0 +1,515 @@
+use std::collections::HashSet;
+use std::f32::consts::FRAC_PI_2;
+use std::ops::{Add, Mul, Sub};
+
+use nalgebra_glm as glm;
+use winit::event::{
+    DeviceEvent, ElementState, Event, KeyboardInput, MouseButton, MouseScrollDelta, VirtualKeyCode,
+    WindowEvent,
+};
+
+use super::Camera;
+
+// Pixel scroll deltas (touchpads) are turned into lines with this
+const PIXELS_PER_LINE: f32 = 20.0;
+
+// The orbit pitch stays this far from straight up or down
+const MAX_PITCH: f32 = FRAC_PI_2 - 0.01;
+
+// Turns input into camera movement, feed it every event and update the camera once per
+// frame with the time since the last update in seconds
+pub trait CameraController {
+    fn handle_event(&mut self, event: &Event<()>);
+    fn update(&mut self, camera: &mut Camera, dt: f32);
+}
+
+// How much of the distance to a goal is left after dt seconds, when a value follows it
+// with the time constant smoothing. A smoothing of 0 reaches the goal immediately.
+pub fn smoothing_decay(smoothing: f32, dt: f32) -> f32 {
+    if smoothing <= 0.0 {
+        0.0
+    } else {
+        (-dt.max(0.0) / smoothing).exp()
+    }
+}
+
+// Moves a velocity exponentially towards target and returns it together with the distance
+// covered during dt. Integrated exactly, so the result doesn't depend on how a period of
+// time is split into frames.
+pub fn damp_velocity<T>(velocity: T, target: T, smoothing: f32, dt: f32) -> (T, T)
+where
+    T: Copy + Add<Output = T> + Sub<Output = T> + Mul<f32, Output = T>,
+{
+    let dt = dt.max(0.0);
+    if smoothing <= 0.0 {
+        return (target, target * dt);
+    }
+    let decay = smoothing_decay(smoothing, dt);
+    let new_velocity = target + (velocity - target) * decay;
+    let distance = target * dt + (velocity - target) * (smoothing * (1.0 - decay));
+    (new_velocity, distance)
+}
+
+fn scroll_lines(delta: &MouseScrollDelta) -> f32 {
+    match delta {
+        MouseScrollDelta::LineDelta(_, y) => *y,
+        MouseScrollDelta::PixelDelta(position) => position.y as f32 / PIXELS_PER_LINE,
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FlyBindings {
+    pub forward: VirtualKeyCode,
+    pub backward: VirtualKeyCode,
+    pub left: VirtualKeyCode,
+    pub right: VirtualKeyCode,
+    pub up: VirtualKeyCode,
+    pub down: VirtualKeyCode,
+    pub turn_left: VirtualKeyCode,
+    pub turn_right: VirtualKeyCode,
+    pub turn_up: VirtualKeyCode,
+    pub turn_down: VirtualKeyCode,
+    pub fast: VirtualKeyCode,
+    pub slow: VirtualKeyCode,
+}
+
+impl Default for FlyBindings {
+    fn default() -> Self {
+        Self {
+            forward: VirtualKeyCode::W,
+            backward: VirtualKeyCode::S,
+            left: VirtualKeyCode::A,
+            right: VirtualKeyCode::D,
+            up: VirtualKeyCode::Space,
+            down: VirtualKeyCode::C,
+            turn_left: VirtualKeyCode::Q,
+            turn_right: VirtualKeyCode::E,
+            turn_up: VirtualKeyCode::R,
+            turn_down: VirtualKeyCode::F,
+            fast: VirtualKeyCode::LShift,
+            slow: VirtualKeyCode::LControl,
+        }
+    }
+}
+
+// Free flying camera, moved with keys and turned with keys or the mouse while mouse look
+// is enabled. The scroll wheel changes the speed.
+#[derive(Clone, Debug)]
+pub struct FlyController {
+    pub bindings: FlyBindings,
+    // Units per second
+    pub speed: f32,
+    pub min_speed: f32,
+    pub max_speed: f32,
+    // Radians per second, for the turn keys
+    pub turn_speed: f32,
+    // Radians per pixel
+    pub mouse_sensitivity: f32,
+    pub fast_multiplier: f32,
+    pub slow_multiplier: f32,
+    // The speed is multiplied by this for every line scrolled up
+    pub scroll_step: f32,
+    // Time constant of the velocity in seconds
+    pub smoothing: f32,
+    mouse_look: bool,
+    pressed: HashSet<VirtualKeyCode>,
+    mouse_delta: glm::Vec2,
+    // Camera space, x is right, y is up and z is forward
+    velocity: glm::Vec3,
+    // Right and up, in radians per second
+    turn_velocity: glm::Vec2,
+}
+
+impl Default for FlyController {
+    fn default() -> Self {
+        Self {
+            bindings: FlyBindings::default(),
+            speed: 3.0,
+            min_speed: 0.1,
+            max_speed: 100.0,
+            turn_speed: 0.6,
+            mouse_sensitivity: 0.002,
+            fast_multiplier: 2.0,
+            slow_multiplier: 0.25,
+            scroll_step: 1.1,
+            smoothing: 0.1,
+            mouse_look: false,
+            pressed: HashSet::new(),
+            mouse_delta: glm::Vec2::zeros(),
+            velocity: glm::Vec3::zeros(),
+            turn_velocity: glm::Vec2::zeros(),
+        }
+    }
+}
+
+impl FlyController {
+    pub fn set_mouse_look(&mut self, enabled: bool) {
+        self.mouse_look = enabled;
+        self.mouse_delta = glm::Vec2::zeros();
+    }
+
+    pub fn is_mouse_look(&self) -> bool {
+        self.mouse_look
+    }
+
+    pub fn velocity(&self) -> glm::Vec3 {
+        self.velocity
+    }
+
+    fn axis(&self, positive: VirtualKeyCode, negative: VirtualKeyCode) -> f32 {
+        let value = |key: VirtualKeyCode| {
+            if self.pressed.contains(&key) {
+                1.0
+            } else {
+                0.0
+            }
+        };
+        value(positive) - value(negative)
+    }
+
+    // Camera space direction of the pressed movement keys, diagonals are not faster
+    pub fn input_direction(&self) -> glm::Vec3 {
+        let b = &self.bindings;
+        let direction = glm::vec3(
+            self.axis(b.right, b.left),
+            self.axis(b.up, b.down),
+            self.axis(b.forward, b.backward),
+        );
+        if direction.norm() > 0.0 {
+            direction.normalize()
+        } else {
+            direction
+        }
+    }
+
+    pub fn speed_multiplier(&self) -> f32 {
+        let mut multiplier = 1.0;
+        if self.pressed.contains(&self.bindings.fast) {
+            multiplier *= self.fast_multiplier;
+        }
+        if self.pressed.contains(&self.bindings.slow) {
+            multiplier *= self.slow_multiplier;
+        }
+        multiplier
+    }
+}
+
+impl CameraController for FlyController {
+    fn handle_event(&mut self, event: &Event<()>) {
+        match event {
+            Event::WindowEvent { event, .. } => match event {
+                WindowEvent::KeyboardInput {
+                    input:
+                        KeyboardInput {
+                            state,
+                            virtual_keycode: Some(key),
+                            ..
+                        },
+                    ..
+                } => match state {
+                    ElementState::Pressed => {
+                        self.pressed.insert(*key);
+                    }
+                    ElementState::Released => {
+                        self.pressed.remove(key);
+                    }
+                },
+                WindowEvent::MouseWheel { delta, .. } => {
+                    let speed = self.speed * self.scroll_step.powf(scroll_lines(delta));
+                    self.speed = speed.clamp(self.min_speed, self.max_speed);
+                }
+                // Releases are not reported while unfocused, so keys would get stuck
+                WindowEvent::Focused(false) => self.pressed.clear(),
+                _ => {}
+            },
+            Event::DeviceEvent {
+                event: DeviceEvent::MouseMotion { delta },
+                ..
+            } if self.mouse_look => {
+                self.mouse_delta += glm::vec2(delta.0 as f32, delta.1 as f32);
+            }
+            _ => {}
+        }
+    }
+
+    fn update(&mut self, camera: &mut Camera, dt: f32) {
+        let target = self.input_direction() * self.speed * self.speed_multiplier();
+        let (velocity, distance) = damp_velocity(self.velocity, target, self.smoothing, dt);
+        self.velocity = velocity;
+
+        let b = &self.bindings;
+        let turn_target = glm::vec2(
+            self.axis(b.turn_right, b.turn_left),
+            self.axis(b.turn_up, b.turn_down),
+        ) * self.turn_speed;
+        let (turn_velocity, turn) =
+            damp_velocity(self.turn_velocity, turn_target, self.smoothing, dt);
+        self.turn_velocity = turn_velocity;
+
+        // Mouse motion is a distance already, so it is not scaled by dt
+        let mouse_turn = self.mouse_delta * self.mouse_sensitivity;
+        self.mouse_delta = glm::Vec2::zeros();
+
+        camera.turn_right(turn.x + mouse_turn.x);
+        camera.turn_up(turn.y - mouse_turn.y);
+        camera.move_right(distance.x);
+        camera.move_up(distance.y);
+        camera.move_forward(distance.z);
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct OrbitPose {
+    target: glm::Vec3,
+    distance: f32,
+    yaw: f32,
+    pitch: f32,
+}
+
+// Circles around a target: the left mouse button orbits, the right or middle one pans and
+// the scroll wheel zooms. The public pose is the goal the camera smoothly follows.
+#[derive(Clone, Debug)]
+pub struct OrbitController {
+    pub target: glm::Vec3,
+    pub distance: f32,
+    // Around the down axis, and towards up from the plane orthogonal to it, in radians
+    pub yaw: f32,
+    pub pitch: f32,
+    // The world's down direction, the camera stays upright relative to it
+    pub down: glm::Vec3,
+    // Radians per pixel
+    pub orbit_sensitivity: f32,
+    // Fraction of the distance per pixel
+    pub pan_sensitivity: f32,
+    // The distance is divided by this for every line scrolled up
+    pub zoom_step: f32,
+    pub min_distance: f32,
+    pub max_distance: f32,
+    // Time constant in seconds
+    pub smoothing: f32,
+    current: OrbitPose,
+    orbiting: bool,
+    panning: bool,
+    // Camera right and down of the current pose, for panning
+    right: glm::Vec3,
+    screen_down: glm::Vec3,
+}
+
+impl Default for OrbitController {
+    fn default() -> Self {
+        Self::new(glm::Vec3::zeros(), 10.0)
+    }
+}
+
+impl OrbitController {
+    pub fn new(target: glm::Vec3, distance: f32) -> Self {
+        let pose = OrbitPose {
+            target,
+            distance,
+            yaw: 0.0,
+            pitch: 0.3,
+        };
+        Self {
+            target,
+            distance,
+            yaw: pose.yaw,
+            pitch: pose.pitch,
+            down: glm::vec3(0.0, 1.0, 0.0),
+            orbit_sensitivity: 0.005,
+            pan_sensitivity: 0.002,
+            zoom_step: 1.1,
+            min_distance: 0.1,
+            max_distance: 1000.0,
+            smoothing: 0.08,
+            current: pose,
+            orbiting: false,
+            panning: false,
+            right: glm::vec3(1.0, 0.0, 0.0),
+            screen_down: glm::vec3(0.0, 1.0, 0.0),
+        }
+    }
+
+    // Jumps to the goal without smoothing, e.g. after changing it from code
+    pub fn snap(&mut self) {
+        self.current = self.goal();
+    }
+
+    // Takes over the pose of a camera that was moved by something else, like a
+    // FlyController, so switching to orbiting doesn't move the eye. The new target is the
+    // point distance ahead of the camera.
+    pub fn align_to_camera(&mut self, camera: &Camera, distance: f32) {
+        let down = self.down.normalize();
+        let view = camera.get_view_direction().normalize();
+        let offset = -view;
+        let pitch = (-offset.dot(&down)).clamp(-1.0, 1.0).asin();
+        let (first, second) = Self::horizontal_basis(&down);
+        let horizontal = offset + pitch.sin() * down;
+        self.yaw = horizontal.dot(&second).atan2(horizontal.dot(&first));
+        self.pitch = pitch.clamp(-MAX_PITCH, MAX_PITCH);
+        self.distance = distance.clamp(self.min_distance, self.max_distance);
+        self.target = camera.get_position() + view * self.distance;
+        self.snap();
+    }
+
+    // In pixels of mouse motion, scaled by orbit_sensitivity. The pitch stops short of
+    // the poles, where the view would flip.
+    pub fn rotate_around_target(&mut self, dx: f32, dy: f32) {
+        self.yaw += dx * self.orbit_sensitivity;
+        self.pitch = (self.pitch + dy * self.orbit_sensitivity).clamp(-MAX_PITCH, MAX_PITCH);
+    }
+
+    // In lines scrolled, positive zooms in
+    pub fn zoom(&mut self, lines: f32) {
+        let distance = self.distance / self.zoom_step.powf(lines);
+        self.distance = distance.clamp(self.min_distance, self.max_distance);
+    }
+
+    // Moves the target in the view plane, in pixels of mouse motion scaled by
+    // pan_sensitivity. The scene follows the mouse.
+    pub fn pan(&mut self, dx: f32, dy: f32) {
+        let scale = self.pan_sensitivity * self.distance;
+        self.target -= (self.right * dx + self.screen_down * dy) * scale;
+    }
+
+    fn goal(&self) -> OrbitPose {
+        OrbitPose {
+            target: self.target,
+            distance: self.distance,
+            yaw: self.yaw,
+            pitch: self.pitch,
+        }
+    }
+
+    // Two directions orthogonal to the down axis and to each other, yaw 0 is the first
+    fn horizontal_basis(down: &glm::Vec3) -> (glm::Vec3, glm::Vec3) {
+        let reference = if down.x.abs() < 0.9 {
+            glm::vec3(1.0, 0.0, 0.0)
+        } else {
+            glm::vec3(0.0, 0.0, 1.0)
+        };
+        let first = (reference - reference.dot(down) * down).normalize();
+        (first, down.cross(&first))
+    }
+
+    // From the target to the camera
+    pub fn offset_direction(down: &glm::Vec3, yaw: f32, pitch: f32) -> glm::Vec3 {
+        let down = down.normalize();
+        let (first, second) = Self::horizontal_basis(&down);
+        let horizontal = yaw.cos() * first + yaw.sin() * second;
+        pitch.cos() * horizontal - pitch.sin() * down
+    }
+}
+
+impl CameraController for OrbitController {
+    fn handle_event(&mut self, event: &Event<()>) {
+        match event {
+            Event::WindowEvent { event, .. } => match event {
+                WindowEvent::MouseInput { state, button, .. } => {
+                    let pressed = *state == ElementState::Pressed;
+                    match button {
+                        MouseButton::Left => self.orbiting = pressed,
+                        MouseButton::Right | MouseButton::Middle => self.panning = pressed,
+                        _ => {}
+                    }
+                }
+                WindowEvent::MouseWheel { delta, .. } => self.zoom(scroll_lines(delta)),
+                WindowEvent::Focused(false) => {
+                    self.orbiting = false;
+                    self.panning = false;
+                }
+                _ => {}
+            },
+            Event::DeviceEvent {
+                event: DeviceEvent::MouseMotion { delta },
+                ..
+            } => {
+                if self.orbiting {
+                    self.rotate_around_target(delta.0 as f32, delta.1 as f32);
+                } else if self.panning {
+                    self.pan(delta.0 as f32, delta.1 as f32);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn update(&mut self, camera: &mut Camera, dt: f32) {
+        let decay = smoothing_decay(self.smoothing, dt);
+        let goal = self.goal();
+        let current = &mut self.current;
+        current.target = goal.target + (current.target - goal.target) * decay;
+        current.distance = goal.distance + (current.distance - goal.distance) * decay;
+        current.yaw = goal.yaw + (current.yaw - goal.yaw) * decay;
+        current.pitch = goal.pitch + (current.pitch - goal.pitch) * decay;
+
+        let offset = Self::offset_direction(&self.down, current.yaw, current.pitch);
+        camera.set_position(current.target + offset * current.distance);
+        camera.look_at(&current.target, &self.down);
+
+        let view = -offset;
+        self.right = self.down.cross(&view).normalize();
+        self.screen_down = view.cross(&self.right).normalize();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The velocity and distance after stepping through one second in frames of dt
+    fn simulate(frames: usize, smoothing: f32) -> (f32, f32) {
+        let dt = 1.0 / frames as f32;
+        let (mut velocity, mut distance) = (0.0f32, 0.0f32);
+        for _ in 0..frames {
+            let (new_velocity, step) = damp_velocity(velocity, 5.0, smoothing, dt);
+            velocity = new_velocity;
+            distance += step;
+        }
+        (velocity, distance)
+    }
+
+    #[test]
+    fn damping_is_frame_rate_independent() {
+        let (velocity_30, distance_30) = simulate(30, 0.2);
+        let (velocity_144, distance_144) = simulate(144, 0.2);
+        let (velocity_1, distance_1) = simulate(1, 0.2);
+        assert!((velocity_30 - velocity_144).abs() < 1e-4);
+        assert!((distance_30 - distance_144).abs() < 1e-3);
+        assert!((velocity_30 - velocity_1).abs() < 1e-4);
+        assert!((distance_30 - distance_1).abs() < 1e-3);
+        // 5 * (1 - e^-5) and 5 * (1 - 0.2 * (1 - e^-5))
+        assert!((velocity_1 - 4.96631).abs() < 1e-4);
+        assert!((distance_1 - 4.00674).abs() < 1e-4);
+    }
+
+    #[test]
+    fn zero_smoothing_reaches_the_target_immediately() {
+        assert_eq!(smoothing_decay(0.0, 0.016), 0.0);
+        assert_eq!(damp_velocity(1.0f32, 3.0, 0.0, 0.5), (3.0, 1.5));
+        // Negative time steps don't move anything backwards
+        assert_eq!(smoothing_decay(0.1, -1.0), 1.0);
+        assert_eq!(damp_velocity(1.0f32, 3.0, 0.1, -1.0), (1.0, 0.0));
+    }
+
+    #[test]
+    fn orbit_limits() {
+        let mut orbit = OrbitController::new(glm::Vec3::zeros(), 10.0);
+        orbit.rotate_around_target(0.0, 1e6);
+        assert_eq!(orbit.pitch, MAX_PITCH);
+        orbit.zoom(1e3);
+        assert_eq!(orbit.distance, orbit.min_distance);
+        orbit.zoom(-1e3);
+        assert_eq!(orbit.distance, orbit.max_distance);
+    }
+
+    #[test]
+    fn orbit_offset_is_a_unit_vector_above_the_target() {
+        let down = glm::vec3(0.0, 1.0, 0.0);
+        for (yaw, pitch) in [(0.0, 0.3), (2.0, -1.0), (-3.0, 1.5)] {
+            let offset = OrbitController::offset_direction(&down, yaw, pitch);
+            assert!((offset.norm() - 1.0).abs() < 1e-5);
+            // Positive pitch is against the down axis
+            assert!((offset.dot(&down) + pitch.sin()).abs() < 1e-5);
+        }
+    }
+}
@@ -0,0 +1,183 @@
+use ash::vk;
+
+use super::color::OutputEncoding;
+use super::config::{OutputColorSpace, RendererConfig, TransparencyTechnique};
+
+// The format everything is rendered in before the output pass encodes it for the
+// swapchain. Floats keep values above 1 for HDR swapchains. The scene is always rendered
+// in it, before the upscale pass tone maps it.
+pub const HDR_WORKING_FORMAT: vk::Format = vk::Format::R16G16B16A16_SFLOAT;
+
+// What the surface supports and what the renderer picked from it
+#[derive(Clone, Debug)]
+pub struct RendererCapabilities {
+    // Every format and color space pair the surface offers
+    pub surface_formats: Vec<vk::SurfaceFormatKHR>,
+    // Without VK_EXT_swapchain_colorspace surfaces only offer sRGB
+    pub swapchain_colorspace_extension: bool,
+    pub color_space: OutputColorSpace,
+    pub surface_format: vk::SurfaceFormatKHR,
+    // The format of the render pass the scene, text and UI are drawn with
+    pub working_format: vk::Format,
+    // None when the swapchain is rendered to directly
+    pub output_encoding: Option<OutputEncoding>,
+    // The samples of the working format attachments, TYPE_1 without multisampling
+    pub msaa_samples: vk::SampleCountFlags,
+    pub transparency: TransparencyTechnique,
+}
+
+impl RendererCapabilities {
+    pub(crate) fn new(
+        surface_formats: Vec<vk::SurfaceFormatKHR>,
+        swapchain_colorspace_extension: bool,
+        limits: &vk::PhysicalDeviceLimits,
+        independent_blend: bool,
+        config: &RendererConfig,
+    ) -> Option<Self> {
+        let (surface_format, color_space) =
+            choose_surface_format(&surface_formats, config.preferred_color_space)?;
+        let (working_format, output_encoding) = if is_srgb_format(surface_format.format) {
+            (surface_format.format, None)
+        } else if color_space.is_hdr() {
+            (
+                HDR_WORKING_FORMAT,
+                Some(output_encoding(color_space, config)),
+            )
+        } else {
+            // A UNORM swapchain with sRGB content, 8 bits are enough
+            (vk::Format::B8G8R8A8_SRGB, Some(OutputEncoding::Srgb))
+        };
+        let msaa_samples = choose_sample_count(
+            config.msaa_samples,
+            limits.framebuffer_color_sample_counts & limits.framebuffer_depth_sample_counts,
+        );
+        // The accumulation and revealage targets blend differently
+        let transparency = match config.transparency_mode {
+            TransparencyTechnique::WeightedBlended if !independent_blend => {
+                TransparencyTechnique::SortedAlpha
+            }
+            transparency => transparency,
+        };
+        Some(Self {
+            surface_formats,
+            swapchain_colorspace_extension,
+            color_space,
+            surface_format,
+            working_format,
+            output_encoding,
+            msaa_samples,
+            transparency,
+        })
+    }
+
+    // Whether the surface offers a color space at all, with a format the renderer can use
+    pub fn supports(&self, color_space: OutputColorSpace) -> bool {
+        self.surface_formats
+            .iter()
+            .any(|format| surface_format_color_space(format) == Some(color_space))
+    }
+}
+
+pub(crate) fn output_encoding(
+    color_space: OutputColorSpace,
+    config: &RendererConfig,
+) -> OutputEncoding {
+    let paper_white_nits = config.hdr_paper_white_nits.max(1.0);
+    let peak_nits = config.hdr_peak_nits.max(paper_white_nits);
+    match color_space {
+        OutputColorSpace::SrgbNonlinear => OutputEncoding::Srgb,
+        OutputColorSpace::HdrSt2084 => OutputEncoding::Pq {
+            paper_white_nits,
+            peak_nits,
+        },
+        OutputColorSpace::ExtendedSrgbLinear => OutputEncoding::ScRgb {
+            paper_white_nits,
+            peak_nits,
+        },
+    }
+}
+
+// The highest supported sample count that isn't above the requested one
+pub(crate) fn choose_sample_count(
+    requested: u32,
+    supported: vk::SampleCountFlags,
+) -> vk::SampleCountFlags {
+    [
+        vk::SampleCountFlags::TYPE_64,
+        vk::SampleCountFlags::TYPE_32,
+        vk::SampleCountFlags::TYPE_16,
+        vk::SampleCountFlags::TYPE_8,
+        vk::SampleCountFlags::TYPE_4,
+        vk::SampleCountFlags::TYPE_2,
+    ]
+    .into_iter()
+    .find(|samples| samples.as_raw() <= requested && supported.contains(*samples))
+    .unwrap_or(vk::SampleCountFlags::TYPE_1)
+}
+
+fn is_srgb_format(format: vk::Format) -> bool {
+    matches!(
+        format,
+        vk::Format::B8G8R8A8_SRGB | vk::Format::R8G8B8A8_SRGB
+    )
+}
+
+// The color space a surface format is rendered in, None for pairs the renderer can't
+// encode for
+fn surface_format_color_space(format: &vk::SurfaceFormatKHR) -> Option<OutputColorSpace> {
+    match (format.color_space, format.format) {
+        (
+            vk::ColorSpaceKHR::SRGB_NONLINEAR,
+            vk::Format::B8G8R8A8_SRGB
+            | vk::Format::R8G8B8A8_SRGB
+            | vk::Format::B8G8R8A8_UNORM
+            | vk::Format::R8G8B8A8_UNORM,
+        ) => Some(OutputColorSpace::SrgbNonlinear),
+        (
+            vk::ColorSpaceKHR::HDR10_ST2084_EXT,
+            vk::Format::A2B10G10R10_UNORM_PACK32
+            | vk::Format::A2R10G10B10_UNORM_PACK32
+            | vk::Format::R16G16B16A16_SFLOAT,
+        ) => Some(OutputColorSpace::HdrSt2084),
+        (vk::ColorSpaceKHR::EXTENDED_SRGB_LINEAR_EXT, vk::Format::R16G16B16A16_SFLOAT) => {
+            Some(OutputColorSpace::ExtendedSrgbLinear)
+        }
+        _ => None,
+    }
+}
+
+// The first surface format in the preferred color space, or an sRGB one when there is
+// none. sRGB formats come before UNORM ones, since they need no output pass. None if the
+// surface offers nothing usable.
+pub(crate) fn choose_surface_format(
+    surface_formats: &[vk::SurfaceFormatKHR],
+    preferred: OutputColorSpace,
+) -> Option<(vk::SurfaceFormatKHR, OutputColorSpace)> {
+    // A single UNDEFINED format means the surface takes anything
+    if let [format] = surface_formats {
+        if format.format == vk::Format::UNDEFINED {
+            let format = vk::SurfaceFormatKHR {
+                format: vk::Format::B8G8R8A8_SRGB,
+                color_space: vk::ColorSpaceKHR::SRGB_NONLINEAR,
+            };
+            return Some((format, OutputColorSpace::SrgbNonlinear));
+        }
+    }
+    let in_color_space = |color_space: OutputColorSpace| {
+        surface_formats
+            .iter()
+            .filter(move |format| surface_format_color_space(format) == Some(color_space))
+    };
+    let preferred_format = if preferred.is_hdr() {
+        in_color_space(preferred).next()
+    } else {
+        None
+    };
+    preferred_format
+        .map(|format| (*format, preferred))
+        .or_else(|| {
+            in_color_space(OutputColorSpace::SrgbNonlinear)
+                .min_by_key(|format| !is_srgb_format(format.format))
+                .map(|format| (*format, OutputColorSpace::SrgbNonlinear))
+        })
+}
@@ -0,0 +1,293 @@
+// The shaders output linear colors, which the sRGB swapchain encodes on write, or the
+// output pass for other swapchains, see OutputEncoding.
+// Colors picked by people (hex codes, color pickers, design mockups) are sRGB though, and
+// using them as linear values makes them too bright, e.g. 0.5 gray shows up as #BCBCBC.
+// Color always holds linear values, the from_srgb* constructors are the only place where
+// sRGB gets converted.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Color {
+    pub r: f32,
+    pub g: f32,
+    pub b: f32,
+    pub a: f32,
+}
+
+// Standard sRGB transfer function, for values in [0, 1]
+pub fn srgb_to_linear(c: f32) -> f32 {
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+pub fn linear_to_srgb(c: f32) -> f32 {
+    if c <= 0.0031308 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+// SMPTE ST 2084 (PQ), the transfer function of HDR10. Encodes absolute luminance up to
+// PQ_MAX_NITS, e.g. 100 nits is 0.508 and 1000 nits is 0.752.
+pub const PQ_MAX_NITS: f32 = 10000.0;
+const PQ_M1: f32 = 2610.0 / 16384.0;
+const PQ_M2: f32 = 2523.0 / 4096.0 * 128.0;
+const PQ_C1: f32 = 3424.0 / 4096.0;
+const PQ_C2: f32 = 2413.0 / 4096.0 * 32.0;
+const PQ_C3: f32 = 2392.0 / 4096.0 * 32.0;
+
+pub fn nits_to_pq(nits: f32) -> f32 {
+    let y = (nits / PQ_MAX_NITS).clamp(0.0, 1.0).powf(PQ_M1);
+    ((PQ_C1 + PQ_C2 * y) / (1.0 + PQ_C3 * y)).powf(PQ_M2)
+}
+
+pub fn pq_to_nits(value: f32) -> f32 {
+    let e = value.clamp(0.0, 1.0).powf(1.0 / PQ_M2);
+    ((e - PQ_C1).max(0.0) / (PQ_C2 - PQ_C3 * e)).powf(1.0 / PQ_M1) * PQ_MAX_NITS
+}
+
+// scRGB has the sRGB primaries and is linear, with 1 at 80 nits
+pub const SCRGB_NITS: f32 = 80.0;
+
+// HDR10 uses the BT.2020 primaries, linear values only
+pub fn rec709_to_rec2020(rgb: [f32; 3]) -> [f32; 3] {
+    let [r, g, b] = rgb;
+    [
+        0.6274 * r + 0.3293 * g + 0.0433 * b,
+        0.0691 * r + 0.9195 * g + 0.0114 * b,
+        0.0164 * r + 0.0880 * g + 0.8956 * b,
+    ]
+}
+
+// Half floats as read back from R16G16B16A16_SFLOAT images
+pub fn f16_to_f32(bits: u16) -> f32 {
+    let sign = if bits & 0x8000 != 0 { -1.0 } else { 1.0 };
+    let exponent = ((bits >> 10) & 0x1f) as i32;
+    let mantissa = (bits & 0x3ff) as f32;
+    match exponent {
+        0 => sign * mantissa * 2f32.powi(-24),
+        0x1f if mantissa == 0.0 => sign * f32::INFINITY,
+        0x1f => f32::NAN,
+        _ => sign * (1.0 + mantissa / 1024.0) * 2f32.powi(exponent - 15),
+    }
+}
+
+// How the final output pass turns the linear working colors into what the swapchain
+// expects, the same math as output_encode.frag. Values of 1 are SDR white, which is shown
+// at paper_white_nits on HDR displays and clipped at peak_nits.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputEncoding {
+    // For UNORM swapchains in the sRGB color space, sRGB swapchains encode on write
+    Srgb,
+    Pq {
+        paper_white_nits: f32,
+        peak_nits: f32,
+    },
+    ScRgb {
+        paper_white_nits: f32,
+        peak_nits: f32,
+    },
+}
+
+impl OutputEncoding {
+    pub fn encode(&self, rgb: [f32; 3]) -> [f32; 3] {
+        let rgb = rgb.map(|c| c.max(0.0));
+        match *self {
+            OutputEncoding::Srgb => rgb.map(|c| linear_to_srgb(c.min(1.0))),
+            OutputEncoding::Pq {
+                paper_white_nits,
+                peak_nits,
+            } => rec709_to_rec2020(rgb).map(|c| nits_to_pq((c * paper_white_nits).min(peak_nits))),
+            OutputEncoding::ScRgb {
+                paper_white_nits,
+                peak_nits,
+            } => rgb.map(|c| (c * paper_white_nits).min(peak_nits) / SCRGB_NITS),
+        }
+    }
+
+    // The push constants of output_encode.frag: the encoding, paper white and peak nits
+    pub fn push_constants(&self) -> [u32; 3] {
+        let (encoding, paper_white_nits, peak_nits) = match *self {
+            OutputEncoding::Srgb => (0, 0.0, 0.0),
+            OutputEncoding::Pq {
+                paper_white_nits,
+                peak_nits,
+            } => (1, paper_white_nits, peak_nits),
+            OutputEncoding::ScRgb {
+                paper_white_nits,
+                peak_nits,
+            } => (2, paper_white_nits, peak_nits),
+        };
+        [encoding, paper_white_nits.to_bits(), peak_nits.to_bits()]
+    }
+}
+
+impl Color {
+    pub const fn linear(r: f32, g: f32, b: f32) -> Self {
+        Self { r, g, b, a: 1.0 }
+    }
+
+    pub const fn linear_with_alpha(r: f32, g: f32, b: f32, a: f32) -> Self {
+        Self { r, g, b, a }
+    }
+
+    // Alpha is not part of the sRGB encoding and stays as it is
+    pub fn from_srgb(r: f32, g: f32, b: f32) -> Self {
+        Self::linear(srgb_to_linear(r), srgb_to_linear(g), srgb_to_linear(b))
+    }
+
+    pub fn from_srgb_u8(r: u8, g: u8, b: u8) -> Self {
+        let channel = |c: u8| c as f32 / 255.0;
+        Self::from_srgb(channel(r), channel(g), channel(b))
+    }
+
+    // "#RRGGBB" or "#RRGGBBAA", the # is optional. None if it is not a hex color.
+    pub fn from_srgb_hex(hex: &str) -> Option<Self> {
+        let digits = hex.strip_prefix('#').unwrap_or(hex);
+        if !(digits.len() == 6 || digits.len() == 8) || !digits.is_ascii() {
+            return None;
+        }
+        let channel = |i: usize| u8::from_str_radix(&digits[i..i + 2], 16).ok();
+        let mut color = Self::from_srgb_u8(channel(0)?, channel(2)?, channel(4)?);
+        if digits.len() == 8 {
+            color.a = channel(6)? as f32 / 255.0;
+        }
+        Some(color)
+    }
+
+    pub fn to_linear(&self) -> [f32; 3] {
+        [self.r, self.g, self.b]
+    }
+
+    pub fn to_linear_rgba(&self) -> [f32; 4] {
+        [self.r, self.g, self.b, self.a]
+    }
+
+    pub fn to_srgb(&self) -> [f32; 3] {
+        [
+            linear_to_srgb(self.r),
+            linear_to_srgb(self.g),
+            linear_to_srgb(self.b),
+        ]
+    }
+}
+
+// Deprecated: plain arrays are taken as linear, like before Color existed. New code should
+// say which space it means with Color::linear or Color::from_srgb*.
+impl From<[f32; 3]> for Color {
+    fn from(value: [f32; 3]) -> Self {
+        Self::linear(value[0], value[1], value[2])
+    }
+}
+
+impl From<[f32; 4]> for Color {
+    fn from(value: [f32; 4]) -> Self {
+        Self::linear_with_alpha(value[0], value[1], value[2], value[3])
+    }
+}
+
+// Linear values of common sRGB colors
+pub mod palette {
+    use super::Color;
+
+    pub const BLACK: Color = Color::linear(0.0, 0.0, 0.0);
+    pub const WHITE: Color = Color::linear(1.0, 1.0, 1.0);
+    // #404040
+    pub const DARK_GRAY: Color = Color::linear(0.0513, 0.0513, 0.0513);
+    // #808080
+    pub const GRAY: Color = Color::linear(0.2159, 0.2159, 0.2159);
+    // #C0C0C0
+    pub const LIGHT_GRAY: Color = Color::linear(0.5271, 0.5271, 0.5271);
+    pub const RED: Color = Color::linear(1.0, 0.0, 0.0);
+    pub const GREEN: Color = Color::linear(0.0, 1.0, 0.0);
+    pub const BLUE: Color = Color::linear(0.0, 0.0, 1.0);
+    pub const YELLOW: Color = Color::linear(1.0, 1.0, 0.0);
+    // #FFA500
+    pub const ORANGE: Color = Color::linear(1.0, 0.3763, 0.0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: f32, b: f32, tolerance: f32) {
+        assert!(
+            (a - b).abs() <= tolerance,
+            "{} is not within {} of {}",
+            a,
+            tolerance,
+            b
+        );
+    }
+
+    #[test]
+    fn mid_gray_is_dark_in_linear() {
+        let gray = Color::from_srgb_hex("#808080").unwrap();
+        assert_close(gray.r, 0.2158, 1e-3);
+        assert_eq!(gray.r, gray.g);
+        assert_eq!(gray.r, gray.b);
+        assert_eq!(gray.a, 1.0);
+        assert_close(gray.r, palette::GRAY.r, 1e-3);
+    }
+
+    #[test]
+    fn srgb_round_trips() {
+        for i in 0..=255u8 {
+            let c = i as f32 / 255.0;
+            assert_close(linear_to_srgb(srgb_to_linear(c)), c, 1e-5);
+        }
+        let color = Color::from_srgb(0.2, 0.5, 0.9);
+        let [r, g, b] = color.to_srgb();
+        assert_close(r, 0.2, 1e-5);
+        assert_close(g, 0.5, 1e-5);
+        assert_close(b, 0.9, 1e-5);
+    }
+
+    #[test]
+    fn hex_parsing() {
+        assert_eq!(
+            Color::from_srgb_hex("FFFFFF"),
+            Some(Color::linear(1.0, 1.0, 1.0))
+        );
+        let translucent = Color::from_srgb_hex("#00000080").unwrap();
+        assert_close(translucent.a, 128.0 / 255.0, 1e-6);
+        assert_eq!(Color::from_srgb_hex("#12345"), None);
+        assert_eq!(Color::from_srgb_hex("#GG0000"), None);
+        assert_eq!(Color::from_srgb_hex("#ééé"), None);
+    }
+
+    #[test]
+    fn pq_encodes_reference_luminances() {
+        assert_close(nits_to_pq(0.0), 0.0, 1e-5);
+        assert_close(nits_to_pq(100.0), 0.508, 1e-3);
+        assert_close(nits_to_pq(PQ_MAX_NITS), 1.0, 1e-5);
+        // Out of range luminances are clamped
+        assert_eq!(nits_to_pq(2.0 * PQ_MAX_NITS), nits_to_pq(PQ_MAX_NITS));
+    }
+
+    #[test]
+    fn pq_decodes_what_it_encodes() {
+        assert_close(pq_to_nits(nits_to_pq(0.0)), 0.0, 1e-3);
+        assert_close(pq_to_nits(nits_to_pq(100.0)), 100.0, 0.1);
+        assert_close(pq_to_nits(nits_to_pq(PQ_MAX_NITS)), PQ_MAX_NITS, 1.0);
+        assert_close(pq_to_nits(0.0), 0.0, 1e-6);
+        assert_close(pq_to_nits(1.0), PQ_MAX_NITS, 1.0);
+    }
+
+    #[test]
+    fn pq_output_puts_white_at_paper_white() {
+        let encoding = OutputEncoding::Pq {
+            paper_white_nits: 100.0,
+            peak_nits: 1000.0,
+        };
+        for c in encoding.encode([1.0, 1.0, 1.0]) {
+            assert_close(c, 0.508, 1e-3);
+        }
+        // Clipped at the peak
+        for c in encoding.encode([50.0, 50.0, 50.0]) {
+            assert_close(c, nits_to_pq(1000.0), 1e-5);
+        }
+    }
+}
@@ -0,0 +1,165 @@
+use nalgebra_glm as glm;
+
+// Everything the commands of a frame are recorded from, apart from what the GPU reads
+// from buffers and images when it runs them. The camera is part of it, culling, the
+// draw order and the shadow map follow it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct RecordKey {
+    pub scene: u64,
+    pub materials: u64,
+    pub text: u64,
+    pub lights: u64,
+    // Bumped by Renderer::request_redraw, which every setter of the renderer calls
+    pub renderer: u64,
+    pub view_projection: glm::Mat4,
+    pub layer_mask: u32,
+}
+
+// Which swapchain images have a command buffer recorded with the current key, that can
+// be submitted again instead of being recorded. A frame recorded with another key drops
+// the recordings of every image, so all kept recordings always match each other.
+#[derive(Debug, Default)]
+pub(crate) struct RecordedFrames {
+    key: Option<RecordKey>,
+    recorded: Vec<bool>,
+}
+
+impl RecordedFrames {
+    pub fn new(image_count: usize) -> Self {
+        Self {
+            key: None,
+            recorded: vec![false; image_count],
+        }
+    }
+
+    // Nothing may be in flight, the command buffers were allocated again
+    pub fn set_image_count(&mut self, image_count: usize) {
+        self.recorded = vec![false; image_count];
+    }
+
+    // The next frame of every image is recorded again
+    pub fn invalidate(&mut self) {
+        self.recorded
+            .iter_mut()
+            .for_each(|recorded| *recorded = false);
+    }
+
+    // Whether the command buffer of image_index can be submitted as it is
+    pub fn reusable(&mut self, image_index: usize, key: RecordKey) -> bool {
+        if self.key != Some(key) {
+            self.key = Some(key);
+            self.invalidate();
+        }
+        self.recorded.get(image_index).copied().unwrap_or(false)
+    }
+
+    // After the command buffer of image_index was recorded with the key of the last
+    // reusable call. Frames with something that changes every frame in them, e.g. the UI
+    // or GPU timestamps, are not kept.
+    pub fn recorded(&mut self, image_index: usize, keep: bool) {
+        if let Some(recorded) = self.recorded.get_mut(image_index) {
+            *recorded = keep;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IMAGES: usize = 3;
+
+    fn key() -> RecordKey {
+        RecordKey {
+            scene: 1,
+            materials: 1,
+            text: 0,
+            lights: 0,
+            renderer: 0,
+            view_projection: glm::identity(),
+            layer_mask: u32::MAX,
+        }
+    }
+
+    // Like Renderer::submit_commands, images are acquired in turn. Returns how many
+    // command buffers were recorded.
+    fn render(frames: &mut RecordedFrames, count: usize, key: RecordKey, keep: bool) -> usize {
+        let mut recordings = 0;
+        for frame in 0..count {
+            let image_index = frame % IMAGES;
+            if !frames.reusable(image_index, key) {
+                frames.recorded(image_index, keep);
+                recordings += 1;
+            }
+        }
+        recordings
+    }
+
+    #[test]
+    fn static_scene_records_nothing_after_warmup() {
+        let mut frames = RecordedFrames::new(IMAGES);
+        assert_eq!(render(&mut frames, IMAGES, key(), true), IMAGES);
+        assert_eq!(render(&mut frames, 100, key(), true), 0);
+    }
+
+    #[test]
+    fn a_new_generation_records_every_image_once() {
+        let mut frames = RecordedFrames::new(IMAGES);
+        render(&mut frames, 10, key(), true);
+        let changed = RecordKey { scene: 2, ..key() };
+        assert_eq!(render(&mut frames, 10, changed, true), IMAGES);
+    }
+
+    #[test]
+    fn moving_the_camera_records_again() {
+        let mut frames = RecordedFrames::new(IMAGES);
+        render(&mut frames, 10, key(), true);
+        let moved = RecordKey {
+            view_projection: glm::translation(&glm::vec3(0.0, 0.0, -1.0)),
+            ..key()
+        };
+        assert_eq!(render(&mut frames, 1, moved, true), 1);
+        // Back where it was, the old recordings were dropped with the first move
+        assert_eq!(render(&mut frames, IMAGES, key(), true), IMAGES);
+    }
+
+    #[test]
+    fn invalidate_records_every_image_again() {
+        let mut frames = RecordedFrames::new(IMAGES);
+        render(&mut frames, 10, key(), true);
+        frames.invalidate();
+        assert_eq!(render(&mut frames, 10, key(), true), IMAGES);
+    }
+
+    #[test]
+    fn a_new_image_count_records_every_image_once() {
+        let mut frames = RecordedFrames::new(IMAGES);
+        render(&mut frames, 10, key(), true);
+        for image_count in [5, 1, IMAGES] {
+            frames.set_image_count(image_count);
+            let recordings = (0..image_count * 3)
+                .map(|frame| frame % image_count)
+                .filter(|image_index| {
+                    let reusable = frames.reusable(*image_index, key());
+                    frames.recorded(*image_index, true);
+                    !reusable
+                })
+                .count();
+            assert_eq!(recordings, image_count);
+        }
+    }
+
+    #[test]
+    fn frames_that_are_not_kept_record_every_time() {
+        let mut frames = RecordedFrames::new(IMAGES);
+        assert_eq!(render(&mut frames, 10, key(), false), 10);
+    }
+
+    #[test]
+    fn new_image_count_records_again() {
+        let mut frames = RecordedFrames::new(IMAGES);
+        render(&mut frames, 10, key(), true);
+        frames.set_image_count(IMAGES);
+        assert_eq!(render(&mut frames, 10, key(), true), IMAGES);
+    }
+}
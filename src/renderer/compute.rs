@@ -0,0 +1,285 @@
+use ash::vk;
+
+use super::{
+    buffer::Buffer,
+    descriptor::DescriptorAllocator,
+    error::{ComputeDispatchError, InvalidHandle},
+    material::ComputePipelineBuilder,
+    shaders::ShaderCache,
+    utils::{Handle, HandleArray},
+    RendererResult,
+};
+
+// A buffer bound to a set and binding of a compute shader for one dispatch
+#[derive(Clone, Copy, Debug)]
+pub struct ComputeBufferBinding {
+    pub set: u32,
+    pub binding: u32,
+    pub buffer: vk::Buffer,
+    pub offset: vk::DeviceSize,
+    pub range: vk::DeviceSize,
+}
+
+impl ComputeBufferBinding {
+    pub fn whole(set: u32, binding: u32, buffer: &Buffer) -> Self {
+        Self {
+            set,
+            binding,
+            buffer: buffer.get_buffer().buffer,
+            offset: 0,
+            range: vk::WHOLE_SIZE,
+        }
+    }
+}
+
+// A compute shader built into a pipeline, see Renderer::register_compute_pass. The
+// layouts belong to the shader cache.
+pub struct ComputePass {
+    pub(crate) pipeline: vk::Pipeline,
+    pub(crate) layout: vk::PipelineLayout,
+    pub(crate) set_layouts: [vk::DescriptorSetLayout; 4],
+    // Set, binding and type of every descriptor the shader declares
+    pub(crate) bindings: Vec<(u32, u32, vk::DescriptorType)>,
+}
+
+struct ComputeDispatch {
+    pass: Handle<ComputePass>,
+    group_counts: [u32; 3],
+    bindings: Vec<ComputeBufferBinding>,
+}
+
+// The compute passes and the dispatches waiting for the next recorded frame. Their
+// descriptor sets live as long as the frame they were recorded in.
+#[derive(Default)]
+pub(crate) struct ComputeDispatcher {
+    passes: HandleArray<ComputePass>,
+    pending: Vec<ComputeDispatch>,
+    // One per swapchain image, reset when its command buffer is recorded again
+    descriptor_allocators: Vec<DescriptorAllocator>,
+}
+
+impl ComputeDispatcher {
+    pub fn register(
+        &mut self,
+        device: &ash::Device,
+        shader_cache: &mut ShaderCache,
+        compute_shader: &str,
+    ) -> RendererResult<Handle<ComputePass>> {
+        let effect_handle = shader_cache.build_compute_effect(device, compute_shader)?;
+        let effect = shader_cache.get_shader_effect_by_handle(effect_handle)?;
+        let stage = *effect
+            .get_stages(shader_cache)?
+            .first()
+            .ok_or(InvalidHandle)?;
+        let pipeline = ComputePipelineBuilder::new(stage, effect.pipeline_layout)
+            .build_pipeline(device, shader_cache.pipeline_cache())?;
+        Ok(self.passes.insert(ComputePass {
+            pipeline,
+            layout: effect.pipeline_layout,
+            set_layouts: effect.set_layouts,
+            bindings: effect.descriptor_bindings().collect(),
+        }))
+    }
+
+    // Every binding has to be a uniform or storage buffer the shader declares, and every
+    // one the shader declares has to be bound
+    pub fn queue(
+        &mut self,
+        pass: Handle<ComputePass>,
+        group_counts: [u32; 3],
+        bindings: &[ComputeBufferBinding],
+    ) -> RendererResult<()> {
+        let compute_pass = self.passes.get(pass).ok_or(InvalidHandle)?;
+        for binding in bindings {
+            match compute_pass
+                .bindings
+                .iter()
+                .find(|(set, b, _)| *set == binding.set && *b == binding.binding)
+            {
+                Some((_, _, vk::DescriptorType::STORAGE_BUFFER))
+                | Some((_, _, vk::DescriptorType::UNIFORM_BUFFER)) => {}
+                Some((_, _, typ)) => {
+                    return Err(ComputeDispatchError(format!(
+                        "set {} binding {} is a {:?}, not a buffer",
+                        binding.set, binding.binding, typ
+                    ))
+                    .into())
+                }
+                None => {
+                    return Err(ComputeDispatchError(format!(
+                        "the shader declares nothing at set {} binding {}",
+                        binding.set, binding.binding
+                    ))
+                    .into())
+                }
+            }
+        }
+        let missing: Vec<String> = compute_pass
+            .bindings
+            .iter()
+            .filter(|(set, b, _)| {
+                !bindings
+                    .iter()
+                    .any(|binding| binding.set == *set && binding.binding == *b)
+            })
+            .map(|(set, b, _)| format!("set {} binding {}", set, b))
+            .collect();
+        if !missing.is_empty() {
+            return Err(
+                ComputeDispatchError(format!("nothing bound to {}", missing.join(", "))).into(),
+            );
+        }
+        self.pending.push(ComputeDispatch {
+            pass,
+            group_counts,
+            bindings: bindings.to_vec(),
+        });
+        Ok(())
+    }
+
+    pub fn has_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    // Records the queued dispatches in order. What they write is visible to the vertex
+    // input and the shaders of everything recorded after them, and they wait for the
+    // shaders of earlier frames that read the same buffers.
+    pub fn record(
+        &mut self,
+        device: &ash::Device,
+        command_buffer: vk::CommandBuffer,
+        image_index: usize,
+    ) -> RendererResult<()> {
+        if self.descriptor_allocators.len() <= image_index {
+            self.descriptor_allocators
+                .resize_with(image_index + 1, DescriptorAllocator::default);
+        }
+        // The frame that used these sets last is finished
+        let allocator = &mut self.descriptor_allocators[image_index];
+        allocator.reset_pools(device)?;
+        let readers = vk::PipelineStageFlags::VERTEX_INPUT
+            | vk::PipelineStageFlags::VERTEX_SHADER
+            | vk::PipelineStageFlags::FRAGMENT_SHADER
+            | vk::PipelineStageFlags::COMPUTE_SHADER;
+        let read_access = vk::AccessFlags::VERTEX_ATTRIBUTE_READ
+            | vk::AccessFlags::UNIFORM_READ
+            | vk::AccessFlags::SHADER_READ;
+        for dispatch in self.pending.drain(..) {
+            let pass = match self.passes.get(dispatch.pass) {
+                Some(pass) => pass,
+                None => continue,
+            };
+            let mut sets = [vk::DescriptorSet::null(); 4];
+            let mut buffer_infos = Vec::with_capacity(dispatch.bindings.len());
+            for binding in dispatch.bindings.iter() {
+                let set = binding.set as usize;
+                if sets[set] == vk::DescriptorSet::null() {
+                    sets[set] = allocator.allocate(device, pass.set_layouts[set])?;
+                }
+                buffer_infos.push([vk::DescriptorBufferInfo {
+                    buffer: binding.buffer,
+                    offset: binding.offset,
+                    range: binding.range,
+                }]);
+            }
+            let writes: Vec<vk::WriteDescriptorSet> = dispatch
+                .bindings
+                .iter()
+                .zip(buffer_infos.iter())
+                .map(|(binding, info)| {
+                    let typ = pass
+                        .bindings
+                        .iter()
+                        .find(|(set, b, _)| *set == binding.set && *b == binding.binding)
+                        .map_or(vk::DescriptorType::STORAGE_BUFFER, |(_, _, typ)| *typ);
+                    vk::WriteDescriptorSet::builder()
+                        .dst_set(sets[binding.set as usize])
+                        .dst_binding(binding.binding)
+                        .descriptor_type(typ)
+                        .buffer_info(info)
+                        .build()
+                })
+                .collect();
+            let barriers = |src_access: vk::AccessFlags,
+                            dst_access: vk::AccessFlags|
+             -> Vec<vk::BufferMemoryBarrier> {
+                dispatch
+                    .bindings
+                    .iter()
+                    .map(|binding| {
+                        vk::BufferMemoryBarrier::builder()
+                            .buffer(binding.buffer)
+                            .offset(binding.offset)
+                            .size(binding.range)
+                            .src_access_mask(src_access)
+                            .dst_access_mask(dst_access)
+                            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                            .build()
+                    })
+                    .collect()
+            };
+            // Reads of earlier passes only need the execution dependency
+            let before = barriers(
+                vk::AccessFlags::SHADER_WRITE,
+                vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE,
+            );
+            let after = barriers(vk::AccessFlags::SHADER_WRITE, read_access);
+            unsafe {
+                device.update_descriptor_sets(&writes, &[]);
+                device.cmd_pipeline_barrier(
+                    command_buffer,
+                    readers,
+                    vk::PipelineStageFlags::COMPUTE_SHADER,
+                    vk::DependencyFlags::empty(),
+                    &[],
+                    &before,
+                    &[],
+                );
+                device.cmd_bind_pipeline(
+                    command_buffer,
+                    vk::PipelineBindPoint::COMPUTE,
+                    pass.pipeline,
+                );
+                for (index, set) in sets.iter().enumerate() {
+                    if *set != vk::DescriptorSet::null() {
+                        device.cmd_bind_descriptor_sets(
+                            command_buffer,
+                            vk::PipelineBindPoint::COMPUTE,
+                            pass.layout,
+                            index as u32,
+                            &[*set],
+                            &[],
+                        );
+                    }
+                }
+                let [x, y, z] = dispatch.group_counts;
+                device.cmd_dispatch(command_buffer, x, y, z);
+                device.cmd_pipeline_barrier(
+                    command_buffer,
+                    vk::PipelineStageFlags::COMPUTE_SHADER,
+                    readers,
+                    vk::DependencyFlags::empty(),
+                    &[],
+                    &after,
+                    &[],
+                );
+            }
+        }
+        Ok(())
+    }
+
+    // The effects belong to the shader cache
+    pub fn destroy(&mut self, device: &ash::Device) {
+        for pass in self.passes.iter() {
+            unsafe {
+                device.destroy_pipeline(pass.pipeline, None);
+            }
+        }
+        self.passes.clear();
+        self.pending.clear();
+        for allocator in self.descriptor_allocators.iter_mut() {
+            allocator.destroy(device);
+        }
+    }
+}
@@ -0,0 +1,265 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use super::bloom::BloomConfig;
+use super::exposure::AutoExposureConfig;
+use super::probe::ProbeBlend;
+
+// What Renderer::render does while the window is unfocused or occluded
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum BackgroundBehavior {
+    #[default]
+    ContinueFullRate,
+    // Target frames per second while in the background
+    ThrottleTo(f32),
+    SkipRendering,
+}
+
+// When Renderer::render draws a frame
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RedrawPolicy {
+    #[default]
+    Continuous,
+    // Only when something visible changed since the last frame, or after
+    // Renderer::request_redraw. The last presented image stays on screen otherwise,
+    // for editors and other mostly static scenes.
+    OnDemand,
+}
+
+// How the scene rendered at render_scale is brought up to the window size
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum UpscaleFilter {
+    #[default]
+    Bilinear,
+}
+
+impl UpscaleFilter {
+    // The effect template doing the upscale, filters like FSR need their own
+    pub fn template_name(&self) -> &'static str {
+        match self {
+            UpscaleFilter::Bilinear => "upscale_bilinear",
+        }
+    }
+}
+
+// How the upscale pass maps the HDR scene into the range the swapchain shows
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ToneMapping {
+    // x / (1 + x) per channel
+    #[default]
+    Reinhard,
+    // Narkowicz's fit of the ACES filmic curve, more contrast and highlights that roll
+    // off to white
+    Aces,
+}
+
+impl ToneMapping {
+    // The operator push constant of upscale_bilinear.frag
+    pub(crate) fn shader_index(&self) -> u32 {
+        match self {
+            ToneMapping::Reinhard => 0,
+            ToneMapping::Aces => 1,
+        }
+    }
+}
+
+// The order opaque objects with the same pipeline, material and mesh are drawn in
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OpaqueSort {
+    // The order of the scene
+    #[default]
+    None,
+    // Closest first, so the depth test can reject what is behind them. Whether that pays
+    // off depends on the scene and the GPU, see the opaque_sort_timings test. Distances
+    // are quantized, see draw_list::distance_bucket.
+    FrontToBack,
+}
+
+// How the swapchain presents, Fifo waits for vertical blank. A mode the surface doesn't
+// support falls back to the next one that is supported: Immediate to Mailbox then Fifo,
+// Mailbox and FifoRelaxed to Fifo, which every surface supports.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PresentModePreference {
+    Fifo,
+    // Doesn't tear, and doesn't wait either since a queued image is replaced
+    #[default]
+    Mailbox,
+    // Tears
+    Immediate,
+    // Only tears for the frames that missed a vertical blank
+    FifoRelaxed,
+}
+
+// How the Transparency pass blends the transparent objects over the scene
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TransparencyTechnique {
+    // Back to front, by the distance of the objects. Objects that intersect are blended
+    // in the same order on both sides of the intersection.
+    #[default]
+    SortedAlpha,
+    // Weighted blended order independent transparency (McGuire and Bavoil 2013). The
+    // objects are accumulated unsorted into an RGBA16F and an R8 target and composited
+    // over the scene, closer surfaces weigh more. Needs independent blending, falls back
+    // to SortedAlpha on devices without it.
+    WeightedBlended,
+}
+
+impl TransparencyTechnique {
+    // The forward subpass, the Transparency pass, and for WeightedBlended its composite
+    pub(crate) fn scene_subpasses(&self) -> u32 {
+        match self {
+            TransparencyTechnique::SortedAlpha => 2,
+            TransparencyTechnique::WeightedBlended => 3,
+        }
+    }
+
+    // The last one, particles are blended over the composited transparent objects
+    pub(crate) fn particle_subpass(&self) -> u32 {
+        self.scene_subpasses() - 1
+    }
+}
+
+pub const MIN_RENDER_SCALE: f32 = 0.25;
+
+// The color space of the swapchain. The HDR ones need VK_EXT_swapchain_colorspace and a
+// display that supports them, otherwise the renderer falls back to sRGB.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OutputColorSpace {
+    #[default]
+    SrgbNonlinear,
+    // HDR10, PQ encoded with the BT.2020 primaries
+    HdrSt2084,
+    // scRGB, linear floats with the sRGB primaries that go above 1
+    ExtendedSrgbLinear,
+}
+
+impl OutputColorSpace {
+    pub fn is_hdr(&self) -> bool {
+        *self != OutputColorSpace::SrgbNonlinear
+    }
+}
+
+// Which physical device the renderer runs on. Devices without the swapchain extension, a
+// graphics queue that can present, or surface formats and present modes are never
+// picked, an override naming one of them fails with NoSuitableDevice.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum DeviceSelection {
+    // Discrete over integrated over virtual over CPU
+    #[default]
+    Best,
+    // In the order the devices are enumerated, which is the order they are logged in
+    Index(usize),
+    // The first device whose name contains this, ignoring case
+    NameContains(String),
+}
+
+#[derive(Clone, Debug)]
+pub struct RendererConfig {
+    // Hand out ids that are identical across processes doing the same operations,
+    // see Renderer::debug_handle_hash
+    pub deterministic_ids: bool,
+    // Fixed at creation
+    pub device_selection: DeviceSelection,
+    pub background_behavior: BackgroundBehavior,
+    pub redraw_policy: RedrawPolicy,
+    pub opaque_sort: OpaqueSort,
+    // See RendererCapabilities::transparency for what was picked. Fixed at creation.
+    pub transparency_mode: TransparencyTechnique,
+    // Number of frames the frame time percentiles are computed over
+    pub frame_time_history: usize,
+    // Frames slower than this get logged, together with what happened that frame
+    pub frame_spike_threshold: Option<Duration>,
+    // Scene chunks whose bounds are closer than this to the camera are active
+    pub chunk_activation_radius: f32,
+    // Skip objects outside the camera frustum, turning it off is meant for debugging the
+    // bounds of objects that disappear
+    pub frustum_culling: bool,
+    // The scene is rendered at this fraction of the window size and upscaled, text and
+    // the UI stay at full resolution. Between MIN_RENDER_SCALE and 1.
+    pub render_scale: f32,
+    pub upscale_filter: UpscaleFilter,
+    // Applied after the exposure, the scene is rendered in HDR_WORKING_FORMAT before it
+    pub tone_mapping: ToneMapping,
+    // Adapt the exposure to the measured scene luminance, off with None
+    pub auto_exposure: Option<AutoExposureConfig>,
+    // Makes what is brighter than a threshold glow, off with an intensity of 0
+    pub bloom: BloomConfig,
+    // Run compute passes like the luminance measurement on a queue of their own, next to
+    // the graphics work. Only has an effect on devices with a separate compute queue
+    // family, otherwise they are recorded inline. Fixed at creation.
+    pub async_compute: bool,
+    // Samples per pixel of the scene, text and UI, resolved at the end of the render
+    // pass. 1 turns multisampling off. Falls back to the highest count the device
+    // supports below it, see Renderer::msaa_samples. Fixed at creation.
+    pub msaa_samples: u32,
+    // Width and height in texels of the depth map the first directional light casts its
+    // shadows with. Fixed at creation.
+    pub shadow_map_resolution: u32,
+    // Shadows are cast onto everything closer than this to the camera, in m. Larger
+    // distances make the shadows blurrier.
+    pub shadow_distance: f32,
+    // Keep the per-object data in device local memory, written through staging buffers on
+    // the transfer queue, instead of host visible memory the GPU reads over the bus.
+    // Fixed at creation.
+    pub device_local_object_data: bool,
+    // Render the screen space motion of the opaque objects every frame, for effects like
+    // motion blur or temporal antialiasing, see Renderer::velocity_texture. Costs another
+    // pass over the opaque objects. Fixed at creation.
+    pub velocity_target: bool,
+    // Falls back when the surface doesn't support it, see Renderer::set_present_mode for
+    // changing it afterwards
+    pub present_mode: PresentModePreference,
+    // Used when the surface supports it, see RendererCapabilities for what was picked
+    pub preferred_color_space: OutputColorSpace,
+    // The brightness SDR white is shown at on an HDR display
+    pub hdr_paper_white_nits: f32,
+    // The brightest the display can show, brighter values are clipped
+    pub hdr_peak_nits: f32,
+    // How objects pick the light probes they take their ambient light from
+    pub probe_blend: ProbeBlend,
+    // Watch the files assets were loaded from, needs the hot-reload feature
+    pub watch_assets: bool,
+    // How long a changed file has to stay untouched before it is reloaded
+    pub asset_reload_debounce: Duration,
+    // The compiled pipelines are loaded from here at creation and written back when the
+    // renderer is dropped, None keeps them in memory only. Fixed at creation.
+    pub pipeline_cache_path: Option<PathBuf>,
+}
+
+impl Default for RendererConfig {
+    fn default() -> Self {
+        Self {
+            deterministic_ids: false,
+            device_selection: DeviceSelection::default(),
+            background_behavior: BackgroundBehavior::default(),
+            redraw_policy: RedrawPolicy::default(),
+            opaque_sort: OpaqueSort::default(),
+            transparency_mode: TransparencyTechnique::default(),
+            frame_time_history: 300,
+            frame_spike_threshold: Some(Duration::from_millis(50)),
+            chunk_activation_radius: 200.0,
+            frustum_culling: true,
+            render_scale: 1.0,
+            upscale_filter: UpscaleFilter::default(),
+            tone_mapping: ToneMapping::default(),
+            auto_exposure: None,
+            bloom: BloomConfig::default(),
+            async_compute: false,
+            msaa_samples: 1,
+            shadow_map_resolution: 2048,
+            shadow_distance: 50.0,
+            device_local_object_data: false,
+            velocity_target: false,
+            present_mode: PresentModePreference::default(),
+            preferred_color_space: OutputColorSpace::default(),
+            hdr_paper_white_nits: 203.0,
+            hdr_peak_nits: 1000.0,
+            probe_blend: ProbeBlend::default(),
+            watch_assets: true,
+            asset_reload_debounce: Duration::from_millis(200),
+            pipeline_cache_path: Some(PathBuf::from("pipeline_cache.bin")),
+        }
+    }
+}
@@ -6,7 +6,10 @@ use ash::{
 };
 use log::{debug, error, info, warn};
 
-use super::{queue::Queue, utils::InternalWindow, RendererResult};
+use super::{
+    config::DeviceSelection, debug_namer::DebugNamer, error::NoSuitableDevice, queue::Queue,
+    utils::InternalWindow, RendererResult,
+};
 
 unsafe extern "system" fn vulkan_debug_utils_callback(
     message_severity: vk::DebugUtilsMessageSeverityFlagsEXT,
@@ -22,15 +25,29 @@ pub struct VulkanContext {
     _entry: ash::Entry,
     pub instance: Instance,
     pub physical_device: vk::PhysicalDevice,
+    pub physical_device_properties: vk::PhysicalDeviceProperties,
     pub device: ash::Device,
     pub max_texture_extent: vk::Extent3D, // TODO I think this should be queryable dynamically
-    pub surface: vk::SurfaceKHR,
+    // None for a headless renderer, which also has no surface capabilities, present modes
+    // or formats
+    pub surface: Option<vk::SurfaceKHR>,
     pub surface_loader: khr::Surface,
     pub surface_capabilities: vk::SurfaceCapabilitiesKHR,
     pub surface_present_modes: Vec<vk::PresentModeKHR>,
     pub surface_formats: Vec<vk::SurfaceFormatKHR>,
     pub transfer_queue: Queue,
     pub graphics_queue: Queue,
+    // A queue of a family without graphics, for async compute. None if the device only
+    // has compute on its graphics family.
+    pub compute_queue: Option<Queue>,
+    // Core since 1.2, but not every driver (e.g. older MoltenVK) exposes the feature
+    pub timeline_semaphores: bool,
+    // Color attachments of a subpass that blend differently, for weighted blended
+    // transparency
+    pub independent_blend: bool,
+    // VK_EXT_swapchain_colorspace, surfaces only offer HDR color spaces with it
+    pub swapchain_colorspace: bool,
+    pub(crate) debug_namer: DebugNamer,
     debug_utils: ext::DebugUtils,
     utils_messenger: vk::DebugUtilsMessengerEXT,
 }
@@ -42,7 +59,9 @@ impl VulkanContext {
         entry: &ash::Entry,
         layer_names: &[*const i8],
         mut debug_create_info: vk::DebugUtilsMessengerCreateInfoEXT,
-        internal_window: InternalWindow,
+        internal_window: Option<InternalWindow>,
+        swapchain_colorspace: bool,
+        debug_utils: bool,
     ) -> RendererResult<Instance> {
         // TODO Return errors
         let engine_name_c = CString::new(engine_name).unwrap();
@@ -56,35 +75,49 @@ impl VulkanContext {
             .engine_version(vk::make_api_version(0, 0, 42, 0))
             .api_version(vk::API_VERSION_1_3);
 
-        let mut instance_extension_names = vec![
-            ext::DebugUtils::name().as_ptr(),
-            khr::Surface::name().as_ptr(),
-        ];
+        let mut instance_extension_names = vec![];
+        if debug_utils {
+            instance_extension_names.push(ext::DebugUtils::name().as_ptr());
+        }
+        if internal_window.is_some() {
+            instance_extension_names.push(khr::Surface::name().as_ptr());
+        }
         match internal_window {
-            InternalWindow::WindowsWindow { .. } => {
+            Some(InternalWindow::WindowsWindow { .. }) => {
                 instance_extension_names.push(khr::Win32Surface::name().as_ptr());
             }
-            InternalWindow::LinuxWindow { is_wayland, .. } => {
+            Some(InternalWindow::LinuxWindow { is_wayland, .. }) => {
                 if is_wayland {
                     instance_extension_names.push(khr::WaylandSurface::name().as_ptr());
                 } else {
                     instance_extension_names.push(khr::XlibSurface::name().as_ptr());
                 }
             }
-            InternalWindow::MacOsWindow { .. } => {
+            Some(InternalWindow::MacOsWindow { .. }) => {
                 instance_extension_names.push(vk::ExtMetalSurfaceFn::name().as_ptr());
-                instance_extension_names.push(vk::KhrPortabilityEnumerationFn::name().as_ptr());
             }
+            None => {}
+        }
+        // MoltenVK is only enumerated with portability enumeration, with a window or without
+        if cfg!(target_os = "macos") {
+            instance_extension_names.push(vk::KhrPortabilityEnumerationFn::name().as_ptr());
+        }
+
+        if swapchain_colorspace {
+            instance_extension_names.push(vk::ExtSwapchainColorspaceFn::name().as_ptr());
         }
 
         // Create instance
         let mut instance_create_info = vk::InstanceCreateInfo::builder()
-            .push_next(&mut debug_create_info)
             .application_info(&app_info)
             .enabled_layer_names(layer_names)
             .enabled_extension_names(&instance_extension_names);
+        // Also reports messages of instance creation and destruction
+        if debug_utils {
+            instance_create_info = instance_create_info.push_next(&mut debug_create_info);
+        }
 
-        if matches!(internal_window, InternalWindow::MacOsWindow { .. }) {
+        if cfg!(target_os = "macos") {
             instance_create_info =
                 instance_create_info.flags(vk::InstanceCreateFlags::ENUMERATE_PORTABILITY_KHR);
         }
@@ -92,43 +125,182 @@ impl VulkanContext {
         unsafe { Ok(entry.create_instance(&instance_create_info, None)?) }
     }
 
+    // Devices that can be used at all get a score by type, higher is better
+    fn device_type_score(device_type: vk::PhysicalDeviceType) -> u32 {
+        match device_type {
+            vk::PhysicalDeviceType::DISCRETE_GPU => 4,
+            vk::PhysicalDeviceType::INTEGRATED_GPU => 3,
+            vk::PhysicalDeviceType::VIRTUAL_GPU => 2,
+            vk::PhysicalDeviceType::CPU => 1,
+            _ => 0,
+        }
+    }
+
+    // Why the renderer can't run on the device, None if it can
+    fn unsuitable_reason(
+        instance: &Instance,
+        physical_device: vk::PhysicalDevice,
+        surface: Option<vk::SurfaceKHR>,
+        surface_loader: &khr::Surface,
+    ) -> RendererResult<Option<String>> {
+        let extensions =
+            unsafe { instance.enumerate_device_extension_properties(physical_device)? };
+        let missing = Self::required_device_extensions()
+            .iter()
+            .filter(|required| {
+                !extensions.iter().any(|extension| {
+                    let name = unsafe { CStr::from_ptr(extension.extension_name.as_ptr()) };
+                    name == *required
+                })
+            })
+            .map(|required| required.to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        if !missing.is_empty() {
+            return Ok(Some(format!("missing {}", missing.join(", "))));
+        }
+
+        let queue_family_properties =
+            unsafe { instance.get_physical_device_queue_family_properties(physical_device) };
+        let mut has_graphics = false;
+        for (i, qfam) in queue_family_properties.iter().enumerate() {
+            if qfam.queue_count == 0 || !qfam.queue_flags.contains(vk::QueueFlags::GRAPHICS) {
+                continue;
+            }
+            has_graphics = match surface {
+                Some(surface) => unsafe {
+                    surface_loader.get_physical_device_surface_support(
+                        physical_device,
+                        i as u32,
+                        surface,
+                    )?
+                },
+                None => true,
+            };
+            if has_graphics {
+                break;
+            }
+        }
+        if !has_graphics {
+            return Ok(Some(if surface.is_some() {
+                "no graphics queue that can present to the window".to_string()
+            } else {
+                "no graphics queue".to_string()
+            }));
+        }
+
+        if let Some(surface) = surface {
+            let (_, present_modes, formats) =
+                Self::query_surface(surface_loader, physical_device, surface)?;
+            if formats.is_empty() {
+                return Ok(Some("the window surface has no formats".to_string()));
+            }
+            if present_modes.is_empty() {
+                return Ok(Some("the window surface has no present modes".to_string()));
+            }
+        }
+        Ok(None)
+    }
+
+    fn required_device_extensions() -> Vec<&'static CStr> {
+        vec![
+            khr::Swapchain::name(),
+            #[cfg(target_os = "macos")]
+            vk::KhrPortabilitySubsetFn::name(),
+        ]
+    }
+
+    // Logs every device with its score, so a surprising pick can be traced back
     fn pick_physical_device(
         instance: &Instance,
+        surface: Option<vk::SurfaceKHR>,
+        surface_loader: &khr::Surface,
+        selection: &DeviceSelection,
     ) -> RendererResult<(vk::PhysicalDevice, vk::PhysicalDeviceProperties)> {
         // Physical Device
         let phys_devs = unsafe { instance.enumerate_physical_devices()? };
 
-        let mut chosen = None;
-        for p in phys_devs {
+        let mut chosen: Option<(u32, vk::PhysicalDevice, vk::PhysicalDeviceProperties)> = None;
+        let mut reasons = vec![];
+        for (index, p) in phys_devs.into_iter().enumerate() {
             let props = unsafe { instance.get_physical_device_properties(p) };
-            info!("{:?}", props);
-            if chosen.is_none() {
-                chosen = Some((p, props));
+            debug!("{:?}", props);
+            let name = unsafe { CStr::from_ptr(props.device_name.as_ptr()) }
+                .to_string_lossy()
+                .into_owned();
+            let reason = Self::unsuitable_reason(instance, p, surface, surface_loader)?;
+            let score = Self::device_type_score(props.device_type);
+            match &reason {
+                Some(reason) => info!(
+                    "Device {}: {} ({:?}), unsuitable: {}",
+                    index, name, props.device_type, reason
+                ),
+                None => info!(
+                    "Device {}: {} ({:?}), score {}",
+                    index, name, props.device_type, score
+                ),
+            }
+
+            let selected = match selection {
+                DeviceSelection::Best => true,
+                DeviceSelection::Index(selected) => *selected == index,
+                DeviceSelection::NameContains(part) => {
+                    name.to_lowercase().contains(&part.to_lowercase())
+                }
+            };
+            if !selected {
+                continue;
+            }
+            match reason {
+                Some(reason) => reasons.push(format!("{}: {}", name, reason)),
+                // The first of equally good devices wins
+                None if chosen.as_ref().map_or(true, |(best, _, _)| score > *best) => {
+                    chosen = Some((score, p, props));
+                }
+                None => {}
+            }
+        }
+
+        match chosen {
+            Some((_, p, props)) => {
+                let name = unsafe { CStr::from_ptr(props.device_name.as_ptr()) };
+                info!("Using {:?}", name);
+                Ok((p, props))
+            }
+            None => {
+                if reasons.is_empty() && *selection != DeviceSelection::Best {
+                    reasons.push(format!("no device matches {:?}", selection));
+                }
+                Err(NoSuitableDevice(reasons).into())
             }
         }
-        chosen.ok_or_else(|| vk::Result::ERROR_UNKNOWN.into())
     }
 
     fn pick_queues(
         instance: &Instance,
         physical_device: &vk::PhysicalDevice,
-        surface: &vk::SurfaceKHR,
+        surface: Option<vk::SurfaceKHR>,
         surface_loader: &khr::Surface,
-    ) -> RendererResult<(u32, u32)> {
+    ) -> RendererResult<(u32, u32, Option<u32>)> {
         let queue_family_properties =
             unsafe { instance.get_physical_device_queue_family_properties(*physical_device) };
         let mut g_index = None;
         let mut t_index = None;
+        let mut c_index = None;
         for (i, qfam) in queue_family_properties.iter().enumerate() {
-            if qfam.queue_count > 0
-                && qfam.queue_flags.contains(vk::QueueFlags::GRAPHICS)
-                && unsafe {
+            // Without a surface there is nothing to present to
+            let can_present = match surface {
+                Some(surface) => unsafe {
                     surface_loader.get_physical_device_surface_support(
                         *physical_device,
                         i as u32,
-                        *surface,
+                        surface,
                     )?
-                }
+                },
+                None => true,
+            };
+            if qfam.queue_count > 0
+                && qfam.queue_flags.contains(vk::QueueFlags::GRAPHICS)
+                && can_present
             {
                 g_index = Some(i as u32);
             }
@@ -138,10 +310,19 @@ impl VulkanContext {
             {
                 t_index = Some(i as u32);
             }
+            // Only a family without graphics can run next to the graphics queue
+            if qfam.queue_count > 0
+                && qfam.queue_flags.contains(vk::QueueFlags::COMPUTE)
+                && !qfam.queue_flags.contains(vk::QueueFlags::GRAPHICS)
+                && c_index.is_none()
+            {
+                c_index = Some(i as u32);
+            }
         }
         Ok((
             g_index.ok_or(vk::Result::ERROR_UNKNOWN)?,
             t_index.ok_or(vk::Result::ERROR_UNKNOWN)?,
+            c_index,
         ))
     }
 
@@ -149,99 +330,113 @@ impl VulkanContext {
         instance: &Instance,
         physical_device: &vk::PhysicalDevice,
         layers: &[*const i8],
-        graphics_queue_index: u32,
-        transfer_queue_index: u32,
+        queue_families: &[u32],
+        timeline_semaphores: bool,
+        independent_blend: bool,
     ) -> RendererResult<ash::Device> {
-        let device_extension_names = [
-            ash::extensions::khr::Swapchain::name().as_ptr(),
-            #[cfg(target_os = "macos")]
-            vk::KhrPortabilitySubsetFn::name().as_ptr(),
-        ];
+        let device_extension_names = Self::required_device_extensions()
+            .iter()
+            .map(|name| name.as_ptr())
+            .collect::<Vec<_>>();
 
         // create logical device
         let priorities = [1.0f32];
-        let queue_infos = if graphics_queue_index != transfer_queue_index {
-            vec![
-                vk::DeviceQueueCreateInfo::builder()
-                    .queue_family_index(graphics_queue_index)
-                    .queue_priorities(&priorities)
-                    .build(),
+        // One queue per family, queues of the same family are shared
+        let mut families = queue_families.to_vec();
+        families.sort_unstable();
+        families.dedup();
+        let queue_infos = families
+            .iter()
+            .map(|family| {
                 vk::DeviceQueueCreateInfo::builder()
-                    .queue_family_index(transfer_queue_index)
+                    .queue_family_index(*family)
                     .queue_priorities(&priorities)
-                    .build(),
-            ]
-        } else {
-            vec![vk::DeviceQueueCreateInfo::builder()
-                .queue_family_index(graphics_queue_index)
-                .queue_priorities(&priorities)
-                .build()]
-        };
-
+                    .build()
+            })
+            .collect::<Vec<_>>();
+
+        // Light cookies are picked from an array by an index from the light buffer
+        let features = vk::PhysicalDeviceFeatures::builder()
+            .shader_sampled_image_array_dynamic_indexing(true)
+            .independent_blend(independent_blend);
         let mut indexing_features = vk::PhysicalDeviceDescriptorIndexingFeatures::builder()
             .runtime_descriptor_array(true)
             .descriptor_binding_variable_descriptor_count(true);
+        let mut timeline_features = vk::PhysicalDeviceTimelineSemaphoreFeatures::builder()
+            .timeline_semaphore(timeline_semaphores);
 
         let device_create_info = vk::DeviceCreateInfo::builder()
             .queue_create_infos(&queue_infos)
             .enabled_extension_names(&device_extension_names)
             .enabled_layer_names(layers)
-            .push_next(&mut indexing_features);
+            .enabled_features(&features)
+            .push_next(&mut indexing_features)
+            .push_next(&mut timeline_features);
         let device =
             unsafe { instance.create_device(*physical_device, &device_create_info, None)? };
         Ok(device)
     }
 
-    pub fn new(name: &str, internal_window: InternalWindow) -> RendererResult<Self> {
-        // Layers
-        let layers = unsafe {
-            [CStr::from_bytes_with_nul_unchecked(b"VK_LAYER_KHRONOS_validation\0").as_ptr()]
-        };
+    fn supports_timeline_semaphores(
+        instance: &Instance,
+        physical_device: &vk::PhysicalDevice,
+    ) -> bool {
+        let mut timeline_features = vk::PhysicalDeviceTimelineSemaphoreFeatures::default();
+        {
+            let mut features =
+                vk::PhysicalDeviceFeatures2::builder().push_next(&mut timeline_features);
+            unsafe { instance.get_physical_device_features2(*physical_device, &mut features) };
+        }
+        timeline_features.timeline_semaphore == vk::TRUE
+    }
 
-        let entry = unsafe { ash::Entry::load()? };
-        // Messenger info
-        let debug_create_info = vk::DebugUtilsMessengerCreateInfoEXT::builder()
-            .message_severity(
-                vk::DebugUtilsMessageSeverityFlagsEXT::WARNING
-                    | vk::DebugUtilsMessageSeverityFlagsEXT::VERBOSE
-                    | vk::DebugUtilsMessageSeverityFlagsEXT::INFO
-                    | vk::DebugUtilsMessageSeverityFlagsEXT::ERROR,
-            )
-            .message_type(
-                vk::DebugUtilsMessageTypeFlagsEXT::GENERAL
-                    | vk::DebugUtilsMessageTypeFlagsEXT::PERFORMANCE
-                    | vk::DebugUtilsMessageTypeFlagsEXT::VALIDATION,
-            )
-            .pfn_user_callback(Some(vulkan_debug_utils_callback));
+    fn supports_independent_blend(
+        instance: &Instance,
+        physical_device: &vk::PhysicalDevice,
+    ) -> bool {
+        let features = unsafe { instance.get_physical_device_features(*physical_device) };
+        features.independent_blend == vk::TRUE
+    }
 
-        let instance = Self::create_instance(
-            name,
-            "My Engine",
-            &entry,
-            &layers[..],
-            *debug_create_info,
-            internal_window,
-        )?;
+    // For the optional instance extensions, the renderer works without them
+    fn supports_instance_extension(entry: &ash::Entry, name: &CStr) -> bool {
+        match entry.enumerate_instance_extension_properties(None) {
+            Ok(extensions) => extensions.iter().any(|extension| {
+                let extension_name = unsafe { CStr::from_ptr(extension.extension_name.as_ptr()) };
+                extension_name == name
+            }),
+            Err(_) => false,
+        }
+    }
 
-        // Create debug messenger
-        let debug_utils = ext::DebugUtils::new(&entry, &instance);
-        let utils_messenger =
-            unsafe { debug_utils.create_debug_utils_messenger(&debug_create_info, None)? };
+    // The validation layer is only there with the Vulkan SDK installed
+    fn supports_layer(entry: &ash::Entry, name: &CStr) -> bool {
+        match entry.enumerate_instance_layer_properties() {
+            Ok(layers) => layers.iter().any(|layer| {
+                let layer_name = unsafe { CStr::from_ptr(layer.layer_name.as_ptr()) };
+                layer_name == name
+            }),
+            Err(_) => false,
+        }
+    }
 
-        // Create surface
+    fn create_surface(
+        entry: &ash::Entry,
+        instance: &Instance,
+        internal_window: InternalWindow,
+    ) -> RendererResult<vk::SurfaceKHR> {
         let surface = match internal_window {
             InternalWindow::WindowsWindow { hinstance, hwnd } => {
                 let win32_create_info = vk::Win32SurfaceCreateInfoKHR::builder()
                     .hinstance(hinstance)
                     .hwnd(hwnd);
-                let win32_surface_loader =
-                    ash::extensions::khr::Win32Surface::new(&entry, &instance);
+                let win32_surface_loader = ash::extensions::khr::Win32Surface::new(entry, instance);
                 unsafe { win32_surface_loader.create_win32_surface(&win32_create_info, None)? }
             }
             InternalWindow::MacOsWindow { layer } => {
                 let metal_create_info =
                     vk::MetalSurfaceCreateInfoEXT::builder().layer(layer as *const c_void);
-                let metal_surface_loader = ext::MetalSurface::new(&entry, &instance);
+                let metal_surface_loader = ext::MetalSurface::new(entry, instance);
                 unsafe { metal_surface_loader.create_metal_surface(&metal_create_info, None)? }
             }
             InternalWindow::LinuxWindow {
@@ -254,7 +449,7 @@ impl VulkanContext {
                         .display(display)
                         .surface(surface);
                     let wayland_surface_loader =
-                        ash::extensions::khr::WaylandSurface::new(&entry, &instance);
+                        ash::extensions::khr::WaylandSurface::new(entry, instance);
                     unsafe {
                         wayland_surface_loader.create_wayland_surface(&wayland_create_info, None)?
                     }
@@ -267,26 +462,126 @@ impl VulkanContext {
                         .window(window)
                         .dpy(display as *mut *const c_void);
                     let xlib_surface_loader =
-                        ash::extensions::khr::XlibSurface::new(&entry, &instance);
+                        ash::extensions::khr::XlibSurface::new(entry, instance);
                     unsafe { xlib_surface_loader.create_xlib_surface(&x11_create_info, None)? }
                 }
             }
         };
+        Ok(surface)
+    }
 
-        let surface_loader = ash::extensions::khr::Surface::new(&entry, &instance);
+    // Capabilities, present modes and formats
+    fn query_surface(
+        surface_loader: &khr::Surface,
+        physical_device: vk::PhysicalDevice,
+        surface: vk::SurfaceKHR,
+    ) -> RendererResult<(
+        vk::SurfaceCapabilitiesKHR,
+        Vec<vk::PresentModeKHR>,
+        Vec<vk::SurfaceFormatKHR>,
+    )> {
+        unsafe {
+            Ok((
+                surface_loader
+                    .get_physical_device_surface_capabilities(physical_device, surface)?,
+                surface_loader
+                    .get_physical_device_surface_present_modes(physical_device, surface)?,
+                surface_loader.get_physical_device_surface_formats(physical_device, surface)?,
+            ))
+        }
+    }
+
+    // Without a window the renderer is headless, there is no surface to present to
+    pub fn new(
+        name: &str,
+        internal_window: Option<InternalWindow>,
+        device_selection: &DeviceSelection,
+    ) -> RendererResult<Self> {
+        let entry = unsafe { ash::Entry::load()? };
+
+        // Layers
+        let validation_layer =
+            unsafe { CStr::from_bytes_with_nul_unchecked(b"VK_LAYER_KHRONOS_validation\0") };
+        let layers = if Self::supports_layer(&entry, validation_layer) {
+            vec![validation_layer.as_ptr()]
+        } else {
+            info!("The validation layer is not available");
+            vec![]
+        };
+        let debug_utils_supported =
+            Self::supports_instance_extension(&entry, ext::DebugUtils::name());
+        if !debug_utils_supported {
+            info!("VK_EXT_debug_utils is not available, objects won't be named");
+        }
+        // Messenger info
+        let debug_create_info = vk::DebugUtilsMessengerCreateInfoEXT::builder()
+            .message_severity(
+                vk::DebugUtilsMessageSeverityFlagsEXT::WARNING
+                    | vk::DebugUtilsMessageSeverityFlagsEXT::VERBOSE
+                    | vk::DebugUtilsMessageSeverityFlagsEXT::INFO
+                    | vk::DebugUtilsMessageSeverityFlagsEXT::ERROR,
+            )
+            .message_type(
+                vk::DebugUtilsMessageTypeFlagsEXT::GENERAL
+                    | vk::DebugUtilsMessageTypeFlagsEXT::PERFORMANCE
+                    | vk::DebugUtilsMessageTypeFlagsEXT::VALIDATION,
+            )
+            .pfn_user_callback(Some(vulkan_debug_utils_callback));
 
-        let (physical_device, _physical_device_properties) = Self::pick_physical_device(&instance)?;
-        let (graphics_queue_index, transfer_queue_index) =
-            Self::pick_queues(&instance, &physical_device, &surface, &surface_loader)?;
+        // It extends VK_KHR_surface
+        let swapchain_colorspace = internal_window.is_some()
+            && Self::supports_instance_extension(&entry, vk::ExtSwapchainColorspaceFn::name());
+        let instance = Self::create_instance(
+            name,
+            "My Engine",
+            &entry,
+            &layers[..],
+            *debug_create_info,
+            internal_window,
+            swapchain_colorspace,
+            debug_utils_supported,
+        )?;
 
+        // Create debug messenger
+        let debug_utils = ext::DebugUtils::new(&entry, &instance);
+        let utils_messenger = if debug_utils_supported {
+            unsafe { debug_utils.create_debug_utils_messenger(&debug_create_info, None)? }
+        } else {
+            vk::DebugUtilsMessengerEXT::null()
+        };
+
+        let surface = internal_window
+            .map(|internal_window| Self::create_surface(&entry, &instance, internal_window))
+            .transpose()?;
+
+        let surface_loader = ash::extensions::khr::Surface::new(&entry, &instance);
+
+        let (physical_device, physical_device_properties) =
+            Self::pick_physical_device(&instance, surface, &surface_loader, device_selection)?;
+        let (graphics_queue_index, transfer_queue_index, compute_queue_index) =
+            Self::pick_queues(&instance, &physical_device, surface, &surface_loader)?;
+        let queue_families = [
+            Some(graphics_queue_index),
+            Some(transfer_queue_index),
+            compute_queue_index,
+        ]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>();
+
+        let timeline_semaphores = Self::supports_timeline_semaphores(&instance, &physical_device);
+        let independent_blend = Self::supports_independent_blend(&instance, &physical_device);
         let device = Self::create_logical_device(
             &instance,
             &physical_device,
             &layers[..],
-            graphics_queue_index,
-            transfer_queue_index,
+            &queue_families,
+            timeline_semaphores,
+            independent_blend,
         )?;
 
+        let debug_namer = DebugNamer::new(&debug_utils, &device, debug_utils_supported);
+
         let graphics_queue = Queue {
             index: graphics_queue_index,
             queue: unsafe { device.get_device_queue(graphics_queue_index, 0) },
@@ -297,15 +592,14 @@ impl VulkanContext {
             queue: unsafe { device.get_device_queue(transfer_queue_index, 0) },
         };
 
-        // Get capabilities of the surface
-        let surface_capabilities = unsafe {
-            surface_loader.get_physical_device_surface_capabilities(physical_device, surface)?
-        };
-        let surface_present_modes = unsafe {
-            surface_loader.get_physical_device_surface_present_modes(physical_device, surface)?
-        };
-        let surface_formats = unsafe {
-            surface_loader.get_physical_device_surface_formats(physical_device, surface)?
+        let compute_queue = compute_queue_index.map(|index| Queue {
+            index,
+            queue: unsafe { device.get_device_queue(index, 0) },
+        });
+
+        let (surface_capabilities, surface_present_modes, surface_formats) = match surface {
+            Some(surface) => Self::query_surface(&surface_loader, physical_device, surface)?,
+            None => Default::default(),
         };
 
         // TODO this is only for the text atlas textures
@@ -324,6 +618,7 @@ impl VulkanContext {
             _entry: entry,
             instance,
             physical_device,
+            physical_device_properties,
             max_texture_extent: limits.max_extent,
             device,
             surface,
@@ -333,28 +628,40 @@ impl VulkanContext {
             surface_formats,
             graphics_queue,
             transfer_queue,
+            compute_queue,
+            timeline_semaphores,
+            independent_blend,
+            swapchain_colorspace,
+            debug_namer,
             debug_utils,
             utils_messenger,
         })
     }
 
     pub fn refresh_surface_data(&mut self) -> RendererResult<()> {
-        // Get capabilities of the surface
-        self.surface_capabilities = unsafe {
-            self.surface_loader
-                .get_physical_device_surface_capabilities(self.physical_device, self.surface)?
-        };
-        self.surface_present_modes = unsafe {
-            self.surface_loader
-                .get_physical_device_surface_present_modes(self.physical_device, self.surface)?
-        };
-        self.surface_formats = unsafe {
-            self.surface_loader
-                .get_physical_device_surface_formats(self.physical_device, self.surface)?
-        };
+        if let Some(surface) = self.surface {
+            (
+                self.surface_capabilities,
+                self.surface_present_modes,
+                self.surface_formats,
+            ) = Self::query_surface(&self.surface_loader, self.physical_device, surface)?;
+        }
         Ok(())
     }
 
+    // Rounds size up so that it can be used as a dynamic uniform buffer offset
+    pub fn pad_uniform_buffer_size(&self, size: usize) -> usize {
+        let alignment = self
+            .physical_device_properties
+            .limits
+            .min_uniform_buffer_offset_alignment as usize;
+        if alignment > 0 {
+            (size + alignment - 1) & !(alignment - 1)
+        } else {
+            size
+        }
+    }
+
     fn handle_debug_callback(
         &self,
         severity: vk::DebugUtilsMessageSeverityFlagsEXT,
@@ -419,10 +726,14 @@ impl VulkanContext {
 impl Drop for VulkanContext {
     fn drop(&mut self) {
         unsafe {
-            self.surface_loader.destroy_surface(self.surface, None);
+            if let Some(surface) = self.surface {
+                self.surface_loader.destroy_surface(surface, None);
+            }
             self.device.destroy_device(None);
-            self.debug_utils
-                .destroy_debug_utils_messenger(self.utils_messenger, None);
+            if self.utils_messenger != vk::DebugUtilsMessengerEXT::null() {
+                self.debug_utils
+                    .destroy_debug_utils_messenger(self.utils_messenger, None);
+            }
             self.instance.destroy_instance(None);
         }
     }
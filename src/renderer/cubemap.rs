@@ -0,0 +1,329 @@
+use ash::vk;
+use nalgebra_glm as glm;
+
+use super::{
+    descriptor::DescriptorAllocator, error::InvalidHandle, material::ComputePipelineBuilder,
+    shaders::ShaderCache, RendererResult,
+};
+
+// Conventions of the cubemap conversions:
+//
+// The array layers of a cubemap are its faces in the Vulkan order +X, -X, +Y, -Y, +Z, -Z.
+// On a face u goes right and v goes down, both from -1 to 1, and every texel holds the
+// direction Vulkan samples it for, see CubemapFace::direction.
+//
+// Equirectangular images have +Y along the top row and -Y along the bottom row, +X in
+// the middle column, +Z three quarters of the way to the right and -X at the left and
+// right edges, where longitude wraps around.
+//
+// Seams: cube texels are sampled at their centers, so neighbouring faces meet half a
+// texel from the shared edge. The equirectangular sampler repeats horizontally to filter
+// across the longitude wrap and clamps vertically at the poles, and cube sampling in
+// Vulkan always filters across face edges.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CubemapFace {
+    PositiveX,
+    NegativeX,
+    PositiveY,
+    NegativeY,
+    PositiveZ,
+    NegativeZ,
+}
+
+pub const CUBEMAP_FACES: [CubemapFace; 6] = [
+    CubemapFace::PositiveX,
+    CubemapFace::NegativeX,
+    CubemapFace::PositiveY,
+    CubemapFace::NegativeY,
+    CubemapFace::PositiveZ,
+    CubemapFace::NegativeZ,
+];
+
+impl CubemapFace {
+    pub fn layer(&self) -> u32 {
+        *self as u32
+    }
+
+    // Appended to the file names written by save_cubemap
+    pub fn suffix(&self) -> &'static str {
+        match self {
+            CubemapFace::PositiveX => "px",
+            CubemapFace::NegativeX => "nx",
+            CubemapFace::PositiveY => "py",
+            CubemapFace::NegativeY => "ny",
+            CubemapFace::PositiveZ => "pz",
+            CubemapFace::NegativeZ => "nz",
+        }
+    }
+
+    // Not normalized, the same as face_direction in equirect_to_cube.comp
+    pub fn direction(&self, uv: &glm::Vec2) -> glm::Vec3 {
+        let (u, v) = (uv.x, uv.y);
+        match self {
+            CubemapFace::PositiveX => glm::vec3(1.0, -v, -u),
+            CubemapFace::NegativeX => glm::vec3(-1.0, -v, u),
+            CubemapFace::PositiveY => glm::vec3(u, 1.0, v),
+            CubemapFace::NegativeY => glm::vec3(u, -1.0, -v),
+            CubemapFace::PositiveZ => glm::vec3(u, -v, 1.0),
+            CubemapFace::NegativeZ => glm::vec3(-u, -v, -1.0),
+        }
+    }
+
+    // The face Vulkan samples for a direction and where on it, ties go to X, then Y
+    pub fn from_direction(direction: &glm::Vec3) -> (Self, glm::Vec2) {
+        let abs = direction.abs();
+        let (face, major) = if abs.x >= abs.y && abs.x >= abs.z {
+            if direction.x >= 0.0 {
+                (CubemapFace::PositiveX, abs.x)
+            } else {
+                (CubemapFace::NegativeX, abs.x)
+            }
+        } else if abs.y >= abs.z {
+            if direction.y >= 0.0 {
+                (CubemapFace::PositiveY, abs.y)
+            } else {
+                (CubemapFace::NegativeY, abs.y)
+            }
+        } else if direction.z >= 0.0 {
+            (CubemapFace::PositiveZ, abs.z)
+        } else {
+            (CubemapFace::NegativeZ, abs.z)
+        };
+        let d = direction / major.max(f32::EPSILON);
+        let uv = match face {
+            CubemapFace::PositiveX => glm::vec2(-d.z, -d.y),
+            CubemapFace::NegativeX => glm::vec2(d.z, -d.y),
+            CubemapFace::PositiveY => glm::vec2(d.x, d.z),
+            CubemapFace::NegativeY => glm::vec2(d.x, -d.z),
+            CubemapFace::PositiveZ => glm::vec2(d.x, -d.y),
+            CubemapFace::NegativeZ => glm::vec2(-d.x, -d.y),
+        };
+        (face, uv)
+    }
+
+    // Colors of the faces in direction_test_pattern
+    pub fn test_color(&self) -> [u8; 3] {
+        match self {
+            CubemapFace::PositiveX => [255, 0, 0],
+            CubemapFace::NegativeX => [0, 255, 255],
+            CubemapFace::PositiveY => [0, 255, 0],
+            CubemapFace::NegativeY => [255, 0, 255],
+            CubemapFace::PositiveZ => [0, 0, 255],
+            CubemapFace::NegativeZ => [255, 255, 0],
+        }
+    }
+}
+
+// Texture coordinates of a direction in an equirectangular image
+pub fn equirect_uv(direction: &glm::Vec3) -> glm::Vec2 {
+    let d = direction.normalize();
+    glm::vec2(
+        0.5 + d.z.atan2(d.x) / (2.0 * std::f32::consts::PI),
+        0.5 - d.y.clamp(-1.0, 1.0).asin() / std::f32::consts::PI,
+    )
+}
+
+// Normalized direction of equirectangular texture coordinates
+pub fn equirect_direction(uv: &glm::Vec2) -> glm::Vec3 {
+    let longitude = (uv.x - 0.5) * 2.0 * std::f32::consts::PI;
+    let latitude = (0.5 - uv.y) * std::f32::consts::PI;
+    glm::vec3(
+        latitude.cos() * longitude.cos(),
+        latitude.sin(),
+        latitude.cos() * longitude.sin(),
+    )
+}
+
+// An equirectangular RGBA8 image for checking the conventions, upload it as sRGB. Every
+// direction has the test_color of the face it falls on and the top left quarter of every
+// face is darker, so after equirect_to_cubemap each face should be a single color with
+// its dark quarter in the top left corner. A wrong face, flip or rotation stands out.
+pub fn direction_test_pattern(width: u32, height: u32) -> Vec<u8> {
+    let mut data = Vec::with_capacity(width as usize * height as usize * 4);
+    for y in 0..height {
+        for x in 0..width {
+            let uv = glm::vec2(
+                (x as f32 + 0.5) / width as f32,
+                (y as f32 + 0.5) / height as f32,
+            );
+            let (face, face_uv) = CubemapFace::from_direction(&equirect_direction(&uv));
+            let [r, g, b] = face.test_color();
+            let shade = |c: u8| {
+                if face_uv.x < 0.0 && face_uv.y < 0.0 {
+                    c / 2
+                } else {
+                    c
+                }
+            };
+            data.extend_from_slice(&[shade(r), shade(g), shade(b), 255]);
+        }
+    }
+    data
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum CubemapConversion {
+    EquirectToCubemap,
+    CubemapToEquirect,
+}
+
+struct ConversionPass {
+    pipeline: vk::Pipeline,
+    pipeline_layout: vk::PipelineLayout,
+    // Rewritten for every conversion, they are waited for before the next one starts
+    descriptor_set: vk::DescriptorSet,
+}
+
+impl ConversionPass {
+    fn new(
+        device: &ash::Device,
+        shader_cache: &mut ShaderCache,
+        descriptor_allocator: &mut DescriptorAllocator,
+        name: &str,
+        code: Vec<u32>,
+    ) -> RendererResult<Self> {
+        shader_cache.add_shader_module(device, name, code)?;
+        let effect_handle = shader_cache.build_compute_effect(device, name)?;
+        let effect = shader_cache.get_shader_effect_by_handle(effect_handle)?;
+        let stage = *effect
+            .get_stages(shader_cache)?
+            .first()
+            .ok_or(InvalidHandle)?;
+        let pipeline = ComputePipelineBuilder::new(stage, effect.pipeline_layout)
+            .build_pipeline(device, shader_cache.pipeline_cache())?;
+        let descriptor_set = descriptor_allocator.allocate(device, effect.set_layouts[0])?;
+        Ok(Self {
+            pipeline,
+            pipeline_layout: effect.pipeline_layout,
+            descriptor_set,
+        })
+    }
+}
+
+// The conversion pipelines, created by the first conversion and kept until the renderer
+// is dropped
+pub(crate) struct CubemapConverter {
+    to_cubemap: ConversionPass,
+    to_equirect: ConversionPass,
+    equirect_sampler: vk::Sampler,
+    cubemap_sampler: vk::Sampler,
+}
+
+impl CubemapConverter {
+    pub fn new(
+        device: &ash::Device,
+        shader_cache: &mut ShaderCache,
+        descriptor_allocator: &mut DescriptorAllocator,
+    ) -> RendererResult<Self> {
+        let to_cubemap = ConversionPass::new(
+            device,
+            shader_cache,
+            descriptor_allocator,
+            "./shaders/equirect_to_cube.comp",
+            vk_shader_macros::include_glsl!("./shaders/equirect_to_cube.comp", kind: comp).to_vec(),
+        )?;
+        let to_equirect = ConversionPass::new(
+            device,
+            shader_cache,
+            descriptor_allocator,
+            "./shaders/cube_to_equirect.comp",
+            vk_shader_macros::include_glsl!("./shaders/cube_to_equirect.comp", kind: comp).to_vec(),
+        )?;
+
+        let equirect_sampler_info = vk::SamplerCreateInfo::builder()
+            .mag_filter(vk::Filter::LINEAR)
+            .min_filter(vk::Filter::LINEAR)
+            .address_mode_u(vk::SamplerAddressMode::REPEAT)
+            .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_EDGE);
+        let equirect_sampler = unsafe { device.create_sampler(&equirect_sampler_info, None) }?;
+        let cubemap_sampler_info = vk::SamplerCreateInfo::builder()
+            .mag_filter(vk::Filter::LINEAR)
+            .min_filter(vk::Filter::LINEAR)
+            .address_mode_u(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_EDGE);
+        let cubemap_sampler = unsafe { device.create_sampler(&cubemap_sampler_info, None) }?;
+
+        Ok(Self {
+            to_cubemap,
+            to_equirect,
+            equirect_sampler,
+            cubemap_sampler,
+        })
+    }
+
+    // The source has to be in SHADER_READ_ONLY_OPTIMAL and the target in GENERAL, the
+    // target view is the storage view of all of its layers
+    pub fn record(
+        &self,
+        device: &ash::Device,
+        command_buffer: vk::CommandBuffer,
+        conversion: CubemapConversion,
+        source_view: vk::ImageView,
+        target_view: vk::ImageView,
+        target_extent: vk::Extent2D,
+    ) {
+        let (pass, sampler, layers) = match conversion {
+            CubemapConversion::EquirectToCubemap => (&self.to_cubemap, self.equirect_sampler, 6),
+            CubemapConversion::CubemapToEquirect => (&self.to_equirect, self.cubemap_sampler, 1),
+        };
+        let source_info = [vk::DescriptorImageInfo {
+            sampler,
+            image_view: source_view,
+            image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        }];
+        let target_info = [vk::DescriptorImageInfo {
+            sampler: vk::Sampler::null(),
+            image_view: target_view,
+            image_layout: vk::ImageLayout::GENERAL,
+        }];
+        let writes = [
+            vk::WriteDescriptorSet::builder()
+                .dst_set(pass.descriptor_set)
+                .dst_binding(0)
+                .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                .image_info(&source_info)
+                .build(),
+            vk::WriteDescriptorSet::builder()
+                .dst_set(pass.descriptor_set)
+                .dst_binding(1)
+                .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
+                .image_info(&target_info)
+                .build(),
+        ];
+        unsafe {
+            device.update_descriptor_sets(&writes, &[]);
+            device.cmd_bind_pipeline(
+                command_buffer,
+                vk::PipelineBindPoint::COMPUTE,
+                pass.pipeline,
+            );
+            device.cmd_bind_descriptor_sets(
+                command_buffer,
+                vk::PipelineBindPoint::COMPUTE,
+                pass.pipeline_layout,
+                0,
+                &[pass.descriptor_set],
+                &[],
+            );
+            // 8x8 work groups, see the shaders
+            device.cmd_dispatch(
+                command_buffer,
+                (target_extent.width + 7) / 8,
+                (target_extent.height + 7) / 8,
+                layers,
+            );
+        }
+    }
+
+    // The effects and shader modules belong to the shader cache
+    pub fn destroy(&mut self, device: &ash::Device) {
+        unsafe {
+            device.destroy_pipeline(self.to_cubemap.pipeline, None);
+            device.destroy_pipeline(self.to_equirect.pipeline, None);
+            device.destroy_sampler(self.equirect_sampler, None);
+            device.destroy_sampler(self.cubemap_sampler, None);
+        }
+    }
+}
@@ -0,0 +1,258 @@
+use nalgebra_glm as glm;
+
+// How an object is tested against the view frustum
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum CullingMode {
+    // Uses the bounding box of the mesh
+    #[default]
+    Automatic,
+    // A sphere in the object's local space, for meshes whose vertices are moved in the
+    // vertex shader or whose mesh bounds are too tight or too loose
+    Sphere(glm::Vec3, f32),
+    // Always drawn, e.g. skyboxes or first person arms
+    Never,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Aabb {
+    pub min: glm::Vec3,
+    pub max: glm::Vec3,
+}
+
+impl Aabb {
+    pub fn from_points<I: IntoIterator<Item = glm::Vec3>>(points: I) -> Self {
+        let mut points = points.into_iter();
+        let first = match points.next() {
+            Some(p) => p,
+            None => return Self::default(),
+        };
+        points.fold(
+            Self {
+                min: first,
+                max: first,
+            },
+            |aabb, p| Self {
+                min: glm::min2(&aabb.min, &p),
+                max: glm::max2(&aabb.max, &p),
+            },
+        )
+    }
+
+    pub fn center(&self) -> glm::Vec3 {
+        0.5 * (self.min + self.max)
+    }
+
+    pub fn half_extents(&self) -> glm::Vec3 {
+        0.5 * (self.max - self.min)
+    }
+
+    // 0 for points inside the box
+    pub fn distance_to_point(&self, point: &glm::Vec3) -> f32 {
+        glm::distance(point, &glm::clamp_vec(point, &self.min, &self.max))
+    }
+
+    // The box around the transformed box (Arvo's method)
+    pub fn transformed(&self, transform: &glm::Mat4) -> Self {
+        let center = (transform * self.center().push(1.0)).xyz();
+        let half = self.half_extents();
+        let new_half = glm::Vec3::from_fn(|i, _| {
+            (0..3)
+                .map(|j| transform[(i, j)].abs() * half[j])
+                .sum::<f32>()
+        });
+        Self {
+            min: center - new_half,
+            max: center + new_half,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CullingStats {
+    pub drawn: usize,
+    pub culled: usize,
+    // Objects with CullingMode::Never, these are also counted in drawn
+    pub never_culled: usize,
+    // Visible objects left out because none of their layers are in the camera's mask
+    pub masked: usize,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Frustum {
+    // Normalized planes with the normals pointing inside
+    planes: [glm::Vec4; 6],
+}
+
+impl Default for Frustum {
+    // Lets everything through
+    fn default() -> Self {
+        Self {
+            planes: [glm::Vec4::new(0.0, 0.0, 0.0, 1.0); 6],
+        }
+    }
+}
+
+impl Frustum {
+    // Extracts the planes from a view projection matrix with a 0..1 depth range
+    pub fn from_view_projection(view_projection: &glm::Mat4) -> Self {
+        let row = |i: usize| -> glm::Vec4 { view_projection.row(i).transpose() };
+        let planes = [
+            row(3) + row(0),
+            row(3) - row(0),
+            row(3) + row(1),
+            row(3) - row(1),
+            row(2),
+            row(3) - row(2),
+        ]
+        .map(|p| {
+            let length = p.xyz().norm();
+            if length > 0.0 {
+                p / length
+            } else {
+                p
+            }
+        });
+        Self { planes }
+    }
+
+    // Left, right, top, bottom, near and far, as (normal, distance)
+    pub fn planes(&self) -> [glm::Vec4; 6] {
+        self.planes
+    }
+
+    fn distance(plane: &glm::Vec4, point: &glm::Vec3) -> f32 {
+        plane.xyz().dot(point) + plane.w
+    }
+
+    pub fn intersects_sphere(&self, center: &glm::Vec3, radius: f32) -> bool {
+        self.planes
+            .iter()
+            .all(|plane| Self::distance(plane, center) >= -radius)
+    }
+
+    pub fn intersects_aabb(&self, aabb: &Aabb) -> bool {
+        let center = aabb.center();
+        let half = aabb.half_extents();
+        self.planes.iter().all(|plane| {
+            let radius = half.x * plane.x.abs() + half.y * plane.y.abs() + half.z * plane.z.abs();
+            Self::distance(plane, &center) >= -radius
+        })
+    }
+}
+
+// The world space version of a CullingMode::Sphere, scaled by the largest axis scale
+pub(crate) fn world_sphere(
+    center: &glm::Vec3,
+    radius: f32,
+    transform: &glm::Mat4,
+) -> (glm::Vec3, f32) {
+    let world_center = (transform * center.push(1.0)).xyz();
+    let scale = (0..3)
+        .map(|i| glm::vec3(transform[(0, i)], transform[(1, i)], transform[(2, i)]).norm())
+        .fold(0.0f32, f32::max);
+    (world_center, radius * scale)
+}
+
+pub(crate) fn is_visible(
+    mode: &CullingMode,
+    mesh_bounds: &Aabb,
+    transform: &glm::Mat4,
+    frustum: &Frustum,
+) -> bool {
+    match mode {
+        CullingMode::Automatic => frustum.intersects_aabb(&mesh_bounds.transformed(transform)),
+        CullingMode::Sphere(center, radius) => {
+            let (center, radius) = world_sphere(center, *radius, transform);
+            frustum.intersects_sphere(&center, radius)
+        }
+        CullingMode::Never => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::renderer::camera::Camera;
+
+    // Looking at the origin from 4 in front of it
+    fn frustum() -> Frustum {
+        let mut camera = Camera::builder()
+            .position(glm::vec3(0.0, 0.0, -4.0))
+            .aspect(1.0)
+            .build();
+        camera.look_at(&glm::Vec3::zeros(), &glm::vec3(0.0, 1.0, 0.0));
+        Frustum::from_view_projection(&camera.view_projection_matrix())
+    }
+
+    fn unit_cube() -> Aabb {
+        Aabb {
+            min: glm::vec3(-1.0, -1.0, -1.0),
+            max: glm::vec3(1.0, 1.0, 1.0),
+        }
+    }
+
+    #[test]
+    fn never_mode_objects_are_drawn_outside_the_frustum() {
+        let frustum = frustum();
+        let far_away = glm::translation(&glm::vec3(100.0, 0.0, 0.0));
+        let behind = glm::translation(&glm::vec3(0.0, 0.0, -20.0));
+        assert!(is_visible(
+            &CullingMode::Automatic,
+            &unit_cube(),
+            &glm::Mat4::identity(),
+            &frustum
+        ));
+        for transform in [far_away, behind] {
+            assert!(!is_visible(
+                &CullingMode::Automatic,
+                &unit_cube(),
+                &transform,
+                &frustum
+            ));
+            assert!(is_visible(
+                &CullingMode::Never,
+                &unit_cube(),
+                &transform,
+                &frustum
+            ));
+        }
+    }
+
+    #[test]
+    fn sphere_overrides_replace_the_mesh_bounds() {
+        let frustum = frustum();
+        let far_away = glm::translation(&glm::vec3(100.0, 0.0, 0.0));
+        // Reaching back to the origin, e.g. for vertices moved there in the vertex shader
+        let reaching_back = CullingMode::Sphere(glm::vec3(-100.0, 0.0, 0.0), 1.0);
+        assert!(is_visible(
+            &reaching_back,
+            &unit_cube(),
+            &far_away,
+            &frustum
+        ));
+        let around_the_mesh = CullingMode::Sphere(glm::Vec3::zeros(), 2.0);
+        assert!(!is_visible(
+            &around_the_mesh,
+            &unit_cube(),
+            &far_away,
+            &frustum
+        ));
+        // The mesh bounds would keep this one
+        let pushed_away = CullingMode::Sphere(glm::vec3(100.0, 0.0, 0.0), 1.0);
+        assert!(!is_visible(
+            &pushed_away,
+            &unit_cube(),
+            &glm::Mat4::identity(),
+            &frustum
+        ));
+    }
+
+    #[test]
+    fn sphere_radius_follows_the_largest_scale() {
+        let transform =
+            glm::translation(&glm::vec3(1.0, 2.0, 3.0)) * glm::scaling(&glm::vec3(1.0, 3.0, 2.0));
+        let (center, radius) = world_sphere(&glm::vec3(1.0, 0.0, 0.0), 2.0, &transform);
+        assert!(glm::distance(&center, &glm::vec3(2.0, 2.0, 3.0)) < 1e-5);
+        assert!((radius - 6.0).abs() < 1e-5);
+    }
+}
@@ -0,0 +1,178 @@
+use nalgebra_glm as glm;
+
+use super::{material::Material, text::TextVertexData, texture::Texture, utils::Handle};
+
+// A texture drawn at the mouse position on top of everything else, for when the
+// system cursor is hidden, e.g. a crosshair while the mouse is grabbed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CursorConfig {
+    pub texture: Handle<Texture>,
+    // Offset of the cursor position from the top left corner of the drawn quad
+    pub hotspot_px: glm::Vec2,
+    pub size_px: glm::Vec2,
+}
+
+pub(crate) struct SoftwareCursor {
+    pub config: CursorConfig,
+    pub material: Handle<Material>,
+}
+
+// Grabbed cursors don't report a position, so their motion is accumulated into a
+// virtual position that stays inside the window instead
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CursorTracker {
+    position: glm::Vec2,
+    grabbed: bool,
+}
+
+impl CursorTracker {
+    pub fn position(&self) -> glm::Vec2 {
+        self.position
+    }
+
+    pub fn is_grabbed(&self) -> bool {
+        self.grabbed
+    }
+
+    // The virtual position starts where the cursor was when it got grabbed
+    pub fn set_grabbed(&mut self, grabbed: bool) {
+        self.grabbed = grabbed;
+    }
+
+    pub fn cursor_moved(&mut self, position: glm::Vec2) {
+        if !self.grabbed {
+            self.position = position;
+        }
+    }
+
+    pub fn mouse_motion(&mut self, delta: glm::Vec2, window_size: glm::Vec2) {
+        if self.grabbed {
+            self.position = clamp_virtual_position(&self.position, &delta, &window_size);
+        }
+    }
+
+    // Keeps the cursor inside a shrunk window
+    pub fn resized(&mut self, window_size: glm::Vec2) {
+        self.position = clamp_virtual_position(&self.position, &glm::Vec2::zeros(), &window_size);
+    }
+}
+
+pub fn clamp_virtual_position(
+    position: &glm::Vec2,
+    delta: &glm::Vec2,
+    window_size: &glm::Vec2,
+) -> glm::Vec2 {
+    let max = glm::max2(window_size, &glm::Vec2::zeros());
+    glm::clamp_vec(&(position + delta), &glm::Vec2::zeros(), &max)
+}
+
+// Top left and bottom right corners in pixels, the hotspot ends up on the position
+pub fn cursor_rect(position: &glm::Vec2, config: &CursorConfig) -> (glm::Vec2, glm::Vec2) {
+    let top_left = position - config.hotspot_px;
+    (top_left, top_left + config.size_px)
+}
+
+// Two triangles in normalized device coordinates, drawn with the cursor template
+pub(crate) fn cursor_vertices(
+    position: &glm::Vec2,
+    config: &CursorConfig,
+    screen_size: &glm::Vec2,
+) -> [TextVertexData; 6] {
+    let (top_left, bottom_right) = cursor_rect(position, config);
+    let to_ndc = |p: glm::Vec2| {
+        glm::Vec2::new(
+            2.0 * p.x / screen_size.x.max(1.0) - 1.0,
+            2.0 * p.y / screen_size.y.max(1.0) - 1.0,
+        )
+    };
+    let min = to_ndc(top_left);
+    let max = to_ndc(bottom_right);
+    let vertex = |x: f32, y: f32, u: f32, v: f32| TextVertexData {
+        position: [x, y, 0.0],
+        texture_coordinates: [u, v],
+        color: [1.0, 1.0, 1.0],
+    };
+    let v1 = vertex(min.x, min.y, 0.0, 0.0);
+    let v2 = vertex(min.x, max.y, 0.0, 1.0);
+    let v3 = vertex(max.x, min.y, 1.0, 0.0);
+    let v4 = vertex(max.x, max.y, 1.0, 1.0);
+    [v1, v2, v3, v3, v2, v4]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(hotspot: glm::Vec2) -> CursorConfig {
+        CursorConfig {
+            texture: Handle::for_test(1),
+            hotspot_px: hotspot,
+            size_px: glm::vec2(16.0, 16.0),
+        }
+    }
+
+    #[test]
+    fn virtual_position_stays_in_the_window() {
+        let window = glm::vec2(800.0, 600.0);
+        let clamp =
+            |x, y, dx, dy| clamp_virtual_position(&glm::vec2(x, y), &glm::vec2(dx, dy), &window);
+        assert_eq!(clamp(400.0, 300.0, 10.0, -20.0), glm::vec2(410.0, 280.0));
+        assert_eq!(clamp(10.0, 10.0, -50.0, -50.0), glm::vec2(0.0, 0.0));
+        assert_eq!(clamp(790.0, 590.0, 50.0, 50.0), glm::vec2(800.0, 600.0));
+        assert_eq!(clamp(400.0, 590.0, 1000.0, -5.0), glm::vec2(800.0, 585.0));
+    }
+
+    #[test]
+    fn minimized_window_pins_the_position_to_the_corner() {
+        let position = clamp_virtual_position(
+            &glm::vec2(40.0, 40.0),
+            &glm::vec2(5.0, 5.0),
+            &glm::vec2(-1.0, 0.0),
+        );
+        assert_eq!(position, glm::Vec2::zeros());
+    }
+
+    #[test]
+    fn tracker_only_accumulates_while_grabbed() {
+        let window = glm::vec2(100.0, 100.0);
+        let mut tracker = CursorTracker::default();
+        tracker.cursor_moved(glm::vec2(50.0, 60.0));
+        tracker.mouse_motion(glm::vec2(5.0, 5.0), window);
+        assert_eq!(tracker.position(), glm::vec2(50.0, 60.0));
+
+        tracker.set_grabbed(true);
+        tracker.cursor_moved(glm::vec2(0.0, 0.0));
+        tracker.mouse_motion(glm::vec2(80.0, -10.0), window);
+        assert_eq!(tracker.position(), glm::vec2(100.0, 50.0));
+
+        tracker.resized(glm::vec2(60.0, 40.0));
+        assert_eq!(tracker.position(), glm::vec2(60.0, 40.0));
+    }
+
+    #[test]
+    fn hotspot_lands_on_the_position() {
+        let position = glm::vec2(100.0, 50.0);
+        assert_eq!(
+            cursor_rect(&position, &config(glm::Vec2::zeros())),
+            (glm::vec2(100.0, 50.0), glm::vec2(116.0, 66.0))
+        );
+        assert_eq!(
+            cursor_rect(&position, &config(glm::vec2(8.0, 8.0))),
+            (glm::vec2(92.0, 42.0), glm::vec2(108.0, 58.0))
+        );
+    }
+
+    #[test]
+    fn vertices_cover_the_rect_in_ndc() {
+        let vertices = cursor_vertices(
+            &glm::vec2(16.0, 16.0),
+            &config(glm::vec2(8.0, 8.0)),
+            &glm::vec2(32.0, 32.0),
+        );
+        let corner = |i: usize| (vertices[i].position[0], vertices[i].position[1]);
+        assert_eq!(corner(0), (-0.5, -0.5));
+        assert_eq!(corner(5), (0.5, 0.5));
+        assert_eq!(vertices[0].texture_coordinates, [0.0, 0.0]);
+        assert_eq!(vertices[5].texture_coordinates, [1.0, 1.0]);
+    }
+}
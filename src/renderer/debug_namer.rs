@@ -0,0 +1,129 @@
+use std::ffi::CString;
+use std::fmt;
+
+use ash::{extensions::ext, vk};
+use log::debug;
+
+// Names Vulkan objects and labels regions of command buffers so that validation messages
+// and captures (e.g. RenderDoc) show something more useful than the raw handle. Does
+// nothing in release builds or without VK_EXT_debug_utils.
+#[derive(Clone)]
+pub(crate) struct DebugNamer {
+    debug_utils: ext::DebugUtils,
+    device: vk::Device,
+    enabled: bool,
+}
+
+impl fmt::Debug for DebugNamer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DebugNamer")
+            .field("enabled", &self.enabled)
+            .finish()
+    }
+}
+
+// What vkSetDebugUtilsObjectNameEXT is called with for the handle, None for null
+// handles and names it can't take
+fn object_name<H: vk::Handle>(handle: H, name: &str) -> Option<(vk::ObjectType, u64, CString)> {
+    let raw = handle.as_raw();
+    if raw == 0 {
+        return None;
+    }
+    Some((H::TYPE, raw, CString::new(name).ok()?))
+}
+
+impl DebugNamer {
+    pub fn new(debug_utils: &ext::DebugUtils, device: &ash::Device, supported: bool) -> Self {
+        Self {
+            debug_utils: debug_utils.clone(),
+            device: device.handle(),
+            enabled: supported && cfg!(debug_assertions),
+        }
+    }
+
+    // The object type comes from the handle type, so any handle can be passed in
+    pub fn name<H: vk::Handle>(&self, handle: H, name: &str) {
+        if !self.enabled {
+            return;
+        }
+        let (object_type, raw, name) = match object_name(handle, name) {
+            Some(object_name) => object_name,
+            None => return,
+        };
+        let name_info = vk::DebugUtilsObjectNameInfoEXT::builder()
+            .object_type(object_type)
+            .object_handle(raw)
+            .object_name(&name);
+        if let Err(e) = unsafe {
+            self.debug_utils
+                .debug_utils_set_object_name(self.device, &name_info)
+        } {
+            debug!("Unable to name {:?} {:x}: {}", object_type, raw, e);
+        }
+    }
+
+    // Groups everything recorded until the matching end_label, e.g. a pass
+    pub fn begin_label(&self, command_buffer: vk::CommandBuffer, name: &str) {
+        if !self.enabled {
+            return;
+        }
+        let name = CString::new(name).unwrap_or_default();
+        let label = vk::DebugUtilsLabelEXT::builder().label_name(&name);
+        unsafe {
+            self.debug_utils
+                .cmd_begin_debug_utils_label(command_buffer, &label)
+        };
+    }
+
+    pub fn end_label(&self, command_buffer: vk::CommandBuffer) {
+        if !self.enabled {
+            return;
+        }
+        unsafe { self.debug_utils.cmd_end_debug_utils_label(command_buffer) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ash::vk::Handle;
+
+    fn object_type<H: Handle>(handle: H) -> vk::ObjectType {
+        object_name(handle, "name").unwrap().0
+    }
+
+    #[test]
+    fn object_type_follows_the_handle_type() {
+        assert_eq!(object_type(vk::Buffer::from_raw(1)), vk::ObjectType::BUFFER);
+        assert_eq!(object_type(vk::Image::from_raw(1)), vk::ObjectType::IMAGE);
+        assert_eq!(
+            object_type(vk::ImageView::from_raw(1)),
+            vk::ObjectType::IMAGE_VIEW
+        );
+        assert_eq!(
+            object_type(vk::Pipeline::from_raw(1)),
+            vk::ObjectType::PIPELINE
+        );
+        assert_eq!(
+            object_type(vk::CommandBuffer::from_raw(1)),
+            vk::ObjectType::COMMAND_BUFFER
+        );
+        assert_eq!(
+            object_type(vk::Semaphore::from_raw(1)),
+            vk::ObjectType::SEMAPHORE
+        );
+    }
+
+    #[test]
+    fn keeps_the_handle_and_the_name() {
+        let (_, raw, name) = object_name(vk::Fence::from_raw(0x55a3), "frame 0 fence").unwrap();
+        assert_eq!(raw, 0x55a3);
+        assert_eq!(name.to_str().unwrap(), "frame 0 fence");
+    }
+
+    #[test]
+    fn skips_null_handles_and_names_with_nul() {
+        assert!(object_name(vk::Buffer::null(), "buffer").is_none());
+        assert!(object_name(vk::Buffer::from_raw(1), "buf\0fer").is_none());
+    }
+}
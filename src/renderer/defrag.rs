@@ -0,0 +1,117 @@
+use std::cmp::Reverse;
+use std::collections::VecDeque;
+
+use super::{buffer::InternalBuffer, texture::Texture, utils::Handle};
+
+// A resource Renderer::defragment can move, with the size of its allocation in bytes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum DefragCandidate {
+    Buffer(Handle<InternalBuffer>, u64),
+    Texture(Handle<Texture>, u64),
+}
+
+impl DefragCandidate {
+    fn size(&self) -> u64 {
+        match self {
+            DefragCandidate::Buffer(_, size) | DefragCandidate::Texture(_, size) => *size,
+        }
+    }
+}
+
+// What one call of Renderer::defragment did
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DefragReport {
+    pub moved_buffers: usize,
+    pub moved_textures: usize,
+    pub bytes_moved: u64,
+    // Every movable resource has been moved once, the next call starts over
+    pub finished: bool,
+}
+
+// Where the last call of Renderer::defragment stopped
+#[derive(Debug, Default)]
+pub(crate) struct DefragState {
+    pending: VecDeque<DefragCandidate>,
+    in_pass: bool,
+    // Textures bound outside of material descriptor sets, like light cookies, which would
+    // keep pointing at the old image
+    pinned: Vec<Handle<Texture>>,
+}
+
+impl DefragState {
+    pub fn in_pass(&self) -> bool {
+        self.in_pass
+    }
+
+    // Largest first, they are the hardest to place once the holes get filled
+    pub fn start(&mut self, mut candidates: Vec<DefragCandidate>) {
+        candidates.sort_by_key(|candidate| Reverse(candidate.size()));
+        self.pending = candidates.into();
+        self.in_pass = true;
+    }
+
+    // None ends the pass
+    pub fn next_candidate(&mut self) -> Option<DefragCandidate> {
+        let next = self.pending.pop_front();
+        if next.is_none() {
+            self.in_pass = false;
+        }
+        next
+    }
+
+    pub fn set_pinned(&mut self, textures: Vec<Handle<Texture>>) {
+        self.pinned = textures;
+    }
+
+    pub fn is_pinned(&self, texture: Handle<Texture>) -> bool {
+        self.pinned.contains(&texture)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buffer(id: usize, size: u64) -> DefragCandidate {
+        DefragCandidate::Buffer(Handle::for_test(id), size)
+    }
+
+    fn texture(id: usize, size: u64) -> DefragCandidate {
+        DefragCandidate::Texture(Handle::for_test(id), size)
+    }
+
+    #[test]
+    fn moves_the_largest_first() {
+        let mut state = DefragState::default();
+        state.start(vec![buffer(1, 64), texture(1, 4096), buffer(2, 1024)]);
+        assert_eq!(state.next_candidate(), Some(texture(1, 4096)));
+        assert_eq!(state.next_candidate(), Some(buffer(2, 1024)));
+        assert_eq!(state.next_candidate(), Some(buffer(1, 64)));
+    }
+
+    #[test]
+    fn pass_resumes_until_everything_moved() {
+        let mut state = DefragState::default();
+        assert!(!state.in_pass());
+        state.start((1..=100).map(|i| texture(i, i as u64 * 256)).collect());
+        let mut moved = 0;
+        // One candidate per call, like calls with a budget that runs out after one move
+        while state.in_pass() {
+            if state.next_candidate().is_some() {
+                moved += 1;
+            }
+        }
+        assert_eq!(moved, 100);
+        assert_eq!(state.next_candidate(), None);
+    }
+
+    #[test]
+    fn pinned_textures_are_remembered_until_replaced() {
+        let mut state = DefragState::default();
+        state.set_pinned(vec![Handle::for_test(3)]);
+        assert!(state.is_pinned(Handle::for_test(3)));
+        assert!(!state.is_pinned(Handle::for_test(4)));
+        state.set_pinned(vec![]);
+        assert!(!state.is_pinned(Handle::for_test(3)));
+    }
+}
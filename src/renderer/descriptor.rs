@@ -3,50 +3,23 @@ use std::collections::HashMap;
 use ash;
 use ash::vk;
 
+use super::shaders::hash_descriptor_layout_bindings;
 use super::RendererResult;
 
-#[derive(Default)]
-struct DescriptorLayoutInfo {
-    bindings: Vec<vk::DescriptorSetLayoutBinding>,
+// The parts of a vk::DescriptorSetLayoutBinding that layouts are told apart by. The
+// binding itself holds a pointer to its immutable samplers, which would keep the cache,
+// and so the renderer, from being Send.
+#[derive(PartialEq, Eq, Hash)]
+struct BindingInfo {
+    binding: u32,
+    descriptor_type: vk::DescriptorType,
+    descriptor_count: u32,
+    stage_flags: vk::ShaderStageFlags,
 }
 
-impl PartialEq for DescriptorLayoutInfo {
-    fn eq(&self, other: &Self) -> bool {
-        if self.bindings.len() != other.bindings.len() {
-            return false;
-        }
-        self.bindings
-            .iter()
-            .zip(other.bindings.iter())
-            .all(|(a, b)| {
-                if a.binding != b.binding {
-                    return false;
-                }
-                if a.descriptor_type != b.descriptor_type {
-                    return false;
-                }
-                if a.descriptor_count != b.descriptor_count {
-                    return false;
-                }
-                if a.stage_flags != b.stage_flags {
-                    return false;
-                }
-                true
-            })
-    }
-}
-
-impl Eq for DescriptorLayoutInfo {}
-
-impl std::hash::Hash for DescriptorLayoutInfo {
-    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-        for b in self.bindings.iter() {
-            b.binding.hash(state);
-            b.descriptor_type.hash(state);
-            b.descriptor_count.hash(state);
-            b.stage_flags.hash(state);
-        }
-    }
+#[derive(Default, PartialEq, Eq, Hash)]
+struct DescriptorLayoutInfo {
+    bindings: Vec<BindingInfo>,
 }
 
 #[derive(Default)]
@@ -66,7 +39,12 @@ impl DescriptorLayoutCache {
         let mut last_binding = -1i32;
         for i in 0..info.binding_count {
             let binding = unsafe { *info.p_bindings.add(i as usize) };
-            layout_info.bindings.push(binding);
+            layout_info.bindings.push(BindingInfo {
+                binding: binding.binding,
+                descriptor_type: binding.descriptor_type,
+                descriptor_count: binding.descriptor_count,
+                stage_flags: binding.stage_flags,
+            });
             if binding.binding as i32 > last_binding {
                 last_binding = binding.binding as i32;
             } else {
@@ -139,6 +117,7 @@ pub struct DescriptorAllocator {
     current_pool: vk::DescriptorPool,
     used_pools: Vec<vk::DescriptorPool>,
     free_pools: Vec<vk::DescriptorPool>,
+    allocated_sets: usize,
 }
 
 impl DescriptorAllocator {
@@ -150,17 +129,17 @@ impl DescriptorAllocator {
         }
     }
 
+    // Frees every set allocated so far, none of them may be in use anymore
     pub fn reset_pools(&mut self, device: &ash::Device) -> RendererResult<()> {
-        assert!(self.free_pools.is_empty());
         for p in self.used_pools.iter() {
             unsafe {
                 device.reset_descriptor_pool(*p, vk::DescriptorPoolResetFlags::empty())?;
             }
         }
-        self.free_pools.clear();
         for p in self.used_pools.drain(0..self.used_pools.len()) {
             self.free_pools.push(p);
         }
+        self.current_pool = vk::DescriptorPool::null();
         Ok(())
     }
 
@@ -180,6 +159,7 @@ impl DescriptorAllocator {
             .set_layouts(&layouts)
             .descriptor_pool(self.current_pool);
 
+        self.allocated_sets += 1;
         match unsafe { device.allocate_descriptor_sets(&alloc_info) } {
             Ok(sets) => Ok(sets[0]),
             Err(res) => match res {
@@ -199,6 +179,11 @@ impl DescriptorAllocator {
         }
     }
 
+    // Number of sets allocated since creation, for spotting unneeded allocations
+    pub fn allocated_set_count(&self) -> usize {
+        self.allocated_sets
+    }
+
     pub fn destroy(&mut self, device: &ash::Device) {
         for p in self.free_pools.drain(0..self.free_pools.len()) {
             unsafe {
@@ -282,6 +267,11 @@ impl<'a> DescriptorBuilder<'a> {
         self
     }
 
+    // Comparable with ShaderEffect::get_set_hash
+    pub fn layout_hash(&self) -> u64 {
+        hash_descriptor_layout_bindings(&self.bindings)
+    }
+
     pub fn build(
         &mut self,
         device: &ash::Device,
@@ -0,0 +1,246 @@
+use super::config::OpaqueSort;
+
+// Distances are quantized into buckets that double in size every this many buckets, so
+// close objects are ordered finely and far ones coarsely. Objects only change places
+// when they move into another bucket, and objects in the same bucket keep the order of
+// the scene, so the draw order stays the same from frame to frame while the camera
+// moves a little.
+pub const DISTANCE_BUCKETS_PER_DOUBLING: f32 = 4.0;
+
+// The bucket of a distance to the camera in m, 0 for anything closer than 1 m
+pub fn distance_bucket(distance: f32) -> u32 {
+    if distance.is_nan() || distance <= 1.0 {
+        return 0;
+    }
+    (distance.log2() * DISTANCE_BUCKETS_PER_DOUBLING) as u32
+}
+
+// Draws are sorted by this, see sort_draws
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DrawKey {
+    // Transparent draws go last, back to front
+    pub transparent: bool,
+    // The distance to the camera inverted, so far transparent draws sort first. Always 0
+    // for opaque draws, transparent draws at the same distance keep the order of the scene
+    pub far_first: u32,
+    pub pipeline: u64,
+    pub material: usize,
+    // Puts objects with the same mesh next to each other, so they can be instanced.
+    // Before the distance, which would split the runs of a mesh otherwise.
+    pub mesh: usize,
+    // Always 0 without OpaqueSort::FrontToBack
+    pub distance_bucket: u32,
+}
+
+impl DrawKey {
+    pub fn opaque(
+        pipeline: u64,
+        material: usize,
+        mesh: usize,
+        distance: f32,
+        sort: OpaqueSort,
+    ) -> Self {
+        Self {
+            transparent: false,
+            far_first: 0,
+            pipeline,
+            material,
+            mesh,
+            distance_bucket: match sort {
+                OpaqueSort::None => 0,
+                OpaqueSort::FrontToBack => distance_bucket(distance),
+            },
+        }
+    }
+
+    // distance is to the center of the object, the closest point of large transparent
+    // objects around the camera would put them in front of everything inside them
+    pub fn transparent(distance: f32) -> Self {
+        // The bits of non negative floats order like the floats
+        let distance = if distance.is_nan() {
+            0.0
+        } else {
+            distance.max(0.0)
+        };
+        Self {
+            transparent: true,
+            far_first: u32::MAX - distance.to_bits(),
+            ..Default::default()
+        }
+    }
+}
+
+// How finely the opaque draws of the last frame were sorted
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DrawSortStats {
+    // Opaque draws with the same pipeline and material
+    pub material_buckets: usize,
+    // Runs of draws in the same distance bucket summed over all material buckets, the
+    // same as material_buckets without OpaqueSort::FrontToBack
+    pub distance_buckets: usize,
+    // Draws that cover several objects with the same mesh and material, and the objects
+    // they cover
+    pub instanced_draws: usize,
+    pub instanced_objects: usize,
+}
+
+impl DrawSortStats {
+    pub fn average_distance_buckets(&self) -> f32 {
+        if self.material_buckets == 0 {
+            return 0.0;
+        }
+        self.distance_buckets as f32 / self.material_buckets as f32
+    }
+}
+
+// Groups the draws by pipeline and material so they are bound as rarely as possible, then
+// by mesh so they can be instanced, and sorts the opaque draws of every mesh by their
+// distance bucket. The sort is stable, so draws with the same key stay in the order they
+// were added.
+pub fn sort_draws<T>(draws: &mut [(DrawKey, T)]) -> DrawSortStats {
+    draws.sort_by_key(|(key, _)| *key);
+    let mut stats = DrawSortStats::default();
+    let mut previous: Option<DrawKey> = None;
+    for (key, _) in draws.iter().filter(|(key, _)| !key.transparent) {
+        match previous {
+            Some(p) if p.pipeline == key.pipeline && p.material == key.material => {
+                if p.distance_bucket != key.distance_bucket {
+                    stats.distance_buckets += 1;
+                }
+            }
+            _ => {
+                stats.material_buckets += 1;
+                stats.distance_buckets += 1;
+            }
+        }
+        previous = Some(*key);
+    }
+    stats
+}
+
+// A draw of the Transparency pass, where transparent objects and the billboards of the
+// particle emitters blend in one order
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransparentDraw<O, E> {
+    Object(O),
+    Emitter(E),
+}
+
+// Both lists have DrawKey::transparent keys. Objects go first at the same distance, so
+// particles stay over the surface they are emitted from.
+pub fn merge_transparent<O: Copy, E: Copy>(
+    objects: &[(DrawKey, O)],
+    emitters: &[(DrawKey, E)],
+) -> Vec<TransparentDraw<O, E>> {
+    let mut draws = objects
+        .iter()
+        .map(|(key, object)| (*key, TransparentDraw::Object(*object)))
+        .chain(
+            emitters
+                .iter()
+                .map(|(key, emitter)| (*key, TransparentDraw::Emitter(*emitter))),
+        )
+        .collect::<Vec<_>>();
+    draws.sort_by_key(|(key, _)| *key);
+    draws.into_iter().map(|(_, draw)| draw).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn distance_buckets_grow_with_distance() {
+        assert_eq!(distance_bucket(0.5), 0);
+        assert_eq!(distance_bucket(f32::NAN), 0);
+        assert_eq!(distance_bucket(2.0), 4);
+        assert_eq!(distance_bucket(4.0), 8);
+        assert!(distance_bucket(100.0) < distance_bucket(200.0));
+    }
+
+    #[test]
+    fn front_to_back_keeps_meshes_together() {
+        let sort = OpaqueSort::FrontToBack;
+        let mut draws = vec![
+            (DrawKey::opaque(1, 1, 10, 2.0, sort), 0),
+            (DrawKey::opaque(1, 1, 20, 3.0, sort), 1),
+            (DrawKey::opaque(1, 1, 10, 50.0, sort), 2),
+            (DrawKey::opaque(1, 1, 20, 1.0, sort), 3),
+            (DrawKey::opaque(1, 1, 10, 8.0, sort), 4),
+        ];
+        sort_draws(&mut draws);
+        let order = draws.iter().map(|(_, i)| *i).collect::<Vec<_>>();
+        assert_eq!(order, vec![0, 4, 2, 3, 1]);
+    }
+
+    #[test]
+    fn transparent_draws_go_last_back_to_front() {
+        let mut draws = vec![
+            (DrawKey::transparent(1.0), 0),
+            (DrawKey::opaque(2, 1, 1, 5.0, OpaqueSort::None), 1),
+            (DrawKey::transparent(10.0), 2),
+            (DrawKey::opaque(1, 1, 1, 5.0, OpaqueSort::None), 3),
+        ];
+        let stats = sort_draws(&mut draws);
+        let order = draws.iter().map(|(_, i)| *i).collect::<Vec<_>>();
+        assert_eq!(order, vec![3, 1, 2, 0]);
+        assert_eq!(stats.material_buckets, 2);
+        assert_eq!(stats.distance_buckets, 2);
+    }
+
+    #[test]
+    fn transparent_draws_at_the_same_distance_keep_their_order() {
+        let mut draws = vec![
+            (DrawKey::transparent(3.0), 0),
+            (DrawKey::transparent(12.0), 1),
+            (DrawKey::opaque(1, 2, 1, 20.0, OpaqueSort::FrontToBack), 2),
+            (DrawKey::transparent(7.0), 3),
+            (DrawKey::transparent(7.0), 4),
+        ];
+        let stats = sort_draws(&mut draws);
+        let order = draws.iter().map(|(_, i)| *i).collect::<Vec<_>>();
+        assert_eq!(order, vec![2, 1, 3, 4, 0]);
+        assert_eq!(stats.material_buckets, 1);
+    }
+
+    #[test]
+    fn stats_count_runs_of_distance_buckets() {
+        let sort = OpaqueSort::FrontToBack;
+        let mut draws = vec![
+            (DrawKey::opaque(1, 1, 10, 2.0, sort), ()),
+            (DrawKey::opaque(1, 1, 10, 2.0, sort), ()),
+            (DrawKey::opaque(1, 1, 10, 50.0, sort), ()),
+            (DrawKey::opaque(1, 1, 20, 2.0, sort), ()),
+        ];
+        let stats = sort_draws(&mut draws);
+        assert_eq!(stats.material_buckets, 1);
+        assert_eq!(stats.distance_buckets, 3);
+        assert_eq!(stats.average_distance_buckets(), 3.0);
+    }
+
+    #[test]
+    fn emitters_sort_between_transparent_objects() {
+        let objects = [
+            (DrawKey::transparent(10.0), 'a'),
+            (DrawKey::transparent(4.0), 'b'),
+            (DrawKey::transparent(1.0), 'c'),
+        ];
+        let emitters = [
+            (DrawKey::transparent(20.0), 0),
+            (DrawKey::transparent(4.0), 1),
+            (DrawKey::transparent(2.0), 2),
+        ];
+        assert_eq!(
+            merge_transparent(&objects, &emitters),
+            vec![
+                TransparentDraw::Emitter(0),
+                TransparentDraw::Object('a'),
+                TransparentDraw::Object('b'),
+                TransparentDraw::Emitter(1),
+                TransparentDraw::Emitter(2),
+                TransparentDraw::Object('c'),
+            ]
+        );
+        assert!(merge_transparent::<char, u32>(&[], &[]).is_empty());
+    }
+}
@@ -86,6 +86,450 @@ impl From<String> for MissingTemplate {
     }
 }
 
+#[derive(Debug, Clone)]
+pub struct SceneLoadError(pub String);
+
+impl fmt::Display for SceneLoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "scene load error: {}", self.0)
+    }
+}
+
+impl error::Error for SceneLoadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        None
+    }
+}
+
+impl From<String> for SceneLoadError {
+    fn from(value: String) -> Self {
+        Self(value)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct GltfError(pub String);
+
+impl fmt::Display for GltfError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "glTF error: {}", self.0)
+    }
+}
+
+impl error::Error for GltfError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        None
+    }
+}
+
+impl From<String> for GltfError {
+    fn from(value: String) -> Self {
+        Self(value)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct UnsupportedWindowHandle(pub &'static str);
+
+impl fmt::Display for UnsupportedWindowHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "window error: {}", self.0)
+    }
+}
+
+impl error::Error for UnsupportedWindowHandle {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        None
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct IncompatibleMaterial(pub String);
+
+impl fmt::Display for IncompatibleMaterial {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "incompatible material: {}", self.0)
+    }
+}
+
+impl error::Error for IncompatibleMaterial {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        None
+    }
+}
+
+impl From<String> for IncompatibleMaterial {
+    fn from(value: String) -> Self {
+        Self(value)
+    }
+}
+
+// A material file that can't be used, the message names the file and the offending key
+#[derive(Debug, Clone)]
+pub struct MaterialFileError(pub String);
+
+impl fmt::Display for MaterialFileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "material file error: {}", self.0)
+    }
+}
+
+impl error::Error for MaterialFileError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        None
+    }
+}
+
+impl From<String> for MaterialFileError {
+    fn from(value: String) -> Self {
+        Self(value)
+    }
+}
+
+// A settings file that can't be read or written, the message names the file
+#[derive(Debug, Clone)]
+pub struct SettingsFileError(pub String);
+
+impl fmt::Display for SettingsFileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "settings file error: {}", self.0)
+    }
+}
+
+impl error::Error for SettingsFileError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        None
+    }
+}
+
+impl From<String> for SettingsFileError {
+    fn from(value: String) -> Self {
+        Self(value)
+    }
+}
+
+// A scene file that can't be read or written, the message names the file
+#[derive(Debug, Clone)]
+pub struct SceneFileError(pub String);
+
+impl fmt::Display for SceneFileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "scene file error: {}", self.0)
+    }
+}
+
+impl error::Error for SceneFileError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        None
+    }
+}
+
+impl From<String> for SceneFileError {
+    fn from(value: String) -> Self {
+        Self(value)
+    }
+}
+
+// Lists every binding of an effect that the renderer would not bind
+#[derive(Debug, Clone)]
+pub struct BindingContractError(pub String);
+
+impl fmt::Display for BindingContractError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "binding contract error: {}", self.0)
+    }
+}
+
+impl error::Error for BindingContractError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        None
+    }
+}
+
+impl From<String> for BindingContractError {
+    fn from(value: String) -> Self {
+        Self(value)
+    }
+}
+
+// A texture can't be destroyed while materials use it, lists every name of those materials
+#[derive(Debug, Clone)]
+pub struct TextureInUse {
+    pub material_names: Vec<String>,
+}
+
+impl fmt::Display for TextureInUse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "texture is still used by: {}",
+            self.material_names.join(", ")
+        )
+    }
+}
+
+impl error::Error for TextureInUse {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        None
+    }
+}
+
+impl From<Vec<String>> for TextureInUse {
+    fn from(material_names: Vec<String>) -> Self {
+        Self { material_names }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CubemapError(pub String);
+
+impl fmt::Display for CubemapError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "cubemap error: {}", self.0)
+    }
+}
+
+impl error::Error for CubemapError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        None
+    }
+}
+
+impl From<String> for CubemapError {
+    fn from(value: String) -> Self {
+        Self(value)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ScheduleError(pub String);
+
+impl fmt::Display for ScheduleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "render schedule error: {}", self.0)
+    }
+}
+
+impl error::Error for ScheduleError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        None
+    }
+}
+
+impl From<String> for ScheduleError {
+    fn from(value: String) -> Self {
+        Self(value)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FrameError(pub String);
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "frame error: {}", self.0)
+    }
+}
+
+impl error::Error for FrameError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        None
+    }
+}
+
+impl From<String> for FrameError {
+    fn from(value: String) -> Self {
+        Self(value)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ObjectParamsError(pub String);
+
+impl fmt::Display for ObjectParamsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "object parameter error: {}", self.0)
+    }
+}
+
+impl error::Error for ObjectParamsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        None
+    }
+}
+
+impl From<String> for ObjectParamsError {
+    fn from(value: String) -> Self {
+        Self(value)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MorphTargetError(pub String);
+
+impl fmt::Display for MorphTargetError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "morph target error: {}", self.0)
+    }
+}
+
+impl error::Error for MorphTargetError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        None
+    }
+}
+
+impl From<String> for MorphTargetError {
+    fn from(value: String) -> Self {
+        Self(value)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ShaderReloadError(pub String);
+
+impl fmt::Display for ShaderReloadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "shader reload error: {}", self.0)
+    }
+}
+
+impl error::Error for ShaderReloadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        None
+    }
+}
+
+impl From<String> for ShaderReloadError {
+    fn from(value: String) -> Self {
+        Self(value)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ComputeDispatchError(pub String);
+
+impl fmt::Display for ComputeDispatchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "compute dispatch error: {}", self.0)
+    }
+}
+
+impl error::Error for ComputeDispatchError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        None
+    }
+}
+
+impl From<String> for ComputeDispatchError {
+    fn from(value: String) -> Self {
+        Self(value)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TransferError(pub String);
+
+impl fmt::Display for TransferError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "transfer error: {}", self.0)
+    }
+}
+
+impl error::Error for TransferError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        None
+    }
+}
+
+impl From<String> for TransferError {
+    fn from(value: String) -> Self {
+        Self(value)
+    }
+}
+
+// A write through a buffer's mapping that doesn't fit, the buffer is left untouched
+#[derive(Debug, Clone, Copy)]
+pub struct OutOfBoundsWrite {
+    pub offset: usize,
+    pub len: usize,
+    pub size: u64,
+}
+
+impl fmt::Display for OutOfBoundsWrite {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "out of bounds write: {} bytes at offset {} into a buffer of {} bytes",
+            self.len, self.offset, self.size
+        )
+    }
+}
+
+impl error::Error for OutOfBoundsWrite {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        None
+    }
+}
+
+// A buffer's memory that can't be written or read from the CPU
+#[derive(Debug, Clone)]
+pub struct BufferAccessError(pub String);
+
+impl fmt::Display for BufferAccessError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "buffer access error: {}", self.0)
+    }
+}
+
+impl error::Error for BufferAccessError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        None
+    }
+}
+
+impl From<String> for BufferAccessError {
+    fn from(value: String) -> Self {
+        Self(value)
+    }
+}
+
+// Why each physical device was rejected, one reason per device
+#[derive(Debug, Clone)]
+pub struct NoSuitableDevice(pub Vec<String>);
+
+impl fmt::Display for NoSuitableDevice {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.0.is_empty() {
+            write!(f, "no suitable device: no Vulkan devices found")
+        } else {
+            write!(f, "no suitable device: {}", self.0.join("; "))
+        }
+    }
+}
+
+impl error::Error for NoSuitableDevice {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        None
+    }
+}
+
+// The allocator's mutex is poisoned, something panicked while it was locked
+#[derive(Debug, Clone, Copy)]
+pub struct AllocatorUnavailable;
+
+impl fmt::Display for AllocatorUnavailable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "allocator unavailable: its lock is poisoned")
+    }
+}
+
+impl error::Error for AllocatorUnavailable {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        None
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum RendererError {
     #[error("Unable to load Vulkan")]
@@ -112,6 +556,12 @@ pub enum RendererError {
         source: InvalidHandle,
         backtrace: Backtrace,
     },
+    #[error("Unable to decode image")]
+    ImageError {
+        #[from]
+        source: image::ImageError,
+        backtrace: Backtrace,
+    },
     #[error("IO Error")]
     IoError {
         #[from]
@@ -148,6 +598,132 @@ pub enum RendererError {
         source: MissingTemplate,
         backtrace: Backtrace,
     },
+    #[error("Error loading scene")]
+    SceneLoadError {
+        #[from]
+        source: SceneLoadError,
+        backtrace: Backtrace,
+    },
+    #[error("Error loading glTF")]
+    GltfError {
+        #[from]
+        source: GltfError,
+        backtrace: Backtrace,
+    },
+    #[error("Unsupported window handle")]
+    UnsupportedWindowHandle {
+        #[from]
+        source: UnsupportedWindowHandle,
+        backtrace: Backtrace,
+    },
+    #[error("Incompatible material")]
+    IncompatibleMaterial {
+        #[from]
+        source: IncompatibleMaterial,
+        backtrace: Backtrace,
+    },
+    #[error("Invalid material file")]
+    MaterialFileError {
+        #[from]
+        source: MaterialFileError,
+        backtrace: Backtrace,
+    },
+    #[error("Invalid settings file")]
+    SettingsFileError {
+        #[from]
+        source: SettingsFileError,
+        backtrace: Backtrace,
+    },
+    #[error("Invalid scene file")]
+    SceneFileError {
+        #[from]
+        source: SceneFileError,
+        backtrace: Backtrace,
+    },
+    #[error("Shader bindings don't match what the renderer binds")]
+    BindingContractError {
+        #[from]
+        source: BindingContractError,
+        backtrace: Backtrace,
+    },
+    #[error("Texture is still in use")]
+    TextureInUse {
+        #[from]
+        source: TextureInUse,
+        backtrace: Backtrace,
+    },
+    #[error("Cubemap conversion error")]
+    CubemapError {
+        #[from]
+        source: CubemapError,
+        backtrace: Backtrace,
+    },
+    #[error("Invalid render schedule")]
+    ScheduleError {
+        #[from]
+        source: ScheduleError,
+        backtrace: Backtrace,
+    },
+    #[error("Frame begun or ended out of order")]
+    FrameError {
+        #[from]
+        source: FrameError,
+        backtrace: Backtrace,
+    },
+    #[error("Invalid object parameters")]
+    ObjectParamsError {
+        #[from]
+        source: ObjectParamsError,
+        backtrace: Backtrace,
+    },
+    #[error("Invalid morph targets")]
+    MorphTargetError {
+        #[from]
+        source: MorphTargetError,
+        backtrace: Backtrace,
+    },
+    #[error("Unable to reload the shaders")]
+    ShaderReloadError {
+        #[from]
+        source: ShaderReloadError,
+        backtrace: Backtrace,
+    },
+    #[error("Invalid compute dispatch")]
+    ComputeDispatchError {
+        #[from]
+        source: ComputeDispatchError,
+        backtrace: Backtrace,
+    },
+    #[error("Unable to write a buffer on the transfer queue")]
+    TransferError {
+        #[from]
+        source: TransferError,
+        backtrace: Backtrace,
+    },
+    #[error("Write past the end of a buffer")]
+    OutOfBoundsWrite {
+        #[from]
+        source: OutOfBoundsWrite,
+        backtrace: Backtrace,
+    },
+    #[error("Unable to access a buffer from the CPU")]
+    BufferAccessError {
+        #[from]
+        source: BufferAccessError,
+        backtrace: Backtrace,
+    },
+    #[error("No suitable device")]
+    NoSuitableDevice {
+        #[from]
+        source: NoSuitableDevice,
+        backtrace: Backtrace,
+    },
+    #[error("Allocator unavailable")]
+    AllocatorUnavailable {
+        #[from]
+        source: AllocatorUnavailable,
+        backtrace: Backtrace,
+    },
     #[error("Imgui Render Error")]
     ImguiRenderError {
         #[from]
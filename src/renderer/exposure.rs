@@ -0,0 +1,337 @@
+use std::sync::{Arc, Mutex};
+
+use ash::vk;
+use gpu_allocator::{vulkan::Allocator, MemoryLocation};
+
+use super::{
+    buffer::{Buffer, BufferManager},
+    descriptor::DescriptorAllocator,
+    error::InvalidHandle,
+    material::ComputePipelineBuilder,
+    readback::ReadbackRing,
+    shaders::ShaderCache,
+    RendererResult,
+};
+
+// How the exposure follows the luminance of the scene
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AutoExposureConfig {
+    // The average luminance the exposure aims for, middle grey by default
+    pub target_luminance: f32,
+    // How fast the exposure adapts, per second. After 1 / speed seconds about two thirds
+    // of a change in brightness are compensated.
+    pub speed: f32,
+    pub min_exposure: f32,
+    pub max_exposure: f32,
+}
+
+impl Default for AutoExposureConfig {
+    fn default() -> Self {
+        Self {
+            target_luminance: 0.18,
+            speed: 1.5,
+            min_exposure: 0.25,
+            max_exposure: 4.0,
+        }
+    }
+}
+
+// Moves the exposure from current toward the one that brings the measured luminance to
+// the target. Works on the logarithm, so brightening and darkening by the same factor
+// take the same time, and doesn't depend on the frame rate.
+pub fn adapt_exposure(
+    current: f32,
+    luminance: f32,
+    config: &AutoExposureConfig,
+    delta_seconds: f32,
+) -> f32 {
+    let min = config.min_exposure.max(f32::EPSILON);
+    let max = config.max_exposure.max(min);
+    // The luminance was measured before the exposure is applied
+    let wanted = (config.target_luminance / luminance.max(f32::EPSILON)).clamp(min, max);
+    let current = current.clamp(min, max);
+    let blend = 1.0 - (-config.speed.max(0.0) * delta_seconds.max(0.0)).exp();
+    let log_exposure = current.ln() + (wanted.ln() - current.ln()) * blend;
+    log_exposure.exp().clamp(min, max)
+}
+
+// Reduces the scene color to its average luminance every frame and reads the results
+// back through a ReadbackRing. Created when auto exposure is first turned on and kept
+// until the renderer is dropped.
+pub(crate) struct LuminancePass {
+    pipeline: vk::Pipeline,
+    pipeline_layout: vk::PipelineLayout,
+    sampler: vk::Sampler,
+    // One result buffer and descriptor set per frame in flight, so a frame never writes
+    // what an earlier one is still copying
+    results: Vec<Buffer>,
+    descriptor_sets: Vec<vk::DescriptorSet>,
+    readback: ReadbackRing,
+    source_view: vk::ImageView,
+}
+
+impl LuminancePass {
+    pub fn new(
+        device: &ash::Device,
+        allocator: &mut Allocator,
+        buffer_manager: Arc<Mutex<BufferManager>>,
+        shader_cache: &mut ShaderCache,
+        descriptor_allocator: &mut DescriptorAllocator,
+        frames_in_flight: usize,
+    ) -> RendererResult<Self> {
+        let name = "./shaders/luminance.comp";
+        shader_cache.add_shader_module(
+            device,
+            name,
+            vk_shader_macros::include_glsl!("./shaders/luminance.comp", kind: comp).to_vec(),
+        )?;
+        let effect_handle = shader_cache.build_compute_effect(device, name)?;
+        let effect = shader_cache.get_shader_effect_by_handle(effect_handle)?;
+        let stage = *effect
+            .get_stages(shader_cache)?
+            .first()
+            .ok_or(InvalidHandle)?;
+        let pipeline = ComputePipelineBuilder::new(stage, effect.pipeline_layout)
+            .build_pipeline(device, shader_cache.pipeline_cache())?;
+
+        let sampler_info = vk::SamplerCreateInfo::builder()
+            .mag_filter(vk::Filter::LINEAR)
+            .min_filter(vk::Filter::LINEAR)
+            .address_mode_u(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_EDGE);
+        let sampler = unsafe { device.create_sampler(&sampler_info, None) }?;
+
+        let result_size = std::mem::size_of::<f32>() as u64;
+        let mut results = Vec::with_capacity(frames_in_flight);
+        let mut descriptor_sets = Vec::with_capacity(frames_in_flight);
+        for _ in 0..frames_in_flight {
+            results.push(BufferManager::new_buffer(
+                buffer_manager.clone(),
+                device,
+                allocator,
+                result_size,
+                vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::TRANSFER_SRC,
+                MemoryLocation::GpuOnly,
+                "scene-luminance",
+            )?);
+            descriptor_sets.push(descriptor_allocator.allocate(device, effect.set_layouts[0])?);
+        }
+        let readback = ReadbackRing::new(
+            device,
+            allocator,
+            buffer_manager,
+            result_size,
+            frames_in_flight,
+            "scene-luminance-readback",
+        )?;
+
+        Ok(Self {
+            pipeline,
+            pipeline_layout: effect.pipeline_layout,
+            sampler,
+            results,
+            descriptor_sets,
+            readback,
+            source_view: vk::ImageView::null(),
+        })
+    }
+
+    // Points the descriptor sets at a new scene color. None of the frames in flight may
+    // use them anymore.
+    pub fn set_source(&mut self, device: &ash::Device, source_view: vk::ImageView) {
+        if self.source_view == source_view {
+            return;
+        }
+        self.source_view = source_view;
+        let source_info = [vk::DescriptorImageInfo {
+            sampler: self.sampler,
+            image_view: source_view,
+            image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        }];
+        for (set, result) in self.descriptor_sets.iter().zip(self.results.iter()) {
+            let result_info = [vk::DescriptorBufferInfo {
+                buffer: result.get_buffer().buffer,
+                offset: 0,
+                range: vk::WHOLE_SIZE,
+            }];
+            let writes = [
+                vk::WriteDescriptorSet::builder()
+                    .dst_set(*set)
+                    .dst_binding(0)
+                    .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                    .image_info(&source_info)
+                    .build(),
+                vk::WriteDescriptorSet::builder()
+                    .dst_set(*set)
+                    .dst_binding(1)
+                    .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                    .buffer_info(&result_info)
+                    .build(),
+            ];
+            unsafe { device.update_descriptor_sets(&writes, &[]) };
+        }
+        // Measured on the old target
+        self.readback.clear();
+    }
+
+    // The scene color has to be in SHADER_READ_ONLY_OPTIMAL and visible to compute
+    // shaders, the scene render pass does both
+    pub fn record(
+        &mut self,
+        device: &ash::Device,
+        command_buffer: vk::CommandBuffer,
+        slot: usize,
+        frame: u64,
+    ) {
+        let result = self.results[slot].get_buffer().buffer;
+        let barrier = vk::BufferMemoryBarrier::builder()
+            .buffer(result)
+            .offset(0)
+            .size(vk::WHOLE_SIZE)
+            .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+            .dst_access_mask(vk::AccessFlags::TRANSFER_READ)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .build();
+        unsafe {
+            device.cmd_bind_pipeline(
+                command_buffer,
+                vk::PipelineBindPoint::COMPUTE,
+                self.pipeline,
+            );
+            device.cmd_bind_descriptor_sets(
+                command_buffer,
+                vk::PipelineBindPoint::COMPUTE,
+                self.pipeline_layout,
+                0,
+                &[self.descriptor_sets[slot]],
+                &[],
+            );
+            // A single work group, see luminance.comp
+            device.cmd_dispatch(command_buffer, 1, 1, 1);
+            device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[barrier],
+                &[],
+            );
+        }
+        self.readback
+            .record_copy(device, command_buffer, result, slot, frame);
+    }
+
+    // The average luminance measured by the frame that last used the slot, once that
+    // frame is finished
+    pub fn read(&mut self, slot: usize) -> RendererResult<Option<f32>> {
+        Ok(self
+            .readback
+            .read::<f32>(slot, 1)?
+            .map(|(_, average_log)| average_log[0].exp()))
+    }
+
+    // Results of frames recorded before auto exposure was turned off are stale
+    pub fn clear(&mut self) {
+        self.readback.clear();
+    }
+
+    // The effect and shader module belong to the shader cache, the descriptor sets to
+    // the descriptor allocator
+    pub fn destroy(&mut self, device: &ash::Device) -> RendererResult<()> {
+        unsafe {
+            device.destroy_pipeline(self.pipeline, None);
+            device.destroy_sampler(self.sampler, None);
+        }
+        for result in self.results.iter_mut() {
+            result.queue_free()?;
+        }
+        self.readback.destroy()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The exposure after following the luminance of each frame, from an exposure of 1
+    fn follow(luminances: &[f32], config: &AutoExposureConfig, delta_seconds: f32) -> Vec<f32> {
+        luminances
+            .iter()
+            .scan(1.0, |exposure, luminance| {
+                *exposure = adapt_exposure(*exposure, *luminance, config, delta_seconds);
+                Some(*exposure)
+            })
+            .collect()
+    }
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-4
+    }
+
+    #[test]
+    fn settles_on_the_exposure_that_reaches_the_target() {
+        let config = AutoExposureConfig::default();
+        // A room twice as bright as middle grey
+        let exposures = follow(&[0.36; 600], &config, 1.0 / 60.0);
+        assert!(exposures.windows(2).all(|w| w[1] <= w[0]));
+        assert!(close(*exposures.last().unwrap(), 0.5));
+    }
+
+    #[test]
+    fn follows_a_change_in_brightness() {
+        let config = AutoExposureConfig::default();
+        let mut luminances = vec![0.18; 300];
+        luminances.extend([0.09; 600]);
+        let exposures = follow(&luminances, &config, 1.0 / 60.0);
+        assert!(close(exposures[299], 1.0));
+        // About two thirds of the way after 1 / speed seconds, on the log scale
+        let after = exposures[299 + 40];
+        assert!(close(after.log2(), 1.0 - (-1.0f32).exp()), "{}", after);
+        assert!(close(*exposures.last().unwrap(), 2.0));
+    }
+
+    #[test]
+    fn does_not_depend_on_the_frame_rate() {
+        let config = AutoExposureConfig::default();
+        let at_30 = follow(&[0.05; 30], &config, 1.0 / 30.0);
+        let at_144 = follow(&[0.05; 144], &config, 1.0 / 144.0);
+        assert!(close(*at_30.last().unwrap(), *at_144.last().unwrap()));
+    }
+
+    #[test]
+    fn brightening_and_darkening_take_as_long() {
+        let config = AutoExposureConfig::default();
+        let darker = adapt_exposure(1.0, 0.36, &config, 0.5);
+        let brighter = adapt_exposure(1.0, 0.09, &config, 0.5);
+        assert!(close(darker.ln(), -brighter.ln()));
+    }
+
+    #[test]
+    fn stays_inside_the_limits() {
+        let config = AutoExposureConfig::default();
+        let black = follow(&[0.0; 600], &config, 1.0 / 60.0);
+        assert!(close(*black.last().unwrap(), config.max_exposure));
+        let sun = follow(&[1000.0; 600], &config, 1.0 / 60.0);
+        assert!(close(*sun.last().unwrap(), config.min_exposure));
+        assert!(black
+            .iter()
+            .chain(sun.iter())
+            .all(|e| (config.min_exposure..=config.max_exposure).contains(e)));
+    }
+
+    #[test]
+    fn no_speed_or_time_keeps_the_exposure() {
+        let still = AutoExposureConfig {
+            speed: 0.0,
+            ..Default::default()
+        };
+        assert_eq!(adapt_exposure(2.0, 0.01, &still, 1.0), 2.0);
+        assert_eq!(
+            adapt_exposure(2.0, 0.01, &AutoExposureConfig::default(), 0.0),
+            2.0
+        );
+    }
+}
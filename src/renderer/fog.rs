@@ -0,0 +1,62 @@
+use nalgebra_glm as glm;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FogMode {
+    None,
+    // Distances in world units
+    Linear { start: f32, end: f32 },
+    Exponential { density: f32 },
+    ExponentialSquared { density: f32 },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FogConfig {
+    pub mode: FogMode,
+    // Linear radiance, the fog is applied before tone mapping
+    pub color: glm::Vec3,
+    // Height fog: above height_base (measured along -y, which is up for the default camera)
+    // the fog thins out by exp(-height_falloff * height). 0 disables it
+    pub height_falloff: f32,
+    pub height_base: f32,
+    // Clear the background to the fully fogged color
+    pub apply_to_background: bool,
+}
+
+impl Default for FogConfig {
+    fn default() -> Self {
+        Self {
+            mode: FogMode::None,
+            color: glm::Vec3::new(0.5, 0.5, 0.5),
+            height_falloff: 0.0,
+            height_base: 0.0,
+            apply_to_background: false,
+        }
+    }
+}
+
+// Matches the fog_* members of the UniformBufferObject in the default shaders
+pub(crate) type FogUniformData = [[f32; 4]; 3];
+
+impl FogConfig {
+    pub(crate) fn uniform_data(&self) -> FogUniformData {
+        let (mode, density, start, end) = match self.mode {
+            FogMode::None => (0.0, 0.0, 0.0, 0.0),
+            FogMode::Linear { start, end } => (1.0, 0.0, start, end),
+            FogMode::Exponential { density } => (2.0, density, 0.0, 0.0),
+            FogMode::ExponentialSquared { density } => (3.0, density, 0.0, 0.0),
+        };
+        [
+            [self.color.x, self.color.y, self.color.z, mode],
+            [density, start, end, 0.0],
+            [self.height_falloff, self.height_base, 0.0, 0.0],
+        ]
+    }
+
+    // Radiance, the upscale pass tone maps it with the rest of the scene
+    pub(crate) fn background_color(&self) -> Option<[f32; 4]> {
+        if !self.apply_to_background || self.mode == FogMode::None {
+            return None;
+        }
+        Some([self.color.x, self.color.y, self.color.z, 1.0])
+    }
+}
@@ -0,0 +1,749 @@
+use nalgebra_glm as glm;
+
+use super::{
+    buffer::Buffer,
+    color::{palette, Color},
+    material::Material,
+    scene::SceneObject,
+    text::TextVertexData,
+    utils::Handle,
+};
+
+// Editor handles drawn over the selected object. The math works in world space, with
+// the handles along the gizmo's orientation, which is the identity for translation and
+// rotation and the object's rotation for scaling, since scaling happens along the
+// object's own axes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GizmoMode {
+    Translate,
+    Rotate,
+    Scale,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GizmoHandle {
+    // 0, 1 and 2 for X, Y and Z
+    Axis(usize),
+    // The center cube of the scale gizmo
+    Uniform,
+}
+
+// What a drag did since the previous event, applied to the object by the renderer
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GizmoDrag {
+    // World space offset
+    Translate(glm::Vec3),
+    // Radians around the world space axis, total is the angle since the drag started and
+    // keeps going past a full turn
+    Rotate {
+        axis: glm::Vec3,
+        delta: f32,
+        total: f32,
+    },
+    // Factors to multiply the scaling with
+    Scale(glm::Vec3),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GizmoResponse {
+    // True when the application should not use the event for anything else
+    pub consumed: bool,
+    pub drag: Option<GizmoDrag>,
+}
+
+// Where the gizmo is and how it is seen this event
+#[derive(Clone, Copy, Debug)]
+pub struct GizmoView {
+    pub center: glm::Vec3,
+    pub orientation: glm::Quat,
+    // World space length of the handles
+    pub size: f32,
+    pub view_projection: glm::Mat4,
+    // In pixels
+    pub screen_size: glm::Vec2,
+}
+
+impl GizmoView {
+    fn axis(&self, axis: usize) -> glm::Vec3 {
+        let mut unit = glm::Vec3::zeros();
+        unit[axis] = 1.0;
+        glm::quat_rotate_vec3(&self.orientation, &unit)
+    }
+
+    fn to_screen(&self, point: &glm::Vec3) -> Option<glm::Vec2> {
+        project_to_screen(point, &self.view_projection, &self.screen_size)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Ray {
+    pub origin: glm::Vec3,
+    // Normalized
+    pub direction: glm::Vec3,
+}
+
+impl Ray {
+    // The ray through a pixel, starting on the near plane
+    pub fn from_screen(
+        cursor: &glm::Vec2,
+        screen_size: &glm::Vec2,
+        view_projection: &glm::Mat4,
+    ) -> Option<Self> {
+        let inverse = view_projection.try_inverse()?;
+        let x = 2.0 * cursor.x / screen_size.x.max(1.0) - 1.0;
+        let y = 2.0 * cursor.y / screen_size.y.max(1.0) - 1.0;
+        let unproject = |z: f32| {
+            let p = inverse * glm::vec4(x, y, z, 1.0);
+            p.xyz() / p.w
+        };
+        let near = unproject(0.0);
+        let far = unproject(1.0);
+        let direction = far - near;
+        if glm::length(&direction) <= f32::EPSILON {
+            return None;
+        }
+        Some(Self {
+            origin: near,
+            direction: glm::normalize(&direction),
+        })
+    }
+
+    pub fn at(&self, t: f32) -> glm::Vec3 {
+        self.origin + self.direction * t
+    }
+}
+
+// None for points behind the camera
+pub fn project_to_screen(
+    point: &glm::Vec3,
+    view_projection: &glm::Mat4,
+    screen_size: &glm::Vec2,
+) -> Option<glm::Vec2> {
+    let clip = view_projection * glm::vec4(point.x, point.y, point.z, 1.0);
+    if clip.w <= 1e-5 {
+        return None;
+    }
+    Some(glm::vec2(
+        (clip.x / clip.w + 1.0) * 0.5 * screen_size.x,
+        (clip.y / clip.w + 1.0) * 0.5 * screen_size.y,
+    ))
+}
+
+// Closest hit in front of the ray with the side of a cylinder from base to
+// base + axis * length, axis has to be normalized
+pub fn ray_cylinder(
+    ray: &Ray,
+    base: &glm::Vec3,
+    axis: &glm::Vec3,
+    length: f32,
+    radius: f32,
+) -> Option<f32> {
+    let w = ray.origin - base;
+    // Remove the components along the axis, what is left is a circle in 2D
+    let d_perp = ray.direction - axis * glm::dot(&ray.direction, axis);
+    let w_perp = w - axis * glm::dot(&w, axis);
+    let a = glm::dot(&d_perp, &d_perp);
+    if a <= 1e-8 {
+        // Parallel to the axis
+        return None;
+    }
+    let b = 2.0 * glm::dot(&d_perp, &w_perp);
+    let c = glm::dot(&w_perp, &w_perp) - radius * radius;
+    let discriminant = b * b - 4.0 * a * c;
+    if discriminant < 0.0 {
+        return None;
+    }
+    let root = discriminant.sqrt();
+    [(-b - root) / (2.0 * a), (-b + root) / (2.0 * a)]
+        .into_iter()
+        .find(|t| {
+            let height = glm::dot(&(w + ray.direction * *t), axis);
+            *t >= 0.0 && (0.0..=length).contains(&height)
+        })
+}
+
+// Closest hit in front of the ray with an axis aligned box
+pub fn ray_box(ray: &Ray, min: &glm::Vec3, max: &glm::Vec3) -> Option<f32> {
+    let mut t_min = 0.0f32;
+    let mut t_max = f32::INFINITY;
+    for i in 0..3 {
+        if ray.direction[i].abs() <= 1e-8 {
+            if ray.origin[i] < min[i] || ray.origin[i] > max[i] {
+                return None;
+            }
+            continue;
+        }
+        let t1 = (min[i] - ray.origin[i]) / ray.direction[i];
+        let t2 = (max[i] - ray.origin[i]) / ray.direction[i];
+        t_min = t_min.max(t1.min(t2));
+        t_max = t_max.min(t1.max(t2));
+        if t_min > t_max {
+            return None;
+        }
+    }
+    Some(t_min)
+}
+
+// Closest hit in front of the ray with a torus around center, in the plane with the
+// given normalized normal. The exact distance to a torus is cheap while the quartic for
+// the intersection is not, so this sphere traces the distance inside the bounding sphere.
+pub fn ray_torus(
+    ray: &Ray,
+    center: &glm::Vec3,
+    normal: &glm::Vec3,
+    major_radius: f32,
+    minor_radius: f32,
+) -> Option<f32> {
+    let distance = |p: &glm::Vec3| {
+        let local = p - center;
+        let height = glm::dot(&local, normal);
+        let radial = glm::length(&(local - normal * height)) - major_radius;
+        (radial * radial + height * height).sqrt() - minor_radius
+    };
+
+    // Only march where the ray is inside the bounding sphere
+    let bound = major_radius + minor_radius;
+    let to_center = center - ray.origin;
+    let closest = glm::dot(&to_center, &ray.direction);
+    let miss_squared = glm::dot(&to_center, &to_center) - closest * closest;
+    if miss_squared > bound * bound {
+        return None;
+    }
+    let half_chord = (bound * bound - miss_squared).sqrt();
+    let end = closest + half_chord;
+    let mut t = (closest - half_chord).max(0.0);
+    let epsilon = minor_radius * 1e-3;
+    for _ in 0..128 {
+        if t > end {
+            return None;
+        }
+        let d = distance(&ray.at(t));
+        if d < epsilon {
+            return Some(t);
+        }
+        t += d;
+    }
+    None
+}
+
+// Wraps an angle to [-pi, pi)
+pub fn wrap_angle(angle: f32) -> f32 {
+    (angle + std::f32::consts::PI).rem_euclid(std::f32::consts::TAU) - std::f32::consts::PI
+}
+
+// How far moving the cursor from `from` to `to` moves along the axis in world units.
+// The axis is projected to the screen and only the cursor motion along it counts, which
+// keeps dragging intuitive from any angle. Zero while the axis points at the camera.
+pub fn axis_drag_distance(
+    from: &glm::Vec2,
+    to: &glm::Vec2,
+    center: &glm::Vec3,
+    axis: &glm::Vec3,
+    view_projection: &glm::Mat4,
+    screen_size: &glm::Vec2,
+) -> f32 {
+    let (start, end) = match (
+        project_to_screen(center, view_projection, screen_size),
+        project_to_screen(&(center + axis), view_projection, screen_size),
+    ) {
+        (Some(start), Some(end)) => (start, end),
+        _ => return 0.0,
+    };
+    // Pixels per world unit along the axis
+    let screen_axis = end - start;
+    let length_squared = glm::dot(&screen_axis, &screen_axis);
+    if length_squared < 1e-4 {
+        return 0.0;
+    }
+    glm::dot(&(to - from), &screen_axis) / length_squared
+}
+
+// Screen space angle of the cursor around the projected center. The screen's x goes
+// right and y goes down, so with the view direction as the third axis, a growing angle
+// is a right handed rotation around the view direction.
+pub fn screen_angle(cursor: &glm::Vec2, center: &glm::Vec2) -> f32 {
+    let offset = cursor - center;
+    offset.y.atan2(offset.x)
+}
+
+#[derive(Clone, Copy, Debug)]
+struct DragState {
+    handle: GizmoHandle,
+    last_cursor: glm::Vec2,
+    total_angle: f32,
+}
+
+const AXIS_COLORS: [Color; 3] = [palette::RED, palette::GREEN, palette::BLUE];
+const HIGHLIGHT_COLOR: Color = palette::YELLOW;
+// Fractions of the gizmo size
+const HANDLE_RADIUS: f32 = 0.08;
+const RING_RADIUS: f32 = 0.06;
+const SCALE_CUBE: f32 = 0.08;
+const UNIFORM_CUBE: f32 = 0.12;
+// In pixels
+const LINE_WIDTH: f32 = 3.0;
+const HIGHLIGHT_LINE_WIDTH: f32 = 5.0;
+const RING_SEGMENTS: usize = 48;
+// Enough for the rotation rings, the biggest of the gizmos
+pub(crate) const GIZMO_MAX_VERTICES: usize = 3 * RING_SEGMENTS * 6;
+
+pub struct Gizmo {
+    pub mode: GizmoMode,
+    pub target: Handle<SceneObject>,
+    // World space size per unit of distance to the camera, keeps the size on screen fixed
+    pub screen_scale: f32,
+    hovered: Option<GizmoHandle>,
+    drag: Option<DragState>,
+}
+
+impl Gizmo {
+    pub fn new(mode: GizmoMode, target: Handle<SceneObject>) -> Self {
+        Self {
+            mode,
+            target,
+            screen_scale: 0.2,
+            hovered: None,
+            drag: None,
+        }
+    }
+
+    pub fn size(&self, center: &glm::Vec3, camera_position: &glm::Vec3) -> f32 {
+        glm::distance(center, camera_position).max(1e-3) * self.screen_scale
+    }
+
+    pub fn is_dragging(&self) -> bool {
+        self.drag.is_some()
+    }
+
+    // The handle being dragged, or the one under the cursor
+    pub fn active_handle(&self) -> Option<GizmoHandle> {
+        self.drag.map(|drag| drag.handle).or(self.hovered)
+    }
+
+    pub fn hit_test(&self, ray: &Ray, view: &GizmoView) -> Option<GizmoHandle> {
+        let size = view.size;
+        let mut hits: Vec<(f32, GizmoHandle)> = vec![];
+        for i in 0..3 {
+            let axis = view.axis(i);
+            let t = match self.mode {
+                GizmoMode::Translate => {
+                    ray_cylinder(ray, &view.center, &axis, size, size * HANDLE_RADIUS)
+                }
+                GizmoMode::Rotate => ray_torus(ray, &view.center, &axis, size, size * RING_RADIUS),
+                GizmoMode::Scale => {
+                    // The cube at the end, tested in the gizmo's frame where it is axis aligned
+                    let local = local_ray(ray, view);
+                    let mut tip = glm::Vec3::zeros();
+                    tip[i] = size;
+                    let half = glm::Vec3::repeat(size * SCALE_CUBE);
+                    ray_box(&local, &(tip - half), &(tip + half)).or_else(|| {
+                        ray_cylinder(ray, &view.center, &axis, size, size * HANDLE_RADIUS)
+                    })
+                }
+            };
+            if let Some(t) = t {
+                hits.push((t, GizmoHandle::Axis(i)));
+            }
+        }
+        if self.mode == GizmoMode::Scale {
+            let local = local_ray(ray, view);
+            let half = glm::Vec3::repeat(size * UNIFORM_CUBE);
+            if let Some(t) = ray_box(&local, &-half, &half) {
+                hits.push((t, GizmoHandle::Uniform));
+            }
+        }
+        hits.into_iter()
+            .min_by(|a, b| a.0.total_cmp(&b.0))
+            .map(|(_, handle)| handle)
+    }
+
+    // Hovering never consumes the input, pressing on a handle and dragging it does
+    pub fn handle_input(
+        &mut self,
+        cursor: &glm::Vec2,
+        primary_down: bool,
+        view: &GizmoView,
+    ) -> GizmoResponse {
+        let mut drag = match (self.drag, primary_down) {
+            (Some(drag), true) => drag,
+            (Some(_), false) => {
+                self.drag = None;
+                return GizmoResponse {
+                    consumed: true,
+                    drag: None,
+                };
+            }
+            (None, pressed) => {
+                self.hovered = Ray::from_screen(cursor, &view.screen_size, &view.view_projection)
+                    .and_then(|ray| self.hit_test(&ray, view));
+                return match (self.hovered, pressed) {
+                    (Some(handle), true) => {
+                        self.drag = Some(DragState {
+                            handle,
+                            last_cursor: *cursor,
+                            total_angle: 0.0,
+                        });
+                        GizmoResponse {
+                            consumed: true,
+                            drag: None,
+                        }
+                    }
+                    _ => GizmoResponse {
+                        consumed: false,
+                        drag: None,
+                    },
+                };
+            }
+        };
+
+        let result = self.drag_delta(&mut drag, cursor, view);
+        drag.last_cursor = *cursor;
+        self.drag = Some(drag);
+        GizmoResponse {
+            consumed: true,
+            drag: result,
+        }
+    }
+
+    fn drag_delta(
+        &self,
+        drag: &mut DragState,
+        cursor: &glm::Vec2,
+        view: &GizmoView,
+    ) -> Option<GizmoDrag> {
+        if *cursor == drag.last_cursor {
+            return None;
+        }
+        match (self.mode, drag.handle) {
+            (GizmoMode::Translate, GizmoHandle::Axis(i)) => {
+                let axis = view.axis(i);
+                let distance = axis_drag_distance(
+                    &drag.last_cursor,
+                    cursor,
+                    &view.center,
+                    &axis,
+                    &view.view_projection,
+                    &view.screen_size,
+                );
+                Some(GizmoDrag::Translate(axis * distance))
+            }
+            (GizmoMode::Rotate, GizmoHandle::Axis(i)) => {
+                let axis = view.axis(i);
+                let center = view.to_screen(&view.center)?;
+                // Rings seen from behind turn the other way on screen
+                let view_direction = camera_direction(view, &view.center)?;
+                let sign = if glm::dot(&axis, &view_direction) >= 0.0 {
+                    1.0
+                } else {
+                    -1.0
+                };
+                let delta = sign
+                    * wrap_angle(
+                        screen_angle(cursor, &center) - screen_angle(&drag.last_cursor, &center),
+                    );
+                drag.total_angle += delta;
+                Some(GizmoDrag::Rotate {
+                    axis,
+                    delta,
+                    total: drag.total_angle,
+                })
+            }
+            (GizmoMode::Scale, GizmoHandle::Axis(i)) => {
+                let distance = axis_drag_distance(
+                    &drag.last_cursor,
+                    cursor,
+                    &view.center,
+                    &view.axis(i),
+                    &view.view_projection,
+                    &view.screen_size,
+                );
+                // Dragging the cube by the gizmo's size doubles the scale
+                let mut factors = glm::Vec3::repeat(1.0);
+                factors[i] = (1.0 + distance / view.size).max(0.01);
+                Some(GizmoDrag::Scale(factors))
+            }
+            (GizmoMode::Scale, GizmoHandle::Uniform) => {
+                // Relative to the distance to the center on screen
+                let center = view.to_screen(&view.center)?;
+                let before = glm::distance(&drag.last_cursor, &center).max(1.0);
+                let after = glm::distance(cursor, &center).max(1.0);
+                Some(GizmoDrag::Scale(glm::Vec3::repeat(after / before)))
+            }
+            _ => None,
+        }
+    }
+
+    // Screen space triangles for the cursor pipeline, drawn with a white texture
+    pub(crate) fn vertices(&self, view: &GizmoView) -> Vec<TextVertexData> {
+        let mut builder = OverlayBuilder {
+            vertices: vec![],
+            screen_size: view.screen_size,
+        };
+        let active = self.active_handle();
+        let style = |handle: GizmoHandle, color: Color| {
+            if active == Some(handle) {
+                (HIGHLIGHT_COLOR, HIGHLIGHT_LINE_WIDTH)
+            } else {
+                (color, LINE_WIDTH)
+            }
+        };
+        let size = view.size;
+        let center = view.to_screen(&view.center);
+        for (i, axis_color) in AXIS_COLORS.iter().enumerate() {
+            let (color, width) = style(GizmoHandle::Axis(i), *axis_color);
+            let axis = view.axis(i);
+            match self.mode {
+                GizmoMode::Translate => {
+                    let shaft_end = view.to_screen(&(view.center + axis * size * 0.8));
+                    let tip = view.to_screen(&(view.center + axis * size));
+                    if let (Some(center), Some(shaft_end), Some(tip)) = (center, shaft_end, tip) {
+                        builder.line(&center, &shaft_end, width, &color);
+                        builder.arrow_head(&shaft_end, &tip, 10.0, &color);
+                    }
+                }
+                GizmoMode::Rotate => {
+                    // Any two directions perpendicular to the axis span the ring
+                    let u = view.axis((i + 1) % 3);
+                    let v = view.axis((i + 2) % 3);
+                    let point = |k: usize| {
+                        let angle = k as f32 / RING_SEGMENTS as f32 * std::f32::consts::TAU;
+                        view.to_screen(&(view.center + (u * angle.cos() + v * angle.sin()) * size))
+                    };
+                    for k in 0..RING_SEGMENTS {
+                        if let (Some(a), Some(b)) = (point(k), point(k + 1)) {
+                            builder.line(&a, &b, width, &color);
+                        }
+                    }
+                }
+                GizmoMode::Scale => {
+                    let tip = view.to_screen(&(view.center + axis * size));
+                    if let (Some(center), Some(tip)) = (center, tip) {
+                        builder.line(&center, &tip, width, &color);
+                        builder.square(&tip, 10.0, &color);
+                    }
+                }
+            }
+        }
+        if self.mode == GizmoMode::Scale {
+            let (color, _) = style(GizmoHandle::Uniform, palette::LIGHT_GRAY);
+            if let Some(center) = center {
+                builder.square(&center, 12.0, &color);
+            }
+        }
+        builder.vertices.truncate(GIZMO_MAX_VERTICES);
+        builder.vertices
+    }
+}
+
+// The ray in the gizmo's frame, with the center at the origin
+fn local_ray(ray: &Ray, view: &GizmoView) -> Ray {
+    let inverse = glm::quat_inverse(&view.orientation);
+    Ray {
+        origin: glm::quat_rotate_vec3(&inverse, &(ray.origin - view.center)),
+        direction: glm::quat_rotate_vec3(&inverse, &ray.direction),
+    }
+}
+
+// Direction from the camera to the point, recovered from the view projection
+fn camera_direction(view: &GizmoView, point: &glm::Vec3) -> Option<glm::Vec3> {
+    let screen = view.to_screen(point)?;
+    Ray::from_screen(&screen, &view.screen_size, &view.view_projection).map(|ray| ray.direction)
+}
+
+struct OverlayBuilder {
+    vertices: Vec<TextVertexData>,
+    screen_size: glm::Vec2,
+}
+
+impl OverlayBuilder {
+    fn vertex(&self, p: &glm::Vec2, color: &Color) -> TextVertexData {
+        TextVertexData {
+            position: [
+                2.0 * p.x / self.screen_size.x.max(1.0) - 1.0,
+                2.0 * p.y / self.screen_size.y.max(1.0) - 1.0,
+                0.0,
+            ],
+            texture_coordinates: [0.5, 0.5],
+            color: color.to_linear(),
+        }
+    }
+
+    fn triangle(&mut self, a: &glm::Vec2, b: &glm::Vec2, c: &glm::Vec2, color: &Color) {
+        let vertices = [
+            self.vertex(a, color),
+            self.vertex(b, color),
+            self.vertex(c, color),
+        ];
+        self.vertices.extend_from_slice(&vertices);
+    }
+
+    fn quad(&mut self, corners: [glm::Vec2; 4], color: &Color) {
+        self.triangle(&corners[0], &corners[1], &corners[2], color);
+        self.triangle(&corners[2], &corners[1], &corners[3], color);
+    }
+
+    fn line(&mut self, a: &glm::Vec2, b: &glm::Vec2, width: f32, color: &Color) {
+        let direction = b - a;
+        if glm::length(&direction) < 1e-3 {
+            return;
+        }
+        let offset = glm::normalize(&glm::vec2(-direction.y, direction.x)) * width * 0.5;
+        self.quad([a + offset, a - offset, b + offset, b - offset], color);
+    }
+
+    fn arrow_head(&mut self, base: &glm::Vec2, tip: &glm::Vec2, width: f32, color: &Color) {
+        let direction = tip - base;
+        if glm::length(&direction) < 1e-3 {
+            return;
+        }
+        let offset = glm::normalize(&glm::vec2(-direction.y, direction.x)) * width * 0.5;
+        self.triangle(&(base + offset), &(base - offset), tip, color);
+    }
+
+    fn square(&mut self, center: &glm::Vec2, size: f32, color: &Color) {
+        let h = size * 0.5;
+        self.quad(
+            [
+                center + glm::vec2(-h, -h),
+                center + glm::vec2(-h, h),
+                center + glm::vec2(h, -h),
+                center + glm::vec2(h, h),
+            ],
+            color,
+        );
+    }
+}
+
+pub(crate) struct GizmoResources {
+    pub material: Handle<Material>,
+    // GIZMO_MAX_VERTICES per swapchain image
+    pub vertex_buffer: Buffer,
+    pub vertex_counts: Vec<u32>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ray(origin: glm::Vec3, direction: glm::Vec3) -> Ray {
+        Ray {
+            origin,
+            direction: glm::normalize(&direction),
+        }
+    }
+
+    #[test]
+    fn cylinder_hits_the_side_in_front() {
+        let axis = glm::vec3(1.0, 0.0, 0.0);
+        let down = ray(glm::vec3(0.5, 5.0, 0.0), glm::vec3(0.0, -1.0, 0.0));
+        let t = ray_cylinder(&down, &glm::Vec3::zeros(), &axis, 1.0, 0.1).unwrap();
+        assert!((t - 4.9).abs() < 1e-5);
+        // Past the end of the cylinder
+        let past = ray(glm::vec3(1.5, 5.0, 0.0), glm::vec3(0.0, -1.0, 0.0));
+        assert_eq!(
+            ray_cylinder(&past, &glm::Vec3::zeros(), &axis, 1.0, 0.1),
+            None
+        );
+        // Behind the ray
+        let away = ray(glm::vec3(0.5, 5.0, 0.0), glm::vec3(0.0, 1.0, 0.0));
+        assert_eq!(
+            ray_cylinder(&away, &glm::Vec3::zeros(), &axis, 1.0, 0.1),
+            None
+        );
+        // Along the axis
+        let along = ray(glm::vec3(-5.0, 0.0, 0.0), axis);
+        assert_eq!(
+            ray_cylinder(&along, &glm::Vec3::zeros(), &axis, 1.0, 0.1),
+            None
+        );
+    }
+
+    #[test]
+    fn box_hits_from_outside_and_inside() {
+        let min = glm::vec3(-1.0, -1.0, -1.0);
+        let max = glm::vec3(1.0, 1.0, 1.0);
+        let outside = ray(glm::vec3(-5.0, 0.0, 0.0), glm::vec3(1.0, 0.0, 0.0));
+        assert!((ray_box(&outside, &min, &max).unwrap() - 4.0).abs() < 1e-5);
+        let inside = ray(glm::Vec3::zeros(), glm::vec3(0.0, 0.0, 1.0));
+        assert_eq!(ray_box(&inside, &min, &max), Some(0.0));
+        let parallel_miss = ray(glm::vec3(-5.0, 2.0, 0.0), glm::vec3(1.0, 0.0, 0.0));
+        assert_eq!(ray_box(&parallel_miss, &min, &max), None);
+    }
+
+    #[test]
+    fn torus_hits_the_ring_not_the_hole() {
+        let normal = glm::vec3(0.0, 1.0, 0.0);
+        let through_ring = ray(glm::vec3(1.0, 5.0, 0.0), glm::vec3(0.0, -1.0, 0.0));
+        let t = ray_torus(&through_ring, &glm::Vec3::zeros(), &normal, 1.0, 0.1).unwrap();
+        assert!((t - 4.9).abs() < 1e-3);
+        let through_hole = ray(glm::vec3(0.0, 5.0, 0.0), glm::vec3(0.0, -1.0, 0.0));
+        assert_eq!(
+            ray_torus(&through_hole, &glm::Vec3::zeros(), &normal, 1.0, 0.1),
+            None
+        );
+    }
+
+    #[test]
+    fn angles_wrap_to_half_open_range() {
+        use std::f32::consts::PI;
+        assert!((wrap_angle(3.0 * PI / 2.0) + PI / 2.0).abs() < 1e-5);
+        assert!((wrap_angle(-3.0 * PI / 2.0) - PI / 2.0).abs() < 1e-5);
+        assert!((wrap_angle(PI) + PI).abs() < 1e-5);
+        assert!((wrap_angle(0.25) - 0.25).abs() < 1e-6);
+    }
+
+    #[test]
+    fn screen_projection_round_trips_through_rays() {
+        let view_projection = glm::perspective_rh_zo(1.0, 1.0, 0.1, 100.0)
+            * glm::look_at_rh(
+                &glm::vec3(0.0, 0.0, 10.0),
+                &glm::Vec3::zeros(),
+                &glm::vec3(0.0, 1.0, 0.0),
+            );
+        let screen_size = glm::vec2(800.0, 800.0);
+        let point = glm::vec3(1.0, -2.0, 0.5);
+        let pixel = project_to_screen(&point, &view_projection, &screen_size).unwrap();
+        let ray = Ray::from_screen(&pixel, &screen_size, &view_projection).unwrap();
+        // The ray through the pixel passes through the point
+        let to_point = point - ray.origin;
+        let miss = to_point - ray.direction * glm::dot(&to_point, &ray.direction);
+        assert!(glm::length(&miss) < 1e-3);
+        // Behind the camera
+        assert_eq!(
+            project_to_screen(&glm::vec3(0.0, 0.0, 20.0), &view_projection, &screen_size),
+            None
+        );
+    }
+
+    #[test]
+    fn axis_drags_follow_the_projected_axis() {
+        let view_projection = glm::ortho_rh_zo(-10.0, 10.0, -10.0, 10.0, 0.1, 100.0)
+            * glm::look_at_rh(
+                &glm::vec3(0.0, 0.0, 10.0),
+                &glm::Vec3::zeros(),
+                &glm::vec3(0.0, 1.0, 0.0),
+            );
+        let screen_size = glm::vec2(200.0, 200.0);
+        let center = glm::Vec3::zeros();
+        // 10 pixels per unit, only the motion along the axis counts
+        let distance = axis_drag_distance(
+            &glm::vec2(100.0, 100.0),
+            &glm::vec2(120.0, 130.0),
+            &center,
+            &glm::vec3(1.0, 0.0, 0.0),
+            &view_projection,
+            &screen_size,
+        );
+        assert!((distance - 2.0).abs() < 1e-4);
+        // Pointing at the camera
+        let towards = axis_drag_distance(
+            &glm::vec2(100.0, 100.0),
+            &glm::vec2(120.0, 130.0),
+            &center,
+            &glm::vec3(0.0, 0.0, 1.0),
+            &view_projection,
+            &screen_size,
+        );
+        assert_eq!(towards, 0.0);
+    }
+}
@@ -0,0 +1,124 @@
+use ash::vk;
+
+use super::{context::VulkanContext, RendererResult};
+
+// The nanoseconds per tick and the mask of the meaningful bits of the timestamps of a
+// queue family, None if it can't write timestamps
+pub(crate) fn timestamp_support(
+    context: &VulkanContext,
+    queue_family_index: u32,
+) -> Option<(f32, u64)> {
+    let period = context.physical_device_properties.limits.timestamp_period;
+    let valid_bits = unsafe {
+        context
+            .instance
+            .get_physical_device_queue_family_properties(context.physical_device)
+    }
+    .get(queue_family_index as usize)
+    .map_or(0, |family| family.timestamp_valid_bits);
+    if period <= 0.0 || valid_bits == 0 {
+        return None;
+    }
+    let valid_mask = if valid_bits >= 64 {
+        u64::MAX
+    } else {
+        (1 << valid_bits) - 1
+    };
+    Some((period, valid_mask))
+}
+
+// The milliseconds between two timestamps
+pub(crate) fn elapsed_ms(start: u64, end: u64, period: f32, valid_mask: u64) -> f32 {
+    let ticks = (end & valid_mask).wrapping_sub(start & valid_mask) & valid_mask;
+    ticks as f32 * period / 1_000_000.0
+}
+
+// Two timestamps around the graphics work of every frame in flight. The results are read
+// once the frame is done, so they lag behind by the frames in flight.
+pub(crate) struct GpuFrameTimer {
+    query_pool: vk::QueryPool,
+    // Nanoseconds per tick
+    period: f32,
+    // Only the low timestamp_valid_bits of a timestamp are meaningful
+    valid_mask: u64,
+    // Whether the queries of each frame were written and not read yet
+    pending: Vec<bool>,
+}
+
+impl GpuFrameTimer {
+    // None if the graphics queue can't write timestamps
+    pub fn new(context: &VulkanContext, frames: usize) -> RendererResult<Option<Self>> {
+        let (period, valid_mask) = match timestamp_support(context, context.graphics_queue.index) {
+            Some(support) => support,
+            None => return Ok(None),
+        };
+        let query_pool_info = vk::QueryPoolCreateInfo::builder()
+            .query_type(vk::QueryType::TIMESTAMP)
+            .query_count(2 * frames as u32);
+        let query_pool = unsafe { context.device.create_query_pool(&query_pool_info, None)? };
+        Ok(Some(Self {
+            query_pool,
+            period,
+            valid_mask,
+            pending: vec![false; frames],
+        }))
+    }
+
+    // Outside of a render pass, before anything else of the frame
+    pub fn begin(&self, device: &ash::Device, command_buffer: vk::CommandBuffer, frame: usize) {
+        let first = 2 * frame as u32;
+        unsafe {
+            device.cmd_reset_query_pool(command_buffer, self.query_pool, first, 2);
+            device.cmd_write_timestamp(
+                command_buffer,
+                vk::PipelineStageFlags::TOP_OF_PIPE,
+                self.query_pool,
+                first,
+            );
+        }
+    }
+
+    // After everything else of the frame, on the same queue as begin
+    pub fn end(&mut self, device: &ash::Device, command_buffer: vk::CommandBuffer, frame: usize) {
+        unsafe {
+            device.cmd_write_timestamp(
+                command_buffer,
+                vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                self.query_pool,
+                2 * frame as u32 + 1,
+            );
+        }
+        self.pending[frame] = true;
+    }
+
+    // The GPU time in milliseconds of the last frame recorded with frame, once the GPU is
+    // done with it. Every frame is only read once.
+    pub fn read(&mut self, device: &ash::Device, frame: usize) -> Option<f32> {
+        if !std::mem::replace(&mut self.pending[frame], false) {
+            return None;
+        }
+        let mut timestamps = [0u64; 2];
+        unsafe {
+            device.get_query_pool_results(
+                self.query_pool,
+                2 * frame as u32,
+                2,
+                &mut timestamps,
+                vk::QueryResultFlags::TYPE_64,
+            )
+        }
+        .ok()?;
+        Some(elapsed_ms(
+            timestamps[0],
+            timestamps[1],
+            self.period,
+            self.valid_mask,
+        ))
+    }
+
+    pub fn destroy(&mut self, device: &ash::Device) {
+        unsafe {
+            device.destroy_query_pool(self.query_pool, None);
+        }
+    }
+}
@@ -0,0 +1,175 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use ash::vk;
+use gpu_allocator::{vulkan::Allocator, MemoryLocation};
+
+use super::{
+    buffer::{Buffer, BufferManager},
+    scene::{InstanceData, SceneObject},
+    utils::Handle,
+    RendererResult,
+};
+
+// Groups items by key, in the order of the keys. Items with the same key keep their
+// order.
+pub fn group_by_key<K: Ord, T>(items: impl IntoIterator<Item = (K, T)>) -> Vec<(K, Vec<T>)> {
+    let mut groups: BTreeMap<K, Vec<T>> = BTreeMap::new();
+    for (key, item) in items {
+        groups.entry(key).or_default().push(item);
+    }
+    groups.into_iter().collect()
+}
+
+// Opaque objects that can share an instanced draw
+#[derive(Debug)]
+pub(crate) struct InstanceGroup {
+    pub pipeline: u64,
+    pub material: usize,
+    pub mesh: usize,
+    // In the order of the scene
+    pub objects: Vec<Handle<SceneObject>>,
+}
+
+// Every object sorted into a group of objects with the same pipeline, material and mesh
+// or into the objects drawn on their own. Only rebuilt when the scene changes, the
+// members are culled every frame.
+#[derive(Debug, Default)]
+pub(crate) struct InstanceGroups {
+    groups: Vec<InstanceGroup>,
+    // Transparent objects and the ones with anything bound per object
+    singles: Vec<Handle<SceneObject>>,
+    valid: bool,
+}
+
+impl InstanceGroups {
+    pub fn is_valid(&self) -> bool {
+        self.valid
+    }
+
+    pub fn invalidate(&mut self) {
+        self.valid = false;
+    }
+
+    // The keys are the pipeline, material and mesh of the objects
+    pub fn rebuild(
+        &mut self,
+        batchable: Vec<((u64, usize, usize), Handle<SceneObject>)>,
+        singles: Vec<Handle<SceneObject>>,
+    ) {
+        self.groups = group_by_key(batchable)
+            .into_iter()
+            .map(|((pipeline, material, mesh), objects)| InstanceGroup {
+                pipeline,
+                material,
+                mesh,
+                objects,
+            })
+            .collect();
+        self.singles = singles;
+        self.valid = true;
+    }
+
+    pub fn groups(&self) -> &[InstanceGroup] {
+        &self.groups
+    }
+
+    pub fn singles(&self) -> &[Handle<SceneObject>] {
+        &self.singles
+    }
+}
+
+// The instance data of the objects that are drawn together, copied next to each other
+// every frame since their slots in the object data are scattered. Every frame in flight
+// has a region of its own, like the instances of the particle emitters.
+pub(crate) struct InstanceBatchBuffer {
+    buffer: Option<Buffer>,
+    // Instances per frame
+    capacity: usize,
+    frames: usize,
+}
+
+impl InstanceBatchBuffer {
+    pub fn new(frames: usize) -> Self {
+        Self {
+            buffer: None,
+            capacity: 0,
+            frames,
+        }
+    }
+
+    pub fn buffer(&self) -> Option<&Buffer> {
+        self.buffer.as_ref()
+    }
+
+    // Replaces the instances of the frame and returns the offset of the first one. The
+    // buffer grows to fit, the old one is freed once the frames using it are done.
+    pub fn write(
+        &mut self,
+        frame: usize,
+        instances: &[u8],
+        device: &ash::Device,
+        allocator: &mut Allocator,
+        buffer_manager: Arc<Mutex<BufferManager>>,
+    ) -> RendererResult<vk::DeviceSize> {
+        let stride = std::mem::size_of::<InstanceData>();
+        let count = instances.len() / stride;
+        if count > self.capacity {
+            let capacity = count.next_power_of_two().max(64);
+            let buffer = BufferManager::new_buffer(
+                buffer_manager,
+                device,
+                allocator,
+                (capacity * stride * self.frames) as u64,
+                vk::BufferUsageFlags::VERTEX_BUFFER,
+                MemoryLocation::CpuToGpu,
+                "instance-batches",
+            )?;
+            if let Some(mut old) = self.buffer.replace(buffer) {
+                old.queue_free()?;
+            }
+            self.capacity = capacity;
+        }
+        let offset = frame * self.capacity * stride;
+        if let Some(buffer) = self.buffer.as_mut() {
+            if !instances.is_empty() {
+                buffer.copy_to_offset(allocator, instances, offset)?;
+            }
+        }
+        Ok(offset as vk::DeviceSize)
+    }
+
+    // The regions of the frames move, so the buffer is replaced with the next write
+    pub fn set_frames(&mut self, frames: usize) -> RendererResult<()> {
+        self.destroy()?;
+        self.frames = frames;
+        Ok(())
+    }
+
+    pub fn destroy(&mut self) -> RendererResult<()> {
+        if let Some(mut buffer) = self.buffer.take() {
+            buffer.queue_free()?;
+        }
+        self.capacity = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn groups_are_ordered_by_key() {
+        let groups = group_by_key([(2, 'a'), (1, 'b'), (2, 'c'), (3, 'd'), (1, 'e')]);
+        assert_eq!(
+            groups,
+            vec![(1, vec!['b', 'e']), (2, vec!['a', 'c']), (3, vec!['d'])]
+        );
+    }
+
+    #[test]
+    fn no_items_no_groups() {
+        assert!(group_by_key(Vec::<(u32, u32)>::new()).is_empty());
+    }
+}
@@ -1,9 +1,28 @@
 use ash::{vk, Device};
 use gpu_allocator::vulkan::Allocator;
+use log::warn;
 use nalgebra as na;
 use nalgebra_glm as glm;
 
-use super::{buffer::Buffer, RendererResult};
+use std::sync::{Arc, Mutex};
+
+use gpu_allocator::MemoryLocation;
+
+use super::{
+    buffer::{Buffer, BufferManager, MAX_UPDATE_SIZE},
+    error::{InvalidHandle, RendererError},
+    probe::{LightProbe, ProbeData},
+    texture::{Texture, TextureStorage},
+    utils::{Handle, HandleArray},
+    RendererResult,
+};
+
+// Size of the cookie texture array in the lights descriptor set
+pub const MAX_LIGHT_COOKIES: usize = 8;
+
+// The light storage buffer starts with room for this many bytes and doubles when the
+// lights outgrow it
+const MIN_LIGHT_STORAGE_SIZE: u64 = 1024;
 
 #[derive(Debug)]
 pub struct DirectionalLight {
@@ -17,9 +36,23 @@ pub struct PointLight {
     pub luminous_flux: glm::Vec3,  // in lm
 }
 
+#[derive(Debug)]
+pub struct SpotLight {
+    pub position: na::Point3<f32>,      // in m
+    pub direction: na::Unit<glm::Vec3>, // the direction the light shines in
+    pub inner_angle: f32,               // in radians, full intensity inside
+    pub outer_angle: f32,               // in radians, no light outside, below 90 degrees
+    // in lm, as if it was a point light, so changing the cone does not change the brightness
+    pub luminous_flux: glm::Vec3,
+    // Projected along the direction so that it covers the outer cone, and multiplied
+    // into the light. Attach it with LightManager::attach_cookie
+    pub cookie: Option<Handle<Texture>>,
+}
+
 pub enum Light {
     Directional(DirectionalLight),
     Point(PointLight),
+    Spot(SpotLight),
 }
 
 impl From<PointLight> for Light {
@@ -34,40 +67,195 @@ impl From<DirectionalLight> for Light {
     }
 }
 
+impl From<SpotLight> for Light {
+    fn from(s: SpotLight) -> Self {
+        Light::Spot(s)
+    }
+}
+
+// Returned by LightManager::add_light, stays valid until the light is removed
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum LightHandle {
+    Directional(Handle<DirectionalLight>),
+    Point(Handle<PointLight>),
+    Spot(Handle<SpotLight>),
+}
+
+// The lights the renderer draws with, see Renderer::lights. Every change bumps the
+// generation, and the renderer uploads what changed with the next frame.
 #[derive(Debug, Default)]
 pub struct LightManager {
-    directional_lights: Vec<DirectionalLight>,
-    point_lights: Vec<PointLight>,
+    directional_lights: HandleArray<DirectionalLight>,
+    point_lights: HandleArray<PointLight>,
+    spot_lights: HandleArray<SpotLight>,
+    probes: Vec<LightProbe>,
+    generation: u64,
 }
 
 impl LightManager {
-    pub fn add_light<L: Into<Light>>(&mut self, l: L) {
+    pub fn add_light<L: Into<Light>>(&mut self, l: L) -> LightHandle {
+        self.generation += 1;
         match l.into() {
-            Light::Directional(dl) => {
-                self.directional_lights.push(dl);
-            }
-            Light::Point(pl) => {
-                self.point_lights.push(pl);
+            Light::Directional(dl) => LightHandle::Directional(self.directional_lights.insert(dl)),
+            Light::Point(pl) => LightHandle::Point(self.point_lights.insert(pl)),
+            Light::Spot(sl) => LightHandle::Spot(self.spot_lights.insert(sl)),
+        }
+    }
+
+    // The last light of the same kind takes the place of the removed one in the lists
+    // below, the handles of all others stay valid
+    pub fn remove_light(&mut self, handle: LightHandle) -> RendererResult<()> {
+        match handle {
+            LightHandle::Directional(h) => self.directional_lights.remove(h).map(|_| ())?,
+            LightHandle::Point(h) => self.point_lights.remove(h).map(|_| ())?,
+            LightHandle::Spot(h) => self.spot_lights.remove(h).map(|_| ())?,
+        }
+        self.generation += 1;
+        Ok(())
+    }
+
+    // The _mut accessors count as a change, even if nothing is written
+    pub fn directional_light_mut(
+        &mut self,
+        handle: Handle<DirectionalLight>,
+    ) -> Option<&mut DirectionalLight> {
+        self.generation += 1;
+        self.directional_lights.get_mut(handle)
+    }
+
+    pub fn point_light_mut(&mut self, handle: Handle<PointLight>) -> Option<&mut PointLight> {
+        self.generation += 1;
+        self.point_lights.get_mut(handle)
+    }
+
+    pub fn spot_light_mut(&mut self, handle: Handle<SpotLight>) -> Option<&mut SpotLight> {
+        self.generation += 1;
+        self.spot_lights.get_mut(handle)
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    // The first one casts shadows
+    pub fn directional_lights(&self) -> &[DirectionalLight] {
+        self.directional_lights.get_data()
+    }
+
+    pub fn point_lights(&self) -> &[PointLight] {
+        self.point_lights.get_data()
+    }
+
+    pub fn spot_lights(&self) -> &[SpotLight] {
+        self.spot_lights.get_data()
+    }
+
+    pub fn probes(&self) -> &[LightProbe] {
+        &self.probes
+    }
+
+    // Returns the index of the probe in probes()
+    pub fn add_probe(&mut self, position: na::Point3<f32>, data: ProbeData) -> usize {
+        self.generation += 1;
+        self.probes.push(LightProbe { position, data });
+        self.probes.len() - 1
+    }
+
+    pub fn set_probe_data(&mut self, index: usize, data: ProbeData) -> RendererResult<()> {
+        self.generation += 1;
+        self.probes
+            .get_mut(index)
+            .ok_or::<RendererError>(InvalidHandle.into())?
+            .data = data;
+        Ok(())
+    }
+
+    pub fn clear_probes(&mut self) {
+        self.generation += 1;
+        self.probes.clear();
+    }
+
+    pub fn attach_cookie(
+        &mut self,
+        handle: Handle<SpotLight>,
+        cookie: Handle<Texture>,
+    ) -> RendererResult<()> {
+        self.spot_light_mut(handle)
+            .ok_or::<RendererError>(InvalidHandle.into())?
+            .cookie = Some(cookie);
+        Ok(())
+    }
+
+    pub fn detach_cookie(&mut self, handle: Handle<SpotLight>) -> RendererResult<()> {
+        self.spot_light_mut(handle)
+            .ok_or::<RendererError>(InvalidHandle.into())?
+            .cookie = None;
+        Ok(())
+    }
+
+    // The distinct cookie textures in the order of their slots. Lights using a cookie
+    // that did not fit into the MAX_LIGHT_COOKIES slots are drawn without one.
+    pub fn cookie_textures(&self) -> Vec<Handle<Texture>> {
+        let mut cookies = vec![];
+        for cookie in self.spot_lights.iter().filter_map(|sl| sl.cookie) {
+            if !cookies.contains(&cookie) {
+                if cookies.len() == MAX_LIGHT_COOKIES {
+                    warn!(
+                        "More than {} light cookies, some lights are drawn without them",
+                        MAX_LIGHT_COOKIES
+                    );
+                    break;
+                }
+                cookies.push(cookie);
             }
         }
+        cookies
     }
 
-    pub fn update_buffer(
+    // Binds the cookie textures to their slots and everything else to the fallback,
+    // as every slot has to be valid when drawing
+    pub(crate) fn update_cookie_descriptors(
         &self,
         device: &Device,
-        allocator: &mut Allocator,
-        buffer: &mut Buffer,
         descriptor_set_lights: vk::DescriptorSet,
+        texture_storage: &TextureStorage,
+        sampler: vk::Sampler,
+        fallback: Handle<Texture>,
     ) -> RendererResult<()> {
-        // 0.0s are for padding
+        let cookies = self.cookie_textures();
+        let image_infos = (0..MAX_LIGHT_COOKIES)
+            .map(|slot| {
+                let handle = cookies.get(slot).copied().unwrap_or(fallback);
+                let texture = texture_storage
+                    .get_texture(handle)
+                    .ok_or::<RendererError>(InvalidHandle.into())?;
+                Ok(vk::DescriptorImageInfo {
+                    sampler,
+                    image_view: texture.image_view,
+                    image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                })
+            })
+            .collect::<RendererResult<Vec<_>>>()?;
+        let desc_sets_write = [vk::WriteDescriptorSet::builder()
+            .dst_set(descriptor_set_lights)
+            .dst_binding(1)
+            .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+            .image_info(&image_infos)
+            .build()];
+        unsafe { device.update_descriptor_sets(&desc_sets_write, &[]) };
+        Ok(())
+    }
+
+    // Counts, then the lights of each kind and the probes, as the shaders read them
+    pub(crate) fn storage_data(&self) -> Vec<f32> {
         let mut data_vec: Vec<f32> = vec![
             self.directional_lights.len() as f32,
             self.point_lights.len() as f32,
-            0.0,
-            0.0,
+            self.spot_lights.len() as f32,
+            self.probes.len() as f32,
         ];
 
-        for dl in &self.directional_lights {
+        for dl in self.directional_lights.iter() {
             data_vec.push(dl.direction.x);
             data_vec.push(dl.direction.y);
             data_vec.push(dl.direction.z);
@@ -77,7 +265,7 @@ impl LightManager {
             data_vec.push(dl.illuminance.z);
             data_vec.push(0.0); // Padding
         }
-        for pl in &self.point_lights {
+        for pl in self.point_lights.iter() {
             data_vec.push(pl.position.x);
             data_vec.push(pl.position.y);
             data_vec.push(pl.position.z);
@@ -87,21 +275,368 @@ impl LightManager {
             data_vec.push(pl.luminous_flux.z);
             data_vec.push(0.0); // Padding
         }
-        buffer.fill(allocator, &data_vec)?;
-        let int_buf = buffer.get_buffer();
-        let buffer_infos = [vk::DescriptorBufferInfo {
-            buffer: int_buf.buffer,
-            offset: 0,
-            range: int_buf.size,
-        }];
-        let desc_sets_write = [vk::WriteDescriptorSet::builder()
-            .dst_set(descriptor_set_lights)
-            .dst_binding(0)
-            .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
-            .buffer_info(&buffer_infos)
+        let cookies = self.cookie_textures();
+        for sl in self.spot_lights.iter() {
+            data_vec.push(sl.position.x);
+            data_vec.push(sl.position.y);
+            data_vec.push(sl.position.z);
+            data_vec.push(0.0); // Padding
+            data_vec.push(sl.luminous_flux.x);
+            data_vec.push(sl.luminous_flux.y);
+            data_vec.push(sl.luminous_flux.z);
+            data_vec.push(0.0); // Padding
+            data_vec.push(sl.direction.x);
+            data_vec.push(sl.direction.y);
+            data_vec.push(sl.direction.z);
+            data_vec.push(0.0); // Padding
+            data_vec.push(sl.inner_angle.cos());
+            data_vec.push(sl.outer_angle.cos());
+            // -1 means no cookie
+            let cookie_slot = sl
+                .cookie
+                .and_then(|c| cookies.iter().position(|h| *h == c))
+                .map_or(-1.0, |slot| slot as f32);
+            data_vec.push(cookie_slot);
+            data_vec.push(0.0); // Padding
+        }
+        for probe in &self.probes {
+            for coefficient in probe.data.to_spherical_harmonics().coefficients {
+                data_vec.push(coefficient.x);
+                data_vec.push(coefficient.y);
+                data_vec.push(coefficient.z);
+                data_vec.push(0.0); // Padding
+            }
+        }
+        data_vec
+    }
+
+    // For buffers no frame in flight reads, like the one of the preview scene
+    pub fn update_buffer(
+        &self,
+        device: &Device,
+        allocator: &mut Allocator,
+        buffer: &mut Buffer,
+        descriptor_set_lights: vk::DescriptorSet,
+    ) -> RendererResult<()> {
+        buffer.fill(allocator, &self.storage_data())?;
+        update_storage_descriptor(device, descriptor_set_lights, buffer);
+        Ok(())
+    }
+}
+
+fn update_storage_descriptor(
+    device: &Device,
+    descriptor_set_lights: vk::DescriptorSet,
+    buffer: &Buffer,
+) {
+    let buffer_infos = [vk::DescriptorBufferInfo {
+        buffer: buffer.get_buffer().buffer,
+        offset: 0,
+        range: vk::WHOLE_SIZE,
+    }];
+    let desc_sets_write = [vk::WriteDescriptorSet::builder()
+        .dst_set(descriptor_set_lights)
+        .dst_binding(0)
+        .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+        .buffer_info(&buffer_infos)
+        .build()];
+    unsafe { device.update_descriptor_sets(&desc_sets_write, &[]) };
+}
+
+// The storage buffer the renderer's lights are read from. It has room to spare and
+// doubles when the lights outgrow it. Changes are written by the command buffer of the
+// next frame, and only the part that differs from what the buffer holds, so the frames
+// in flight keep the lights they were recorded with.
+pub(crate) struct LightStorage {
+    buffer: Buffer,
+    // In bytes
+    capacity: u64,
+    // What the buffer holds
+    uploaded: Vec<f32>,
+    // Of the LightManager that was uploaded, None before the first upload
+    generation: Option<u64>,
+    // Bound to the cookie slots, None before the first upload
+    cookies: Option<Vec<Handle<Texture>>>,
+}
+
+impl LightStorage {
+    pub fn new(
+        device: &Device,
+        allocator: &mut Allocator,
+        buffer_manager: Arc<Mutex<BufferManager>>,
+    ) -> RendererResult<Self> {
+        let mut buffer = BufferManager::new_buffer(
+            buffer_manager,
+            device,
+            allocator,
+            MIN_LIGHT_STORAGE_SIZE,
+            vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::TRANSFER_DST,
+            MemoryLocation::CpuToGpu,
+            "lights",
+        )?;
+        // No lights and no probes
+        let uploaded = vec![0.0f32; 4];
+        buffer.fill(allocator, &uploaded)?;
+        Ok(Self {
+            buffer,
+            capacity: MIN_LIGHT_STORAGE_SIZE,
+            uploaded,
+            generation: None,
+            cookies: None,
+        })
+    }
+
+    pub fn is_current(&self, lights: &LightManager) -> bool {
+        self.generation == Some(lights.generation())
+    }
+
+    // Whether the changes can be recorded, otherwise the buffer has to grow or the
+    // descriptors have to change, see write
+    pub fn can_record(&self, data: &[f32], cookies: &[Handle<Texture>]) -> bool {
+        (data.len() * std::mem::size_of::<f32>()) as u64 <= self.capacity
+            && self.cookies.as_deref() == Some(cookies)
+    }
+
+    // Writes the part of data that changed with cmd_buf, between barriers that also wait
+    // for the frames submitted before it
+    pub fn record_upload(
+        &mut self,
+        device: &Device,
+        cmd_buf: vk::CommandBuffer,
+        data: Vec<f32>,
+        generation: u64,
+    ) {
+        self.generation = Some(generation);
+        let first = data
+            .iter()
+            .zip(self.uploaded.iter())
+            .position(|(a, b)| a.to_bits() != b.to_bits())
+            .unwrap_or_else(|| data.len().min(self.uploaded.len()));
+        let end = if data.len() == self.uploaded.len() {
+            data.iter()
+                .zip(self.uploaded.iter())
+                .rposition(|(a, b)| a.to_bits() != b.to_bits())
+                .map_or(first, |last| last + 1)
+        } else {
+            data.len()
+        };
+        if first >= end {
+            self.uploaded = data;
+            return;
+        }
+        let buffer = self.buffer.get_buffer().buffer;
+        let shader_stages =
+            vk::PipelineStageFlags::VERTEX_SHADER | vk::PipelineStageFlags::FRAGMENT_SHADER;
+        let before = [vk::MemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::SHADER_READ)
+            .dst_access_mask(vk::AccessFlags::TRANSFER_WRITE)
             .build()];
-        unsafe { device.update_descriptor_sets(&desc_sets_write, &[]) };
+        let after = [vk::MemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .dst_access_mask(vk::AccessFlags::SHADER_READ)
+            .build()];
+        let bytes = data[first..end]
+            .iter()
+            .flat_map(|f| f.to_ne_bytes())
+            .collect::<Vec<_>>();
+        unsafe {
+            device.cmd_pipeline_barrier(
+                cmd_buf,
+                shader_stages,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::DependencyFlags::empty(),
+                &before,
+                &[],
+                &[],
+            );
+            for (i, chunk) in bytes.chunks(MAX_UPDATE_SIZE).enumerate() {
+                let offset = first * std::mem::size_of::<f32>() + i * MAX_UPDATE_SIZE;
+                device.cmd_update_buffer(cmd_buf, buffer, offset as vk::DeviceSize, chunk);
+            }
+            device.cmd_pipeline_barrier(
+                cmd_buf,
+                vk::PipelineStageFlags::TRANSFER,
+                shader_stages,
+                vk::DependencyFlags::empty(),
+                &after,
+                &[],
+                &[],
+            );
+        }
+        self.uploaded = data;
+    }
 
+    // Writes everything through the mapping and rebinds the buffer and the cookies. The
+    // caller makes sure no frame in flight uses the descriptor set. The buffer grows to
+    // fit, the old one is freed once the frames using it are done.
+    #[allow(clippy::too_many_arguments)]
+    pub fn write(
+        &mut self,
+        device: &Device,
+        allocator: &mut Allocator,
+        buffer_manager: Arc<Mutex<BufferManager>>,
+        lights: &LightManager,
+        descriptor_set_lights: vk::DescriptorSet,
+        texture_storage: &TextureStorage,
+        sampler: vk::Sampler,
+        fallback: Handle<Texture>,
+    ) -> RendererResult<()> {
+        let data = lights.storage_data();
+        let size = (data.len() * std::mem::size_of::<f32>()) as u64;
+        if size > self.capacity {
+            let mut capacity = self.capacity;
+            while capacity < size {
+                capacity *= 2;
+            }
+            let buffer = BufferManager::new_buffer(
+                buffer_manager,
+                device,
+                allocator,
+                capacity,
+                vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::TRANSFER_DST,
+                MemoryLocation::CpuToGpu,
+                "lights",
+            )?;
+            let mut old = std::mem::replace(&mut self.buffer, buffer);
+            old.queue_free()?;
+            self.capacity = capacity;
+        }
+        self.buffer.fill(allocator, &data)?;
+        update_storage_descriptor(device, descriptor_set_lights, &self.buffer);
+        lights.update_cookie_descriptors(
+            device,
+            descriptor_set_lights,
+            texture_storage,
+            sampler,
+            fallback,
+        )?;
+        self.uploaded = data;
+        self.cookies = Some(lights.cookie_textures());
+        self.generation = Some(lights.generation());
         Ok(())
     }
+
+    pub fn destroy(&mut self) -> RendererResult<()> {
+        self.buffer.queue_free()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Floats before the first spot light with no directional or point lights
+    const HEADER: usize = 4;
+    const SPOT_LIGHT_FLOATS: usize = 16;
+
+    fn spot_light(x: f32) -> SpotLight {
+        SpotLight {
+            position: na::Point3::new(x, 2.0, 3.0),
+            direction: na::Unit::new_normalize(glm::vec3(0.0, -1.0, 0.0)),
+            inner_angle: 0.0,
+            outer_angle: std::f32::consts::FRAC_PI_3,
+            luminous_flux: glm::vec3(10.0, 20.0, 30.0),
+            cookie: None,
+        }
+    }
+
+    fn add_spot_light(lights: &mut LightManager, x: f32) -> Handle<SpotLight> {
+        match lights.add_light(spot_light(x)) {
+            LightHandle::Spot(handle) => handle,
+            _ => unreachable!(),
+        }
+    }
+
+    fn cookie_slots(lights: &LightManager) -> Vec<f32> {
+        let data = lights.storage_data();
+        (0..lights.spot_lights().len())
+            .map(|i| data[HEADER + i * SPOT_LIGHT_FLOATS + 14])
+            .collect()
+    }
+
+    #[test]
+    fn spot_lights_are_packed_in_vec4s() {
+        let mut lights = LightManager::default();
+        add_spot_light(&mut lights, 1.0);
+        let data = lights.storage_data();
+        assert_eq!(data.len(), HEADER + SPOT_LIGHT_FLOATS);
+        assert_eq!(&data[..HEADER], &[0.0, 0.0, 1.0, 0.0]);
+        let cos_outer = std::f32::consts::FRAC_PI_3.cos();
+        assert_eq!(
+            &data[HEADER..],
+            &[
+                1.0, 2.0, 3.0, 0.0, // position
+                10.0, 20.0, 30.0, 0.0, // luminous flux
+                0.0, -1.0, 0.0, 0.0, // direction
+                1.0, cos_outer, -1.0, 0.0, // cone and no cookie
+            ]
+        );
+    }
+
+    #[test]
+    fn spot_lights_follow_the_other_lights() {
+        let mut lights = LightManager::default();
+        lights.add_light(PointLight::default());
+        lights.add_light(DirectionalLight {
+            direction: na::Unit::new_normalize(glm::vec3(1.0, 0.0, 0.0)),
+            illuminance: glm::vec3(1.0, 1.0, 1.0),
+        });
+        add_spot_light(&mut lights, 5.0);
+        let data = lights.storage_data();
+        assert_eq!(&data[..HEADER], &[1.0, 1.0, 1.0, 0.0]);
+        assert_eq!(data[HEADER + 8 + 8], 5.0);
+    }
+
+    #[test]
+    fn shared_cookies_share_a_slot() {
+        let mut lights = LightManager::default();
+        let handles: Vec<_> = (0..4)
+            .map(|i| add_spot_light(&mut lights, i as f32))
+            .collect();
+        lights
+            .attach_cookie(handles[0], Handle::for_test(7))
+            .unwrap();
+        lights
+            .attach_cookie(handles[2], Handle::for_test(3))
+            .unwrap();
+        lights
+            .attach_cookie(handles[3], Handle::for_test(7))
+            .unwrap();
+
+        assert_eq!(
+            lights.cookie_textures(),
+            vec![Handle::for_test(7), Handle::for_test(3)]
+        );
+        assert_eq!(cookie_slots(&lights), vec![0.0, -1.0, 1.0, 0.0]);
+
+        // The slots are handed out again in the order of the lights
+        lights.detach_cookie(handles[0]).unwrap();
+        assert_eq!(cookie_slots(&lights), vec![-1.0, -1.0, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn cookies_past_the_slots_are_dropped() {
+        let mut lights = LightManager::default();
+        for i in 0..MAX_LIGHT_COOKIES + 2 {
+            let handle = add_spot_light(&mut lights, i as f32);
+            lights
+                .attach_cookie(handle, Handle::for_test(i + 1))
+                .unwrap();
+        }
+        assert_eq!(lights.cookie_textures().len(), MAX_LIGHT_COOKIES);
+        let slots = cookie_slots(&lights);
+        let expected: Vec<f32> = (0..MAX_LIGHT_COOKIES)
+            .map(|slot| slot as f32)
+            .chain([-1.0, -1.0])
+            .collect();
+        assert_eq!(slots, expected);
+    }
+
+    #[test]
+    fn attaching_to_a_removed_light_fails() {
+        let mut lights = LightManager::default();
+        let handle = add_spot_light(&mut lights, 0.0);
+        lights.remove_light(LightHandle::Spot(handle)).unwrap();
+        assert!(lights.attach_cookie(handle, Handle::for_test(1)).is_err());
+    }
 }
@@ -0,0 +1,214 @@
+use ash::vk;
+use nalgebra_glm as glm;
+
+use super::{
+    material::{EffectTemplate, MaterialSystem},
+    shaders::ShaderCache,
+    utils::Handle,
+    RendererResult,
+};
+
+// Lightmaps are baked into and read back from a target of this format, the encoded
+// irradiance in RGB and the coverage in alpha
+pub const LIGHTMAP_FORMAT: vk::Format = vk::Format::R8G8B8A8_UNORM;
+
+// Matches the push constant block of lightmap_bake.vert
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct LightmapPushConstants {
+    model_matrix: [[f32; 4]; 4],
+    normal_offset: f32,
+}
+
+impl LightmapPushConstants {
+    // Positions are moved normal_offset along their normal before the shadow lookup
+    pub fn new(model: &glm::Mat4, normal_offset: f32) -> Self {
+        Self {
+            model_matrix: (*model).into(),
+            normal_offset,
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe {
+            std::slice::from_raw_parts(
+                self as *const Self as *const u8,
+                std::mem::size_of::<Self>(),
+            )
+        }
+    }
+}
+
+// Grows the texels lightmap_bake.frag covered by a texel, so that filtering at UV seams
+// doesn't pull in black, and makes every texel opaque. The texels are RGBA8, row by row
+// for resolution x resolution texels, with an alpha of 0 where the mesh isn't.
+pub(crate) fn grow_coverage(texels: &mut [u8], resolution: u32) {
+    let size = resolution as usize;
+    let is_covered = |texels: &[u8], index: usize| texels[4 * index + 3] != 0;
+    let covered = texels.to_vec();
+    for y in 0..size {
+        for x in 0..size {
+            if is_covered(&covered, y * size + x) {
+                continue;
+            }
+            let neighbour = (-1i64..=1)
+                .flat_map(|dy| (-1i64..=1).map(move |dx| (x as i64 + dx, y as i64 + dy)))
+                .filter(|(nx, ny)| {
+                    *nx >= 0 && *ny >= 0 && (*nx as usize) < size && (*ny as usize) < size
+                })
+                .map(|(nx, ny)| ny as usize * size + nx as usize)
+                .find(|index| is_covered(&covered, *index));
+            if let Some(neighbour) = neighbour {
+                let index = y * size + x;
+                texels[4 * index..4 * index + 4]
+                    .copy_from_slice(&covered[4 * neighbour..4 * neighbour + 4]);
+            }
+        }
+    }
+    for texel in texels.chunks_exact_mut(4) {
+        texel[3] = u8::MAX;
+    }
+}
+
+// Only created by the first bake, kept until the renderer is dropped. Draws a mesh in
+// its UV space, lightmap_bake.frag lights every texel it covers. The template reads the
+// camera slot and lights set of the frames like the scene templates, so the shadow map
+// and the light data of the frames are the ones baked.
+pub(crate) struct LightmapBaker {
+    pub render_pass: vk::RenderPass,
+    pub template: Handle<EffectTemplate>,
+}
+
+impl LightmapBaker {
+    pub fn new(
+        device: &ash::Device,
+        material_system: &mut MaterialSystem,
+        shader_cache: &mut ShaderCache,
+    ) -> RendererResult<Self> {
+        let render_pass = Self::create_render_pass(device)?;
+        let template =
+            material_system.build_lightmap_bake_template(device, render_pass, shader_cache)?;
+        Ok(Self {
+            render_pass,
+            template,
+        })
+    }
+
+    // The lightmap ends up ready to be copied from. The depth attachment is only there
+    // so the target can be a RenderTarget::new_offscreen, nothing tests against it.
+    fn create_render_pass(device: &ash::Device) -> RendererResult<vk::RenderPass> {
+        let attachments = [
+            vk::AttachmentDescription::builder()
+                .format(LIGHTMAP_FORMAT)
+                .load_op(vk::AttachmentLoadOp::CLEAR)
+                .store_op(vk::AttachmentStoreOp::STORE)
+                .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+                .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+                .initial_layout(vk::ImageLayout::UNDEFINED)
+                .final_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+                .samples(vk::SampleCountFlags::TYPE_1)
+                .build(),
+            vk::AttachmentDescription::builder()
+                .format(vk::Format::D32_SFLOAT)
+                .load_op(vk::AttachmentLoadOp::DONT_CARE)
+                .store_op(vk::AttachmentStoreOp::DONT_CARE)
+                .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+                .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+                .initial_layout(vk::ImageLayout::UNDEFINED)
+                .final_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
+                .samples(vk::SampleCountFlags::TYPE_1)
+                .build(),
+        ];
+        let color_attachment_references = [vk::AttachmentReference {
+            attachment: 0,
+            layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+        }];
+        let depth_attachment_reference = vk::AttachmentReference {
+            attachment: 1,
+            layout: vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+        };
+
+        let subpasses = [vk::SubpassDescription::builder()
+            .color_attachments(&color_attachment_references)
+            .depth_stencil_attachment(&depth_attachment_reference)
+            .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+            .build()];
+
+        let subpass_dependencies = [vk::SubpassDependency::builder()
+            .src_subpass(0)
+            .src_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
+            .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+            .dst_subpass(vk::SUBPASS_EXTERNAL)
+            .dst_stage_mask(vk::PipelineStageFlags::TRANSFER)
+            .dst_access_mask(vk::AccessFlags::TRANSFER_READ)
+            .build()];
+
+        let renderpass_info = vk::RenderPassCreateInfo::builder()
+            .attachments(&attachments)
+            .subpasses(&subpasses)
+            .dependencies(&subpass_dependencies);
+        unsafe { Ok(device.create_render_pass(&renderpass_info, None)?) }
+    }
+
+    // The pipeline belongs to the template
+    pub fn destroy(&mut self, device: &ash::Device) {
+        unsafe {
+            device.destroy_render_pass(self.render_pass, None);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Covered texels as lightmap_bake.frag leaves them, the rest is cleared to zeros
+    fn texels(resolution: u32, covered: impl Fn(u32, u32) -> bool) -> Vec<u8> {
+        (0..resolution)
+            .flat_map(|y| (0..resolution).map(move |x| (x, y)))
+            .flat_map(|(x, y)| {
+                if covered(x, y) {
+                    [x as u8 + 1, y as u8 + 1, 7, u8::MAX]
+                } else {
+                    [0; 4]
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn coverage_grows_by_a_texel() {
+        // The left half of the lightmap, like a quad with its UVs in [0, 0.5] x [0, 1]
+        let mut lightmap = texels(8, |x, _| x < 4);
+        grow_coverage(&mut lightmap, 8);
+        for y in 0..8 {
+            for x in 0..8 {
+                let texel = &lightmap[4 * (y * 8 + x)..4 * (y * 8 + x) + 4];
+                match x {
+                    0..=3 => assert_eq!(texel, [x as u8 + 1, y as u8 + 1, 7, 255]),
+                    // Copied from the first covered neighbour, the one above to the left
+                    4 => assert_eq!(texel[0], 4, "texel {} {}", x, y),
+                    _ => assert_eq!(texel, [0, 0, 0, 255], "texel {} {}", x, y),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn texels_far_from_the_mesh_stay_black() {
+        let mut lightmap = texels(4, |_, _| false);
+        grow_coverage(&mut lightmap, 4);
+        assert!(lightmap
+            .chunks_exact(4)
+            .all(|texel| texel == [0, 0, 0, 255]));
+    }
+
+    #[test]
+    fn push_constants_match_the_shader_block() {
+        // A mat4 and a float
+        assert_eq!(std::mem::size_of::<LightmapPushConstants>(), 68);
+        let constants = LightmapPushConstants::new(&glm::identity(), 0.25);
+        assert_eq!(constants.as_bytes().len(), 68);
+        assert_eq!(&constants.as_bytes()[64..], &0.25f32.to_ne_bytes());
+    }
+}
@@ -0,0 +1,483 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::time::Duration;
+
+use nalgebra_glm as glm;
+use serde::{Deserialize, Serialize};
+
+use super::buffer::Buffer;
+use super::error::SceneFileError;
+use super::light::LightHandle;
+use super::material::Material;
+use super::material_file::MaterialFile;
+use super::mesh::Mesh;
+use super::probe::LightProbe;
+use super::scene::{SceneGroup, SceneObject};
+use super::scene_file::SceneFile;
+use super::texture::Texture;
+use super::utils::Handle;
+use super::RendererResult;
+
+pub struct TextureDescription {
+    pub name: String,
+    pub path: PathBuf,
+}
+
+// Also what MeshManager remembers about the meshes it built, see SceneFile
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum MeshSource {
+    Obj(PathBuf),
+    Sphere(u32),
+    Cube,
+    Icosahedron,
+}
+
+pub struct MeshDescription {
+    pub name: String,
+    pub source: MeshSource,
+}
+
+pub struct MaterialDescription {
+    pub name: String,
+    pub base_template: String,
+    // Names of textures from SceneDescription::textures, in the binding order of the
+    // template's texture slots
+    pub textures: Vec<String>,
+    // Uploaded into a uniform buffer that is bound after the textures
+    pub uniform_data: Vec<f32>,
+}
+
+pub struct ObjectDescription {
+    pub mesh: String,
+    pub material: String,
+    pub position: glm::Vec3,
+    pub rotation: glm::Quat,
+    pub scaling: glm::Vec3,
+    // Index into SceneDescription::objects, the parent has to come before its children
+    pub parent: Option<usize>,
+}
+
+impl ObjectDescription {
+    pub fn new<S: Into<String>>(mesh: S, material: S) -> Self {
+        Self {
+            mesh: mesh.into(),
+            material: material.into(),
+            position: glm::Vec3::default(),
+            rotation: glm::Quat::identity(),
+            scaling: glm::Vec3::new(1.0, 1.0, 1.0),
+            parent: None,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct SceneDescription {
+    pub textures: Vec<TextureDescription>,
+    pub meshes: Vec<MeshDescription>,
+    pub materials: Vec<MaterialDescription>,
+    pub objects: Vec<ObjectDescription>,
+    // The renderer doesn't own the lights, these are handed back in SceneLoadResult for
+    // the application to add with LightManager::add_probe
+    pub probes: Vec<LightProbe>,
+}
+
+#[derive(Debug, Default)]
+pub struct SceneLoadResult {
+    pub textures: HashMap<String, Handle<Texture>>,
+    pub meshes: HashMap<String, Handle<Mesh>>,
+    pub materials: HashMap<String, Handle<Material>>,
+    pub objects: Vec<Handle<SceneObject>>,
+    pub probes: Vec<LightProbe>,
+    // Only filled by Renderer::load_scene_file, which adds the lights itself
+    pub lights: Vec<LightHandle>,
+}
+
+// What ResourceContext::new_obj_scene created
+#[derive(Debug)]
+pub struct ObjSceneInstance {
+    // Holds every object of the scene, to move or hide them together
+    pub group: Handle<SceneGroup>,
+    // Named like the groups of the file, in the same order
+    pub objects: Vec<(String, Handle<SceneObject>)>,
+    pub meshes: Vec<Handle<Mesh>>,
+}
+
+impl ObjSceneInstance {
+    pub fn find_object(&self, name: &str) -> Option<Handle<SceneObject>> {
+        self.objects
+            .iter()
+            .find(|(object_name, _)| object_name == name)
+            .map(|(_, handle)| *handle)
+    }
+}
+
+// What Renderer::spawn_obj created, in the order of the submeshes of the file
+#[derive(Debug)]
+pub struct SpawnedObj {
+    // Hidden, only carries the transform of the objects
+    pub parent: Handle<SceneObject>,
+    // One per submesh, all children of parent
+    pub objects: Vec<Handle<SceneObject>>,
+    pub meshes: Vec<Handle<Mesh>>,
+    pub materials: Vec<Handle<Material>>,
+    // Of the diffuse textures, or of the diffuse colors for materials without one
+    pub textures: Vec<Handle<Texture>>,
+}
+
+// What Renderer::spawn_gltf created
+#[derive(Debug)]
+pub struct SpawnedGltf {
+    // Hidden, the parent of the root nodes
+    pub root: Handle<SceneObject>,
+    // One hidden object per node carrying its transform, named like the nodes, parents
+    // before their children
+    pub nodes: Vec<(String, Handle<SceneObject>)>,
+    // One per primitive of the mesh of a node, children of the node's object
+    pub objects: Vec<Handle<SceneObject>>,
+    // One per primitive of the file, shared by the nodes using the same mesh
+    pub meshes: Vec<Handle<Mesh>>,
+    // Of the glTF materials in the order of the file, followed by the default material
+    pub materials: Vec<Handle<Material>>,
+    pub textures: Vec<Handle<Texture>>,
+}
+
+impl SpawnedGltf {
+    pub fn find_node(&self, name: &str) -> Option<Handle<SceneObject>> {
+        self.nodes
+            .iter()
+            .find(|(node_name, _)| node_name == name)
+            .map(|(_, handle)| *handle)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LoadProgress {
+    pub done: usize,
+    pub total: usize,
+    pub current_item: String,
+}
+
+#[derive(Clone, Copy)]
+pub(crate) enum LoadStep {
+    // Only for scene files, waits for the loader thread to parse the file
+    Parse,
+    Texture(usize),
+    Mesh(usize),
+    Material(usize),
+    // All objects are added in one step, so that a half loaded hierarchy never shows up.
+    // The lights of a scene file are added with them.
+    Objects,
+}
+
+// A scene file of Renderer::load_scene_file_incremental. It is read and parsed on a thread
+// of its own, its meshes become the meshes of the description and its materials, objects
+// and lights are loaded like those of load_scene_file.
+pub(crate) struct FileLoad {
+    pub path: PathBuf,
+    // Until the loader thread has sent the parsed file
+    parsing: Option<Receiver<RendererResult<SceneFile>>>,
+    // Why the file couldn't be parsed, returned again by every later step
+    error: Option<String>,
+    pub scene: SceneFile,
+}
+
+pub struct SceneLoadTicket {
+    pub(crate) description: SceneDescription,
+    pub(crate) file: Option<FileLoad>,
+    pub(crate) next_step: usize,
+    pub(crate) result: SceneLoadResult,
+    pub(crate) uniform_buffers: Vec<Buffer>,
+    result_taken: bool,
+}
+
+impl SceneLoadTicket {
+    pub(crate) fn new(mut description: SceneDescription) -> Self {
+        Self {
+            result: SceneLoadResult {
+                probes: std::mem::take(&mut description.probes),
+                ..Default::default()
+            },
+            description,
+            file: None,
+            next_step: 0,
+            uniform_buffers: vec![],
+            result_taken: false,
+        }
+    }
+
+    // The steps after the parse step are only known once the loader thread is done
+    pub(crate) fn from_file(path: PathBuf) -> Self {
+        let (sender, receiver) = mpsc::channel();
+        let thread_path = path.clone();
+        std::thread::spawn(move || {
+            // Nobody listens anymore when the ticket was dropped in the meantime
+            let _ = sender.send(SceneFile::load(thread_path));
+        });
+        Self {
+            file: Some(FileLoad {
+                path,
+                parsing: Some(receiver),
+                error: None,
+                scene: SceneFile::default(),
+            }),
+            ..Self::new(SceneDescription::default())
+        }
+    }
+
+    // Waits up to timeout for the loader thread and returns whether the file is parsed.
+    // Always true for tickets of a description.
+    pub(crate) fn wait_parsed(&mut self, timeout: Duration) -> RendererResult<bool> {
+        let file = match self.file.as_mut() {
+            Some(file) => file,
+            None => return Ok(true),
+        };
+        if let Some(error) = file.error.as_ref() {
+            return Err(SceneFileError(error.clone()).into());
+        }
+        let scene = match file
+            .parsing
+            .as_ref()
+            .map(|parsing| parsing.recv_timeout(timeout))
+        {
+            None => return Ok(true),
+            Some(Ok(scene)) => scene,
+            Some(Err(RecvTimeoutError::Timeout)) => return Ok(false),
+            Some(Err(RecvTimeoutError::Disconnected)) => Err(SceneFileError(format!(
+                "{}: the loader thread stopped without a result",
+                file.path.display()
+            ))
+            .into()),
+        };
+        file.parsing = None;
+        file.scene = match scene {
+            Ok(scene) => scene,
+            Err(e) => {
+                file.error = Some(match std::error::Error::source(&e) {
+                    Some(source) => format!("{}: {}", e, source),
+                    None => e.to_string(),
+                });
+                return Err(e);
+            }
+        };
+        self.description.meshes = file
+            .scene
+            .meshes
+            .iter()
+            .map(|(name, source)| MeshDescription {
+                name: name.clone(),
+                source: match source {
+                    MeshSource::Obj(obj) => {
+                        MeshSource::Obj(MaterialFile::resolve_texture_path(&file.path, obj))
+                    }
+                    other => other.clone(),
+                },
+            })
+            .collect();
+        Ok(true)
+    }
+
+    // The file of a ticket of Renderer::load_scene_file_incremental, once it is parsed
+    pub(crate) fn parsed_file(&self) -> Option<(&Path, &SceneFile)> {
+        match self.file.as_ref() {
+            Some(file) if file.parsing.is_none() && file.error.is_none() => {
+                Some((&file.path, &file.scene))
+            }
+            _ => None,
+        }
+    }
+
+    fn parse_steps(&self) -> usize {
+        if self.file.is_some() {
+            1
+        } else {
+            0
+        }
+    }
+
+    fn material_count(&self) -> usize {
+        match self.file.as_ref() {
+            Some(file) => file.scene.materials.len(),
+            None => self.description.materials.len(),
+        }
+    }
+
+    fn has_objects(&self) -> bool {
+        match self.file.as_ref() {
+            Some(file) => !file.scene.objects.is_empty() || !file.scene.lights.is_empty(),
+            None => !self.description.objects.is_empty(),
+        }
+    }
+
+    fn total_steps(&self) -> usize {
+        let objects = if self.has_objects() { 1 } else { 0 };
+        self.parse_steps()
+            + self.description.textures.len()
+            + self.description.meshes.len()
+            + self.material_count()
+            + objects
+    }
+
+    pub(crate) fn step(&self, index: usize) -> Option<LoadStep> {
+        let mut index = index;
+        if index < self.parse_steps() {
+            return Some(LoadStep::Parse);
+        }
+        index -= self.parse_steps();
+        if index < self.description.textures.len() {
+            return Some(LoadStep::Texture(index));
+        }
+        index -= self.description.textures.len();
+        if index < self.description.meshes.len() {
+            return Some(LoadStep::Mesh(index));
+        }
+        index -= self.description.meshes.len();
+        if index < self.material_count() {
+            return Some(LoadStep::Material(index));
+        }
+        index -= self.material_count();
+        if index == 0 && self.has_objects() {
+            return Some(LoadStep::Objects);
+        }
+        None
+    }
+
+    fn step_name(&self, step: LoadStep) -> String {
+        match step {
+            LoadStep::Parse => self
+                .file
+                .as_ref()
+                .map(|file| file.path.display().to_string())
+                .unwrap_or_default(),
+            LoadStep::Texture(i) => self.description.textures[i].name.clone(),
+            LoadStep::Mesh(i) => self.description.meshes[i].name.clone(),
+            LoadStep::Material(i) => match self.file.as_ref() {
+                Some(file) => file
+                    .scene
+                    .materials
+                    .keys()
+                    .nth(i)
+                    .cloned()
+                    .unwrap_or_default(),
+                None => self.description.materials[i].name.clone(),
+            },
+            LoadStep::Objects => "objects".to_string(),
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.next_step >= self.total_steps()
+    }
+
+    pub fn progress(&self) -> LoadProgress {
+        LoadProgress {
+            done: self.next_step.min(self.total_steps()),
+            total: self.total_steps(),
+            current_item: self
+                .step(self.next_step)
+                .map(|s| self.step_name(s))
+                .unwrap_or_default(),
+        }
+    }
+
+    // Only returns the result once, after loading has finished
+    pub fn take_result(&mut self) -> Option<SceneLoadResult> {
+        if !self.is_finished() || self.result_taken {
+            return None;
+        }
+        self.result_taken = true;
+        Some(std::mem::take(&mut self.result))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_scene(name: &str, source: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("{}_{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("scene.ron");
+        std::fs::write(&path, source).unwrap();
+        path
+    }
+
+    #[test]
+    fn scene_files_are_parsed_into_steps() {
+        let path = write_scene(
+            "loading_steps",
+            r#"(
+                meshes: {"box": Cube, "ship": Obj("models/ship.obj")},
+                materials: {
+                    "hull": (
+                        base_template: "default",
+                        textures: [None],
+                        parameters: {},
+                        uv_offset: (0.0, 0.0),
+                        uv_scale: (1.0, 1.0),
+                        uv_rotation: 0.0,
+                    ),
+                },
+                objects: [
+                    (
+                        mesh: "ship",
+                        material: "hull",
+                        position: (0.0, 1.0, 0.0),
+                        rotation: (0.0, 0.0, 0.0, 1.0),
+                        scaling: (1.0, 1.0, 1.0),
+                        parent: None,
+                        visible: true,
+                    ),
+                ],
+            )"#,
+        );
+        let mut ticket = SceneLoadTicket::from_file(path.clone());
+        assert!(ticket.wait_parsed(Duration::MAX).unwrap());
+        // Parse, two meshes, one material and the objects
+        let progress = ticket.progress();
+        assert_eq!(progress.done, 0);
+        assert_eq!(progress.total, 5);
+        assert_eq!(progress.current_item, path.display().to_string());
+        let ship = &ticket.description.meshes[1];
+        assert_eq!(ship.name, "ship");
+        assert_eq!(
+            ship.source,
+            MeshSource::Obj(path.parent().unwrap().join("models/ship.obj"))
+        );
+
+        let names = (1..5)
+            .map(|i| ticket.step_name(ticket.step(i).unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["box", "ship", "hull", "objects"]);
+        assert!(ticket.step(5).is_none());
+        ticket.next_step = 5;
+        assert!(ticket.is_finished());
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn unparsable_files_fail_every_step() {
+        let path = write_scene("loading_broken", "(meshes: {\"box\": Cube");
+        let mut ticket = SceneLoadTicket::from_file(path.clone());
+        assert!(ticket.wait_parsed(Duration::MAX).is_err());
+        // Retrying doesn't finish with an empty scene
+        assert!(ticket.wait_parsed(Duration::MAX).is_err());
+        assert!(ticket.parsed_file().is_none());
+        assert!(!ticket.is_finished());
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn description_tickets_have_no_parse_step() {
+        let mut ticket = SceneLoadTicket::new(SceneDescription {
+            meshes: vec![MeshDescription {
+                name: "box".to_string(),
+                source: MeshSource::Cube,
+            }],
+            ..Default::default()
+        });
+        assert!(ticket.wait_parsed(Duration::ZERO).unwrap());
+        assert!(matches!(ticket.step(0), Some(LoadStep::Mesh(0))));
+        assert_eq!(ticket.progress().total, 1);
+    }
+}
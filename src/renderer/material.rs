@@ -6,19 +6,31 @@ use std::{
 };
 
 use ash::vk;
+use gpu_allocator::{vulkan::Allocator, MemoryLocation};
 use itertools::Itertools;
+use nalgebra_glm as glm;
+use serde::{Deserialize, Serialize};
 
 use super::{
-    buffer::{BufferManager, InternalBuffer},
+    aov::AOV_FORMATS,
+    buffer::{Buffer, BufferManager, InternalBuffer},
+    config::TransparencyTechnique,
+    debug_namer::DebugNamer,
     descriptor::{DescriptorAllocator, DescriptorBuilder, DescriptorLayoutCache},
-    error::{InvalidHandle, MissingTemplate, RendererError},
-    shaders::{ShaderCache, ShaderEffect},
+    error::{
+        BindingContractError, IncompatibleMaterial, InvalidHandle, MissingTemplate, RendererError,
+    },
+    particles::ParticleInstance,
+    shaders::{BindingContract, ParameterBlock, ShaderCache, ShaderEffect},
+    stats::FrameEvents,
     text::TextVertexData,
     texture::{Texture, TextureStorage},
-    utils::{Handle, HandleArray},
+    utils::{fnv1a, fnv1a_start, Handle, HandleArray},
     vertex::Vertex,
     RendererResult,
 };
+#[cfg(feature = "shader-reload")]
+use super::{error::ShaderReloadError, shaders::RetiredShaders};
 
 // TODO move this somewhere
 pub enum MeshPassType {
@@ -47,23 +59,46 @@ pub struct VertexInputDescription {
 pub struct PipelineBuilder {
     shader_stages: Vec<vk::PipelineShaderStageCreateInfo>,
     vertex_description: VertexInputDescription,
-    vertex_input_info: vk::PipelineVertexInputStateCreateInfo,
     input_assembly: vk::PipelineInputAssemblyStateCreateInfo,
     viewport: vk::Viewport,
     scissor: vk::Rect2D,
     rasterizer: vk::PipelineRasterizationStateCreateInfo,
     color_blend_attachment: vk::PipelineColorBlendAttachmentState,
+    // Every color attachment blends the same, 0 means 1
+    color_attachment_count: usize,
+    // One per color attachment when they blend differently, replaces the two above
+    color_blend_attachments: Vec<vk::PipelineColorBlendAttachmentState>,
+    // For render passes without color attachments, e.g. the shadow map
+    depth_only: bool,
+    // The Transparency passes go into the later subpasses of the scene render passes, see
+    // Renderer::create_scene_subpasses
+    subpass: u32,
     multisampling: vk::PipelineMultisampleStateCreateInfo,
     pipeline_layout: vk::PipelineLayout,
     depth_stencil: vk::PipelineDepthStencilStateCreateInfo,
 }
 
+// SAFETY: The create infos hold raw pointers, which is all that keeps the builder from
+// being Send. None of them points at data owned by the builder or by any thread:
+// - p_name of every shader stage is MAIN_FUNCTION_NAME, a &'static CStr set by
+//   ShaderEffect::get_stages, and p_next and p_specialization_info are null
+// - p_next of input_assembly, rasterizer, multisampling and depth_stencil is null, and so
+//   is p_sample_mask of multisampling. They are only ever set from builders without
+//   push_next or sample_mask, in this module.
+// The pointers are only read by Vulkan inside build_pipeline, which checks them in debug
+// builds. The pointer-free vertex_description replaces the vertex input create info,
+// whose pointers would point into the builder. Moving the builder to another thread
+// can't leave anything dangling or shared.
+unsafe impl Send for PipelineBuilder {}
+
 impl PipelineBuilder {
     pub fn build_pipeline(
         &self,
         device: &ash::Device,
+        pipeline_cache: vk::PipelineCache,
         render_pass: vk::RenderPass,
     ) -> RendererResult<vk::Pipeline> {
+        debug_assert!(self.has_no_owned_pointers());
         let vertex_input_info = vk::PipelineVertexInputStateCreateInfo::builder()
             .vertex_attribute_descriptions(&self.vertex_description.attributes[..])
             .vertex_binding_descriptions(&self.vertex_description.bindings[..])
@@ -76,7 +111,13 @@ impl PipelineBuilder {
             .viewports(&viewports)
             .scissors(&scissors);
 
-        let attachments = [self.color_blend_attachment];
+        let attachments = if self.depth_only {
+            vec![]
+        } else if !self.color_blend_attachments.is_empty() {
+            self.color_blend_attachments.clone()
+        } else {
+            vec![self.color_blend_attachment; self.color_attachment_count.max(1)]
+        };
 
         let color_blend_info = vk::PipelineColorBlendStateCreateInfo::builder()
             .logic_op_enable(false)
@@ -98,15 +139,11 @@ impl PipelineBuilder {
             .layout(self.pipeline_layout)
             .render_pass(render_pass)
             .dynamic_state(&dynamic_state_create_info)
-            .subpass(0);
+            .subpass(self.subpass);
 
         unsafe {
             device
-                .create_graphics_pipelines(
-                    vk::PipelineCache::null(),
-                    &[pipeline_info.build()],
-                    None,
-                )
+                .create_graphics_pipelines(pipeline_cache, &[pipeline_info.build()], None)
                 .map_err(|(_pipelines, err)| {
                     // TODO delete created pipelines on error?
                     err.into()
@@ -115,13 +152,20 @@ impl PipelineBuilder {
         }
     }
 
-    pub fn clear_vertex_input(&mut self) {
-        // TODO is there a better way to clear these?
-        self.vertex_input_info.p_vertex_attribute_descriptions = std::ptr::null();
-        self.vertex_input_info.vertex_attribute_description_count = 0;
+    // What the Send impl relies on
+    fn has_no_owned_pointers(&self) -> bool {
+        self.shader_stages
+            .iter()
+            .all(|stage| stage.p_next.is_null() && stage.p_specialization_info.is_null())
+            && self.input_assembly.p_next.is_null()
+            && self.rasterizer.p_next.is_null()
+            && self.multisampling.p_next.is_null()
+            && self.multisampling.p_sample_mask.is_null()
+            && self.depth_stencil.p_next.is_null()
+    }
 
-        self.vertex_input_info.p_vertex_binding_descriptions = std::ptr::null();
-        self.vertex_input_info.vertex_binding_description_count = 0;
+    pub fn clear_vertex_input(&mut self) {
+        self.vertex_description = VertexInputDescription::default();
     }
 
     pub fn set_shaders(
@@ -157,14 +201,28 @@ pub struct ComputePipelineBuilder {
 }
 
 impl ComputePipelineBuilder {
-    pub fn build_pipeline(&self, device: &ash::Device) -> RendererResult<vk::Pipeline> {
+    pub fn new(
+        shader_stage: vk::PipelineShaderStageCreateInfo,
+        pipeline_layout: vk::PipelineLayout,
+    ) -> Self {
+        Self {
+            shader_stage,
+            pipeline_layout,
+        }
+    }
+
+    pub fn build_pipeline(
+        &self,
+        device: &ash::Device,
+        pipeline_cache: vk::PipelineCache,
+    ) -> RendererResult<vk::Pipeline> {
         let create_info = vk::ComputePipelineCreateInfo::builder()
             .stage(self.shader_stage)
             .layout(self.pipeline_layout);
 
         let pipelines = unsafe {
             device
-                .create_compute_pipelines(vk::PipelineCache::null(), &[*create_info], None)
+                .create_compute_pipelines(pipeline_cache, &[*create_info], None)
                 .map_err::<RendererError, _>(|(_, err)| err.into())?
         };
         Ok(pipelines[0])
@@ -176,6 +234,11 @@ pub struct BuiltShaderPass {
     pub effect_handle: Option<Handle<ShaderEffect>>,
     pub pipeline: vk::Pipeline,
     pub layout: vk::PipelineLayout,
+    // What the pipeline was built with, to build it again from reloaded shaders
+    #[cfg(feature = "shader-reload")]
+    builder: PipelineBuilder,
+    #[cfg(feature = "shader-reload")]
+    render_pass: vk::RenderPass,
 }
 
 pub struct BuiltPerPassData<T> {
@@ -214,15 +277,153 @@ impl<T> IndexMut<MeshPassType> for BuiltPerPassData<T> {
     }
 }
 
-#[derive(Default, Clone, PartialEq)]
+// How a change to the material of one name is made, see MaterialSystem::modify_material
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum MaterialWrite {
+    Unchanged,
+    // Other names share the material, so the changed name gets a copy of its own
+    Split,
+    // The changed material is identical to another one, the name aliases that one
+    Alias(Handle<Material>),
+    // Nobody else sees the material, so it can be changed in place
+    InPlace,
+}
+
+fn material_write(
+    unchanged: bool,
+    shared: bool,
+    existing: Option<Handle<Material>>,
+) -> MaterialWrite {
+    match (unchanged, shared, existing) {
+        (true, _, _) => MaterialWrite::Unchanged,
+        (false, true, _) => MaterialWrite::Split,
+        (false, false, Some(existing)) => MaterialWrite::Alias(existing),
+        (false, false, None) => MaterialWrite::InPlace,
+    }
+}
+
+// The names pointing at handle, sorted
+fn aliases(materials: &HashMap<String, Handle<Material>>, handle: Handle<Material>) -> Vec<String> {
+    materials
+        .iter()
+        .filter(|(_, h)| **h == handle)
+        .map(|(name, _)| name.clone())
+        .sorted()
+        .collect()
+}
+
+// A value of the material_parameters block. Floats only fill the first component of
+// vector members, vectors only as many components as the member has.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ParameterValue {
+    Float(f32),
+    Vec4([f32; 4]),
+}
+
+impl From<f32> for ParameterValue {
+    fn from(value: f32) -> Self {
+        ParameterValue::Float(value)
+    }
+}
+
+impl From<glm::Vec4> for ParameterValue {
+    fn from(value: glm::Vec4) -> Self {
+        ParameterValue::Vec4(value.into())
+    }
+}
+
+impl ParameterValue {
+    fn components(&self) -> &[f32] {
+        match self {
+            ParameterValue::Float(value) => std::slice::from_ref(value),
+            ParameterValue::Vec4(values) => values.as_slice(),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq)]
 pub struct ShaderParameters {
-    parameters: HashMap<String, f32>, // TODO support more parameter types
+    parameters: HashMap<String, ParameterValue>,
+    // Applied to the texture coordinates before sampling, pushed as push constants
+    pub uv_offset: glm::Vec2,
+    pub uv_scale: glm::Vec2,
+    pub uv_rotation: f32,
+}
+
+impl Default for ShaderParameters {
+    fn default() -> Self {
+        Self {
+            parameters: HashMap::new(),
+            uv_offset: glm::Vec2::zeros(),
+            uv_scale: glm::Vec2::new(1.0, 1.0),
+            uv_rotation: 0.0,
+        }
+    }
+}
+
+impl ShaderParameters {
+    pub fn get(&self, name: &str) -> Option<ParameterValue> {
+        self.parameters.get(name).copied()
+    }
+
+    pub fn set<S: Into<String>, V: Into<ParameterValue>>(&mut self, name: S, value: V) {
+        self.parameters.insert(name.into(), value.into());
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, ParameterValue)> {
+        self.parameters.iter().map(|(k, v)| (k.as_str(), *v))
+    }
+
+    // The contents of the block, members without a value are taken from defaults, or
+    // zero without one there either
+    pub(crate) fn block_data(
+        &self,
+        block: &ParameterBlock,
+        defaults: &ShaderParameters,
+    ) -> Vec<u8> {
+        let mut data = vec![0u8; block.size as usize];
+        for member in block.members.iter() {
+            let value = match self
+                .get(&member.name)
+                .or_else(|| defaults.get(&member.name))
+            {
+                Some(value) => value,
+                None => continue,
+            };
+            let bytes = value
+                .components()
+                .iter()
+                .flat_map(|c| c.to_ne_bytes())
+                .take(member.size as usize)
+                .collect::<Vec<_>>();
+            let offset = member.offset as usize;
+            data[offset..offset + bytes.len()].copy_from_slice(&bytes);
+        }
+        data
+    }
+
+    // Matches the UV transform at the start of the push constant block in default.vert
+    pub(crate) fn uv_transform_push_constants(&self) -> [f32; 5] {
+        [
+            self.uv_offset.x,
+            self.uv_offset.y,
+            self.uv_scale.x,
+            self.uv_scale.y,
+            self.uv_rotation,
+        ]
+    }
 }
 
 impl Hash for ShaderParameters {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
         for (k, v) in self.parameters.iter().sorted_by_key(|p| p.0) {
             k.hash(state);
+            for c in v.components() {
+                c.to_be_bytes().hash(state);
+            }
+        }
+        for v in self.uv_transform_push_constants() {
             v.to_be_bytes().hash(state);
         }
     }
@@ -232,9 +433,48 @@ pub struct EffectTemplate {
     pub pass_shaders: BuiltPerPassData<BuiltShaderPass>,
     pub default_parameters: ShaderParameters,
     pub transparency_mode: TransparencyMode,
+    // Bumped every time the template is rebuilt, materials built against an older
+    // generation have to be rebuilt as well
+    pub generation: u32,
+    // Bound by the template's descriptor hook, see Renderer::set_descriptor_hook
+    pub user_managed_sets: Vec<u32>,
+    // The texture slots the shaders declare and their bindings, sorted by binding
+    pub texture_slots: Vec<(MaterialTextureSlot, u32)>,
+    // Size of the object_params block the shaders declare, see SceneTree::set_object_params
+    pub object_params_size: Option<u32>,
+    // The material_parameters block the shaders declare, materials without buffers of
+    // their own get one that is filled from their parameters
+    pub parameter_block: Option<ParameterBlock>,
+    // The shaders read the morph deltas, so draws push the morph constants, see morph.rs
+    pub morph_targets: bool,
 }
 
 impl EffectTemplate {
+    // The pass the objects using the template are drawn in, transparent ones after the
+    // opaque ones, back to front
+    pub fn mesh_pass(&self) -> MeshPassType {
+        match self.transparency_mode {
+            TransparencyMode::Transparent => MeshPassType::Transparency,
+            TransparencyMode::Opaque | TransparencyMode::Masked => MeshPassType::Forward,
+        }
+    }
+
+    pub fn texture_binding(&self, slot: MaterialTextureSlot) -> Option<u32> {
+        self.texture_slots
+            .iter()
+            .find(|(s, _)| *s == slot)
+            .map(|(_, binding)| *binding)
+    }
+
+    // Uniform buffers of materials are bound after the textures
+    fn first_buffer_binding(&self) -> u32 {
+        self.texture_slots
+            .iter()
+            .map(|(_, binding)| binding + 1)
+            .max()
+            .unwrap_or(0)
+    }
+
     fn destroy(&mut self, device: &ash::Device) {
         for sp in self.pass_shaders.data.iter() {
             unsafe {
@@ -245,9 +485,100 @@ impl EffectTemplate {
     }
 }
 
+// What a texture is used for. Shaders name the sampler of a slot after it, see
+// binding_name, and the template binds the slots in the order the shader declares them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum MaterialTextureSlot {
+    Albedo,
+    Normal,
+    MetallicRoughness,
+    Emissive,
+    Occlusion,
+    Lightmap,
+    Custom(u8),
+}
+
+impl MaterialTextureSlot {
+    const NAMED: [MaterialTextureSlot; 6] = [
+        MaterialTextureSlot::Albedo,
+        MaterialTextureSlot::Normal,
+        MaterialTextureSlot::MetallicRoughness,
+        MaterialTextureSlot::Emissive,
+        MaterialTextureSlot::Occlusion,
+        MaterialTextureSlot::Lightmap,
+    ];
+
+    // e.g. "albedo_tex" or "custom3_tex"
+    pub fn binding_name(&self) -> String {
+        match self {
+            MaterialTextureSlot::Albedo => "albedo_tex".to_string(),
+            MaterialTextureSlot::Normal => "normal_tex".to_string(),
+            MaterialTextureSlot::MetallicRoughness => "metallic_roughness_tex".to_string(),
+            MaterialTextureSlot::Emissive => "emissive_tex".to_string(),
+            MaterialTextureSlot::Occlusion => "occlusion_tex".to_string(),
+            MaterialTextureSlot::Lightmap => "lightmap_tex".to_string(),
+            MaterialTextureSlot::Custom(i) => format!("custom{}_tex", i),
+        }
+    }
+
+    pub fn from_binding_name(name: &str) -> Option<Self> {
+        if let Some(slot) = Self::NAMED.iter().find(|s| s.binding_name() == name) {
+            return Some(*slot);
+        }
+        name.strip_prefix("custom")?
+            .strip_suffix("_tex")?
+            .parse()
+            .ok()
+            .map(MaterialTextureSlot::Custom)
+    }
+
+    // Bound when a material leaves the slot empty, chosen so the slot has no effect
+    fn default_texture(&self, defaults: &DefaultTextures) -> Handle<Texture> {
+        match self {
+            MaterialTextureSlot::Normal => defaults.flat_normal,
+            // No glow and no baked light
+            MaterialTextureSlot::Emissive | MaterialTextureSlot::Lightmap => defaults.black,
+            // Full occlusion and metallic roughness values, the material factors scale them
+            MaterialTextureSlot::Albedo
+            | MaterialTextureSlot::MetallicRoughness
+            | MaterialTextureSlot::Occlusion
+            | MaterialTextureSlot::Custom(_) => defaults.white,
+        }
+    }
+}
+
+// Migration helper for code written when textures were a Vec whose first element was the
+// albedo. Further elements become Custom slots, which only shaders declaring them accept.
+pub fn textures_from_vec(
+    textures: Vec<Handle<Texture>>,
+) -> HashMap<MaterialTextureSlot, Handle<Texture>> {
+    textures
+        .into_iter()
+        .enumerate()
+        .map(|(i, texture)| match i {
+            0 => (MaterialTextureSlot::Albedo, texture),
+            _ => (MaterialTextureSlot::Custom(i as u8), texture),
+        })
+        .collect()
+}
+
+// 1x1 textures owned by the renderer that fill the slots materials leave empty
+#[derive(Clone, Copy, Debug)]
+pub struct DefaultTextures {
+    pub white: Handle<Texture>,
+    pub black: Handle<Texture>,
+    pub flat_normal: Handle<Texture>,
+}
+
+impl DefaultTextures {
+    pub fn contains(&self, texture: Handle<Texture>) -> bool {
+        [self.white, self.black, self.flat_normal].contains(&texture)
+    }
+}
+
 #[derive(Clone)]
 pub struct MaterialData {
-    pub textures: Vec<Handle<Texture>>,
+    pub textures: HashMap<MaterialTextureSlot, Handle<Texture>>,
     pub buffers: Vec<Handle<InternalBuffer>>,
     pub parameters: ShaderParameters,
     pub base_template: String,
@@ -257,19 +588,11 @@ impl PartialEq for MaterialData {
     fn eq(&self, other: &Self) -> bool {
         if self.base_template != other.base_template
             || self.parameters != other.parameters
-            || self.textures.len() != other.textures.len()
+            || self.textures != other.textures
             || self.buffers.len() != other.buffers.len()
         {
             return false;
         }
-        if self
-            .textures
-            .iter()
-            .zip(other.textures.iter())
-            .any(|(a, b)| a != b)
-        {
-            return false;
-        }
 
         !self
             .buffers
@@ -285,7 +608,9 @@ impl Hash for MaterialData {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
         self.base_template.hash(state);
 
-        for tex in self.textures.iter() {
+        // Sorted, so the order of the map doesn't matter
+        for (slot, tex) in self.textures.iter().sorted_by_key(|(slot, _)| **slot) {
+            slot.hash(state);
             tex.hash(state);
         }
 
@@ -299,9 +624,30 @@ impl Hash for MaterialData {
 
 pub struct Material {
     pub original: Handle<EffectTemplate>,
+    pub template_generation: u32,
     pub pass_sets: BuiltPerPassData<vk::DescriptorSet>,
-    pub textures: Vec<Handle<Texture>>,
+    // Hashes of the layouts of pass_sets, see ShaderEffect::get_set_hash
+    pub pass_set_hashes: BuiltPerPassData<u64>,
+    // Only the slots the material was given, the others are bound to defaults
+    pub textures: HashMap<MaterialTextureSlot, Handle<Texture>>,
+    pub buffers: Vec<Handle<InternalBuffer>>,
     pub parameters: ShaderParameters,
+    pub base_template: String,
+    // Filled from parameters, for templates with a material_parameters block when no
+    // buffers were given
+    parameter_buffer: Option<Buffer>,
+}
+
+impl Material {
+    // What build_material would need to build this material again
+    pub fn data(&self) -> MaterialData {
+        MaterialData {
+            textures: self.textures.clone(),
+            buffers: self.buffers.clone(),
+            parameters: self.parameters.clone(),
+            base_template: self.base_template.clone(),
+        }
+    }
 }
 
 fn build_shader_pass(
@@ -315,18 +661,142 @@ fn build_shader_pass(
     let layout = effect.pipeline_layout;
     let mut builder = builder.clone();
     builder.set_shaders(shader_cache, effect)?;
-    let pipeline = builder.build_pipeline(device, render_pass)?;
+    let pipeline = builder.build_pipeline(device, shader_cache.pipeline_cache(), render_pass)?;
     Ok(BuiltShaderPass {
         effect_handle: Some(effect_handle),
         pipeline,
         layout,
+        #[cfg(feature = "shader-reload")]
+        builder,
+        #[cfg(feature = "shader-reload")]
+        render_pass,
     })
 }
 
+// A new effect and pipeline for the pass from the current modules of its effect. The
+// materials and objects drawn with it stay as they are, so the descriptor sets and
+// texture slots have to stay the same.
+#[cfg(feature = "shader-reload")]
+fn rebuild_shader_pass(
+    device: &ash::Device,
+    shader_cache: &ShaderCache,
+    pass: &BuiltShaderPass,
+    effect_handle: Handle<ShaderEffect>,
+) -> RendererResult<(ShaderEffect, vk::Pipeline)> {
+    let old = shader_cache.get_shader_effect_by_handle(effect_handle)?;
+    let mut effect = shader_cache.rebuild_effect(device, effect_handle)?;
+    let mut changes = old
+        .differing_sets(&effect)
+        .into_iter()
+        .map(|set| format!("set {} has another layout", set))
+        .collect::<Vec<_>>();
+    if effect.material_sampler_bindings() != old.material_sampler_bindings() {
+        changes.push("the texture slots changed".to_string());
+    }
+    if effect.object_params_size() != old.object_params_size() {
+        changes.push("the object_params block has another size".to_string());
+    }
+    if effect.material_parameter_block() != old.material_parameter_block() {
+        changes.push("the material_parameters block has another layout".to_string());
+    }
+    let pipeline = if changes.is_empty() {
+        let mut builder = pass.builder.clone();
+        builder.set_shaders(shader_cache, &effect).and_then(|_| {
+            builder.build_pipeline(device, shader_cache.pipeline_cache(), pass.render_pass)
+        })
+    } else {
+        Err(ShaderReloadError(format!("{}, restart to apply", changes.join(", "))).into())
+    };
+    match pipeline {
+        Ok(pipeline) => Ok((effect, pipeline)),
+        Err(e) => {
+            effect.destroy(device);
+            Err(e)
+        }
+    }
+}
+
+// Every sampler of the material set has to be named after a MaterialTextureSlot
+fn reflect_texture_slots(
+    shader_cache: &ShaderCache,
+    effect_handle: Handle<ShaderEffect>,
+) -> RendererResult<Vec<(MaterialTextureSlot, u32)>> {
+    let effect = shader_cache.get_shader_effect_by_handle(effect_handle)?;
+    let mut slots = vec![];
+    let mut unknown = vec![];
+    for (name, binding) in effect.material_sampler_bindings() {
+        match MaterialTextureSlot::from_binding_name(name) {
+            Some(slot) => slots.push((slot, binding)),
+            None => unknown.push(format!(
+                "`{}` (binding {}) is not a texture slot name like albedo_tex or normal_tex",
+                name, binding
+            )),
+        }
+    }
+    if !unknown.is_empty() {
+        return Err(BindingContractError(unknown.join("; ")).into());
+    }
+    Ok(slots)
+}
+
+// Materials can only fill slots their template's shaders declare
+fn check_texture_slots(
+    texture_slots: &[(MaterialTextureSlot, u32)],
+    info: &MaterialData,
+) -> RendererResult<()> {
+    let undeclared = info
+        .textures
+        .keys()
+        .filter(|slot| !texture_slots.iter().any(|(declared, _)| declared == *slot))
+        .sorted()
+        .map(|slot| slot.binding_name())
+        .collect::<Vec<_>>();
+    if undeclared.is_empty() {
+        return Ok(());
+    }
+    Err(IncompatibleMaterial(format!(
+        "the shaders of template {} don't declare {}",
+        info.base_template,
+        undeclared.join(", ")
+    ))
+    .into())
+}
+
+// The texture bound to each declared slot, by binding, with defaults for the empty ones
+fn bound_textures(
+    texture_slots: &[(MaterialTextureSlot, u32)],
+    textures: &HashMap<MaterialTextureSlot, Handle<Texture>>,
+    defaults: &DefaultTextures,
+) -> Vec<(u32, Handle<Texture>)> {
+    texture_slots
+        .iter()
+        .map(|(slot, binding)| {
+            let texture = textures
+                .get(slot)
+                .copied()
+                .unwrap_or_else(|| slot.default_texture(defaults));
+            (*binding, texture)
+        })
+        .collect()
+}
+
+// Only built for weighted blended transparency, see build_oit_composite_template
+pub(crate) const OIT_COMPOSITE_TEMPLATE: &str = "oit_composite";
+
 pub struct MaterialSystem {
     forward_builder: PipelineBuilder,
     text_builder: PipelineBuilder,
     shadow_builder: PipelineBuilder,
+    transparent_builder: PipelineBuilder,
+    particle_builder: PipelineBuilder,
+    upscale_builder: PipelineBuilder,
+    skybox_builder: PipelineBuilder,
+    // Of the main render pass, the shadow, AOV and output pipelines are single sampled
+    msaa_samples: vk::SampleCountFlags,
+    // Of the scene render passes, which the transparent pipelines are built for
+    transparency: TransparencyTechnique,
+    // The DirectionalShadow passes of the opaque scene templates are built for it
+    shadow_render_pass: vk::RenderPass,
 
     effect_template_handles: HandleArray<EffectTemplate>,
     template_cache: HashMap<String, Handle<EffectTemplate>>,
@@ -334,25 +804,80 @@ pub struct MaterialSystem {
     materials_handles: HandleArray<Material>,
     materials: HashMap<String, Handle<Material>>,
     material_cache: HashMap<MaterialData, Handle<Material>>,
+    // Number of live materials using each texture, a material counts once per texture.
+    // Defaults bound to empty slots are not counted.
+    texture_references: HashMap<Handle<Texture>, usize>,
+    // Materials whose parameter buffer is uploaded with the next frame
+    dirty_parameters: Vec<Handle<Material>>,
+    default_textures: DefaultTextures,
+    events: FrameEvents,
+    // Bumped by every change, see Renderer::force_rerecord
+    generation: u64,
 }
 
 impl MaterialSystem {
+    pub(crate) fn take_frame_events(&mut self) -> FrameEvents {
+        std::mem::take(&mut self.events)
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    pub fn allocation_hash(&self) -> u64 {
+        let hash = fnv1a(
+            fnv1a_start(),
+            &self.effect_template_handles.allocation_hash().to_le_bytes(),
+        );
+        fnv1a(
+            hash,
+            &self.materials_handles.allocation_hash().to_le_bytes(),
+        )
+    }
+
+    // The scene templates are built for scene_render_pass, the upscale one for
+    // render_pass and the text and cursor ones for the single sampled overlay_render_pass
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         device: &ash::Device,
         render_pass: vk::RenderPass,
+        overlay_render_pass: vk::RenderPass,
+        scene_render_pass: vk::RenderPass,
+        shadow_render_pass: vk::RenderPass,
+        msaa_samples: vk::SampleCountFlags,
+        transparency: TransparencyTechnique,
         shader_cache: &mut ShaderCache,
+        default_textures: DefaultTextures,
     ) -> RendererResult<Self> {
         let mut ret = Self {
             forward_builder: Default::default(),
             text_builder: Default::default(),
             shadow_builder: Default::default(),
+            transparent_builder: Default::default(),
+            particle_builder: Default::default(),
+            upscale_builder: Default::default(),
+            skybox_builder: Default::default(),
+            msaa_samples,
+            transparency,
+            shadow_render_pass,
             effect_template_handles: HandleArray::new(),
             template_cache: HashMap::new(),
             materials_handles: HandleArray::new(),
             materials: HashMap::new(),
             material_cache: HashMap::new(),
+            texture_references: HashMap::new(),
+            dirty_parameters: vec![],
+            default_textures,
+            events: FrameEvents::default(),
+            generation: 0,
         };
-        ret.build_default_templates(device, render_pass, shader_cache)?;
+        ret.build_default_templates(
+            device,
+            render_pass,
+            overlay_render_pass,
+            scene_render_pass,
+            shader_cache,
+        )?;
         Ok(ret)
     }
 
@@ -360,6 +885,8 @@ impl MaterialSystem {
         &mut self,
         device: &ash::Device,
         render_pass: vk::RenderPass,
+        overlay_render_pass: vk::RenderPass,
+        scene_render_pass: vk::RenderPass,
         shader_cache: &mut ShaderCache,
     ) -> RendererResult<()> {
         self.fill_builders();
@@ -368,46 +895,237 @@ impl MaterialSystem {
             device,
             "./shaders/default.vert",
             Some("./shaders/default.frag"),
+            &BindingContract::scene(),
+        )?;
+        let lightmapped_effect_handle = shader_cache.build_effect(
+            device,
+            "./shaders/default.vert",
+            Some("./shaders/lightmapped.frag"),
+            &BindingContract::scene(),
+        )?;
+        // Weighted blended transparency writes the accumulation and revealage instead
+        let transparent_fragment_shader = match self.transparency {
+            TransparencyTechnique::SortedAlpha => "./shaders/transparent.frag",
+            TransparencyTechnique::WeightedBlended => "./shaders/transparent_oit.frag",
+        };
+        let transparent_effect_handle = shader_cache.build_effect(
+            device,
+            "./shaders/default.vert",
+            Some(transparent_fragment_shader),
+            &BindingContract::scene(),
         )?;
         let text_effect_handle = shader_cache.build_effect(
             device,
             "./shaders/text.vert",
             Some("./shaders/text.frag"),
+            &BindingContract::material_only(),
+        )?;
+        let cursor_effect_handle = shader_cache.build_effect(
+            device,
+            "./shaders/text.vert",
+            Some("./shaders/cursor.frag"),
+            &BindingContract::material_only(),
+        )?;
+        // Set 3 is the depth of the scene, which draw_emitter binds
+        let particle_fragment_shader = if self.msaa_samples == vk::SampleCountFlags::TYPE_1 {
+            "./shaders/particle.frag"
+        } else {
+            "./shaders/particle_msaa.frag"
+        };
+        let particle_effect_handle = shader_cache.build_effect(
+            device,
+            "./shaders/particle.vert",
+            Some(particle_fragment_shader),
+            &BindingContract::scene().with_user_managed_set(3),
+        )?;
+        let upscale_effect_handle = shader_cache.build_effect(
+            device,
+            "./shaders/upscale.vert",
+            Some("./shaders/upscale_bilinear.frag"),
+            &BindingContract::material_only(),
+        )?;
+        let skybox_effect_handle = shader_cache.build_effect(
+            device,
+            "./shaders/skybox.vert",
+            Some("./shaders/skybox.frag"),
+            &BindingContract::scene(),
         )?;
 
         let default_pass = build_shader_pass(
             device,
-            render_pass,
+            scene_render_pass,
             shader_cache,
             &self.forward_builder,
             default_effect_handle,
         )?;
 
+        let lightmapped_pass = build_shader_pass(
+            device,
+            scene_render_pass,
+            shader_cache,
+            &self.forward_builder,
+            lightmapped_effect_handle,
+        )?;
+
+        let transparent_pass = build_shader_pass(
+            device,
+            scene_render_pass,
+            shader_cache,
+            &self.transparent_builder,
+            transparent_effect_handle,
+        )?;
+
+        // The overlay render pass has neither samples nor depth
+        let mut overlay_builder = self.text_builder.clone();
+        overlay_builder.multisampling.rasterization_samples = vk::SampleCountFlags::TYPE_1;
+        overlay_builder.depth_stencil.depth_test_enable = vk::FALSE;
+        overlay_builder.depth_stencil.depth_write_enable = vk::FALSE;
         let text_pass = build_shader_pass(
             device,
-            render_pass,
+            overlay_render_pass,
             shader_cache,
-            &self.text_builder,
+            &overlay_builder,
             text_effect_handle,
         )?;
 
+        let cursor_pass = build_shader_pass(
+            device,
+            overlay_render_pass,
+            shader_cache,
+            &overlay_builder,
+            cursor_effect_handle,
+        )?;
+
+        let particle_pass = build_shader_pass(
+            device,
+            scene_render_pass,
+            shader_cache,
+            &self.particle_builder,
+            particle_effect_handle,
+        )?;
+
+        let upscale_pass = build_shader_pass(
+            device,
+            render_pass,
+            shader_cache,
+            &self.upscale_builder,
+            upscale_effect_handle,
+        )?;
+
+        let skybox_pass = build_shader_pass(
+            device,
+            scene_render_pass,
+            shader_cache,
+            &self.skybox_builder,
+            skybox_effect_handle,
+        )?;
+
+        if self.transparency == TransparencyTechnique::WeightedBlended {
+            self.build_oit_composite_template(device, scene_render_pass, shader_cache)?;
+        }
+        self.events |= FrameEvents::PIPELINE_BUILT;
+        self.generation += 1;
+
         {
             let mut default_template = EffectTemplate {
                 pass_shaders: Default::default(),
                 default_parameters: ShaderParameters::default(),
                 transparency_mode: TransparencyMode::Opaque,
+                generation: 0,
+                user_managed_sets: vec![],
+                texture_slots: reflect_texture_slots(shader_cache, default_effect_handle)?,
+                object_params_size: shader_cache
+                    .get_shader_effect_by_handle(default_effect_handle)?
+                    .object_params_size(),
+                parameter_block: shader_cache
+                    .get_shader_effect_by_handle(default_effect_handle)?
+                    .material_parameter_block(),
+                morph_targets: shader_cache
+                    .get_shader_effect_by_handle(default_effect_handle)?
+                    .uses_morph_targets(),
             };
 
             default_template.pass_shaders[MeshPassType::Forward] = default_pass;
+            default_template.pass_shaders[MeshPassType::DirectionalShadow] =
+                self.build_shadow_pass(device, shader_cache)?;
             let handle = self.effect_template_handles.insert(default_template);
             self.template_cache.insert("default".to_string(), handle);
         }
 
+        {
+            // Expects albedo and lightmap textures, see Renderer::bake_lightmaps
+            let mut lightmapped_template = EffectTemplate {
+                pass_shaders: Default::default(),
+                default_parameters: ShaderParameters::default(),
+                transparency_mode: TransparencyMode::Opaque,
+                generation: 0,
+                user_managed_sets: vec![],
+                texture_slots: reflect_texture_slots(shader_cache, lightmapped_effect_handle)?,
+                object_params_size: shader_cache
+                    .get_shader_effect_by_handle(lightmapped_effect_handle)?
+                    .object_params_size(),
+                parameter_block: shader_cache
+                    .get_shader_effect_by_handle(lightmapped_effect_handle)?
+                    .material_parameter_block(),
+                morph_targets: shader_cache
+                    .get_shader_effect_by_handle(lightmapped_effect_handle)?
+                    .uses_morph_targets(),
+            };
+
+            lightmapped_template.pass_shaders[MeshPassType::Forward] = lightmapped_pass;
+            lightmapped_template.pass_shaders[MeshPassType::DirectionalShadow] =
+                self.build_shadow_pass(device, shader_cache)?;
+            let handle = self.effect_template_handles.insert(lightmapped_template);
+            self.template_cache
+                .insert("lightmapped".to_string(), handle);
+        }
+
+        {
+            // Lit like the default template, with the alpha of the albedo times opacity
+            let mut default_parameters = ShaderParameters::default();
+            default_parameters.set("roughness", 0.5);
+            default_parameters.set("opacity", 0.5);
+            let mut transparent_template = EffectTemplate {
+                pass_shaders: Default::default(),
+                default_parameters,
+                transparency_mode: TransparencyMode::Transparent,
+                generation: 0,
+                user_managed_sets: vec![],
+                texture_slots: reflect_texture_slots(shader_cache, transparent_effect_handle)?,
+                object_params_size: shader_cache
+                    .get_shader_effect_by_handle(transparent_effect_handle)?
+                    .object_params_size(),
+                parameter_block: shader_cache
+                    .get_shader_effect_by_handle(transparent_effect_handle)?
+                    .material_parameter_block(),
+                morph_targets: shader_cache
+                    .get_shader_effect_by_handle(transparent_effect_handle)?
+                    .uses_morph_targets(),
+            };
+
+            transparent_template.pass_shaders[MeshPassType::Transparency] = transparent_pass;
+            let handle = self.effect_template_handles.insert(transparent_template);
+            self.template_cache
+                .insert("transparent".to_string(), handle);
+        }
+
         {
             let mut text_template = EffectTemplate {
                 pass_shaders: Default::default(),
                 default_parameters: ShaderParameters::default(),
                 transparency_mode: TransparencyMode::Opaque,
+                generation: 0,
+                user_managed_sets: vec![],
+                texture_slots: reflect_texture_slots(shader_cache, text_effect_handle)?,
+                object_params_size: shader_cache
+                    .get_shader_effect_by_handle(text_effect_handle)?
+                    .object_params_size(),
+                parameter_block: shader_cache
+                    .get_shader_effect_by_handle(text_effect_handle)?
+                    .material_parameter_block(),
+                morph_targets: shader_cache
+                    .get_shader_effect_by_handle(text_effect_handle)?
+                    .uses_morph_targets(),
             };
 
             text_template.pass_shaders[MeshPassType::Forward] = text_pass;
@@ -415,111 +1133,1288 @@ impl MaterialSystem {
             self.template_cache.insert("text".to_string(), handle);
         }
 
-        Ok(())
-    }
+        {
+            // Screen space quads like text, but with the colors of the texture
+            let mut cursor_template = EffectTemplate {
+                pass_shaders: Default::default(),
+                default_parameters: ShaderParameters::default(),
+                transparency_mode: TransparencyMode::Opaque,
+                generation: 0,
+                user_managed_sets: vec![],
+                texture_slots: reflect_texture_slots(shader_cache, cursor_effect_handle)?,
+                object_params_size: shader_cache
+                    .get_shader_effect_by_handle(cursor_effect_handle)?
+                    .object_params_size(),
+                parameter_block: shader_cache
+                    .get_shader_effect_by_handle(cursor_effect_handle)?
+                    .material_parameter_block(),
+                morph_targets: shader_cache
+                    .get_shader_effect_by_handle(cursor_effect_handle)?
+                    .uses_morph_targets(),
+            };
 
-    pub fn build_material(
-        &mut self,
-        device: &ash::Device,
-        texture_storage: &TextureStorage,
-        buffer_manager: Arc<Mutex<BufferManager>>,
-        descriptor_layout_cache: &mut DescriptorLayoutCache,
-        descriptor_allocator: &mut DescriptorAllocator,
-        material_name: &str,
-        info: MaterialData,
-    ) -> RendererResult<Handle<Material>> {
-        match self.material_cache.entry(info) {
-            std::collections::hash_map::Entry::Occupied(o) => {
-                self.materials.insert(material_name.to_string(), *o.get());
-                Ok(*o.get())
-            }
-            std::collections::hash_map::Entry::Vacant(v) => {
-                let info = v.key();
-                let original = {
-                    let res = self.template_cache.get(&info.base_template);
-                    match res {
-                        Some(handle) => *handle,
-                        None => return Err(MissingTemplate(info.base_template.clone()).into()),
-                    }
-                };
-                let mut new_mat = Material {
-                    original,
-                    pass_sets: Default::default(),
-                    textures: info.textures.clone(),
-                    parameters: info.parameters.clone(),
-                };
+            cursor_template.pass_shaders[MeshPassType::Forward] = cursor_pass;
+            let handle = self.effect_template_handles.insert(cursor_template);
+            self.template_cache.insert("cursor".to_string(), handle);
+        }
 
-                let mut db =
-                    DescriptorBuilder::begin(descriptor_layout_cache, descriptor_allocator);
-
-                let mut image_infos = vec![];
-                image_infos.reserve(info.textures.len());
-                for (i, tex_handle) in info.textures.iter().enumerate() {
-                    let tex = texture_storage
-                        .get_texture(*tex_handle)
-                        .expect("Invalid handle");
-                    let image_info = [vk::DescriptorImageInfo::builder()
-                        .sampler(tex.sampler)
-                        .image_view(tex.image_view)
-                        .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
-                        .build()];
-                    image_infos.push(image_info);
-                    db.bind_image(
-                        i as u32,
-                        image_infos.last().unwrap(),
-                        vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
-                        vk::ShaderStageFlags::FRAGMENT,
-                    );
-                }
-                let mut buffer_infos = vec![];
-                buffer_infos.reserve(info.buffers.len());
-                let buf_manag = buffer_manager.lock().unwrap();
-                for (i, buf_handle) in info.buffers.iter().enumerate() {
-                    let buf = buf_manag.get_buffer(*buf_handle).expect("Invalid handle");
-                    let buf_info = [vk::DescriptorBufferInfo::builder()
-                        .buffer(buf.buffer)
-                        .offset(0)
-                        .range(buf.size)
-                        .build()];
-                    buffer_infos.push(buf_info);
-                    db.bind_buffer(
-                        (image_infos.len() + i) as u32,
-                        buffer_infos.last().unwrap(),
-                        vk::DescriptorType::UNIFORM_BUFFER,
-                        vk::ShaderStageFlags::FRAGMENT,
-                    );
-                }
+        {
+            // Expects the texture of the particles, see ParticleEmitter
+            let mut particle_template = EffectTemplate {
+                pass_shaders: Default::default(),
+                default_parameters: ShaderParameters::default(),
+                transparency_mode: TransparencyMode::Transparent,
+                generation: 0,
+                user_managed_sets: vec![],
+                texture_slots: reflect_texture_slots(shader_cache, particle_effect_handle)?,
+                object_params_size: shader_cache
+                    .get_shader_effect_by_handle(particle_effect_handle)?
+                    .object_params_size(),
+                parameter_block: shader_cache
+                    .get_shader_effect_by_handle(particle_effect_handle)?
+                    .material_parameter_block(),
+                morph_targets: shader_cache
+                    .get_shader_effect_by_handle(particle_effect_handle)?
+                    .uses_morph_targets(),
+            };
 
-                new_mat.pass_sets[MeshPassType::Forward] = db.build(device)?.0;
+            particle_template.pass_shaders[MeshPassType::Transparency] = particle_pass;
+            let handle = self.effect_template_handles.insert(particle_template);
+            self.template_cache.insert("particle".to_string(), handle);
+        }
 
-                let handle = self.materials_handles.insert(new_mat);
-                self.materials.insert(material_name.to_string(), handle);
-                Ok(*v.insert(handle))
-            }
+        {
+            // Expects the scaled scene as albedo, see UpscaleFilter
+            let mut upscale_template = EffectTemplate {
+                pass_shaders: Default::default(),
+                default_parameters: ShaderParameters::default(),
+                transparency_mode: TransparencyMode::Opaque,
+                generation: 0,
+                user_managed_sets: vec![],
+                texture_slots: reflect_texture_slots(shader_cache, upscale_effect_handle)?,
+                object_params_size: shader_cache
+                    .get_shader_effect_by_handle(upscale_effect_handle)?
+                    .object_params_size(),
+                parameter_block: shader_cache
+                    .get_shader_effect_by_handle(upscale_effect_handle)?
+                    .material_parameter_block(),
+                morph_targets: shader_cache
+                    .get_shader_effect_by_handle(upscale_effect_handle)?
+                    .uses_morph_targets(),
+            };
+
+            upscale_template.pass_shaders[MeshPassType::Forward] = upscale_pass;
+            let handle = self.effect_template_handles.insert(upscale_template);
+            self.template_cache
+                .insert("upscale_bilinear".to_string(), handle);
         }
-    }
 
-    pub fn get_material_handle<S: AsRef<str>>(
-        &self,
-        material_name: S,
-    ) -> RendererResult<Handle<Material>> {
-        match self.materials.get(material_name.as_ref()) {
-            Some(handle) => Ok(*handle),
-            None => Err(InvalidHandle.into()),
+        {
+            // Expects a cubemap as albedo, see Renderer::set_skybox_from_files
+            let mut skybox_template = EffectTemplate {
+                pass_shaders: Default::default(),
+                default_parameters: ShaderParameters::default(),
+                transparency_mode: TransparencyMode::Opaque,
+                generation: 0,
+                user_managed_sets: vec![],
+                texture_slots: reflect_texture_slots(shader_cache, skybox_effect_handle)?,
+                object_params_size: shader_cache
+                    .get_shader_effect_by_handle(skybox_effect_handle)?
+                    .object_params_size(),
+                parameter_block: shader_cache
+                    .get_shader_effect_by_handle(skybox_effect_handle)?
+                    .material_parameter_block(),
+                morph_targets: shader_cache
+                    .get_shader_effect_by_handle(skybox_effect_handle)?
+                    .uses_morph_targets(),
+            };
+
+            skybox_template.pass_shaders[MeshPassType::Forward] = skybox_pass;
+            let handle = self.effect_template_handles.insert(skybox_template);
+            self.template_cache.insert("skybox".to_string(), handle);
         }
-    }
 
-    pub fn get_material_by_handle(&self, handle: Handle<Material>) -> RendererResult<&Material> {
-        self.materials_handles
-            .get(handle)
-            .ok_or(InvalidHandle.into())
+        Ok(())
     }
 
-    pub fn get_effect_template_handle<S: AsRef<str>>(
-        &self,
-        template_name: S,
+    // The template of the output pass, which has a render pass of its own with only the
+    // swapchain image. Expects the composite target as albedo.
+    pub fn build_output_template(
+        &mut self,
+        device: &ash::Device,
+        output_render_pass: vk::RenderPass,
+        shader_cache: &mut ShaderCache,
     ) -> RendererResult<Handle<EffectTemplate>> {
-        match self.template_cache.get(template_name.as_ref()) {
+        let effect_handle = shader_cache.build_effect(
+            device,
+            "./shaders/upscale.vert",
+            Some("./shaders/output_encode.frag"),
+            &BindingContract::material_only(),
+        )?;
+        let mut builder = self.upscale_builder.clone();
+        builder.multisampling.rasterization_samples = vk::SampleCountFlags::TYPE_1;
+        let pass = build_shader_pass(
+            device,
+            output_render_pass,
+            shader_cache,
+            &builder,
+            effect_handle,
+        )?;
+        self.events |= FrameEvents::PIPELINE_BUILT;
+        self.generation += 1;
+
+        let mut template = EffectTemplate {
+            pass_shaders: Default::default(),
+            default_parameters: ShaderParameters::default(),
+            transparency_mode: TransparencyMode::Opaque,
+            generation: 0,
+            user_managed_sets: vec![],
+            texture_slots: reflect_texture_slots(shader_cache, effect_handle)?,
+            object_params_size: shader_cache
+                .get_shader_effect_by_handle(effect_handle)?
+                .object_params_size(),
+            parameter_block: shader_cache
+                .get_shader_effect_by_handle(effect_handle)?
+                .material_parameter_block(),
+            morph_targets: shader_cache
+                .get_shader_effect_by_handle(effect_handle)?
+                .uses_morph_targets(),
+        };
+        template.pass_shaders[MeshPassType::Forward] = pass;
+        let handle = self.effect_template_handles.insert(template);
+        self.template_cache
+            .insert("output_encode".to_string(), handle);
+        Ok(handle)
+    }
+
+    // The template of the AOV pass, which writes several attachments that can't blend,
+    // see AovPass
+    pub fn build_aov_template(
+        &mut self,
+        device: &ash::Device,
+        aov_render_pass: vk::RenderPass,
+        shader_cache: &mut ShaderCache,
+    ) -> RendererResult<Handle<EffectTemplate>> {
+        let effect_handle = shader_cache.build_effect(
+            device,
+            "./shaders/aov.vert",
+            Some("./shaders/aov.frag"),
+            &BindingContract::scene(),
+        )?;
+        let mut builder = self.forward_builder.clone();
+        builder.color_blend_attachment.blend_enable = vk::FALSE;
+        builder.color_attachment_count = AOV_FORMATS.len();
+        builder.multisampling.rasterization_samples = vk::SampleCountFlags::TYPE_1;
+        let pass = build_shader_pass(
+            device,
+            aov_render_pass,
+            shader_cache,
+            &builder,
+            effect_handle,
+        )?;
+        self.events |= FrameEvents::PIPELINE_BUILT;
+        self.generation += 1;
+
+        let mut template = EffectTemplate {
+            pass_shaders: Default::default(),
+            default_parameters: ShaderParameters::default(),
+            transparency_mode: TransparencyMode::Opaque,
+            generation: 0,
+            user_managed_sets: vec![],
+            texture_slots: reflect_texture_slots(shader_cache, effect_handle)?,
+            object_params_size: shader_cache
+                .get_shader_effect_by_handle(effect_handle)?
+                .object_params_size(),
+            parameter_block: shader_cache
+                .get_shader_effect_by_handle(effect_handle)?
+                .material_parameter_block(),
+            morph_targets: shader_cache
+                .get_shader_effect_by_handle(effect_handle)?
+                .uses_morph_targets(),
+        };
+        template.pass_shaders[MeshPassType::Forward] = pass;
+        let handle = self.effect_template_handles.insert(template);
+        self.template_cache.insert("aov".to_string(), handle);
+        Ok(handle)
+    }
+
+    // The template of the velocity pass, one attachment without blending, see
+    // VelocityPass
+    pub fn build_velocity_template(
+        &mut self,
+        device: &ash::Device,
+        velocity_render_pass: vk::RenderPass,
+        shader_cache: &mut ShaderCache,
+    ) -> RendererResult<Handle<EffectTemplate>> {
+        let effect_handle = shader_cache.build_effect(
+            device,
+            "./shaders/velocity.vert",
+            Some("./shaders/velocity.frag"),
+            &BindingContract::scene(),
+        )?;
+        let mut builder = self.forward_builder.clone();
+        builder.color_blend_attachment.blend_enable = vk::FALSE;
+        builder.color_attachment_count = 1;
+        builder.multisampling.rasterization_samples = vk::SampleCountFlags::TYPE_1;
+        let pass = build_shader_pass(
+            device,
+            velocity_render_pass,
+            shader_cache,
+            &builder,
+            effect_handle,
+        )?;
+        self.events |= FrameEvents::PIPELINE_BUILT;
+        self.generation += 1;
+
+        let mut template = EffectTemplate {
+            pass_shaders: Default::default(),
+            default_parameters: ShaderParameters::default(),
+            transparency_mode: TransparencyMode::Opaque,
+            generation: 0,
+            user_managed_sets: vec![],
+            texture_slots: reflect_texture_slots(shader_cache, effect_handle)?,
+            object_params_size: shader_cache
+                .get_shader_effect_by_handle(effect_handle)?
+                .object_params_size(),
+            parameter_block: shader_cache
+                .get_shader_effect_by_handle(effect_handle)?
+                .material_parameter_block(),
+            morph_targets: shader_cache
+                .get_shader_effect_by_handle(effect_handle)?
+                .uses_morph_targets(),
+        };
+        template.pass_shaders[MeshPassType::Forward] = pass;
+        let handle = self.effect_template_handles.insert(template);
+        self.template_cache.insert("velocity".to_string(), handle);
+        Ok(handle)
+    }
+
+    // The template that bakes lightmaps in UV space, one attachment without blending or
+    // depth testing, see LightmapBaker
+    pub fn build_lightmap_bake_template(
+        &mut self,
+        device: &ash::Device,
+        lightmap_render_pass: vk::RenderPass,
+        shader_cache: &mut ShaderCache,
+    ) -> RendererResult<Handle<EffectTemplate>> {
+        let effect_handle = shader_cache.build_effect(
+            device,
+            "./shaders/lightmap_bake.vert",
+            Some("./shaders/lightmap_bake.frag"),
+            &BindingContract::scene(),
+        )?;
+        let mut builder = self.forward_builder.clone();
+        builder.color_blend_attachment.blend_enable = vk::FALSE;
+        builder.color_attachment_count = 1;
+        builder.multisampling.rasterization_samples = vk::SampleCountFlags::TYPE_1;
+        // Triangles don't overlap in UV space
+        builder.depth_stencil.depth_test_enable = vk::FALSE;
+        builder.depth_stencil.depth_write_enable = vk::FALSE;
+        let pass = build_shader_pass(
+            device,
+            lightmap_render_pass,
+            shader_cache,
+            &builder,
+            effect_handle,
+        )?;
+        self.events |= FrameEvents::PIPELINE_BUILT;
+        self.generation += 1;
+
+        let mut template = EffectTemplate {
+            pass_shaders: Default::default(),
+            default_parameters: ShaderParameters::default(),
+            transparency_mode: TransparencyMode::Opaque,
+            generation: 0,
+            user_managed_sets: vec![],
+            texture_slots: reflect_texture_slots(shader_cache, effect_handle)?,
+            object_params_size: shader_cache
+                .get_shader_effect_by_handle(effect_handle)?
+                .object_params_size(),
+            parameter_block: shader_cache
+                .get_shader_effect_by_handle(effect_handle)?
+                .material_parameter_block(),
+            morph_targets: shader_cache
+                .get_shader_effect_by_handle(effect_handle)?
+                .uses_morph_targets(),
+        };
+        template.pass_shaders[MeshPassType::Forward] = pass;
+        let handle = self.effect_template_handles.insert(template);
+        self.template_cache
+            .insert("lightmap_bake".to_string(), handle);
+        Ok(handle)
+    }
+
+    // Builds a forward template from shaders already in the shader cache. A template that
+    // is registered again under the same name is only used by materials built afterwards.
+    #[allow(clippy::too_many_arguments)]
+    pub fn register_template(
+        &mut self,
+        device: &ash::Device,
+        render_pass: vk::RenderPass,
+        shader_cache: &mut ShaderCache,
+        name: &str,
+        vertex_shader: &str,
+        fragment_shader: &str,
+        contract: &BindingContract,
+    ) -> RendererResult<Handle<EffectTemplate>> {
+        let effect_handle =
+            shader_cache.build_effect(device, vertex_shader, Some(fragment_shader), contract)?;
+        let pass = build_shader_pass(
+            device,
+            render_pass,
+            shader_cache,
+            &self.forward_builder,
+            effect_handle,
+        )?;
+        self.events |= FrameEvents::PIPELINE_BUILT;
+        self.generation += 1;
+
+        let mut template = EffectTemplate {
+            pass_shaders: Default::default(),
+            default_parameters: ShaderParameters::default(),
+            transparency_mode: TransparencyMode::Opaque,
+            generation: 0,
+            user_managed_sets: contract.user_managed_sets.clone(),
+            texture_slots: reflect_texture_slots(shader_cache, effect_handle)?,
+            object_params_size: shader_cache
+                .get_shader_effect_by_handle(effect_handle)?
+                .object_params_size(),
+            parameter_block: shader_cache
+                .get_shader_effect_by_handle(effect_handle)?
+                .material_parameter_block(),
+            morph_targets: shader_cache
+                .get_shader_effect_by_handle(effect_handle)?
+                .uses_morph_targets(),
+        };
+        template.pass_shaders[MeshPassType::Forward] = pass;
+        if contract.scene_globals {
+            template.pass_shaders[MeshPassType::DirectionalShadow] =
+                self.build_shadow_pass(device, shader_cache)?;
+        }
+        let handle = self.effect_template_handles.insert(template);
+        self.template_cache.insert(name.to_string(), handle);
+        Ok(handle)
+    }
+
+    // The depth of scene objects as seen by the directional light, the same for every
+    // template. Each template gets an effect of its own, so reloading shadow.vert
+    // rebuilds every pass once.
+    fn build_shadow_pass(
+        &self,
+        device: &ash::Device,
+        shader_cache: &mut ShaderCache,
+    ) -> RendererResult<BuiltShaderPass> {
+        let effect_handle = shader_cache.build_effect(
+            device,
+            "./shaders/shadow.vert",
+            None,
+            &BindingContract::scene(),
+        )?;
+        build_shader_pass(
+            device,
+            self.shadow_render_pass,
+            shader_cache,
+            &self.shadow_builder,
+            effect_handle,
+        )
+    }
+
+    // Builds the effects and pipelines of the templates using one of the shaders again,
+    // after ShaderCache::reload_from_disk swapped in their new modules. Either every
+    // affected template is rebuilt or none is. Returns what was replaced, which frames in
+    // flight may still use.
+    #[cfg(feature = "shader-reload")]
+    pub fn rebuild_templates(
+        &mut self,
+        device: &ash::Device,
+        shader_cache: &mut ShaderCache,
+        shaders: &[String],
+    ) -> RendererResult<RetiredShaders> {
+        let mut rebuilt = vec![];
+        let mut failure = None;
+        'templates: for (template_handle, template) in
+            self.effect_template_handles.iter_with_handles()
+        {
+            for (pass_index, pass) in template.pass_shaders.data.iter().enumerate() {
+                let effect_handle = match pass.effect_handle {
+                    Some(handle) if shader_cache.effect_uses_shaders(handle, shaders) => handle,
+                    _ => continue,
+                };
+                match rebuild_shader_pass(device, shader_cache, pass, effect_handle) {
+                    Ok(built) => rebuilt.push((template_handle, pass_index, effect_handle, built)),
+                    Err(e) => {
+                        let name = self
+                            .get_effect_template_name(template_handle)
+                            .unwrap_or("unnamed template");
+                        failure = Some(match e {
+                            RendererError::ShaderReloadError { source, .. } => {
+                                ShaderReloadError(format!("{}: {}", name, source.0)).into()
+                            }
+                            e => e,
+                        });
+                        break 'templates;
+                    }
+                }
+            }
+        }
+        if let Some(e) = failure {
+            for (_, _, _, (mut effect, pipeline)) in rebuilt {
+                unsafe { device.destroy_pipeline(pipeline, None) };
+                effect.destroy(device);
+            }
+            return Err(e);
+        }
+
+        let mut retired = RetiredShaders::default();
+        let mut rebuilt_templates = vec![];
+        for (template_handle, pass_index, effect_handle, (effect, pipeline)) in rebuilt {
+            rebuilt_templates.push(template_handle);
+            let layout = effect.pipeline_layout;
+            let morph_targets = effect.uses_morph_targets();
+            retired
+                .effects
+                .push(shader_cache.replace_effect(effect_handle, effect)?);
+            if let Some(template) = self.effect_template_handles.get_mut(template_handle) {
+                let pass = &mut template.pass_shaders.data[pass_index];
+                retired
+                    .pipelines
+                    .push(std::mem::replace(&mut pass.pipeline, pipeline));
+                pass.layout = layout;
+                template.morph_targets = morph_targets;
+            }
+        }
+        // Reloaded shaders keep their descriptor set layouts, so the materials of the
+        // rebuilt templates still fit them and move to the new generation with them
+        rebuilt_templates.dedup();
+        for template_handle in rebuilt_templates {
+            if let Some(template) = self.effect_template_handles.get_mut(template_handle) {
+                template.generation += 1;
+                let generation = template.generation;
+                for material in self.materials_handles.iter_mut() {
+                    if material.original == template_handle {
+                        material.template_generation = generation;
+                    }
+                }
+            }
+        }
+        self.events |= FrameEvents::PIPELINE_BUILT;
+        self.generation += 1;
+        Ok(retired)
+    }
+
+    // Identical data under another name gives back the same material, the names are
+    // aliases of it then. See set_material_texture for how changing one of them works.
+    #[allow(clippy::too_many_arguments)]
+    pub fn build_material(
+        &mut self,
+        device: &ash::Device,
+        allocator: &mut Allocator,
+        texture_storage: &TextureStorage,
+        buffer_manager: Arc<Mutex<BufferManager>>,
+        descriptor_layout_cache: &mut DescriptorLayoutCache,
+        descriptor_allocator: &mut DescriptorAllocator,
+        material_name: &str,
+        info: MaterialData,
+    ) -> RendererResult<Handle<Material>> {
+        if let Some(handle) = self.material_cache.get(&info).copied() {
+            self.materials.insert(material_name.to_string(), handle);
+            return Ok(handle);
+        }
+        let handle = self.create_material(
+            device,
+            allocator,
+            texture_storage,
+            buffer_manager,
+            descriptor_layout_cache,
+            descriptor_allocator,
+            &info,
+        )?;
+        self.materials.insert(material_name.to_string(), handle);
+        self.material_cache.insert(info, handle);
+        Ok(handle)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn create_material(
+        &mut self,
+        device: &ash::Device,
+        allocator: &mut Allocator,
+        texture_storage: &TextureStorage,
+        buffer_manager: Arc<Mutex<BufferManager>>,
+        descriptor_layout_cache: &mut DescriptorLayoutCache,
+        descriptor_allocator: &mut DescriptorAllocator,
+        info: &MaterialData,
+    ) -> RendererResult<Handle<Material>> {
+        self.events |= FrameEvents::MATERIAL_BUILT;
+        self.generation += 1;
+        let original = {
+            let res = self.template_cache.get(&info.base_template);
+            match res {
+                Some(handle) => *handle,
+                None => return Err(MissingTemplate(info.base_template.clone()).into()),
+            }
+        };
+        let template = self
+            .effect_template_handles
+            .get(original)
+            .ok_or::<RendererError>(InvalidHandle.into())?;
+        check_texture_slots(&template.texture_slots, info)?;
+        let template_generation = template.generation;
+        let texture_slots = template.texture_slots.clone();
+        let first_buffer_binding = template.first_buffer_binding();
+        let parameter_block = template.parameter_block.clone();
+        let mut new_mat = Material {
+            original,
+            template_generation,
+            pass_sets: Default::default(),
+            pass_set_hashes: Default::default(),
+            textures: info.textures.clone(),
+            buffers: info.buffers.clone(),
+            parameters: info.parameters.clone(),
+            base_template: info.base_template.clone(),
+            parameter_buffer: None,
+        };
+        if let Some(block) = parameter_block.as_ref().filter(|_| info.buffers.is_empty()) {
+            let data = info
+                .parameters
+                .block_data(block, &template.default_parameters);
+            let mut buffer = BufferManager::new_buffer(
+                buffer_manager.clone(),
+                device,
+                allocator,
+                block.size as u64,
+                vk::BufferUsageFlags::UNIFORM_BUFFER | vk::BufferUsageFlags::TRANSFER_DST,
+                MemoryLocation::CpuToGpu,
+                &format!("parameters-{}", info.base_template),
+            )?;
+            buffer.fill(allocator, &data)?;
+            new_mat.parameter_buffer = Some(buffer);
+        }
+
+        let mut db = DescriptorBuilder::begin(descriptor_layout_cache, descriptor_allocator);
+
+        let mut image_infos = vec![];
+        image_infos.reserve(texture_slots.len());
+        for (binding, tex_handle) in
+            bound_textures(&texture_slots, &info.textures, &self.default_textures)
+        {
+            let tex = texture_storage
+                .get_texture(tex_handle)
+                .expect("Invalid handle");
+            let image_info = [vk::DescriptorImageInfo::builder()
+                .sampler(tex.sampler)
+                .image_view(tex.image_view)
+                .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+                .build()];
+            image_infos.push(image_info);
+            db.bind_image(
+                binding,
+                image_infos.last().unwrap(),
+                vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                vk::ShaderStageFlags::FRAGMENT,
+            );
+        }
+        let mut buffer_infos = vec![];
+        buffer_infos.reserve(info.buffers.len() + 1);
+        let buf_manag = buffer_manager.lock().unwrap();
+        if let (Some(block), Some(buffer)) =
+            (parameter_block.as_ref(), new_mat.parameter_buffer.as_ref())
+        {
+            let buf = buf_manag
+                .get_buffer(buffer.get_handle())
+                .expect("Invalid handle");
+            buffer_infos.push([vk::DescriptorBufferInfo::builder()
+                .buffer(buf.buffer)
+                .offset(0)
+                .range(buf.size)
+                .build()]);
+            db.bind_buffer(
+                block.binding,
+                buffer_infos.last().unwrap(),
+                vk::DescriptorType::UNIFORM_BUFFER,
+                vk::ShaderStageFlags::FRAGMENT,
+            );
+        }
+        for (i, buf_handle) in info.buffers.iter().enumerate() {
+            let buf = buf_manag.get_buffer(*buf_handle).expect("Invalid handle");
+            let buf_info = [vk::DescriptorBufferInfo::builder()
+                .buffer(buf.buffer)
+                .offset(0)
+                .range(buf.size)
+                .build()];
+            buffer_infos.push(buf_info);
+            db.bind_buffer(
+                first_buffer_binding + i as u32,
+                buffer_infos.last().unwrap(),
+                vk::DescriptorType::UNIFORM_BUFFER,
+                vk::ShaderStageFlags::FRAGMENT,
+            );
+        }
+
+        // The transparency pipelines of a template use the same layout as the forward ones
+        let hash = db.layout_hash();
+        let set = db.build(device)?.0;
+        new_mat.pass_set_hashes[MeshPassType::Forward] = hash;
+        new_mat.pass_set_hashes[MeshPassType::Transparency] = hash;
+        new_mat.pass_sets[MeshPassType::Forward] = set;
+        new_mat.pass_sets[MeshPassType::Transparency] = set;
+
+        for texture in new_mat.textures.values().unique() {
+            *self.texture_references.entry(*texture).or_insert(0) += 1;
+        }
+        Ok(self.materials_handles.insert(new_mat))
+    }
+
+    // More than one name points at the material
+    pub fn is_shared(&self, handle: Handle<Material>) -> bool {
+        aliases(&self.materials, handle).len() > 1
+    }
+
+    // Every name the material is registered under, sorted
+    pub fn aliases(&self, handle: Handle<Material>) -> Vec<String> {
+        aliases(&self.materials, handle)
+    }
+
+    pub fn default_textures(&self) -> &DefaultTextures {
+        &self.default_textures
+    }
+
+    // For formats that list textures without naming them, like material files and scene
+    // descriptions: the textures fill the slots of the template in binding order
+    pub fn textures_in_template_order(
+        &self,
+        base_template: &str,
+        textures: Vec<Handle<Texture>>,
+    ) -> RendererResult<HashMap<MaterialTextureSlot, Handle<Texture>>> {
+        self.optional_textures_in_template_order(
+            base_template,
+            textures.into_iter().map(Some).collect(),
+        )
+    }
+
+    // None leaves the slot empty, so it gets its default texture
+    pub fn optional_textures_in_template_order(
+        &self,
+        base_template: &str,
+        textures: Vec<Option<Handle<Texture>>>,
+    ) -> RendererResult<HashMap<MaterialTextureSlot, Handle<Texture>>> {
+        let template = self.get_effect_template_by_handle(
+            self.template_cache
+                .get(base_template)
+                .copied()
+                .ok_or_else(|| MissingTemplate(base_template.to_string()))?,
+        )?;
+        if textures.len() > template.texture_slots.len() {
+            return Err(IncompatibleMaterial(format!(
+                "template {} has {} texture slots, {} textures were given",
+                base_template,
+                template.texture_slots.len(),
+                textures.len()
+            ))
+            .into());
+        }
+        Ok(template
+            .texture_slots
+            .iter()
+            .map(|(slot, _)| *slot)
+            .zip(textures)
+            .filter_map(|(slot, texture)| Some((slot, texture?)))
+            .collect())
+    }
+
+    // The reverse of textures_in_template_order, empty slots before the last filled one
+    // get their default texture
+    pub fn textures_to_template_order(
+        &self,
+        handle: Handle<Material>,
+    ) -> RendererResult<Vec<Handle<Texture>>> {
+        let material = self.get_material_by_handle(handle)?;
+        let template = self.get_effect_template_by_handle(material.original)?;
+        Ok(self
+            .optional_textures_to_template_order(handle)?
+            .into_iter()
+            .zip(template.texture_slots.iter())
+            .map(|(texture, (slot, _))| {
+                texture.unwrap_or_else(|| slot.default_texture(&self.default_textures))
+            })
+            .collect())
+    }
+
+    // The reverse of optional_textures_in_template_order, up to the last filled slot
+    pub fn optional_textures_to_template_order(
+        &self,
+        handle: Handle<Material>,
+    ) -> RendererResult<Vec<Option<Handle<Texture>>>> {
+        let material = self.get_material_by_handle(handle)?;
+        let template = self.get_effect_template_by_handle(material.original)?;
+        let mut textures = template
+            .texture_slots
+            .iter()
+            .map(|(slot, _)| material.textures.get(slot).copied())
+            .collect::<Vec<_>>();
+        while let Some(None) = textures.last() {
+            textures.pop();
+        }
+        Ok(textures)
+    }
+
+    // The first of the names the material is registered under, in alphabetical order
+    pub fn get_material_name(&self, handle: Handle<Material>) -> Option<&str> {
+        self.materials
+            .iter()
+            .filter(|(_, h)| **h == handle)
+            .map(|(name, _)| name.as_str())
+            .min()
+    }
+
+    // Copy on write: if other names share the material, material_name gets a material of
+    // its own and the others keep the old one. Objects hold on to material handles, so
+    // the returned handle is the one to give to objects that should see the change.
+    #[allow(clippy::too_many_arguments)]
+    pub fn set_material_texture(
+        &mut self,
+        device: &ash::Device,
+        allocator: &mut Allocator,
+        texture_storage: &TextureStorage,
+        buffer_manager: Arc<Mutex<BufferManager>>,
+        descriptor_layout_cache: &mut DescriptorLayoutCache,
+        descriptor_allocator: &mut DescriptorAllocator,
+        material_name: &str,
+        slot: MaterialTextureSlot,
+        texture: Handle<Texture>,
+    ) -> RendererResult<Handle<Material>> {
+        if texture_storage.get_texture(texture).is_none() {
+            return Err(InvalidHandle.into());
+        }
+        self.modify_material(
+            device,
+            allocator,
+            texture_storage,
+            buffer_manager,
+            descriptor_layout_cache,
+            descriptor_allocator,
+            material_name,
+            |data| {
+                data.textures.insert(slot, texture);
+                Ok(())
+            },
+        )
+    }
+
+    // Copy on write like set_material_texture
+    #[allow(clippy::too_many_arguments)]
+    pub fn set_parameters(
+        &mut self,
+        device: &ash::Device,
+        allocator: &mut Allocator,
+        texture_storage: &TextureStorage,
+        buffer_manager: Arc<Mutex<BufferManager>>,
+        descriptor_layout_cache: &mut DescriptorLayoutCache,
+        descriptor_allocator: &mut DescriptorAllocator,
+        material_name: &str,
+        parameters: ShaderParameters,
+    ) -> RendererResult<Handle<Material>> {
+        self.modify_material(
+            device,
+            allocator,
+            texture_storage,
+            buffer_manager,
+            descriptor_layout_cache,
+            descriptor_allocator,
+            material_name,
+            |data| {
+                data.parameters = parameters;
+                Ok(())
+            },
+        )
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn modify_material<F: FnOnce(&mut MaterialData) -> RendererResult<()>>(
+        &mut self,
+        device: &ash::Device,
+        allocator: &mut Allocator,
+        texture_storage: &TextureStorage,
+        buffer_manager: Arc<Mutex<BufferManager>>,
+        descriptor_layout_cache: &mut DescriptorLayoutCache,
+        descriptor_allocator: &mut DescriptorAllocator,
+        material_name: &str,
+        edit: F,
+    ) -> RendererResult<Handle<Material>> {
+        let handle = self.get_material_handle(material_name)?;
+        let old_data = self.get_material_by_handle(handle)?.data();
+        let mut data = old_data.clone();
+        edit(&mut data)?;
+        let write = material_write(
+            data == old_data,
+            self.is_shared(handle),
+            self.material_cache.get(&data).copied(),
+        );
+        match write {
+            MaterialWrite::Unchanged => return Ok(handle),
+            MaterialWrite::Split => {
+                self.generation += 1;
+                self.materials.remove(material_name);
+                return self.build_material(
+                    device,
+                    allocator,
+                    texture_storage,
+                    buffer_manager,
+                    descriptor_layout_cache,
+                    descriptor_allocator,
+                    material_name,
+                    data,
+                );
+            }
+            MaterialWrite::Alias(existing) => {
+                self.generation += 1;
+                self.remove_material(material_name)?;
+                self.materials.insert(material_name.to_string(), existing);
+                return Ok(existing);
+            }
+            MaterialWrite::InPlace => self.generation += 1,
+        }
+
+        let material = self
+            .materials_handles
+            .get_mut(handle)
+            .ok_or::<RendererError>(InvalidHandle.into())?;
+        let template = self
+            .effect_template_handles
+            .get(material.original)
+            .ok_or::<RendererError>(InvalidHandle.into())?;
+        check_texture_slots(&template.texture_slots, &data)?;
+        let set = material.pass_sets[MeshPassType::Forward];
+        let mut image_infos = vec![];
+        for (slot, binding) in template.texture_slots.iter() {
+            let new = data.textures.get(slot);
+            if old_data.textures.get(slot) == new {
+                continue;
+            }
+            let new = new
+                .copied()
+                .unwrap_or_else(|| slot.default_texture(&self.default_textures));
+            let texture = texture_storage
+                .get_texture(new)
+                .ok_or::<RendererError>(InvalidHandle.into())?;
+            image_infos.push((
+                *binding,
+                [vk::DescriptorImageInfo::builder()
+                    .sampler(texture.sampler)
+                    .image_view(texture.image_view)
+                    .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+                    .build()],
+            ));
+        }
+        let writes = image_infos
+            .iter()
+            .map(|(binding, image_info)| {
+                vk::WriteDescriptorSet::builder()
+                    .dst_set(set)
+                    .dst_binding(*binding)
+                    .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                    .image_info(image_info)
+                    .build()
+            })
+            .collect::<Vec<_>>();
+        // The caller makes sure the set is not used by an in-flight frame
+        unsafe {
+            device.update_descriptor_sets(&writes, &[]);
+        }
+        material.textures = data.textures.clone();
+        material.parameters = data.parameters.clone();
+        if material.parameter_buffer.is_some() && data.parameters != old_data.parameters {
+            self.mark_parameters_dirty(handle);
+        }
+
+        for texture in old_data.textures.values().unique() {
+            if !data.textures.values().any(|t| t == texture) {
+                self.release_texture_reference(*texture);
+            }
+        }
+        for texture in data.textures.values().unique() {
+            if !old_data.textures.values().any(|t| t == texture) {
+                *self.texture_references.entry(*texture).or_insert(0) += 1;
+            }
+        }
+        if self.material_cache.get(&old_data) == Some(&handle) {
+            self.material_cache.remove(&old_data);
+        }
+        self.material_cache.insert(data, handle);
+        Ok(handle)
+    }
+
+    // Names the pipelines after their template and pass, e.g. "default/forward"
+    pub(crate) fn set_debug_names(&self, debug_namer: &DebugNamer) {
+        for (name, handle) in self.template_cache.iter() {
+            if let Some(template) = self.effect_template_handles.get(*handle) {
+                // MeshPassType isn't Copy, so the passes are listed again for every template
+                for (pass, pass_name) in [
+                    (MeshPassType::Forward, "forward"),
+                    (MeshPassType::Transparency, "transparency"),
+                    (MeshPassType::DirectionalShadow, "shadow"),
+                ] {
+                    debug_namer.name(
+                        template.pass_shaders[pass].pipeline,
+                        &format!("{}/{}", name, pass_name),
+                    );
+                }
+            }
+        }
+    }
+
+    pub fn get_material_handle<S: AsRef<str>>(
+        &self,
+        material_name: S,
+    ) -> RendererResult<Handle<Material>> {
+        match self.materials.get(material_name.as_ref()) {
+            Some(handle) => Ok(*handle),
+            None => Err(InvalidHandle.into()),
+        }
+    }
+
+    pub fn remove_material<S: AsRef<str>>(&mut self, material_name: S) -> RendererResult<()> {
+        let handle = self
+            .materials
+            .remove(material_name.as_ref())
+            .ok_or::<RendererError>(InvalidHandle.into())?;
+        self.generation += 1;
+        if self.materials.values().any(|h| *h == handle) {
+            // Still in use under another name
+            return Ok(());
+        }
+        self.material_cache.retain(|_, h| *h != handle);
+        self.dirty_parameters.retain(|h| *h != handle);
+        // TODO the descriptor set stays allocated until the pool is reset
+        let mut material = self.materials_handles.remove(handle)?;
+        if let Some(mut buffer) = material.parameter_buffer.take() {
+            buffer.queue_free()?;
+        }
+        for texture in material.textures.values().unique() {
+            self.release_texture_reference(*texture);
+        }
+        Ok(())
+    }
+
+    // Changes one value of the material_parameters block in place, every name and object
+    // using the material sees it with the next frame. Unlike set_parameters the material
+    // is never copied and its descriptor set stays as it is.
+    pub fn set_parameter<V: Into<ParameterValue>>(
+        &mut self,
+        handle: Handle<Material>,
+        name: &str,
+        value: V,
+    ) -> RendererResult<()> {
+        self.generation += 1;
+        let material = self
+            .materials_handles
+            .get(handle)
+            .ok_or::<RendererError>(InvalidHandle.into())?;
+        let template = self.get_effect_template_by_handle(material.original)?;
+        let block = match (
+            template.parameter_block.as_ref(),
+            &material.parameter_buffer,
+        ) {
+            (Some(block), Some(_)) => block,
+            (Some(_), None) => {
+                return Err(IncompatibleMaterial(format!(
+                    "material {} binds its own buffers instead of its parameters",
+                    material.base_template
+                ))
+                .into())
+            }
+            (None, _) => {
+                return Err(IncompatibleMaterial(format!(
+                    "template {} has no material_parameters block",
+                    material.base_template
+                ))
+                .into())
+            }
+        };
+        if !block.members.iter().any(|member| member.name == name) {
+            return Err(IncompatibleMaterial(format!(
+                "template {} has no parameter {}",
+                material.base_template, name
+            ))
+            .into());
+        }
+        let old_data = material.data();
+        let material = self
+            .materials_handles
+            .get_mut(handle)
+            .ok_or::<RendererError>(InvalidHandle.into())?;
+        material.parameters.set(name, value);
+        let data = material.data();
+        if data == old_data {
+            return Ok(());
+        }
+        if self.material_cache.get(&old_data) == Some(&handle) {
+            self.material_cache.remove(&old_data);
+        }
+        self.material_cache.entry(data).or_insert(handle);
+        self.mark_parameters_dirty(handle);
+        Ok(())
+    }
+
+    fn mark_parameters_dirty(&mut self, handle: Handle<Material>) {
+        if !self.dirty_parameters.contains(&handle) {
+            self.dirty_parameters.push(handle);
+        }
+    }
+
+    // Copies the changed parameters into the buffers of their materials, outside of a
+    // render pass before anything reads them. The first barrier also covers the frames
+    // submitted before, which may still read the old values.
+    pub(crate) fn record_parameter_uploads(
+        &mut self,
+        device: &ash::Device,
+        cmd_buf: vk::CommandBuffer,
+    ) -> RendererResult<()> {
+        if self.dirty_parameters.is_empty() {
+            return Ok(());
+        }
+        let mut uploads = vec![];
+        for handle in std::mem::take(&mut self.dirty_parameters) {
+            let material = self.get_material_by_handle(handle)?;
+            let template = self.get_effect_template_by_handle(material.original)?;
+            if let (Some(block), Some(buffer)) = (
+                template.parameter_block.as_ref(),
+                material.parameter_buffer.as_ref(),
+            ) {
+                let data = material
+                    .parameters
+                    .block_data(block, &template.default_parameters);
+                uploads.push((buffer.get_buffer().buffer, data));
+            }
+        }
+        let before = [vk::MemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::UNIFORM_READ)
+            .dst_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .build()];
+        let after = [vk::MemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .dst_access_mask(vk::AccessFlags::UNIFORM_READ)
+            .build()];
+        let shader_stages =
+            vk::PipelineStageFlags::VERTEX_SHADER | vk::PipelineStageFlags::FRAGMENT_SHADER;
+        unsafe {
+            device.cmd_pipeline_barrier(
+                cmd_buf,
+                shader_stages,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::DependencyFlags::empty(),
+                &before,
+                &[],
+                &[],
+            );
+            for (buffer, data) in uploads.iter() {
+                device.cmd_update_buffer(cmd_buf, *buffer, 0, data);
+            }
+            device.cmd_pipeline_barrier(
+                cmd_buf,
+                vk::PipelineStageFlags::TRANSFER,
+                shader_stages,
+                vk::DependencyFlags::empty(),
+                &after,
+                &[],
+                &[],
+            );
+        }
+        Ok(())
+    }
+
+    fn release_texture_reference(&mut self, texture: Handle<Texture>) {
+        if let Some(count) = self.texture_references.get_mut(&texture) {
+            *count -= 1;
+            if *count == 0 {
+                self.texture_references.remove(&texture);
+            }
+        }
+    }
+
+    pub fn texture_reference_count(&self, texture: Handle<Texture>) -> usize {
+        self.texture_references.get(&texture).copied().unwrap_or(0)
+    }
+
+    pub fn materials_using_texture(&self, texture: Handle<Texture>) -> Vec<Handle<Material>> {
+        self.materials_handles
+            .iter_with_handles()
+            .filter(|(_, material)| material.textures.values().any(|t| *t == texture))
+            .map(|(handle, _)| handle)
+            .collect()
+    }
+
+    // Every name the materials are registered under, sorted for stable error messages
+    pub fn material_names_using_texture(&self, texture: Handle<Texture>) -> Vec<String> {
+        let handles = self.materials_using_texture(texture);
+        self.materials
+            .iter()
+            .filter(|(_, h)| handles.contains(h))
+            .map(|(name, _)| name.clone())
+            .sorted()
+            .collect()
+    }
+
+    // Points every material using old at new instead. The descriptor sets are updated in
+    // place, so the caller has to make sure none of them are used by an in-flight frame.
+    pub(crate) fn replace_texture(
+        &mut self,
+        device: &ash::Device,
+        texture_storage: &TextureStorage,
+        old: Handle<Texture>,
+        new: Handle<Texture>,
+    ) -> RendererResult<()> {
+        if old == new {
+            return Ok(());
+        }
+        self.generation += 1;
+        let new_texture = texture_storage
+            .get_texture(new)
+            .ok_or::<RendererError>(InvalidHandle.into())?;
+        let image_info = [vk::DescriptorImageInfo::builder()
+            .sampler(new_texture.sampler)
+            .image_view(new_texture.image_view)
+            .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+            .build()];
+
+        for handle in self.materials_using_texture(old) {
+            let material = self
+                .materials_handles
+                .get_mut(handle)
+                .ok_or::<RendererError>(InvalidHandle.into())?;
+            let template = self
+                .effect_template_handles
+                .get(material.original)
+                .ok_or::<RendererError>(InvalidHandle.into())?;
+            let already_used_new = material.textures.values().any(|t| *t == new);
+            let set = material.pass_sets[MeshPassType::Forward];
+            let writes = material
+                .textures
+                .iter_mut()
+                .filter(|(_, texture)| **texture == old)
+                .filter_map(|(slot, texture)| {
+                    *texture = new;
+                    template.texture_binding(*slot)
+                })
+                .map(|binding| {
+                    vk::WriteDescriptorSet::builder()
+                        .dst_set(set)
+                        .dst_binding(binding)
+                        .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                        .image_info(&image_info)
+                        .build()
+                })
+                .collect::<Vec<_>>();
+            unsafe {
+                device.update_descriptor_sets(&writes, &[]);
+            }
+            self.release_texture_reference(old);
+            if !already_used_new {
+                *self.texture_references.entry(new).or_insert(0) += 1;
+            }
+        }
+
+        // Keep the cache keyed by what the materials use now
+        let stale = self
+            .material_cache
+            .keys()
+            .filter(|data| data.textures.values().any(|t| *t == old))
+            .cloned()
+            .collect::<Vec<_>>();
+        for mut data in stale {
+            if let Some(handle) = self.material_cache.remove(&data) {
+                for texture in data.textures.values_mut().filter(|t| **t == old) {
+                    *texture = new;
+                }
+                self.material_cache.entry(data).or_insert(handle);
+            }
+        }
+        Ok(())
+    }
+
+    // Writes the current image of a texture into every material using it, after it was
+    // reloaded under the same handle. Same in-flight caveat as replace_texture. Returns
+    // the number of materials that were updated.
+    pub(crate) fn rewrite_texture_descriptors(
+        &mut self,
+        device: &ash::Device,
+        texture_storage: &TextureStorage,
+        texture: Handle<Texture>,
+    ) -> RendererResult<usize> {
+        let image = texture_storage
+            .get_texture(texture)
+            .ok_or::<RendererError>(InvalidHandle.into())?;
+        let image_info = [vk::DescriptorImageInfo::builder()
+            .sampler(image.sampler)
+            .image_view(image.image_view)
+            .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+            .build()];
+
+        let materials = self.materials_using_texture(texture);
+        for handle in materials.iter() {
+            let material = self
+                .materials_handles
+                .get(*handle)
+                .ok_or::<RendererError>(InvalidHandle.into())?;
+            let template = self
+                .effect_template_handles
+                .get(material.original)
+                .ok_or::<RendererError>(InvalidHandle.into())?;
+            let set = material.pass_sets[MeshPassType::Forward];
+            let writes = material
+                .textures
+                .iter()
+                .filter(|(_, t)| **t == texture)
+                .filter_map(|(slot, _)| template.texture_binding(*slot))
+                .map(|binding| {
+                    vk::WriteDescriptorSet::builder()
+                        .dst_set(set)
+                        .dst_binding(binding)
+                        .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                        .image_info(&image_info)
+                        .build()
+                })
+                .collect::<Vec<_>>();
+            unsafe {
+                device.update_descriptor_sets(&writes, &[]);
+            }
+        }
+        // Recorded frames still bind the sets, which are no longer the ones they used
+        if !materials.is_empty() {
+            self.generation += 1;
+        }
+        Ok(materials.len())
+    }
+
+    pub fn get_effect_template_name(&self, handle: Handle<EffectTemplate>) -> Option<&str> {
+        self.template_cache
+            .iter()
+            .find(|(_, h)| **h == handle)
+            .map(|(name, _)| name.as_str())
+    }
+
+    pub fn get_material_by_handle(&self, handle: Handle<Material>) -> RendererResult<&Material> {
+        self.materials_handles
+            .get(handle)
+            .ok_or(InvalidHandle.into())
+    }
+
+    // Counts as a change, even if nothing is written
+    pub fn get_material_by_handle_mut(
+        &mut self,
+        handle: Handle<Material>,
+    ) -> RendererResult<&mut Material> {
+        self.generation += 1;
+        self.materials_handles
+            .get_mut(handle)
+            .ok_or(InvalidHandle.into())
+    }
+
+    pub fn get_effect_template_handle<S: AsRef<str>>(
+        &self,
+        template_name: S,
+    ) -> RendererResult<Handle<EffectTemplate>> {
+        match self.template_cache.get(template_name.as_ref()) {
             Some(handle) => Ok(*handle),
             None => Err(InvalidHandle.into()),
         }
@@ -534,6 +2429,67 @@ impl MaterialSystem {
             .ok_or(InvalidHandle.into())
     }
 
+    // Counts as a rebuilt pipeline, so the draws are sorted and checked again
+    pub fn get_effect_template_by_handle_mut(
+        &mut self,
+        handle: Handle<EffectTemplate>,
+    ) -> RendererResult<&mut EffectTemplate> {
+        self.events |= FrameEvents::PIPELINE_BUILT;
+        self.generation += 1;
+        self.effect_template_handles
+            .get_mut(handle)
+            .ok_or(InvalidHandle.into())
+    }
+
+    // Puts the transparent objects accumulated by weighted blended transparency over the
+    // scene in the last subpass, see Renderer::draw_oit_composite. Its set 0 holds the
+    // accumulation and revealage input attachments, which the renderer writes.
+    fn build_oit_composite_template(
+        &mut self,
+        device: &ash::Device,
+        scene_render_pass: vk::RenderPass,
+        shader_cache: &mut ShaderCache,
+    ) -> RendererResult<()> {
+        let fragment_shader = if self.msaa_samples == vk::SampleCountFlags::TYPE_1 {
+            "./shaders/oit_composite.frag"
+        } else {
+            "./shaders/oit_composite_msaa.frag"
+        };
+        let effect_handle = shader_cache.build_effect(
+            device,
+            "./shaders/upscale.vert",
+            Some(fragment_shader),
+            &BindingContract::material_only(),
+        )?;
+        // A fullscreen triangle blended over the color
+        let mut builder = self.upscale_builder.clone();
+        builder.color_blend_attachment = self.forward_builder.color_blend_attachment;
+        builder.subpass = self.transparency.particle_subpass();
+        let pass = build_shader_pass(
+            device,
+            scene_render_pass,
+            shader_cache,
+            &builder,
+            effect_handle,
+        )?;
+        let mut template = EffectTemplate {
+            pass_shaders: Default::default(),
+            default_parameters: ShaderParameters::default(),
+            transparency_mode: TransparencyMode::Transparent,
+            generation: 0,
+            user_managed_sets: vec![],
+            texture_slots: vec![],
+            object_params_size: None,
+            parameter_block: None,
+            morph_targets: false,
+        };
+        template.pass_shaders[MeshPassType::Transparency] = pass;
+        let handle = self.effect_template_handles.insert(template);
+        self.template_cache
+            .insert(OIT_COMPOSITE_TEMPLATE.to_string(), handle);
+        Ok(())
+    }
+
     pub fn fill_builders(&mut self) {
         {
             self.shadow_builder.vertex_description = Vertex::get_vertex_description();
@@ -547,12 +2503,13 @@ impl MaterialSystem {
                 .rasterizer_discard_enable(false)
                 .polygon_mode(vk::PolygonMode::FILL)
                 .line_width(1.0)
-                .cull_mode(vk::CullModeFlags::FRONT)
+                .cull_mode(vk::CullModeFlags::NONE)
                 .front_face(vk::FrontFace::CLOCKWISE)
+                // Keeps lit surfaces from shadowing themselves
                 .depth_bias_enable(true)
-                .depth_bias_constant_factor(0.0)
+                .depth_bias_constant_factor(1.25)
                 .depth_bias_clamp(0.0)
-                .depth_bias_slope_factor(0.0)
+                .depth_bias_slope_factor(1.75)
                 .build();
             self.shadow_builder.multisampling = vk::PipelineMultisampleStateCreateInfo::builder()
                 .sample_shading_enable(false)
@@ -561,17 +2518,7 @@ impl MaterialSystem {
                 .alpha_to_coverage_enable(false)
                 .alpha_to_one_enable(false)
                 .build();
-            self.shadow_builder.color_blend_attachment =
-                vk::PipelineColorBlendAttachmentState::builder()
-                    .color_write_mask(vk::ColorComponentFlags::RGBA)
-                    .blend_enable(true)
-                    .src_color_blend_factor(vk::BlendFactor::SRC_ALPHA)
-                    .dst_color_blend_factor(vk::BlendFactor::ONE_MINUS_SRC_ALPHA)
-                    .color_blend_op(vk::BlendOp::ADD)
-                    .src_alpha_blend_factor(vk::BlendFactor::SRC_ALPHA)
-                    .dst_alpha_blend_factor(vk::BlendFactor::ONE_MINUS_SRC_ALPHA)
-                    .alpha_blend_op(vk::BlendOp::ADD)
-                    .build();
+            self.shadow_builder.depth_only = true;
             self.shadow_builder.depth_stencil = vk::PipelineDepthStencilStateCreateInfo::builder()
                 .depth_test_enable(true)
                 .depth_write_enable(true)
@@ -602,7 +2549,7 @@ impl MaterialSystem {
                 .build();
             self.text_builder.multisampling = vk::PipelineMultisampleStateCreateInfo::builder()
                 .sample_shading_enable(false)
-                .rasterization_samples(vk::SampleCountFlags::TYPE_1)
+                .rasterization_samples(self.msaa_samples)
                 .min_sample_shading(1.0)
                 .alpha_to_coverage_enable(false)
                 .alpha_to_one_enable(false)
@@ -649,7 +2596,7 @@ impl MaterialSystem {
                 .build();
             self.forward_builder.multisampling = vk::PipelineMultisampleStateCreateInfo::builder()
                 .sample_shading_enable(false)
-                .rasterization_samples(vk::SampleCountFlags::TYPE_1)
+                .rasterization_samples(self.msaa_samples)
                 .min_sample_shading(1.0)
                 .alpha_to_coverage_enable(false)
                 .alpha_to_one_enable(false)
@@ -675,6 +2622,65 @@ impl MaterialSystem {
                 .stencil_test_enable(false)
                 .build();
         }
+        {
+            // Blended over the opaque objects, tested against them but not hiding each
+            // other, which is why they are drawn back to front. The depth is read only in
+            // their subpass.
+            self.transparent_builder = self.forward_builder.clone();
+            self.transparent_builder.depth_stencil.depth_write_enable = vk::FALSE;
+            self.transparent_builder.subpass = 1;
+        }
+        {
+            // Billboards are tested against the scene but don't hide each other. They are
+            // blended over the composited transparent objects.
+            self.particle_builder = self.transparent_builder.clone();
+            self.particle_builder.vertex_description = ParticleInstance::get_vertex_description();
+            self.particle_builder.subpass = self.transparency.particle_subpass();
+        }
+        if self.transparency == TransparencyTechnique::WeightedBlended {
+            // In any order: the weighted colors and alphas add up in the accumulation,
+            // the revealage is multiplied by (1 - alpha)
+            self.transparent_builder.color_blend_attachments = vec![
+                vk::PipelineColorBlendAttachmentState::builder()
+                    .color_write_mask(vk::ColorComponentFlags::RGBA)
+                    .blend_enable(true)
+                    .src_color_blend_factor(vk::BlendFactor::ONE)
+                    .dst_color_blend_factor(vk::BlendFactor::ONE)
+                    .color_blend_op(vk::BlendOp::ADD)
+                    .src_alpha_blend_factor(vk::BlendFactor::ONE)
+                    .dst_alpha_blend_factor(vk::BlendFactor::ONE)
+                    .alpha_blend_op(vk::BlendOp::ADD)
+                    .build(),
+                vk::PipelineColorBlendAttachmentState::builder()
+                    .color_write_mask(vk::ColorComponentFlags::R)
+                    .blend_enable(true)
+                    .src_color_blend_factor(vk::BlendFactor::ZERO)
+                    .dst_color_blend_factor(vk::BlendFactor::ONE_MINUS_SRC_COLOR)
+                    .color_blend_op(vk::BlendOp::ADD)
+                    .src_alpha_blend_factor(vk::BlendFactor::ZERO)
+                    .dst_alpha_blend_factor(vk::BlendFactor::ONE_MINUS_SRC_ALPHA)
+                    .alpha_blend_op(vk::BlendOp::ADD)
+                    .build(),
+            ];
+        }
+        {
+            // A fullscreen triangle generated in the vertex shader that replaces what is
+            // below it
+            self.upscale_builder = self.forward_builder.clone();
+            self.upscale_builder.vertex_description = VertexInputDescription::default();
+            self.upscale_builder.color_blend_attachment.blend_enable = vk::FALSE;
+            self.upscale_builder.depth_stencil.depth_test_enable = vk::FALSE;
+            self.upscale_builder.depth_stencil.depth_write_enable = vk::FALSE;
+        }
+        {
+            // A cube generated in the vertex shader on the far plane, behind everything
+            // drawn after it
+            self.skybox_builder = self.forward_builder.clone();
+            self.skybox_builder.vertex_description = VertexInputDescription::default();
+            self.skybox_builder.color_blend_attachment.blend_enable = vk::FALSE;
+            self.skybox_builder.depth_stencil.depth_compare_op = vk::CompareOp::LESS_OR_EQUAL;
+            self.skybox_builder.depth_stencil.depth_write_enable = vk::FALSE;
+        }
     }
 
     pub fn destroy(&mut self, device: &ash::Device) {
@@ -685,6 +2691,210 @@ impl MaterialSystem {
         self.effect_template_handles.clear();
         self.materials.clear();
         self.material_cache.clear();
+        self.texture_references.clear();
+        self.dirty_parameters.clear();
+        for material in self.materials_handles.iter_mut() {
+            if let Some(mut buffer) = material.parameter_buffer.take() {
+                buffer.queue_free().expect("Invalid Handle?!");
+            }
+        }
         self.materials_handles.clear();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(entries: &[(&str, usize)]) -> HashMap<String, Handle<Material>> {
+        entries
+            .iter()
+            .map(|(name, id)| (name.to_string(), Handle::for_test(*id)))
+            .collect()
+    }
+
+    #[test]
+    fn identical_data_under_two_names_is_shared() {
+        let materials = names(&[("brick", 1), ("wall", 1), ("floor", 2)]);
+        assert_eq!(
+            aliases(&materials, Handle::for_test(1)),
+            vec!["brick", "wall"]
+        );
+        assert_eq!(aliases(&materials, Handle::for_test(2)), vec!["floor"]);
+        assert!(aliases(&materials, Handle::for_test(3)).is_empty());
+    }
+
+    #[test]
+    fn writing_a_shared_material_splits_it_once() {
+        let mut materials = names(&[("brick", 1), ("wall", 1)]);
+        let shared =
+            |materials: &HashMap<_, _>, name: &str| aliases(materials, materials[name]).len() > 1;
+
+        assert_eq!(
+            material_write(false, shared(&materials, "brick"), None),
+            MaterialWrite::Split
+        );
+        // modify_material builds the copy under the name
+        materials.insert("brick".to_string(), Handle::for_test(2));
+        assert_eq!(aliases(&materials, Handle::for_test(1)), vec!["wall"]);
+
+        assert_eq!(
+            material_write(false, shared(&materials, "brick"), None),
+            MaterialWrite::InPlace
+        );
+    }
+
+    #[test]
+    fn writing_the_data_of_another_material_aliases_it() {
+        assert_eq!(
+            material_write(false, false, Some(Handle::for_test(4))),
+            MaterialWrite::Alias(Handle::for_test(4))
+        );
+        // A shared material still splits, build_material finds the other one
+        assert_eq!(
+            material_write(false, true, Some(Handle::for_test(4))),
+            MaterialWrite::Split
+        );
+    }
+
+    fn data(textures: &[(MaterialTextureSlot, usize)]) -> MaterialData {
+        MaterialData {
+            textures: textures
+                .iter()
+                .map(|(slot, id)| (*slot, Handle::for_test(*id)))
+                .collect(),
+            buffers: vec![],
+            parameters: ShaderParameters::default(),
+            base_template: "lightmapped".to_string(),
+        }
+    }
+
+    const LIGHTMAPPED: [(MaterialTextureSlot, u32); 2] = [
+        (MaterialTextureSlot::Albedo, 1),
+        (MaterialTextureSlot::Lightmap, 2),
+    ];
+
+    #[test]
+    fn undeclared_slots_are_rejected_by_name() {
+        assert!(check_texture_slots(&LIGHTMAPPED, &data(&[])).is_ok());
+        assert!(
+            check_texture_slots(&LIGHTMAPPED, &data(&[(MaterialTextureSlot::Lightmap, 5)])).is_ok()
+        );
+        let error = check_texture_slots(
+            &LIGHTMAPPED,
+            &data(&[
+                (MaterialTextureSlot::Custom(3), 5),
+                (MaterialTextureSlot::Albedo, 5),
+                (MaterialTextureSlot::Normal, 6),
+            ]),
+        );
+        match error {
+            Err(RendererError::IncompatibleMaterial { source, .. }) => assert_eq!(
+                source.0,
+                "the shaders of template lightmapped don't declare normal_tex, custom3_tex"
+            ),
+            other => panic!("expected an incompatible material, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn empty_slots_get_their_defaults() {
+        let defaults = DefaultTextures {
+            white: Handle::for_test(100),
+            black: Handle::for_test(101),
+            flat_normal: Handle::for_test(102),
+        };
+        let slots = [
+            (MaterialTextureSlot::Albedo, 1),
+            (MaterialTextureSlot::Normal, 2),
+            (MaterialTextureSlot::Emissive, 3),
+            (MaterialTextureSlot::Occlusion, 4),
+            (MaterialTextureSlot::Custom(0), 5),
+        ];
+        let textures = data(&[(MaterialTextureSlot::Occlusion, 7)]).textures;
+        assert_eq!(
+            bound_textures(&slots, &textures, &defaults),
+            vec![
+                (1, defaults.white),
+                (2, defaults.flat_normal),
+                (3, defaults.black),
+                (4, Handle::for_test(7)),
+                (5, defaults.white),
+            ]
+        );
+    }
+
+    #[test]
+    fn slot_names_round_trip() {
+        for slot in MaterialTextureSlot::NAMED.into_iter().chain([
+            MaterialTextureSlot::Custom(0),
+            MaterialTextureSlot::Custom(12),
+        ]) {
+            assert_eq!(
+                MaterialTextureSlot::from_binding_name(&slot.binding_name()),
+                Some(slot)
+            );
+        }
+        assert_eq!(MaterialTextureSlot::from_binding_name("diffuse"), None);
+        assert_eq!(MaterialTextureSlot::from_binding_name("customx_tex"), None);
+    }
+
+    #[test]
+    fn texture_order_does_not_change_the_data() {
+        let mut forward = data(&[]);
+        let mut backward = data(&[]);
+        for i in 0..8 {
+            forward.textures.insert(
+                MaterialTextureSlot::Custom(i),
+                Handle::for_test(i as usize + 1),
+            );
+            backward.textures.insert(
+                MaterialTextureSlot::Custom(7 - i),
+                Handle::for_test(8 - i as usize),
+            );
+        }
+        let hash = |data: &MaterialData| {
+            let mut hasher = std::collections::hash_map::DefaultHasher::new();
+            data.hash(&mut hasher);
+            std::hash::Hasher::finish(&hasher)
+        };
+        assert!(forward == backward);
+        assert_eq!(hash(&forward), hash(&backward));
+    }
+
+    #[test]
+    fn unchanged_data_writes_nothing() {
+        assert_eq!(material_write(true, true, None), MaterialWrite::Unchanged);
+        assert_eq!(material_write(true, false, None), MaterialWrite::Unchanged);
+    }
+
+    fn template(transparency_mode: TransparencyMode) -> EffectTemplate {
+        EffectTemplate {
+            pass_shaders: Default::default(),
+            default_parameters: ShaderParameters::default(),
+            transparency_mode,
+            generation: 0,
+            user_managed_sets: vec![],
+            texture_slots: vec![],
+            object_params_size: None,
+            parameter_block: None,
+            morph_targets: false,
+        }
+    }
+
+    #[test]
+    fn only_transparent_templates_draw_in_the_transparency_pass() {
+        assert!(matches!(
+            template(TransparencyMode::Transparent).mesh_pass(),
+            MeshPassType::Transparency
+        ));
+        assert!(matches!(
+            template(TransparencyMode::Opaque).mesh_pass(),
+            MeshPassType::Forward
+        ));
+        assert!(matches!(
+            template(TransparencyMode::Masked).mesh_pass(),
+            MeshPassType::Forward
+        ));
+    }
+}
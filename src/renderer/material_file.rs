@@ -0,0 +1,268 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use log::warn;
+use ron::ser::PrettyConfig;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use super::{
+    error::{MaterialFileError, RendererError},
+    material::{ParameterValue, ShaderParameters},
+    RendererResult,
+};
+
+// Part of the format, but the templates fix their pipeline state for now
+const UNSUPPORTED_KEYS: [&str; 3] = ["blend", "cull", "derived_template"];
+
+// A material as stored in a .material file, written in RON:
+//
+// (
+//     base_template: "default",
+//     textures: ["textures/albedo.png"],
+//     parameters: {"roughness": 0.5, "tint": (1.0, 0.5, 0.5, 1.0)},
+//     uv_scale: (2.0, 2.0),
+// )
+//
+// Everything except base_template is optional. Relative texture paths are
+// relative to the material file. The textures fill the texture slots of the
+// template in binding order, e.g. albedo then lightmap for "lightmapped".
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MaterialFile {
+    pub base_template: String,
+    pub textures: Vec<PathBuf>,
+    pub parameters: BTreeMap<String, ParameterValue>,
+    pub uv_offset: [f32; 2],
+    pub uv_scale: [f32; 2],
+    pub uv_rotation: f32,
+}
+
+impl Default for MaterialFile {
+    fn default() -> Self {
+        Self {
+            base_template: "default".to_string(),
+            textures: vec![],
+            parameters: BTreeMap::new(),
+            uv_offset: [0.0, 0.0],
+            uv_scale: [1.0, 1.0],
+            uv_rotation: 0.0,
+        }
+    }
+}
+
+fn file_error(path: &Path, message: String) -> RendererError {
+    MaterialFileError(format!("{}: {}", path.display(), message)).into()
+}
+
+fn field<T: DeserializeOwned>(value: ron::Value, key: &str, path: &Path) -> RendererResult<T> {
+    value
+        .into_rust()
+        .map_err(|e| file_error(path, format!("invalid value for {}: {}", key, e)))
+}
+
+impl MaterialFile {
+    pub fn new(base_template: &str, textures: Vec<PathBuf>, parameters: &ShaderParameters) -> Self {
+        Self {
+            base_template: base_template.to_string(),
+            textures,
+            parameters: parameters
+                .iter()
+                .map(|(name, value)| (name.to_string(), value))
+                .collect(),
+            uv_offset: parameters.uv_offset.into(),
+            uv_scale: parameters.uv_scale.into(),
+            uv_rotation: parameters.uv_rotation,
+        }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> RendererResult<Self> {
+        let path = path.as_ref();
+        let source = std::fs::read_to_string(path)?;
+        Self::parse(&source, path)
+    }
+
+    // The path is only used for messages. Unknown keys are skipped with a warning,
+    // values of the wrong type are an error.
+    pub fn parse(source: &str, path: &Path) -> RendererResult<Self> {
+        let value: ron::Value =
+            ron::from_str(source).map_err(|e| file_error(path, e.to_string()))?;
+        let map = match value {
+            ron::Value::Map(map) => map,
+            _ => {
+                return Err(file_error(
+                    path,
+                    "expected a struct of material settings".to_string(),
+                ))
+            }
+        };
+
+        let mut file = Self::default();
+        let mut has_base_template = false;
+        for (key, value) in map {
+            let key = match key {
+                ron::Value::String(key) => key,
+                other => return Err(file_error(path, format!("invalid key {:?}", other))),
+            };
+            match key.as_str() {
+                "base_template" => {
+                    file.base_template = field(value, &key, path)?;
+                    has_base_template = true;
+                }
+                "textures" => file.textures = field(value, &key, path)?,
+                "parameters" => file.parameters = field(value, &key, path)?,
+                "uv_offset" => file.uv_offset = field(value, &key, path)?,
+                "uv_scale" => file.uv_scale = field(value, &key, path)?,
+                "uv_rotation" => file.uv_rotation = field(value, &key, path)?,
+                k if UNSUPPORTED_KEYS.contains(&k) => {
+                    warn!(
+                        "{}: {} is not supported yet, ignoring it",
+                        path.display(),
+                        k
+                    )
+                }
+                k => warn!("{}: unknown key {}, ignoring it", path.display(), k),
+            }
+        }
+        if !has_base_template {
+            return Err(file_error(path, "missing base_template".to_string()));
+        }
+        Ok(file)
+    }
+
+    pub fn to_ron_string(&self) -> RendererResult<String> {
+        ron::ser::to_string_pretty(self, PrettyConfig::default())
+            .map_err(|e| MaterialFileError(e.to_string()).into())
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> RendererResult<()> {
+        std::fs::write(path, self.to_ron_string()?)?;
+        Ok(())
+    }
+
+    pub fn shader_parameters(&self) -> ShaderParameters {
+        let mut parameters = ShaderParameters::default();
+        for (name, value) in self.parameters.iter() {
+            parameters.set(name.as_str(), *value);
+        }
+        parameters.uv_offset = self.uv_offset.into();
+        parameters.uv_scale = self.uv_scale.into();
+        parameters.uv_rotation = self.uv_rotation;
+        parameters
+    }
+
+    fn directory(material_path: &Path) -> &Path {
+        match material_path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        }
+    }
+
+    pub fn resolve_texture_path(material_path: &Path, texture: &Path) -> PathBuf {
+        Self::directory(material_path).join(texture)
+    }
+
+    // The inverse of resolve_texture_path, textures outside of the material's
+    // directory keep their absolute path
+    pub fn relative_texture_path(material_path: &Path, texture: &Path) -> PathBuf {
+        std::fs::canonicalize(Self::directory(material_path))
+            .ok()
+            .and_then(|dir| texture.strip_prefix(dir).ok())
+            .unwrap_or(texture)
+            .to_path_buf()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_error(source: &str) -> String {
+        match MaterialFile::parse(source, Path::new("assets/brick.material")) {
+            Err(RendererError::MaterialFileError { source, .. }) => source.0,
+            other => panic!("expected a material file error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn errors_name_the_file_and_the_key() {
+        let message = parse_error(r#"(base_template: "default", uv_scale: "big")"#);
+        assert!(
+            message.starts_with("assets/brick.material: "),
+            "{}",
+            message
+        );
+        assert!(message.contains("uv_scale"), "{}", message);
+        let message = parse_error(r#"(base_template: "default", parameters: {"roughness": "x"})"#);
+        assert!(message.contains("parameters"), "{}", message);
+    }
+
+    #[test]
+    fn rejects_files_that_are_not_materials() {
+        assert!(parse_error("(").starts_with("assets/brick.material: "));
+        assert!(parse_error(r#""default""#).contains("expected a struct"));
+        assert!(parse_error("(textures: [])").contains("missing base_template"));
+    }
+
+    #[test]
+    fn unknown_and_unsupported_keys_are_ignored() {
+        let file = MaterialFile::parse(
+            r#"(base_template: "unlit", shininess: 3.0, cull: "none", uv_rotation: 0.5)"#,
+            Path::new("brick.material"),
+        )
+        .unwrap();
+        assert_eq!(
+            file,
+            MaterialFile {
+                base_template: "unlit".to_string(),
+                uv_rotation: 0.5,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn round_trips_through_ron() {
+        let mut parameters = ShaderParameters::default();
+        parameters.set("roughness", 0.25);
+        parameters.set("tint", ParameterValue::Vec4([1.0, 0.5, 0.5, 1.0]));
+        parameters.uv_scale = [2.0, 3.0].into();
+        parameters.uv_rotation = 0.5;
+        let file = MaterialFile::new(
+            "lightmapped",
+            vec![
+                PathBuf::from("textures/albedo.png"),
+                PathBuf::from("lightmap.png"),
+            ],
+            &parameters,
+        );
+        let source = file.to_ron_string().unwrap();
+        let parsed = MaterialFile::parse(&source, Path::new("brick.material")).unwrap();
+        assert_eq!(parsed, file);
+        assert_eq!(
+            parsed.shader_parameters().get("roughness"),
+            Some(0.25.into())
+        );
+        assert_eq!(parsed.shader_parameters().uv_scale, parameters.uv_scale);
+    }
+
+    #[test]
+    fn texture_paths_round_trip_relative_to_the_material() {
+        let dir = std::env::temp_dir().join(format!("material_file_{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("textures")).unwrap();
+        let material = dir.join("brick.material");
+        let resolved = MaterialFile::resolve_texture_path(&material, Path::new("textures/a.png"));
+        std::fs::write(&resolved, []).unwrap();
+        let canonical = std::fs::canonicalize(&resolved).unwrap();
+        let relative = MaterialFile::relative_texture_path(&material, &canonical);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(relative, Path::new("textures/a.png"));
+        assert_eq!(
+            MaterialFile::relative_texture_path(&material, Path::new("/elsewhere/b.png")),
+            Path::new("/elsewhere/b.png")
+        );
+        assert_eq!(
+            MaterialFile::resolve_texture_path(Path::new("brick.material"), Path::new("a.png")),
+            Path::new("./a.png")
+        );
+    }
+}
@@ -7,11 +7,16 @@ use ash::vk;
 use nalgebra_glm::{Vec2, Vec3};
 
 use gpu_allocator::vulkan::Allocator;
-use gpu_allocator::MemoryLocation;
 
 use crate::renderer::Buffer;
 
+use self::loaders::obj::ObjMaterial;
+
 use super::buffer::BufferManager;
+use super::culling::Aabb;
+use super::error::MorphTargetError;
+use super::loading::MeshSource;
+use super::morph::MorphTarget;
 use super::utils::{Handle, HandleArray};
 use super::vertex::Vertex;
 use super::RendererResult;
@@ -22,8 +27,13 @@ pub mod loaders;
 pub struct Mesh {
     vertex_data: Vec<Vertex>,
     index_data: Vec<u32>,
+    // Updated together with the vertex buffer
+    bounds: Aabb,
     pub vertex_buffer: Option<Buffer>,
     pub index_buffer: Option<Buffer>,
+    morph_targets: Vec<MorphTarget>,
+    // Where the deltas start in the morph delta buffer of the renderer
+    morph_base: u32,
 }
 
 impl Mesh {
@@ -31,11 +41,68 @@ impl Mesh {
         Mesh {
             vertex_data: vertices,
             index_data: indices,
+            bounds: Aabb::default(),
             vertex_buffer: None,
             index_buffer: None,
+            morph_targets: vec![],
+            morph_base: 0,
         }
     }
 
+    pub fn vertices(&self) -> &[Vertex] {
+        &self.vertex_data
+    }
+
+    pub fn indices(&self) -> &[u32] {
+        &self.index_data
+    }
+
+    pub fn bounds(&self) -> &Aabb {
+        &self.bounds
+    }
+
+    pub fn morph_targets(&self) -> &[MorphTarget] {
+        &self.morph_targets
+    }
+
+    pub fn morph_target_index(&self, name: &str) -> Option<usize> {
+        self.morph_targets
+            .iter()
+            .position(|target| target.name == name)
+    }
+
+    // Every target needs a delta for every vertex, normal deltas can be left out. The
+    // bounds don't include the targets, objects using them may need CullingMode::Never.
+    pub fn set_morph_targets(&mut self, targets: Vec<MorphTarget>) -> RendererResult<()> {
+        let vertex_count = self.vertex_data.len();
+        for target in targets.iter() {
+            if target.position_deltas.len() != vertex_count
+                || !(target.normal_deltas.is_empty() || target.normal_deltas.len() == vertex_count)
+            {
+                return Err(MorphTargetError(format!(
+                    "target {:?} has {} position and {} normal deltas for {} vertices",
+                    target.name,
+                    target.position_deltas.len(),
+                    target.normal_deltas.len(),
+                    vertex_count
+                ))
+                .into());
+            }
+        }
+        self.morph_targets = targets;
+        Ok(())
+    }
+
+    pub(crate) fn morph_base(&self) -> u32 {
+        self.morph_base
+    }
+
+    pub(crate) fn set_morph_base(&mut self, base: u32) {
+        self.morph_base = base;
+    }
+
+    // The morph deltas of the new vertices are interpolated, Renderer::update_morph_deltas
+    // uploads them
     pub fn subdivide(&mut self) {
         let mut new_indices = vec![];
         let mut midpoints = HashMap::<(u32, u32), u32>::new();
@@ -82,6 +149,25 @@ impl Mesh {
             new_indices.extend_from_slice(&[mca, a, mab, mab, b, mbc, mbc, c, mca, mab, mbc, mca]);
         }
         self.index_data = new_indices;
+
+        // The new vertices move halfway between the ends of their edge
+        let mut edges = midpoints
+            .into_iter()
+            .filter(|((a, b), _)| a <= b)
+            .map(|((a, b), midpoint)| (midpoint, a as usize, b as usize))
+            .collect::<Vec<_>>();
+        edges.sort_unstable();
+        for target in self.morph_targets.iter_mut() {
+            for deltas in [&mut target.position_deltas, &mut target.normal_deltas] {
+                if deltas.is_empty() {
+                    continue;
+                }
+                for (_, a, b) in edges.iter() {
+                    let delta = 0.5 * (deltas[*a] + deltas[*b]);
+                    deltas.push(delta);
+                }
+            }
+        }
     }
 
     fn cube() -> Mesh {
@@ -278,21 +364,19 @@ impl Mesh {
         allocator: &mut Allocator,
         buffer_manager: Arc<Mutex<BufferManager>>,
     ) -> RendererResult<()> {
+        self.bounds = Aabb::from_points(self.vertex_data.iter().map(|v| v.pos));
         if let Some(buffer) = &mut self.vertex_buffer {
             buffer.fill(allocator, &self.vertex_data)?;
             Ok(())
         } else {
-            let bytes = self.vertex_data.len() * std::mem::size_of::<Vertex>();
-            let mut buffer = BufferManager::new_buffer(
+            let buffer = BufferManager::new_device_local_buffer(
                 buffer_manager,
                 device,
                 allocator,
-                bytes as u64,
+                &self.vertex_data,
                 vk::BufferUsageFlags::VERTEX_BUFFER,
-                MemoryLocation::CpuToGpu,
                 "vertex-buffer",
             )?;
-            buffer.fill(allocator, &self.vertex_data)?;
             self.vertex_buffer = Some(buffer);
             Ok(())
         }
@@ -308,23 +392,30 @@ impl Mesh {
             buffer.fill(allocator, &self.index_data)?;
             Ok(())
         } else {
-            let bytes = self.index_data.len() * std::mem::size_of::<u32>();
-            let mut buffer = BufferManager::new_buffer(
+            let buffer = BufferManager::new_device_local_buffer(
                 buffer_manager,
                 device,
                 allocator,
-                bytes as u64,
+                &self.index_data,
                 vk::BufferUsageFlags::INDEX_BUFFER,
-                MemoryLocation::CpuToGpu,
                 "index-buffer",
             )?;
-            buffer.fill(allocator, &self.index_data)?;
             self.index_buffer = Some(buffer);
             Ok(())
         }
     }
 
     pub fn draw(&self, device: &ash::Device, command_buffer: vk::CommandBuffer) {
+        self.draw_instanced(device, command_buffer, 1);
+    }
+
+    // The instance data is read from the vertex buffer bound to binding 1
+    pub fn draw_instanced(
+        &self,
+        device: &ash::Device,
+        command_buffer: vk::CommandBuffer,
+        instance_count: u32,
+    ) {
         if let Some(vert_buf) = &self.vertex_buffer {
             if let Some(ind_buf) = &self.index_buffer {
                 unsafe {
@@ -340,7 +431,7 @@ impl Mesh {
                     device.cmd_draw_indexed(
                         command_buffer,
                         self.index_data.len() as u32,
-                        1,
+                        instance_count,
                         0,
                         0,
                         0,
@@ -354,10 +445,10 @@ impl Mesh {
 impl Drop for Mesh {
     fn drop(&mut self) {
         if let Some(buf) = &mut self.vertex_buffer {
-            buf.queue_free(None).expect("Could not free buffer");
+            buf.queue_free().expect("Could not free buffer");
         }
         if let Some(buf) = &mut self.index_buffer {
-            buf.queue_free(None).expect("Could not free buffer");
+            buf.queue_free().expect("Could not free buffer");
         }
     }
 }
@@ -365,10 +456,16 @@ impl Drop for Mesh {
 #[derive(Debug, Default)]
 pub struct MeshManager {
     meshs: HandleArray<Mesh>,
+    // Of the meshes built from a file or a primitive, the others can't be built again
+    sources: HashMap<Handle<Mesh>, MeshSource>,
 }
 
 impl MeshManager {
-    fn add_mesh(
+    pub fn allocation_hash(&self) -> u64 {
+        self.meshs.allocation_hash()
+    }
+
+    pub fn add_mesh(
         &mut self,
         mut mesh: Mesh,
         device: &ash::Device,
@@ -399,7 +496,9 @@ impl MeshManager {
         buffer_manager: Arc<Mutex<BufferManager>>,
     ) -> RendererResult<Handle<Mesh>> {
         let mesh = Mesh::cube();
-        self.add_mesh(mesh, device, allocator, buffer_manager)
+        let handle = self.add_mesh(mesh, device, allocator, buffer_manager)?;
+        self.sources.insert(handle, MeshSource::Cube);
+        Ok(handle)
     }
 
     pub fn new_icosahedron_mesh(
@@ -409,7 +508,9 @@ impl MeshManager {
         buffer_manager: Arc<Mutex<BufferManager>>,
     ) -> RendererResult<Handle<Mesh>> {
         let mesh = Mesh::icosahedron();
-        self.add_mesh(mesh, device, allocator, buffer_manager)
+        let handle = self.add_mesh(mesh, device, allocator, buffer_manager)?;
+        self.sources.insert(handle, MeshSource::Icosahedron);
+        Ok(handle)
     }
 
     pub fn new_sphere_mesh(
@@ -420,7 +521,9 @@ impl MeshManager {
         buffer_manager: Arc<Mutex<BufferManager>>,
     ) -> RendererResult<Handle<Mesh>> {
         let mesh = Mesh::sphere(refinements);
-        self.add_mesh(mesh, device, allocator, buffer_manager)
+        let handle = self.add_mesh(mesh, device, allocator, buffer_manager)?;
+        self.sources.insert(handle, MeshSource::Sphere(refinements));
+        Ok(handle)
     }
 
     pub fn new_mesh_from_obj<P: AsRef<Path>>(
@@ -430,14 +533,48 @@ impl MeshManager {
         allocator: &mut Allocator,
         buffer_manager: Arc<Mutex<BufferManager>>,
     ) -> RendererResult<Handle<Mesh>> {
-        let mesh = loaders::obj::load_obj(path)?;
-        self.add_mesh(mesh, device, allocator, buffer_manager)
+        let mesh = loaders::obj::load_obj(path.as_ref())?;
+        let handle = self.add_mesh(mesh, device, allocator, buffer_manager)?;
+        self.sources
+            .insert(handle, MeshSource::Obj(path.as_ref().to_path_buf()));
+        Ok(handle)
+    }
+
+    // One mesh per material of the file, with what its MTL files say about the material
+    pub fn new_meshes_from_obj<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        device: &ash::Device,
+        allocator: &mut Allocator,
+        buffer_manager: Arc<Mutex<BufferManager>>,
+    ) -> RendererResult<Vec<(Handle<Mesh>, ObjMaterial)>> {
+        loaders::obj::load_obj_with_materials(path)?
+            .submeshes
+            .into_iter()
+            .map(|(mesh, material)| {
+                let handle = self.add_mesh(mesh, device, allocator, buffer_manager.clone())?;
+                Ok((handle, material))
+            })
+            .collect()
+    }
+
+    pub fn remove_mesh(&mut self, handle: Handle<Mesh>) -> RendererResult<()> {
+        // Dropping the mesh queues its buffers for freeing
+        self.meshs.remove(handle)?;
+        self.sources.remove(&handle);
+        Ok(())
     }
 
     pub fn get_mesh(&self, handle: Handle<Mesh>) -> Option<&Mesh> {
         self.meshs.get(handle)
     }
 
+    // What the mesh was built from, changes to its vertices since are not reflected. None
+    // for meshes built from vertices, or from a file with more than one material.
+    pub fn get_mesh_source(&self, handle: Handle<Mesh>) -> Option<&MeshSource> {
+        self.sources.get(&handle)
+    }
+
     pub fn get_mesh_mut(&mut self, handle: Handle<Mesh>) -> Option<&mut Mesh> {
         self.meshs.get_mut(handle)
     }
@@ -452,5 +589,6 @@ impl MeshManager {
 
     pub fn destroy(&mut self) {
         self.meshs.clear();
+        self.sources.clear();
     }
 }
@@ -1 +1,2 @@
+pub mod gltf;
 pub mod obj;
@@ -0,0 +1,437 @@
+use std::path::Path;
+
+use log::warn;
+use nalgebra_glm as glm;
+use serde::Deserialize;
+
+use crate::renderer::error::{GltfError, RendererResult};
+use crate::renderer::mesh::Mesh;
+use crate::renderer::morph::MorphTarget;
+use crate::renderer::vertex::Vertex;
+
+// A triangle list of a glTF mesh
+pub struct GltfPrimitive {
+    pub mesh: Mesh,
+    // Index into GltfScene::materials, None for the default material
+    pub material: Option<usize>,
+}
+
+pub struct GltfMaterial {
+    pub name: String,
+    // Linear RGBA, only used without a base color image
+    pub base_color_factor: [f32; 4],
+    // Index into GltfScene::images
+    pub base_color_image: Option<usize>,
+    pub metallic_factor: f32,
+    pub roughness_factor: f32,
+}
+
+// Converted to RGBA with 8 bits per channel
+pub struct GltfImage {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>,
+}
+
+pub struct GltfNode {
+    pub name: String,
+    // Index into GltfScene::meshes
+    pub mesh: Option<usize>,
+    // The default weight of each morph target of the mesh, in the order of
+    // Mesh::morph_targets. From the node, or from the mesh if the node has none.
+    pub morph_weights: Vec<f32>,
+    // Relative to the parent node
+    pub translation: glm::Vec3,
+    pub rotation: glm::Quat,
+    pub scale: glm::Vec3,
+    // Indices into GltfScene::nodes
+    pub children: Vec<usize>,
+}
+
+// Everything is indexed like in the file
+#[derive(Default)]
+pub struct GltfScene {
+    pub nodes: Vec<GltfNode>,
+    // Of the default scene, or of the first one without a default
+    pub roots: Vec<usize>,
+    // The primitives of each mesh
+    pub meshes: Vec<Vec<GltfPrimitive>>,
+    pub materials: Vec<GltfMaterial>,
+    // None for images in a format that isn't supported
+    pub images: Vec<Option<GltfImage>>,
+}
+
+// The exporters' convention for naming morph targets, glTF itself leaves them unnamed
+#[derive(Default, Deserialize)]
+struct MeshExtras {
+    #[serde(rename = "targetNames", default)]
+    target_names: Vec<String>,
+}
+
+fn target_names(mesh: &gltf::Mesh) -> Vec<String> {
+    mesh.extras()
+        .as_ref()
+        .and_then(|extras| serde_json::from_str::<MeshExtras>(extras.get()).ok())
+        .unwrap_or_default()
+        .target_names
+}
+
+// Targets without a name in the extras are called "target <index>", the same names for
+// every primitive of the mesh, so the node's weights apply to all of them
+fn read_morph_targets<'a, 's, F>(
+    reader: &gltf::mesh::Reader<'a, 's, F>,
+    names: &[String],
+    vertex_count: usize,
+) -> Vec<MorphTarget>
+where
+    F: Clone + Fn(gltf::Buffer<'a>) -> Option<&'s [u8]>,
+{
+    reader
+        .read_morph_targets()
+        .enumerate()
+        .map(|(i, (positions, normals, _))| MorphTarget {
+            name: names
+                .get(i)
+                .cloned()
+                .unwrap_or_else(|| format!("target {}", i)),
+            // A target may leave out the positions when it only moves the normals
+            position_deltas: match positions {
+                Some(positions) => positions.map(glm::Vec3::from).collect(),
+                None => vec![glm::Vec3::zeros(); vertex_count],
+            },
+            normal_deltas: normals
+                .map(|normals| normals.map(glm::Vec3::from).collect())
+                .unwrap_or_default(),
+        })
+        .collect()
+}
+
+fn convert_image(image: &gltf::image::Data) -> Option<GltfImage> {
+    use gltf::image::Format;
+    let pixels = match image.format {
+        Format::R8G8B8A8 => image.pixels.clone(),
+        Format::R8G8B8 => image
+            .pixels
+            .chunks_exact(3)
+            .flat_map(|p| [p[0], p[1], p[2], 255])
+            .collect(),
+        Format::R8G8 => image
+            .pixels
+            .chunks_exact(2)
+            .flat_map(|p| [p[0], p[0], p[0], p[1]])
+            .collect(),
+        Format::R8 => image
+            .pixels
+            .iter()
+            .flat_map(|p| [*p, *p, *p, 255])
+            .collect(),
+        _ => return None,
+    };
+    Some(GltfImage {
+        width: image.width,
+        height: image.height,
+        pixels,
+    })
+}
+
+// Loads .gltf and .glb files with their buffers and images. Only triangle lists are
+// kept, other primitives are skipped with a warning. Vertices without normals or
+// texture coordinates get zeros, primitives without indices are indexed in order.
+// Morph targets keep their position and normal deltas, tangent deltas are dropped.
+pub fn load_gltf<P: AsRef<Path>>(path: P) -> RendererResult<GltfScene> {
+    let path = path.as_ref();
+    let (document, buffers, images) =
+        gltf::import(path).map_err(|e| GltfError(format!("{}: {}", path.display(), e)))?;
+
+    let mut scene = GltfScene {
+        images: images
+            .iter()
+            .enumerate()
+            .map(|(i, image)| {
+                let converted = convert_image(image);
+                if converted.is_none() {
+                    warn!(
+                        "{}: image {} has unsupported format {:?}, skipping it",
+                        path.display(),
+                        i,
+                        image.format
+                    );
+                }
+                converted
+            })
+            .collect(),
+        ..Default::default()
+    };
+
+    for (i, material) in document.materials().enumerate() {
+        let pbr = material.pbr_metallic_roughness();
+        scene.materials.push(GltfMaterial {
+            name: material
+                .name()
+                .map_or_else(|| format!("material {}", i), str::to_string),
+            base_color_factor: pbr.base_color_factor(),
+            base_color_image: pbr
+                .base_color_texture()
+                .map(|info| info.texture().source().index()),
+            metallic_factor: pbr.metallic_factor(),
+            roughness_factor: pbr.roughness_factor(),
+        });
+    }
+
+    for mesh in document.meshes() {
+        let names = target_names(&mesh);
+        let mut primitives = vec![];
+        for primitive in mesh.primitives() {
+            if primitive.mode() != gltf::mesh::Mode::Triangles {
+                warn!(
+                    "{}: skipping a {:?} primitive of mesh {}",
+                    path.display(),
+                    primitive.mode(),
+                    mesh.index()
+                );
+                continue;
+            }
+            let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
+            let positions = match reader.read_positions() {
+                Some(positions) => positions,
+                None => {
+                    warn!(
+                        "{}: skipping a primitive of mesh {} without positions",
+                        path.display(),
+                        mesh.index()
+                    );
+                    continue;
+                }
+            };
+            let mut vertices: Vec<Vertex> = positions
+                .map(|pos| Vertex {
+                    pos: pos.into(),
+                    ..Default::default()
+                })
+                .collect();
+            if let Some(normals) = reader.read_normals() {
+                for (vertex, normal) in vertices.iter_mut().zip(normals) {
+                    vertex.normal = normal.into();
+                }
+            }
+            if let Some(uvs) = reader.read_tex_coords(0) {
+                for (vertex, uv) in vertices.iter_mut().zip(uvs.into_f32()) {
+                    vertex.uv = uv.into();
+                }
+            }
+            let indices = match reader.read_indices() {
+                Some(indices) => indices.into_u32().collect(),
+                None => (0..vertices.len() as u32).collect(),
+            };
+            let targets = read_morph_targets(&reader, &names, vertices.len());
+            let mut converted = Mesh::new(vertices, indices);
+            converted.set_morph_targets(targets).map_err(|e| {
+                GltfError(format!("{}: mesh {}: {}", path.display(), mesh.index(), e))
+            })?;
+            primitives.push(GltfPrimitive {
+                mesh: converted,
+                material: primitive.material().index(),
+            });
+        }
+        scene.meshes.push(primitives);
+    }
+
+    for node in document.nodes() {
+        let (translation, [x, y, z, w], scale) = node.transform().decomposed();
+        scene.nodes.push(GltfNode {
+            name: node
+                .name()
+                .map_or_else(|| format!("node {}", node.index()), str::to_string),
+            mesh: node.mesh().map(|mesh| mesh.index()),
+            morph_weights: node
+                .weights()
+                .or_else(|| node.mesh().and_then(|mesh| mesh.weights()))
+                .map(<[f32]>::to_vec)
+                .unwrap_or_default(),
+            translation: translation.into(),
+            rotation: glm::Quat::new(w, x, y, z),
+            scale: scale.into(),
+            children: node.children().map(|child| child.index()).collect(),
+        });
+    }
+
+    if let Some(root_scene) = document
+        .default_scene()
+        .or_else(|| document.scenes().next())
+    {
+        scene.roots = root_scene.nodes().map(|node| node.index()).collect();
+    }
+    Ok(scene)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // One triangle with a target that moves its first vertex up, named in the extras
+    const TRIANGLE: &str = r#"{
+        "asset": { "version": "2.0" },
+        "scene": 0,
+        "scenes": [{ "nodes": [0] }],
+        "nodes": [{ "name": "morphing", "mesh": 0 }],
+        "meshes": [{
+            "primitives": [{
+                "attributes": { "POSITION": 0 },
+                "targets": [{ "POSITION": 1 }]
+            }],
+            "weights": [0.5],
+            "extras": { "targetNames": ["raise"] }
+        }],
+        "buffers": [{ "uri": "triangle.bin", "byteLength": 72 }],
+        "bufferViews": [
+            { "buffer": 0, "byteOffset": 0, "byteLength": 36 },
+            { "buffer": 0, "byteOffset": 36, "byteLength": 36 }
+        ],
+        "accessors": [
+            {
+                "bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3",
+                "min": [0.0, 0.0, 0.0], "max": [1.0, 1.0, 0.0]
+            },
+            {
+                "bufferView": 1, "componentType": 5126, "count": 3, "type": "VEC3",
+                "min": [0.0, 0.0, 0.0], "max": [0.0, 2.0, 0.0]
+            }
+        ]
+    }"#;
+
+    fn write_triangle(dir: &Path) {
+        let floats: [f32; 18] = [
+            0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0, // positions
+            0.0, 2.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, // deltas
+        ];
+        let bytes: Vec<u8> = floats.iter().flat_map(|f| f.to_le_bytes()).collect();
+        std::fs::create_dir_all(dir).unwrap();
+        std::fs::write(dir.join("triangle.bin"), bytes).unwrap();
+        std::fs::write(dir.join("triangle.gltf"), TRIANGLE).unwrap();
+    }
+
+    #[test]
+    fn loads_morph_targets_and_default_weights() {
+        let dir = std::env::temp_dir().join(format!("gltf_morph_{}", std::process::id()));
+        write_triangle(&dir);
+        let scene = load_gltf(dir.join("triangle.gltf"));
+        std::fs::remove_dir_all(&dir).unwrap();
+        let scene = scene.unwrap();
+
+        let mesh = &scene.meshes[0][0].mesh;
+        assert_eq!(mesh.morph_targets().len(), 1);
+        let target = &mesh.morph_targets()[0];
+        assert_eq!(target.name, "raise");
+        assert_eq!(
+            target.position_deltas,
+            vec![
+                glm::vec3(0.0, 2.0, 0.0),
+                glm::Vec3::zeros(),
+                glm::Vec3::zeros()
+            ]
+        );
+        assert!(target.normal_deltas.is_empty());
+        assert_eq!(scene.nodes[0].morph_weights, vec![0.5]);
+    }
+
+    #[test]
+    fn unnamed_targets_are_numbered() {
+        let dir = std::env::temp_dir().join(format!("gltf_unnamed_{}", std::process::id()));
+        write_triangle(&dir);
+        let unnamed = TRIANGLE.replace(
+            r#""extras": { "targetNames": ["raise"] }"#,
+            r#""name": "m""#,
+        );
+        std::fs::write(dir.join("triangle.gltf"), unnamed).unwrap();
+        let scene = load_gltf(dir.join("triangle.gltf"));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(
+            scene.unwrap().meshes[0][0].mesh.morph_targets()[0].name,
+            "target 0"
+        );
+    }
+
+    // A root with two children sharing the triangle of triangle.bin, one with a named
+    // material and one with an unnamed one, in the second scene, which is the default
+    const HIERARCHY: &str = r#"{
+        "asset": { "version": "2.0" },
+        "scene": 1,
+        "scenes": [{ "nodes": [1] }, { "nodes": [0] }],
+        "nodes": [
+            { "name": "root", "translation": [1.0, 2.0, 3.0], "children": [1, 2] },
+            { "name": "red", "mesh": 0, "scale": [2.0, 2.0, 2.0] },
+            { "mesh": 1, "rotation": [0.0, 0.0, 1.0, 0.0] }
+        ],
+        "meshes": [
+            { "primitives": [{ "attributes": { "POSITION": 0 }, "material": 0 }] },
+            {
+                "primitives": [
+                    { "attributes": { "POSITION": 0 }, "material": 1 },
+                    { "attributes": { "POSITION": 0 } }
+                ]
+            }
+        ],
+        "materials": [
+            {
+                "name": "red",
+                "pbrMetallicRoughness": {
+                    "baseColorFactor": [1.0, 0.0, 0.0, 1.0],
+                    "metallicFactor": 0.25,
+                    "roughnessFactor": 0.75
+                }
+            },
+            {}
+        ],
+        "buffers": [{ "uri": "triangle.bin", "byteLength": 72 }],
+        "bufferViews": [{ "buffer": 0, "byteOffset": 0, "byteLength": 36 }],
+        "accessors": [{
+            "bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3",
+            "min": [0.0, 0.0, 0.0], "max": [1.0, 1.0, 0.0]
+        }]
+    }"#;
+
+    #[test]
+    fn loads_nodes_materials_and_hierarchy() {
+        let dir = std::env::temp_dir().join(format!("gltf_hierarchy_{}", std::process::id()));
+        write_triangle(&dir);
+        std::fs::write(dir.join("hierarchy.gltf"), HIERARCHY).unwrap();
+        let scene = load_gltf(dir.join("hierarchy.gltf"));
+        std::fs::remove_dir_all(&dir).unwrap();
+        let scene = scene.unwrap();
+
+        assert_eq!(scene.roots, vec![0]);
+        let names: Vec<&str> = scene.nodes.iter().map(|node| node.name.as_str()).collect();
+        assert_eq!(names, vec!["root", "red", "node 2"]);
+        let root = &scene.nodes[0];
+        assert_eq!(root.mesh, None);
+        assert_eq!(root.children, vec![1, 2]);
+        assert_eq!(root.translation, glm::vec3(1.0, 2.0, 3.0));
+        assert_eq!(scene.nodes[1].mesh, Some(0));
+        assert_eq!(scene.nodes[1].scale, glm::vec3(2.0, 2.0, 2.0));
+        assert!(scene.nodes[1].children.is_empty());
+        assert_eq!(scene.nodes[2].mesh, Some(1));
+        assert_eq!(scene.nodes[2].rotation, glm::Quat::new(0.0, 0.0, 0.0, 1.0));
+
+        let materials: Vec<Option<usize>> = scene
+            .meshes
+            .iter()
+            .flatten()
+            .map(|primitive| primitive.material)
+            .collect();
+        assert_eq!(materials, vec![Some(0), Some(1), None]);
+        assert_eq!(scene.meshes[1][0].mesh.vertex_data.len(), 3);
+
+        let red = &scene.materials[0];
+        assert_eq!(red.name, "red");
+        assert_eq!(red.base_color_factor, [1.0, 0.0, 0.0, 1.0]);
+        assert_eq!(red.base_color_image, None);
+        assert_eq!(red.metallic_factor, 0.25);
+        assert_eq!(red.roughness_factor, 0.75);
+        let unnamed = &scene.materials[1];
+        assert_eq!(unnamed.name, "material 1");
+        assert_eq!(unnamed.base_color_factor, [1.0; 4]);
+        assert_eq!(unnamed.metallic_factor, 1.0);
+        assert_eq!(unnamed.roughness_factor, 1.0);
+    }
+}
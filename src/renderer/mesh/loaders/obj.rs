@@ -1,7 +1,9 @@
 use std::collections::HashMap;
 use std::fs::File;
 use std::io::{BufRead, BufReader};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+
+use log::warn;
 
 use crate::renderer::error::RendererResult;
 use crate::renderer::mesh::Mesh;
@@ -241,3 +243,535 @@ pub fn load_obj<P: AsRef<Path>>(path: P) -> RendererResult<Mesh> {
 
     Ok(Mesh::new(vertices_structs, indices))
 }
+
+// A part of an OBJ file started by an o or g line, or by a usemtl line in the middle of
+// a group
+pub struct ObjGroup {
+    // Unique within the scene, see load_obj_scene
+    pub name: String,
+    // Of the last usemtl line before the group, None before the first one
+    pub material: Option<String>,
+    pub mesh: Mesh,
+}
+
+#[derive(Default)]
+pub struct ObjScene {
+    // In the order they appear in the file
+    pub groups: Vec<ObjGroup>,
+    // Of the MTL files of the mtllib lines, by name
+    pub materials: HashMap<String, ObjMaterial>,
+}
+
+// What an MTL file says about a material, see load_mtl
+#[derive(Clone, Debug, PartialEq)]
+pub struct ObjMaterial {
+    // Of the newmtl line, empty for the default material
+    pub name: String,
+    // Kd
+    pub diffuse_color: [f32; 3],
+    // map_Kd, joined to the directory of the MTL file
+    pub diffuse_texture: Option<PathBuf>,
+    // Ns
+    pub specular_exponent: f32,
+}
+
+impl Default for ObjMaterial {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            diffuse_color: [1.0; 3],
+            diffuse_texture: None,
+            specular_exponent: 0.0,
+        }
+    }
+}
+
+// The faces of an OBJ file merged by material, see load_obj_with_materials
+#[derive(Default)]
+pub struct LoadedObj {
+    pub submeshes: Vec<(Mesh, ObjMaterial)>,
+}
+
+// The name of the group faces before the first o or g line are put into
+const DEFAULT_GROUP_NAME: &str = "default";
+
+struct GroupBuilder {
+    name: String,
+    material: Option<String>,
+    vertices: Vec<Vertex>,
+    indices: Vec<u32>,
+    vertex_to_index_map: HashMap<Vertex, u32>,
+}
+
+impl GroupBuilder {
+    fn new(name: String, material: Option<String>) -> Self {
+        Self {
+            name,
+            material,
+            vertices: vec![],
+            indices: vec![],
+            vertex_to_index_map: HashMap::new(),
+        }
+    }
+}
+
+// OBJ indices start at 1, negative ones count back from the last element read so far
+fn resolve_index<R: Copy>(vector: &[R], index: &str) -> Option<R> {
+    let index = index.parse::<i64>().ok()?;
+    let position = match index.cmp(&0) {
+        std::cmp::Ordering::Less => vector.len().checked_sub(index.unsigned_abs() as usize)?,
+        std::cmp::Ordering::Equal => return None,
+        std::cmp::Ordering::Greater => index as usize - 1,
+    };
+    vector.get(position).copied()
+}
+
+// One corner of a face, as v, v/vt, v//vn or v/vt/vn
+fn parse_face_vertex(
+    corner: &str,
+    positions: &[[f32; 3]],
+    uvs: &[[f32; 2]],
+    normals: &[[f32; 3]],
+) -> Option<Vertex> {
+    let mut parts = corner.split('/');
+    let mut vertex = Vertex {
+        pos: resolve_index(positions, parts.next()?)?.into(),
+        ..Default::default()
+    };
+    match parts.next() {
+        Some("") | None => {}
+        Some(uv) => vertex.uv = resolve_index(uvs, uv)?.into(),
+    }
+    match parts.next() {
+        Some("") | None => {}
+        Some(normal) => vertex.normal = resolve_index(normals, normal)?.into(),
+    }
+    Some(vertex)
+}
+
+fn parse_floats<const N: usize>(args: &[&str]) -> Option<[f32; N]> {
+    let mut values = [0.0; N];
+    for (value, arg) in values.iter_mut().zip(args) {
+        *value = arg.parse().ok()?;
+    }
+    (args.len() >= N).then_some(values)
+}
+
+// Reads newmtl, Kd, map_Kd and Ns, everything else is ignored. Malformed lines are
+// skipped with a warning. Options in front of a texture file, like -s 1 1 1, are skipped
+// too, so the file name can't have spaces.
+pub fn load_mtl<P: AsRef<Path>>(path: P) -> RendererResult<HashMap<String, ObjMaterial>> {
+    let path = path.as_ref();
+    let directory = path.parent().unwrap_or_else(|| Path::new(""));
+    let reader = BufReader::new(File::open(path)?);
+
+    let mut materials = HashMap::new();
+    let mut current: Option<ObjMaterial> = None;
+    for (line_number, line) in reader.lines().enumerate() {
+        let line = line?;
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        if parts.is_empty() || parts[0].starts_with('#') {
+            continue;
+        }
+        let args = &parts[1..];
+        if parts[0] == "newmtl" {
+            if let Some(material) = current.take() {
+                materials.insert(material.name.clone(), material);
+            }
+            current = Some(ObjMaterial {
+                name: args.join(" "),
+                ..Default::default()
+            });
+            continue;
+        }
+        // Nothing before the first newmtl belongs to a material
+        let material = match current.as_mut() {
+            Some(material) => material,
+            None => continue,
+        };
+        let malformed = || {
+            warn!(
+                "{}:{}: skipping malformed line: {}",
+                path.display(),
+                line_number + 1,
+                line
+            )
+        };
+        match parts[0] {
+            "Kd" => match parse_floats::<3>(args) {
+                Some(color) => material.diffuse_color = color,
+                None => malformed(),
+            },
+            "Ns" => match parse_floats::<1>(args) {
+                Some([exponent]) => material.specular_exponent = exponent,
+                None => malformed(),
+            },
+            "map_Kd" => match args.last() {
+                Some(file) => material.diffuse_texture = Some(directory.join(file)),
+                None => malformed(),
+            },
+            _ => {}
+        }
+    }
+    if let Some(material) = current {
+        materials.insert(material.name.clone(), material);
+    }
+    Ok(materials)
+}
+
+// Keeps the groups apart instead of merging them into one mesh like load_obj does.
+// Malformed lines and faces are skipped with a warning instead of failing the whole
+// file, polygons are split into triangle fans and groups without faces are dropped.
+// Groups with a name that was already used get a suffix, e.g. wheel, wheel.1, wheel.2,
+// in the order of the file. MTL files that can't be read are skipped with a warning.
+pub fn load_obj_scene<P: AsRef<Path>>(path: P) -> RendererResult<ObjScene> {
+    let path = path.as_ref();
+    let reader = BufReader::new(File::open(path)?);
+
+    let mut positions = Vec::<[f32; 3]>::new();
+    let mut normals = Vec::<[f32; 3]>::new();
+    let mut uvs = Vec::<[f32; 2]>::new();
+
+    let mut scene = ObjScene::default();
+    let mut name_counts = HashMap::<String, usize>::new();
+    let mut current = GroupBuilder::new(DEFAULT_GROUP_NAME.to_string(), None);
+    let mut finish_group = |group: GroupBuilder, scene: &mut ObjScene| {
+        if group.indices.is_empty() {
+            if group.name != DEFAULT_GROUP_NAME {
+                warn!(
+                    "{}: group {} has no faces, skipping it",
+                    path.display(),
+                    group.name
+                );
+            }
+            return;
+        }
+        let count = name_counts.entry(group.name.clone()).or_insert(0);
+        let name = if *count == 0 {
+            group.name
+        } else {
+            format!("{}.{}", group.name, count)
+        };
+        *count += 1;
+        scene.groups.push(ObjGroup {
+            name,
+            material: group.material,
+            mesh: Mesh::new(group.vertices, group.indices),
+        });
+    };
+
+    for (line_number, line) in reader.lines().enumerate() {
+        let line = line?;
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        if parts.is_empty() || parts[0].starts_with('#') {
+            continue;
+        }
+        let args = &parts[1..];
+        let malformed = || {
+            warn!(
+                "{}:{}: skipping malformed line: {}",
+                path.display(),
+                line_number + 1,
+                line
+            )
+        };
+        match parts[0] {
+            "v" => match parse_floats::<3>(args) {
+                Some(position) => positions.push(position),
+                // Still counts for the indices of later faces
+                None => {
+                    malformed();
+                    positions.push([0.0; 3]);
+                }
+            },
+            "vt" => match parse_floats::<2>(args) {
+                Some(uv) => uvs.push(uv),
+                None => {
+                    malformed();
+                    uvs.push([0.0; 2]);
+                }
+            },
+            "vn" => match parse_floats::<3>(args) {
+                Some(normal) => normals.push(normal),
+                None => {
+                    malformed();
+                    normals.push([0.0; 3]);
+                }
+            },
+            "o" | "g" => {
+                let name = if args.is_empty() {
+                    DEFAULT_GROUP_NAME.to_string()
+                } else {
+                    args.join(" ")
+                };
+                let material = current.material.clone();
+                finish_group(
+                    std::mem::replace(&mut current, GroupBuilder::new(name, material)),
+                    &mut scene,
+                );
+            }
+            "mtllib" => {
+                let directory = path.parent().unwrap_or_else(|| Path::new(""));
+                for file in args {
+                    match load_mtl(directory.join(file)) {
+                        Ok(materials) => scene.materials.extend(materials),
+                        Err(e) => warn!(
+                            "{}: could not read the materials of {}: {}",
+                            path.display(),
+                            file,
+                            e
+                        ),
+                    }
+                }
+            }
+            "usemtl" => {
+                let material = args.first().map(|m| m.to_string());
+                if current.indices.is_empty() {
+                    current.material = material;
+                } else {
+                    let name = current.name.clone();
+                    finish_group(
+                        std::mem::replace(&mut current, GroupBuilder::new(name, material)),
+                        &mut scene,
+                    );
+                }
+            }
+            "f" => {
+                let corners = args
+                    .iter()
+                    .map(|corner| parse_face_vertex(corner, &positions, &uvs, &normals))
+                    .collect::<Option<Vec<_>>>();
+                let corners = match corners {
+                    Some(corners) if corners.len() >= 3 => corners,
+                    _ => {
+                        malformed();
+                        continue;
+                    }
+                };
+                let indices = corners
+                    .iter()
+                    .map(|vertex| {
+                        insert_or_get_index_of_vertex(
+                            &mut current.vertex_to_index_map,
+                            &mut current.vertices,
+                            vertex,
+                        )
+                    })
+                    .collect::<Vec<_>>();
+                for pair in indices[1..].windows(2) {
+                    current.indices.extend([indices[0], pair[0], pair[1]]);
+                }
+            }
+            _ => {}
+        }
+    }
+    finish_group(current, &mut scene);
+    Ok(scene)
+}
+
+// One submesh per material, in the order the materials are first used. Groups using the
+// same material are merged, with their vertices deduplicated within the submesh. Faces
+// without a material, e.g. of files without an MTL file, get ObjMaterial::default(), so
+// do materials the MTL files don't have, keeping their usemtl name.
+pub fn load_obj_with_materials<P: AsRef<Path>>(path: P) -> RendererResult<LoadedObj> {
+    let path = path.as_ref();
+    let scene = load_obj_scene(path)?;
+    let mut builders: Vec<GroupBuilder> = vec![];
+    for group in scene.groups {
+        let builder = match builders
+            .iter()
+            .position(|builder| builder.material == group.material)
+        {
+            Some(position) => &mut builders[position],
+            None => {
+                builders.push(GroupBuilder::new(group.name, group.material));
+                builders.last_mut().expect("A builder was just pushed")
+            }
+        };
+        let vertices = group.mesh.vertices();
+        for index in group.mesh.indices() {
+            let index = insert_or_get_index_of_vertex(
+                &mut builder.vertex_to_index_map,
+                &mut builder.vertices,
+                &vertices[*index as usize],
+            );
+            builder.indices.push(index);
+        }
+    }
+    let submeshes = builders
+        .into_iter()
+        .map(|builder| {
+            let material = match builder.material {
+                Some(name) => scene.materials.get(&name).cloned().unwrap_or_else(|| {
+                    warn!(
+                        "{}: no material {} in the MTL files, using the default",
+                        path.display(),
+                        name
+                    );
+                    ObjMaterial {
+                        name,
+                        ..Default::default()
+                    }
+                }),
+                None => ObjMaterial::default(),
+            };
+            (Mesh::new(builder.vertices, builder.indices), material)
+        })
+        .collect();
+    Ok(LoadedObj { submeshes })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Three quads over six positions, the first and the last share the paint material
+    // and two corners
+    const CAR: &str = "mtllib car.mtl
+v 0 0 0
+v 1 0 0
+v 1 1 0
+v 0 1 0
+v 2 0 0
+v 2 1 0
+g body
+usemtl paint
+f 1 2 3 4
+g wheel
+usemtl rubber
+f 2 5 6 3
+g trim
+usemtl paint
+f 2 5 6 3
+";
+
+    const MTL: &str = "# car
+newmtl paint
+Kd 0.8 0.1 0.1
+Ns 96
+map_Kd paint.png
+
+newmtl rubber
+Kd 0.05 0.05 0.05
+";
+
+    fn write_fixture(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("obj_{}_{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for (file, contents) in files {
+            std::fs::write(dir.join(file), contents).unwrap();
+        }
+        dir
+    }
+
+    fn positions(mesh: &Mesh) -> Vec<[f32; 3]> {
+        mesh.vertices()
+            .iter()
+            .map(|vertex| {
+                // Vertex is packed
+                let pos = vertex.pos;
+                [pos.x, pos.y, pos.z]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn groups_keep_their_materials() {
+        let dir = write_fixture("groups", &[("car.obj", CAR), ("car.mtl", MTL)]);
+        let scene = load_obj_scene(dir.join("car.obj"));
+        let loaded = load_obj_with_materials(dir.join("car.obj"));
+        std::fs::remove_dir_all(&dir).unwrap();
+        let scene = scene.unwrap();
+        let loaded = loaded.unwrap();
+
+        let groups = scene
+            .groups
+            .iter()
+            .map(|group| (group.name.as_str(), group.material.as_deref()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            groups,
+            vec![
+                ("body", Some("paint")),
+                ("wheel", Some("rubber")),
+                ("trim", Some("paint"))
+            ]
+        );
+        assert_eq!(
+            scene.materials["paint"],
+            ObjMaterial {
+                name: "paint".to_string(),
+                diffuse_color: [0.8, 0.1, 0.1],
+                diffuse_texture: Some(dir.join("paint.png")),
+                specular_exponent: 96.0,
+            }
+        );
+
+        let materials = loaded
+            .submeshes
+            .iter()
+            .map(|(_, material)| material.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            materials,
+            vec![
+                scene.materials["paint"].clone(),
+                scene.materials["rubber"].clone()
+            ]
+        );
+    }
+
+    #[test]
+    fn vertices_are_deduplicated_per_submesh() {
+        let dir = write_fixture("dedup", &[("car.obj", CAR), ("car.mtl", MTL)]);
+        let loaded = load_obj_with_materials(dir.join("car.obj"));
+        std::fs::remove_dir_all(&dir).unwrap();
+        let loaded = loaded.unwrap();
+
+        // The trim adds only the two corners the body doesn't have
+        let (paint, _) = &loaded.submeshes[0];
+        assert_eq!(paint.indices().len(), 12);
+        assert_eq!(
+            positions(paint),
+            vec![
+                [0.0, 0.0, 0.0],
+                [1.0, 0.0, 0.0],
+                [1.0, 1.0, 0.0],
+                [0.0, 1.0, 0.0],
+                [2.0, 0.0, 0.0],
+                [2.0, 1.0, 0.0]
+            ]
+        );
+        // and the wheel has its own copies of the shared ones
+        let (rubber, _) = &loaded.submeshes[1];
+        assert_eq!(rubber.indices(), &[0, 1, 2, 0, 2, 3]);
+        assert_eq!(
+            positions(rubber),
+            vec![
+                [1.0, 0.0, 0.0],
+                [2.0, 0.0, 0.0],
+                [2.0, 1.0, 0.0],
+                [1.0, 1.0, 0.0]
+            ]
+        );
+    }
+
+    #[test]
+    fn files_without_materials_load_as_one_default_submesh() {
+        let obj = CAR
+            .lines()
+            .filter(|line| !line.starts_with("mtllib") && !line.starts_with("usemtl"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let dir = write_fixture("no_mtl", &[("plain.obj", &obj)]);
+        let loaded = load_obj_with_materials(dir.join("plain.obj"));
+        std::fs::remove_dir_all(&dir).unwrap();
+        let loaded = loaded.unwrap();
+
+        assert_eq!(loaded.submeshes.len(), 1);
+        let (mesh, material) = &loaded.submeshes[0];
+        assert_eq!(material, &ObjMaterial::default());
+        assert_eq!(mesh.vertices().len(), 6);
+        assert_eq!(mesh.indices().len(), 18);
+    }
+}
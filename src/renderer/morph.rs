@@ -0,0 +1,208 @@
+use ash::vk;
+use nalgebra_glm as glm;
+
+use super::buffer::Buffer;
+use super::mesh::{Mesh, MeshManager};
+
+// Targets with a weight that are applied to one draw, the ones with the largest weights
+// win when an object sets more
+pub const MAX_ACTIVE_MORPH_TARGETS: usize = 4;
+
+// Where the morph constants start in the push constant block of default.vert, after the
+// UV transform
+pub(crate) const MORPH_PUSH_CONSTANT_OFFSET: u32 = 32;
+
+// Where velocity.vert takes the targets of the previous frame, another MorphPushConstants
+// after the current one, rounded up to the 16 byte alignment of its first member
+pub(crate) const PREVIOUS_MORPH_PUSH_CONSTANT_OFFSET: u32 = 80;
+
+// A blend shape of a mesh: per vertex offsets of the positions and normals, which are
+// added to the mesh scaled by the target's weight
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MorphTarget {
+    pub name: String,
+    pub position_deltas: Vec<glm::Vec3>,
+    // Empty if the target only moves the vertices
+    pub normal_deltas: Vec<glm::Vec3>,
+}
+
+// The targets a draw applies, as (target index, weight), at most MAX_ACTIVE_MORPH_TARGETS
+// of the ones with a non-zero weight. Larger absolute weights first, equal ones by
+// index, so the choice doesn't flicker between frames.
+pub fn select_active_targets(weights: &[f32]) -> Vec<(u32, f32)> {
+    let mut active = weights
+        .iter()
+        .enumerate()
+        .filter(|(_, weight)| **weight != 0.0 && weight.is_finite())
+        .map(|(index, weight)| (index as u32, *weight))
+        .collect::<Vec<_>>();
+    active.sort_by(|a, b| b.1.abs().total_cmp(&a.1.abs()).then_with(|| a.0.cmp(&b.0)));
+    active.truncate(MAX_ACTIVE_MORPH_TARGETS);
+    active
+}
+
+// Matches the morph part of the push constant block in default.vert
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct MorphPushConstants {
+    indices: [u32; MAX_ACTIVE_MORPH_TARGETS],
+    weights: [f32; MAX_ACTIVE_MORPH_TARGETS],
+    // Where the deltas of the mesh start in the morph delta buffer, in vec4s
+    delta_base: u32,
+    vertex_count: u32,
+    count: u32,
+}
+
+impl MorphPushConstants {
+    // No targets, what draws of meshes without any push
+    pub fn none() -> Self {
+        Self::default()
+    }
+
+    pub fn new(delta_base: u32, vertex_count: u32, active: &[(u32, f32)]) -> Self {
+        let mut constants = Self {
+            delta_base,
+            vertex_count,
+            count: active.len().min(MAX_ACTIVE_MORPH_TARGETS) as u32,
+            ..Self::none()
+        };
+        for (i, (index, weight)) in active.iter().take(MAX_ACTIVE_MORPH_TARGETS).enumerate() {
+            constants.indices[i] = *index;
+            constants.weights[i] = *weight;
+        }
+        constants
+    }
+
+    // A draw of mesh by an object with these weights, names the mesh doesn't have are
+    // ignored
+    pub fn for_draw(mesh: &Mesh, weights: &[(String, f32)]) -> Self {
+        if mesh.morph_targets().is_empty() {
+            return Self::none();
+        }
+        let mut target_weights = vec![0.0; mesh.morph_targets().len()];
+        for (name, weight) in weights.iter() {
+            if let Some(index) = mesh.morph_target_index(name) {
+                target_weights[index] = *weight;
+            }
+        }
+        Self::new(
+            mesh.morph_base(),
+            mesh.vertices().len() as u32,
+            &select_active_targets(&target_weights),
+        )
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe {
+            std::slice::from_raw_parts(
+                self as *const Self as *const u8,
+                std::mem::size_of::<Self>(),
+            )
+        }
+    }
+}
+
+// The deltas of every mesh with morph targets, packed for the morph delta buffer. Every
+// vertex of every target takes two vec4s, the position delta and the normal delta, and
+// the targets of a mesh follow each other. Sets the base of every mesh.
+pub(crate) fn pack_deltas(meshs: &mut MeshManager) -> Vec<[f32; 4]> {
+    let mut data = vec![];
+    for mesh in meshs.iter_mut() {
+        if mesh.morph_targets().is_empty() {
+            mesh.set_morph_base(0);
+            continue;
+        }
+        mesh.set_morph_base(data.len() as u32);
+        let vertex_count = mesh.vertices().len();
+        for target in mesh.morph_targets() {
+            for i in 0..vertex_count {
+                let position = target.position_deltas[i];
+                let normal = target
+                    .normal_deltas
+                    .get(i)
+                    .copied()
+                    .unwrap_or_else(glm::Vec3::zeros);
+                data.push([position.x, position.y, position.z, 0.0]);
+                data.push([normal.x, normal.y, normal.z, 0.0]);
+            }
+        }
+    }
+    data
+}
+
+// Points binding 2 of a lights set at the delta buffer
+pub(crate) fn update_descriptor(
+    device: &ash::Device,
+    descriptor_set_lights: vk::DescriptorSet,
+    buffer: &Buffer,
+) {
+    let buffer_info = [vk::DescriptorBufferInfo {
+        buffer: buffer.get_buffer().buffer,
+        offset: 0,
+        range: vk::WHOLE_SIZE,
+    }];
+    let desc_sets_write = [vk::WriteDescriptorSet::builder()
+        .dst_set(descriptor_set_lights)
+        .dst_binding(2)
+        .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+        .buffer_info(&buffer_info)
+        .build()];
+    unsafe { device.update_descriptor_sets(&desc_sets_write, &[]) };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn largest_absolute_weights_win() {
+        let weights = [0.1, -0.9, 0.0, 0.5, 0.3, 0.7];
+        assert_eq!(
+            select_active_targets(&weights),
+            vec![(1, -0.9), (5, 0.7), (3, 0.5), (4, 0.3)]
+        );
+    }
+
+    #[test]
+    fn zero_and_non_finite_weights_are_skipped() {
+        let weights = [0.0, f32::NAN, 0.2, f32::INFINITY, -0.0];
+        assert_eq!(select_active_targets(&weights), vec![(2, 0.2)]);
+        assert!(select_active_targets(&[]).is_empty());
+    }
+
+    #[test]
+    fn ties_keep_the_lower_index() {
+        let weights = [0.5, -0.5, 0.5, 0.5, 0.5, 0.5];
+        assert_eq!(
+            select_active_targets(&weights),
+            vec![(0, 0.5), (1, -0.5), (2, 0.5), (3, 0.5)]
+        );
+    }
+
+    #[test]
+    fn push_constants_take_at_most_the_active_limit() {
+        let active = [(7, 1.0), (2, 0.5), (3, 0.25), (4, 0.125), (5, 0.1)];
+        let constants = MorphPushConstants::new(16, 24, &active);
+        assert_eq!(constants.count, MAX_ACTIVE_MORPH_TARGETS as u32);
+        assert_eq!(constants.indices, [7, 2, 3, 4]);
+        assert_eq!(constants.weights, [1.0, 0.5, 0.25, 0.125]);
+        assert_eq!((constants.delta_base, constants.vertex_count), (16, 24));
+        assert_eq!(
+            constants.as_bytes().len(),
+            4 * (2 * MAX_ACTIVE_MORPH_TARGETS + 3)
+        );
+    }
+
+    #[test]
+    fn previous_targets_fit_after_the_current_ones() {
+        let end = MORPH_PUSH_CONSTANT_OFFSET as usize + std::mem::size_of::<MorphPushConstants>();
+        assert!(PREVIOUS_MORPH_PUSH_CONSTANT_OFFSET as usize >= end);
+        assert_eq!(PREVIOUS_MORPH_PUSH_CONSTANT_OFFSET % 16, 0);
+        // The smallest push constant block every device supports
+        assert!(
+            PREVIOUS_MORPH_PUSH_CONSTANT_OFFSET as usize
+                + std::mem::size_of::<MorphPushConstants>()
+                <= 128
+        );
+    }
+}
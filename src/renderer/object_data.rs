@@ -0,0 +1,396 @@
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+
+use ash::vk;
+use gpu_allocator::{vulkan::Allocator, MemoryLocation};
+
+use super::{
+    buffer::{Buffer, BufferManager},
+    RendererResult,
+};
+
+// Above this fraction of dirty slots the whole buffer is uploaded in one copy
+pub const DEFAULT_FULL_UPLOAD_FRACTION: f32 = 0.5;
+
+// Room for the object_params block of every object, see SceneTree::set_object_params
+pub const MAX_OBJECT_PARAMS_SIZE: usize = 64;
+
+// Slots of buffers bound as dynamic uniform buffers are this far apart until the
+// renderer sets the alignment of the device, the largest one Vulkan allows
+pub const OBJECT_PARAMS_STRIDE: usize = 256;
+
+// What the last flush of the per-object data copied
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ObjectUploadStats {
+    pub dirty_slots: usize,
+    pub ranges: usize,
+    pub bytes_copied: u64,
+    pub full_upload: bool,
+}
+
+// Slot ranges to copy, merged so that no two of them touch
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct UploadPlan {
+    pub ranges: Vec<Range<u32>>,
+    pub full_upload: bool,
+}
+
+impl UploadPlan {
+    pub fn slot_count(&self) -> u32 {
+        self.ranges.iter().map(|r| r.end - r.start).sum()
+    }
+
+    // The same copies as regions of a staged upload, for device local buffers
+    pub fn buffer_copies(&self, stride: u64) -> Vec<vk::BufferCopy> {
+        self.ranges
+            .iter()
+            .map(|r| vk::BufferCopy {
+                src_offset: r.start as u64 * stride,
+                dst_offset: r.start as u64 * stride,
+                size: (r.end - r.start) as u64 * stride,
+            })
+            .collect()
+    }
+}
+
+// One range per dirty slot, what uploading without coalescing copies. Only for comparing
+// against coalesce_dirty_slots, see benches/object_upload.rs.
+pub fn per_slot_plan(dirty: &[u32], used_slots: u32) -> UploadPlan {
+    let mut slots = dirty
+        .iter()
+        .copied()
+        .filter(|slot| *slot < used_slots)
+        .collect::<Vec<_>>();
+    slots.sort_unstable();
+    slots.dedup();
+    UploadPlan {
+        ranges: slots.into_iter().map(|slot| slot..slot + 1).collect(),
+        full_upload: false,
+    }
+}
+
+// Sorts the dirty slots and merges runs of adjacent ones. With more than
+// full_upload_fraction of the used slots dirty, a single range over all of them is
+// cheaper than many small copies.
+pub fn coalesce_dirty_slots(
+    dirty: &[u32],
+    used_slots: u32,
+    full_upload_fraction: f32,
+) -> UploadPlan {
+    let mut slots = dirty
+        .iter()
+        .copied()
+        .filter(|slot| *slot < used_slots)
+        .collect::<Vec<_>>();
+    slots.sort_unstable();
+    slots.dedup();
+    if slots.is_empty() {
+        return UploadPlan::default();
+    }
+    if slots.len() as f32 > used_slots as f32 * full_upload_fraction {
+        return UploadPlan {
+            ranges: vec![0..used_slots],
+            full_upload: true,
+        };
+    }
+
+    let mut ranges: Vec<Range<u32>> = vec![];
+    for slot in slots {
+        match ranges.last_mut() {
+            Some(range) if range.end == slot => range.end += 1,
+            _ => ranges.push(slot..slot + 1),
+        }
+    }
+    UploadPlan {
+        ranges,
+        full_upload: false,
+    }
+}
+
+// Per-object data of the whole scene in one buffer, one slot per object, like the
+// instance data in a vertex buffer or the object parameters in a uniform buffer. Writes
+// go to a CPU copy and are uploaded once per frame by flush, in as few copies as the
+// dirty slots allow. The buffer is host visible, or device local with set_device_local,
+// where the copies go through one staging buffer on the transfer queue.
+pub(crate) struct ObjectDataBuffer {
+    stride: usize,
+    usage: vk::BufferUsageFlags,
+    name: &'static str,
+    device_local: bool,
+    data: Vec<u8>,
+    buffer: Option<Buffer>,
+    capacity: u32,
+    used_slots: u32,
+    free_slots: Vec<u32>,
+    dirty: Vec<u32>,
+    dirty_flags: Vec<bool>,
+    // The buffer was recreated and has none of the data yet
+    needs_full_upload: bool,
+    pub full_upload_fraction: f32,
+}
+
+impl std::fmt::Debug for ObjectDataBuffer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ObjectDataBuffer")
+            .field("stride", &self.stride)
+            .field("capacity", &self.capacity)
+            .field("used_slots", &self.used_slots)
+            .field("free_slots", &self.free_slots.len())
+            .field("dirty", &self.dirty.len())
+            .finish()
+    }
+}
+
+impl ObjectDataBuffer {
+    pub fn new(stride: usize, usage: vk::BufferUsageFlags, name: &'static str) -> Self {
+        Self {
+            stride,
+            usage,
+            name,
+            device_local: false,
+            data: vec![],
+            buffer: None,
+            capacity: 0,
+            used_slots: 0,
+            free_slots: vec![],
+            dirty: vec![],
+            dirty_flags: vec![],
+            needs_full_upload: false,
+            full_upload_fraction: DEFAULT_FULL_UPLOAD_FRACTION,
+        }
+    }
+
+    // Only before the first slot is allocated
+    pub fn set_stride(&mut self, stride: usize) {
+        debug_assert_eq!(self.capacity, 0);
+        self.stride = stride;
+    }
+
+    // Only before the first slot is allocated. Falls back to a host visible buffer
+    // without a transfer queue, see BufferManager::new_device_local_buffer.
+    pub fn set_device_local(&mut self, device_local: bool) {
+        debug_assert_eq!(self.capacity, 0);
+        self.device_local = device_local;
+    }
+
+    pub fn buffer(&self) -> Option<&Buffer> {
+        self.buffer.as_ref()
+    }
+
+    pub fn offset(&self, slot: u32) -> vk::DeviceSize {
+        slot as u64 * self.stride as u64
+    }
+
+    // Freed slots are reused before the buffer grows
+    pub fn allocate_slot(
+        &mut self,
+        device: &ash::Device,
+        allocator: &mut Allocator,
+        buffer_manager: Arc<Mutex<BufferManager>>,
+    ) -> RendererResult<u32> {
+        if let Some(slot) = self.free_slots.pop() {
+            return Ok(slot);
+        }
+        if self.used_slots == self.capacity {
+            self.grow(device, allocator, buffer_manager)?;
+        }
+        let slot = self.used_slots;
+        self.used_slots += 1;
+        Ok(slot)
+    }
+
+    // For buffers that share the slots of another one, which handed out slot
+    pub fn reserve_slot(
+        &mut self,
+        slot: u32,
+        device: &ash::Device,
+        allocator: &mut Allocator,
+        buffer_manager: Arc<Mutex<BufferManager>>,
+    ) -> RendererResult<()> {
+        while slot >= self.capacity {
+            self.grow(device, allocator, buffer_manager.clone())?;
+        }
+        self.used_slots = self.used_slots.max(slot + 1);
+        Ok(())
+    }
+
+    pub fn free_slot(&mut self, slot: u32) {
+        debug_assert!(slot < self.used_slots && !self.free_slots.contains(&slot));
+        self.free_slots.push(slot);
+    }
+
+    // The rest of the slot is zeroed if bytes is shorter than the stride
+    pub fn write(&mut self, slot: u32, bytes: &[u8]) {
+        debug_assert!(bytes.len() <= self.stride);
+        let start = slot as usize * self.stride;
+        self.data[start..start + bytes.len()].copy_from_slice(bytes);
+        self.data[start + bytes.len()..start + self.stride].fill(0);
+        if !self.dirty_flags[slot as usize] {
+            self.dirty_flags[slot as usize] = true;
+            self.dirty.push(slot);
+        }
+    }
+
+    // Doubles the capacity. The old buffer may still be read by frames in flight, so it
+    // is queued to be freed and the new one gets everything on the next flush. A device
+    // local buffer is created with everything instead.
+    fn grow(
+        &mut self,
+        device: &ash::Device,
+        allocator: &mut Allocator,
+        buffer_manager: Arc<Mutex<BufferManager>>,
+    ) -> RendererResult<()> {
+        let capacity = (self.capacity * 2).max(64);
+        self.data.resize(capacity as usize * self.stride, 0);
+        let buffer = if self.device_local {
+            BufferManager::new_device_local_buffer(
+                buffer_manager,
+                device,
+                allocator,
+                &self.data,
+                self.usage,
+                self.name,
+            )?
+        } else {
+            BufferManager::new_buffer(
+                buffer_manager,
+                device,
+                allocator,
+                (capacity as usize * self.stride) as u64,
+                self.usage,
+                MemoryLocation::CpuToGpu,
+                self.name,
+            )?
+        };
+        if let Some(mut old) = self.buffer.replace(buffer) {
+            old.queue_free()?;
+        }
+        self.capacity = capacity;
+        self.dirty_flags.resize(capacity as usize, false);
+        self.needs_full_upload = !self.device_local;
+        Ok(())
+    }
+
+    // Uploads the slots written since the last flush
+    pub fn flush(&mut self, allocator: &mut Allocator) -> RendererResult<ObjectUploadStats> {
+        let plan = if self.needs_full_upload && self.used_slots > 0 {
+            UploadPlan {
+                ranges: vec![0..self.used_slots],
+                full_upload: true,
+            }
+        } else {
+            coalesce_dirty_slots(&self.dirty, self.used_slots, self.full_upload_fraction)
+        };
+        let mut stats = ObjectUploadStats {
+            dirty_slots: self.dirty.len(),
+            ranges: plan.ranges.len(),
+            bytes_copied: 0,
+            full_upload: plan.full_upload,
+        };
+        let device_local = self.buffer.as_ref().map_or(false, |buffer| {
+            buffer.get_buffer().location == MemoryLocation::GpuOnly
+        });
+        if let (true, Some(buffer)) = (device_local, self.buffer.as_mut()) {
+            // Every range in one submission, from a staging buffer holding only them
+            let copies = plan.buffer_copies(self.stride as u64);
+            buffer.copy_regions(allocator, &self.data, &copies)?;
+            stats.bytes_copied = copies.iter().map(|copy| copy.size).sum();
+        } else if let Some(buffer) = self.buffer.as_mut() {
+            // The buffer is host visible, so every range is a plain write to mapped memory
+            for range in plan.ranges.iter() {
+                let bytes = &self.data
+                    [range.start as usize * self.stride..range.end as usize * self.stride];
+                buffer.copy_to_offset(allocator, bytes, range.start as usize * self.stride)?;
+                stats.bytes_copied += bytes.len() as u64;
+            }
+        }
+        for slot in self.dirty.drain(..) {
+            self.dirty_flags[slot as usize] = false;
+        }
+        self.needs_full_upload = false;
+        Ok(stats)
+    }
+
+    pub fn destroy(&mut self) -> RendererResult<()> {
+        if let Some(mut buffer) = self.buffer.take() {
+            buffer.queue_free()?;
+        }
+        self.capacity = 0;
+        self.used_slots = 0;
+        self.data.clear();
+        self.free_slots.clear();
+        self.dirty.clear();
+        self.dirty_flags.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn adjacent_slots_merge() {
+        let plan = coalesce_dirty_slots(&[3, 4, 5, 6], 100, DEFAULT_FULL_UPLOAD_FRACTION);
+        assert_eq!(plan.ranges, vec![3..7]);
+        assert!(!plan.full_upload);
+        assert_eq!(plan.slot_count(), 4);
+    }
+
+    #[test]
+    fn gaps_split_ranges() {
+        let plan = coalesce_dirty_slots(&[1, 2, 4, 9, 10], 100, DEFAULT_FULL_UPLOAD_FRACTION);
+        assert_eq!(plan.ranges, vec![1..3, 4..5, 9..11]);
+        assert_eq!(plan.slot_count(), 5);
+    }
+
+    #[test]
+    fn unsorted_and_repeated_slots_are_sorted_first() {
+        let plan = coalesce_dirty_slots(&[9, 2, 10, 1, 2, 4], 100, DEFAULT_FULL_UPLOAD_FRACTION);
+        assert_eq!(plan.ranges, vec![1..3, 4..5, 9..11]);
+    }
+
+    #[test]
+    fn unused_slots_are_dropped() {
+        let plan = coalesce_dirty_slots(&[1, 20], 10, DEFAULT_FULL_UPLOAD_FRACTION);
+        assert_eq!(plan.ranges, vec![1..2]);
+        assert_eq!(
+            coalesce_dirty_slots(&[], 10, DEFAULT_FULL_UPLOAD_FRACTION),
+            UploadPlan::default()
+        );
+    }
+
+    #[test]
+    fn many_dirty_slots_upload_everything() {
+        let plan = coalesce_dirty_slots(&[0, 2, 4, 6, 8, 9], 10, 0.5);
+        assert_eq!(plan.ranges, vec![0..10]);
+        assert!(plan.full_upload);
+        // Exactly the fraction is still uploaded by range
+        assert!(!coalesce_dirty_slots(&[0, 2, 4, 6, 8], 10, 0.5).full_upload);
+    }
+
+    #[test]
+    fn per_slot_plans_copy_every_slot_alone() {
+        let plan = per_slot_plan(&[5, 3, 4, 3, 50], 10);
+        assert_eq!(plan.ranges, vec![3..4, 4..5, 5..6]);
+        assert_eq!(
+            plan.slot_count(),
+            coalesce_dirty_slots(&[5, 3, 4], 10, 1.0).slot_count()
+        );
+    }
+
+    #[test]
+    fn buffer_copies_scale_by_the_stride() {
+        let plan = coalesce_dirty_slots(&[1, 2, 5], 100, DEFAULT_FULL_UPLOAD_FRACTION);
+        let copies = plan.buffer_copies(64);
+        assert_eq!(copies.len(), 2);
+        assert_eq!(
+            (copies[0].src_offset, copies[0].dst_offset, copies[0].size),
+            (64, 64, 128)
+        );
+        assert_eq!(
+            (copies[1].src_offset, copies[1].dst_offset, copies[1].size),
+            (320, 320, 64)
+        );
+    }
+}
@@ -0,0 +1,460 @@
+use ash::vk;
+use memoffset::offset_of;
+use nalgebra_glm as glm;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use super::{
+    buffer::Buffer, color::Color, material::Material, material::VertexInputDescription,
+    scene::SceneObject, texture::Texture, utils::Handle,
+};
+
+// Piecewise linear over the normalized age of a particle, 0 when it spawns and 1 when
+// it dies. Ages outside of the keys get the value of the closest key.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Curve {
+    keys: Vec<(f32, f32)>,
+}
+
+impl Curve {
+    pub fn constant(value: f32) -> Self {
+        Self {
+            keys: vec![(0.0, value)],
+        }
+    }
+
+    pub fn linear(start: f32, end: f32) -> Self {
+        Self {
+            keys: vec![(0.0, start), (1.0, end)],
+        }
+    }
+
+    // (age, value) pairs, in any order
+    pub fn from_keys(mut keys: Vec<(f32, f32)>) -> Self {
+        keys.sort_by(|a, b| a.0.total_cmp(&b.0));
+        if keys.is_empty() {
+            keys.push((0.0, 0.0));
+        }
+        Self { keys }
+    }
+
+    pub fn evaluate(&self, age: f32) -> f32 {
+        let first = self.keys[0];
+        if age <= first.0 {
+            return first.1;
+        }
+        for window in self.keys.windows(2) {
+            let (a, b) = (window[0], window[1]);
+            if age <= b.0 {
+                let span = b.0 - a.0;
+                if span <= f32::EPSILON {
+                    return b.1;
+                }
+                return a.1 + (b.1 - a.1) * (age - a.0) / span;
+            }
+        }
+        self.keys[self.keys.len() - 1].1
+    }
+}
+
+// Every component of the initial velocity is picked uniformly between min and max
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct VelocityRange {
+    pub min: glm::Vec3,
+    pub max: glm::Vec3,
+}
+
+impl VelocityRange {
+    pub fn constant(velocity: glm::Vec3) -> Self {
+        Self {
+            min: velocity,
+            max: velocity,
+        }
+    }
+
+    fn sample(&self, rng: &mut StdRng) -> glm::Vec3 {
+        let mut velocity = self.min;
+        for i in 0..3 {
+            if self.max[i] > self.min[i] {
+                velocity[i] = rng.gen_range(self.min[i]..self.max[i]);
+            }
+        }
+        velocity
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct EmitterConfig {
+    pub texture: Handle<Texture>,
+    // Particles per second, bursts come on top of these
+    pub spawn_rate: f32,
+    // In seconds
+    pub lifetime: f32,
+    pub velocity: VelocityRange,
+    // World space size of the billboards
+    pub size_over_lifetime: Curve,
+    // Multiplied with the alpha of color
+    pub alpha_over_lifetime: Curve,
+    pub color: Color,
+    // World space acceleration, remember that +Y is down
+    pub gravity: glm::Vec3,
+    // No more particles than this are alive at once
+    pub capacity: usize,
+    // Emitters with the same seed and inputs simulate the same particles
+    pub seed: u64,
+    // Particles fade out over this many meters in front of the opaque scene, instead of
+    // being cut off where they intersect it. 0 turns the fade off.
+    pub softness: f32,
+    // The emitter follows the object, offset is relative to its origin in world space
+    pub attached_to: Option<Handle<SceneObject>>,
+    pub offset: glm::Vec3,
+}
+
+impl EmitterConfig {
+    pub fn new(texture: Handle<Texture>) -> Self {
+        Self {
+            texture,
+            spawn_rate: 10.0,
+            lifetime: 2.0,
+            velocity: VelocityRange::constant(glm::Vec3::zeros()),
+            size_over_lifetime: Curve::constant(1.0),
+            alpha_over_lifetime: Curve::linear(1.0, 0.0),
+            color: Color::linear(1.0, 1.0, 1.0),
+            gravity: glm::Vec3::zeros(),
+            capacity: 256,
+            seed: 0,
+            softness: 0.5,
+            attached_to: None,
+            offset: glm::Vec3::zeros(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Particle {
+    pub position: glm::Vec3,
+    pub velocity: glm::Vec3,
+    // In seconds
+    pub age: f32,
+}
+
+// Per instance data of the particle pipeline, see particle.vert
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct ParticleInstance {
+    pub position: [f32; 3],
+    pub size: f32,
+    pub color: [f32; 4],
+}
+
+impl ParticleInstance {
+    pub fn get_vertex_attributes() -> [vk::VertexInputAttributeDescription; 3] {
+        [
+            vk::VertexInputAttributeDescription {
+                binding: 0,
+                location: 0,
+                offset: offset_of!(ParticleInstance, position) as u32,
+                format: vk::Format::R32G32B32_SFLOAT,
+            },
+            vk::VertexInputAttributeDescription {
+                binding: 0,
+                location: 1,
+                offset: offset_of!(ParticleInstance, size) as u32,
+                format: vk::Format::R32_SFLOAT,
+            },
+            vk::VertexInputAttributeDescription {
+                binding: 0,
+                location: 2,
+                offset: offset_of!(ParticleInstance, color) as u32,
+                format: vk::Format::R32G32B32A32_SFLOAT,
+            },
+        ]
+    }
+
+    pub fn get_vertex_bindings() -> [vk::VertexInputBindingDescription; 1] {
+        [vk::VertexInputBindingDescription {
+            binding: 0,
+            stride: std::mem::size_of::<ParticleInstance>() as u32,
+            input_rate: vk::VertexInputRate::INSTANCE,
+        }]
+    }
+
+    pub fn get_vertex_description() -> VertexInputDescription {
+        VertexInputDescription {
+            bindings: Self::get_vertex_bindings().to_vec(),
+            attributes: Self::get_vertex_attributes().to_vec(),
+            flags: vk::PipelineVertexInputStateCreateFlags::empty(),
+        }
+    }
+}
+
+// The CPU side of an emitter, without anything on the GPU
+struct Simulation {
+    config: EmitterConfig,
+    particles: Vec<Particle>,
+    rng: StdRng,
+    // Fractions of particles carried over to the next frame
+    spawn_accumulator: f32,
+    pending_burst: usize,
+    origin: glm::Vec3,
+}
+
+impl Simulation {
+    fn new(config: EmitterConfig) -> Self {
+        Self {
+            rng: StdRng::seed_from_u64(config.seed),
+            particles: Vec::with_capacity(config.capacity),
+            spawn_accumulator: 0.0,
+            pending_burst: 0,
+            origin: config.offset,
+            config,
+        }
+    }
+
+    fn step(&mut self, dt: f32, origin: glm::Vec3) {
+        self.origin = origin;
+        let lifetime = self.config.lifetime;
+        for particle in self.particles.iter_mut() {
+            particle.age += dt;
+        }
+        self.particles.retain(|particle| particle.age < lifetime);
+        for particle in self.particles.iter_mut() {
+            particle.velocity += self.config.gravity * dt;
+            particle.position += particle.velocity * dt;
+        }
+
+        self.spawn_accumulator += self.config.spawn_rate * dt;
+        let spawned = self.spawn_accumulator.floor();
+        self.spawn_accumulator -= spawned;
+        let count = spawned as usize + std::mem::take(&mut self.pending_burst);
+        let free = self.config.capacity.saturating_sub(self.particles.len());
+        for _ in 0..count.min(free) {
+            let velocity = self.config.velocity.sample(&mut self.rng);
+            self.particles.push(Particle {
+                position: origin,
+                velocity,
+                age: 0.0,
+            });
+        }
+    }
+
+    fn normalized_age(&self, particle: &Particle) -> f32 {
+        if self.config.lifetime <= 0.0 {
+            return 1.0;
+        }
+        (particle.age / self.config.lifetime).clamp(0.0, 1.0)
+    }
+
+    fn instances(&self, camera_position: &glm::Vec3) -> Vec<ParticleInstance> {
+        let mut instances = self
+            .particles
+            .iter()
+            .map(|particle| {
+                let age = self.normalized_age(particle);
+                let color = self.config.color;
+                let distance = glm::distance2(&particle.position, camera_position);
+                let instance = ParticleInstance {
+                    position: particle.position.into(),
+                    size: self.config.size_over_lifetime.evaluate(age),
+                    color: [
+                        color.r,
+                        color.g,
+                        color.b,
+                        color.a * self.config.alpha_over_lifetime.evaluate(age),
+                    ],
+                };
+                (distance, instance)
+            })
+            .collect::<Vec<_>>();
+        instances.sort_by(|a, b| b.0.total_cmp(&a.0));
+        instances
+            .into_iter()
+            .map(|(_, instance)| instance)
+            .collect()
+    }
+}
+
+// Simulated on the CPU, drawn as camera facing billboards in the Transparency pass,
+// sorted with the transparent objects
+pub struct ParticleEmitter {
+    simulation: Simulation,
+    pub(crate) material: Handle<Material>,
+    // capacity instances per swapchain image
+    pub(crate) instance_buffer: Buffer,
+    pub(crate) instance_counts: Vec<u32>,
+}
+
+impl ParticleEmitter {
+    pub(crate) fn new(
+        config: EmitterConfig,
+        material: Handle<Material>,
+        instance_buffer: Buffer,
+        image_count: usize,
+    ) -> Self {
+        Self {
+            simulation: Simulation::new(config),
+            material,
+            instance_buffer,
+            instance_counts: vec![0; image_count],
+        }
+    }
+
+    pub fn config(&self) -> &EmitterConfig {
+        &self.simulation.config
+    }
+
+    pub fn particles(&self) -> &[Particle] {
+        &self.simulation.particles
+    }
+
+    pub fn origin(&self) -> glm::Vec3 {
+        self.simulation.origin
+    }
+
+    pub fn set_spawn_rate(&mut self, spawn_rate: f32) {
+        self.simulation.config.spawn_rate = spawn_rate.max(0.0);
+    }
+
+    // Spawned all at once on the next simulation step, as far as the capacity allows
+    pub fn burst(&mut self, count: usize) {
+        self.simulation.pending_burst += count;
+    }
+
+    pub fn simulate(&mut self, dt: f32, origin: glm::Vec3) {
+        self.simulation.step(dt, origin);
+    }
+
+    pub fn normalized_age(&self, particle: &Particle) -> f32 {
+        self.simulation.normalized_age(particle)
+    }
+
+    // Sorted back to front, so blending works between the particles of the emitter
+    pub fn instances(&self, camera_position: &glm::Vec3) -> Vec<ParticleInstance> {
+        self.simulation.instances(camera_position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> EmitterConfig {
+        EmitterConfig {
+            spawn_rate: 4.0,
+            lifetime: 1.0,
+            velocity: VelocityRange {
+                min: glm::vec3(-1.0, -2.0, -1.0),
+                max: glm::vec3(1.0, -1.0, 1.0),
+            },
+            size_over_lifetime: Curve::linear(0.5, 2.5),
+            alpha_over_lifetime: Curve::from_keys(vec![(1.0, 0.0), (0.0, 0.0), (0.25, 1.0)]),
+            color: Color::linear(1.0, 0.5, 0.25),
+            seed: 7,
+            ..EmitterConfig::new(Handle::for_test(1))
+        }
+    }
+
+    #[test]
+    fn curves_interpolate_between_keys_and_clamp_outside() {
+        let linear = Curve::linear(0.5, 2.5);
+        assert_eq!(linear.evaluate(-1.0), 0.5);
+        assert_eq!(linear.evaluate(0.5), 1.5);
+        assert_eq!(linear.evaluate(2.0), 2.5);
+        let keys = Curve::from_keys(vec![(1.0, 0.0), (0.0, 0.0), (0.25, 1.0)]);
+        assert_eq!(keys.evaluate(0.125), 0.5);
+        assert_eq!(keys.evaluate(0.25), 1.0);
+        assert_eq!(keys.evaluate(0.625), 0.5);
+        assert_eq!(Curve::constant(3.0).evaluate(0.7), 3.0);
+        assert_eq!(Curve::from_keys(vec![]).evaluate(0.5), 0.0);
+    }
+
+    #[test]
+    fn particles_die_at_the_end_of_their_lifetime() {
+        let mut simulation = Simulation::new(EmitterConfig {
+            spawn_rate: 0.0,
+            ..config()
+        });
+        simulation.pending_burst = 3;
+        simulation.step(0.1, glm::Vec3::zeros());
+        assert_eq!(simulation.particles.len(), 3);
+        assert!(simulation.particles.iter().all(|p| p.age == 0.0));
+        simulation.step(0.9, glm::Vec3::zeros());
+        assert_eq!(simulation.particles.len(), 3);
+        simulation.step(0.2, glm::Vec3::zeros());
+        assert!(simulation.particles.is_empty());
+    }
+
+    #[test]
+    fn spawn_rate_carries_fractions_and_respects_the_capacity() {
+        let mut simulation = Simulation::new(EmitterConfig {
+            capacity: 5,
+            ..config()
+        });
+        // 4 particles per second, one every quarter second
+        for _ in 0..3 {
+            simulation.step(0.1, glm::Vec3::zeros());
+        }
+        assert_eq!(simulation.particles.len(), 1);
+        simulation.pending_burst = 10;
+        simulation.step(0.1, glm::Vec3::zeros());
+        assert_eq!(simulation.particles.len(), 5);
+    }
+
+    #[test]
+    fn size_and_alpha_follow_the_age() {
+        let mut simulation = Simulation::new(EmitterConfig {
+            spawn_rate: 0.0,
+            velocity: VelocityRange::constant(glm::Vec3::zeros()),
+            ..config()
+        });
+        simulation.pending_burst = 1;
+        simulation.step(0.0, glm::vec3(1.0, 2.0, 3.0));
+        simulation.step(0.125, glm::vec3(1.0, 2.0, 3.0));
+        let instance = simulation.instances(&glm::Vec3::zeros())[0];
+        assert_eq!(instance.position, [1.0, 2.0, 3.0]);
+        assert_eq!(instance.size, 0.75);
+        assert_eq!(instance.color, [1.0, 0.5, 0.25, 0.5]);
+        simulation.step(0.5, glm::vec3(1.0, 2.0, 3.0));
+        let instance = simulation.instances(&glm::Vec3::zeros())[0];
+        assert_eq!(instance.size, 1.75);
+        assert_eq!(instance.color[3], 0.5);
+    }
+
+    #[test]
+    fn instances_are_sorted_back_to_front() {
+        let mut simulation = Simulation::new(EmitterConfig {
+            spawn_rate: 0.0,
+            velocity: VelocityRange::constant(glm::Vec3::zeros()),
+            ..config()
+        });
+        for x in [2.0, 8.0, 4.0] {
+            simulation.pending_burst = 1;
+            simulation.step(0.0, glm::vec3(x, 0.0, 0.0));
+        }
+        let xs = simulation
+            .instances(&glm::Vec3::zeros())
+            .iter()
+            .map(|instance| instance.position[0])
+            .collect::<Vec<_>>();
+        assert_eq!(xs, vec![8.0, 4.0, 2.0]);
+    }
+
+    #[test]
+    fn the_same_seed_simulates_the_same_particles() {
+        let run = |seed| {
+            let mut simulation = Simulation::new(EmitterConfig { seed, ..config() });
+            simulation.pending_burst = 8;
+            for frame in 0..60 {
+                simulation.step(1.0 / 60.0, glm::vec3(frame as f32 * 0.1, 0.0, 0.0));
+            }
+            simulation.particles
+        };
+        let first = run(7);
+        assert!(!first.is_empty());
+        assert_eq!(first, run(7));
+        assert_ne!(first, run(8));
+        // Without gravity the particles keep the velocities sampled from the range
+        assert!(first
+            .iter()
+            .all(|p| p.velocity.y >= -2.0 && p.velocity.y < -1.0));
+    }
+}
@@ -0,0 +1,86 @@
+use std::path::PathBuf;
+
+use ash::vk;
+use log::{info, warn};
+
+use super::context::VulkanContext;
+use super::RendererResult;
+
+// The size of the header every pipeline cache starts with, version one
+const HEADER_SIZE: usize = 32;
+
+// Every graphics and compute pipeline is built with this, so pipelines built in an earlier
+// run come from the cache instead of being compiled again. The data is loaded from path
+// at startup and written back by save.
+pub(crate) struct PipelineCache {
+    cache: vk::PipelineCache,
+    // None keeps the cache in memory only
+    path: Option<PathBuf>,
+}
+
+impl PipelineCache {
+    pub fn new(context: &VulkanContext, path: Option<PathBuf>) -> RendererResult<Self> {
+        let data = path
+            .as_ref()
+            .and_then(|path| std::fs::read(path).ok())
+            .filter(|data| {
+                let compatible = is_compatible(data, &context.physical_device_properties);
+                if !compatible {
+                    info!("The pipeline cache is from another device or driver, starting empty");
+                }
+                compatible
+            })
+            .unwrap_or_default();
+        let create_info = vk::PipelineCacheCreateInfo::builder().initial_data(&data);
+        let cache = unsafe { context.device.create_pipeline_cache(&create_info, None)? };
+        context.debug_namer.name(cache, "pipeline cache");
+        Ok(Self { cache, path })
+    }
+
+    pub fn handle(&self) -> vk::PipelineCache {
+        self.cache
+    }
+
+    // Writes the cache to its path, if it has one
+    pub fn save(&self, device: &ash::Device) -> RendererResult<()> {
+        if let Some(path) = &self.path {
+            let data = unsafe { device.get_pipeline_cache_data(self.cache)? };
+            if let Err(e) = std::fs::write(path, data) {
+                warn!(
+                    "Could not write the pipeline cache to {}: {}",
+                    path.display(),
+                    e
+                );
+            }
+        }
+        Ok(())
+    }
+
+    pub fn destroy(&mut self, device: &ash::Device) {
+        unsafe {
+            device.destroy_pipeline_cache(self.cache, None);
+        }
+        self.cache = vk::PipelineCache::null();
+    }
+}
+
+// Drivers are supposed to ignore data they can't use, but not all of them do, so the
+// header is checked against the device first
+fn is_compatible(data: &[u8], properties: &vk::PhysicalDeviceProperties) -> bool {
+    if data.len() < HEADER_SIZE {
+        return false;
+    }
+    let read_u32 = |offset: usize| {
+        u32::from_ne_bytes([
+            data[offset],
+            data[offset + 1],
+            data[offset + 2],
+            data[offset + 3],
+        ])
+    };
+    read_u32(0) as usize >= HEADER_SIZE
+        && read_u32(4) == vk::PipelineCacheHeaderVersion::ONE.as_raw() as u32
+        && read_u32(8) == properties.vendor_id
+        && read_u32(12) == properties.device_id
+        && data[16..HEADER_SIZE] == properties.pipeline_cache_uuid
+}
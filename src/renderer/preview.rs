@@ -0,0 +1,188 @@
+use std::sync::{Arc, Mutex};
+
+use ash::vk;
+use gpu_allocator::{vulkan::Allocator, MemoryLocation};
+use nalgebra as na;
+use nalgebra_glm as glm;
+
+use super::{
+    buffer::{Buffer, BufferManager},
+    camera::{Camera, CameraUniformData},
+    descriptor::DescriptorAllocator,
+    fog::FogConfig,
+    light::{DirectionalLight, LightManager},
+    mesh::{Mesh, MeshManager},
+    scene::InstanceData,
+    shadow::NO_SHADOW,
+    utils::Handle,
+    RendererResult, GLOBAL_UNIFORM_SIZE, SHADER_TONE_MAPPING, SHADOW_UNIFORM_OFFSET,
+    TONE_MAP_UNIFORM_OFFSET,
+};
+
+// Neutral gray, in linear color
+pub(crate) const PREVIEW_BACKGROUND: [f32; 4] = [0.2, 0.2, 0.2, 1.0];
+
+// A unit sphere at the origin with its own camera, lights and descriptor sets,
+// so rendering a preview never touches the main scene's state.
+// Created on the first preview and reused for all later ones.
+pub(crate) struct PreviewScene {
+    uniform_buffer: Buffer,
+    light_buffer: Buffer,
+    instance_buffer: Buffer,
+    pub descriptor_set_camera: vk::DescriptorSet,
+    pub descriptor_set_lights: vk::DescriptorSet,
+    pub sphere: Handle<Mesh>,
+}
+
+impl PreviewScene {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        device: &ash::Device,
+        allocator: &mut Allocator,
+        buffer_manager: Arc<Mutex<BufferManager>>,
+        descriptor_allocator: &mut DescriptorAllocator,
+        meshs: &mut MeshManager,
+        camera_layout: vk::DescriptorSetLayout,
+        lights_layout: vk::DescriptorSetLayout,
+    ) -> RendererResult<Self> {
+        let mut uniform_buffer = BufferManager::new_buffer(
+            buffer_manager.clone(),
+            device,
+            allocator,
+            GLOBAL_UNIFORM_SIZE as u64,
+            vk::BufferUsageFlags::UNIFORM_BUFFER,
+            MemoryLocation::CpuToGpu,
+            "preview-uniforms",
+        )?;
+        Self::write_camera(&mut uniform_buffer, &Self::camera())?;
+
+        let descriptor_set_camera = descriptor_allocator.allocate(device, camera_layout)?;
+        unsafe {
+            let buffer_info = [vk::DescriptorBufferInfo::builder()
+                .buffer(uniform_buffer.get_buffer().buffer)
+                .range(GLOBAL_UNIFORM_SIZE as u64)
+                .build()];
+            let descriptor_write = vk::WriteDescriptorSet::builder()
+                .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC)
+                .dst_binding(0)
+                .dst_set(descriptor_set_camera)
+                .buffer_info(&buffer_info[..]);
+            device.update_descriptor_sets(&[*descriptor_write], &[]);
+        }
+
+        let mut light_buffer = BufferManager::new_buffer(
+            buffer_manager.clone(),
+            device,
+            allocator,
+            8,
+            vk::BufferUsageFlags::STORAGE_BUFFER,
+            MemoryLocation::CpuToGpu,
+            "preview-lights",
+        )?;
+        let descriptor_set_lights = descriptor_allocator.allocate(device, lights_layout)?;
+        Self::lights().update_buffer(
+            device,
+            allocator,
+            &mut light_buffer,
+            descriptor_set_lights,
+        )?;
+
+        let mut instance_buffer = BufferManager::new_buffer(
+            buffer_manager.clone(),
+            device,
+            allocator,
+            std::mem::size_of::<InstanceData>() as u64,
+            vk::BufferUsageFlags::VERTEX_BUFFER,
+            MemoryLocation::CpuToGpu,
+            "preview-instance",
+        )?;
+        Self::write_transform(allocator, &mut instance_buffer, &glm::Mat4::identity())?;
+
+        let sphere = meshs.new_sphere_mesh(3, device, allocator, buffer_manager)?;
+
+        Ok(Self {
+            uniform_buffer,
+            light_buffer,
+            instance_buffer,
+            descriptor_set_camera,
+            descriptor_set_lights,
+            sphere,
+        })
+    }
+
+    fn camera() -> Camera {
+        Camera::builder()
+            .position(glm::Vec3::new(0.0, 0.0, -3.0))
+            .view_direction(glm::Vec3::new(0.0, 0.0, 1.0))
+            .down_direction(glm::Vec3::new(0.0, 1.0, 0.0))
+            .aspect(1.0)
+            .build()
+    }
+
+    fn write_camera(uniform_buffer: &mut Buffer, camera: &Camera) -> RendererResult<()> {
+        camera.update_buffer(uniform_buffer, 0, &camera.view_projection_matrix())?;
+        uniform_buffer.write(
+            &FogConfig::default().uniform_data(),
+            std::mem::size_of::<CameraUniformData>(),
+        )?;
+        uniform_buffer.write(&NO_SHADOW, SHADOW_UNIFORM_OFFSET)?;
+        uniform_buffer.write(&SHADER_TONE_MAPPING, TONE_MAP_UNIFORM_OFFSET)
+    }
+
+    fn write_transform(
+        allocator: &mut Allocator,
+        instance_buffer: &mut Buffer,
+        transform: &glm::Mat4,
+    ) -> RendererResult<()> {
+        instance_buffer.fill(
+            allocator,
+            InstanceData::new(*transform, *transform).as_slice(),
+        )
+    }
+
+    // Views something else than the preview sphere, e.g. for turntables. Every preview
+    // waits for its draw to finish, so this can be changed between them.
+    pub fn set_view(
+        &mut self,
+        allocator: &mut Allocator,
+        camera: &Camera,
+        transform: &glm::Mat4,
+    ) -> RendererResult<()> {
+        Self::write_camera(&mut self.uniform_buffer, camera)?;
+        Self::write_transform(allocator, &mut self.instance_buffer, transform)
+    }
+
+    // Back to the view of the material previews
+    pub fn reset_view(&mut self, allocator: &mut Allocator) -> RendererResult<()> {
+        self.set_view(allocator, &Self::camera(), &glm::Mat4::identity())
+    }
+
+    // Fixed three point lighting: a key light from the upper left, a dimmer fill
+    // from the right and a rim light from behind
+    fn lights() -> LightManager {
+        let mut lights = LightManager::default();
+        lights.add_light(DirectionalLight {
+            direction: na::Unit::new_normalize(glm::Vec3::new(-1.0, -1.0, -1.0)),
+            illuminance: glm::Vec3::new(8.0, 8.0, 8.0),
+        });
+        lights.add_light(DirectionalLight {
+            direction: na::Unit::new_normalize(glm::Vec3::new(1.0, -0.3, -1.0)),
+            illuminance: glm::Vec3::new(3.0, 3.0, 3.0),
+        });
+        lights.add_light(DirectionalLight {
+            direction: na::Unit::new_normalize(glm::Vec3::new(0.0, -1.0, 1.0)),
+            illuminance: glm::Vec3::new(5.0, 5.0, 5.0),
+        });
+        lights
+    }
+
+    pub fn instance_buffer(&self) -> &Buffer {
+        &self.instance_buffer
+    }
+
+    pub fn destroy(&mut self) -> RendererResult<()> {
+        self.uniform_buffer.queue_free()?;
+        self.light_buffer.queue_free()?;
+        self.instance_buffer.queue_free()
+    }
+}
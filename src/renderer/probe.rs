@@ -0,0 +1,369 @@
+use nalgebra as na;
+use nalgebra_glm as glm;
+use serde::{Deserialize, Serialize};
+
+use super::color;
+use super::cubemap::CUBEMAP_FACES;
+
+// Light probes store the light arriving at a point from every direction as second order
+// spherical harmonics, 9 coefficients per color channel. Objects blend the probes
+// nearest to them on the CPU, the vertex shader evaluates the blend for the normal and
+// adds the result as ambient light, see probe_irradiance in default.vert.
+
+pub const SH_COEFFICIENT_COUNT: usize = 9;
+// An object blends at most this many probes
+pub const MAX_BLENDED_PROBES: usize = 4;
+// Probe indices are packed into 16 bits of the instance data
+pub const MAX_LIGHT_PROBES: usize = u16::MAX as usize + 1;
+
+// Ramamoorthi and Hanrahan, "An Efficient Representation for Irradiance Environment Maps"
+const SH_C1: f32 = 0.429043;
+const SH_C2: f32 = 0.511664;
+const SH_C3: f32 = 0.743125;
+const SH_C4: f32 = 0.886227;
+const SH_C5: f32 = 0.247708;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SphericalHarmonics {
+    // Of the incoming radiance, in the order L00, L1-1, L10, L11, L2-2, L2-1, L20, L21, L22
+    pub coefficients: [glm::Vec3; SH_COEFFICIENT_COUNT],
+}
+
+impl Default for SphericalHarmonics {
+    fn default() -> Self {
+        Self {
+            coefficients: [glm::Vec3::zeros(); SH_COEFFICIENT_COUNT],
+        }
+    }
+}
+
+impl SphericalHarmonics {
+    // The real SH basis functions for a normalized direction
+    pub fn basis(direction: &glm::Vec3) -> [f32; SH_COEFFICIENT_COUNT] {
+        let (x, y, z) = (direction.x, direction.y, direction.z);
+        [
+            0.282095,
+            0.488603 * y,
+            0.488603 * z,
+            0.488603 * x,
+            1.092548 * x * y,
+            1.092548 * y * z,
+            0.315392 * (3.0 * z * z - 1.0),
+            1.092548 * x * z,
+            0.546274 * (x * x - y * y),
+        ]
+    }
+
+    // The same radiance from every direction
+    pub fn from_ambient(radiance: glm::Vec3) -> Self {
+        let mut sh = Self::default();
+        sh.coefficients[0] = radiance * (0.282095 * 4.0 * std::f32::consts::PI);
+        sh
+    }
+
+    // Adds radiance arriving from direction over solid_angle steradians
+    pub fn add_sample(&mut self, direction: &glm::Vec3, radiance: &glm::Vec3, solid_angle: f32) {
+        let basis = Self::basis(&glm::normalize(direction));
+        for (coefficient, b) in self.coefficients.iter_mut().zip(basis) {
+            *coefficient += radiance * (b * solid_angle);
+        }
+    }
+
+    pub fn scaled(&self, factor: f32) -> Self {
+        Self {
+            coefficients: self.coefficients.map(|c| c * factor),
+        }
+    }
+
+    // The irradiance on a surface facing normal, pi times the radiance for ambient light
+    pub fn irradiance(&self, normal: &glm::Vec3) -> glm::Vec3 {
+        let n = glm::normalize(normal);
+        let (x, y, z) = (n.x, n.y, n.z);
+        let l = &self.coefficients;
+        l[8] * (SH_C1 * (x * x - y * y)) + l[6] * (SH_C3 * z * z) + l[0] * SH_C4 - l[6] * SH_C5
+            + (l[4] * (x * y) + l[7] * (x * z) + l[5] * (y * z)) * (2.0 * SH_C1)
+            + (l[3] * x + l[1] * y + l[2] * z) * (2.0 * SH_C2)
+    }
+
+    // The radiance averaged over all directions, e.g. to color a probe in a debug view
+    pub fn average_radiance(&self) -> glm::Vec3 {
+        self.coefficients[0] * 0.282095
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProbeData {
+    // Linear radiance arriving from every direction alike
+    Ambient(glm::Vec3),
+    SphericalHarmonics(SphericalHarmonics),
+}
+
+impl ProbeData {
+    pub fn to_spherical_harmonics(&self) -> SphericalHarmonics {
+        match self {
+            ProbeData::Ambient(radiance) => SphericalHarmonics::from_ambient(*radiance),
+            ProbeData::SphericalHarmonics(sh) => *sh,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LightProbe {
+    pub position: na::Point3<f32>, // in m
+    pub data: ProbeData,
+}
+
+// Which probes an object takes its ambient light from
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ProbeBlend {
+    Nearest,
+    // The MAX_BLENDED_PROBES nearest probes, weighted by their inverse distance
+    #[default]
+    InverseDistance,
+}
+
+// Indices into probes and weights that sum up to 1, empty without probes
+pub fn blend_weights(
+    probes: &[glm::Vec3],
+    point: &glm::Vec3,
+    blend: ProbeBlend,
+) -> Vec<(usize, f32)> {
+    let mut distances = probes
+        .iter()
+        .take(MAX_LIGHT_PROBES)
+        .map(|probe| glm::distance(probe, point))
+        .enumerate()
+        .collect::<Vec<_>>();
+    distances.sort_by(|a, b| a.1.total_cmp(&b.1));
+    let count = match blend {
+        ProbeBlend::Nearest => 1,
+        ProbeBlend::InverseDistance => MAX_BLENDED_PROBES,
+    };
+    distances.truncate(count);
+    match distances.first() {
+        None => vec![],
+        // Sitting on a probe, which would divide by zero
+        Some((index, distance)) if *distance <= 1e-4 => vec![(*index, 1.0)],
+        Some(_) => {
+            let total = distances.iter().map(|(_, d)| 1.0 / d).sum::<f32>();
+            distances
+                .into_iter()
+                .map(|(index, d)| (index, 1.0 / d / total))
+                .collect()
+        }
+    }
+}
+
+// The layout of InstanceData::probes: two 16 bit indices in each of x and y, two 16 bit
+// unorm weights in each of z and w. Unused entries have a weight of 0.
+pub fn pack_blend_weights(weights: &[(usize, f32)]) -> [u32; 4] {
+    let mut indices = [0u32; MAX_BLENDED_PROBES];
+    let mut packed_weights = [0u32; MAX_BLENDED_PROBES];
+    for (i, (index, weight)) in weights.iter().take(MAX_BLENDED_PROBES).enumerate() {
+        indices[i] = (*index as u32).min(u16::MAX as u32);
+        packed_weights[i] = (weight.clamp(0.0, 1.0) * u16::MAX as f32).round() as u32;
+    }
+    [
+        indices[0] | indices[1] << 16,
+        indices[2] | indices[3] << 16,
+        packed_weights[0] | packed_weights[1] << 16,
+        packed_weights[2] | packed_weights[3] << 16,
+    ]
+}
+
+// One of the six views a probe is baked from, with a vertical field of view of 90
+// degrees and a square aspect
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ProbeView {
+    pub view: glm::Vec3,
+    pub down: glm::Vec3,
+}
+
+impl ProbeView {
+    // The same as the right direction of a camera with this view and down direction
+    pub fn right(&self) -> glm::Vec3 {
+        self.down.cross(&self.view)
+    }
+
+    // x goes right and y goes down, both from -1 to 1 across the image
+    pub fn direction(&self, x: f32, y: f32) -> glm::Vec3 {
+        glm::normalize(&(self.view + self.right() * x + self.down * y))
+    }
+}
+
+// Looking through the faces of a cube, in the order of CUBEMAP_FACES
+pub fn probe_views() -> [ProbeView; 6] {
+    CUBEMAP_FACES.map(|face| {
+        let view = face.direction(&glm::vec2(0.0, 0.0));
+        ProbeView {
+            view,
+            down: face.direction(&glm::vec2(0.0, 1.0)) - view,
+        }
+    })
+}
+
+// The radiance a pixel of a rendered view stands for. The forward shaders tone map probe
+// views with x / (1 + x) and the target stores that as sRGB, both are undone here. Fully saturated
+// pixels are clamped, so the brightest lights are underestimated.
+pub fn radiance_from_pixel(pixel: &image::Rgba<u8>) -> glm::Vec3 {
+    let channel = |c: u8| {
+        let tone_mapped = color::srgb_to_linear(c as f32 / 255.0).min(0.99);
+        tone_mapped / (1.0 - tone_mapped)
+    };
+    glm::vec3(channel(pixel[0]), channel(pixel[1]), channel(pixel[2]))
+}
+
+// Projects the six views around a probe onto spherical harmonics. Every pixel is
+// weighted by the solid angle it covers, which shrinks towards the edges of a view.
+pub fn project_views(views: &[(ProbeView, image::RgbaImage)]) -> SphericalHarmonics {
+    let mut sh = SphericalHarmonics::default();
+    let mut total_solid_angle = 0.0;
+    for (view, image) in views {
+        let (width, height) = (image.width() as f32, image.height() as f32);
+        let texel_area = (2.0 / width) * (2.0 / height);
+        for (px, py, pixel) in image.enumerate_pixels() {
+            let x = 2.0 * (px as f32 + 0.5) / width - 1.0;
+            let y = 2.0 * (py as f32 + 0.5) / height - 1.0;
+            let solid_angle = texel_area / (1.0 + x * x + y * y).powf(1.5);
+            sh.add_sample(
+                &view.direction(x, y),
+                &radiance_from_pixel(pixel),
+                solid_angle,
+            );
+            total_solid_angle += solid_angle;
+        }
+    }
+    if total_solid_angle <= 0.0 {
+        return sh;
+    }
+    // The pixel solid angles are approximations, six full views cover exactly 4 pi
+    let coverage = views.len() as f32 / 6.0;
+    sh.scaled(4.0 * std::f32::consts::PI * coverage / total_solid_angle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PI: f32 = std::f32::consts::PI;
+
+    fn close(a: &glm::Vec3, b: &glm::Vec3, tolerance: f32) -> bool {
+        (a - b).abs().max() < tolerance
+    }
+
+    // Six views filled with pixel, except the ones bright is true for, filled with white
+    fn views(
+        pixel: [u8; 4],
+        bright: impl Fn(&ProbeView) -> bool,
+    ) -> Vec<(ProbeView, image::RgbaImage)> {
+        probe_views()
+            .into_iter()
+            .map(|view| {
+                let pixel = if bright(&view) { [255; 4] } else { pixel };
+                (
+                    view,
+                    image::RgbaImage::from_pixel(16, 16, image::Rgba(pixel)),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn ambient_light_gives_pi_times_the_radiance() {
+        let radiance = glm::vec3(0.5, 1.0, 2.0);
+        let sh = SphericalHarmonics::from_ambient(radiance);
+        for normal in [glm::vec3(0.0, 1.0, 0.0), glm::vec3(1.0, -1.0, 0.5)] {
+            assert!(close(&sh.irradiance(&normal), &(radiance * PI), 1e-3));
+        }
+        assert!(close(&sh.average_radiance(), &radiance, 1e-5));
+    }
+
+    #[test]
+    fn uniform_views_project_to_ambient_light() {
+        let pixel = image::Rgba([128, 64, 32, 255]);
+        let sh = project_views(&views(pixel.0, |_| false));
+        let ambient = SphericalHarmonics::from_ambient(radiance_from_pixel(&pixel));
+        assert!(close(&sh.coefficients[0], &ambient.coefficients[0], 1e-3));
+        for coefficient in &sh.coefficients[1..] {
+            assert!(close(coefficient, &glm::Vec3::zeros(), 1e-3));
+        }
+    }
+
+    #[test]
+    fn light_from_above_lights_upward_faces() {
+        let up = glm::vec3(0.0, 1.0, 0.0);
+        let sh = project_views(&views([0, 0, 0, 255], |view| {
+            glm::dot(&view.view, &up) > 0.9
+        }));
+        let top = sh.irradiance(&up);
+        let bottom = sh.irradiance(&-up);
+        let side = sh.irradiance(&glm::vec3(1.0, 0.0, 0.0));
+        assert!(
+            top.x > side.x && side.x > bottom.x,
+            "{} {} {}",
+            top,
+            side,
+            bottom
+        );
+        // Second order harmonics ring a little below zero opposite the light
+        assert!(bottom.x.abs() < 0.1 * top.x);
+    }
+
+    #[test]
+    fn nearest_takes_one_probe() {
+        let probes = [glm::vec3(0.0, 0.0, 0.0), glm::vec3(4.0, 0.0, 0.0)];
+        let point = glm::vec3(3.0, 0.0, 0.0);
+        assert_eq!(
+            blend_weights(&probes, &point, ProbeBlend::Nearest),
+            vec![(1, 1.0)]
+        );
+        assert!(blend_weights(&[], &point, ProbeBlend::Nearest).is_empty());
+    }
+
+    #[test]
+    fn inverse_distance_weights_sum_to_one() {
+        let probes = [glm::vec3(0.0, 0.0, 0.0), glm::vec3(4.0, 0.0, 0.0)];
+        let weights = blend_weights(
+            &probes,
+            &glm::vec3(3.0, 0.0, 0.0),
+            ProbeBlend::InverseDistance,
+        );
+        assert_eq!(weights, vec![(1, 0.75), (0, 0.25)]);
+    }
+
+    #[test]
+    fn blends_only_the_nearest_probes() {
+        let probes: Vec<_> = (0..8).map(|i| glm::vec3(i as f32, 0.0, 0.0)).collect();
+        let weights = blend_weights(
+            &probes,
+            &glm::vec3(-1.0, 0.0, 0.0),
+            ProbeBlend::InverseDistance,
+        );
+        let indices: Vec<_> = weights.iter().map(|(index, _)| *index).collect();
+        assert_eq!(indices, vec![0, 1, 2, 3]);
+        assert!((weights.iter().map(|(_, w)| w).sum::<f32>() - 1.0).abs() < 1e-6);
+        assert!(weights.windows(2).all(|w| w[0].1 > w[1].1));
+    }
+
+    #[test]
+    fn sitting_on_a_probe_takes_only_that_one() {
+        let probes = [glm::vec3(0.0, 0.0, 0.0), glm::vec3(1.0, 0.0, 0.0)];
+        assert_eq!(
+            blend_weights(
+                &probes,
+                &glm::vec3(1.0, 0.0, 0.0),
+                ProbeBlend::InverseDistance
+            ),
+            vec![(1, 1.0)]
+        );
+    }
+
+    #[test]
+    fn packs_indices_and_weights_in_16_bits() {
+        let packed = pack_blend_weights(&[(3, 1.0), (70000, 0.5), (7, 0.0)]);
+        assert_eq!(packed[0], 3 | 0xffff << 16);
+        assert_eq!(packed[1], 7);
+        assert_eq!(packed[2], 0xffff | 0x8000 << 16);
+        assert_eq!(packed[3], 0);
+    }
+}
@@ -0,0 +1,253 @@
+use ash::vk;
+
+use super::context::VulkanContext;
+use super::gpu_timer::{elapsed_ms, timestamp_support};
+use super::RendererResult;
+
+// The most scopes timed per frame, the ones past it are not timed
+pub const MAX_PROFILER_SCOPES: usize = 32;
+
+// The GPU time of one pass of a finished frame
+#[derive(Clone, Copy, Debug)]
+pub struct PassTiming {
+    pub name: &'static str,
+    // From the start of the first pass of the frame. Passes on the async compute queue
+    // can start before the graphics passes in front of them end.
+    pub start_ms: f32,
+    pub gpu_ms: f32,
+}
+
+// How much of the passes ran at the same time as others: the sum of their times minus
+// the time at least one of them was running. More than 0 only with passes on the async
+// compute queue, see RendererConfig::async_compute.
+pub fn overlap_ms(timings: &[PassTiming]) -> f32 {
+    let mut spans = timings
+        .iter()
+        .map(|timing| (timing.start_ms, timing.start_ms + timing.gpu_ms))
+        .collect::<Vec<_>>();
+    spans.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let mut busy = 0.0;
+    let mut covered_until = f32::NEG_INFINITY;
+    for (start, end) in spans {
+        if end > covered_until {
+            busy += end - start.max(covered_until);
+            covered_until = end;
+        }
+    }
+    let total: f32 = timings.iter().map(|timing| timing.gpu_ms).sum();
+    (total - busy).max(0.0)
+}
+
+struct ProfilerFrame {
+    query_pool: vk::QueryPool,
+    // In the order they were opened, the queries of scope i are 2 * i and 2 * i + 1
+    scopes: Vec<&'static str>,
+    // Whether a scope is open and not closed yet
+    open: bool,
+    // Whether the queries were written and not read yet
+    pending: bool,
+}
+
+// A query pool per frame in flight with two timestamps around every scope, usually a
+// pass. Like GpuFrameTimer the results are read once the frame is done.
+pub(crate) struct GpuProfiler {
+    frames: Vec<ProfilerFrame>,
+    period: f32,
+    valid_mask: u64,
+    // Whether scopes can be on the compute queue too
+    times_compute: bool,
+}
+
+impl GpuProfiler {
+    // None if the graphics queue can't write timestamps
+    pub fn new(context: &VulkanContext, frames: usize) -> RendererResult<Option<Self>> {
+        let (period, valid_mask) = match timestamp_support(context, context.graphics_queue.index) {
+            Some(support) => support,
+            None => return Ok(None),
+        };
+        // The timestamps of both queues are compared with each other for the start
+        // times, so they need to have the same bits
+        let times_compute = context
+            .compute_queue
+            .as_ref()
+            .filter(|queue| queue.index != context.graphics_queue.index)
+            .and_then(|queue| timestamp_support(context, queue.index))
+            .map_or(false, |support| support == (period, valid_mask));
+        let query_pool_info = vk::QueryPoolCreateInfo::builder()
+            .query_type(vk::QueryType::TIMESTAMP)
+            .query_count(2 * MAX_PROFILER_SCOPES as u32);
+        let mut profiler_frames = Vec::with_capacity(frames);
+        for i in 0..frames {
+            let query_pool = unsafe { context.device.create_query_pool(&query_pool_info, None)? };
+            context
+                .debug_namer
+                .name(query_pool, &format!("profiler frame {}", i));
+            profiler_frames.push(ProfilerFrame {
+                query_pool,
+                scopes: Vec::with_capacity(MAX_PROFILER_SCOPES),
+                open: false,
+                pending: false,
+            });
+        }
+        Ok(Some(Self {
+            frames: profiler_frames,
+            period,
+            valid_mask,
+            times_compute,
+        }))
+    }
+
+    pub fn times_compute(&self) -> bool {
+        self.times_compute
+    }
+
+    // Outside of a render pass, before any scope of the frame
+    pub fn begin_frame(
+        &mut self,
+        device: &ash::Device,
+        command_buffer: vk::CommandBuffer,
+        frame: usize,
+    ) {
+        let profiler_frame = &mut self.frames[frame];
+        profiler_frame.scopes.clear();
+        profiler_frame.open = false;
+        profiler_frame.pending = false;
+        unsafe {
+            device.cmd_reset_query_pool(
+                command_buffer,
+                profiler_frame.query_pool,
+                0,
+                2 * MAX_PROFILER_SCOPES as u32,
+            );
+        }
+    }
+
+    // Scopes don't nest, every scope has to be recorded after the one before it ended.
+    // They have to be on the graphics queue, or on the compute queue with times_compute,
+    // after a wait for the graphics work with begin_frame.
+    pub fn begin_scope(
+        &mut self,
+        device: &ash::Device,
+        command_buffer: vk::CommandBuffer,
+        frame: usize,
+        name: &'static str,
+    ) {
+        let profiler_frame = &mut self.frames[frame];
+        debug_assert!(!profiler_frame.open, "Profiler scopes don't nest");
+        if profiler_frame.scopes.len() >= MAX_PROFILER_SCOPES {
+            return;
+        }
+        unsafe {
+            device.cmd_write_timestamp(
+                command_buffer,
+                vk::PipelineStageFlags::TOP_OF_PIPE,
+                profiler_frame.query_pool,
+                2 * profiler_frame.scopes.len() as u32,
+            );
+        }
+        profiler_frame.scopes.push(name);
+        profiler_frame.open = true;
+    }
+
+    pub fn end_scope(
+        &mut self,
+        device: &ash::Device,
+        command_buffer: vk::CommandBuffer,
+        frame: usize,
+    ) {
+        let profiler_frame = &mut self.frames[frame];
+        if !profiler_frame.open {
+            return;
+        }
+        unsafe {
+            device.cmd_write_timestamp(
+                command_buffer,
+                vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                profiler_frame.query_pool,
+                2 * profiler_frame.scopes.len() as u32 - 1,
+            );
+        }
+        profiler_frame.open = false;
+        profiler_frame.pending = true;
+    }
+
+    // The timings of the last frame recorded with frame, once the GPU is done with it.
+    // Every frame is only read once.
+    pub fn read(&mut self, device: &ash::Device, frame: usize) -> Option<Vec<PassTiming>> {
+        let profiler_frame = &mut self.frames[frame];
+        if !std::mem::replace(&mut profiler_frame.pending, false) {
+            return None;
+        }
+        let mut timestamps = vec![0u64; 2 * profiler_frame.scopes.len()];
+        unsafe {
+            device.get_query_pool_results(
+                profiler_frame.query_pool,
+                0,
+                timestamps.len() as u32,
+                &mut timestamps,
+                vk::QueryResultFlags::TYPE_64,
+            )
+        }
+        .ok()?;
+        Some(
+            profiler_frame
+                .scopes
+                .iter()
+                .zip(timestamps.chunks_exact(2))
+                .map(|(name, pair)| PassTiming {
+                    name: *name,
+                    start_ms: elapsed_ms(timestamps[0], pair[0], self.period, self.valid_mask),
+                    gpu_ms: elapsed_ms(pair[0], pair[1], self.period, self.valid_mask),
+                })
+                .collect(),
+        )
+    }
+
+    pub fn destroy(&mut self, device: &ash::Device) {
+        for profiler_frame in self.frames.drain(..) {
+            unsafe {
+                device.destroy_query_pool(profiler_frame.query_pool, None);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timing(start_ms: f32, gpu_ms: f32) -> PassTiming {
+        PassTiming {
+            name: "pass",
+            start_ms,
+            gpu_ms,
+        }
+    }
+
+    #[test]
+    fn passes_one_after_another_do_not_overlap() {
+        assert_eq!(overlap_ms(&[]), 0.0);
+        assert_eq!(
+            overlap_ms(&[timing(0.0, 1.0), timing(1.0, 2.0), timing(4.0, 0.5)]),
+            0.0
+        );
+    }
+
+    #[test]
+    fn compute_next_to_graphics_overlaps() {
+        // Luminance on the compute queue from 1 to 3, next to graphics from 1 to 4
+        let timings = [
+            timing(0.0, 1.0),
+            timing(1.0, 2.0),
+            timing(1.0, 1.5),
+            timing(2.5, 1.5),
+        ];
+        assert_eq!(overlap_ms(&timings), 2.0);
+    }
+
+    #[test]
+    fn a_pass_inside_another_overlaps_with_all_of_it() {
+        assert_eq!(overlap_ms(&[timing(0.0, 4.0), timing(1.0, 1.0)]), 1.0);
+        assert_eq!(overlap_ms(&[timing(1.0, 1.0), timing(0.0, 4.0)]), 1.0);
+    }
+}
@@ -0,0 +1,208 @@
+use std::sync::{Arc, Mutex};
+
+use ash::vk;
+use gpu_allocator::{
+    vulkan::{Allocation, Allocator},
+    MemoryLocation,
+};
+
+use super::{
+    buffer::{Buffer, BufferManager},
+    RendererResult,
+};
+
+struct ReadbackSlot {
+    buffer: Buffer,
+    // The frame that copied into the slot and hasn't been read back yet
+    written: Option<u64>,
+}
+
+// Host visible copies of a small GPU buffer, one per frame in flight. Every frame copies
+// into its own slot and the CPU reads the slot once its frame finished, which happens
+// anyway before the slot is reused, so results arrive a few frames late but reading
+// them never waits for the GPU.
+pub(crate) struct ReadbackRing {
+    size: u64,
+    slots: Vec<ReadbackSlot>,
+}
+
+impl std::fmt::Debug for ReadbackRing {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ReadbackRing")
+            .field("size", &self.size)
+            .field("slots", &self.slots.len())
+            .finish()
+    }
+}
+
+impl ReadbackRing {
+    pub fn new(
+        device: &ash::Device,
+        allocator: &mut Allocator,
+        buffer_manager: Arc<Mutex<BufferManager>>,
+        size: u64,
+        slot_count: usize,
+        name: &str,
+    ) -> RendererResult<Self> {
+        let mut slots = Vec::with_capacity(slot_count);
+        for _ in 0..slot_count {
+            let buffer = BufferManager::new_buffer(
+                buffer_manager.clone(),
+                device,
+                allocator,
+                size,
+                vk::BufferUsageFlags::TRANSFER_DST,
+                MemoryLocation::GpuToCpu,
+                name,
+            )?;
+            slots.push(ReadbackSlot {
+                buffer,
+                written: None,
+            });
+        }
+        Ok(Self { size, slots })
+    }
+
+    // Copies the first bytes of source into the slot. Whatever wrote source has to be
+    // made visible to transfers by the caller.
+    pub fn record_copy(
+        &mut self,
+        device: &ash::Device,
+        command_buffer: vk::CommandBuffer,
+        source: vk::Buffer,
+        slot: usize,
+        frame: u64,
+    ) {
+        let slot = &mut self.slots[slot];
+        let target = slot.buffer.get_buffer().buffer;
+        let regions = [vk::BufferCopy {
+            src_offset: 0,
+            dst_offset: 0,
+            size: self.size,
+        }];
+        let barrier = vk::BufferMemoryBarrier::builder()
+            .buffer(target)
+            .offset(0)
+            .size(self.size)
+            .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .dst_access_mask(vk::AccessFlags::HOST_READ)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .build();
+        unsafe {
+            device.cmd_copy_buffer(command_buffer, source, target, &regions);
+            device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::PipelineStageFlags::HOST,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[barrier],
+                &[],
+            );
+        }
+        slot.written = Some(frame);
+    }
+
+    // The frame that wrote the slot and its first count elements, once. The frame has to
+    // be finished, None if nothing was copied into the slot since it was last read.
+    pub fn read<T: Copy>(
+        &mut self,
+        slot: usize,
+        count: usize,
+    ) -> RendererResult<Option<(u64, Vec<T>)>> {
+        let slot = &mut self.slots[slot];
+        match slot.written.take() {
+            Some(frame) => Ok(Some((frame, slot.buffer.read(count)?))),
+            None => Ok(None),
+        }
+    }
+
+    // Results that weren't read yet are dropped
+    pub fn clear(&mut self) {
+        for slot in self.slots.iter_mut() {
+            slot.written = None;
+        }
+    }
+
+    pub fn destroy(&mut self) -> RendererResult<()> {
+        for slot in self.slots.iter_mut() {
+            slot.buffer.queue_free()?;
+        }
+        self.slots.clear();
+        Ok(())
+    }
+}
+
+// A copy of an image layer into a linear host visible image, submitted without waiting
+// for it, see Renderer::screenshot_async
+pub(crate) struct PendingImageReadback {
+    pub command_buffer: vk::CommandBuffer,
+    pub fence: vk::Fence,
+    pub image: vk::Image,
+    pub allocation: Allocation,
+    pub extent: vk::Extent2D,
+    pub texel_size: usize,
+    // Of the image that was copied, the texels are converted from it
+    pub format: vk::Format,
+}
+
+impl PendingImageReadback {
+    pub fn is_done(&self, device: &ash::Device) -> RendererResult<bool> {
+        Ok(unsafe { device.get_fence_status(self.fence)? })
+    }
+
+    // Waits for the copy, the texels come back row after row without padding
+    pub fn finish(
+        self,
+        device: &ash::Device,
+        command_pool: vk::CommandPool,
+        allocator: &mut Allocator,
+    ) -> RendererResult<Vec<u8>> {
+        unsafe { device.wait_for_fences(&[self.fence], true, std::u64::MAX)? };
+        let source_ptr = self
+            .allocation
+            .mapped_ptr()
+            .expect("No mapped memory for image")
+            .as_ptr() as *const u8;
+        let subresource_layout = unsafe {
+            device.get_image_subresource_layout(
+                self.image,
+                vk::ImageSubresource {
+                    aspect_mask: vk::ImageAspectFlags::COLOR,
+                    mip_level: 0,
+                    array_layer: 0,
+                },
+            )
+        };
+
+        // Rows can be padded, so copy them one by one
+        let row_size = self.extent.width as usize * self.texel_size;
+        let mut data = Vec::<u8>::with_capacity(row_size * self.extent.height as usize);
+        for row in 0..self.extent.height as usize {
+            let row_start =
+                subresource_layout.offset as usize + row * subresource_layout.row_pitch as usize;
+            data.extend_from_slice(unsafe {
+                std::slice::from_raw_parts(source_ptr.add(row_start), row_size)
+            });
+        }
+        self.destroy(device, command_pool, allocator)?;
+        Ok(data)
+    }
+
+    // The copy has to be finished
+    pub fn destroy(
+        self,
+        device: &ash::Device,
+        command_pool: vk::CommandPool,
+        allocator: &mut Allocator,
+    ) -> RendererResult<()> {
+        unsafe {
+            device.destroy_fence(self.fence, None);
+            device.free_command_buffers(command_pool, &[self.command_buffer]);
+        }
+        allocator.free(self.allocation)?;
+        unsafe { device.destroy_image(self.image, None) };
+        Ok(())
+    }
+}
@@ -4,7 +4,13 @@ use gpu_allocator::{
     MemoryLocation,
 };
 
-use super::{context::VulkanContext, RendererResult};
+use super::{config::TransparencyTechnique, context::VulkanContext, RendererResult};
+
+// Of the attachments weighted blended transparency accumulates into, the premultiplied
+// color and alpha summed with their weights, and the product of what each surface lets
+// through
+pub const OIT_ACCUMULATION_FORMAT: vk::Format = vk::Format::R16G16B16A16_SFLOAT;
+pub const OIT_REVEALAGE_FORMAT: vk::Format = vk::Format::R8_UNORM;
 
 pub struct RenderTarget {
     pub extent: vk::Extent3D,
@@ -17,9 +23,83 @@ pub struct RenderTarget {
     pub depth_image: Option<vk::Image>,
     pub depth_image_allocation: Option<Allocation>,
     pub depth_image_view: Option<vk::ImageView>,
+    // Rendered to when multisampling and resolved into the image at the end of the
+    // render pass
+    pub msaa_image: Option<vk::Image>,
+    pub msaa_image_allocation: Option<Allocation>,
+    pub msaa_image_view: Option<vk::ImageView>,
+    // The accumulation and revealage attachments of TransparencyTechnique::WeightedBlended
+    // in that order, empty for the other techniques. They come after the color, depth
+    // and resolve attachments.
+    pub oit_images: Vec<vk::Image>,
+    pub oit_image_allocations: Vec<Allocation>,
+    pub oit_image_views: Vec<vk::ImageView>,
+    // Of the overlay render pass, which only has the single sampled color, see
+    // add_overlay_framebuffer
+    pub overlay_framebuffer: Option<vk::Framebuffer>,
+}
+
+// An attachment that only lives within the render pass, so it may never need memory on
+// tilers
+fn create_transient_image(
+    context: &VulkanContext,
+    allocator: &mut Allocator,
+    name: &str,
+    format: vk::Format,
+    extent: vk::Extent3D,
+    samples: vk::SampleCountFlags,
+    usage: vk::ImageUsageFlags,
+) -> RendererResult<(vk::Image, Allocation, vk::ImageView)> {
+    let queue_family_indices = [context.graphics_queue.index];
+    let image_info = vk::ImageCreateInfo::builder()
+        .image_type(vk::ImageType::TYPE_2D)
+        .format(format)
+        .extent(extent)
+        .mip_levels(1)
+        .array_layers(1)
+        .samples(samples)
+        .tiling(vk::ImageTiling::OPTIMAL)
+        .usage(usage | vk::ImageUsageFlags::TRANSIENT_ATTACHMENT)
+        .sharing_mode(vk::SharingMode::EXCLUSIVE)
+        .queue_family_indices(&queue_family_indices);
+
+    let image = unsafe { context.device.create_image(&image_info, None) }?;
+    let reqs = unsafe { context.device.get_image_memory_requirements(image) };
+    let allocation = allocator.allocate(&AllocationCreateDesc {
+        name,
+        requirements: reqs,
+        location: MemoryLocation::GpuOnly,
+        linear: false,
+        allocation_scheme: AllocationScheme::GpuAllocatorManaged,
+    })?;
+    unsafe {
+        context
+            .device
+            .bind_image_memory(image, allocation.memory(), allocation.offset())?;
+    }
+    let subresource_range = vk::ImageSubresourceRange::builder()
+        .aspect_mask(vk::ImageAspectFlags::COLOR)
+        .base_mip_level(0)
+        .level_count(1)
+        .base_array_layer(0)
+        .layer_count(1);
+    let image_view_create_info = vk::ImageViewCreateInfo::builder()
+        .image(image)
+        .view_type(vk::ImageViewType::TYPE_2D)
+        .format(format)
+        .subresource_range(*subresource_range);
+    let image_view = unsafe {
+        context
+            .device
+            .create_image_view(&image_view_create_info, None)
+    }?;
+    Ok((image, allocation, image_view))
 }
 
 impl RenderTarget {
+    // The render pass has the attachments of the transparency technique, see
+    // Renderer::create_scene_subpasses
+    #[allow(clippy::too_many_arguments)]
     pub fn new_from_image(
         context: &VulkanContext,
         allocator: &mut Allocator,
@@ -27,6 +107,8 @@ impl RenderTarget {
         format: vk::Format,
         extent: vk::Extent2D,
         render_pass: &vk::RenderPass,
+        samples: vk::SampleCountFlags,
+        transparency: TransparencyTechnique,
     ) -> RendererResult<Self> {
         let subresource_range = vk::ImageSubresourceRange::builder()
             .aspect_mask(vk::ImageAspectFlags::COLOR)
@@ -59,9 +141,13 @@ impl RenderTarget {
             .extent(extent)
             .mip_levels(1)
             .array_layers(1)
-            .samples(vk::SampleCountFlags::TYPE_1)
+            .samples(samples)
             .tiling(vk::ImageTiling::OPTIMAL)
-            .usage(vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT)
+            // Read by the particles in the transparency subpass of the scene render passes
+            .usage(
+                vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT
+                    | vk::ImageUsageFlags::INPUT_ATTACHMENT,
+            )
             .sharing_mode(vk::SharingMode::EXCLUSIVE)
             .queue_family_indices(&queue_family_indices);
 
@@ -98,7 +184,48 @@ impl RenderTarget {
                 .create_image_view(&image_view_create_info, None)
         }?;
 
-        let iview = [image_view, depth_image_view];
+        let mut msaa = None;
+        if samples != vk::SampleCountFlags::TYPE_1 {
+            msaa = Some(create_transient_image(
+                context,
+                allocator,
+                "msaa_image",
+                format,
+                extent,
+                samples,
+                vk::ImageUsageFlags::COLOR_ATTACHMENT,
+            )?);
+        }
+        let mut oit_images = vec![];
+        let mut oit_image_allocations = vec![];
+        let mut oit_image_views = vec![];
+        if transparency == TransparencyTechnique::WeightedBlended {
+            // Read by the composite subpass
+            for (name, format) in [
+                ("oit_accumulation", OIT_ACCUMULATION_FORMAT),
+                ("oit_revealage", OIT_REVEALAGE_FORMAT),
+            ] {
+                let (image, allocation, view) = create_transient_image(
+                    context,
+                    allocator,
+                    name,
+                    format,
+                    extent,
+                    samples,
+                    vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::INPUT_ATTACHMENT,
+                )?;
+                oit_images.push(image);
+                oit_image_allocations.push(allocation);
+                oit_image_views.push(view);
+            }
+        }
+
+        // The resolve attachment comes after the depth, see Renderer::create_scene_subpasses
+        let mut iview = match &msaa {
+            Some((_, _, msaa_image_view)) => vec![*msaa_image_view, depth_image_view, image_view],
+            None => vec![image_view, depth_image_view],
+        };
+        iview.extend(oit_image_views.iter().copied());
         let framebuffer_info = vk::FramebufferCreateInfo::builder()
             .render_pass(*render_pass)
             .attachments(&iview)
@@ -107,6 +234,10 @@ impl RenderTarget {
             .layers(1);
         let framebuffer = unsafe { context.device.create_framebuffer(&framebuffer_info, None) }?;
 
+        let (msaa_image, msaa_image_allocation, msaa_image_view) = match msaa {
+            Some((image, allocation, view)) => (Some(image), Some(allocation), Some(view)),
+            None => (None, None, None),
+        };
         Ok(Self {
             extent,
             image,
@@ -118,9 +249,194 @@ impl RenderTarget {
             depth_image: Some(depth_image),
             depth_image_allocation: Some(depth_image_allocation),
             depth_image_view: Some(depth_image_view),
+            msaa_image,
+            msaa_image_allocation,
+            msaa_image_view,
+            oit_images,
+            oit_image_allocations,
+            oit_image_views,
+            overlay_framebuffer: None,
         })
     }
 
+    // Without a depth image, for render passes that only have a color attachment
+    pub fn new_color_only(
+        context: &VulkanContext,
+        image: vk::Image,
+        format: vk::Format,
+        extent: vk::Extent2D,
+        render_pass: &vk::RenderPass,
+    ) -> RendererResult<Self> {
+        let subresource_range = vk::ImageSubresourceRange::builder()
+            .aspect_mask(vk::ImageAspectFlags::COLOR)
+            .base_mip_level(0)
+            .level_count(1)
+            .base_array_layer(0)
+            .layer_count(1);
+        let image_view_create_info = vk::ImageViewCreateInfo::builder()
+            .image(image)
+            .view_type(vk::ImageViewType::TYPE_2D)
+            .format(format)
+            .subresource_range(*subresource_range);
+        let image_view = unsafe {
+            context
+                .device
+                .create_image_view(&image_view_create_info, None)
+        }?;
+
+        let iview = [image_view];
+        let framebuffer_info = vk::FramebufferCreateInfo::builder()
+            .render_pass(*render_pass)
+            .attachments(&iview)
+            .width(extent.width)
+            .height(extent.height)
+            .layers(1);
+        let framebuffer = unsafe { context.device.create_framebuffer(&framebuffer_info, None) }?;
+
+        Ok(Self {
+            extent: vk::Extent3D {
+                width: extent.width,
+                height: extent.height,
+                depth: 1,
+            },
+            image,
+            should_destroy_image: false,
+            image_allocation: None,
+            image_format: format,
+            image_view,
+            framebuffer,
+            depth_image: None,
+            depth_image_allocation: None,
+            depth_image_view: None,
+            msaa_image: None,
+            msaa_image_allocation: None,
+            msaa_image_view: None,
+            oit_images: vec![],
+            oit_image_allocations: vec![],
+            oit_image_views: vec![],
+            overlay_framebuffer: None,
+        })
+    }
+
+    // For targets the overlays are drawn to after the render pass the target was created
+    // for, see Renderer::create_overlay_render_pass
+    pub fn add_overlay_framebuffer(
+        &mut self,
+        context: &VulkanContext,
+        render_pass: &vk::RenderPass,
+    ) -> RendererResult<()> {
+        let iview = [self.image_view];
+        let framebuffer_info = vk::FramebufferCreateInfo::builder()
+            .render_pass(*render_pass)
+            .attachments(&iview)
+            .width(self.extent.width)
+            .height(self.extent.height)
+            .layers(1);
+        let framebuffer = unsafe { context.device.create_framebuffer(&framebuffer_info, None) }?;
+        if let Some(old) = self.overlay_framebuffer.replace(framebuffer) {
+            unsafe {
+                context.device.destroy_framebuffer(old, None);
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn set_debug_name(&self, context: &VulkanContext, name: &str) {
+        let debug_namer = &context.debug_namer;
+        debug_namer.name(self.image, &format!("{} color", name));
+        debug_namer.name(self.image_view, &format!("{} color view", name));
+        if let Some(depth_image) = self.depth_image {
+            debug_namer.name(depth_image, &format!("{} depth", name));
+        }
+        if let Some(depth_image_view) = self.depth_image_view {
+            debug_namer.name(depth_image_view, &format!("{} depth view", name));
+        }
+        if let Some(msaa_image) = self.msaa_image {
+            debug_namer.name(msaa_image, &format!("{} multisampled color", name));
+        }
+        if let Some(msaa_image_view) = self.msaa_image_view {
+            debug_namer.name(
+                msaa_image_view,
+                &format!("{} multisampled color view", name),
+            );
+        }
+        for ((image, view), attachment) in self
+            .oit_images
+            .iter()
+            .zip(&self.oit_image_views)
+            .zip(["accumulation", "revealage"])
+        {
+            debug_namer.name(*image, &format!("{} {}", name, attachment));
+            debug_namer.name(*view, &format!("{} {} view", name, attachment));
+        }
+        debug_namer.name(self.framebuffer, &format!("{} framebuffer", name));
+    }
+
+    // Creates a target that owns its color image, which can be copied from after rendering.
+    // With multisampling the image holds the resolved samples. It can be left in
+    // SHADER_READ_ONLY_OPTIMAL, like the scene render pass does.
+    pub fn new_offscreen(
+        context: &VulkanContext,
+        allocator: &mut Allocator,
+        format: vk::Format,
+        extent: vk::Extent2D,
+        render_pass: &vk::RenderPass,
+        samples: vk::SampleCountFlags,
+        transparency: TransparencyTechnique,
+    ) -> RendererResult<Self> {
+        let queue_family_indices = [context.graphics_queue.index];
+        let image_info = vk::ImageCreateInfo::builder()
+            .image_type(vk::ImageType::TYPE_2D)
+            .format(format)
+            .extent(vk::Extent3D {
+                width: extent.width,
+                height: extent.height,
+                depth: 1,
+            })
+            .mip_levels(1)
+            .array_layers(1)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .tiling(vk::ImageTiling::OPTIMAL)
+            .usage(
+                vk::ImageUsageFlags::COLOR_ATTACHMENT
+                    | vk::ImageUsageFlags::TRANSFER_SRC
+                    | vk::ImageUsageFlags::SAMPLED,
+            )
+            .sharing_mode(vk::SharingMode::EXCLUSIVE)
+            .queue_family_indices(&queue_family_indices);
+
+        let image = unsafe { context.device.create_image(&image_info, None) }?;
+        let reqs = unsafe { context.device.get_image_memory_requirements(image) };
+        let image_allocation = allocator.allocate(&AllocationCreateDesc {
+            name: "offscreen_image",
+            requirements: reqs,
+            location: MemoryLocation::GpuOnly,
+            linear: false,
+            allocation_scheme: AllocationScheme::GpuAllocatorManaged,
+        })?;
+        unsafe {
+            context.device.bind_image_memory(
+                image,
+                image_allocation.memory(),
+                image_allocation.offset(),
+            )?;
+        }
+
+        let mut target = Self::new_from_image(
+            context,
+            allocator,
+            image,
+            format,
+            extent,
+            render_pass,
+            samples,
+            transparency,
+        )?;
+        target.should_destroy_image = true;
+        target.image_allocation = Some(image_allocation);
+        Ok(target)
+    }
+
     pub fn destroy(&mut self, context: &VulkanContext, allocator: &mut Allocator) {
         if let Some(depth_image_allocation) = self.depth_image_allocation.take() {
             allocator
@@ -138,11 +454,46 @@ impl RenderTarget {
             }
         }
 
+        if let Some(msaa_image_allocation) = self.msaa_image_allocation.take() {
+            allocator
+                .free(msaa_image_allocation)
+                .expect("Could not free memory");
+        }
+        if let Some(msaa_image_view) = self.msaa_image_view.take() {
+            unsafe {
+                context.device.destroy_image_view(msaa_image_view, None);
+            }
+        }
+        if let Some(msaa_image) = self.msaa_image.take() {
+            unsafe {
+                context.device.destroy_image(msaa_image, None);
+            }
+        }
+
+        for allocation in self.oit_image_allocations.drain(..) {
+            allocator.free(allocation).expect("Could not free memory");
+        }
+        for view in self.oit_image_views.drain(..) {
+            unsafe {
+                context.device.destroy_image_view(view, None);
+            }
+        }
+        for image in self.oit_images.drain(..) {
+            unsafe {
+                context.device.destroy_image(image, None);
+            }
+        }
+
         if let Some(allocation) = self.image_allocation.take() {
             allocator.free(allocation).expect("Could not free memory");
         }
 
         unsafe {
+            if let Some(overlay_framebuffer) = self.overlay_framebuffer.take() {
+                context
+                    .device
+                    .destroy_framebuffer(overlay_framebuffer, None);
+            }
             context.device.destroy_framebuffer(self.framebuffer, None);
             context.device.destroy_image_view(self.image_view, None);
             if self.should_destroy_image {
@@ -0,0 +1,237 @@
+use std::collections::HashMap;
+use std::ops::DerefMut;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use ash::vk;
+use gpu_allocator::vulkan::Allocator;
+use gpu_allocator::MemoryLocation;
+use log::warn;
+
+use super::{
+    buffer::{Buffer, BufferManager},
+    loading::ObjSceneInstance,
+    material::Material,
+    mesh::{
+        loaders::obj::{ObjMaterial, ObjScene},
+        Mesh, MeshManager,
+    },
+    scene::{SceneObject, SceneObjectMutGuard, SceneTree},
+    texture::{Texture, TextureCreateOptions, TextureStorage},
+    utils::Handle,
+    RendererResult,
+};
+
+// Everything that allocates GPU memory, with the allocator locked once for all of it.
+// Borrows the renderer mutably, so no frame can be recorded while it is alive, see
+// Renderer::resources.
+pub struct ResourceContext<'a> {
+    pub(crate) allocator: MutexGuard<'a, Allocator>,
+    pub(crate) device: &'a ash::Device,
+    pub(crate) buffer_manager: &'a Arc<Mutex<BufferManager>>,
+    pub(crate) command_pool: vk::CommandPool,
+    pub(crate) queue: vk::Queue,
+    pub(crate) meshs: &'a mut MeshManager,
+    pub(crate) texture_storage: &'a mut TextureStorage,
+    pub(crate) scene_tree: &'a mut SceneTree,
+}
+
+impl<'a> ResourceContext<'a> {
+    // For the few things not covered below
+    pub fn allocator(&mut self) -> &mut Allocator {
+        self.allocator.deref_mut()
+    }
+
+    pub fn new_buffer(
+        &mut self,
+        size: u64,
+        usage: vk::BufferUsageFlags,
+        location: MemoryLocation,
+        name: &str,
+    ) -> RendererResult<Buffer> {
+        BufferManager::new_buffer(
+            self.buffer_manager.clone(),
+            self.device,
+            self.allocator.deref_mut(),
+            size,
+            usage,
+            location,
+            name,
+        )
+    }
+
+    pub fn add_mesh(&mut self, mesh: Mesh) -> RendererResult<Handle<Mesh>> {
+        self.meshs.add_mesh(
+            mesh,
+            self.device,
+            self.allocator.deref_mut(),
+            self.buffer_manager.clone(),
+        )
+    }
+
+    pub fn new_cube_mesh(&mut self) -> RendererResult<Handle<Mesh>> {
+        self.meshs.new_cube_mesh(
+            self.device,
+            self.allocator.deref_mut(),
+            self.buffer_manager.clone(),
+        )
+    }
+
+    pub fn new_sphere_mesh(&mut self, refinements: u32) -> RendererResult<Handle<Mesh>> {
+        self.meshs.new_sphere_mesh(
+            refinements,
+            self.device,
+            self.allocator.deref_mut(),
+            self.buffer_manager.clone(),
+        )
+    }
+
+    pub fn new_mesh_from_obj<P: AsRef<Path>>(&mut self, path: P) -> RendererResult<Handle<Mesh>> {
+        self.meshs.new_mesh_from_obj(
+            path,
+            self.device,
+            self.allocator.deref_mut(),
+            self.buffer_manager.clone(),
+        )
+    }
+
+    pub fn new_meshes_from_obj<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+    ) -> RendererResult<Vec<(Handle<Mesh>, ObjMaterial)>> {
+        self.meshs.new_meshes_from_obj(
+            path,
+            self.device,
+            self.allocator.deref_mut(),
+            self.buffer_manager.clone(),
+        )
+    }
+
+    pub fn new_texture_from_file<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+    ) -> RendererResult<Handle<Texture>> {
+        self.texture_storage.new_texture_from_file(
+            path,
+            self.device,
+            self.allocator.deref_mut(),
+            self.buffer_manager.clone(),
+            self.command_pool,
+            self.queue,
+        )
+    }
+
+    pub fn new_texture_from_file_with_options<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        options: TextureCreateOptions,
+    ) -> RendererResult<Handle<Texture>> {
+        self.texture_storage.new_texture_from_file_with_options(
+            path,
+            options,
+            self.device,
+            self.allocator.deref_mut(),
+            self.buffer_manager.clone(),
+            self.command_pool,
+            self.queue,
+        )
+    }
+
+    // The faces in the order +X, -X, +Y, -Y, +Z, -Z
+    pub fn new_cubemap_from_files<P: AsRef<Path>>(
+        &mut self,
+        paths: &[P; 6],
+    ) -> RendererResult<Handle<Texture>> {
+        self.texture_storage.new_cubemap_from_files(
+            paths,
+            self.device,
+            self.allocator.deref_mut(),
+            self.buffer_manager.clone(),
+            self.command_pool,
+            self.queue,
+        )
+    }
+
+    pub fn new_texture_from_u8(
+        &mut self,
+        data: &[u8],
+        width: u32,
+        height: u32,
+        format: vk::Format,
+    ) -> RendererResult<Handle<Texture>> {
+        self.texture_storage.new_texture_from_u8(
+            data,
+            width,
+            height,
+            format,
+            self.device,
+            self.allocator.deref_mut(),
+            self.buffer_manager.clone(),
+            &self.command_pool,
+            &self.queue,
+        )
+    }
+
+    pub fn new_object(
+        &mut self,
+        mesh: Handle<Mesh>,
+        material: Handle<Material>,
+    ) -> RendererResult<Handle<SceneObject>> {
+        self.scene_tree.new_object(
+            mesh,
+            material,
+            self.device,
+            self.allocator.deref_mut(),
+            self.buffer_manager.clone(),
+        )
+    }
+
+    // Adds every group of an OBJ scene as an object of a new scene group called name.
+    // Materials are looked up by their usemtl names, groups with an unknown material or
+    // none get fallback.
+    pub fn new_obj_scene(
+        &mut self,
+        name: &str,
+        scene: ObjScene,
+        materials: &HashMap<String, Handle<Material>>,
+        fallback: Handle<Material>,
+    ) -> RendererResult<ObjSceneInstance> {
+        let mut instance = ObjSceneInstance {
+            group: self.scene_tree.create_group(name),
+            objects: Vec::with_capacity(scene.groups.len()),
+            meshes: Vec::with_capacity(scene.groups.len()),
+        };
+        for group in scene.groups {
+            let material = match &group.material {
+                Some(material) => materials.get(material).copied().unwrap_or_else(|| {
+                    warn!(
+                        "{}: no material {} for {}, using the fallback",
+                        name, material, group.name
+                    );
+                    fallback
+                }),
+                None => fallback,
+            };
+            let mesh = self.meshs.add_mesh(
+                group.mesh,
+                self.device,
+                self.allocator.deref_mut(),
+                self.buffer_manager.clone(),
+            )?;
+            let object = self.new_object(mesh, material)?;
+            self.scene_tree.add_to_group(instance.group, object)?;
+            instance.meshes.push(mesh);
+            instance.objects.push((group.name, object));
+        }
+        Ok(instance)
+    }
+
+    // Changes are written to the object data when the guard is dropped
+    pub fn get_object_mut(
+        &mut self,
+        handle: Handle<SceneObject>,
+    ) -> Option<SceneObjectMutGuard<'_>> {
+        self.scene_tree
+            .get_object_mut(handle, self.allocator.deref_mut())
+    }
+}
@@ -1,34 +1,70 @@
 use core::slice;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use ash::vk;
-use gpu_allocator::{vulkan::Allocator, MemoryLocation};
+use gpu_allocator::vulkan::Allocator;
+use log::warn;
 use nalgebra_glm as glm;
 
 use super::{
     buffer::{Buffer, BufferManager},
-    error::{InvalidHandle, RendererError},
+    culling::{Aabb, CullingMode},
+    error::{InvalidHandle, ObjectParamsError, RendererError},
     material::Material,
     mesh::Mesh,
+    object_data::{
+        ObjectDataBuffer, ObjectUploadStats, MAX_OBJECT_PARAMS_SIZE, OBJECT_PARAMS_STRIDE,
+    },
+    probe::{self, ProbeBlend},
+    stats::FrameEvents,
     utils::{Handle, HandleArray},
+    velocity::PreviousFrame,
     RendererResult,
 };
 
+// The layer mask of new objects and cameras, every object in every view
+pub const ALL_LAYERS: u32 = u32::MAX;
+
+// A bit of SceneObject::flags: the directional, point and spot lights don't light the
+// object, only the light probes and a lightmap do, see Renderer::bake_lightmaps
+pub const NO_DYNAMIC_LIGHTING: u32 = 1 << 31;
+
 #[allow(dead_code)]
 #[derive(Debug)]
 pub struct InstanceData {
     pub model_matrix: [[f32; 4]; 4],
     pub inverse_model_matrix: [[f32; 4]; 4],
+    // The model matrix that was used the last time this object was rendered
+    pub previous_model_matrix: [[f32; 4]; 4],
+    // The light probes the object blends, see probe::pack_blend_weights
+    pub probes: [u32; 4],
+    // SceneObject::flags, the shaders only look at NO_DYNAMIC_LIGHTING
+    pub flags: u32,
 }
 
 impl InstanceData {
-    pub fn new(model: glm::Mat4) -> Self {
+    pub fn new(model: glm::Mat4, previous_model: glm::Mat4) -> Self {
         InstanceData {
             model_matrix: model.into(),
             inverse_model_matrix: model.try_inverse().expect("Could not get inverse!").into(),
+            previous_model_matrix: previous_model.into(),
+            // All weights 0, no ambient light
+            probes: [0; 4],
+            flags: 0,
         }
     }
 
+    pub fn with_probes(mut self, probes: [u32; 4]) -> Self {
+        self.probes = probes;
+        self
+    }
+
+    pub fn with_flags(mut self, flags: u32) -> Self {
+        self.flags = flags;
+        self
+    }
+
     pub fn as_slice(&self) -> &[u8] {
         unsafe {
             slice::from_raw_parts(
@@ -46,33 +82,208 @@ pub struct SceneObject {
     pub position: glm::Vec3,
     pub rotation: glm::Quat,
     pub scaling: glm::Vec3,
+    pub visible: bool,
+    pub culling: CullingMode,
+    // Free for the application to use, except for NO_DYNAMIC_LIGHTING
+    pub flags: u32,
+    // Drawn in a view only if this shares a bit with the view's camera mask
+    pub layer_mask: u32,
 
     transform_dirty: bool,
     transform: glm::Mat4,
     instance_data: InstanceData,
     global_transform: glm::Mat4,
-    instance_buffer: Buffer,
+    // The global transform of the last frame the object was rendered in
+    transform_history: PreviousFrame<glm::Mat4>,
+    // Where the instance data lives in the SceneTree's object data buffer, None while
+    // the object's chunk has freed its buffers
+    instance_slot: Option<u32>,
+    // See SceneTree::set_object_params, empty for zeros
+    object_params: Vec<u8>,
+    // See SceneTree::set_morph_weights, targets of the mesh that aren't in here weigh 0
+    morph_weights: Vec<(String, f32)>,
+    // The morph weights of the last frame the object was rendered in
+    morph_history: PreviousFrame<Vec<(String, f32)>>,
+    // What morph_history gave for the current frame, for the velocity pass
+    previous_morph_weights: Vec<(String, f32)>,
 
     parent: Option<Handle<SceneObject>>,
     children: Vec<Handle<SceneObject>>,
+    groups: Vec<Handle<SceneGroup>>,
+    chunk: Option<Handle<SceneChunk>>,
 }
 
 impl SceneObject {
-    fn update_instance(&mut self, allocator: &mut Allocator) -> RendererResult<()> {
-        self.instance_buffer
-            .fill(allocator, self.instance_data.as_slice())
+    fn new(
+        mesh: Handle<Mesh>,
+        material: Handle<Material>,
+        instance_data: InstanceData,
+        instance_slot: Option<u32>,
+    ) -> Self {
+        Self {
+            mesh,
+            material,
+            position: glm::Vec3::default(),
+            rotation: glm::Quat::identity(),
+            scaling: glm::Vec3::new(1.0, 1.0, 1.0),
+            visible: true,
+            culling: CullingMode::Automatic,
+            flags: 0,
+            layer_mask: ALL_LAYERS,
+            transform_dirty: Default::default(),
+            transform: glm::Mat4::identity(),
+            global_transform: glm::Mat4::identity(),
+            transform_history: PreviousFrame::default(),
+            instance_data,
+            instance_slot,
+            object_params: vec![],
+            morph_weights: vec![],
+            morph_history: PreviousFrame::default(),
+            previous_morph_weights: vec![],
+            parent: None,
+            children: Vec::new(),
+            groups: Vec::new(),
+            chunk: None,
+        }
+    }
+
+    // Uploaded with the next SceneTree::prepare_frame
+    fn update_instance(&self, object_data: &mut ObjectDataBuffer) {
+        if let Some(slot) = self.instance_slot {
+            object_data.write(slot, self.instance_data.as_slice());
+        }
+    }
+
+    // Lives in the same slot as the instance data
+    fn update_params(&self, object_params: &mut ObjectDataBuffer) {
+        if let Some(slot) = self.instance_slot {
+            object_params.write(slot, &self.object_params);
+        }
+    }
+
+    pub fn get_object_params(&self) -> &[u8] {
+        &self.object_params
+    }
+
+    pub fn get_morph_weights(&self) -> &[(String, f32)] {
+        &self.morph_weights
+    }
+
+    // The morph weights the object was drawn with in the previous frame, set by
+    // SceneTree::prepare_frame
+    pub fn get_previous_morph_weights(&self) -> &[(String, f32)] {
+        &self.previous_morph_weights
+    }
+
+    pub fn get_instance_slot(&self) -> Option<u32> {
+        self.instance_slot
+    }
+
+    pub fn get_global_transform(&self) -> glm::Mat4 {
+        self.global_transform
+    }
+
+    pub fn get_previous_transform(&self) -> glm::Mat4 {
+        self.transform_history.previous(self.global_transform)
+    }
+
+    pub fn get_instance_data(&self) -> &InstanceData {
+        &self.instance_data
+    }
+
+    pub fn get_parent(&self) -> Option<Handle<SceneObject>> {
+        self.parent
+    }
+
+    pub fn get_children(&self) -> &[Handle<SceneObject>] {
+        &self.children
+    }
+
+    pub fn get_groups(&self) -> &[Handle<SceneGroup>] {
+        &self.groups
     }
 
-    pub fn get_buffer(&self) -> &Buffer {
-        &self.instance_buffer
+    pub fn get_chunk(&self) -> Option<Handle<SceneChunk>> {
+        self.chunk
     }
 }
 
-impl Drop for SceneObject {
-    fn drop(&mut self) {
-        self.instance_buffer
-            .queue_free(None)
-            .expect("Could not free buffer");
+// Groups are tags on objects and are independent from the parent/child hierarchy
+#[derive(Debug)]
+pub struct SceneGroup {
+    name: String,
+    visible: bool,
+    members: Vec<Handle<SceneObject>>,
+}
+
+impl SceneGroup {
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn is_visible(&self) -> bool {
+        self.visible
+    }
+
+    pub fn get_members(&self) -> &[Handle<SceneObject>] {
+        &self.members
+    }
+}
+
+// What happens to the instance buffers of a chunk's objects while it is inactive
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ChunkPolicy {
+    #[default]
+    KeepBuffers,
+    // Freed once the chunk has been inactive for this long, and created again when
+    // the chunk is activated
+    FreeBuffersAfter(Duration),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChunkEvent {
+    Activated,
+    Deactivated,
+    BuffersFreed,
+    BuffersRestored,
+}
+
+// A region of the world whose objects are only drawn while the camera is close to
+// it. Objects belong to at most one chunk, objects without one are always active.
+#[derive(Debug)]
+pub struct SceneChunk {
+    name: String,
+    bounds: Aabb,
+    policy: ChunkPolicy,
+    active: bool,
+    inactive_since: Option<Instant>,
+    buffers_freed: bool,
+    members: Vec<Handle<SceneObject>>,
+}
+
+impl SceneChunk {
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn get_bounds(&self) -> &Aabb {
+        &self.bounds
+    }
+
+    pub fn get_policy(&self) -> ChunkPolicy {
+        self.policy
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    pub fn are_buffers_freed(&self) -> bool {
+        self.buffers_freed
+    }
+
+    pub fn get_members(&self) -> &[Handle<SceneObject>] {
+        &self.members
     }
 }
 
@@ -109,12 +320,92 @@ impl<'a> SceneObjectMutGuard<'a> {
     }
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct SceneTree {
     objects: HandleArray<SceneObject>,
+    groups: HandleArray<SceneGroup>,
+    chunks: HandleArray<SceneChunk>,
+    // Objects without an instance slot that left a chunk with freed buffers
+    pending_restore: Vec<Handle<SceneObject>>,
+    object_data: ObjectDataBuffer,
+    // Uses the slots of object_data
+    object_params: ObjectDataBuffer,
+    // Zeroed, for drawing meshes that are not scene objects
+    default_params_slot: Option<u32>,
+    events: FrameEvents,
+    // Bumped by every change, see Renderer::force_rerecord
+    generation: u64,
+    // Positions of the light probes, see Renderer::lights
+    probe_positions: Vec<glm::Vec3>,
+    probe_blend: ProbeBlend,
+}
+
+impl Default for SceneTree {
+    fn default() -> Self {
+        Self {
+            objects: Default::default(),
+            groups: Default::default(),
+            chunks: Default::default(),
+            pending_restore: Default::default(),
+            object_data: ObjectDataBuffer::new(
+                std::mem::size_of::<InstanceData>(),
+                vk::BufferUsageFlags::VERTEX_BUFFER,
+                "object-data",
+            ),
+            object_params: ObjectDataBuffer::new(
+                OBJECT_PARAMS_STRIDE,
+                vk::BufferUsageFlags::UNIFORM_BUFFER,
+                "object-params",
+            ),
+            default_params_slot: None,
+            events: Default::default(),
+            generation: 0,
+            probe_positions: Default::default(),
+            probe_blend: Default::default(),
+        }
+    }
 }
 
 impl SceneTree {
+    pub fn allocation_hash(&self) -> u64 {
+        self.objects.allocation_hash()
+    }
+
+    pub(crate) fn take_frame_events(&mut self) -> FrameEvents {
+        std::mem::take(&mut self.events)
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    // Object parameters are bound at offsets of this many bytes, which the renderer sets to
+    // the uniform buffer offset alignment of the device before the first object exists
+    pub(crate) fn set_object_params_stride(&mut self, stride: usize) {
+        self.object_params
+            .set_stride(stride.max(MAX_OBJECT_PARAMS_SIZE));
+    }
+
+    // Keeps the instance data and object parameters in device local memory. Only before
+    // the first object exists, see ObjectDataBuffer::set_device_local
+    pub(crate) fn set_device_local_object_data(&mut self, device_local: bool) {
+        self.object_data.set_device_local(device_local);
+        self.object_params.set_device_local(device_local);
+    }
+
+    // A slot of the instance data and the same one of the object parameters
+    fn allocate_slot(
+        object_data: &mut ObjectDataBuffer,
+        object_params: &mut ObjectDataBuffer,
+        device: &ash::Device,
+        allocator: &mut Allocator,
+        buffer_manager: Arc<Mutex<BufferManager>>,
+    ) -> RendererResult<u32> {
+        let slot = object_data.allocate_slot(device, allocator, buffer_manager.clone())?;
+        object_params.reserve_slot(slot, device, allocator, buffer_manager)?;
+        Ok(slot)
+    }
+
     pub fn new_object(
         &mut self,
         mesh: Handle<Mesh>,
@@ -123,41 +414,583 @@ impl SceneTree {
         allocator: &mut Allocator,
         buffer_manager: Arc<Mutex<BufferManager>>,
     ) -> RendererResult<Handle<SceneObject>> {
-        let instance_buffer = BufferManager::new_buffer(
-            buffer_manager,
+        if self.default_params_slot.is_none() {
+            let slot = Self::allocate_slot(
+                &mut self.object_data,
+                &mut self.object_params,
+                device,
+                allocator,
+                buffer_manager.clone(),
+            )?;
+            self.object_params.write(slot, &[]);
+            self.default_params_slot = Some(slot);
+        }
+        let instance_slot = Self::allocate_slot(
+            &mut self.object_data,
+            &mut self.object_params,
             device,
             allocator,
-            std::mem::size_of::<InstanceData>() as u64,
-            vk::BufferUsageFlags::VERTEX_BUFFER,
-            MemoryLocation::CpuToGpu,
-            "instance-buffer",
+            buffer_manager,
         )?;
-        let scene_object = SceneObject {
+        let scene_object = SceneObject::new(
             mesh,
             material,
-            position: glm::Vec3::default(),
-            rotation: glm::Quat::identity(),
-            scaling: glm::Vec3::new(1.0, 1.0, 1.0),
-            transform_dirty: Default::default(),
-            transform: glm::Mat4::identity(),
-            global_transform: glm::Mat4::identity(),
-            instance_data: InstanceData::new(glm::Mat4::identity()),
-            instance_buffer,
-            parent: None,
-            children: Vec::new(),
-        };
+            InstanceData::new(glm::Mat4::identity(), glm::Mat4::identity()).with_probes(
+                probe::pack_blend_weights(&probe::blend_weights(
+                    &self.probe_positions,
+                    &glm::Vec3::zeros(),
+                    self.probe_blend,
+                )),
+            ),
+            Some(instance_slot),
+        );
+        scene_object.update_params(&mut self.object_params);
+        self.events |= FrameEvents::SCENE_CHANGED;
+        self.generation += 1;
         Ok(self.objects.insert(scene_object))
     }
 
+    // Removes the object and all of its children, including their group memberships
+    pub fn remove_object(&mut self, handle: Handle<SceneObject>) -> RendererResult<()> {
+        let (parent, children, groups, chunk) = {
+            let obj = self
+                .objects
+                .get_mut(handle)
+                .ok_or::<RendererError>(InvalidHandle.into())?;
+            (
+                obj.parent.take(),
+                std::mem::take(&mut obj.children),
+                std::mem::take(&mut obj.groups),
+                obj.chunk.take(),
+            )
+        };
+        for child in children {
+            self.remove_object(child)?;
+        }
+        if let Some(parent) = parent.and_then(|p| self.objects.get_mut(p)) {
+            parent.children.retain(|c| *c != handle);
+        }
+        for group in groups {
+            if let Some(group) = self.groups.get_mut(group) {
+                group.members.retain(|m| *m != handle);
+            }
+        }
+        if let Some(chunk) = chunk.and_then(|c| self.chunks.get_mut(c)) {
+            chunk.members.retain(|m| *m != handle);
+        }
+        let obj = self.objects.remove(handle)?;
+        if let Some(slot) = obj.instance_slot {
+            self.object_data.free_slot(slot);
+        }
+        self.events |= FrameEvents::SCENE_CHANGED;
+        self.generation += 1;
+        Ok(())
+    }
+
+    // Removes only the object, its children move up to its parent, or become roots. They
+    // keep their local transform, so they now move with the new parent.
+    pub fn remove_object_keep_children(
+        &mut self,
+        handle: Handle<SceneObject>,
+        allocator: &mut Allocator,
+    ) -> RendererResult<()> {
+        let (parent, children) = {
+            let obj = self
+                .objects
+                .get_mut(handle)
+                .ok_or::<RendererError>(InvalidHandle.into())?;
+            (obj.parent, std::mem::take(&mut obj.children))
+        };
+        for child in children.iter() {
+            if let Some(child) = self.objects.get_mut(*child) {
+                child.parent = parent;
+            }
+        }
+        if let Some(parent) = parent.and_then(|p| self.objects.get_mut(p)) {
+            parent.children.extend(children.iter().copied());
+        }
+        self.remove_object(handle)?;
+        for child in children {
+            self.update_transform(child, allocator)?;
+        }
+        Ok(())
+    }
+
+    // An object is drawn if it and every group it belongs to is visible, and its chunk
+    // is active
+    pub fn is_object_visible(&self, handle: Handle<SceneObject>) -> bool {
+        self.objects.get(handle).map_or(false, |obj| {
+            obj.visible
+                && Self::is_chunk_active(&self.chunks, obj.chunk)
+                && obj
+                    .groups
+                    .iter()
+                    .all(|g| self.groups.get(*g).map_or(true, |g| g.visible))
+        })
+    }
+
+    // Visible and on one of the layers of the view
+    pub fn is_object_visible_in(&self, handle: Handle<SceneObject>, view_mask: u32) -> bool {
+        self.objects
+            .get(handle)
+            .map_or(false, |obj| obj.layer_mask & view_mask != 0)
+            && self.is_object_visible(handle)
+    }
+
+    pub fn set_layer(&mut self, handle: Handle<SceneObject>, mask: u32) -> RendererResult<()> {
+        self.objects
+            .get_mut(handle)
+            .ok_or::<RendererError>(InvalidHandle.into())?
+            .layer_mask = mask;
+        self.events |= FrameEvents::SCENE_CHANGED;
+        self.generation += 1;
+        Ok(())
+    }
+
+    // Hides or shows the object, and all of its descendants too if children is set. The
+    // buffers and handles stay, the object is only skipped while drawing.
+    pub fn set_visible(
+        &mut self,
+        handle: Handle<SceneObject>,
+        visible: bool,
+        children: bool,
+    ) -> RendererResult<()> {
+        let obj = self
+            .objects
+            .get_mut(handle)
+            .ok_or::<RendererError>(InvalidHandle.into())?;
+        obj.visible = visible;
+        let descendants = if children {
+            obj.children.clone()
+        } else {
+            vec![]
+        };
+        for child in descendants {
+            self.set_visible(child, visible, true)?;
+        }
+        self.events |= FrameEvents::SCENE_CHANGED;
+        self.generation += 1;
+        Ok(())
+    }
+
+    fn is_chunk_active(
+        chunks: &HandleArray<SceneChunk>,
+        chunk: Option<Handle<SceneChunk>>,
+    ) -> bool {
+        chunk.and_then(|c| chunks.get(c)).map_or(true, |c| c.active)
+    }
+
+    // Chunks start out active, Renderer::render updates them from the camera position
+    pub fn create_chunk<S: Into<String>>(&mut self, name: S, bounds: Aabb) -> Handle<SceneChunk> {
+        self.chunks.insert(SceneChunk {
+            name: name.into(),
+            bounds,
+            policy: ChunkPolicy::default(),
+            active: true,
+            inactive_since: None,
+            buffers_freed: false,
+            members: Vec::new(),
+        })
+    }
+
+    pub fn get_chunk(&self, chunk: Handle<SceneChunk>) -> Option<&SceneChunk> {
+        self.chunks.get(chunk)
+    }
+
+    pub fn find_chunk(&self, name: &str) -> Option<Handle<SceneChunk>> {
+        self.chunks
+            .iter_with_handles()
+            .find(|(_, c)| c.name == name)
+            .map(|(h, _)| h)
+    }
+
+    pub fn set_chunk_policy(
+        &mut self,
+        chunk: Handle<SceneChunk>,
+        policy: ChunkPolicy,
+    ) -> RendererResult<()> {
+        self.chunks
+            .get_mut(chunk)
+            .ok_or::<RendererError>(InvalidHandle.into())?
+            .policy = policy;
+        Ok(())
+    }
+
+    // Deletes the chunk itself, its members are kept and become always active
+    pub fn delete_chunk(&mut self, chunk: Handle<SceneChunk>) -> RendererResult<()> {
+        let chunk_data = self.chunks.remove(chunk)?;
+        for member in chunk_data.members {
+            if let Some(obj) = self.objects.get_mut(member) {
+                obj.chunk = None;
+                if obj.instance_slot.is_none() {
+                    self.pending_restore.push(member);
+                }
+            }
+        }
+        self.events |= FrameEvents::SCENE_CHANGED;
+        self.generation += 1;
+        Ok(())
+    }
+
+    // Moves the object out of its current chunk, if it has one
+    pub fn assign_to_chunk(
+        &mut self,
+        chunk: Handle<SceneChunk>,
+        object: Handle<SceneObject>,
+    ) -> RendererResult<()> {
+        if self.chunks.get(chunk).is_none() {
+            return Err(InvalidHandle.into());
+        }
+        self.remove_from_chunk(object)?;
+        let chunk_data = self
+            .chunks
+            .get_mut(chunk)
+            .ok_or::<RendererError>(InvalidHandle.into())?;
+        chunk_data.members.push(object);
+        let obj = self
+            .objects
+            .get_mut(object)
+            .ok_or::<RendererError>(InvalidHandle.into())?;
+        obj.chunk = Some(chunk);
+        // A chunk with freed buffers frees the new member's buffer on the next update
+        if !chunk_data.buffers_freed && obj.instance_slot.is_none() {
+            self.pending_restore.push(object);
+        }
+        self.events |= FrameEvents::SCENE_CHANGED;
+        self.generation += 1;
+        Ok(())
+    }
+
+    pub fn remove_from_chunk(&mut self, object: Handle<SceneObject>) -> RendererResult<()> {
+        let obj = self
+            .objects
+            .get_mut(object)
+            .ok_or::<RendererError>(InvalidHandle.into())?;
+        if let Some(chunk) = obj.chunk.take() {
+            if let Some(chunk) = self.chunks.get_mut(chunk) {
+                chunk.members.retain(|m| *m != object);
+            }
+            if obj.instance_slot.is_none() {
+                self.pending_restore.push(object);
+            }
+            self.events |= FrameEvents::SCENE_CHANGED;
+            self.generation += 1;
+        }
+        Ok(())
+    }
+
+    // Activates the chunks within radius of the position and deactivates the rest,
+    // freeing and restoring instance buffers as their policies say. Returns every
+    // change in the order it happened.
+    pub(crate) fn update_chunks(
+        &mut self,
+        position: &glm::Vec3,
+        radius: f32,
+        now: Instant,
+        device: &ash::Device,
+        allocator: &mut Allocator,
+        buffer_manager: Arc<Mutex<BufferManager>>,
+    ) -> RendererResult<Vec<(Handle<SceneChunk>, ChunkEvent)>> {
+        let mut changes = vec![];
+        for (handle, chunk) in self.chunks.iter_mut_with_handles() {
+            let in_range = chunk.bounds.distance_to_point(position) <= radius;
+            if in_range && !chunk.active {
+                chunk.active = true;
+                chunk.inactive_since = None;
+                for member in chunk.members.iter() {
+                    let obj = match self.objects.get_mut(*member) {
+                        Some(obj) => obj,
+                        None => continue,
+                    };
+                    if obj.instance_slot.is_none() {
+                        obj.instance_slot = Some(Self::allocate_slot(
+                            &mut self.object_data,
+                            &mut self.object_params,
+                            device,
+                            allocator,
+                            buffer_manager.clone(),
+                        )?);
+                        obj.update_params(&mut self.object_params);
+                    }
+                    // Whatever moved while inactive is uploaded now, without motion
+                    // from where it was last drawn
+                    obj.transform_history.reset();
+                    obj.morph_history.reset();
+                    obj.instance_data =
+                        InstanceData::new(obj.global_transform, obj.global_transform)
+                            .with_probes(obj.instance_data.probes)
+                            .with_flags(obj.flags);
+                    obj.update_instance(&mut self.object_data);
+                }
+                if chunk.buffers_freed {
+                    chunk.buffers_freed = false;
+                    changes.push((handle, ChunkEvent::BuffersRestored));
+                }
+                changes.push((handle, ChunkEvent::Activated));
+            } else if !in_range && chunk.active {
+                chunk.active = false;
+                chunk.inactive_since = Some(now);
+                changes.push((handle, ChunkEvent::Deactivated));
+            }
+
+            if let (ChunkPolicy::FreeBuffersAfter(grace_period), Some(since)) =
+                (chunk.policy, chunk.inactive_since)
+            {
+                if !chunk.buffers_freed && now.duration_since(since) >= grace_period {
+                    chunk.buffers_freed = true;
+                    changes.push((handle, ChunkEvent::BuffersFreed));
+                }
+            }
+            if chunk.buffers_freed {
+                // Also catches objects that were assigned after the buffers were freed
+                for member in chunk.members.iter() {
+                    if let Some(slot) = self
+                        .objects
+                        .get_mut(*member)
+                        .and_then(|obj| obj.instance_slot.take())
+                    {
+                        // The memory stays with the object data buffer, for other objects
+                        self.object_data.free_slot(slot);
+                    }
+                }
+            }
+        }
+
+        for object in std::mem::take(&mut self.pending_restore) {
+            let active = match self.objects.get(object) {
+                Some(obj) => Self::is_chunk_active(&self.chunks, obj.chunk),
+                None => continue,
+            };
+            let freed = self
+                .objects
+                .get(object)
+                .and_then(|obj| obj.chunk)
+                .and_then(|c| self.chunks.get(c))
+                .map_or(false, |c| c.buffers_freed);
+            if let Some(obj) = self.objects.get_mut(object) {
+                if obj.instance_slot.is_none() && !freed {
+                    obj.instance_slot = Some(Self::allocate_slot(
+                        &mut self.object_data,
+                        &mut self.object_params,
+                        device,
+                        allocator,
+                        buffer_manager.clone(),
+                    )?);
+                    obj.update_params(&mut self.object_params);
+                    if active {
+                        obj.update_instance(&mut self.object_data);
+                    }
+                }
+            }
+        }
+
+        if !changes.is_empty() {
+            self.events |= FrameEvents::SCENE_CHANGED;
+            self.generation += 1;
+        }
+        Ok(changes)
+    }
+
+    pub fn create_group<S: Into<String>>(&mut self, name: S) -> Handle<SceneGroup> {
+        self.groups.insert(SceneGroup {
+            name: name.into(),
+            visible: true,
+            members: Vec::new(),
+        })
+    }
+
+    pub fn get_group(&self, group: Handle<SceneGroup>) -> Option<&SceneGroup> {
+        self.groups.get(group)
+    }
+
+    pub fn find_group(&self, name: &str) -> Option<Handle<SceneGroup>> {
+        self.groups
+            .iter_with_handles()
+            .find(|(_, g)| g.name == name)
+            .map(|(h, _)| h)
+    }
+
+    // Deletes the group itself, its members are kept
+    pub fn delete_group(&mut self, group: Handle<SceneGroup>) -> RendererResult<()> {
+        let group_data = self.groups.remove(group)?;
+        for member in group_data.members {
+            if let Some(obj) = self.objects.get_mut(member) {
+                obj.groups.retain(|g| *g != group);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn add_to_group(
+        &mut self,
+        group: Handle<SceneGroup>,
+        object: Handle<SceneObject>,
+    ) -> RendererResult<()> {
+        let group_data = self
+            .groups
+            .get_mut(group)
+            .ok_or::<RendererError>(InvalidHandle.into())?;
+        let obj = self
+            .objects
+            .get_mut(object)
+            .ok_or::<RendererError>(InvalidHandle.into())?;
+        if !obj.groups.contains(&group) {
+            obj.groups.push(group);
+            group_data.members.push(object);
+        }
+        Ok(())
+    }
+
+    pub fn remove_from_group(
+        &mut self,
+        group: Handle<SceneGroup>,
+        object: Handle<SceneObject>,
+    ) -> RendererResult<()> {
+        let group_data = self
+            .groups
+            .get_mut(group)
+            .ok_or::<RendererError>(InvalidHandle.into())?;
+        let obj = self
+            .objects
+            .get_mut(object)
+            .ok_or::<RendererError>(InvalidHandle.into())?;
+        obj.groups.retain(|g| *g != group);
+        group_data.members.retain(|m| *m != object);
+        Ok(())
+    }
+
+    pub fn iter_group(
+        &self,
+        group: Handle<SceneGroup>,
+    ) -> impl Iterator<Item = Handle<SceneObject>> + '_ {
+        self.groups
+            .get(group)
+            .map(|g| g.members.as_slice())
+            .unwrap_or_default()
+            .iter()
+            .copied()
+    }
+
+    // Only flips the group flag, the members are checked against it while drawing
+    pub fn set_group_visible(
+        &mut self,
+        group: Handle<SceneGroup>,
+        visible: bool,
+    ) -> RendererResult<()> {
+        self.groups
+            .get_mut(group)
+            .ok_or::<RendererError>(InvalidHandle.into())?
+            .visible = visible;
+        self.events |= FrameEvents::SCENE_CHANGED;
+        self.generation += 1;
+        Ok(())
+    }
+
+    pub fn set_group_flags(&mut self, group: Handle<SceneGroup>, flags: u32) -> RendererResult<()> {
+        let group_data = self
+            .groups
+            .get(group)
+            .ok_or::<RendererError>(InvalidHandle.into())?;
+        for member in &group_data.members {
+            if let Some(obj) = self.objects.get_mut(*member) {
+                obj.flags = flags;
+                obj.instance_data.flags = flags;
+                if Self::is_chunk_active(&self.chunks, obj.chunk) {
+                    obj.update_instance(&mut self.object_data);
+                }
+            }
+        }
+        self.events |= FrameEvents::SCENE_CHANGED;
+        self.generation += 1;
+        Ok(())
+    }
+
+    pub fn set_group_layer(&mut self, group: Handle<SceneGroup>, mask: u32) -> RendererResult<()> {
+        let group_data = self
+            .groups
+            .get(group)
+            .ok_or::<RendererError>(InvalidHandle.into())?;
+        for member in &group_data.members {
+            if let Some(obj) = self.objects.get_mut(*member) {
+                obj.layer_mask = mask;
+            }
+        }
+        self.events |= FrameEvents::SCENE_CHANGED;
+        self.generation += 1;
+        Ok(())
+    }
+
+    // Applied to the local transform of every member. Members with an ancestor in the
+    // group are skipped, they already move with it.
+    pub fn transform_group(
+        &mut self,
+        group: Handle<SceneGroup>,
+        translation: &glm::Vec3,
+        rotation: &glm::Quat,
+        allocator: &mut Allocator,
+    ) -> RendererResult<()> {
+        let members = self
+            .groups
+            .get(group)
+            .ok_or::<RendererError>(InvalidHandle.into())?
+            .members
+            .clone();
+        for member in members {
+            if self.has_ancestor_in_group(member, group) {
+                continue;
+            }
+            if let Some(obj) = self.objects.get_mut(member) {
+                obj.position += translation;
+                obj.rotation = rotation * obj.rotation;
+            }
+            self.update_transform(member, allocator)?;
+        }
+        Ok(())
+    }
+
+    fn has_ancestor_in_group(
+        &self,
+        object: Handle<SceneObject>,
+        group: Handle<SceneGroup>,
+    ) -> bool {
+        let mut parent = self.objects.get(object).and_then(|obj| obj.parent);
+        while let Some(obj) = parent.and_then(|p| self.objects.get(p)) {
+            if obj.groups.contains(&group) {
+                return true;
+            }
+            parent = obj.parent;
+        }
+        false
+    }
+
+    // Removes every member (and their children), the group itself stays around
+    pub fn remove_group_objects(&mut self, group: Handle<SceneGroup>) -> RendererResult<()> {
+        let members = self
+            .groups
+            .get(group)
+            .ok_or::<RendererError>(InvalidHandle.into())?
+            .members
+            .clone();
+        for member in members {
+            // A member may already be gone as the child of an earlier member
+            if self.objects.get(member).is_some() {
+                self.remove_object(member)?;
+            }
+        }
+        Ok(())
+    }
+
     pub fn get_object(&self, handle: Handle<SceneObject>) -> Option<&SceneObject> {
         self.objects.get(handle)
     }
 
+    // Counts as a change, even if nothing is written
     pub fn get_object_mut<'a>(
         &'a mut self,
         handle: Handle<SceneObject>,
         allocator: &'a mut Allocator,
     ) -> Option<SceneObjectMutGuard<'a>> {
+        self.generation += 1;
         let self_ptr = self as *mut SceneTree;
         self.objects.get_mut(handle).map(|obj| SceneObjectMutGuard {
             allocator,
@@ -179,6 +1012,9 @@ impl SceneTree {
                 .expect("Invalid parent handle?")
                 .global_transform
         });
+        let chunks = &self.chunks;
+        let probes = &self.probe_positions;
+        let probe_blend = self.probe_blend;
         let children_handles = if let Some(obj) = self.objects.get_mut(handle) {
             obj.transform = glm::Mat4::new_translation(&obj.position)
                 * glm::quat_to_mat4(&obj.rotation)
@@ -188,9 +1024,17 @@ impl SceneTree {
             } else {
                 obj.global_transform = obj.transform;
             }
-            obj.instance_data = InstanceData::new(obj.global_transform);
+            obj.instance_data =
+                InstanceData::new(obj.global_transform, obj.get_previous_transform())
+                    .with_probes(Self::object_probes(probes, probe_blend, obj))
+                    .with_flags(obj.flags);
             obj.transform_dirty = false;
-            obj.update_instance(allocator)?;
+            // Inactive chunks upload their objects when they are activated
+            if Self::is_chunk_active(chunks, obj.chunk) {
+                obj.update_instance(&mut self.object_data);
+            }
+            self.events |= FrameEvents::SCENE_CHANGED;
+            self.generation += 1;
             obj.children.clone()
         } else {
             return Err(InvalidHandle.into());
@@ -203,15 +1047,344 @@ impl SceneTree {
         Ok(())
     }
 
+    // The probes an object blends are picked by the origin of the object
+    fn object_probes(probes: &[glm::Vec3], blend: ProbeBlend, obj: &SceneObject) -> [u32; 4] {
+        let position = (obj.global_transform * glm::vec4(0.0, 0.0, 0.0, 1.0)).xyz();
+        probe::pack_blend_weights(&probe::blend_weights(probes, &position, blend))
+    }
+
+    // Picks the probes of every object again, uploaded with the next prepare_frame
+    pub(crate) fn set_light_probes(&mut self, positions: Vec<glm::Vec3>, blend: ProbeBlend) {
+        if positions == self.probe_positions && blend == self.probe_blend {
+            return;
+        }
+        if positions.len() > probe::MAX_LIGHT_PROBES {
+            warn!(
+                "More than {} light probes, only the first ones are used",
+                probe::MAX_LIGHT_PROBES
+            );
+        }
+        self.probe_positions = positions;
+        self.probe_blend = blend;
+        for obj in self.objects.iter_mut() {
+            let probes = Self::object_probes(&self.probe_positions, blend, obj);
+            if obj.instance_data.probes != probes {
+                obj.instance_data.probes = probes;
+                // Inactive chunks upload their objects when they are activated
+                if Self::is_chunk_active(&self.chunks, obj.chunk) {
+                    obj.update_instance(&mut self.object_data);
+                }
+            }
+        }
+        self.events |= FrameEvents::SCENE_CHANGED;
+        self.generation += 1;
+    }
+
+    // Uploads what was written since the last frame without starting a new one, for
+    // drawing the scene between frames
+    pub(crate) fn flush_object_data(
+        &mut self,
+        allocator: &mut Allocator,
+    ) -> RendererResult<ObjectUploadStats> {
+        let instances = self.object_data.flush(allocator)?;
+        let params = self.object_params.flush(allocator)?;
+        Ok(ObjectUploadStats {
+            dirty_slots: instances.dirty_slots + params.dirty_slots,
+            ranges: instances.ranges + params.ranges,
+            bytes_copied: instances.bytes_copied + params.bytes_copied,
+            full_upload: instances.full_upload || params.full_upload,
+        })
+    }
+
+    // Called once per rendered frame, before the instance data is used.
+    // The transform each object is drawn with this frame becomes its previous
+    // transform for the next frame, which also covers frames where it didn't move.
+    // Everything written since the last frame is uploaded here.
+    pub(crate) fn prepare_frame(
+        &mut self,
+        allocator: &mut Allocator,
+    ) -> RendererResult<ObjectUploadStats> {
+        for obj in self.objects.iter_mut() {
+            if !Self::is_chunk_active(&self.chunks, obj.chunk) {
+                continue;
+            }
+            let previous: [[f32; 4]; 4] =
+                obj.transform_history.advance(obj.global_transform).into();
+            if obj.instance_data.previous_model_matrix != previous {
+                obj.instance_data.previous_model_matrix = previous;
+                obj.update_instance(&mut self.object_data);
+            }
+            obj.previous_morph_weights = obj.morph_history.advance(obj.morph_weights.clone());
+        }
+        self.flush_object_data(allocator)
+    }
+
+    // The instance data of every object, bound at the offset of the object's slot
+    pub fn get_instance_buffer(&self) -> Option<&Buffer> {
+        self.object_data.buffer()
+    }
+
+    pub fn get_instance_offset(&self, slot: u32) -> u64 {
+        self.object_data.offset(slot)
+    }
+
+    // Copied into the object_params block of the object's template, as is, so T has to
+    // be laid out like the block, e.g. #[repr(C)] with the std140 padding. Only checked
+    // against MAX_OBJECT_PARAMS_SIZE, Renderer::set_object_params also checks that the
+    // block of the object's template has the same size.
+    pub fn set_object_params<T: Copy>(
+        &mut self,
+        handle: Handle<SceneObject>,
+        params: &T,
+    ) -> RendererResult<()> {
+        let size = std::mem::size_of::<T>();
+        if size > MAX_OBJECT_PARAMS_SIZE {
+            return Err(ObjectParamsError(format!(
+                "{} bytes of parameters don't fit into the {} bytes every object has room for",
+                size, MAX_OBJECT_PARAMS_SIZE
+            ))
+            .into());
+        }
+        let obj = self
+            .objects
+            .get_mut(handle)
+            .ok_or::<RendererError>(InvalidHandle.into())?;
+        obj.object_params =
+            unsafe { slice::from_raw_parts(params as *const T as *const u8, size) }.to_vec();
+        obj.update_params(&mut self.object_params);
+        self.generation += 1;
+        Ok(())
+    }
+
+    // Back to zeros
+    pub fn clear_object_params(&mut self, handle: Handle<SceneObject>) -> RendererResult<()> {
+        let obj = self
+            .objects
+            .get_mut(handle)
+            .ok_or::<RendererError>(InvalidHandle.into())?;
+        obj.object_params.clear();
+        obj.update_params(&mut self.object_params);
+        self.generation += 1;
+        Ok(())
+    }
+
+    // Sets the weights of the named morph targets of the object's mesh and leaves the
+    // others as they are
+    pub fn set_morph_weights(
+        &mut self,
+        handle: Handle<SceneObject>,
+        weights: &[(&str, f32)],
+    ) -> RendererResult<()> {
+        let obj = self
+            .objects
+            .get_mut(handle)
+            .ok_or::<RendererError>(InvalidHandle.into())?;
+        for (name, weight) in weights.iter() {
+            match obj.morph_weights.iter_mut().find(|(n, _)| n == name) {
+                Some(entry) => entry.1 = *weight,
+                None => obj.morph_weights.push((name.to_string(), *weight)),
+            }
+        }
+        self.generation += 1;
+        Ok(())
+    }
+
+    // Back to the mesh without any targets applied
+    pub fn clear_morph_weights(&mut self, handle: Handle<SceneObject>) -> RendererResult<()> {
+        let obj = self
+            .objects
+            .get_mut(handle)
+            .ok_or::<RendererError>(InvalidHandle.into())?;
+        obj.morph_weights.clear();
+        self.generation += 1;
+        Ok(())
+    }
+
+    // The object parameters of every object, bound at the offset of the object's slot
+    pub fn get_object_params_buffer(&self) -> Option<&Buffer> {
+        self.object_params.buffer()
+    }
+
+    // Dynamic offsets are 32 bit
+    pub fn get_object_params_offset(&self, slot: u32) -> u32 {
+        self.object_params.offset(slot) as u32
+    }
+
+    // Zeroed parameters for meshes drawn without an object, None until the first object
+    // is created
+    pub fn get_default_object_params_offset(&self) -> Option<u32> {
+        self.default_params_slot
+            .map(|slot| self.get_object_params_offset(slot))
+    }
+
+    // Fraction of slots that can be dirty before a frame uploads all of them at once
+    pub fn set_full_upload_fraction(&mut self, fraction: f32) {
+        self.object_data.full_upload_fraction = fraction.clamp(0.0, 1.0);
+        self.object_params.full_upload_fraction = fraction.clamp(0.0, 1.0);
+    }
+
+    pub fn iter_with_handles(&self) -> impl Iterator<Item = (Handle<SceneObject>, &SceneObject)> {
+        self.objects.iter_with_handles()
+    }
+
+    // The objects drawn in a view with the layer mask, in the order they are stored
+    pub fn iter_visible(
+        &self,
+        layer_mask: u32,
+    ) -> impl Iterator<Item = (Handle<SceneObject>, &SceneObject)> {
+        self.objects
+            .iter_with_handles()
+            .filter(move |(handle, obj)| {
+                obj.layer_mask & layer_mask != 0 && self.is_object_visible(*handle)
+            })
+    }
+
     pub fn iter(&self) -> std::slice::Iter<'_, SceneObject> {
         self.objects.iter()
     }
 
+    // Counts as a change, like get_object_mut
     pub fn iter_mut(&mut self) -> std::slice::IterMut<'_, SceneObject> {
+        self.generation += 1;
         self.objects.iter_mut()
     }
 
     pub fn destroy(&mut self) {
+        self.chunks.clear();
+        self.pending_restore.clear();
+        self.groups.clear();
         self.objects.clear();
+        self.object_data
+            .destroy()
+            .expect("Could not free object data buffer");
+        self.object_params
+            .destroy()
+            .expect("Could not free object parameter buffer");
+        self.default_params_slot = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An object without buffers, which is enough to walk the tree
+    fn insert(tree: &mut SceneTree, parent: Option<Handle<SceneObject>>) -> Handle<SceneObject> {
+        let mut obj = SceneObject::new(
+            Handle::for_test(1),
+            Handle::for_test(1),
+            InstanceData::new(glm::Mat4::identity(), glm::Mat4::identity()),
+            None,
+        );
+        obj.parent = parent;
+        let handle = tree.objects.insert(obj);
+        if let Some(parent) = parent {
+            tree.objects.get_mut(parent).unwrap().children.push(handle);
+        }
+        handle
+    }
+
+    fn visible(tree: &SceneTree) -> Vec<Handle<SceneObject>> {
+        tree.iter_visible(ALL_LAYERS)
+            .map(|(handle, _)| handle)
+            .collect()
+    }
+
+    #[test]
+    fn hiding_an_object_skips_only_it() {
+        let mut tree = SceneTree::default();
+        let car = insert(&mut tree, None);
+        let wheel = insert(&mut tree, Some(car));
+        let hubcap = insert(&mut tree, Some(wheel));
+        let road = insert(&mut tree, None);
+        assert_eq!(visible(&tree), vec![car, wheel, hubcap, road]);
+
+        tree.set_visible(car, false, false).unwrap();
+        assert_eq!(visible(&tree), vec![wheel, hubcap, road]);
+        tree.set_visible(hubcap, false, false).unwrap();
+        assert_eq!(visible(&tree), vec![wheel, road]);
+        tree.set_visible(car, true, false).unwrap();
+        assert_eq!(visible(&tree), vec![car, wheel, road]);
+    }
+
+    #[test]
+    fn hiding_a_subtree_skips_every_descendant() {
+        let mut tree = SceneTree::default();
+        let car = insert(&mut tree, None);
+        let wheel = insert(&mut tree, Some(car));
+        let road = insert(&mut tree, None);
+        let hubcap = insert(&mut tree, Some(wheel));
+
+        tree.set_visible(car, false, true).unwrap();
+        assert_eq!(visible(&tree), vec![road]);
+        // Showing a child again doesn't show its parent
+        tree.set_visible(wheel, true, true).unwrap();
+        assert_eq!(visible(&tree), vec![wheel, road, hubcap]);
+        tree.set_visible(car, true, true).unwrap();
+        assert_eq!(visible(&tree), vec![car, wheel, road, hubcap]);
+        // The handles stay valid while hidden
+        assert!(tree.get_object(hubcap).is_some());
+    }
+
+    #[test]
+    fn hidden_groups_and_other_layers_are_skipped() {
+        let mut tree = SceneTree::default();
+        let first = insert(&mut tree, None);
+        let second = insert(&mut tree, None);
+        let third = insert(&mut tree, None);
+        let group = tree.create_group("hidden");
+        tree.add_to_group(group, second).unwrap();
+        tree.set_group_visible(group, false).unwrap();
+        tree.set_layer(third, 0b10).unwrap();
+
+        assert_eq!(visible(&tree), vec![first, third]);
+        let on_first_layer = tree
+            .iter_visible(0b01)
+            .map(|(handle, _)| handle)
+            .collect::<Vec<_>>();
+        assert_eq!(on_first_layer, vec![first]);
+    }
+
+    #[test]
+    fn disjoint_layer_masks_partition_the_visible_objects() {
+        let mut tree = SceneTree::default();
+        let objects = (0..6)
+            .map(|i| {
+                let object = insert(&mut tree, None);
+                tree.set_layer(object, 1 << (i % 2)).unwrap();
+                object
+            })
+            .collect::<Vec<_>>();
+        tree.set_visible(objects[4], false, false).unwrap();
+
+        let on = |mask: u32| {
+            tree.iter_visible(mask)
+                .map(|(handle, _)| handle)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(on(0b01), [objects[0], objects[2]]);
+        assert_eq!(on(0b10), [objects[1], objects[3], objects[5]]);
+        let mut both = [on(0b01), on(0b10)].concat();
+        both.sort_by_key(|handle| handle.id());
+        assert_eq!(both, visible(&tree));
+    }
+
+    #[test]
+    fn group_flags_reach_the_instance_data() {
+        let mut tree = SceneTree::default();
+        let baked = insert(&mut tree, None);
+        let dynamic = insert(&mut tree, None);
+        let group = tree.create_group("baked");
+        tree.add_to_group(group, baked).unwrap();
+        tree.set_group_flags(group, NO_DYNAMIC_LIGHTING | 1)
+            .unwrap();
+
+        let object = tree.get_object(baked).unwrap();
+        assert_eq!(object.flags, NO_DYNAMIC_LIGHTING | 1);
+        assert_eq!(object.get_instance_data().flags, NO_DYNAMIC_LIGHTING | 1);
+        assert_eq!(
+            tree.get_object(dynamic).unwrap().get_instance_data().flags,
+            0
+        );
     }
 }
@@ -0,0 +1,254 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use nalgebra as na;
+use nalgebra_glm as glm;
+use ron::ser::PrettyConfig;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    error::{RendererError, SceneFileError},
+    light::{DirectionalLight, Light, PointLight, SpotLight},
+    loading::MeshSource,
+    material::{ParameterValue, ShaderParameters},
+    material_file::MaterialFile,
+    RendererResult,
+};
+
+// A scene as written by Renderer::save_scene and read by Renderer::load_scene_file, in
+// RON:
+//
+// (
+//     meshes: {"mesh1": Cube, "mesh2": Obj("models/ship.obj")},
+//     materials: {
+//         "hull": (
+//             base_template: "default",
+//             textures: [Some("textures/hull.png")],
+//             parameters: {"roughness": 0.5},
+//             uv_offset: (0.0, 0.0),
+//             uv_scale: (1.0, 1.0),
+//             uv_rotation: 0.0,
+//         ),
+//     },
+//     objects: [
+//         (
+//             mesh: "mesh2",
+//             material: "hull",
+//             position: (0.0, 1.0, 0.0),
+//             rotation: (0.0, 0.0, 0.0, 1.0),
+//             scaling: (1.0, 1.0, 1.0),
+//             parent: None,
+//             visible: true,
+//         ),
+//     ],
+//     lights: [Point(position: (0.0, 3.0, 0.0), luminous_flux: (800.0, 800.0, 800.0))],
+// )
+//
+// Sections missing from a file are empty. Relative paths are relative to the scene file.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SceneFile {
+    pub meshes: BTreeMap<String, MeshSource>,
+    pub materials: BTreeMap<String, SceneFileMaterial>,
+    // Parents come before their children
+    pub objects: Vec<SceneFileObject>,
+    pub lights: Vec<SceneFileLight>,
+}
+
+// Like a MaterialFile, but slots can be left to their default texture
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SceneFileMaterial {
+    pub base_template: String,
+    // In the binding order of the template's texture slots, None for the default
+    pub textures: Vec<Option<PathBuf>>,
+    pub parameters: BTreeMap<String, ParameterValue>,
+    pub uv_offset: [f32; 2],
+    pub uv_scale: [f32; 2],
+    pub uv_rotation: f32,
+}
+
+impl SceneFileMaterial {
+    pub fn new(
+        base_template: &str,
+        textures: Vec<Option<PathBuf>>,
+        parameters: &ShaderParameters,
+    ) -> Self {
+        let file = MaterialFile::new(base_template, vec![], parameters);
+        Self {
+            base_template: file.base_template,
+            textures,
+            parameters: file.parameters,
+            uv_offset: file.uv_offset,
+            uv_scale: file.uv_scale,
+            uv_rotation: file.uv_rotation,
+        }
+    }
+
+    pub fn shader_parameters(&self) -> ShaderParameters {
+        MaterialFile {
+            base_template: self.base_template.clone(),
+            textures: vec![],
+            parameters: self.parameters.clone(),
+            uv_offset: self.uv_offset,
+            uv_scale: self.uv_scale,
+            uv_rotation: self.uv_rotation,
+        }
+        .shader_parameters()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SceneFileObject {
+    // Keys of SceneFile::meshes and SceneFile::materials
+    pub mesh: String,
+    pub material: String,
+    pub position: [f32; 3],
+    // x, y, z, w
+    pub rotation: [f32; 4],
+    pub scaling: [f32; 3],
+    // Index into SceneFile::objects
+    pub parent: Option<usize>,
+    pub visible: bool,
+}
+
+impl SceneFileObject {
+    pub fn rotation(&self) -> glm::Quat {
+        glm::Quat::from(glm::Vec4::from(self.rotation))
+    }
+}
+
+// In the units of the lights of the same name, see light.rs
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum SceneFileLight {
+    Directional {
+        direction: [f32; 3],
+        illuminance: [f32; 3],
+    },
+    Point {
+        position: [f32; 3],
+        luminous_flux: [f32; 3],
+    },
+    Spot {
+        position: [f32; 3],
+        direction: [f32; 3],
+        inner_angle: f32,
+        outer_angle: f32,
+        luminous_flux: [f32; 3],
+        // A texture file, attached with LightManager::attach_cookie
+        cookie: Option<PathBuf>,
+    },
+}
+
+impl From<&DirectionalLight> for SceneFileLight {
+    fn from(light: &DirectionalLight) -> Self {
+        SceneFileLight::Directional {
+            direction: light.direction.into_inner().into(),
+            illuminance: light.illuminance.into(),
+        }
+    }
+}
+
+impl From<&PointLight> for SceneFileLight {
+    fn from(light: &PointLight) -> Self {
+        SceneFileLight::Point {
+            position: light.position.coords.into(),
+            luminous_flux: light.luminous_flux.into(),
+        }
+    }
+}
+
+impl SceneFileLight {
+    // The cookie is a path, the caller looks up its texture
+    pub fn from_spot_light(light: &SpotLight, cookie: Option<PathBuf>) -> Self {
+        SceneFileLight::Spot {
+            position: light.position.coords.into(),
+            direction: light.direction.into_inner().into(),
+            inner_angle: light.inner_angle,
+            outer_angle: light.outer_angle,
+            luminous_flux: light.luminous_flux.into(),
+            cookie,
+        }
+    }
+
+    // Without the cookie of a spot light
+    pub fn to_light(&self) -> Light {
+        match self {
+            SceneFileLight::Directional {
+                direction,
+                illuminance,
+            } => DirectionalLight {
+                direction: na::Unit::new_normalize(glm::Vec3::from(*direction)),
+                illuminance: glm::Vec3::from(*illuminance),
+            }
+            .into(),
+            SceneFileLight::Point {
+                position,
+                luminous_flux,
+            } => PointLight {
+                position: na::Point3::from(*position),
+                luminous_flux: glm::Vec3::from(*luminous_flux),
+            }
+            .into(),
+            SceneFileLight::Spot {
+                position,
+                direction,
+                inner_angle,
+                outer_angle,
+                luminous_flux,
+                cookie: _,
+            } => SpotLight {
+                position: na::Point3::from(*position),
+                direction: na::Unit::new_normalize(glm::Vec3::from(*direction)),
+                inner_angle: *inner_angle,
+                outer_angle: *outer_angle,
+                luminous_flux: glm::Vec3::from(*luminous_flux),
+                cookie: None,
+            }
+            .into(),
+        }
+    }
+}
+
+fn file_error(path: &Path, message: String) -> RendererError {
+    SceneFileError(format!("{}: {}", path.display(), message)).into()
+}
+
+impl SceneFile {
+    // Checks that the objects only refer to meshes, materials and parents that exist
+    pub fn load<P: AsRef<Path>>(path: P) -> RendererResult<Self> {
+        let path = path.as_ref();
+        let source = std::fs::read_to_string(path)?;
+        let file: Self = ron::from_str(&source).map_err(|e| file_error(path, e.to_string()))?;
+        for (i, object) in file.objects.iter().enumerate() {
+            if !file.meshes.contains_key(&object.mesh) {
+                return Err(file_error(
+                    path,
+                    format!("object {} uses unknown mesh {}", i, object.mesh),
+                ));
+            }
+            if !file.materials.contains_key(&object.material) {
+                return Err(file_error(
+                    path,
+                    format!("object {} uses unknown material {}", i, object.material),
+                ));
+            }
+            if let Some(parent) = object.parent {
+                if parent >= i {
+                    return Err(file_error(
+                        path,
+                        format!("object {} has to come after its parent {}", i, parent),
+                    ));
+                }
+            }
+        }
+        Ok(file)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> RendererResult<()> {
+        let path = path.as_ref();
+        let source = ron::ser::to_string_pretty(self, PrettyConfig::default())
+            .map_err(|e| file_error(path, e.to_string()))?;
+        std::fs::write(path, source)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,641 @@
+use ash::vk;
+
+use super::error::ScheduleError;
+
+// Everything the renderer draws in a frame, in the order given by RenderSchedule
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum PassKind {
+    Forward,
+    // Transparent objects and the billboards of the particle emitters, blended over the
+    // opaque scene back to front in a subpass that can read its depth
+    Transparency,
+    // Reduces the scene color to its average luminance for auto exposure, a compute pass
+    Luminance,
+    // Blurs the bright parts of the scene color for the upscale pass, a compute pass
+    Bloom,
+    // Tone maps the HDR scene and draws it over the whole swapchain image, upscaling it
+    // below a render scale of 1
+    Upscale,
+    Text,
+    // Editor handles over the selected object, see Gizmo
+    Gizmo,
+    // The imgui UI
+    Overlay,
+    SoftwareCursor,
+    // Encodes everything drawn so far for the swapchain, see OutputEncoding
+    Output,
+}
+
+impl PassKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            PassKind::Forward => "forward",
+            PassKind::Transparency => "transparency",
+            PassKind::Luminance => "luminance",
+            PassKind::Bloom => "bloom",
+            PassKind::Upscale => "upscale",
+            PassKind::Text => "text",
+            PassKind::Gizmo => "gizmo",
+            PassKind::Overlay => "overlay",
+            PassKind::SoftwareCursor => "software cursor",
+            PassKind::Output => "output",
+        }
+    }
+
+    // Compute passes are recorded outside of the render passes
+    pub fn is_compute(&self) -> bool {
+        matches!(self, PassKind::Luminance | PassKind::Bloom)
+    }
+
+    // Drawn over the finished scene in a single sampled render pass of their own, which
+    // is recorded every frame into a command buffer separate from the scene's, see
+    // Renderer::record_overlay_commands
+    pub fn is_overlay(&self) -> bool {
+        matches!(
+            self,
+            PassKind::Text | PassKind::Gizmo | PassKind::Overlay | PassKind::SoftwareCursor
+        )
+    }
+
+    // Can run on the compute queue, everything the frame needs from it later is read
+    // back frames later. The upscale pass needs the bloom of the same frame.
+    pub fn async_compute_eligible(&self) -> bool {
+        *self == PassKind::Luminance
+    }
+
+    fn usages(&self, options: &ScheduleOptions) -> Vec<AttachmentUsage> {
+        // With an output pass everything before it draws to the composite target
+        let main_color = if options.output_encode {
+            ScheduleResource::CompositeColor
+        } else {
+            ScheduleResource::SwapchainColor
+        };
+        // With upscaling the scene goes to the scaled target instead
+        let (color, depth) = if options.upscale {
+            (ScheduleResource::SceneColor, ScheduleResource::SceneDepth)
+        } else {
+            (main_color, ScheduleResource::Depth)
+        };
+        match self {
+            PassKind::Forward => vec![
+                AttachmentUsage::color_write(color),
+                AttachmentUsage::depth_write(depth),
+            ],
+            PassKind::Transparency => vec![
+                AttachmentUsage::color_write(color),
+                AttachmentUsage::depth_read_only(depth),
+            ],
+            PassKind::Luminance | PassKind::Bloom => vec![AttachmentUsage::compute_sampled(
+                ScheduleResource::SceneColor,
+            )],
+            PassKind::Upscale => vec![
+                AttachmentUsage::sampled(ScheduleResource::SceneColor),
+                AttachmentUsage::color_write(main_color),
+            ],
+            PassKind::Text | PassKind::Gizmo | PassKind::Overlay | PassKind::SoftwareCursor => {
+                vec![AttachmentUsage::color_write(main_color)]
+            }
+            PassKind::Output => vec![
+                AttachmentUsage::sampled(ScheduleResource::CompositeColor),
+                AttachmentUsage::color_write(ScheduleResource::SwapchainColor),
+            ],
+        }
+    }
+}
+
+// The images passes read from and write to
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ScheduleResource {
+    SwapchainColor,
+    Depth,
+    // The color and depth the scene is rendered to below full resolution
+    SceneColor,
+    SceneDepth,
+    // Everything but the output encoding, in the working format
+    CompositeColor,
+}
+
+impl ScheduleResource {
+    // Where each resource has to end up after the last pass
+    fn final_layout(&self) -> vk::ImageLayout {
+        match self {
+            ScheduleResource::SwapchainColor => vk::ImageLayout::PRESENT_SRC_KHR,
+            ScheduleResource::Depth | ScheduleResource::SceneDepth => {
+                vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL
+            }
+            ScheduleResource::SceneColor | ScheduleResource::CompositeColor => {
+                vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResourceAccess {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl ResourceAccess {
+    fn writes(&self) -> bool {
+        *self != ResourceAccess::Read
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AttachmentUsage {
+    pub resource: ScheduleResource,
+    pub access: ResourceAccess,
+    pub layout: vk::ImageLayout,
+    pub stage: vk::PipelineStageFlags,
+    pub access_mask: vk::AccessFlags,
+}
+
+impl AttachmentUsage {
+    fn color_write(resource: ScheduleResource) -> Self {
+        Self {
+            resource,
+            access: ResourceAccess::ReadWrite,
+            layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+            stage: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+            access_mask: vk::AccessFlags::COLOR_ATTACHMENT_READ
+                | vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
+        }
+    }
+
+    fn depth_write(resource: ScheduleResource) -> Self {
+        Self {
+            resource,
+            access: ResourceAccess::ReadWrite,
+            layout: vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+            stage: vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS
+                | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
+            access_mask: vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ
+                | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+        }
+    }
+
+    fn sampled(resource: ScheduleResource) -> Self {
+        Self {
+            resource,
+            access: ResourceAccess::Read,
+            layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            stage: vk::PipelineStageFlags::FRAGMENT_SHADER,
+            access_mask: vk::AccessFlags::SHADER_READ,
+        }
+    }
+
+    fn compute_sampled(resource: ScheduleResource) -> Self {
+        Self {
+            stage: vk::PipelineStageFlags::COMPUTE_SHADER,
+            ..Self::sampled(resource)
+        }
+    }
+
+    // Depth tested without writing, and read by the fragment shaders as an input
+    // attachment
+    fn depth_read_only(resource: ScheduleResource) -> Self {
+        Self {
+            resource,
+            access: ResourceAccess::Read,
+            layout: vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL,
+            stage: vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS
+                | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS
+                | vk::PipelineStageFlags::FRAGMENT_SHADER,
+            access_mask: vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ
+                | vk::AccessFlags::INPUT_ATTACHMENT_READ,
+        }
+    }
+}
+
+// A barrier the schedule needs before a pass, or after the last one
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Transition {
+    pub resource: ScheduleResource,
+    pub old_layout: vk::ImageLayout,
+    pub new_layout: vk::ImageLayout,
+    pub src_stage: vk::PipelineStageFlags,
+    pub dst_stage: vk::PipelineStageFlags,
+    pub src_access: vk::AccessFlags,
+    pub dst_access: vk::AccessFlags,
+}
+
+// The queue a pass is submitted to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PassQueue {
+    Graphics,
+    // The compute queue, waiting for the graphics passes before it and overlapping with
+    // the ones after it
+    AsyncCompute,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PassDescription {
+    pub kind: PassKind,
+    pub queue: PassQueue,
+    pub usages: Vec<AttachmentUsage>,
+    pub transitions_before: Vec<Transition>,
+}
+
+// The toggles that decide which passes run
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct ScheduleOptions {
+    pub gizmo: bool,
+    pub software_cursor: bool,
+    // Render the scene to the scaled target, see RendererConfig::render_scale
+    pub upscale: bool,
+    // Measure the luminance of the scaled target, only scheduled with upscale
+    pub luminance: bool,
+    // Blur the bright parts of the scaled target, only scheduled with upscale
+    pub bloom: bool,
+    // There is a compute queue for the eligible passes, see RendererConfig::async_compute
+    pub async_compute: bool,
+    // Draw to the composite target and encode it for the swapchain at the end, fixed by
+    // the swapchain format
+    pub output_encode: bool,
+}
+
+#[derive(Clone, Copy)]
+struct ResourceState {
+    layout: vk::ImageLayout,
+    stage: vk::PipelineStageFlags,
+    access_mask: vk::AccessFlags,
+    written: bool,
+}
+
+impl Default for ResourceState {
+    fn default() -> Self {
+        Self {
+            layout: vk::ImageLayout::UNDEFINED,
+            stage: vk::PipelineStageFlags::TOP_OF_PIPE,
+            access_mask: vk::AccessFlags::empty(),
+            written: false,
+        }
+    }
+}
+
+// The passes of a frame in order, with the transitions between them derived from what
+// every pass declares it uses. The forward and transparency passes are recorded into the
+// two subpasses of the main render pass, so layout changes are only possible when
+// entering and leaving those, which is where the render pass does them. With upscaling
+// the scene passes go into the render pass of the scaled target instead, and the main
+// render pass starts with the upscale and leaves its second subpass empty. The overlay
+// passes get a single sampled render pass of their own after it, over the same color.
+// With output encoding the main and overlay render passes draw to the composite target,
+// and the output pass gets a render pass of its own. Compute passes go between the
+// render passes, on the compute queue if they are eligible and there is one. The frame
+// is then split around them: the graphics work before them signals the compute queue,
+// and the next frame's scene waits for it to be done with the scene color.
+pub(crate) struct RenderSchedule {
+    options: ScheduleOptions,
+    passes: Vec<PassDescription>,
+    final_transitions: Vec<Transition>,
+}
+
+impl RenderSchedule {
+    pub fn new(options: ScheduleOptions) -> Result<Self, ScheduleError> {
+        let mut kinds = vec![PassKind::Forward, PassKind::Transparency];
+        if options.luminance && options.upscale {
+            kinds.push(PassKind::Luminance);
+        }
+        if options.bloom && options.upscale {
+            kinds.push(PassKind::Bloom);
+        }
+        if options.upscale {
+            kinds.push(PassKind::Upscale);
+        }
+        kinds.push(PassKind::Text);
+        // Below the UI, which should stay usable while a gizmo is shown
+        if options.gizmo {
+            kinds.push(PassKind::Gizmo);
+        }
+        kinds.push(PassKind::Overlay);
+        // After the UI, so it stays on top of it
+        if options.software_cursor {
+            kinds.push(PassKind::SoftwareCursor);
+        }
+        if options.output_encode {
+            kinds.push(PassKind::Output);
+        }
+
+        let mut states: Vec<(ScheduleResource, ResourceState)> = vec![];
+        let mut passes = Vec::with_capacity(kinds.len());
+        for kind in kinds {
+            let usages = kind.usages(&options);
+            let mut transitions_before = vec![];
+            for usage in usages.iter() {
+                let index = match states.iter().position(|(r, _)| *r == usage.resource) {
+                    Some(index) => index,
+                    None => {
+                        states.push((usage.resource, ResourceState::default()));
+                        states.len() - 1
+                    }
+                };
+                let state = &mut states[index].1;
+                // Attachment writes in the same layout and stage are ordered by the
+                // rasterization order guarantees, everything else needs a barrier
+                let hazard = state.written && state.stage != usage.stage;
+                if state.layout != usage.layout || hazard {
+                    transitions_before.push(Transition {
+                        resource: usage.resource,
+                        old_layout: state.layout,
+                        new_layout: usage.layout,
+                        src_stage: state.stage,
+                        dst_stage: usage.stage,
+                        src_access: state.access_mask,
+                        dst_access: usage.access_mask,
+                    });
+                }
+                *state = ResourceState {
+                    layout: usage.layout,
+                    stage: usage.stage,
+                    access_mask: usage.access_mask,
+                    written: usage.access.writes(),
+                };
+            }
+            let first_overlay = kind.is_overlay()
+                && !passes
+                    .last()
+                    .map_or(false, |pass: &PassDescription| pass.kind.is_overlay());
+            let starts_subpass = passes.is_empty()
+                || first_overlay
+                || kind == PassKind::Transparency
+                || kind == PassKind::Upscale
+                || kind == PassKind::Output
+                || kind.is_compute();
+            if !starts_subpass && !transitions_before.is_empty() {
+                return Err(format!(
+                    "the {} pass needs a transition of {:?} inside a subpass",
+                    kind.name(),
+                    transitions_before[0].resource
+                )
+                .into());
+            }
+            let queue = if options.async_compute && kind.async_compute_eligible() {
+                PassQueue::AsyncCompute
+            } else {
+                PassQueue::Graphics
+            };
+            passes.push(PassDescription {
+                kind,
+                queue,
+                usages,
+                transitions_before,
+            });
+        }
+
+        let final_transitions = states
+            .iter()
+            .filter(|(resource, state)| state.layout != resource.final_layout())
+            .map(|(resource, state)| Transition {
+                resource: *resource,
+                old_layout: state.layout,
+                new_layout: resource.final_layout(),
+                src_stage: state.stage,
+                dst_stage: vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                src_access: state.access_mask,
+                dst_access: vk::AccessFlags::empty(),
+            })
+            .collect();
+
+        Ok(Self {
+            options,
+            passes,
+            final_transitions,
+        })
+    }
+
+    pub fn options(&self) -> ScheduleOptions {
+        self.options
+    }
+
+    pub fn passes(&self) -> &[PassDescription] {
+        &self.passes
+    }
+
+    pub fn pass_kinds(&self) -> Vec<PassKind> {
+        self.passes.iter().map(|pass| pass.kind).collect()
+    }
+
+    // The layout the resource is in during the pass, UNDEFINED if the pass doesn't use it
+    pub fn layout_in(&self, kind: PassKind, resource: ScheduleResource) -> vk::ImageLayout {
+        self.passes
+            .iter()
+            .filter(|pass| pass.kind == kind)
+            .flat_map(|pass| pass.usages.iter())
+            .find(|usage| usage.resource == resource)
+            .map(|usage| usage.layout)
+            .unwrap_or(vk::ImageLayout::UNDEFINED)
+    }
+
+    // The layout the render pass has to put the resource in for its first subpass
+    pub fn subpass_layout(&self, resource: ScheduleResource) -> vk::ImageLayout {
+        self.passes
+            .iter()
+            .flat_map(|pass| pass.usages.iter())
+            .find(|usage| usage.resource == resource)
+            .map(|usage| usage.layout)
+            .unwrap_or(vk::ImageLayout::UNDEFINED)
+    }
+
+    pub fn final_layout(&self, resource: ScheduleResource) -> vk::ImageLayout {
+        self.final_transitions
+            .iter()
+            .find(|transition| transition.resource == resource)
+            .map(|transition| transition.new_layout)
+            .or_else(|| {
+                // Without a transition the resource stays in the layout of its last usage
+                self.passes
+                    .iter()
+                    .rev()
+                    .flat_map(|pass| pass.usages.iter())
+                    .find(|usage| usage.resource == resource)
+                    .map(|usage| usage.layout)
+            })
+            .unwrap_or(vk::ImageLayout::UNDEFINED)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overlay_passes_follow_the_scene_together() {
+        let schedule = RenderSchedule::new(ScheduleOptions {
+            gizmo: true,
+            software_cursor: true,
+            upscale: true,
+            output_encode: true,
+            ..Default::default()
+        })
+        .unwrap();
+        let kinds = schedule.pass_kinds();
+        let first = kinds.iter().position(|kind| kind.is_overlay()).unwrap();
+        let last = kinds.iter().rposition(|kind| kind.is_overlay()).unwrap();
+        assert!(kinds[first..=last].iter().all(|kind| kind.is_overlay()));
+        assert_eq!(kinds[first - 1], PassKind::Upscale);
+        assert_eq!(kinds[last + 1..], [PassKind::Output]);
+    }
+
+    #[test]
+    fn only_eligible_passes_go_on_the_compute_queue() {
+        let options = ScheduleOptions {
+            upscale: true,
+            luminance: true,
+            bloom: true,
+            ..Default::default()
+        };
+        let queues = |options| {
+            RenderSchedule::new(options)
+                .unwrap()
+                .passes()
+                .iter()
+                .filter(|pass| pass.queue == PassQueue::AsyncCompute)
+                .map(|pass| pass.kind)
+                .collect::<Vec<_>>()
+        };
+        assert!(queues(options).is_empty());
+        let async_options = ScheduleOptions {
+            async_compute: true,
+            ..options
+        };
+        assert_eq!(queues(async_options), vec![PassKind::Luminance]);
+    }
+
+    // Bloom is inserted between the scene and the upscale pass that samples the scene
+    // color after it, with the barrier from the color attachment to the compute shader
+    #[test]
+    fn enabling_bloom_inserts_it_before_the_upscale() {
+        let options = ScheduleOptions {
+            upscale: true,
+            ..Default::default()
+        };
+        let without = RenderSchedule::new(options).unwrap();
+        assert_eq!(
+            without.pass_kinds(),
+            [
+                PassKind::Forward,
+                PassKind::Transparency,
+                PassKind::Upscale,
+                PassKind::Text,
+                PassKind::Overlay
+            ]
+        );
+        let with = RenderSchedule::new(ScheduleOptions {
+            bloom: true,
+            ..options
+        })
+        .unwrap();
+        assert_eq!(
+            with.pass_kinds(),
+            [
+                PassKind::Forward,
+                PassKind::Transparency,
+                PassKind::Bloom,
+                PassKind::Upscale,
+                PassKind::Text,
+                PassKind::Overlay
+            ]
+        );
+
+        let to_sampled = Transition {
+            resource: ScheduleResource::SceneColor,
+            old_layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+            new_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            src_stage: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+            dst_stage: vk::PipelineStageFlags::COMPUTE_SHADER,
+            src_access: vk::AccessFlags::COLOR_ATTACHMENT_READ
+                | vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
+            dst_access: vk::AccessFlags::SHADER_READ,
+        };
+        assert_eq!(with.passes()[2].transitions_before, [to_sampled]);
+        // The scene color is already in the layout the upscale pass samples it in
+        assert!(with.passes()[3]
+            .transitions_before
+            .iter()
+            .all(|transition| transition.resource != ScheduleResource::SceneColor));
+        assert!(without.passes()[2]
+            .transitions_before
+            .contains(&Transition {
+                dst_stage: vk::PipelineStageFlags::FRAGMENT_SHADER,
+                ..to_sampled
+            }));
+    }
+
+    // Without the upscale pass the forward pass tone maps, and draws straight to the
+    // swapchain image
+    #[test]
+    fn without_tone_mapping_the_scene_goes_to_the_swapchain() {
+        let schedule = RenderSchedule::new(ScheduleOptions::default()).unwrap();
+        assert_eq!(
+            schedule.pass_kinds(),
+            [
+                PassKind::Forward,
+                PassKind::Transparency,
+                PassKind::Text,
+                PassKind::Overlay
+            ]
+        );
+        let forward = &schedule.passes()[0];
+        assert_eq!(
+            forward.usages[0],
+            AttachmentUsage::color_write(ScheduleResource::SwapchainColor)
+        );
+        assert!(schedule
+            .passes()
+            .iter()
+            .flat_map(|pass| pass.usages.iter())
+            .all(|usage| usage.resource != ScheduleResource::SceneColor));
+        assert_eq!(
+            schedule.final_layout(ScheduleResource::SwapchainColor),
+            vk::ImageLayout::PRESENT_SRC_KHR
+        );
+    }
+
+    // The transparency subpass reads the depth the forward one wrote, the render pass
+    // moves it to the read only layout between them and leaves it there
+    #[test]
+    fn the_transparency_pass_reads_the_forward_depth() {
+        for upscale in [false, true] {
+            let schedule = RenderSchedule::new(ScheduleOptions {
+                upscale,
+                ..Default::default()
+            })
+            .unwrap();
+            let depth = if upscale {
+                ScheduleResource::SceneDepth
+            } else {
+                ScheduleResource::Depth
+            };
+            let transparency = &schedule.passes()[1];
+            assert_eq!(transparency.kind, PassKind::Transparency);
+            assert_eq!(
+                transparency.transitions_before,
+                [Transition {
+                    resource: depth,
+                    old_layout: vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+                    new_layout: vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL,
+                    src_stage: vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS
+                        | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
+                    dst_stage: vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS
+                        | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS
+                        | vk::PipelineStageFlags::FRAGMENT_SHADER,
+                    src_access: vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ
+                        | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+                    dst_access: vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ
+                        | vk::AccessFlags::INPUT_ATTACHMENT_READ,
+                }]
+            );
+            assert_eq!(
+                schedule.layout_in(PassKind::Forward, depth),
+                vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL
+            );
+            assert_eq!(
+                schedule.final_layout(depth),
+                vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL
+            );
+        }
+    }
+}
@@ -0,0 +1,335 @@
+use nalgebra_glm as glm;
+
+use super::culling::Aabb;
+use super::gizmo;
+
+// How Renderer::select_in_rect decides which objects a rectangle selects
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SelectionMode {
+    // The projected bounds of the object are inside the rectangle
+    ContainedBounds,
+    // The projected bounds of the object overlap the rectangle
+    IntersectsBounds,
+    // The object covers at least one pixel of the rectangle, drawn with the AOV pass.
+    // With occlusion only the pixels where the object is in front count.
+    PreciseStencil { occlusion: bool },
+}
+
+// In pixels from the top left corner of the window
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SelectionRect {
+    pub min: glm::Vec2,
+    pub max: glm::Vec2,
+}
+
+impl SelectionRect {
+    // The corners of a drag, in any order
+    pub fn from_corners(a: glm::Vec2, b: glm::Vec2) -> Self {
+        Self {
+            min: glm::min2(&a, &b),
+            max: glm::max2(&a, &b),
+        }
+    }
+
+    pub fn size(&self) -> glm::Vec2 {
+        self.max - self.min
+    }
+
+    // A drag shorter than a pixel, which picks the one object under the cursor
+    pub fn is_click(&self) -> bool {
+        let size = self.size();
+        size.x < 1.0 && size.y < 1.0
+    }
+
+    pub fn contains(&self, other: &SelectionRect) -> bool {
+        other.min.x >= self.min.x
+            && other.min.y >= self.min.y
+            && other.max.x <= self.max.x
+            && other.max.y <= self.max.y
+    }
+
+    pub fn intersects(&self, other: &SelectionRect) -> bool {
+        other.min.x <= self.max.x
+            && other.max.x >= self.min.x
+            && other.min.y <= self.max.y
+            && other.max.y >= self.min.y
+    }
+
+    pub fn contains_point(&self, point: &glm::Vec2) -> bool {
+        point.x >= self.min.x
+            && point.x <= self.max.x
+            && point.y >= self.min.y
+            && point.y <= self.max.y
+    }
+}
+
+// The screen rectangle around the corners of world space bounds, None if a corner is
+// behind the camera, the bounds have no finite rectangle then
+pub fn project_bounds(
+    bounds: &Aabb,
+    view_projection: &glm::Mat4,
+    screen_size: &glm::Vec2,
+) -> Option<SelectionRect> {
+    let mut corners = (0..8).map(|i| {
+        let corner = glm::vec3(
+            if i & 1 == 0 {
+                bounds.min.x
+            } else {
+                bounds.max.x
+            },
+            if i & 2 == 0 {
+                bounds.min.y
+            } else {
+                bounds.max.y
+            },
+            if i & 4 == 0 {
+                bounds.min.z
+            } else {
+                bounds.max.z
+            },
+        );
+        gizmo::project_to_screen(&corner, view_projection, screen_size)
+    });
+    let first = corners.next()??;
+    corners.try_fold(SelectionRect::from_corners(first, first), |rect, corner| {
+        corner.map(|corner| SelectionRect {
+            min: glm::min2(&rect.min, &corner),
+            max: glm::max2(&rect.max, &corner),
+        })
+    })
+}
+
+// Whether projected bounds are selected by rect. Bounds reaching behind the camera may
+// cover anything, so they intersect every rectangle but are never contained.
+pub fn bounds_selected(
+    rect: &SelectionRect,
+    projected: Option<&SelectionRect>,
+    mode: SelectionMode,
+) -> bool {
+    match (mode, projected) {
+        (SelectionMode::ContainedBounds, Some(projected)) => rect.contains(projected),
+        (SelectionMode::ContainedBounds, None) => false,
+        (_, Some(projected)) => rect.intersects(projected),
+        (_, None) => true,
+    }
+}
+
+// The CPU side of select_in_rect, candidates are the objects that may be selected with
+// their world space bounds. A click selects the candidate whose projected bounds are
+// under it and which is closest to the camera, whatever the mode.
+pub fn select_by_bounds<T: Copy>(
+    rect: &SelectionRect,
+    mode: SelectionMode,
+    view_projection: &glm::Mat4,
+    screen_size: &glm::Vec2,
+    camera_position: &glm::Vec3,
+    candidates: impl IntoIterator<Item = (T, Aabb)>,
+) -> Vec<T> {
+    if rect.is_click() {
+        let point = 0.5 * (rect.min + rect.max);
+        return candidates
+            .into_iter()
+            .filter(|(_, bounds)| {
+                project_bounds(bounds, view_projection, screen_size)
+                    .map_or(true, |projected| projected.contains_point(&point))
+            })
+            .map(|(object, bounds)| (object, bounds.distance_to_point(camera_position)))
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(object, _)| object)
+            .into_iter()
+            .collect();
+    }
+    candidates
+        .into_iter()
+        .filter(|(_, bounds)| {
+            let projected = project_bounds(bounds, view_projection, screen_size);
+            bounds_selected(rect, projected.as_ref(), mode)
+        })
+        .map(|(object, _)| object)
+        .collect()
+}
+
+// The whole pixels the GPU path draws for rect, as offset and size, clamped to the
+// screen. At least one pixel, None if rect is off the screen.
+pub fn pixel_region(
+    rect: &SelectionRect,
+    screen_width: u32,
+    screen_height: u32,
+) -> Option<([u32; 2], [u32; 2])> {
+    let clamp = |v: f32, max: u32| (v.max(0.0) as u32).min(max);
+    let x0 = clamp(rect.min.x.floor(), screen_width);
+    let y0 = clamp(rect.min.y.floor(), screen_height);
+    let x1 = clamp(rect.max.x.ceil(), screen_width)
+        .max(x0 + 1)
+        .min(screen_width);
+    let y1 = clamp(rect.max.y.ceil(), screen_height)
+        .max(y0 + 1)
+        .min(screen_height);
+    if x0 >= x1 || y0 >= y1 {
+        return None;
+    }
+    Some(([x0, y0], [x1 - x0, y1 - y0]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Orthographic from +Z, world x and y map to 100 + 10 * x and 100 + 10 * y pixels
+    fn view_projection() -> glm::Mat4 {
+        glm::ortho_rh_zo(-10.0, 10.0, -10.0, 10.0, 0.1, 100.0)
+            * glm::look_at_rh(
+                &glm::vec3(0.0, 0.0, 10.0),
+                &glm::Vec3::zeros(),
+                &glm::vec3(0.0, 1.0, 0.0),
+            )
+    }
+
+    fn screen() -> glm::Vec2 {
+        glm::vec2(200.0, 200.0)
+    }
+
+    fn cube(center: glm::Vec3, half: f32) -> Aabb {
+        Aabb {
+            min: center - glm::vec3(half, half, half),
+            max: center + glm::vec3(half, half, half),
+        }
+    }
+
+    fn rect(min: (f32, f32), max: (f32, f32)) -> SelectionRect {
+        SelectionRect::from_corners(glm::vec2(min.0, min.1), glm::vec2(max.0, max.1))
+    }
+
+    fn assert_rect_close(a: &SelectionRect, b: &SelectionRect) {
+        assert!(
+            glm::length(&(a.min - b.min)) < 1e-3 && glm::length(&(a.max - b.max)) < 1e-3,
+            "{:?} != {:?}",
+            a,
+            b
+        );
+    }
+
+    #[test]
+    fn corners_in_any_order() {
+        let r = rect((30.0, 5.0), (10.0, 20.0));
+        assert_eq!(r.min, glm::vec2(10.0, 5.0));
+        assert_eq!(r.max, glm::vec2(30.0, 20.0));
+        assert!(!r.is_click());
+        assert!(rect((3.0, 3.0), (3.5, 3.9)).is_click());
+    }
+
+    #[test]
+    fn bounds_project_to_their_screen_rectangle() {
+        let projected = project_bounds(
+            &cube(glm::vec3(2.0, -3.0, 0.0), 1.0),
+            &view_projection(),
+            &screen(),
+        )
+        .unwrap();
+        assert_rect_close(&projected, &rect((110.0, 60.0), (130.0, 80.0)));
+    }
+
+    #[test]
+    fn bounds_behind_the_camera_have_no_rectangle() {
+        let perspective = glm::perspective_rh_zo(1.0, 1.0, 0.1, 100.0)
+            * glm::look_at_rh(
+                &glm::vec3(0.0, 0.0, 10.0),
+                &glm::Vec3::zeros(),
+                &glm::vec3(0.0, 1.0, 0.0),
+            );
+        let around_camera = cube(glm::vec3(0.0, 0.0, 10.0), 2.0);
+        assert_eq!(
+            project_bounds(&around_camera, &perspective, &screen()),
+            None
+        );
+        let full = rect((0.0, 0.0), (200.0, 200.0));
+        assert!(!bounds_selected(
+            &full,
+            None,
+            SelectionMode::ContainedBounds
+        ));
+        assert!(bounds_selected(
+            &full,
+            None,
+            SelectionMode::IntersectsBounds
+        ));
+    }
+
+    #[test]
+    fn contained_and_intersecting_modes() {
+        let drag = rect((50.0, 50.0), (105.0, 150.0));
+        let inside = rect((60.0, 60.0), (100.0, 100.0));
+        let overlapping = rect((100.0, 60.0), (120.0, 100.0));
+        let outside = rect((110.0, 60.0), (120.0, 100.0));
+        let table = [
+            (&inside, SelectionMode::ContainedBounds, true),
+            (&overlapping, SelectionMode::ContainedBounds, false),
+            (&outside, SelectionMode::ContainedBounds, false),
+            (&inside, SelectionMode::IntersectsBounds, true),
+            (&overlapping, SelectionMode::IntersectsBounds, true),
+            (&outside, SelectionMode::IntersectsBounds, false),
+        ];
+        for (projected, mode, selected) in table {
+            assert_eq!(
+                bounds_selected(&drag, Some(projected), mode),
+                selected,
+                "{:?} {:?}",
+                projected,
+                mode
+            );
+        }
+    }
+
+    #[test]
+    fn drags_select_every_candidate_and_clicks_the_nearest() {
+        let candidates = [
+            (0, cube(glm::vec3(-5.0, 0.0, 0.0), 1.0)),
+            (1, cube(glm::vec3(0.0, 0.0, -5.0), 1.0)),
+            (2, cube(glm::vec3(0.0, 0.0, 0.0), 1.0)),
+        ];
+        let camera = glm::vec3(0.0, 0.0, 10.0);
+        let drag = rect((0.0, 0.0), (120.0, 200.0));
+        let selected = select_by_bounds(
+            &drag,
+            SelectionMode::ContainedBounds,
+            &view_projection(),
+            &screen(),
+            &camera,
+            candidates,
+        );
+        assert_eq!(selected, vec![0, 1, 2]);
+        // 1 and 2 are both under the cursor, 2 is closer
+        let click = rect((100.0, 100.0), (100.0, 100.0));
+        let selected = select_by_bounds(
+            &click,
+            SelectionMode::ContainedBounds,
+            &view_projection(),
+            &screen(),
+            &camera,
+            candidates,
+        );
+        assert_eq!(selected, vec![2]);
+    }
+
+    #[test]
+    fn pixel_regions_cover_whole_pixels_on_screen() {
+        assert_eq!(
+            pixel_region(&rect((10.5, 20.2), (30.1, 40.0)), 100, 100),
+            Some(([10, 20], [21, 20]))
+        );
+        // A click is one pixel
+        assert_eq!(
+            pixel_region(&rect((10.5, 20.5), (10.5, 20.5)), 100, 100),
+            Some(([10, 20], [1, 1]))
+        );
+        // Clamped to the screen
+        assert_eq!(
+            pixel_region(&rect((-10.0, 90.0), (20.0, 300.0)), 100, 100),
+            Some(([0, 90], [20, 10]))
+        );
+        assert_eq!(
+            pixel_region(&rect((150.0, 10.0), (160.0, 20.0)), 100, 100),
+            None
+        );
+    }
+}
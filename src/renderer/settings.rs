@@ -0,0 +1,340 @@
+use std::path::{Path, PathBuf};
+
+use log::warn;
+use ron::ser::PrettyConfig;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    config::{BackgroundBehavior, RedrawPolicy, RendererConfig, MIN_RENDER_SCALE},
+    error::{RendererError, SettingsFileError},
+    exposure::AutoExposureConfig,
+    probe::ProbeBlend,
+    RendererResult,
+};
+
+const SETTINGS_FILE_NAME: &str = "renderer_settings.ron";
+
+// The part of RendererConfig a user changes from a settings menu, which can be changed
+// while the renderer runs, see Renderer::apply_settings. Stored as RON, keys missing
+// from a file keep their default and unknown keys are skipped, so files written by other
+// versions still load.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RendererSettings {
+    pub render_scale: f32,
+    pub auto_exposure: bool,
+    pub hdr_paper_white_nits: f32,
+    pub hdr_peak_nits: f32,
+    pub probe_blend: ProbeBlend,
+    pub background_behavior: BackgroundBehavior,
+    pub redraw_policy: RedrawPolicy,
+}
+
+impl Default for RendererSettings {
+    fn default() -> Self {
+        Self::from_config(&RendererConfig::default())
+    }
+}
+
+// What applying settings takes, in the order it is done. Every operation is done at most
+// once, however many settings need it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SettingsOperation {
+    // Only changes what begin_frame does
+    SetBackgroundBehavior,
+    // Only changes what render does
+    SetRedrawPolicy,
+    // Recomputes which probes every object blends
+    ReblendProbes,
+    // The output pass picks the new nits up with the next frame
+    UpdateOutputEncoding,
+    // Reallocates the scaled target, for the render scale and auto exposure together
+    RebuildScaledTarget,
+}
+
+// Returned by Renderer::apply_settings
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ApplyReport {
+    pub operations: Vec<SettingsOperation>,
+    // Values that were out of range and clamped, these are logged as well
+    pub warnings: Vec<String>,
+}
+
+impl RendererSettings {
+    pub fn from_config(config: &RendererConfig) -> Self {
+        Self {
+            render_scale: config.render_scale,
+            auto_exposure: config.auto_exposure.is_some(),
+            hdr_paper_white_nits: config.hdr_paper_white_nits,
+            hdr_peak_nits: config.hdr_peak_nits,
+            probe_blend: config.probe_blend,
+            background_behavior: config.background_behavior,
+            redraw_policy: config.redraw_policy,
+        }
+    }
+
+    // For a config the renderer is created with, so stored settings don't have to be
+    // applied again after startup. An auto exposure config already set is kept.
+    pub fn configure(&self, config: &mut RendererConfig) {
+        let (settings, _) = self.clamped();
+        config.render_scale = settings.render_scale;
+        config.auto_exposure = settings.auto_exposure_config(config.auto_exposure);
+        config.hdr_paper_white_nits = settings.hdr_paper_white_nits;
+        config.hdr_peak_nits = settings.hdr_peak_nits;
+        config.probe_blend = settings.probe_blend;
+        config.background_behavior = settings.background_behavior;
+        config.redraw_policy = settings.redraw_policy;
+    }
+
+    pub(crate) fn auto_exposure_config(
+        &self,
+        current: Option<AutoExposureConfig>,
+    ) -> Option<AutoExposureConfig> {
+        if self.auto_exposure {
+            Some(current.unwrap_or_default())
+        } else {
+            None
+        }
+    }
+
+    // The settings with every value moved into its valid range, and a message for each
+    // value that had to be moved
+    pub fn clamped(&self) -> (Self, Vec<String>) {
+        let mut settings = self.clone();
+        let mut warnings = vec![];
+        if !settings.render_scale.is_finite() {
+            warnings.push(format!(
+                "Render scale {} is not a number, using 1",
+                settings.render_scale
+            ));
+            settings.render_scale = 1.0;
+        } else if !(MIN_RENDER_SCALE..=1.0).contains(&settings.render_scale) {
+            let clamped = settings.render_scale.clamp(MIN_RENDER_SCALE, 1.0);
+            warnings.push(format!(
+                "Render scale {} is outside of [{}, 1], using {}",
+                settings.render_scale, MIN_RENDER_SCALE, clamped
+            ));
+            settings.render_scale = clamped;
+        }
+        if settings.hdr_paper_white_nits.is_nan() || settings.hdr_paper_white_nits < 1.0 {
+            warnings.push(format!(
+                "Paper white of {} nits is too dark, using 1",
+                settings.hdr_paper_white_nits
+            ));
+            settings.hdr_paper_white_nits = 1.0;
+        }
+        if settings.hdr_peak_nits.is_nan() || settings.hdr_peak_nits < settings.hdr_paper_white_nits
+        {
+            warnings.push(format!(
+                "Peak of {} nits is below the paper white, using {}",
+                settings.hdr_peak_nits, settings.hdr_paper_white_nits
+            ));
+            settings.hdr_peak_nits = settings.hdr_paper_white_nits;
+        }
+        if let BackgroundBehavior::ThrottleTo(fps) = settings.background_behavior {
+            if fps.is_nan() || fps <= 0.0 {
+                warnings.push(format!(
+                    "Background frame rate {} is not positive, using 1",
+                    fps
+                ));
+                settings.background_behavior = BackgroundBehavior::ThrottleTo(1.0);
+            }
+        }
+        (settings, warnings)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> RendererResult<Self> {
+        let path = path.as_ref();
+        let source = std::fs::read_to_string(path)?;
+        ron::from_str(&source).map_err(|e| file_error(path, e.to_string()))
+    }
+
+    // Creates the directory of the file if needed
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> RendererResult<()> {
+        let path = path.as_ref();
+        let source = ron::ser::to_string_pretty(self, PrettyConfig::default())
+            .map_err(|e| file_error(path, e.to_string()))?;
+        if let Some(directory) = path.parent() {
+            std::fs::create_dir_all(directory)?;
+        }
+        std::fs::write(path, source)?;
+        Ok(())
+    }
+
+    // The stored settings of an application, the defaults if there are none yet or they
+    // can't be read
+    pub fn load_or_default(app_name: &str) -> Self {
+        let path = match settings_path(app_name) {
+            Some(path) => path,
+            None => return Self::default(),
+        };
+        if !path.exists() {
+            return Self::default();
+        }
+        Self::load(&path).unwrap_or_else(|e| {
+            warn!(
+                "Unable to load settings from {}, using the defaults: {}",
+                path.display(),
+                e
+            );
+            Self::default()
+        })
+    }
+}
+
+fn file_error(path: &Path, message: String) -> RendererError {
+    SettingsFileError(format!("{}: {}", path.display(), message)).into()
+}
+
+// The per user configuration directory of the platform, None if the environment doesn't
+// say where it is
+pub fn settings_directory(app_name: &str) -> Option<PathBuf> {
+    let var = |name: &str| std::env::var_os(name).filter(|value| !value.is_empty());
+    let base = if cfg!(target_os = "windows") {
+        var("APPDATA").map(PathBuf::from)
+    } else if cfg!(target_os = "macos") {
+        var("HOME").map(|home| PathBuf::from(home).join("Library/Application Support"))
+    } else {
+        var("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| var("HOME").map(|home| PathBuf::from(home).join(".config")))
+    };
+    base.map(|base| base.join(app_name))
+}
+
+pub fn settings_path(app_name: &str) -> Option<PathBuf> {
+    settings_directory(app_name).map(|directory| directory.join(SETTINGS_FILE_NAME))
+}
+
+// The operations that take the renderer from current to wanted, in the order they have
+// to be done. Both are expected to be clamped.
+pub fn plan_settings(
+    current: &RendererSettings,
+    wanted: &RendererSettings,
+) -> Vec<SettingsOperation> {
+    let mut operations = vec![];
+    if current.background_behavior != wanted.background_behavior {
+        operations.push(SettingsOperation::SetBackgroundBehavior);
+    }
+    if current.redraw_policy != wanted.redraw_policy {
+        operations.push(SettingsOperation::SetRedrawPolicy);
+    }
+    if current.probe_blend != wanted.probe_blend {
+        operations.push(SettingsOperation::ReblendProbes);
+    }
+    if current.hdr_paper_white_nits != wanted.hdr_paper_white_nits
+        || current.hdr_peak_nits != wanted.hdr_peak_nits
+    {
+        operations.push(SettingsOperation::UpdateOutputEncoding);
+    }
+    if current.render_scale != wanted.render_scale || current.auto_exposure != wanted.auto_exposure
+    {
+        operations.push(SettingsOperation::RebuildScaledTarget);
+    }
+    operations
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unchanged_settings_need_nothing() {
+        let settings = RendererSettings::default();
+        assert!(plan_settings(&settings, &settings.clone()).is_empty());
+    }
+
+    #[test]
+    fn each_setting_needs_its_operation() {
+        let current = RendererSettings::default();
+        let changes: [(fn(&mut RendererSettings), SettingsOperation); 7] = [
+            (
+                |s| s.background_behavior = BackgroundBehavior::SkipRendering,
+                SettingsOperation::SetBackgroundBehavior,
+            ),
+            (
+                |s| s.redraw_policy = RedrawPolicy::OnDemand,
+                SettingsOperation::SetRedrawPolicy,
+            ),
+            (
+                |s| s.probe_blend = ProbeBlend::Nearest,
+                SettingsOperation::ReblendProbes,
+            ),
+            (
+                |s| s.hdr_paper_white_nits += 50.0,
+                SettingsOperation::UpdateOutputEncoding,
+            ),
+            (
+                |s| s.hdr_peak_nits += 50.0,
+                SettingsOperation::UpdateOutputEncoding,
+            ),
+            (
+                |s| s.render_scale = 0.5,
+                SettingsOperation::RebuildScaledTarget,
+            ),
+            (
+                |s| s.auto_exposure = !s.auto_exposure,
+                SettingsOperation::RebuildScaledTarget,
+            ),
+        ];
+        for (change, operation) in changes {
+            let mut wanted = current.clone();
+            change(&mut wanted);
+            assert_eq!(plan_settings(&current, &wanted), vec![operation]);
+        }
+    }
+
+    #[test]
+    fn shared_operations_are_planned_once_in_order() {
+        let current = RendererSettings::default();
+        let wanted = RendererSettings {
+            render_scale: 0.5,
+            auto_exposure: !current.auto_exposure,
+            hdr_paper_white_nits: current.hdr_paper_white_nits + 10.0,
+            hdr_peak_nits: current.hdr_peak_nits + 10.0,
+            redraw_policy: RedrawPolicy::OnDemand,
+            ..current.clone()
+        };
+        assert_eq!(
+            plan_settings(&current, &wanted),
+            vec![
+                SettingsOperation::SetRedrawPolicy,
+                SettingsOperation::UpdateOutputEncoding,
+                SettingsOperation::RebuildScaledTarget,
+            ]
+        );
+    }
+
+    #[test]
+    fn out_of_range_values_are_clamped_with_a_warning() {
+        let settings = RendererSettings {
+            render_scale: 4.0,
+            hdr_paper_white_nits: 200.0,
+            hdr_peak_nits: 100.0,
+            background_behavior: BackgroundBehavior::ThrottleTo(-1.0),
+            ..Default::default()
+        };
+        let (clamped, warnings) = settings.clamped();
+        assert_eq!(clamped.render_scale, 1.0);
+        assert_eq!(clamped.hdr_peak_nits, 200.0);
+        assert_eq!(
+            clamped.background_behavior,
+            BackgroundBehavior::ThrottleTo(1.0)
+        );
+        assert_eq!(warnings.len(), 3);
+        let (_, warnings) = RendererSettings::default().clamped();
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn missing_keys_keep_their_defaults() {
+        let settings: RendererSettings = ron::from_str("(render_scale: 0.5)").unwrap();
+        assert_eq!(
+            settings,
+            RendererSettings {
+                render_scale: 0.5,
+                ..Default::default()
+            }
+        );
+    }
+}
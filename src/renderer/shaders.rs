@@ -1,31 +1,169 @@
-use core::slice;
 use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
+#[cfg(feature = "shader-reload")]
+use std::collections::VecDeque;
 use std::ffi::CStr;
+use std::fmt;
 use std::hash::{Hash, Hasher};
 
 use ash::vk;
+use itertools::Itertools;
 
 use spirv_reflect::types::ReflectDescriptorType;
 // To avoid a naming conflict
 use spirv_reflect::ShaderModule as ShaderModuleReflection;
 
-use super::error::{InvalidHandle, RendererError, SpirvError};
+#[cfg(feature = "shader-reload")]
+use super::error::ShaderReloadError;
+use super::error::{BindingContractError, InvalidHandle, RendererError, SpirvError};
+use super::light::MAX_LIGHT_COOKIES;
+use super::object_data::MAX_OBJECT_PARAMS_SIZE;
+use super::pipeline_cache::PipelineCache;
+#[cfg(feature = "shader-reload")]
+use super::timeline;
 use super::utils::{Handle, HandleArray};
 use super::RendererResult;
 
 const MAIN_FUNCTION_NAME: &CStr = unsafe { CStr::from_bytes_with_nul_unchecked(b"main\0") };
 
-fn hash_descriptor_layout_info(info: &vk::DescriptorSetLayoutCreateInfo) -> u64 {
-    let slice = unsafe {
-        let ptr = info as *const vk::DescriptorSetLayoutCreateInfo as *const u8;
-        slice::from_raw_parts(
-            ptr,
-            std::mem::size_of::<vk::DescriptorSetLayoutCreateInfo>(),
-        )
-    };
+// Pipeline layouts only have room for this many sets
+const MAX_SETS: u32 = 4;
+
+pub(crate) const VERTEX_AND_FRAGMENT: vk::ShaderStageFlags = vk::ShaderStageFlags::from_raw(
+    vk::ShaderStageFlags::VERTEX.as_raw() | vk::ShaderStageFlags::FRAGMENT.as_raw(),
+);
+
+pub struct GlobalBinding {
+    pub name: &'static str,
+    pub set: u32,
+    pub binding: u32,
+    pub descriptor_type: vk::DescriptorType,
+    pub count: u32,
+}
+
+// What the renderer binds to sets 0 and 1 of scene templates. Every scene effect gets
+// exactly these layouts, so shaders only have to declare the bindings they use.
+pub const GLOBAL_DESCRIPTORS: [GlobalBinding; 5] = [
+    GlobalBinding {
+        name: "camera and fog",
+        set: 0,
+        binding: 0,
+        descriptor_type: vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC,
+        count: 1,
+    },
+    GlobalBinding {
+        name: "lights",
+        set: 1,
+        binding: 0,
+        descriptor_type: vk::DescriptorType::STORAGE_BUFFER,
+        count: 1,
+    },
+    GlobalBinding {
+        name: "light cookies",
+        set: 1,
+        binding: 1,
+        descriptor_type: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+        count: MAX_LIGHT_COOKIES as u32,
+    },
+    GlobalBinding {
+        name: "morph deltas",
+        set: 1,
+        binding: 2,
+        descriptor_type: vk::DescriptorType::STORAGE_BUFFER,
+        count: 1,
+    },
+    GlobalBinding {
+        name: "shadow map",
+        set: 1,
+        binding: 3,
+        descriptor_type: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+        count: 1,
+    },
+];
+
+// A storage block with this name at set 1, binding 2 gets the morph target deltas of
+// every mesh, see morph.rs. Effects that declare it get the morph push constants.
+pub const MORPH_DELTAS_BLOCK: &str = "morph_deltas";
+
+// A uniform block with this name at binding 0 of OBJECT_PARAMS_SET gets the parameters
+// of the object being drawn, see SceneTree::set_object_params. Only for scene effects.
+pub const OBJECT_PARAMS_BLOCK: &str = "object_params";
+pub const OBJECT_PARAMS_SET: u32 = 3;
+
+// A uniform block with this name in the material set is filled from the ShaderParameters
+// of the material, by its member names. See MaterialSystem::set_parameter.
+pub const MATERIAL_PARAMS_BLOCK: &str = "material_parameters";
+
+// Blocks that are bound with a dynamic offset in every graphics effect
+const DYNAMIC_UNIFORM_BLOCKS: [(&str, vk::DescriptorType); 2] = [
+    ("ubo", vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC),
+    (
+        OBJECT_PARAMS_BLOCK,
+        vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC,
+    ),
+];
+
+// Which descriptor sets of an effect the renderer binds, checked when the effect is built
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BindingContract {
+    // Sets 0 and 1 hold GLOBAL_DESCRIPTORS and the material is set 2, otherwise the
+    // material is set 0 and nothing else is bound (e.g. text)
+    pub scene_globals: bool,
+    // Left alone by the renderer, a descriptor hook has to bind them before each draw
+    pub user_managed_sets: Vec<u32>,
+}
+
+impl BindingContract {
+    pub fn scene() -> Self {
+        Self {
+            scene_globals: true,
+            user_managed_sets: vec![],
+        }
+    }
+
+    pub fn material_only() -> Self {
+        Self {
+            scene_globals: false,
+            user_managed_sets: vec![],
+        }
+    }
+
+    pub fn with_user_managed_set(mut self, set: u32) -> Self {
+        self.user_managed_sets.push(set);
+        self
+    }
+
+    pub fn material_set(&self) -> u32 {
+        if self.scene_globals {
+            2
+        } else {
+            0
+        }
+    }
+
+    fn is_renderer_set(&self, set: u32) -> bool {
+        set == self.material_set() || (self.scene_globals && set < 2)
+    }
+
+    fn global_binding(&self, set: u32, binding: u32) -> Option<&'static GlobalBinding> {
+        if !self.scene_globals {
+            return None;
+        }
+        GLOBAL_DESCRIPTORS
+            .iter()
+            .find(|g| g.set == set && g.binding == binding)
+    }
+}
+
+// Hashes the contents of the bindings, so that identical layouts hash the same
+pub(crate) fn hash_descriptor_layout_bindings(bindings: &[vk::DescriptorSetLayoutBinding]) -> u64 {
     let mut hasher = DefaultHasher::new();
-    slice.hash(&mut hasher);
+    for b in bindings.iter().sorted_by_key(|b| b.binding) {
+        b.binding.hash(&mut hasher);
+        b.descriptor_type.hash(&mut hasher);
+        b.descriptor_count.hash(&mut hasher);
+        b.stage_flags.hash(&mut hasher);
+    }
     hasher.finish()
 }
 
@@ -57,6 +195,41 @@ struct ReflectedBinding {
     set: u32,
     binding: u32,
     typ: vk::DescriptorType,
+    count: u32,
+    stage: vk::ShaderStageFlags,
+    // Of uniform and storage blocks, 0 and empty for everything else
+    block_size: u32,
+    block_members: Vec<BlockMember>,
+}
+
+// A member of a uniform block, offset and size in bytes
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlockMember {
+    pub name: String,
+    pub offset: u32,
+    pub size: u32,
+}
+
+// The material_parameters block of an effect
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParameterBlock {
+    pub binding: u32,
+    pub size: u32,
+    pub members: Vec<BlockMember>,
+}
+
+impl fmt::Display for ReflectedBinding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "set {}, binding {}, {:?}",
+            self.set, self.binding, self.typ
+        )?;
+        if self.count != 1 {
+            write!(f, "[{}]", self.count)?;
+        }
+        write!(f, " in {:?}", self.stage)
+    }
 }
 
 #[derive(Default)]
@@ -72,6 +245,7 @@ pub struct ShaderEffect {
     bindings: HashMap<String, ReflectedBinding>,
     pub set_layouts: [vk::DescriptorSetLayout; 4],
     set_hashes: [u64; 4],
+    binding_contract: BindingContract,
 }
 
 impl ShaderEffect {
@@ -82,9 +256,154 @@ impl ShaderEffect {
             bindings: HashMap::new(),
             set_layouts: [vk::DescriptorSetLayout::null(); 4],
             set_hashes: [0u64; 4],
+            binding_contract: BindingContract::default(),
         }
     }
 
+    pub fn binding_contract(&self) -> &BindingContract {
+        &self.binding_contract
+    }
+
+    // Set, binding and type of everything the shaders declare, sorted by set and binding
+    pub fn descriptor_bindings(&self) -> impl Iterator<Item = (u32, u32, vk::DescriptorType)> + '_ {
+        self.bindings
+            .values()
+            .map(|b| (b.set, b.binding, b.typ))
+            .sorted_by_key(|(set, binding, _)| (*set, *binding))
+    }
+
+    // Names and bindings of the samplers in the material set, sorted by binding
+    pub fn material_sampler_bindings(&self) -> Vec<(&str, u32)> {
+        let material_set = self.binding_contract.material_set();
+        self.bindings
+            .iter()
+            .filter(|(_, b)| {
+                b.set == material_set && b.typ == vk::DescriptorType::COMBINED_IMAGE_SAMPLER
+            })
+            .map(|(name, b)| (name.as_str(), b.binding))
+            .sorted_by_key(|(_, binding)| *binding)
+            .collect()
+    }
+
+    // The size of the object_params block, None if the shaders don't declare one
+    pub fn object_params_size(&self) -> Option<u32> {
+        if !self.binding_contract.scene_globals {
+            return None;
+        }
+        self.bindings
+            .get(OBJECT_PARAMS_BLOCK)
+            .filter(|b| b.set == OBJECT_PARAMS_SET && b.binding == 0)
+            .map(|b| b.block_size)
+    }
+
+    pub fn material_parameter_block(&self) -> Option<ParameterBlock> {
+        let material_set = self.binding_contract.material_set();
+        self.bindings
+            .get(MATERIAL_PARAMS_BLOCK)
+            .filter(|b| b.set == material_set && b.typ == vk::DescriptorType::UNIFORM_BUFFER)
+            .map(|b| ParameterBlock {
+                binding: b.binding,
+                size: b.block_size,
+                members: b.block_members.clone(),
+            })
+    }
+
+    pub fn uses_morph_targets(&self) -> bool {
+        self.binding_contract.scene_globals
+            && self
+                .bindings
+                .get(MORPH_DELTAS_BLOCK)
+                .map_or(false, |b| b.set == 1 && b.binding == 2)
+    }
+
+    // Everything the shaders declare that the renderer would not bind
+    fn binding_contract_violations(&self, contract: &BindingContract) -> Vec<String> {
+        let mut violations = vec![];
+        for set in contract.user_managed_sets.iter() {
+            if *set >= MAX_SETS {
+                violations.push(format!(
+                    "user managed set {} is out of range, only sets 0 to {} exist",
+                    set,
+                    MAX_SETS - 1
+                ));
+            } else if contract.is_renderer_set(*set) {
+                violations.push(format!(
+                    "set {} is bound by the renderer and can't be user managed",
+                    set
+                ));
+            }
+        }
+        for (name, b) in self
+            .bindings
+            .iter()
+            .sorted_by_key(|(_, b)| (b.set, b.binding))
+        {
+            if b.set >= MAX_SETS {
+                violations.push(format!(
+                    "`{}` ({}): only sets 0 to {} are supported",
+                    name,
+                    b,
+                    MAX_SETS - 1
+                ));
+            } else if contract.scene_globals && name == OBJECT_PARAMS_BLOCK {
+                if b.set != OBJECT_PARAMS_SET
+                    || b.binding != 0
+                    || b.typ != vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC
+                {
+                    violations.push(format!(
+                        "`{}` ({}): object parameters are a uniform block at set {}, binding 0",
+                        name, b, OBJECT_PARAMS_SET
+                    ));
+                } else if contract.user_managed_sets.contains(&OBJECT_PARAMS_SET) {
+                    violations.push(format!(
+                        "`{}` ({}): set {} can't be user managed, it holds the object parameters",
+                        name, b, OBJECT_PARAMS_SET
+                    ));
+                } else if b.block_size as usize > MAX_OBJECT_PARAMS_SIZE {
+                    violations.push(format!(
+                        "`{}` ({}): the block is {} bytes, objects only have room for {}",
+                        name, b, b.block_size, MAX_OBJECT_PARAMS_SIZE
+                    ));
+                }
+            } else if contract.user_managed_sets.contains(&b.set)
+                || b.set == contract.material_set()
+            {
+                continue;
+            } else if contract.is_renderer_set(b.set) {
+                match contract.global_binding(b.set, b.binding) {
+                    // Shaders can't declare a buffer as dynamic, so plain uniform buffers
+                    // are accepted for dynamic ones
+                    Some(g)
+                        if (g.descriptor_type == b.typ
+                            || (g.descriptor_type
+                                == vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC
+                                && b.typ == vk::DescriptorType::UNIFORM_BUFFER))
+                            && b.count <= g.count => {}
+                    Some(g) => violations.push(format!(
+                        "`{}` ({}): the renderer binds the {} there as {:?}[{}]",
+                        name, b, g.name, g.descriptor_type, g.count
+                    )),
+                    None => violations.push(format!(
+                        "`{}` ({}): the renderer binds nothing there",
+                        name, b
+                    )),
+                }
+            } else {
+                violations.push(format!(
+                    "`{}` ({}): set {} is not bound by the renderer, declare it as a user \
+                     managed set and bind it from a descriptor hook",
+                    name, b, b.set
+                ));
+            }
+        }
+        violations
+    }
+
+    // 0 if the set is not used by the effect
+    pub fn get_set_hash(&self, set: usize) -> u64 {
+        self.set_hashes[set]
+    }
+
     pub fn add_stage(
         &mut self,
         handle: Handle<ShaderModule>,
@@ -119,6 +438,7 @@ impl ShaderEffect {
         device: &ash::Device,
         shader_cache: &ShaderCache,
         overrides: &[(&str, vk::DescriptorType)],
+        contract: &BindingContract,
     ) -> RendererResult<()> {
         let mut set_layouts = vec![];
         let mut constant_ranges = vec![];
@@ -193,6 +513,19 @@ impl ShaderEffect {
                         binding: layout_binding.binding,
                         set: set.set,
                         typ: desc_type,
+                        count: desc_count,
+                        stage: shader_stage.stage,
+                        block_size: binding.block.size,
+                        block_members: binding
+                            .block
+                            .members
+                            .iter()
+                            .map(|member| BlockMember {
+                                name: member.name.clone(),
+                                offset: member.offset,
+                                size: member.size,
+                            })
+                            .collect(),
                     };
                     self.bindings
                         .insert(binding.name.clone(), reflected_binding);
@@ -219,6 +552,20 @@ impl ShaderEffect {
             }
         }
 
+        let violations = self.binding_contract_violations(contract);
+        if !violations.is_empty() {
+            return Err(BindingContractError(violations.join("; ")).into());
+        }
+        self.binding_contract = contract.clone();
+
+        // Sets below the highest used one still need a layout, even if it is empty
+        let set_count = set_layouts
+            .iter()
+            .map(|layout| layout.set + 1)
+            .chain(std::iter::once(if contract.scene_globals { 2 } else { 0 }))
+            .max()
+            .unwrap_or(0);
+
         let mut merged_layouts = [
             DescriptorSetLayoutData::default(),
             DescriptorSetLayoutData::default(),
@@ -232,8 +579,21 @@ impl ShaderEffect {
             layout_data.set = i;
 
             let mut binds: HashMap<u32, vk::DescriptorSetLayoutBinding> = HashMap::new();
+            if contract.scene_globals && i < 2 {
+                for g in GLOBAL_DESCRIPTORS.iter().filter(|g| g.set == i) {
+                    binds.insert(
+                        g.binding,
+                        vk::DescriptorSetLayoutBinding::builder()
+                            .binding(g.binding)
+                            .descriptor_type(g.descriptor_type)
+                            .descriptor_count(g.count)
+                            .stage_flags(VERTEX_AND_FRAGMENT)
+                            .build(),
+                    );
+                }
+            }
             for set in set_layouts.iter() {
-                if set.set == i {
+                if set.set == i && !(contract.scene_globals && i < 2) {
                     for binding in set.bindings.iter() {
                         binds
                             .entry(binding.binding)
@@ -243,6 +603,13 @@ impl ShaderEffect {
                 }
             }
 
+            // The renderer binds one set for the object parameters of every effect, so
+            // they all get the same layout
+            if i == OBJECT_PARAMS_SET && self.object_params_size().is_some() {
+                if let Some(binding) = binds.get_mut(&0) {
+                    binding.stage_flags = VERTEX_AND_FRAGMENT;
+                }
+            }
             for binding in binds.values() {
                 layout_data.bindings.push(*binding);
             }
@@ -250,11 +617,12 @@ impl ShaderEffect {
                 .bindings
                 .sort_by(|a, b| a.binding.cmp(&b.binding));
 
-            if !layout_data.bindings.is_empty() {
+            if !layout_data.bindings.is_empty() || i < set_count {
                 layout_data.create_info = vk::DescriptorSetLayoutCreateInfo::builder()
                     .bindings(&layout_data.bindings)
                     .build();
-                self.set_hashes[i as usize] = hash_descriptor_layout_info(&layout_data.create_info);
+                self.set_hashes[i as usize] =
+                    hash_descriptor_layout_bindings(&layout_data.bindings);
                 self.set_layouts[i as usize] =
                     unsafe { device.create_descriptor_set_layout(&layout_data.create_info, None)? };
             } else {
@@ -281,6 +649,15 @@ impl ShaderEffect {
         Ok(())
     }
 
+    // The sets whose layout differs from other's, descriptor sets allocated for one of
+    // them can only be bound with the other if this is empty
+    #[cfg(feature = "shader-reload")]
+    pub fn differing_sets(&self, other: &ShaderEffect) -> Vec<u32> {
+        (0..MAX_SETS)
+            .filter(|set| self.set_hashes[*set as usize] != other.set_hashes[*set as usize])
+            .collect()
+    }
+
     pub fn destroy(&mut self, device: &ash::Device) {
         unsafe {
             device.destroy_pipeline_layout(self.pipeline_layout, None);
@@ -302,15 +679,93 @@ impl Default for ShaderEffect {
     }
 }
 
+// What a shader reload replaced, kept until no frame in flight can use it any more
+#[cfg(feature = "shader-reload")]
+#[derive(Default)]
+pub struct RetiredShaders {
+    pub pipelines: Vec<vk::Pipeline>,
+    pub effects: Vec<ShaderEffect>,
+}
+
+#[cfg(feature = "shader-reload")]
+impl RetiredShaders {
+    fn destroy(&mut self, device: &ash::Device) {
+        for pipeline in self.pipelines.drain(..) {
+            unsafe { device.destroy_pipeline(pipeline, None) };
+        }
+        for mut effect in self.effects.drain(..) {
+            effect.destroy(device);
+        }
+    }
+}
+
+// The modules ShaderCache::reload_from_disk replaced, with the hashes of their sources.
+// finish_reload destroys them, revert_reload puts them back.
+#[cfg(feature = "shader-reload")]
+#[derive(Default)]
+pub struct ShaderReload {
+    changed: Vec<String>,
+    previous: Vec<(Handle<ShaderModule>, String, ShaderModule, u64)>,
+}
+
+#[cfg(feature = "shader-reload")]
+impl ShaderReload {
+    // The shaders whose sources changed, e.g. ./shaders/default.frag
+    pub fn changed(&self) -> &[String] {
+        &self.changed
+    }
+}
+
+#[cfg(feature = "shader-reload")]
+fn source_hash(source: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    source.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(feature = "shader-reload")]
+fn compile_glsl(
+    compiler: &mut shaderc::Compiler,
+    path: &str,
+    source: &str,
+) -> Result<Vec<u32>, String> {
+    let kind = match path.rsplit('.').next() {
+        Some("vert") => shaderc::ShaderKind::Vertex,
+        Some("frag") => shaderc::ShaderKind::Fragment,
+        _ => return Err(format!("{}: not a vertex or fragment shader", path)),
+    };
+    let mut options = shaderc::CompileOptions::new()
+        .ok_or_else(|| format!("{}: could not create the compile options", path))?;
+    options.set_target_env(
+        shaderc::TargetEnv::Vulkan,
+        shaderc::EnvVersion::Vulkan1_0 as u32,
+    );
+    compiler
+        .compile_into_spirv(source, kind, path, "main", Some(&options))
+        .map(|artifact| artifact.as_binary().to_vec())
+        .map_err(|e| format!("{}: {}", path, e))
+}
+
 pub struct ShaderCache {
     module_handles: HandleArray<ShaderModule>,
     module_cache: HashMap<String, Handle<ShaderModule>>,
 
     effects_handles: HandleArray<ShaderEffect>,
+
+    // Every pipeline built from the effects goes through it
+    pipeline_cache: PipelineCache,
+
+    // Hashes of the files in ./shaders the modules were compiled from, see
+    // reload_from_disk
+    #[cfg(feature = "shader-reload")]
+    source_hashes: HashMap<String, u64>,
+    // Replaced by reloads, with the last frame that may still use them
+    #[cfg(feature = "shader-reload")]
+    retired: VecDeque<(RetiredShaders, u64)>,
 }
 
 impl ShaderCache {
-    pub fn new(device: &ash::Device) -> RendererResult<Self> {
+    pub(crate) fn new(device: &ash::Device, pipeline_cache: PipelineCache) -> RendererResult<Self> {
         let mut module_handles = HandleArray::new();
         let mut module_cache = HashMap::new();
 
@@ -330,6 +785,22 @@ impl ShaderCache {
             let handle = module_handles.insert(module);
             module_cache.insert("./shaders/default.frag".to_string(), handle);
         }
+        {
+            let module = ShaderModule::new(
+                device,
+                vk_shader_macros::include_glsl!("./shaders/lightmapped.frag", kind: frag).to_vec(),
+            )?;
+            let handle = module_handles.insert(module);
+            module_cache.insert("./shaders/lightmapped.frag".to_string(), handle);
+        }
+        {
+            let module = ShaderModule::new(
+                device,
+                vk_shader_macros::include_glsl!("./shaders/transparent.frag", kind: frag).to_vec(),
+            )?;
+            let handle = module_handles.insert(module);
+            module_cache.insert("./shaders/transparent.frag".to_string(), handle);
+        }
         {
             let module = ShaderModule::new(
                 device,
@@ -346,14 +817,199 @@ impl ShaderCache {
             let handle = module_handles.insert(module);
             module_cache.insert("./shaders/text.frag".to_string(), handle);
         }
+        {
+            let module = ShaderModule::new(
+                device,
+                vk_shader_macros::include_glsl!("./shaders/cursor.frag", kind: frag).to_vec(),
+            )?;
+            let handle = module_handles.insert(module);
+            module_cache.insert("./shaders/cursor.frag".to_string(), handle);
+        }
+        {
+            let module = ShaderModule::new(
+                device,
+                vk_shader_macros::include_glsl!("./shaders/particle.vert", kind: vert).to_vec(),
+            )?;
+            let handle = module_handles.insert(module);
+            module_cache.insert("./shaders/particle.vert".to_string(), handle);
+        }
+        {
+            let module = ShaderModule::new(
+                device,
+                vk_shader_macros::include_glsl!("./shaders/particle.frag", kind: frag).to_vec(),
+            )?;
+            let handle = module_handles.insert(module);
+            module_cache.insert("./shaders/particle.frag".to_string(), handle);
+        }
+        {
+            let module = ShaderModule::new(
+                device,
+                vk_shader_macros::include_glsl!("./shaders/particle_msaa.frag", kind: frag)
+                    .to_vec(),
+            )?;
+            let handle = module_handles.insert(module);
+            module_cache.insert("./shaders/particle_msaa.frag".to_string(), handle);
+        }
+        {
+            let module = ShaderModule::new(
+                device,
+                vk_shader_macros::include_glsl!("./shaders/transparent_oit.frag", kind: frag)
+                    .to_vec(),
+            )?;
+            let handle = module_handles.insert(module);
+            module_cache.insert("./shaders/transparent_oit.frag".to_string(), handle);
+        }
+        {
+            let module = ShaderModule::new(
+                device,
+                vk_shader_macros::include_glsl!("./shaders/oit_composite.frag", kind: frag)
+                    .to_vec(),
+            )?;
+            let handle = module_handles.insert(module);
+            module_cache.insert("./shaders/oit_composite.frag".to_string(), handle);
+        }
+        {
+            let module = ShaderModule::new(
+                device,
+                vk_shader_macros::include_glsl!("./shaders/oit_composite_msaa.frag", kind: frag)
+                    .to_vec(),
+            )?;
+            let handle = module_handles.insert(module);
+            module_cache.insert("./shaders/oit_composite_msaa.frag".to_string(), handle);
+        }
+        {
+            let module = ShaderModule::new(
+                device,
+                vk_shader_macros::include_glsl!("./shaders/upscale.vert", kind: vert).to_vec(),
+            )?;
+            let handle = module_handles.insert(module);
+            module_cache.insert("./shaders/upscale.vert".to_string(), handle);
+        }
+        {
+            let module = ShaderModule::new(
+                device,
+                vk_shader_macros::include_glsl!("./shaders/upscale_bilinear.frag", kind: frag)
+                    .to_vec(),
+            )?;
+            let handle = module_handles.insert(module);
+            module_cache.insert("./shaders/upscale_bilinear.frag".to_string(), handle);
+        }
+        {
+            let module = ShaderModule::new(
+                device,
+                vk_shader_macros::include_glsl!("./shaders/output_encode.frag", kind: frag)
+                    .to_vec(),
+            )?;
+            let handle = module_handles.insert(module);
+            module_cache.insert("./shaders/output_encode.frag".to_string(), handle);
+        }
+        {
+            let module = ShaderModule::new(
+                device,
+                vk_shader_macros::include_glsl!("./shaders/shadow.vert", kind: vert).to_vec(),
+            )?;
+            let handle = module_handles.insert(module);
+            module_cache.insert("./shaders/shadow.vert".to_string(), handle);
+        }
+        {
+            let module = ShaderModule::new(
+                device,
+                vk_shader_macros::include_glsl!("./shaders/aov.vert", kind: vert).to_vec(),
+            )?;
+            let handle = module_handles.insert(module);
+            module_cache.insert("./shaders/aov.vert".to_string(), handle);
+        }
+        {
+            let module = ShaderModule::new(
+                device,
+                vk_shader_macros::include_glsl!("./shaders/aov.frag", kind: frag).to_vec(),
+            )?;
+            let handle = module_handles.insert(module);
+            module_cache.insert("./shaders/aov.frag".to_string(), handle);
+        }
+        {
+            let module = ShaderModule::new(
+                device,
+                vk_shader_macros::include_glsl!("./shaders/skybox.vert", kind: vert).to_vec(),
+            )?;
+            let handle = module_handles.insert(module);
+            module_cache.insert("./shaders/skybox.vert".to_string(), handle);
+        }
+        {
+            let module = ShaderModule::new(
+                device,
+                vk_shader_macros::include_glsl!("./shaders/skybox.frag", kind: frag).to_vec(),
+            )?;
+            let handle = module_handles.insert(module);
+            module_cache.insert("./shaders/skybox.frag".to_string(), handle);
+        }
+        {
+            let module = ShaderModule::new(
+                device,
+                vk_shader_macros::include_glsl!("./shaders/velocity.vert", kind: vert).to_vec(),
+            )?;
+            let handle = module_handles.insert(module);
+            module_cache.insert("./shaders/velocity.vert".to_string(), handle);
+        }
+        {
+            let module = ShaderModule::new(
+                device,
+                vk_shader_macros::include_glsl!("./shaders/velocity.frag", kind: frag).to_vec(),
+            )?;
+            let handle = module_handles.insert(module);
+            module_cache.insert("./shaders/velocity.frag".to_string(), handle);
+        }
+        {
+            let module = ShaderModule::new(
+                device,
+                vk_shader_macros::include_glsl!("./shaders/lightmap_bake.vert", kind: vert)
+                    .to_vec(),
+            )?;
+            let handle = module_handles.insert(module);
+            module_cache.insert("./shaders/lightmap_bake.vert".to_string(), handle);
+        }
+        {
+            let module = ShaderModule::new(
+                device,
+                vk_shader_macros::include_glsl!("./shaders/lightmap_bake.frag", kind: frag)
+                    .to_vec(),
+            )?;
+            let handle = module_handles.insert(module);
+            module_cache.insert("./shaders/lightmap_bake.frag".to_string(), handle);
+        }
+
+        // The embedded SPIR-V was compiled from the files as they are now, unless they
+        // were edited since the build
+        #[cfg(feature = "shader-reload")]
+        let source_hashes = module_cache
+            .keys()
+            .filter_map(|path| {
+                let source = std::fs::read_to_string(path).ok()?;
+                Some((path.clone(), source_hash(&source)))
+            })
+            .collect();
 
         Ok(Self {
             module_handles,
             module_cache,
             effects_handles: HandleArray::new(),
+            pipeline_cache,
+            #[cfg(feature = "shader-reload")]
+            source_hashes,
+            #[cfg(feature = "shader-reload")]
+            retired: VecDeque::new(),
         })
     }
 
+    pub(crate) fn pipeline_cache(&self) -> vk::PipelineCache {
+        self.pipeline_cache.handle()
+    }
+
+    // Before destroy, so the next run starts with the pipelines of this one
+    pub(crate) fn save_pipeline_cache(&self, device: &ash::Device) -> RendererResult<()> {
+        self.pipeline_cache.save(device)
+    }
+
     pub fn get_shader_handle<S: AsRef<str>>(
         &self,
         path: S,
@@ -371,13 +1027,41 @@ impl ShaderCache {
         self.module_handles.get(handle).ok_or(InvalidHandle.into())
     }
 
+    // Registers SPIR-V compiled at runtime under a name that build_effect can use. An
+    // existing module with the same name is replaced, pipelines built from it are not
+    // affected.
+    pub fn add_shader_module(
+        &mut self,
+        device: &ash::Device,
+        name: &str,
+        code: Vec<u32>,
+    ) -> RendererResult<Handle<ShaderModule>> {
+        // Catch garbage before it gets to the driver
+        ShaderModuleReflection::load_u32_data(&code)
+            .map_err::<RendererError, _>(|e| SpirvError(e).into())?;
+        let module = ShaderModule::new(device, code)?;
+        // There is no source to reload it from any more
+        #[cfg(feature = "shader-reload")]
+        self.source_hashes.remove(name);
+        if let Some(handle) = self.module_cache.get(name) {
+            if let Some(old) = self.module_handles.get_mut(*handle) {
+                old.destroy(device);
+                *old = module;
+                return Ok(*handle);
+            }
+        }
+        let handle = self.module_handles.insert(module);
+        self.module_cache.insert(name.to_string(), handle);
+        Ok(handle)
+    }
+
     pub fn build_effect(
         &mut self,
         device: &ash::Device,
         vertex_shader: &str,
         fragment_shader: Option<&str>,
+        contract: &BindingContract,
     ) -> RendererResult<Handle<ShaderEffect>> {
-        let overrides = [("ubo", vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC)];
         let mut effect = ShaderEffect::new();
         effect.add_stage(
             self.get_shader_handle(vertex_shader)?,
@@ -387,13 +1071,42 @@ impl ShaderCache {
             effect.add_stage(self.get_shader_handle(fs)?, vk::ShaderStageFlags::FRAGMENT)?;
         }
 
-        effect.reflect_layout(device, self, &overrides)?;
+        effect
+            .reflect_layout(device, self, &DYNAMIC_UNIFORM_BLOCKS, contract)
+            .map_err(|e| match e {
+                RendererError::BindingContractError { source, .. } => {
+                    BindingContractError(format!(
+                        "{} + {}: {}",
+                        vertex_shader,
+                        fragment_shader.unwrap_or("no fragment shader"),
+                        source.0
+                    ))
+                    .into()
+                }
+                e => e,
+            })?;
 
         let handle = self.effects_handles.insert(effect);
 
         Ok(handle)
     }
 
+    // Compute shaders get nothing from the renderer, so they only use their own sets
+    pub fn build_compute_effect(
+        &mut self,
+        device: &ash::Device,
+        compute_shader: &str,
+    ) -> RendererResult<Handle<ShaderEffect>> {
+        let mut effect = ShaderEffect::new();
+        effect.add_stage(
+            self.get_shader_handle(compute_shader)?,
+            vk::ShaderStageFlags::COMPUTE,
+        )?;
+        effect.reflect_layout(device, self, &[], &BindingContract::material_only())?;
+
+        Ok(self.effects_handles.insert(effect))
+    }
+
     pub fn get_shader_effect_by_handle(
         &self,
         handle: Handle<ShaderEffect>,
@@ -401,6 +1114,162 @@ impl ShaderCache {
         self.effects_handles.get(handle).ok_or(InvalidHandle.into())
     }
 
+    // Recompiles the modules loaded from ./shaders whose files changed since they were
+    // compiled and swaps the new modules in. Nothing is changed when any of them fails to
+    // compile. What was built from the old modules keeps working, see
+    // MaterialSystem::rebuild_templates. Compute shaders are not reloaded, the passes
+    // using them keep their pipelines.
+    #[cfg(feature = "shader-reload")]
+    pub fn reload_from_disk(&mut self, device: &ash::Device) -> RendererResult<ShaderReload> {
+        let mut compiler = shaderc::Compiler::new()
+            .ok_or_else(|| ShaderReloadError("could not start shaderc".to_string()))?;
+        let mut compiled = vec![];
+        let mut errors = vec![];
+        for (path, old_hash) in self.source_hashes.iter().sorted() {
+            let handle = match self.module_cache.get(path) {
+                Some(handle) => *handle,
+                None => continue,
+            };
+            let source = match std::fs::read_to_string(path) {
+                Ok(source) => source,
+                Err(e) => {
+                    errors.push(format!("{}: {}", path, e));
+                    continue;
+                }
+            };
+            let hash = source_hash(&source);
+            if hash == *old_hash {
+                continue;
+            }
+            match compile_glsl(&mut compiler, path, &source) {
+                Ok(code) => compiled.push((handle, path.clone(), code, hash, *old_hash)),
+                Err(e) => errors.push(e),
+            }
+        }
+        if !errors.is_empty() {
+            return Err(ShaderReloadError(errors.join("\n")).into());
+        }
+
+        let mut modules = Vec::with_capacity(compiled.len());
+        for (handle, path, code, hash, old_hash) in compiled {
+            let module = ShaderModuleReflection::load_u32_data(&code)
+                .map_err::<RendererError, _>(|e| SpirvError(e).into())
+                .and_then(|_| ShaderModule::new(device, code));
+            match module {
+                Ok(module) => modules.push((handle, path, module, hash, old_hash)),
+                Err(e) => {
+                    for (_, _, mut module, _, _) in modules {
+                        module.destroy(device);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+
+        let mut reload = ShaderReload::default();
+        for (handle, path, module, hash, old_hash) in modules {
+            if let Some(current) = self.module_handles.get_mut(handle) {
+                let previous = std::mem::replace(current, module);
+                self.source_hashes.insert(path.clone(), hash);
+                reload
+                    .previous
+                    .push((handle, path.clone(), previous, old_hash));
+                reload.changed.push(path);
+            }
+        }
+        Ok(reload)
+    }
+
+    // Pipelines don't need their modules after they are created, so the replaced ones go
+    // right away. Returns the shaders that changed.
+    #[cfg(feature = "shader-reload")]
+    pub fn finish_reload(&mut self, device: &ash::Device, reload: ShaderReload) -> Vec<String> {
+        for (_, _, mut previous, _) in reload.previous {
+            previous.destroy(device);
+        }
+        reload.changed
+    }
+
+    // Puts back the modules a reload replaced, when nothing could be built from the new
+    // ones
+    #[cfg(feature = "shader-reload")]
+    pub fn revert_reload(&mut self, device: &ash::Device, reload: ShaderReload) {
+        for (handle, path, previous, previous_hash) in reload.previous {
+            if let Some(current) = self.module_handles.get_mut(handle) {
+                let mut replaced = std::mem::replace(current, previous);
+                replaced.destroy(device);
+            }
+            self.source_hashes.insert(path, previous_hash);
+        }
+    }
+
+    #[cfg(feature = "shader-reload")]
+    pub fn effect_uses_shaders(&self, handle: Handle<ShaderEffect>, shaders: &[String]) -> bool {
+        self.get_shader_effect_by_handle(handle)
+            .map_or(false, |effect| {
+                effect.stages.iter().any(|stage| {
+                    shaders
+                        .iter()
+                        .any(|name| self.module_cache.get(name) == Some(&stage.handle))
+                })
+            })
+    }
+
+    // A new effect from the current modules of the effect's stages, with the same
+    // binding contract. It is not stored, see replace_effect.
+    #[cfg(feature = "shader-reload")]
+    pub fn rebuild_effect(
+        &self,
+        device: &ash::Device,
+        handle: Handle<ShaderEffect>,
+    ) -> RendererResult<ShaderEffect> {
+        let old = self.get_shader_effect_by_handle(handle)?;
+        let mut effect = ShaderEffect::new();
+        for stage in old.stages.iter() {
+            effect.add_stage(stage.handle, stage.stage)?;
+        }
+        let overrides: &[(&str, vk::DescriptorType)] = if old
+            .stages
+            .iter()
+            .any(|stage| stage.stage == vk::ShaderStageFlags::COMPUTE)
+        {
+            &[]
+        } else {
+            &DYNAMIC_UNIFORM_BLOCKS
+        };
+        if let Err(e) = effect.reflect_layout(device, self, overrides, &old.binding_contract) {
+            effect.destroy(device);
+            return Err(e);
+        }
+        Ok(effect)
+    }
+
+    // Stores the effect under handle and gives back the one it replaces. The set layouts
+    // of the old effect stay, descriptor sets allocated with a layout can't be updated
+    // after it is destroyed. So differing_sets of the two has to be empty.
+    #[cfg(feature = "shader-reload")]
+    pub fn replace_effect(
+        &mut self,
+        handle: Handle<ShaderEffect>,
+        mut effect: ShaderEffect,
+    ) -> RendererResult<ShaderEffect> {
+        let current = self.effects_handles.get_mut(handle).ok_or(InvalidHandle)?;
+        std::mem::swap(&mut effect.set_layouts, &mut current.set_layouts);
+        Ok(std::mem::replace(current, effect))
+    }
+
+    #[cfg(feature = "shader-reload")]
+    pub(crate) fn retire(&mut self, retired: RetiredShaders, last_submitted_frame: u64) {
+        self.retired.push_back((retired, last_submitted_frame));
+    }
+
+    #[cfg(feature = "shader-reload")]
+    pub(crate) fn destroy_retired(&mut self, device: &ash::Device, completed_frame: u64) {
+        for mut retired in timeline::retired(&mut self.retired, completed_frame) {
+            retired.destroy(device);
+        }
+    }
+
     pub fn destroy(&mut self, device: &ash::Device) {
         for module in self.module_handles.iter_mut() {
             module.destroy(device);
@@ -411,5 +1280,10 @@ impl ShaderCache {
             effect.destroy(device);
         }
         self.effects_handles.clear();
+        self.pipeline_cache.destroy(device);
+        #[cfg(feature = "shader-reload")]
+        for (mut retired, _) in self.retired.drain(..) {
+            retired.destroy(device);
+        }
     }
 }
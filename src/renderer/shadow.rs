@@ -0,0 +1,346 @@
+use ash::vk;
+use gpu_allocator::{
+    vulkan::{Allocation, AllocationCreateDesc, AllocationScheme, Allocator},
+    MemoryLocation,
+};
+use nalgebra_glm as glm;
+
+use super::{context::VulkanContext, debug_namer::DebugNamer, RendererResult};
+
+pub const SHADOW_MAP_FORMAT: vk::Format = vk::Format::D32_SFLOAT;
+
+// Matches light_view_projection and shadow_params of the UniformBufferObject in the
+// default shaders, right after the fog
+pub(crate) type ShadowUniformData = [[f32; 4]; 5];
+
+// Looks along the light, which ends up looking along -z
+fn light_rotation(direction_to_light: &glm::Vec3) -> glm::Mat4 {
+    let direction = direction_to_light.normalize();
+    // Any up works as long as it is not along the light
+    let up = if direction.y.abs() < 0.99 {
+        glm::vec3(0.0, -1.0, 0.0)
+    } else {
+        glm::vec3(1.0, 0.0, 0.0)
+    };
+    glm::look_at_rh(&glm::Vec3::zeros(), &-direction, &up)
+}
+
+// An orthographic view along the light onto a square of radius around center. Casters
+// up to twice the radius towards the light still land in the map. The center is moved
+// to whole texels, so the edges of the shadows don't shimmer when the camera moves.
+pub(crate) fn light_view_projection(
+    direction_to_light: &glm::Vec3,
+    center: &glm::Vec3,
+    radius: f32,
+    resolution: u32,
+) -> glm::Mat4 {
+    let rotation = light_rotation(direction_to_light);
+    let light_center = (rotation * glm::vec4(center.x, center.y, center.z, 1.0)).xyz();
+    let texel = 2.0 * radius / resolution.max(1) as f32;
+    let x = (light_center.x / texel).floor() * texel;
+    let y = (light_center.y / texel).floor() * texel;
+    // The light looks along -z
+    let distance = -light_center.z;
+    let projection = glm::ortho_rh_zo(
+        x - radius,
+        x + radius,
+        y - radius,
+        y + radius,
+        distance - 2.0 * radius,
+        distance + radius,
+    );
+    projection * rotation
+}
+
+// For a lightmap bake, the square of radius around the object at center, without moving
+// it to whole texels. Casters up to reach towards the light land in the map.
+pub(crate) fn bake_view_projection(
+    direction_to_light: &glm::Vec3,
+    center: &glm::Vec3,
+    radius: f32,
+    reach: f32,
+) -> glm::Mat4 {
+    let rotation = light_rotation(direction_to_light);
+    let light_center = (rotation * glm::vec4(center.x, center.y, center.z, 1.0)).xyz();
+    let distance = -light_center.z;
+    let projection = glm::ortho_rh_zo(
+        light_center.x - radius,
+        light_center.x + radius,
+        light_center.y - radius,
+        light_center.y + radius,
+        distance - radius.max(reach),
+        distance + radius,
+    );
+    projection * rotation
+}
+
+// Tells the shaders to skip the lookup, for views without a shadow map
+pub(crate) const NO_SHADOW: ShadowUniformData = [[0.0; 4]; 5];
+
+// The depth of the scene seen from the first directional light, rendered before the
+// forward pass every frame that has one and sampled by default.frag. Frames in flight
+// share it, the render pass waits for the previous frame's reads.
+pub(crate) struct ShadowMap {
+    pub render_pass: vk::RenderPass,
+    pub framebuffer: vk::Framebuffer,
+    pub resolution: u32,
+    image: vk::Image,
+    allocation: Option<Allocation>,
+    view: vk::ImageView,
+    // Compares against the depth, outside of the map everything is lit
+    sampler: vk::Sampler,
+}
+
+impl ShadowMap {
+    pub fn new(
+        context: &VulkanContext,
+        allocator: &mut Allocator,
+        command_pool: vk::CommandPool,
+        resolution: u32,
+    ) -> RendererResult<Self> {
+        let device = &context.device;
+        let resolution = resolution.max(1);
+        let render_pass = Self::create_render_pass(device)?;
+
+        let queue_family_indices = [context.graphics_queue.index];
+        let image_info = vk::ImageCreateInfo::builder()
+            .image_type(vk::ImageType::TYPE_2D)
+            .format(SHADOW_MAP_FORMAT)
+            .extent(vk::Extent3D {
+                width: resolution,
+                height: resolution,
+                depth: 1,
+            })
+            .mip_levels(1)
+            .array_layers(1)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .tiling(vk::ImageTiling::OPTIMAL)
+            .usage(vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT | vk::ImageUsageFlags::SAMPLED)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE)
+            .queue_family_indices(&queue_family_indices);
+        let image = unsafe { device.create_image(&image_info, None) }?;
+        let reqs = unsafe { device.get_image_memory_requirements(image) };
+        let allocation = allocator.allocate(&AllocationCreateDesc {
+            name: "shadow_map",
+            requirements: reqs,
+            location: MemoryLocation::GpuOnly,
+            linear: false,
+            allocation_scheme: AllocationScheme::GpuAllocatorManaged,
+        })?;
+        unsafe { device.bind_image_memory(image, allocation.memory(), allocation.offset())? };
+
+        let subresource_range = vk::ImageSubresourceRange::builder()
+            .aspect_mask(vk::ImageAspectFlags::DEPTH)
+            .base_mip_level(0)
+            .level_count(1)
+            .base_array_layer(0)
+            .layer_count(1)
+            .build();
+        let view_info = vk::ImageViewCreateInfo::builder()
+            .image(image)
+            .view_type(vk::ImageViewType::TYPE_2D)
+            .format(SHADOW_MAP_FORMAT)
+            .subresource_range(subresource_range);
+        let view = unsafe { device.create_image_view(&view_info, None) }?;
+
+        let views = [view];
+        let framebuffer_info = vk::FramebufferCreateInfo::builder()
+            .render_pass(render_pass)
+            .attachments(&views)
+            .width(resolution)
+            .height(resolution)
+            .layers(1);
+        let framebuffer = unsafe { device.create_framebuffer(&framebuffer_info, None) }?;
+
+        let sampler_info = vk::SamplerCreateInfo::builder()
+            .mag_filter(vk::Filter::LINEAR)
+            .min_filter(vk::Filter::LINEAR)
+            .address_mode_u(vk::SamplerAddressMode::CLAMP_TO_BORDER)
+            .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_BORDER)
+            .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_BORDER)
+            .border_color(vk::BorderColor::FLOAT_OPAQUE_WHITE)
+            .compare_enable(true)
+            .compare_op(vk::CompareOp::LESS_OR_EQUAL);
+        let sampler = unsafe { device.create_sampler(&sampler_info, None) }?;
+
+        let shadow_map = Self {
+            render_pass,
+            framebuffer,
+            resolution,
+            image,
+            allocation: Some(allocation),
+            view,
+            sampler,
+        };
+        // Frames without a directional light don't render the map, but it is bound all
+        // the same
+        shadow_map.transition_to_shader_read(context, command_pool, subresource_range)?;
+        Ok(shadow_map)
+    }
+
+    // The map ends up ready to be sampled. The first dependency keeps the clear from
+    // overwriting what the previous frame is still reading.
+    fn create_render_pass(device: &ash::Device) -> RendererResult<vk::RenderPass> {
+        let attachments = [vk::AttachmentDescription::builder()
+            .format(SHADOW_MAP_FORMAT)
+            .load_op(vk::AttachmentLoadOp::CLEAR)
+            .store_op(vk::AttachmentStoreOp::STORE)
+            .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+            .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+            .initial_layout(vk::ImageLayout::UNDEFINED)
+            .final_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .build()];
+
+        let depth_attachment_reference = vk::AttachmentReference {
+            attachment: 0,
+            layout: vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+        };
+
+        let subpasses = [vk::SubpassDescription::builder()
+            .depth_stencil_attachment(&depth_attachment_reference)
+            .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+            .build()];
+
+        let subpass_dependencies = [
+            vk::SubpassDependency::builder()
+                .src_subpass(vk::SUBPASS_EXTERNAL)
+                .src_stage_mask(vk::PipelineStageFlags::FRAGMENT_SHADER)
+                .src_access_mask(vk::AccessFlags::SHADER_READ)
+                .dst_subpass(0)
+                .dst_stage_mask(
+                    vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS
+                        | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
+                )
+                .dst_access_mask(vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE)
+                .build(),
+            vk::SubpassDependency::builder()
+                .src_subpass(0)
+                .src_stage_mask(vk::PipelineStageFlags::LATE_FRAGMENT_TESTS)
+                .src_access_mask(vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE)
+                .dst_subpass(vk::SUBPASS_EXTERNAL)
+                .dst_stage_mask(vk::PipelineStageFlags::FRAGMENT_SHADER)
+                .dst_access_mask(vk::AccessFlags::SHADER_READ)
+                .build(),
+        ];
+
+        let renderpass_info = vk::RenderPassCreateInfo::builder()
+            .attachments(&attachments)
+            .subpasses(&subpasses)
+            .dependencies(&subpass_dependencies);
+        unsafe { Ok(device.create_render_pass(&renderpass_info, None)?) }
+    }
+
+    fn transition_to_shader_read(
+        &self,
+        context: &VulkanContext,
+        command_pool: vk::CommandPool,
+        subresource_range: vk::ImageSubresourceRange,
+    ) -> RendererResult<()> {
+        let device = &context.device;
+        let command_buf_allocate_info = vk::CommandBufferAllocateInfo::builder()
+            .command_pool(command_pool)
+            .command_buffer_count(1);
+        let cmd_buf = unsafe { device.allocate_command_buffers(&command_buf_allocate_info) }?[0];
+        let cmd_begin_info = vk::CommandBufferBeginInfo::builder()
+            .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+        let barrier = vk::ImageMemoryBarrier::builder()
+            .image(self.image)
+            .src_access_mask(vk::AccessFlags::empty())
+            .dst_access_mask(vk::AccessFlags::SHADER_READ)
+            .old_layout(vk::ImageLayout::UNDEFINED)
+            .new_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+            .subresource_range(subresource_range)
+            .build();
+        unsafe {
+            device.begin_command_buffer(cmd_buf, &cmd_begin_info)?;
+            device.cmd_pipeline_barrier(
+                cmd_buf,
+                vk::PipelineStageFlags::TOP_OF_PIPE,
+                vk::PipelineStageFlags::FRAGMENT_SHADER,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &[barrier],
+            );
+            device.end_command_buffer(cmd_buf)?;
+        }
+
+        let command_buffers = [cmd_buf];
+        let submit_infos = [vk::SubmitInfo::builder()
+            .command_buffers(&command_buffers)
+            .build()];
+        unsafe {
+            let fence = device.create_fence(&vk::FenceCreateInfo::default(), None)?;
+            device.queue_submit(context.graphics_queue.queue, &submit_infos, fence)?;
+            device.wait_for_fences(&[fence], true, std::u64::MAX)?;
+            device.destroy_fence(fence, None);
+            device.free_command_buffers(command_pool, &command_buffers);
+        }
+        Ok(())
+    }
+
+    pub fn uniform_data(&self, light_view_projection: &glm::Mat4) -> ShadowUniformData {
+        let m: [[f32; 4]; 4] = (*light_view_projection).into();
+        [
+            m[0],
+            m[1],
+            m[2],
+            m[3],
+            [1.0, 1.0 / self.resolution as f32, 0.0, 0.0],
+        ]
+    }
+
+    pub fn extent(&self) -> vk::Extent2D {
+        vk::Extent2D {
+            width: self.resolution,
+            height: self.resolution,
+        }
+    }
+
+    // With the comparison sampler, as the shaders sample it
+    pub fn image_info(&self) -> vk::DescriptorImageInfo {
+        vk::DescriptorImageInfo {
+            sampler: self.sampler,
+            image_view: self.view,
+            image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        }
+    }
+
+    // Binding 3 of the lights set, see GLOBAL_DESCRIPTORS
+    pub fn update_descriptor(
+        &self,
+        device: &ash::Device,
+        descriptor_set_lights: vk::DescriptorSet,
+    ) {
+        let image_info = [self.image_info()];
+        let desc_sets_write = [vk::WriteDescriptorSet::builder()
+            .dst_set(descriptor_set_lights)
+            .dst_binding(3)
+            .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+            .image_info(&image_info)
+            .build()];
+        unsafe { device.update_descriptor_sets(&desc_sets_write, &[]) };
+    }
+
+    pub fn set_debug_names(&self, debug_namer: &DebugNamer) {
+        debug_namer.name(self.render_pass, "shadow");
+        debug_namer.name(self.image, "shadow map");
+        debug_namer.name(self.view, "shadow map");
+        debug_namer.name(self.framebuffer, "shadow map");
+        debug_namer.name(self.sampler, "shadow map comparison");
+    }
+
+    pub fn destroy(&mut self, device: &ash::Device, allocator: &mut Allocator) {
+        unsafe {
+            device.destroy_sampler(self.sampler, None);
+            device.destroy_framebuffer(self.framebuffer, None);
+            device.destroy_image_view(self.view, None);
+            device.destroy_image(self.image, None);
+            device.destroy_render_pass(self.render_pass, None);
+        }
+        if let Some(allocation) = self.allocation.take() {
+            allocator.free(allocation).expect("Could not free memory");
+        }
+    }
+}
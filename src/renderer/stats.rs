@@ -0,0 +1,312 @@
+use std::fmt;
+use std::ops::{BitOr, BitOrAssign};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use super::culling::CullingStats;
+use super::draw_list::DrawSortStats;
+use super::object_data::ObjectUploadStats;
+
+// Expensive things that happened since the last frame, stamped by the subsystems doing
+// them, so that a slow frame can be explained
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FrameEvents(u32);
+
+impl FrameEvents {
+    pub const SCENE_CHANGED: Self = Self(1 << 0);
+    pub const SWAPCHAIN_RECREATED: Self = Self(1 << 1);
+    pub const ATLAS_CREATED: Self = Self(1 << 2);
+    pub const PIPELINE_BUILT: Self = Self(1 << 3);
+    pub const MATERIAL_BUILT: Self = Self(1 << 4);
+    pub const TEXTURE_UPLOADED: Self = Self(1 << 5);
+
+    const NAMES: [(Self, &'static str); 6] = [
+        (Self::SCENE_CHANGED, "scene"),
+        (Self::SWAPCHAIN_RECREATED, "swapchain recreation"),
+        (Self::ATLAS_CREATED, "atlas creation"),
+        (Self::PIPELINE_BUILT, "pipeline build"),
+        (Self::MATERIAL_BUILT, "material build"),
+        (Self::TEXTURE_UPLOADED, "texture upload"),
+    ];
+
+    pub fn empty() -> Self {
+        Self(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn contains(&self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn intersects(&self, other: Self) -> bool {
+        self.0 & other.0 != 0
+    }
+}
+
+impl BitOr for FrameEvents {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl BitOrAssign for FrameEvents {
+    fn bitor_assign(&mut self, rhs: Self) {
+        self.0 |= rhs.0;
+    }
+}
+
+impl fmt::Display for FrameEvents {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return write!(f, "nothing");
+        }
+        let mut first = true;
+        for (event, name) in Self::NAMES {
+            if self.contains(event) {
+                if !first {
+                    write!(f, ", ")?;
+                }
+                write!(f, "{}", name)?;
+                first = false;
+            }
+        }
+        Ok(())
+    }
+}
+
+// In milliseconds
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FrameTimePercentiles {
+    pub p50: f32,
+    pub p95: f32,
+    pub p99: f32,
+    pub max: f32,
+}
+
+// The last `capacity` frame times in milliseconds, overwriting the oldest ones
+#[derive(Clone, Debug)]
+pub struct FrameTimeHistory {
+    samples: Vec<f32>,
+    capacity: usize,
+    next: usize,
+}
+
+impl FrameTimeHistory {
+    pub fn new(capacity: usize) -> Self {
+        let capacity = capacity.max(1);
+        Self {
+            samples: Vec::with_capacity(capacity),
+            capacity,
+            next: 0,
+        }
+    }
+
+    pub fn push(&mut self, milliseconds: f32) {
+        if self.samples.len() < self.capacity {
+            self.samples.push(milliseconds);
+        } else {
+            self.samples[self.next] = milliseconds;
+        }
+        self.next = (self.next + 1) % self.capacity;
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    // Oldest sample first
+    pub fn iter(&self) -> impl Iterator<Item = f32> + '_ {
+        let split = if self.samples.len() < self.capacity {
+            0
+        } else {
+            self.next
+        };
+        self.samples[split..]
+            .iter()
+            .chain(self.samples[..split].iter())
+            .copied()
+    }
+
+    // Nearest rank percentiles
+    pub fn percentiles(&self) -> Option<FrameTimePercentiles> {
+        if self.samples.is_empty() {
+            return None;
+        }
+        let mut sorted = self.samples.clone();
+        sorted.sort_by(f32::total_cmp);
+        let rank = |p: f32| {
+            let index = (p / 100.0 * sorted.len() as f32).ceil() as usize;
+            sorted[index.clamp(1, sorted.len()) - 1]
+        };
+        Some(FrameTimePercentiles {
+            p50: rank(50.0),
+            p95: rank(95.0),
+            p99: rank(99.0),
+            max: sorted[sorted.len() - 1],
+        })
+    }
+}
+
+// Counted while a frame is rendered, starting from zero for every frame. The draws cover
+// the scene objects, the skybox and the particles, not the text, gizmo, cursor or UI.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FrameStats {
+    pub draw_calls: u32,
+    pub pipeline_binds: u32,
+    // From the index counts of the meshes, times their instances
+    pub triangles: u64,
+    pub objects_drawn: u32,
+    pub objects_total: u32,
+    // Through the BufferManager since the last frame, including growing buffers
+    pub buffer_bytes_allocated: u64,
+    pub buffer_bytes_freed: u64,
+    // CPU time spent recording the command buffers
+    pub record_time: Duration,
+    // 0 when the command buffer recorded for an earlier frame was submitted again,
+    // see Renderer::force_rerecord. The overlays are recorded every frame into a command
+    // buffer of their own, which isn't counted.
+    pub command_buffers_recorded: u32,
+    // CPU time spent waiting for the frame in flight and the swapchain image to be free
+    pub fence_wait_time: Duration,
+    // The GPU time of the last frame the GPU finished, which is a few frames behind.
+    // None when the graphics queue has no timestamps.
+    pub gpu_frame_ms: Option<f32>,
+}
+
+impl fmt::Display for FrameStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} draws, {} pipeline binds, {} triangles, {}/{} objects, {} bytes allocated, \
+             {} bytes freed, recording {:.2} ms, waiting {:.2} ms",
+            self.draw_calls,
+            self.pipeline_binds,
+            self.triangles,
+            self.objects_drawn,
+            self.objects_total,
+            self.buffer_bytes_allocated,
+            self.buffer_bytes_freed,
+            self.record_time.as_secs_f32() * 1000.0,
+            self.fence_wait_time.as_secs_f32() * 1000.0
+        )?;
+        if let Some(gpu_frame_ms) = self.gpu_frame_ms {
+            write!(f, ", GPU {:.2} ms", gpu_frame_ms)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RendererStats {
+    pub frame_number: u64,
+    pub cpu_frame_time: Option<FrameTimePercentiles>,
+    // None when the graphics queue has no timestamps
+    pub gpu_frame_time: Option<FrameTimePercentiles>,
+    pub culling: CullingStats,
+    pub draw_sort: DrawSortStats,
+    pub object_uploads: ObjectUploadStats,
+    pub frame: FrameStats,
+}
+
+// A handle to the stats of the last rendered frame, for other threads, e.g. a metrics
+// exporter. The renderer updates it at the end of every frame.
+#[derive(Clone, Debug, Default)]
+pub struct StatsReader {
+    stats: Arc<Mutex<RendererStats>>,
+}
+
+impl StatsReader {
+    pub fn get(&self) -> RendererStats {
+        match self.stats.lock() {
+            Ok(stats) => *stats,
+            // A panic while the renderer held the lock can't leave the stats half written
+            Err(poisoned) => *poisoned.into_inner(),
+        }
+    }
+
+    pub(crate) fn set(&self, stats: RendererStats) {
+        match self.stats.lock() {
+            Ok(mut current) => *current = stats,
+            Err(poisoned) => *poisoned.into_inner() = stats,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles_use_the_nearest_rank() {
+        let mut history = FrameTimeHistory::new(100);
+        assert_eq!(history.percentiles(), None);
+        // Pushed out of order, the percentiles sort a copy
+        for i in (1..=100).rev() {
+            history.push(i as f32);
+        }
+        let percentiles = history.percentiles().unwrap();
+        assert_eq!(percentiles.p50, 50.0);
+        assert_eq!(percentiles.p95, 95.0);
+        assert_eq!(percentiles.p99, 99.0);
+        assert_eq!(percentiles.max, 100.0);
+    }
+
+    #[test]
+    fn percentiles_of_few_samples_round_up() {
+        let mut history = FrameTimeHistory::new(10);
+        for i in 1..=10 {
+            history.push(i as f32);
+        }
+        let percentiles = history.percentiles().unwrap();
+        assert_eq!(percentiles.p50, 5.0);
+        assert_eq!(percentiles.p95, 10.0);
+        assert_eq!(percentiles.p99, 10.0);
+
+        let mut single = FrameTimeHistory::new(10);
+        single.push(7.0);
+        let percentiles = single.percentiles().unwrap();
+        assert_eq!(percentiles.p50, 7.0);
+        assert_eq!(percentiles.max, 7.0);
+    }
+
+    #[test]
+    fn history_wraps_around_oldest_first() {
+        let mut history = FrameTimeHistory::new(3);
+        history.push(1.0);
+        history.push(2.0);
+        assert_eq!(history.iter().collect::<Vec<_>>(), vec![1.0, 2.0]);
+        history.push(3.0);
+        history.push(4.0);
+        history.push(5.0);
+        assert_eq!(history.len(), 3);
+        assert_eq!(history.iter().collect::<Vec<_>>(), vec![3.0, 4.0, 5.0]);
+        // The overwritten samples no longer count
+        assert_eq!(history.percentiles().unwrap().p50, 4.0);
+    }
+
+    #[test]
+    fn zero_capacity_keeps_one_sample() {
+        let mut history = FrameTimeHistory::new(0);
+        history.push(1.0);
+        history.push(2.0);
+        assert_eq!(history.iter().collect::<Vec<_>>(), vec![2.0]);
+    }
+
+    #[test]
+    fn events_list_their_names() {
+        assert_eq!(FrameEvents::empty().to_string(), "nothing");
+        let events = FrameEvents::SCENE_CHANGED | FrameEvents::TEXTURE_UPLOADED;
+        assert_eq!(events.to_string(), "scene, texture upload");
+        assert!(events.intersects(FrameEvents::SCENE_CHANGED | FrameEvents::PIPELINE_BUILT));
+        assert!(!events.contains(FrameEvents::SCENE_CHANGED | FrameEvents::PIPELINE_BUILT));
+    }
+}
@@ -1,23 +1,97 @@
+use std::cell::Cell;
+
 use ash::extensions::khr;
 use ash::vk;
 
 use gpu_allocator::vulkan::Allocator;
+use log::info;
 
+use super::config::{PresentModePreference, TransparencyTechnique};
 use super::context::VulkanContext;
 use super::render_target::RenderTarget;
 use super::RendererResult;
 
+// The surface decides the extent through current_extent, unless it is the u32::MAX
+// sentinel (Wayland), in which case the requested size is used within the allowed range.
+// current_extent is already in the orientation of current_transform, which is what
+// the swapchain is created with as pre_transform, so it never has to be swapped.
+pub(crate) fn choose_extent(
+    capabilities: &vk::SurfaceCapabilitiesKHR,
+    width: u32,
+    height: u32,
+) -> vk::Extent2D {
+    if capabilities.current_extent.width != u32::MAX {
+        return capabilities.current_extent;
+    }
+    vk::Extent2D {
+        width: width.clamp(
+            capabilities.min_image_extent.width,
+            capabilities
+                .max_image_extent
+                .width
+                .max(capabilities.min_image_extent.width),
+        ),
+        height: height.clamp(
+            capabilities.min_image_extent.height,
+            capabilities
+                .max_image_extent
+                .height
+                .max(capabilities.min_image_extent.height),
+        ),
+    }
+}
+
+// The preferred mode, or the first of its fallbacks the surface supports. FIFO is
+// required to be supported.
+pub(crate) fn choose_present_mode(
+    preference: PresentModePreference,
+    supported: &[vk::PresentModeKHR],
+) -> vk::PresentModeKHR {
+    let candidates: &[vk::PresentModeKHR] = match preference {
+        PresentModePreference::Fifo => &[],
+        PresentModePreference::Mailbox => &[vk::PresentModeKHR::MAILBOX],
+        PresentModePreference::Immediate => {
+            &[vk::PresentModeKHR::IMMEDIATE, vk::PresentModeKHR::MAILBOX]
+        }
+        PresentModePreference::FifoRelaxed => &[vk::PresentModeKHR::FIFO_RELAXED],
+    };
+    candidates
+        .iter()
+        .copied()
+        .find(|mode| supported.contains(mode))
+        .unwrap_or(vk::PresentModeKHR::FIFO)
+}
+
+// What a headless renderer renders to. sRGB, so there is no output pass, and RGBA, so it
+// reads back without swizzling.
+pub(crate) const HEADLESS_FORMAT: vk::SurfaceFormatKHR = vk::SurfaceFormatKHR {
+    format: vk::Format::R8G8B8A8_SRGB,
+    color_space: vk::ColorSpaceKHR::SRGB_NONLINEAR,
+};
+
+pub(crate) fn is_zero_extent(extent: vk::Extent2D) -> bool {
+    extent.width == 0 || extent.height == 0
+}
+
 pub struct Swapchain {
+    // Null and None when headless, the render targets then own their images
     swapchain: vk::SwapchainKHR,
-    swapchain_loader: khr::Swapchain,
+    swapchain_loader: Option<khr::Swapchain>,
+    // The image a headless swapchain hands out next
+    next_headless_image: Cell<u32>,
     min_image_count: u32,
     image_count: u32,
     render_targets: Vec<RenderTarget>,
     image_format: vk::SurfaceFormatKHR,
     extent: vk::Extent2D,
+    present_mode: vk::PresentModeKHR,
 }
 
 impl Swapchain {
+    // The targets get a depth image with the given samples and the attachments of the
+    // transparency technique, without samples they only have the swapchain image for
+    // render passes without depth
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         context: &VulkanContext,
         allocator: &mut Allocator,
@@ -25,15 +99,11 @@ impl Swapchain {
         width: u32,
         height: u32,
         render_pass: &vk::RenderPass,
+        samples: Option<vk::SampleCountFlags>,
+        transparency: TransparencyTechnique,
+        present_mode: PresentModePreference,
     ) -> RendererResult<Self> {
-        let extent = vk::Extent2D {
-            width: width
-                .min(context.surface_capabilities.max_image_extent.width)
-                .max(context.surface_capabilities.min_image_extent.width),
-            height: height
-                .min(context.surface_capabilities.max_image_extent.height)
-                .max(context.surface_capabilities.min_image_extent.height),
-        };
+        let extent = choose_extent(&context.surface_capabilities, width, height);
         let queue_families = [context.graphics_queue.index];
         let min_image_count = 3.min(context.surface_capabilities.min_image_count).max(
             if context.surface_capabilities.max_image_count == 0 {
@@ -42,18 +112,15 @@ impl Swapchain {
                 context.surface_capabilities.max_image_count
             },
         );
-        let present_mode = {
-            if context
-                .surface_present_modes
-                .contains(&vk::PresentModeKHR::MAILBOX)
-            {
-                vk::PresentModeKHR::MAILBOX
-            } else {
-                vk::PresentModeKHR::FIFO
-            }
-        };
+        let preference = present_mode;
+        let present_mode = choose_present_mode(preference, &context.surface_present_modes);
+        info!(
+            "Presenting with {:?}, {:?} was preferred",
+            present_mode, preference
+        );
+        let surface = context.surface.ok_or(vk::Result::ERROR_SURFACE_LOST_KHR)?;
         let swapchain_create_info = vk::SwapchainCreateInfoKHR::builder()
-            .surface(context.surface)
+            .surface(surface)
             .min_image_count(min_image_count)
             .image_format(format.format)
             .image_color_space(format.color_space)
@@ -73,29 +140,111 @@ impl Swapchain {
         let images = unsafe { swapchain_loader.get_swapchain_images(swapchain)? };
         let render_targets = images
             .into_iter()
-            .map(|image| {
-                RenderTarget::new_from_image(
-                    context,
-                    allocator,
-                    image,
-                    format.format,
-                    extent,
-                    render_pass,
-                )
+            .enumerate()
+            .map(|(i, image)| {
+                let target = if let Some(samples) = samples {
+                    RenderTarget::new_from_image(
+                        context,
+                        allocator,
+                        image,
+                        format.format,
+                        extent,
+                        render_pass,
+                        samples,
+                        transparency,
+                    )?
+                } else {
+                    RenderTarget::new_color_only(
+                        context,
+                        image,
+                        format.format,
+                        extent,
+                        render_pass,
+                    )?
+                };
+                target.set_debug_name(context, &format!("swapchain {}", i));
+                Ok(target)
             })
             .collect::<RendererResult<Vec<_>>>()?;
 
         Ok(Swapchain {
             swapchain,
-            swapchain_loader,
+            swapchain_loader: Some(swapchain_loader),
+            next_headless_image: Cell::new(0),
             min_image_count,
             image_count: render_targets.len() as u32,
             render_targets,
             image_format: format,
             extent,
+            present_mode,
+        })
+    }
+
+    // Offscreen targets in place of the swapchain images, for a renderer without a surface.
+    // They are used one after the other, and nothing is presented.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new_headless(
+        context: &VulkanContext,
+        allocator: &mut Allocator,
+        width: u32,
+        height: u32,
+        render_pass: &vk::RenderPass,
+        samples: vk::SampleCountFlags,
+        transparency: TransparencyTechnique,
+        image_count: u32,
+    ) -> RendererResult<Self> {
+        let extent = vk::Extent2D { width, height };
+        let render_targets = (0..image_count)
+            .map(|i| {
+                let target = RenderTarget::new_offscreen(
+                    context,
+                    allocator,
+                    HEADLESS_FORMAT.format,
+                    extent,
+                    render_pass,
+                    samples,
+                    transparency,
+                )?;
+                target.set_debug_name(context, &format!("headless {}", i));
+                Ok(target)
+            })
+            .collect::<RendererResult<Vec<_>>>()?;
+
+        Ok(Swapchain {
+            swapchain: vk::SwapchainKHR::null(),
+            swapchain_loader: None,
+            next_headless_image: Cell::new(0),
+            min_image_count: image_count,
+            image_count,
+            render_targets,
+            image_format: HEADLESS_FORMAT,
+            extent,
+            // Frames are never held back
+            present_mode: vk::PresentModeKHR::IMMEDIATE,
         })
     }
 
+    // Without an output pass the overlays are drawn straight to the images
+    pub fn add_overlay_framebuffers(
+        &mut self,
+        context: &VulkanContext,
+        render_pass: &vk::RenderPass,
+    ) -> RendererResult<()> {
+        for (i, target) in self.render_targets.iter_mut().enumerate() {
+            target.add_overlay_framebuffer(context, render_pass)?;
+            if let Some(framebuffer) = target.overlay_framebuffer {
+                context
+                    .debug_namer
+                    .name(framebuffer, &format!("swapchain {} overlay framebuffer", i));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn is_headless(&self) -> bool {
+        self.swapchain_loader.is_none()
+    }
+
     pub fn get_swapchain(&self) -> &vk::SwapchainKHR {
         &self.swapchain
     }
@@ -120,15 +269,29 @@ impl Swapchain {
         self.extent
     }
 
+    // What the preference fell back to
+    pub fn get_present_mode(&self) -> vk::PresentModeKHR {
+        self.present_mode
+    }
+
+    // Headless, the semaphore and fence are not signaled
     pub fn get_next_image(
         &self,
         timeout: u64,
         semaphore: &vk::Semaphore,
         fence: vk::Fence,
     ) -> RendererResult<u32> {
+        let swapchain_loader = match self.swapchain_loader.as_ref() {
+            Some(swapchain_loader) => swapchain_loader,
+            None => {
+                let image_index = self.next_headless_image.get();
+                self.next_headless_image
+                    .set((image_index + 1) % self.image_count);
+                return Ok(image_index);
+            }
+        };
         let (image_index, _) = unsafe {
-            self.swapchain_loader
-                .acquire_next_image(self.swapchain, timeout, *semaphore, fence)?
+            swapchain_loader.acquire_next_image(self.swapchain, timeout, *semaphore, fence)?
         };
         Ok(image_index)
     }
@@ -139,6 +302,10 @@ impl Swapchain {
         semaphore: &vk::Semaphore,
         image_index: u32,
     ) -> RendererResult<()> {
+        let swapchain_loader = match self.swapchain_loader.as_ref() {
+            Some(swapchain_loader) => swapchain_loader,
+            None => return Ok(()),
+        };
         let swapchains = [self.swapchain];
         let indices = [image_index];
         let semaphores = [*semaphore];
@@ -147,7 +314,7 @@ impl Swapchain {
             .swapchains(&swapchains)
             .image_indices(&indices);
         unsafe {
-            self.swapchain_loader.queue_present(*queue, &present_info)?;
+            swapchain_loader.queue_present(*queue, &present_info)?;
         }
         Ok(())
     }
@@ -156,9 +323,10 @@ impl Swapchain {
         for rt in &mut self.render_targets {
             rt.destroy(context, allocator);
         }
-        unsafe {
-            self.swapchain_loader
-                .destroy_swapchain(self.swapchain, None);
+        if let Some(swapchain_loader) = self.swapchain_loader.as_ref() {
+            unsafe {
+                swapchain_loader.destroy_swapchain(self.swapchain, None);
+            }
         }
     }
 }
@@ -168,3 +336,94 @@ impl Drop for Swapchain {
         //self.destroy();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn capabilities(
+        current: (u32, u32),
+        min: (u32, u32),
+        max: (u32, u32),
+    ) -> vk::SurfaceCapabilitiesKHR {
+        vk::SurfaceCapabilitiesKHR {
+            current_extent: vk::Extent2D {
+                width: current.0,
+                height: current.1,
+            },
+            min_image_extent: vk::Extent2D {
+                width: min.0,
+                height: min.1,
+            },
+            max_image_extent: vk::Extent2D {
+                width: max.0,
+                height: max.1,
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn extent_table() {
+        let undefined = (u32::MAX, u32::MAX);
+        // (current, min, max, requested, expected)
+        let cases = [
+            // The surface decides, whatever was requested
+            ((800, 600), (1, 1), (4096, 4096), (1024, 768), (800, 600)),
+            // Rotated surfaces already report the rotated extent
+            ((600, 800), (1, 1), (4096, 4096), (800, 600), (600, 800)),
+            // Minimized windows report zero
+            ((0, 0), (0, 0), (0, 0), (800, 600), (0, 0)),
+            // Undefined: the request is used within the limits
+            (undefined, (1, 1), (4096, 4096), (1024, 768), (1024, 768)),
+            (undefined, (64, 64), (4096, 4096), (16, 8000), (64, 4096)),
+            // A max below the min doesn't panic, the min wins
+            (undefined, (64, 64), (32, 32), (1024, 768), (64, 64)),
+        ];
+        for (current, min, max, requested, expected) in cases {
+            let extent = choose_extent(&capabilities(current, min, max), requested.0, requested.1);
+            assert_eq!(
+                (extent.width, extent.height),
+                expected,
+                "current {:?}, min {:?}, max {:?}, requested {:?}",
+                current,
+                min,
+                max,
+                requested
+            );
+        }
+    }
+
+    #[test]
+    fn zero_extents() {
+        assert!(is_zero_extent(vk::Extent2D {
+            width: 0,
+            height: 600
+        }));
+        assert!(is_zero_extent(vk::Extent2D {
+            width: 800,
+            height: 0
+        }));
+        assert!(!is_zero_extent(vk::Extent2D {
+            width: 1,
+            height: 1
+        }));
+    }
+
+    #[test]
+    fn present_mode_falls_back_to_fifo() {
+        let supported = [vk::PresentModeKHR::FIFO, vk::PresentModeKHR::MAILBOX];
+        assert_eq!(
+            choose_present_mode(PresentModePreference::Immediate, &supported),
+            vk::PresentModeKHR::MAILBOX
+        );
+        assert_eq!(
+            choose_present_mode(PresentModePreference::FifoRelaxed, &supported),
+            vk::PresentModeKHR::FIFO
+        );
+        assert_eq!(
+            choose_present_mode(PresentModePreference::Fifo, &supported),
+            vk::PresentModeKHR::FIFO
+        );
+    }
+}
@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::sync::Arc;
 use std::sync::Mutex;
 
@@ -12,15 +12,16 @@ use memoffset::offset_of;
 
 use super::error::FontError;
 use super::{
-    buffer::{Buffer, BufferManager},
+    buffer::{Buffer, BufferManager, MAX_UPDATE_SIZE},
     descriptor::{DescriptorAllocator, DescriptorLayoutCache},
     error::{InvalidHandle, RendererError},
     material::{
-        Material, MaterialData, MaterialSystem, MeshPassType, ShaderParameters,
-        VertexInputDescription,
+        Material, MaterialData, MaterialSystem, MaterialTextureSlot, MeshPassType,
+        ShaderParameters, VertexInputDescription,
     },
+    stats::FrameEvents,
     texture::{Texture, TextureStorage},
-    utils::Handle,
+    utils::{fnv1a, fnv1a_start, Handle},
     RendererResult,
 };
 
@@ -62,6 +63,7 @@ impl TextAtlasTexture {
             data,
             width,
             height,
+            vk::Format::R8_SRGB,
             device,
             allocator,
             buffer_manager,
@@ -80,6 +82,50 @@ impl TextAtlasTexture {
     }
 }
 
+// The point of the window a text is positioned from. The same point of the text goes
+// there, moved towards the inside of the window by the position in pixels.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Anchor {
+    #[default]
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+    Center,
+}
+
+impl Anchor {
+    // Where the anchor is, as a fraction of the size of the window or the text
+    fn fraction(&self) -> [f32; 2] {
+        match self {
+            Anchor::TopLeft => [0.0, 0.0],
+            Anchor::TopRight => [1.0, 0.0],
+            Anchor::BottomLeft => [0.0, 1.0],
+            Anchor::BottomRight => [1.0, 1.0],
+            Anchor::Center => [0.5, 0.5],
+        }
+    }
+}
+
+// A font added to the TextHandler. Its index is the font_index of the fontdue TextStyles
+// drawn with it, index 0 is the font the renderer was created with.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct FontHandle(usize);
+
+impl FontHandle {
+    pub fn index(self) -> usize {
+        self.0
+    }
+}
+
+fn load_font<P: AsRef<std::path::Path>>(font_path: P) -> RendererResult<(String, fontdue::Font)> {
+    let font_name = font_path.as_ref().to_string_lossy().into_owned();
+    let font_data = std::fs::read(font_path)?;
+    let font = fontdue::Font::from_bytes(font_data, fontdue::FontSettings::default())
+        .map_err::<RendererError, _>(|s| FontError(s).into())?;
+    Ok((font_name, font))
+}
+
 pub struct Letter {
     color: [f32; 3],
     position_and_shape: fontdue::layout::GlyphPosition,
@@ -134,75 +180,231 @@ impl TextVertexData {
     }
 }
 
+// The vertices of the glyphs of one font and size, each of those needs its own atlas
 struct TextBuffer {
+    font: FontHandle,
     px: f32,
-    last_image_index: Option<u32>,
+    anchor: Anchor,
     vertex_buffer: Buffer,
+    // Vertices the buffer has room for
+    capacity: usize,
+    // In pixels from the anchor, converted to vertex_data for the current screen size
+    pixel_vertices: Vec<TextVertexData>,
     vertex_data: Vec<TextVertexData>,
+    // Written into the buffer by the next frame, see TextHandler::record_uploads
+    dirty: bool,
 }
 
 impl TextBuffer {
+    #[allow(clippy::too_many_arguments)]
     fn new(
+        font: FontHandle,
         px: f32,
-        vertex_data: Vec<TextVertexData>,
+        anchor: Anchor,
+        pixel_vertices: Vec<TextVertexData>,
+        screen_size: (u32, u32),
+        capacity: usize,
         device: &Device,
         allocator: &mut Allocator,
         buffer_manager: Arc<Mutex<BufferManager>>,
     ) -> RendererResult<Self> {
-        if vertex_data.is_empty() {
-            // TODO handle this?
-            panic!("Given empty vertex data");
-        }
-        let bytes = (vertex_data.len() * std::mem::size_of::<TextVertexData>()) as u64;
+        let vertex_data = to_screen(&pixel_vertices, anchor, screen_size);
+        let bytes = (capacity * std::mem::size_of::<TextVertexData>()) as u64;
         let mut vertex_buffer = BufferManager::new_buffer(
             buffer_manager,
             device,
             allocator,
             bytes,
-            vk::BufferUsageFlags::VERTEX_BUFFER,
+            vk::BufferUsageFlags::VERTEX_BUFFER | vk::BufferUsageFlags::TRANSFER_DST,
             MemoryLocation::CpuToGpu,
             "text-vertex-buffer",
         )?;
+        // No frame uses the new buffer yet
         vertex_buffer.fill(allocator, &vertex_data)?;
         Ok(Self {
+            font,
             px,
-            last_image_index: None,
+            anchor,
             vertex_buffer,
+            capacity,
+            pixel_vertices,
             vertex_data,
+            dirty: false,
         })
     }
 
     fn destroy(&mut self) {
-        self.vertex_buffer
-            .queue_free(self.last_image_index)
-            .expect("Invalid Buffer!?");
+        self.vertex_buffer.queue_free().expect("Invalid Buffer!?");
     }
 }
 
+// Vertices in pixels from the anchor to normalized device coordinates
+fn to_screen(
+    pixel_vertices: &[TextVertexData],
+    anchor: Anchor,
+    screen_size: (u32, u32),
+) -> Vec<TextVertexData> {
+    let [fraction_x, fraction_y] = anchor.fraction();
+    let width = screen_size.0.max(1) as f32;
+    let height = screen_size.1.max(1) as f32;
+    pixel_vertices
+        .iter()
+        .map(|v| TextVertexData {
+            position: [
+                2.0 * (fraction_x * width + v.position[0]) / width - 1.0,
+                2.0 * (fraction_y * height + v.position[1]) / height - 1.0,
+                v.position[2],
+            ],
+            ..*v
+        })
+        .collect()
+}
+
 pub struct TextHandler {
-    vertex_data: HashMap<usize, TextBuffer>,
-    font: fontdue::Font,
-    font_name: String,
-    atlases: Vec<(f32, TextAtlasTexture)>,
+    // One buffer per size of the text with the id
+    vertex_data: BTreeMap<usize, Vec<TextBuffer>>,
+    // Of the swapchain, in pixels
+    screen_size: (u32, u32),
+    // Indexed by FontHandle, in the order they were added
+    fonts: Vec<fontdue::Font>,
+    font_names: Vec<String>,
+    atlases: Vec<((FontHandle, f32), TextAtlasTexture)>,
+    // Random ids unless deterministic ids were requested
+    deterministic_ids: bool,
+    next_id: usize,
+    id_hash: u64,
+    events: FrameEvents,
+    // Bumped by every change, see Renderer::force_rerecord
+    generation: u64,
 }
 
 impl TextHandler {
-    pub fn new<P: AsRef<std::path::Path>>(font_path: P) -> RendererResult<TextHandler> {
-        let font_name = font_path.as_ref().to_string_lossy().into_owned();
-        let font_data = std::fs::read(font_path)?;
-        let font = fontdue::Font::from_bytes(font_data, fontdue::FontSettings::default())
-            .map_err::<RendererError, _>(|s| FontError(s).into())?;
+    pub fn new<P: AsRef<std::path::Path>>(
+        font_path: P,
+        deterministic_ids: bool,
+    ) -> RendererResult<TextHandler> {
+        let (font_name, font) = load_font(font_path)?;
 
         Ok(TextHandler {
-            vertex_data: HashMap::new(),
-            font,
-            font_name,
+            vertex_data: BTreeMap::new(),
+            screen_size: (1, 1),
+            fonts: vec![font],
+            font_names: vec![font_name],
             atlases: vec![],
+            deterministic_ids,
+            next_id: 1,
+            id_hash: fnv1a_start(),
+            events: FrameEvents::default(),
+            generation: 0,
         })
     }
 
+    // Glyphs missing from the font of a style are taken from the first font that has
+    // them, in the order the fonts were added
+    pub fn add_font<P: AsRef<std::path::Path>>(
+        &mut self,
+        font_path: P,
+    ) -> RendererResult<FontHandle> {
+        let (font_name, font) = load_font(font_path)?;
+        self.fonts.push(font);
+        self.font_names.push(font_name);
+        Ok(FontHandle(self.fonts.len() - 1))
+    }
+
+    // The font of the style if it has c, otherwise the first font that has it. Control
+    // characters and chars no font has stay with the font of the style.
+    fn font_for(&self, c: char, preferred: usize) -> usize {
+        if c.is_control() || self.fonts[preferred].lookup_glyph_index(c) != 0 {
+            return preferred;
+        }
+        self.fonts
+            .iter()
+            .position(|font| font.lookup_glyph_index(c) != 0)
+            .unwrap_or(preferred)
+    }
+
+    // Splits the styles where a char falls back to another font
+    fn split_by_font<'a>(
+        &self,
+        styles: &[&fontdue::layout::TextStyle<'a>],
+    ) -> RendererResult<Vec<fontdue::layout::TextStyle<'a>>> {
+        let mut split = vec![];
+        for style in styles {
+            if style.font_index >= self.fonts.len() {
+                return Err(InvalidHandle.into());
+            }
+            let mut start = 0;
+            let mut current = None;
+            for (i, c) in style.text.char_indices() {
+                let font_index = self.font_for(c, style.font_index);
+                match current {
+                    Some(current) if current != font_index => {
+                        split.push(fontdue::layout::TextStyle::new(
+                            &style.text[start..i],
+                            style.px,
+                            current,
+                        ));
+                        start = i;
+                    }
+                    _ => {}
+                }
+                current = Some(font_index);
+            }
+            if let Some(current) = current {
+                split.push(fontdue::layout::TextStyle::new(
+                    &style.text[start..],
+                    style.px,
+                    current,
+                ));
+            }
+        }
+        Ok(split)
+    }
+
+    // Moves the text to where its anchors are on a screen of the new size, the next
+    // frame writes the vertices
+    pub fn resize(&mut self, extent: vk::Extent2D) {
+        let screen_size = (extent.width, extent.height);
+        if screen_size == self.screen_size {
+            return;
+        }
+        self.screen_size = screen_size;
+        self.generation += 1;
+        for text_buffer in self.vertex_data.values_mut().flatten() {
+            text_buffer.vertex_data =
+                to_screen(&text_buffer.pixel_vertices, text_buffer.anchor, screen_size);
+            text_buffer.dirty = true;
+        }
+    }
+
+    fn new_text_id(&mut self) -> usize {
+        let id = if self.deterministic_ids {
+            let id = self.next_id;
+            self.next_id += 1;
+            id
+        } else {
+            rand::random()
+        };
+        self.id_hash = fnv1a(self.id_hash, &id.to_le_bytes());
+        id
+    }
+
+    // Rolling hash of all text ids handed out
+    pub fn allocation_hash(&self) -> u64 {
+        self.id_hash
+    }
+
+    pub(crate) fn take_frame_events(&mut self) -> FrameEvents {
+        std::mem::take(&mut self.events)
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
     fn generate_texture_atlas(
         &mut self,
+        font: FontHandle,
         px: f32,
         max_extent: &vk::Extent3D,
         device: &Device,
@@ -217,18 +419,20 @@ impl TextHandler {
     ) -> RendererResult<TextAtlasTexture> {
         let mut char_data = HashMap::new();
         let max_texture_width = max_extent.width as usize;
-        let mut char_list_with_metrics: Vec<_> = self
-            .font
+        let font_name = &self.font_names[font.0];
+        let font = &self.fonts[font.0];
+        let mut char_list_with_metrics: Vec<_> = font
             .chars()
             .iter()
             .map(|(c, i)| {
-                let metrics = self.font.metrics_indexed((*i).into(), px);
+                let metrics = font.metrics_indexed((*i).into(), px);
                 (*c, *i, metrics)
             })
             .collect();
 
-        char_list_with_metrics.sort_by(|(_c_l, _i_l, metrics_l), (_c_r, _i_r, metrics_r)| {
-            metrics_r.height.cmp(&metrics_l.height)
+        // font.chars() is a HashMap, so break ties by character to keep the atlas layout stable
+        char_list_with_metrics.sort_by(|(c_l, _i_l, metrics_l), (c_r, _i_r, metrics_r)| {
+            metrics_r.height.cmp(&metrics_l.height).then(c_l.cmp(c_r))
         });
 
         let mut cur_x = 0usize;
@@ -263,7 +467,7 @@ impl TextHandler {
 
         let mut data = vec![0; max_width * max_height];
         for (i, character_data) in char_data.iter_mut() {
-            let (metrics, glyph_data) = self.font.rasterize_indexed(*i, px);
+            let (metrics, glyph_data) = font.rasterize_indexed(*i, px);
             character_data.texture_x = character_data.cur_x as f32 / max_width as f32;
             character_data.texture_y = character_data.cur_y as f32 / max_height as f32;
             for y in 0..metrics.height {
@@ -286,22 +490,27 @@ impl TextHandler {
             command_pool,
             queue,
         )?;
+        texture_storage.set_debug_name(
+            atlas.texture_handle,
+            &format!("text atlas {} {}px", font_name, px),
+        );
 
         // Create new material for this atlas
         let mat_data = MaterialData {
             base_template: "text".to_string(),
             buffers: vec![],
-            textures: vec![atlas.texture_handle],
+            textures: HashMap::from([(MaterialTextureSlot::Albedo, atlas.texture_handle)]),
             parameters: ShaderParameters::default(),
         };
 
         let handle = material_system.build_material(
             device,
+            allocator,
             texture_storage,
             buffer_manager,
             descriptor_layout_cache,
             descriptor_allocator,
-            &format!("{} {}px", self.font_name, px),
+            &format!("{} {}px", font_name, px),
             mat_data,
         )?;
 
@@ -325,16 +534,12 @@ impl TextHandler {
         command_pool: &vk::CommandPool,
         queue: &vk::Queue,
     ) -> RendererResult<Vec<Letter>> {
-        let mut layout =
-            fontdue::layout::Layout::new(fontdue::layout::CoordinateSystem::PositiveYUp);
-        let settings = fontdue::layout::LayoutSettings {
-            ..fontdue::layout::LayoutSettings::default()
-        };
-        layout.reset(&settings);
-        for style in styles {
-            layout.append(&[&self.font], style);
-            if !self.atlases.iter().any(|(px, _)| *px == style.px) {
+        let styles = self.split_by_font(styles)?;
+        for style in styles.iter() {
+            let key = (FontHandle(style.font_index), style.px);
+            if !self.atlases.iter().any(|(atlas_key, _)| *atlas_key == key) {
                 let atlas = self.generate_texture_atlas(
+                    key.0,
                     style.px,
                     max_extent,
                     device,
@@ -347,9 +552,20 @@ impl TextHandler {
                     command_pool,
                     queue,
                 )?;
-                self.atlases.push((style.px, atlas));
+                self.atlases.push((key, atlas));
+                self.events |= FrameEvents::ATLAS_CREATED;
+                self.generation += 1;
             }
         }
+        let mut layout =
+            fontdue::layout::Layout::new(fontdue::layout::CoordinateSystem::PositiveYUp);
+        let settings = fontdue::layout::LayoutSettings {
+            ..fontdue::layout::LayoutSettings::default()
+        };
+        layout.reset(&settings);
+        for style in styles.iter() {
+            layout.append(&self.fonts, style);
+        }
         let mut output = vec![];
         for glyph in layout.glyphs() {
             output.push(Letter {
@@ -360,12 +576,15 @@ impl TextHandler {
         Ok(output)
     }
 
-    pub fn add_text(
+    // The vertices of the laid out glyphs in pixels from the anchor, split into runs of
+    // the same font and size
+    #[allow(clippy::too_many_arguments)]
+    fn layout_vertices(
         &mut self,
         styles: &[&fontdue::layout::TextStyle],
         color: [f32; 3],
         position: (u32, u32), // in pixels
-        window: &winit::window::Window,
+        anchor: Anchor,
         max_extent: &vk::Extent3D,
         device: &Device,
         texture_storage: &mut TextureStorage,
@@ -376,7 +595,7 @@ impl TextHandler {
         descriptor_layout_cache: &mut DescriptorLayoutCache,
         descriptor_allocator: &mut DescriptorAllocator,
         material_system: &mut MaterialSystem,
-    ) -> RendererResult<Vec<usize>> {
+    ) -> RendererResult<Vec<(FontHandle, f32, Vec<TextVertexData>)>> {
         let letters = self.create_letters(
             styles,
             color,
@@ -387,32 +606,23 @@ impl TextHandler {
             descriptor_allocator,
             material_system,
             allocator,
-            buffer_manager.clone(),
+            buffer_manager,
             command_pool,
             queue,
         )?;
-        let screen_size = window.inner_size();
-        let mut vertex_data = vec![];
-        let mut ret_ids = vec![];
-        let mut px = 0.0f32;
+        let mut runs: Vec<(FontHandle, f32, Vec<TextVertexData>)> = vec![];
+        let mut size = (0.0f32, 0.0f32);
         for l in letters {
+            let px = l.position_and_shape.key.px;
             if px == 0.0f32 {
-                px = l.position_and_shape.key.px;
-            } else if px != l.position_and_shape.key.px {
-                // The last style ended, add a new one
-                let id: usize = rand::random();
-                let text_buffer =
-                    TextBuffer::new(px, vertex_data, device, allocator, buffer_manager.clone())?;
-                self.vertex_data.insert(id, text_buffer);
-                ret_ids.push(id);
-                px = l.position_and_shape.key.px;
-                vertex_data = vec![];
+                panic!("px size is 0.0f32!");
             }
+            let font = FontHandle(l.position_and_shape.font_index);
             let atlas = &self
                 .atlases
                 .iter()
-                .find(|(inner_px, _atlas)| *inner_px == px)
-                .expect("No atlas for px?")
+                .find(|(key, _atlas)| *key == (font, px))
+                .expect("No atlas for font and px?")
                 .1;
             let char_data = if let Some(char_data) =
                 atlas.char_data.get(&l.position_and_shape.key.glyph_index)
@@ -422,20 +632,13 @@ impl TextHandler {
                 error!("Could not find char data for glyph?");
                 continue;
             };
-            let left =
-                2.0 * (l.position_and_shape.x + position.0 as f32) / screen_size.width as f32 - 1.0;
-            let right = 2.0
-                * (l.position_and_shape.x + position.0 as f32 + l.position_and_shape.width as f32)
-                / screen_size.width as f32
-                - 1.0;
-            let top = 2.0
-                * (-l.position_and_shape.y + position.1 as f32
-                    - l.position_and_shape.height as f32)
-                / screen_size.height as f32
-                - 1.0;
-            let bottom = 2.0 * (-l.position_and_shape.y + position.1 as f32)
-                / screen_size.height as f32
-                - 1.0;
+            // From the top left corner of the text, moved to the anchor below
+            let left = l.position_and_shape.x;
+            let right = l.position_and_shape.x + l.position_and_shape.width as f32;
+            let top = -l.position_and_shape.y - l.position_and_shape.height as f32;
+            let bottom = -l.position_and_shape.y;
+            size.0 = size.0.max(right);
+            size.1 = size.1.max(bottom);
             let start_u = char_data.texture_x;
             let start_v = char_data.texture_y;
             let end_u = start_u + char_data.width as f32 / atlas.width;
@@ -460,28 +663,255 @@ impl TextHandler {
                 texture_coordinates: [end_u, end_v],
                 color: l.color,
             };
-            vertex_data.push(v1);
-            vertex_data.push(v2);
-            vertex_data.push(v3);
-            vertex_data.push(v3);
-            vertex_data.push(v2);
-            vertex_data.push(v4);
-            if px == 0.0f32 {
-                panic!("px size is 0.0f32!");
+            // A new run starts where the font or size changes
+            let vertex_data = match runs.last_mut() {
+                Some((run_font, run_px, vertex_data)) if *run_font == font && *run_px == px => {
+                    vertex_data
+                }
+                _ => {
+                    runs.push((font, px, vec![]));
+                    &mut runs.last_mut().expect("A run was just pushed").2
+                }
+            };
+            vertex_data.extend([v1, v2, v3, v3, v2, v4]);
+        }
+        // The position points into the window, away from the edges it is anchored to
+        let [fraction_x, fraction_y] = anchor.fraction();
+        let direction = |fraction: f32| if fraction == 1.0 { -1.0 } else { 1.0 };
+        let offset_x = direction(fraction_x) * position.0 as f32 - fraction_x * size.0;
+        let offset_y = direction(fraction_y) * position.1 as f32 - fraction_y * size.1;
+        for v in runs
+            .iter_mut()
+            .flat_map(|(_, _, vertex_data)| vertex_data.iter_mut())
+        {
+            v.position[0] += offset_x;
+            v.position[1] += offset_y;
+        }
+        Ok(runs)
+    }
+
+    // One id for all of the styles, however many fonts and sizes they have. The
+    // font_index of a style is the index of a FontHandle.
+    #[allow(clippy::too_many_arguments)]
+    pub fn add_text(
+        &mut self,
+        styles: &[&fontdue::layout::TextStyle],
+        color: [f32; 3],
+        position: (u32, u32), // in pixels
+        anchor: Anchor,
+        max_extent: &vk::Extent3D,
+        device: &Device,
+        texture_storage: &mut TextureStorage,
+        allocator: &mut Allocator,
+        buffer_manager: Arc<Mutex<BufferManager>>,
+        command_pool: &vk::CommandPool,
+        queue: &vk::Queue,
+        descriptor_layout_cache: &mut DescriptorLayoutCache,
+        descriptor_allocator: &mut DescriptorAllocator,
+        material_system: &mut MaterialSystem,
+    ) -> RendererResult<usize> {
+        let runs = self.layout_vertices(
+            styles,
+            color,
+            position,
+            anchor,
+            max_extent,
+            device,
+            texture_storage,
+            allocator,
+            buffer_manager.clone(),
+            command_pool,
+            queue,
+            descriptor_layout_cache,
+            descriptor_allocator,
+            material_system,
+        )?;
+        let mut text_buffers = vec![];
+        for (font, px, vertex_data) in runs {
+            let capacity = vertex_data.len();
+            text_buffers.push(TextBuffer::new(
+                font,
+                px,
+                anchor,
+                vertex_data,
+                self.screen_size,
+                capacity,
+                device,
+                allocator,
+                buffer_manager.clone(),
+            )?);
+        }
+        let id = self.new_text_id();
+        self.vertex_data.insert(id, text_buffers);
+        self.generation += 1;
+        Ok(id)
+    }
+
+    // Lays out the text of id again. The buffers are reused when the new vertices fit,
+    // they are written by the command buffer of the next frame, so the frames in flight
+    // keep drawing the old text. Buffers that are too small are replaced by ones twice
+    // the size.
+    #[allow(clippy::too_many_arguments)]
+    pub fn update_text(
+        &mut self,
+        id: usize,
+        styles: &[&fontdue::layout::TextStyle],
+        color: [f32; 3],
+        position: (u32, u32), // in pixels
+        anchor: Anchor,
+        max_extent: &vk::Extent3D,
+        device: &Device,
+        texture_storage: &mut TextureStorage,
+        allocator: &mut Allocator,
+        buffer_manager: Arc<Mutex<BufferManager>>,
+        command_pool: &vk::CommandPool,
+        queue: &vk::Queue,
+        descriptor_layout_cache: &mut DescriptorLayoutCache,
+        descriptor_allocator: &mut DescriptorAllocator,
+        material_system: &mut MaterialSystem,
+    ) -> RendererResult<()> {
+        if !self.vertex_data.contains_key(&id) {
+            return Err(InvalidHandle.into());
+        }
+        let runs = self.layout_vertices(
+            styles,
+            color,
+            position,
+            anchor,
+            max_extent,
+            device,
+            texture_storage,
+            allocator,
+            buffer_manager.clone(),
+            command_pool,
+            queue,
+            descriptor_layout_cache,
+            descriptor_allocator,
+            material_system,
+        )?;
+        let screen_size = self.screen_size;
+        let text_buffers = self
+            .vertex_data
+            .get_mut(&id)
+            .ok_or::<RendererError>(InvalidHandle.into())?;
+        let kept = runs.len().min(text_buffers.len());
+        for mut text_buffer in text_buffers.drain(kept..) {
+            text_buffer.destroy();
+        }
+        for (i, (font, px, vertex_data)) in runs.into_iter().enumerate() {
+            match text_buffers.get_mut(i) {
+                Some(text_buffer) if vertex_data.len() <= text_buffer.capacity => {
+                    text_buffer.font = font;
+                    text_buffer.px = px;
+                    text_buffer.anchor = anchor;
+                    text_buffer.vertex_data = to_screen(&vertex_data, anchor, screen_size);
+                    text_buffer.pixel_vertices = vertex_data;
+                    text_buffer.dirty = true;
+                }
+                Some(text_buffer) => {
+                    let capacity = vertex_data.len().max(text_buffer.capacity * 2);
+                    let mut old = std::mem::replace(
+                        text_buffer,
+                        TextBuffer::new(
+                            font,
+                            px,
+                            anchor,
+                            vertex_data,
+                            screen_size,
+                            capacity,
+                            device,
+                            allocator,
+                            buffer_manager.clone(),
+                        )?,
+                    );
+                    old.destroy();
+                }
+                None => {
+                    let capacity = vertex_data.len();
+                    text_buffers.push(TextBuffer::new(
+                        font,
+                        px,
+                        anchor,
+                        vertex_data,
+                        screen_size,
+                        capacity,
+                        device,
+                        allocator,
+                        buffer_manager.clone(),
+                    )?);
+                }
             }
         }
-        let id: usize = rand::random();
-        let text_buffer = TextBuffer::new(px, vertex_data, device, allocator, buffer_manager)?;
-        self.vertex_data.insert(id, text_buffer);
-        ret_ids.push(id);
-        Ok(ret_ids)
+        self.generation += 1;
+        Ok(())
+    }
+
+    // Writes the text changed by update_text, before anything of the frame is drawn
+    pub(crate) fn record_uploads(&mut self, device: &Device, cmd_buf: vk::CommandBuffer) {
+        let dirty = self
+            .vertex_data
+            .values_mut()
+            .flatten()
+            .filter(|text_buffer| text_buffer.dirty)
+            .collect::<Vec<_>>();
+        if dirty.is_empty() {
+            return;
+        }
+        let before = [vk::MemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::VERTEX_ATTRIBUTE_READ)
+            .dst_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .build()];
+        let after = [vk::MemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .dst_access_mask(vk::AccessFlags::VERTEX_ATTRIBUTE_READ)
+            .build()];
+        unsafe {
+            device.cmd_pipeline_barrier(
+                cmd_buf,
+                vk::PipelineStageFlags::VERTEX_INPUT,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::DependencyFlags::empty(),
+                &before,
+                &[],
+                &[],
+            );
+            for text_buffer in dirty {
+                let bytes = std::slice::from_raw_parts(
+                    text_buffer.vertex_data.as_ptr() as *const u8,
+                    std::mem::size_of_val(text_buffer.vertex_data.as_slice()),
+                );
+                let buffer = text_buffer.vertex_buffer.get_buffer().buffer;
+                for (i, chunk) in bytes.chunks(MAX_UPDATE_SIZE).enumerate() {
+                    let offset = (i * MAX_UPDATE_SIZE) as vk::DeviceSize;
+                    device.cmd_update_buffer(cmd_buf, buffer, offset, chunk);
+                }
+                text_buffer.dirty = false;
+            }
+            device.cmd_pipeline_barrier(
+                cmd_buf,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::PipelineStageFlags::VERTEX_INPUT,
+                vk::DependencyFlags::empty(),
+                &after,
+                &[],
+                &[],
+            );
+        }
+    }
+
+    // How far the pen moves for a char of the first font at size px, without kerning
+    pub fn advance(&self, c: char, px: f32) -> f32 {
+        self.fonts[self.font_for(c, 0)].metrics(c, px).advance_width
     }
 
     pub fn remove_text_by_id(&mut self, id: usize) -> RendererResult<()> {
         // TODO Remove the texture atlas too? How?
 
-        if let Some(mut vert_data) = self.vertex_data.remove(&id) {
-            vert_data.destroy();
+        if let Some(text_buffers) = self.vertex_data.remove(&id) {
+            for mut text_buffer in text_buffers {
+                text_buffer.destroy();
+            }
+            self.generation += 1;
             Ok(())
         } else {
             Err(InvalidHandle.into())
@@ -492,7 +922,6 @@ impl TextHandler {
         &mut self,
         device: &Device,
         cmd_buf: vk::CommandBuffer,
-        index: usize,
         extent: vk::Extent2D,
         material_system: &MaterialSystem,
     ) -> RendererResult<()> {
@@ -509,21 +938,31 @@ impl TextHandler {
             extent,
         }];
         let mut pipeline = vk::Pipeline::null();
-        for text_buffer in self.vertex_data.values_mut() {
-            let atlas = if let Some((_px, atlas)) = self
+        for text_buffer in self.vertex_data.values_mut().flatten() {
+            if text_buffer.vertex_data.is_empty() {
+                continue;
+            }
+            let key = (text_buffer.font, text_buffer.px);
+            let atlas = if let Some((_key, atlas)) = self
                 .atlases
                 .iter()
-                .find(|(px, _atlas)| *px == text_buffer.px)
+                .find(|(atlas_key, _atlas)| *atlas_key == key)
             {
                 atlas
             } else {
-                error!("Could not find atlas for px {}", text_buffer.px);
+                error!(
+                    "Could not find atlas for font {} px {}",
+                    text_buffer.font.0, text_buffer.px
+                );
                 continue;
             };
             let material_handle = if let Some(handle) = atlas.material_handle {
                 handle
             } else {
-                error!("Atlas {} px has no material handle!", text_buffer.px);
+                error!(
+                    "Atlas of font {} {} px has no material handle!",
+                    text_buffer.font.0, text_buffer.px
+                );
                 continue;
             };
             let material = material_system.get_material_by_handle(material_handle)?;
@@ -556,17 +995,16 @@ impl TextHandler {
                     0,
                     0,
                 );
-                text_buffer.last_image_index = Some(index as u32);
             }
         }
         Ok(())
     }
 
     pub fn destroy(&mut self) {
-        for text_buffer in self.vertex_data.values_mut() {
+        for text_buffer in self.vertex_data.values_mut().flatten() {
             text_buffer
                 .vertex_buffer
-                .queue_free(text_buffer.last_image_index)
+                .queue_free()
                 .expect("Could not queue buffer for free");
         }
         self.vertex_data.clear();
@@ -0,0 +1,627 @@
+use std::ops::Range;
+
+use nalgebra_glm as glm;
+use winit::event::{
+    ElementState, Event, Ime, KeyboardInput, ModifiersState, MouseButton, VirtualKeyCode,
+    WindowEvent,
+};
+use winit::window::Window;
+
+use super::color::Color;
+
+// What a text input reports back to the application
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TextInputEvent {
+    // Enter was pressed, with the text at that point
+    Submitted(String),
+    // Escape was pressed, the text is left as it is
+    Cancelled,
+}
+
+// Copy and paste go to the system clipboard with the clipboard feature, otherwise they
+// only work within the application
+#[derive(Default)]
+pub struct Clipboard {
+    #[cfg(feature = "clipboard")]
+    system: Option<arboard::Clipboard>,
+    local: String,
+}
+
+impl Clipboard {
+    pub fn new() -> Self {
+        Self {
+            #[cfg(feature = "clipboard")]
+            system: arboard::Clipboard::new()
+                .map_err(|e| log::warn!("No system clipboard, copying locally: {}", e))
+                .ok(),
+            local: String::new(),
+        }
+    }
+
+    pub fn get(&mut self) -> String {
+        #[cfg(feature = "clipboard")]
+        if let Some(text) = self
+            .system
+            .as_mut()
+            .and_then(|clipboard| clipboard.get_text().ok())
+        {
+            return text;
+        }
+        self.local.clone()
+    }
+
+    pub fn set(&mut self, text: &str) {
+        #[cfg(feature = "clipboard")]
+        if let Some(clipboard) = self.system.as_mut() {
+            if let Err(e) = clipboard.set_text(text) {
+                log::warn!("Could not copy to the system clipboard: {}", e);
+            }
+        }
+        self.local = text.to_string();
+    }
+}
+
+// The editing state of a single line of text, without anything to do with drawing it.
+// Positions are byte offsets into the text that always lie on char boundaries.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TextEditor {
+    text: String,
+    cursor: usize,
+    // The other end of the selection, the cursor is one end of it
+    anchor: Option<usize>,
+    // What an input method is composing, shown at the cursor but not part of the text
+    // until it is committed
+    preedit: String,
+}
+
+impl TextEditor {
+    // The cursor starts at the end
+    pub fn new(text: &str) -> Self {
+        Self {
+            text: text.to_string(),
+            cursor: text.len(),
+            anchor: None,
+            preedit: String::new(),
+        }
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    // Never empty
+    pub fn selection(&self) -> Option<Range<usize>> {
+        self.anchor.map(|anchor| {
+            if anchor < self.cursor {
+                anchor..self.cursor
+            } else {
+                self.cursor..anchor
+            }
+        })
+    }
+
+    pub fn selected_text(&self) -> &str {
+        self.selection().map_or("", |range| &self.text[range])
+    }
+
+    pub fn preedit(&self) -> &str {
+        &self.preedit
+    }
+
+    pub fn is_composing(&self) -> bool {
+        !self.preedit.is_empty()
+    }
+
+    pub fn set_text(&mut self, text: &str) {
+        *self = Self::new(text);
+    }
+
+    // Replaces the selection
+    pub fn insert(&mut self, text: &str) {
+        self.delete_selection();
+        self.text.insert_str(self.cursor, text);
+        self.cursor += text.len();
+    }
+
+    pub fn select_all(&mut self) {
+        self.anchor = Some(0);
+        self.cursor = self.text.len();
+        self.clear_empty_selection();
+    }
+
+    // Moves the cursor, extending the selection from where the cursor was instead of
+    // dropping it if extend is set
+    pub fn move_to(&mut self, position: usize, extend: bool) {
+        if extend {
+            self.anchor.get_or_insert(self.cursor);
+        } else {
+            self.anchor = None;
+        }
+        self.cursor = position.min(self.text.len());
+        self.clear_empty_selection();
+    }
+
+    // Returns whether anything was selected
+    pub fn delete_selection(&mut self) -> bool {
+        match self.selection() {
+            Some(range) => {
+                self.cursor = range.start;
+                self.text.replace_range(range, "");
+                self.anchor = None;
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Control characters are left to key, they arrive as characters as well on some
+    // platforms
+    pub fn received_character(&mut self, c: char) {
+        if !c.is_control() && !self.is_composing() {
+            let mut buffer = [0; 4];
+            self.insert(c.encode_utf8(&mut buffer));
+        }
+    }
+
+    pub fn ime(&mut self, ime: &Ime) {
+        match ime {
+            Ime::Preedit(text, _) => self.preedit = text.clone(),
+            Ime::Commit(text) => {
+                self.preedit.clear();
+                self.insert(text);
+            }
+            Ime::Disabled => self.preedit.clear(),
+            Ime::Enabled => {}
+        }
+    }
+
+    // Keys are ignored while an input method composes, it handles them itself
+    pub fn key(
+        &mut self,
+        key: VirtualKeyCode,
+        modifiers: ModifiersState,
+        clipboard: &mut Clipboard,
+    ) -> Option<TextInputEvent> {
+        if self.is_composing() {
+            return None;
+        }
+        let extend = modifiers.shift();
+        // Cmd on macOS
+        let command = modifiers.ctrl() || modifiers.logo();
+        match key {
+            VirtualKeyCode::Left => {
+                let position = match self.selection() {
+                    Some(range) if !extend => range.start,
+                    _ => self.previous_boundary(self.cursor),
+                };
+                self.move_to(position, extend);
+            }
+            VirtualKeyCode::Right => {
+                let position = match self.selection() {
+                    Some(range) if !extend => range.end,
+                    _ => self.next_boundary(self.cursor),
+                };
+                self.move_to(position, extend);
+            }
+            VirtualKeyCode::Home => self.move_to(0, extend),
+            VirtualKeyCode::End => self.move_to(self.text.len(), extend),
+            VirtualKeyCode::Back => {
+                if !self.delete_selection() && self.cursor > 0 {
+                    let start = self.previous_boundary(self.cursor);
+                    self.text.replace_range(start..self.cursor, "");
+                    self.cursor = start;
+                }
+            }
+            VirtualKeyCode::Delete => {
+                if !self.delete_selection() && self.cursor < self.text.len() {
+                    let end = self.next_boundary(self.cursor);
+                    self.text.replace_range(self.cursor..end, "");
+                }
+            }
+            VirtualKeyCode::A if command => self.select_all(),
+            VirtualKeyCode::C if command => {
+                if self.selection().is_some() {
+                    clipboard.set(self.selected_text());
+                }
+            }
+            VirtualKeyCode::X if command => {
+                if self.selection().is_some() {
+                    clipboard.set(self.selected_text());
+                    self.delete_selection();
+                }
+            }
+            VirtualKeyCode::V if command => {
+                // A single line, so line breaks become spaces
+                let text = clipboard.get().replace(['\r', '\n'], " ");
+                self.insert(&text);
+            }
+            VirtualKeyCode::Return | VirtualKeyCode::NumpadEnter => {
+                return Some(TextInputEvent::Submitted(self.text.clone()));
+            }
+            VirtualKeyCode::Escape => return Some(TextInputEvent::Cancelled),
+            _ => {}
+        }
+        None
+    }
+
+    fn previous_boundary(&self, position: usize) -> usize {
+        self.text[..position]
+            .char_indices()
+            .next_back()
+            .map_or(0, |(i, _)| i)
+    }
+
+    fn next_boundary(&self, position: usize) -> usize {
+        self.text[position..]
+            .chars()
+            .next()
+            .map_or(position, |c| position + c.len_utf8())
+    }
+
+    fn clear_empty_selection(&mut self) {
+        if self.anchor == Some(self.cursor) {
+            self.anchor = None;
+        }
+    }
+}
+
+// Returned by TextInput::handle_event
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TextInputResponse {
+    // The event was meant for the text input and should not be handled by anything else,
+    // e.g. key bindings
+    pub consumed: bool,
+    pub event: Option<TextInputEvent>,
+}
+
+// A part of the displayed text drawn in one color, see TextInput::runs
+#[derive(Clone, Debug, PartialEq)]
+pub struct TextRun {
+    pub text: String,
+    // From the left of the input, in pixels
+    pub x: f32,
+    pub color: Color,
+}
+
+// A single line text field drawn with the text overlay, see Renderer::update_text_input.
+// Clicking into its rect focuses it and clicking elsewhere unfocuses it. A focused input
+// takes all keyboard events, so it should see events before any key bindings do.
+pub struct TextInput {
+    // The top left corner and size of the input in pixels
+    pub position: (u32, u32),
+    pub size: (u32, u32),
+    pub px: f32,
+    pub color: Color,
+    // There is no way to draw a background, so the selection is told apart by its color
+    pub selection_color: Color,
+    // What an input method is composing
+    pub preedit_color: Color,
+    editor: TextEditor,
+    focused: bool,
+    modifiers: ModifiersState,
+    cursor_position: glm::Vec2,
+    clipboard: Clipboard,
+    // Pixels the text is moved to the left by to keep the cursor inside the input
+    scroll: f32,
+    pub(crate) text_ids: Vec<usize>,
+    pub(crate) dirty: bool,
+}
+
+impl TextInput {
+    pub fn new(position: (u32, u32), size: (u32, u32), px: f32, text: &str) -> Self {
+        Self {
+            position,
+            size,
+            px,
+            color: Color::from_srgb(1.0, 1.0, 1.0),
+            selection_color: Color::from_srgb(0.3, 0.6, 1.0),
+            preedit_color: Color::from_srgb(1.0, 0.8, 0.3),
+            editor: TextEditor::new(text),
+            focused: false,
+            modifiers: ModifiersState::empty(),
+            cursor_position: glm::Vec2::zeros(),
+            clipboard: Clipboard::new(),
+            scroll: 0.0,
+            text_ids: vec![],
+            dirty: true,
+        }
+    }
+
+    pub fn editor(&self) -> &TextEditor {
+        &self.editor
+    }
+
+    pub fn text(&self) -> &str {
+        self.editor.text()
+    }
+
+    pub fn set_text(&mut self, text: &str) {
+        self.editor.set_text(text);
+        self.dirty = true;
+    }
+
+    pub fn is_focused(&self) -> bool {
+        self.focused
+    }
+
+    // Input methods are only enabled while a text input is focused, so key bindings
+    // keep working otherwise
+    pub fn set_focused(&mut self, window: &Window, focused: bool) {
+        if self.focused != focused {
+            self.focused = focused;
+            self.dirty = true;
+            window.set_ime_allowed(focused);
+            if focused {
+                window.set_ime_position(winit::dpi::PhysicalPosition::new(
+                    self.position.0,
+                    self.position.1 + self.size.1,
+                ));
+            } else {
+                self.editor.preedit.clear();
+            }
+        }
+    }
+
+    pub fn contains(&self, point: &glm::Vec2) -> bool {
+        point.x >= self.position.0 as f32
+            && point.y >= self.position.1 as f32
+            && point.x < (self.position.0 + self.size.0) as f32
+            && point.y < (self.position.1 + self.size.1) as f32
+    }
+
+    pub fn handle_event(&mut self, window: &Window, event: &Event<()>) -> TextInputResponse {
+        let event = match event {
+            Event::WindowEvent { event, .. } => event,
+            _ => return TextInputResponse::default(),
+        };
+        let before = self.editor.clone();
+        let mut response = TextInputResponse::default();
+        match event {
+            // Modifiers and the cursor are tracked even without focus, the input can get
+            // focused while a modifier is held
+            WindowEvent::ModifiersChanged(modifiers) => self.modifiers = *modifiers,
+            WindowEvent::CursorMoved { position, .. } => {
+                self.cursor_position = glm::vec2(position.x as f32, position.y as f32);
+            }
+            WindowEvent::MouseInput {
+                state: ElementState::Pressed,
+                button: MouseButton::Left,
+                ..
+            } => {
+                let inside = self.contains(&self.cursor_position);
+                self.set_focused(window, inside);
+                response.consumed = inside;
+            }
+            _ if !self.focused => {}
+            WindowEvent::ReceivedCharacter(c) => {
+                self.editor.received_character(*c);
+                response.consumed = true;
+            }
+            WindowEvent::Ime(ime) => {
+                self.editor.ime(ime);
+                response.consumed = true;
+            }
+            WindowEvent::KeyboardInput {
+                input:
+                    KeyboardInput {
+                        state,
+                        virtual_keycode,
+                        ..
+                    },
+                ..
+            } => {
+                if let (ElementState::Pressed, Some(key)) = (state, virtual_keycode) {
+                    response.event = self.editor.key(*key, self.modifiers, &mut self.clipboard);
+                    if response.event.is_some() {
+                        // Both submitting and cancelling end the editing
+                        self.set_focused(window, false);
+                    }
+                }
+                response.consumed = true;
+            }
+            _ => {}
+        }
+        if self.editor != before {
+            self.dirty = true;
+        }
+        response
+    }
+
+    // The text as it is drawn, with the preedit at the cursor and the cursor itself as a
+    // bar while focused. advance gives the width of a char at the size of the input.
+    // Chars that don't fit into the input are left out.
+    pub fn runs(&mut self, advance: impl Fn(char) -> f32) -> Vec<TextRun> {
+        let editor = &self.editor;
+        let selection = editor.selection().unwrap_or(0..0);
+        let mut chars = vec![];
+        let mut x = 0.0;
+        let mut cursor_x = 0.0;
+        for (i, c) in editor.text.char_indices() {
+            if i == editor.cursor {
+                for p in editor.preedit.chars() {
+                    chars.push((p, x, self.preedit_color));
+                    x += advance(p);
+                }
+                cursor_x = x;
+            }
+            let color = if selection.contains(&i) {
+                self.selection_color
+            } else {
+                self.color
+            };
+            chars.push((c, x, color));
+            x += advance(c);
+        }
+        if editor.cursor == editor.text.len() {
+            for p in editor.preedit.chars() {
+                chars.push((p, x, self.preedit_color));
+                x += advance(p);
+            }
+            cursor_x = x;
+        }
+        if self.focused {
+            let bar = advance('|');
+            chars.push(('|', cursor_x - 0.5 * bar, self.color));
+        }
+
+        // Scroll just far enough to keep the cursor visible
+        let width = self.size.0 as f32;
+        if cursor_x - self.scroll > width {
+            self.scroll = cursor_x - width;
+        } else if cursor_x < self.scroll {
+            self.scroll = cursor_x;
+        }
+        self.scroll = self.scroll.min((x - width).max(0.0)).max(0.0);
+
+        let mut runs: Vec<TextRun> = vec![];
+        for (c, x, color) in chars {
+            let x = x - self.scroll;
+            if x < 0.0 || x + advance(c) > width {
+                continue;
+            }
+            match runs.last_mut() {
+                // The bar is drawn over the text, so it always gets a run of its own
+                Some(run) if run.color == color && c != '|' => run.text.push(c),
+                _ => runs.push(TextRun {
+                    text: c.to_string(),
+                    x,
+                    color,
+                }),
+            }
+        }
+        runs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn press(editor: &mut TextEditor, key: VirtualKeyCode, modifiers: ModifiersState) {
+        assert_eq!(editor.key(key, modifiers, &mut Clipboard::default()), None);
+    }
+
+    #[test]
+    fn typing_inserts_at_the_cursor() {
+        let mut editor = TextEditor::new("ac");
+        assert_eq!(editor.cursor(), 2);
+        press(&mut editor, VirtualKeyCode::Left, ModifiersState::empty());
+        editor.received_character('b');
+        assert_eq!(editor.text(), "abc");
+        assert_eq!(editor.cursor(), 2);
+        // Control characters come through key instead
+        editor.received_character('\u{8}');
+        assert_eq!(editor.text(), "abc");
+    }
+
+    #[test]
+    fn cursor_moves_by_chars_not_bytes() {
+        let mut editor = TextEditor::new("aé");
+        press(&mut editor, VirtualKeyCode::Left, ModifiersState::empty());
+        assert_eq!(editor.cursor(), 1);
+        press(&mut editor, VirtualKeyCode::Right, ModifiersState::empty());
+        assert_eq!(editor.cursor(), 3);
+        press(&mut editor, VirtualKeyCode::Back, ModifiersState::empty());
+        assert_eq!(editor.text(), "a");
+        press(&mut editor, VirtualKeyCode::Home, ModifiersState::empty());
+        press(&mut editor, VirtualKeyCode::Delete, ModifiersState::empty());
+        assert_eq!(editor.text(), "");
+        // Nothing left to delete
+        press(&mut editor, VirtualKeyCode::Back, ModifiersState::empty());
+        press(&mut editor, VirtualKeyCode::Delete, ModifiersState::empty());
+        assert_eq!(editor.text(), "");
+    }
+
+    #[test]
+    fn shift_extends_and_plain_moves_collapse_the_selection() {
+        let mut editor = TextEditor::new("hello");
+        press(&mut editor, VirtualKeyCode::Left, ModifiersState::SHIFT);
+        press(&mut editor, VirtualKeyCode::Left, ModifiersState::SHIFT);
+        assert_eq!(editor.selection(), Some(3..5));
+        assert_eq!(editor.selected_text(), "lo");
+        // Moving back over the anchor leaves an empty selection, which is none
+        press(&mut editor, VirtualKeyCode::Right, ModifiersState::SHIFT);
+        press(&mut editor, VirtualKeyCode::Right, ModifiersState::SHIFT);
+        assert_eq!(editor.selection(), None);
+        press(&mut editor, VirtualKeyCode::Home, ModifiersState::SHIFT);
+        assert_eq!(editor.selection(), Some(0..5));
+        // Right without shift goes to the end of the selection
+        press(&mut editor, VirtualKeyCode::Right, ModifiersState::empty());
+        assert_eq!((editor.selection(), editor.cursor()), (None, 5));
+    }
+
+    #[test]
+    fn typing_replaces_the_selection() {
+        let mut editor = TextEditor::new("hello world");
+        editor.select_all();
+        editor.received_character('x');
+        assert_eq!(editor.text(), "x");
+        assert_eq!(editor.selection(), None);
+        // Select all on an empty text selects nothing
+        editor.set_text("");
+        editor.select_all();
+        assert_eq!(editor.selection(), None);
+    }
+
+    #[test]
+    fn cut_and_paste_go_through_the_clipboard() {
+        let mut clipboard = Clipboard::default();
+        let mut editor = TextEditor::new("one two");
+        editor.move_to(3, false);
+        editor.move_to(7, true);
+        assert_eq!(
+            editor.key(VirtualKeyCode::X, ModifiersState::CTRL, &mut clipboard),
+            None
+        );
+        assert_eq!(editor.text(), "one");
+        clipboard.set("a\nb");
+        editor.key(VirtualKeyCode::V, ModifiersState::CTRL, &mut clipboard);
+        assert_eq!(editor.text(), "onea b");
+        // Without the modifier it's just a letter key, which arrives as a character
+        editor.key(VirtualKeyCode::V, ModifiersState::empty(), &mut clipboard);
+        assert_eq!(editor.text(), "onea b");
+    }
+
+    #[test]
+    fn composition_blocks_keys_until_committed() {
+        let mut editor = TextEditor::new("");
+        editor.ime(&Ime::Preedit("ka".to_string(), None));
+        assert!(editor.is_composing());
+        editor.received_character('k');
+        press(&mut editor, VirtualKeyCode::Back, ModifiersState::empty());
+        assert_eq!((editor.text(), editor.preedit()), ("", "ka"));
+        editor.ime(&Ime::Commit("か".to_string()));
+        assert!(!editor.is_composing());
+        assert_eq!(editor.text(), "か");
+        editor.ime(&Ime::Preedit("x".to_string(), None));
+        editor.ime(&Ime::Disabled);
+        assert_eq!((editor.text(), editor.preedit()), ("か", ""));
+    }
+
+    #[test]
+    fn enter_submits_and_escape_cancels() {
+        let mut clipboard = Clipboard::default();
+        let mut editor = TextEditor::new("done");
+        assert_eq!(
+            editor.key(
+                VirtualKeyCode::Return,
+                ModifiersState::empty(),
+                &mut clipboard
+            ),
+            Some(TextInputEvent::Submitted("done".to_string()))
+        );
+        assert_eq!(
+            editor.key(
+                VirtualKeyCode::Escape,
+                ModifiersState::empty(),
+                &mut clipboard
+            ),
+            Some(TextInputEvent::Cancelled)
+        );
+        assert_eq!(editor.text(), "done");
+    }
+}
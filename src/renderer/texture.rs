@@ -1,3 +1,5 @@
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 
 use ash::{vk, Device};
@@ -9,20 +11,193 @@ use log::info;
 
 use super::{
     buffer::BufferManager,
+    debug_namer::DebugNamer,
+    error::{CubemapError, InvalidHandle, RendererError},
+    stats::FrameEvents,
+    timeline::retired,
     utils::{Handle, HandleArray},
     RendererResult,
 };
 
+// How a texture loaded from a file is created
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TextureCreateOptions {
+    // Blit the full mip chain down to 1x1 after the upload. Worth turning off for textures
+    // that are never minified, like UI images.
+    pub generate_mipmaps: bool,
+}
+
+impl Default for TextureCreateOptions {
+    fn default() -> Self {
+        Self {
+            generate_mipmaps: true,
+        }
+    }
+}
+
+// The number of levels down to 1x1, every level halves both sides and rounds down
+fn mip_level_count(width: u32, height: u32) -> u32 {
+    32 - width.max(height).max(1).leading_zeros()
+}
+
+// Fills the mip levels after the first by blitting each one from the one above. Expects
+// all levels in TRANSFER_DST_OPTIMAL and leaves them in SHADER_READ_ONLY_OPTIMAL.
+fn record_mipmap_generation(
+    device: &Device,
+    command_buffer: vk::CommandBuffer,
+    image: vk::Image,
+    extent: vk::Extent2D,
+    mip_levels: u32,
+) {
+    let level_barrier = |level: u32,
+                         src_access_mask: vk::AccessFlags,
+                         dst_access_mask: vk::AccessFlags,
+                         old_layout: vk::ImageLayout,
+                         new_layout: vk::ImageLayout| {
+        vk::ImageMemoryBarrier::builder()
+            .image(image)
+            .src_access_mask(src_access_mask)
+            .dst_access_mask(dst_access_mask)
+            .old_layout(old_layout)
+            .new_layout(new_layout)
+            .subresource_range(vk::ImageSubresourceRange {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                base_mip_level: level,
+                level_count: 1,
+                base_array_layer: 0,
+                layer_count: 1,
+            })
+            .build()
+    };
+    let mut width = extent.width as i32;
+    let mut height = extent.height as i32;
+    for level in 1..mip_levels {
+        let to_source = level_barrier(
+            level - 1,
+            vk::AccessFlags::TRANSFER_WRITE,
+            vk::AccessFlags::TRANSFER_READ,
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+        );
+        unsafe {
+            device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &[to_source],
+            )
+        };
+
+        // Odd sizes round down, so the last column or row of a level is averaged away
+        let next_width = (width / 2).max(1);
+        let next_height = (height / 2).max(1);
+        let blit = vk::ImageBlit {
+            src_subresource: vk::ImageSubresourceLayers {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                mip_level: level - 1,
+                base_array_layer: 0,
+                layer_count: 1,
+            },
+            src_offsets: [
+                vk::Offset3D { x: 0, y: 0, z: 0 },
+                vk::Offset3D {
+                    x: width,
+                    y: height,
+                    z: 1,
+                },
+            ],
+            dst_subresource: vk::ImageSubresourceLayers {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                mip_level: level,
+                base_array_layer: 0,
+                layer_count: 1,
+            },
+            dst_offsets: [
+                vk::Offset3D { x: 0, y: 0, z: 0 },
+                vk::Offset3D {
+                    x: next_width,
+                    y: next_height,
+                    z: 1,
+                },
+            ],
+        };
+        unsafe {
+            device.cmd_blit_image(
+                command_buffer,
+                image,
+                vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                image,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                &[blit],
+                vk::Filter::LINEAR,
+            )
+        };
+
+        let to_shader = level_barrier(
+            level - 1,
+            vk::AccessFlags::TRANSFER_READ,
+            vk::AccessFlags::SHADER_READ,
+            vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        );
+        unsafe {
+            device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::PipelineStageFlags::FRAGMENT_SHADER,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &[to_shader],
+            )
+        };
+        width = next_width;
+        height = next_height;
+    }
+
+    // The last level was only written to
+    let to_shader = level_barrier(
+        mip_levels - 1,
+        vk::AccessFlags::TRANSFER_WRITE,
+        vk::AccessFlags::SHADER_READ,
+        vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+        vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+    );
+    unsafe {
+        device.cmd_pipeline_barrier(
+            command_buffer,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::PipelineStageFlags::FRAGMENT_SHADER,
+            vk::DependencyFlags::empty(),
+            &[],
+            &[],
+            &[to_shader],
+        )
+    };
+}
+
 pub struct Texture {
     vk_image: vk::Image,
     pub image_view: vk::ImageView,
     pub sampler: vk::Sampler,
     allocation: Option<Allocation>,
+    extent: vk::Extent2D,
+    format: vk::Format,
+    // 6 for cubemaps, their image_view is a cube view of all faces
+    layer_count: u32,
+    // UNORM view of all layers for compute shaders to write to, sRGB can't be stored to
+    storage_view: Option<vk::ImageView>,
+    usage: vk::ImageUsageFlags,
+    mip_levels: u32,
 }
 
 impl Texture {
     pub fn from_file<P: AsRef<std::path::Path>>(
         path: P,
+        options: TextureCreateOptions,
         device: &Device,
         allocator: &mut Allocator,
         buffer_manager: Arc<Mutex<BufferManager>>,
@@ -30,10 +205,15 @@ impl Texture {
         queue: vk::Queue,
     ) -> RendererResult<Self> {
         // Load image from file
-        let image = image::open(path)
-            .map(|img| img.into_rgba8())
-            .expect("unable to open image");
+        let image = image::open(path)?.into_rgba8();
         let (width, height) = image.dimensions();
+        // R8G8B8A8_SRGB has to support linear blits on every device, so the chain is
+        // generated without checking the format features
+        let mip_levels = if options.generate_mipmaps {
+            mip_level_count(width, height)
+        } else {
+            1
+        };
 
         // Create vulkan image
         let image_create_info = vk::ImageCreateInfo::builder()
@@ -43,11 +223,15 @@ impl Texture {
                 height,
                 depth: 1,
             })
-            .mip_levels(1)
+            .mip_levels(mip_levels)
             .array_layers(1)
             .format(vk::Format::R8G8B8A8_SRGB)
             .samples(vk::SampleCountFlags::TYPE_1)
-            .usage(vk::ImageUsageFlags::TRANSFER_DST | vk::ImageUsageFlags::SAMPLED);
+            .usage(
+                vk::ImageUsageFlags::TRANSFER_DST
+                    | vk::ImageUsageFlags::TRANSFER_SRC
+                    | vk::ImageUsageFlags::SAMPLED,
+            );
         let vk_image = unsafe { device.create_image(&image_create_info, None)? };
 
         // Allocate memory for image
@@ -70,7 +254,7 @@ impl Texture {
             .format(vk::Format::R8G8B8A8_SRGB)
             .subresource_range(vk::ImageSubresourceRange {
                 aspect_mask: vk::ImageAspectFlags::COLOR,
-                level_count: 1,
+                level_count: mip_levels,
                 layer_count: 1,
                 ..Default::default()
             });
@@ -79,7 +263,10 @@ impl Texture {
         // Create sampler
         let sampler_info = vk::SamplerCreateInfo::builder()
             .mag_filter(vk::Filter::LINEAR)
-            .min_filter(vk::Filter::LINEAR);
+            .min_filter(vk::Filter::LINEAR)
+            .mipmap_mode(vk::SamplerMipmapMode::LINEAR)
+            .min_lod(0.0)
+            .max_lod(mip_levels as f32);
         let sampler = unsafe { device.create_sampler(&sampler_info, None) }?;
 
         // Create buffer to copy data into image
@@ -117,7 +304,7 @@ impl Texture {
             .subresource_range(vk::ImageSubresourceRange {
                 aspect_mask: vk::ImageAspectFlags::COLOR,
                 base_mip_level: 0,
-                level_count: 1,
+                level_count: mip_levels,
                 base_array_layer: 0,
                 layer_count: 1,
             })
@@ -164,32 +351,14 @@ impl Texture {
             )
         }
 
-        // Transition image layout for use as texture
-        let barrier = vk::ImageMemoryBarrier::builder()
-            .image(vk_image)
-            .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
-            .dst_access_mask(vk::AccessFlags::SHADER_READ)
-            .old_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
-            .new_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
-            .subresource_range(vk::ImageSubresourceRange {
-                aspect_mask: vk::ImageAspectFlags::COLOR,
-                base_mip_level: 0,
-                level_count: 1,
-                base_array_layer: 0,
-                layer_count: 1,
-            })
-            .build();
-        unsafe {
-            device.cmd_pipeline_barrier(
-                copy_cmd_buf,
-                vk::PipelineStageFlags::TRANSFER,
-                vk::PipelineStageFlags::FRAGMENT_SHADER,
-                vk::DependencyFlags::empty(),
-                &[],
-                &[],
-                &[barrier],
-            )
-        };
+        // Fill the rest of the chain and transition image layout for use as texture
+        record_mipmap_generation(
+            device,
+            copy_cmd_buf,
+            vk_image,
+            vk::Extent2D { width, height },
+            mip_levels,
+        );
 
         // End command buffer
         unsafe { device.end_command_buffer(copy_cmd_buf) }?;
@@ -207,7 +376,7 @@ impl Texture {
 
         // Cleanup
         unsafe { device.destroy_fence(fence, None) };
-        buffer.queue_free(None)?;
+        buffer.queue_free()?;
         unsafe { device.free_command_buffers(command_pool, &[copy_cmd_buf]) };
 
         // Done
@@ -216,6 +385,12 @@ impl Texture {
             image_view,
             sampler,
             allocation: Some(allocation),
+            extent: vk::Extent2D { width, height },
+            format: vk::Format::R8G8B8A8_SRGB,
+            layer_count: 1,
+            storage_view: None,
+            usage: image_create_info.usage,
+            mip_levels,
         })
     }
 
@@ -223,6 +398,7 @@ impl Texture {
         data: &[u8],
         width: u32,
         height: u32,
+        format: vk::Format,
         device: &Device,
         allocator: &mut Allocator,
         buffer_manager: Arc<Mutex<BufferManager>>,
@@ -239,19 +415,23 @@ impl Texture {
             })
             .mip_levels(1)
             .array_layers(1)
-            .format(vk::Format::R8_SRGB)
+            .format(format)
             .samples(vk::SampleCountFlags::TYPE_1)
-            .usage(vk::ImageUsageFlags::TRANSFER_DST | vk::ImageUsageFlags::SAMPLED);
+            .usage(
+                vk::ImageUsageFlags::TRANSFER_DST
+                    | vk::ImageUsageFlags::TRANSFER_SRC
+                    | vk::ImageUsageFlags::SAMPLED,
+            );
         let image = unsafe { device.create_image(&img_create_info, None) }?;
 
         //  allocate memory for image
         let reqs = unsafe { device.get_image_memory_requirements(image) };
         info!(
-            "Creating Texture atlas of size {}x{}, {} bytes",
-            width, height, reqs.size
+            "Creating {:?} texture of size {}x{}, {} bytes",
+            format, width, height, reqs.size
         );
         let allocation = allocator.allocate(&AllocationCreateDesc {
-            name: "text texture",
+            name: "texture-from-u8s",
             requirements: reqs,
             location: MemoryLocation::GpuOnly,
             linear: false,
@@ -265,7 +445,7 @@ impl Texture {
         let view_create_info = vk::ImageViewCreateInfo::builder()
             .image(image)
             .view_type(vk::ImageViewType::TYPE_2D)
-            .format(vk::Format::R8_SRGB)
+            .format(format)
             .subresource_range(vk::ImageSubresourceRange {
                 aspect_mask: vk::ImageAspectFlags::COLOR,
                 level_count: 1,
@@ -403,7 +583,7 @@ impl Texture {
 
         // Cleanup
         unsafe { device.destroy_fence(fence, None) };
-        buffer.queue_free(None)?;
+        buffer.queue_free()?;
         unsafe { device.free_command_buffers(*command_pool, &[copy_buf]) };
 
         // Done
@@ -412,93 +592,1135 @@ impl Texture {
             image_view,
             sampler,
             allocation: Some(allocation),
+            extent: vk::Extent2D { width, height },
+            format,
+            layer_count: 1,
+            storage_view: None,
+            usage: img_create_info.usage,
+            mip_levels: 1,
         })
     }
 
-    pub fn destroy(&mut self, device: &Device, allocator: &mut Allocator) {
-        allocator
-            .free(self.allocation.take().expect("Texture had no allocation!"))
-            .expect("Could not free texture allocation");
-        unsafe {
-            device.destroy_sampler(self.sampler, None);
-            device.destroy_image_view(self.image_view, None);
-            device.destroy_image(self.vk_image, None);
-        }
-    }
-}
-
-#[derive(Default)]
-pub struct TextureStorage {
-    textures: HandleArray<Texture>,
-}
-
-impl TextureStorage {
-    pub fn new_texture_from_file<P: AsRef<std::path::Path>>(
-        &mut self,
-        path: P,
+    // A cubemap with the six files as its faces, in the order +X, -X, +Y, -Y, +Z, -Z, see
+    // cubemap.rs. The faces have to be square and of the same size. Skies cover the
+    // screen and are rarely minified, so there is no mip chain.
+    pub fn cubemap_from_files<P: AsRef<std::path::Path>>(
+        paths: &[P; 6],
         device: &Device,
         allocator: &mut Allocator,
         buffer_manager: Arc<Mutex<BufferManager>>,
         command_pool: vk::CommandPool,
         queue: vk::Queue,
-    ) -> RendererResult<Handle<Texture>> {
-        let texture =
-            Texture::from_file(path, device, allocator, buffer_manager, command_pool, queue)?;
-        let handle = self.textures.insert(texture);
-        Ok(handle)
-    }
+    ) -> RendererResult<Self> {
+        // Load the faces
+        let mut faces = Vec::with_capacity(6);
+        for path in paths {
+            let face = image::open(path)?.into_rgba8();
+            if face.width() != face.height() {
+                return Err(CubemapError(format!(
+                    "{} is {}x{}, cubemap faces have to be square",
+                    path.as_ref().display(),
+                    face.width(),
+                    face.height()
+                ))
+                .into());
+            }
+            faces.push(face);
+        }
+        let size = faces[0].width();
+        if let Some((index, _)) = faces
+            .iter()
+            .enumerate()
+            .find(|(_, face)| face.width() != size)
+        {
+            return Err(CubemapError(format!(
+                "{} is {}x{}, but the first face is {}x{}",
+                paths[index].as_ref().display(),
+                faces[index].width(),
+                faces[index].height(),
+                size,
+                size
+            ))
+            .into());
+        }
+        let format = vk::Format::R8G8B8A8_SRGB;
 
-    pub fn new_texture_from_u8(
-        &mut self,
-        data: &[u8],
-        width: u32,
-        height: u32,
-        device: &Device,
-        allocator: &mut Allocator,
-        buffer_manager: Arc<Mutex<BufferManager>>,
-        command_pool: &vk::CommandPool,
-        queue: &vk::Queue,
-    ) -> RendererResult<Handle<Texture>> {
-        let texture = Texture::from_u8s(
-            data,
-            width,
-            height,
+        // Create vulkan image
+        let image_create_info = vk::ImageCreateInfo::builder()
+            .flags(vk::ImageCreateFlags::CUBE_COMPATIBLE)
+            .image_type(vk::ImageType::TYPE_2D)
+            .extent(vk::Extent3D {
+                width: size,
+                height: size,
+                depth: 1,
+            })
+            .mip_levels(1)
+            .array_layers(6)
+            .format(format)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .usage(
+                vk::ImageUsageFlags::TRANSFER_DST
+                    | vk::ImageUsageFlags::TRANSFER_SRC
+                    | vk::ImageUsageFlags::SAMPLED,
+            );
+        let vk_image = unsafe { device.create_image(&image_create_info, None)? };
+
+        // Allocate memory for image
+        let reqs = unsafe { device.get_image_memory_requirements(vk_image) };
+        let allocation = allocator.allocate(&AllocationCreateDesc {
+            name: "cubemap",
+            requirements: reqs,
+            location: MemoryLocation::GpuOnly,
+            linear: false,
+            allocation_scheme: AllocationScheme::GpuAllocatorManaged,
+        })?;
+        unsafe {
+            device.bind_image_memory(vk_image, allocation.memory(), allocation.offset())?;
+        };
+
+        // Create a cube view of all faces
+        let subresource_range = vk::ImageSubresourceRange {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            base_mip_level: 0,
+            level_count: 1,
+            base_array_layer: 0,
+            layer_count: 6,
+        };
+        let view_create_info = vk::ImageViewCreateInfo::builder()
+            .image(vk_image)
+            .view_type(vk::ImageViewType::CUBE)
+            .format(format)
+            .subresource_range(subresource_range);
+        let image_view = unsafe { device.create_image_view(&view_create_info, None) }?;
+
+        // Create sampler
+        let sampler_info = vk::SamplerCreateInfo::builder()
+            .mag_filter(vk::Filter::LINEAR)
+            .min_filter(vk::Filter::LINEAR)
+            .address_mode_u(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_EDGE);
+        let sampler = unsafe { device.create_sampler(&sampler_info, None) }?;
+
+        // Create buffer with the faces one after the other
+        let face_bytes = (size * size * 4) as usize;
+        let mut data = Vec::with_capacity(face_bytes * 6);
+        for face in faces {
+            data.extend_from_slice(face.as_raw());
+        }
+        let mut buffer = BufferManager::new_buffer(
+            buffer_manager,
             device,
             allocator,
-            buffer_manager,
-            command_pool,
-            queue,
+            data.len() as u64,
+            vk::BufferUsageFlags::TRANSFER_SRC,
+            MemoryLocation::CpuToGpu,
+            "cubemap-copy",
         )?;
-        let handle = self.textures.insert(texture);
-        Ok(handle)
-    }
+        buffer.fill(allocator, &data)?;
 
-    pub fn get_number_of_textures(&self) -> usize {
-        self.textures.len()
-    }
+        // Create command buffer to use for copy
+        let command_buf_allocate_info = vk::CommandBufferAllocateInfo::builder()
+            .command_pool(command_pool)
+            .command_buffer_count(1);
+        let copy_cmd_buf =
+            unsafe { device.allocate_command_buffers(&command_buf_allocate_info) }?[0];
 
-    pub fn get_texture(&self, handle: Handle<Texture>) -> Option<&Texture> {
-        self.textures.get(handle)
-    }
+        // Begin command buffer
+        let cmd_begin_info = vk::CommandBufferBeginInfo::builder()
+            .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+        unsafe { device.begin_command_buffer(copy_cmd_buf, &cmd_begin_info) }?;
 
-    pub fn get_texture_mut(&mut self, handle: Handle<Texture>) -> Option<&mut Texture> {
-        self.textures.get_mut(handle)
-    }
+        // Transition every face to transfer dst
+        let barrier = vk::ImageMemoryBarrier::builder()
+            .image(vk_image)
+            .src_access_mask(vk::AccessFlags::empty())
+            .dst_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .old_layout(vk::ImageLayout::UNDEFINED)
+            .new_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+            .subresource_range(subresource_range)
+            .build();
+        unsafe {
+            device.cmd_pipeline_barrier(
+                copy_cmd_buf,
+                vk::PipelineStageFlags::TOP_OF_PIPE,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &[barrier],
+            )
+        };
 
-    pub fn get_descriptor_image_info(&self) -> Vec<vk::DescriptorImageInfo> {
-        self.textures
-            .iter()
-            .map(|tex| vk::DescriptorImageInfo {
-                image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
-                image_view: tex.image_view,
-                sampler: tex.sampler,
+        // Copy each face to its layer
+        let regions: Vec<vk::BufferImageCopy> = (0..6)
+            .map(|layer| vk::BufferImageCopy {
+                buffer_offset: (layer as usize * face_bytes) as u64,
+                buffer_row_length: 0,
+                buffer_image_height: 0,
+                image_offset: vk::Offset3D { x: 0, y: 0, z: 0 },
+                image_extent: vk::Extent3D {
+                    width: size,
+                    height: size,
+                    depth: 1,
+                },
+                image_subresource: vk::ImageSubresourceLayers {
+                    aspect_mask: vk::ImageAspectFlags::COLOR,
+                    mip_level: 0,
+                    base_array_layer: layer,
+                    layer_count: 1,
+                },
             })
-            .collect()
-    }
-
-    pub fn clean_up(&mut self, device: &Device, allocator: &mut Allocator) {
-        for texture in self.textures.iter_mut() {
-            texture.destroy(device, allocator);
+            .collect();
+        unsafe {
+            let int_buf = buffer.get_buffer();
+            device.cmd_copy_buffer_to_image(
+                copy_cmd_buf,
+                int_buf.buffer,
+                vk_image,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                &regions,
+            )
         }
+
+        // Transition every face for use as texture
+        let barrier = vk::ImageMemoryBarrier::builder()
+            .image(vk_image)
+            .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .dst_access_mask(vk::AccessFlags::SHADER_READ)
+            .old_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+            .new_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+            .subresource_range(subresource_range)
+            .build();
+        unsafe {
+            device.cmd_pipeline_barrier(
+                copy_cmd_buf,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::PipelineStageFlags::FRAGMENT_SHADER,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &[barrier],
+            )
+        };
+
+        // End command buffer
+        unsafe { device.end_command_buffer(copy_cmd_buf) }?;
+
+        // Prepare to submit command buffer
+        let submit_infos = [vk::SubmitInfo::builder()
+            .command_buffers(&[copy_cmd_buf])
+            .build()];
+        // Fence to wait for command buffer to finish
+        let fence = unsafe { device.create_fence(&vk::FenceCreateInfo::default(), None) }?;
+
+        // Submit the commands and wait for completion
+        unsafe { device.queue_submit(queue, &submit_infos, fence) }?;
+        unsafe { device.wait_for_fences(&[fence], true, std::u64::MAX) }?;
+
+        // Cleanup
+        unsafe { device.destroy_fence(fence, None) };
+        buffer.queue_free()?;
+        unsafe { device.free_command_buffers(command_pool, &[copy_cmd_buf]) };
+
+        // Done
+        Ok(Texture {
+            vk_image,
+            image_view,
+            sampler,
+            allocation: Some(allocation),
+            extent: vk::Extent2D {
+                width: size,
+                height: size,
+            },
+            format,
+            layer_count: 6,
+            storage_view: None,
+            usage: image_create_info.usage,
+            mip_levels: 1,
+        })
+    }
+
+    // An sRGB texture that compute shaders write through storage_view, a cubemap if
+    // cube is set. Its contents are undefined until then.
+    pub fn new_storage(
+        extent: vk::Extent2D,
+        cube: bool,
+        device: &Device,
+        allocator: &mut Allocator,
+    ) -> RendererResult<Self> {
+        let layer_count = if cube { 6 } else { 1 };
+        let format = vk::Format::R8G8B8A8_SRGB;
+        let storage_format = vk::Format::R8G8B8A8_UNORM;
+        let flags = if cube {
+            vk::ImageCreateFlags::MUTABLE_FORMAT | vk::ImageCreateFlags::CUBE_COMPATIBLE
+        } else {
+            vk::ImageCreateFlags::MUTABLE_FORMAT
+        };
+        let img_create_info = vk::ImageCreateInfo::builder()
+            .flags(flags)
+            .image_type(vk::ImageType::TYPE_2D)
+            .extent(vk::Extent3D {
+                width: extent.width,
+                height: extent.height,
+                depth: 1,
+            })
+            .mip_levels(1)
+            .array_layers(layer_count)
+            .format(format)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .usage(
+                vk::ImageUsageFlags::STORAGE
+                    | vk::ImageUsageFlags::SAMPLED
+                    | vk::ImageUsageFlags::TRANSFER_SRC,
+            );
+        let image = unsafe { device.create_image(&img_create_info, None) }?;
+
+        let reqs = unsafe { device.get_image_memory_requirements(image) };
+        let allocation = allocator.allocate(&AllocationCreateDesc {
+            name: "storage-texture",
+            requirements: reqs,
+            location: MemoryLocation::GpuOnly,
+            linear: false,
+            allocation_scheme: AllocationScheme::GpuAllocatorManaged,
+        })?;
+        unsafe { device.bind_image_memory(image, allocation.memory(), allocation.offset()) }?;
+
+        let subresource_range = vk::ImageSubresourceRange {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            level_count: 1,
+            layer_count,
+            ..Default::default()
+        };
+        let view_create_info = vk::ImageViewCreateInfo::builder()
+            .image(image)
+            .view_type(if cube {
+                vk::ImageViewType::CUBE
+            } else {
+                vk::ImageViewType::TYPE_2D
+            })
+            .format(format)
+            .subresource_range(subresource_range);
+        let image_view = unsafe { device.create_image_view(&view_create_info, None) }?;
+        let storage_view_create_info = vk::ImageViewCreateInfo::builder()
+            .image(image)
+            .view_type(if cube {
+                vk::ImageViewType::TYPE_2D_ARRAY
+            } else {
+                vk::ImageViewType::TYPE_2D
+            })
+            .format(storage_format)
+            .subresource_range(subresource_range);
+        let storage_view = unsafe { device.create_image_view(&storage_view_create_info, None) }?;
+
+        let sampler_info = vk::SamplerCreateInfo::builder()
+            .mag_filter(vk::Filter::LINEAR)
+            .min_filter(vk::Filter::LINEAR);
+        let sampler = unsafe { device.create_sampler(&sampler_info, None) }?;
+
+        Ok(Texture {
+            vk_image: image,
+            image_view,
+            sampler,
+            allocation: Some(allocation),
+            extent,
+            format,
+            layer_count,
+            storage_view: Some(storage_view),
+            usage: img_create_info.usage,
+            mip_levels: 1,
+        })
+    }
+
+    // A texture with mip_levels levels that compute shaders write level by level, through
+    // views of their own. Sampling it reads the first level, clamped to the edges. Its
+    // contents are undefined until then.
+    pub fn new_storage_chain(
+        extent: vk::Extent2D,
+        format: vk::Format,
+        mip_levels: u32,
+        device: &Device,
+        allocator: &mut Allocator,
+    ) -> RendererResult<Self> {
+        let img_create_info = vk::ImageCreateInfo::builder()
+            .image_type(vk::ImageType::TYPE_2D)
+            .extent(vk::Extent3D {
+                width: extent.width,
+                height: extent.height,
+                depth: 1,
+            })
+            .mip_levels(mip_levels)
+            .array_layers(1)
+            .format(format)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .usage(vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::SAMPLED);
+        let image = unsafe { device.create_image(&img_create_info, None) }?;
+
+        let reqs = unsafe { device.get_image_memory_requirements(image) };
+        let allocation = allocator.allocate(&AllocationCreateDesc {
+            name: "storage-chain",
+            requirements: reqs,
+            location: MemoryLocation::GpuOnly,
+            linear: false,
+            allocation_scheme: AllocationScheme::GpuAllocatorManaged,
+        })?;
+        unsafe { device.bind_image_memory(image, allocation.memory(), allocation.offset()) }?;
+
+        let view_create_info = vk::ImageViewCreateInfo::builder()
+            .image(image)
+            .view_type(vk::ImageViewType::TYPE_2D)
+            .format(format)
+            .subresource_range(vk::ImageSubresourceRange {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                level_count: 1,
+                layer_count: 1,
+                ..Default::default()
+            });
+        let image_view = unsafe { device.create_image_view(&view_create_info, None) }?;
+
+        let sampler_info = vk::SamplerCreateInfo::builder()
+            .mag_filter(vk::Filter::LINEAR)
+            .min_filter(vk::Filter::LINEAR)
+            .address_mode_u(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_EDGE);
+        let sampler = unsafe { device.create_sampler(&sampler_info, None) }?;
+
+        Ok(Texture {
+            vk_image: image,
+            image_view,
+            sampler,
+            allocation: Some(allocation),
+            extent,
+            format,
+            layer_count: 1,
+            storage_view: None,
+            usage: img_create_info.usage,
+            mip_levels,
+        })
+    }
+
+    // A texture that is rendered to, then sampled, clamped so filtering doesn't wrap
+    // around the edges. Its contents are undefined until then.
+    // With more than one queue family the image is shared between them CONCURRENTly
+    pub fn new_color_target(
+        extent: vk::Extent2D,
+        format: vk::Format,
+        queue_families: &[u32],
+        device: &Device,
+        allocator: &mut Allocator,
+    ) -> RendererResult<Self> {
+        let sharing_mode = if queue_families.len() > 1 {
+            vk::SharingMode::CONCURRENT
+        } else {
+            vk::SharingMode::EXCLUSIVE
+        };
+        let img_create_info = vk::ImageCreateInfo::builder()
+            .sharing_mode(sharing_mode)
+            .queue_family_indices(queue_families)
+            .image_type(vk::ImageType::TYPE_2D)
+            .extent(vk::Extent3D {
+                width: extent.width,
+                height: extent.height,
+                depth: 1,
+            })
+            .mip_levels(1)
+            .array_layers(1)
+            .format(format)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .usage(
+                vk::ImageUsageFlags::COLOR_ATTACHMENT
+                    | vk::ImageUsageFlags::SAMPLED
+                    | vk::ImageUsageFlags::TRANSFER_SRC,
+            );
+        let image = unsafe { device.create_image(&img_create_info, None) }?;
+
+        let reqs = unsafe { device.get_image_memory_requirements(image) };
+        let allocation = allocator.allocate(&AllocationCreateDesc {
+            name: "color-target",
+            requirements: reqs,
+            location: MemoryLocation::GpuOnly,
+            linear: false,
+            allocation_scheme: AllocationScheme::GpuAllocatorManaged,
+        })?;
+        unsafe { device.bind_image_memory(image, allocation.memory(), allocation.offset()) }?;
+
+        let view_create_info = vk::ImageViewCreateInfo::builder()
+            .image(image)
+            .view_type(vk::ImageViewType::TYPE_2D)
+            .format(format)
+            .subresource_range(vk::ImageSubresourceRange {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                level_count: 1,
+                layer_count: 1,
+                ..Default::default()
+            });
+        let image_view = unsafe { device.create_image_view(&view_create_info, None) }?;
+
+        let sampler_info = vk::SamplerCreateInfo::builder()
+            .mag_filter(vk::Filter::LINEAR)
+            .min_filter(vk::Filter::LINEAR)
+            .address_mode_u(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_EDGE);
+        let sampler = unsafe { device.create_sampler(&sampler_info, None) }?;
+
+        Ok(Texture {
+            vk_image: image,
+            image_view,
+            sampler,
+            allocation: Some(allocation),
+            extent,
+            format,
+            layer_count: 1,
+            storage_view: None,
+            usage: img_create_info.usage,
+            mip_levels: 1,
+        })
+    }
+
+    pub(crate) fn image(&self) -> vk::Image {
+        self.vk_image
+    }
+
+    pub(crate) fn storage_view(&self) -> Option<vk::ImageView> {
+        self.storage_view
+    }
+
+    pub fn extent(&self) -> vk::Extent2D {
+        self.extent
+    }
+
+    pub fn format(&self) -> vk::Format {
+        self.format
+    }
+
+    pub fn layer_count(&self) -> u32 {
+        self.layer_count
+    }
+
+    pub fn mip_levels(&self) -> u32 {
+        self.mip_levels
+    }
+
+    pub fn is_cubemap(&self) -> bool {
+        self.layer_count == 6
+    }
+
+    pub fn usage(&self) -> vk::ImageUsageFlags {
+        self.usage
+    }
+
+    // Textures that are rendered to or written by compute shaders are bound outside of
+    // material descriptor sets, so only the sampled 2D ones can be moved
+    #[cfg(feature = "defrag")]
+    pub(crate) fn is_movable(&self) -> bool {
+        self.storage_view.is_none()
+            && self.layer_count == 1
+            && self.usage.contains(vk::ImageUsageFlags::TRANSFER_SRC)
+            && !self.usage.contains(vk::ImageUsageFlags::COLOR_ATTACHMENT)
+    }
+
+    #[cfg(feature = "defrag")]
+    pub(crate) fn allocation_size(&self) -> u64 {
+        self.allocation
+            .as_ref()
+            .map_or(0, |allocation| allocation.size())
+    }
+
+    // Copies a movable texture into a new allocation, which the allocator puts into the
+    // first block with room for it. The copy takes over the sampler, this texture is only
+    // good for destroying afterwards. The GPU must not be using the texture.
+    #[cfg(feature = "defrag")]
+    pub(crate) fn relocate(
+        &mut self,
+        device: &Device,
+        allocator: &mut Allocator,
+        command_pool: vk::CommandPool,
+        queue: vk::Queue,
+    ) -> RendererResult<Texture> {
+        let img_create_info = vk::ImageCreateInfo::builder()
+            .image_type(vk::ImageType::TYPE_2D)
+            .extent(vk::Extent3D {
+                width: self.extent.width,
+                height: self.extent.height,
+                depth: 1,
+            })
+            .mip_levels(self.mip_levels)
+            .array_layers(1)
+            .format(self.format)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .usage(self.usage | vk::ImageUsageFlags::TRANSFER_DST);
+        let image = unsafe { device.create_image(&img_create_info, None) }?;
+
+        let reqs = unsafe { device.get_image_memory_requirements(image) };
+        let allocation = allocator.allocate(&AllocationCreateDesc {
+            name: "texture-relocated",
+            requirements: reqs,
+            location: MemoryLocation::GpuOnly,
+            linear: false,
+            allocation_scheme: AllocationScheme::GpuAllocatorManaged,
+        })?;
+        unsafe { device.bind_image_memory(image, allocation.memory(), allocation.offset()) }?;
+
+        let view_create_info = vk::ImageViewCreateInfo::builder()
+            .image(image)
+            .view_type(vk::ImageViewType::TYPE_2D)
+            .format(self.format)
+            .subresource_range(vk::ImageSubresourceRange {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                level_count: self.mip_levels,
+                layer_count: 1,
+                ..Default::default()
+            });
+        let image_view = unsafe { device.create_image_view(&view_create_info, None) }?;
+
+        let command_buf_allocate_info = vk::CommandBufferAllocateInfo::builder()
+            .command_pool(command_pool)
+            .command_buffer_count(1);
+        let copy_buf = unsafe { device.allocate_command_buffers(&command_buf_allocate_info) }?[0];
+        let cmd_begin_info = vk::CommandBufferBeginInfo::builder()
+            .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+        unsafe { device.begin_command_buffer(copy_buf, &cmd_begin_info) }?;
+
+        let subresource_range = vk::ImageSubresourceRange {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            base_mip_level: 0,
+            level_count: self.mip_levels,
+            base_array_layer: 0,
+            layer_count: 1,
+        };
+        // The old image becomes the source, the new one the destination
+        let barriers = [
+            vk::ImageMemoryBarrier::builder()
+                .image(self.vk_image)
+                .src_access_mask(vk::AccessFlags::SHADER_READ)
+                .dst_access_mask(vk::AccessFlags::TRANSFER_READ)
+                .old_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+                .new_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+                .subresource_range(subresource_range)
+                .build(),
+            vk::ImageMemoryBarrier::builder()
+                .image(image)
+                .src_access_mask(vk::AccessFlags::empty())
+                .dst_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+                .old_layout(vk::ImageLayout::UNDEFINED)
+                .new_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+                .subresource_range(subresource_range)
+                .build(),
+        ];
+        unsafe {
+            device.cmd_pipeline_barrier(
+                copy_buf,
+                vk::PipelineStageFlags::FRAGMENT_SHADER,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &barriers,
+            )
+        };
+
+        let regions = (0..self.mip_levels)
+            .map(|level| {
+                let subresource = vk::ImageSubresourceLayers {
+                    aspect_mask: vk::ImageAspectFlags::COLOR,
+                    mip_level: level,
+                    base_array_layer: 0,
+                    layer_count: 1,
+                };
+                vk::ImageCopy {
+                    src_subresource: subresource,
+                    src_offset: vk::Offset3D { x: 0, y: 0, z: 0 },
+                    dst_subresource: subresource,
+                    dst_offset: vk::Offset3D { x: 0, y: 0, z: 0 },
+                    extent: vk::Extent3D {
+                        width: (self.extent.width >> level).max(1),
+                        height: (self.extent.height >> level).max(1),
+                        depth: 1,
+                    },
+                }
+            })
+            .collect::<Vec<_>>();
+        unsafe {
+            device.cmd_copy_image(
+                copy_buf,
+                self.vk_image,
+                vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                image,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                &regions,
+            )
+        };
+
+        // The new image is sampled from now on, the old one is only destroyed
+        let barrier = vk::ImageMemoryBarrier::builder()
+            .image(image)
+            .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .dst_access_mask(vk::AccessFlags::SHADER_READ)
+            .old_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+            .new_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+            .subresource_range(subresource_range)
+            .build();
+        unsafe {
+            device.cmd_pipeline_barrier(
+                copy_buf,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::PipelineStageFlags::FRAGMENT_SHADER,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &[barrier],
+            )
+        };
+        unsafe { device.end_command_buffer(copy_buf) }?;
+
+        let submit_infos = [vk::SubmitInfo::builder()
+            .command_buffers(&[copy_buf])
+            .build()];
+        let fence = unsafe { device.create_fence(&vk::FenceCreateInfo::default(), None) }?;
+        unsafe { device.queue_submit(queue, &submit_infos, fence) }?;
+        unsafe { device.wait_for_fences(&[fence], true, std::u64::MAX) }?;
+        unsafe { device.destroy_fence(fence, None) };
+        unsafe { device.free_command_buffers(command_pool, &[copy_buf]) };
+
+        // Destroying a null sampler does nothing
+        let sampler = std::mem::replace(&mut self.sampler, vk::Sampler::null());
+        Ok(Texture {
+            vk_image: image,
+            image_view,
+            sampler,
+            allocation: Some(allocation),
+            extent: self.extent,
+            format: self.format,
+            layer_count: 1,
+            storage_view: None,
+            usage: img_create_info.usage,
+            mip_levels: self.mip_levels,
+        })
+    }
+
+    fn set_debug_name(&self, debug_namer: &DebugNamer, name: &str) {
+        debug_namer.name(self.vk_image, name);
+        debug_namer.name(self.image_view, &format!("{} view", name));
+        debug_namer.name(self.sampler, &format!("{} sampler", name));
+        if let Some(storage_view) = self.storage_view {
+            debug_namer.name(storage_view, &format!("{} storage view", name));
+        }
+    }
+
+    pub fn destroy(&mut self, device: &Device, allocator: &mut Allocator) {
+        allocator
+            .free(self.allocation.take().expect("Texture had no allocation!"))
+            .expect("Could not free texture allocation");
+        unsafe {
+            device.destroy_sampler(self.sampler, None);
+            if let Some(storage_view) = self.storage_view.take() {
+                device.destroy_image_view(storage_view, None);
+            }
+            device.destroy_image_view(self.image_view, None);
+            device.destroy_image(self.vk_image, None);
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct TextureStorage {
+    textures: HandleArray<Texture>,
+    // Canonical path of every texture loaded from a file
+    file_cache: HashMap<PathBuf, Handle<Texture>>,
+    fallback: Option<Handle<Texture>>,
+    // Ordered by the frame after which each texture can be destroyed
+    to_destroy: VecDeque<(Texture, u64)>,
+    events: FrameEvents,
+    debug_namer: Option<DebugNamer>,
+}
+
+impl TextureStorage {
+    pub fn allocation_hash(&self) -> u64 {
+        self.textures.allocation_hash()
+    }
+
+    pub(crate) fn take_frame_events(&mut self) -> FrameEvents {
+        std::mem::take(&mut self.events)
+    }
+
+    // Textures are named after their file, everything else is named by set_debug_name
+    pub(crate) fn set_debug_namer(&mut self, debug_namer: DebugNamer) {
+        self.debug_namer = Some(debug_namer);
+    }
+
+    pub(crate) fn set_debug_name(&self, handle: Handle<Texture>, name: &str) {
+        if let (Some(debug_namer), Some(texture)) = (&self.debug_namer, self.textures.get(handle)) {
+            texture.set_debug_name(debug_namer, name);
+        }
+    }
+
+    fn cache_key(path: &Path) -> PathBuf {
+        std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+    }
+
+    pub fn new_texture_from_file<P: AsRef<std::path::Path>>(
+        &mut self,
+        path: P,
+        device: &Device,
+        allocator: &mut Allocator,
+        buffer_manager: Arc<Mutex<BufferManager>>,
+        command_pool: vk::CommandPool,
+        queue: vk::Queue,
+    ) -> RendererResult<Handle<Texture>> {
+        self.new_texture_from_file_with_options(
+            path,
+            TextureCreateOptions::default(),
+            device,
+            allocator,
+            buffer_manager,
+            command_pool,
+            queue,
+        )
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn new_texture_from_file_with_options<P: AsRef<std::path::Path>>(
+        &mut self,
+        path: P,
+        options: TextureCreateOptions,
+        device: &Device,
+        allocator: &mut Allocator,
+        buffer_manager: Arc<Mutex<BufferManager>>,
+        command_pool: vk::CommandPool,
+        queue: vk::Queue,
+    ) -> RendererResult<Handle<Texture>> {
+        let key = Self::cache_key(path.as_ref());
+        let name = path
+            .as_ref()
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let texture = Texture::from_file(
+            path,
+            options,
+            device,
+            allocator,
+            buffer_manager,
+            command_pool,
+            queue,
+        )?;
+        self.events |= FrameEvents::TEXTURE_UPLOADED;
+        let handle = self.textures.insert(texture);
+        self.set_debug_name(handle, &name);
+        self.file_cache.entry(key).or_insert(handle);
+        Ok(handle)
+    }
+
+    // Like new_texture_from_file, but returns the existing texture if the file was loaded before
+    pub fn get_or_load_texture_from_file<P: AsRef<std::path::Path>>(
+        &mut self,
+        path: P,
+        device: &Device,
+        allocator: &mut Allocator,
+        buffer_manager: Arc<Mutex<BufferManager>>,
+        command_pool: vk::CommandPool,
+        queue: vk::Queue,
+    ) -> RendererResult<Handle<Texture>> {
+        if let Some(handle) = self.file_cache.get(&Self::cache_key(path.as_ref())) {
+            return Ok(*handle);
+        }
+        self.new_texture_from_file(path, device, allocator, buffer_manager, command_pool, queue)
+    }
+
+    // The file a texture was loaded from, None for generated textures
+    pub fn get_texture_path(&self, handle: Handle<Texture>) -> Option<&Path> {
+        self.file_cache
+            .iter()
+            .find(|(_, h)| **h == handle)
+            .map(|(path, _)| path.as_path())
+    }
+
+    pub fn find_texture_by_path(&self, path: &Path) -> Option<Handle<Texture>> {
+        self.file_cache.get(&Self::cache_key(path)).copied()
+    }
+
+    pub fn file_paths(&self) -> impl Iterator<Item = &Path> {
+        self.file_cache.keys().map(|path| path.as_path())
+    }
+
+    // Swaps a new image in under an existing handle, for reloads. The old one is
+    // destroyed once the GPU is done with last_submitted_frame, and the descriptor sets
+    // pointing at it have to be rewritten by the caller.
+    pub(crate) fn replace_image(
+        &mut self,
+        handle: Handle<Texture>,
+        texture: Texture,
+        last_submitted_frame: u64,
+    ) -> RendererResult<()> {
+        let slot = self
+            .textures
+            .get_mut(handle)
+            .ok_or::<RendererError>(InvalidHandle.into())?;
+        let old = std::mem::replace(slot, texture);
+        self.to_destroy.push_back((old, last_submitted_frame));
+        self.events |= FrameEvents::TEXTURE_UPLOADED;
+        if let Some(name) = self
+            .get_texture_path(handle)
+            .and_then(|path| path.file_stem())
+            .map(|stem| stem.to_string_lossy().into_owned())
+        {
+            self.set_debug_name(handle, &name);
+        }
+        Ok(())
+    }
+
+    // A 1x1 white texture, used in place of textures that could not be found
+    pub fn get_or_create_fallback_texture(
+        &mut self,
+        device: &Device,
+        allocator: &mut Allocator,
+        buffer_manager: Arc<Mutex<BufferManager>>,
+        command_pool: &vk::CommandPool,
+        queue: &vk::Queue,
+    ) -> RendererResult<Handle<Texture>> {
+        if let Some(handle) = self.fallback {
+            return Ok(handle);
+        }
+        let handle = self.new_texture_from_u8(
+            &[255, 255, 255, 255],
+            1,
+            1,
+            vk::Format::R8G8B8A8_UNORM,
+            device,
+            allocator,
+            buffer_manager,
+            command_pool,
+            queue,
+        )?;
+        self.fallback = Some(handle);
+        self.set_debug_name(handle, "fallback white");
+        Ok(handle)
+    }
+
+    pub fn new_texture_from_u8(
+        &mut self,
+        data: &[u8],
+        width: u32,
+        height: u32,
+        format: vk::Format,
+        device: &Device,
+        allocator: &mut Allocator,
+        buffer_manager: Arc<Mutex<BufferManager>>,
+        command_pool: &vk::CommandPool,
+        queue: &vk::Queue,
+    ) -> RendererResult<Handle<Texture>> {
+        let texture = Texture::from_u8s(
+            data,
+            width,
+            height,
+            format,
+            device,
+            allocator,
+            buffer_manager,
+            command_pool,
+            queue,
+        )?;
+        self.events |= FrameEvents::TEXTURE_UPLOADED;
+        let handle = self.textures.insert(texture);
+        self.set_debug_name(handle, &format!("texture {}x{}", width, height));
+        Ok(handle)
+    }
+
+    // Not cached by path, reload_texture can't bring back a cubemap
+    pub fn new_cubemap_from_files<P: AsRef<std::path::Path>>(
+        &mut self,
+        paths: &[P; 6],
+        device: &Device,
+        allocator: &mut Allocator,
+        buffer_manager: Arc<Mutex<BufferManager>>,
+        command_pool: vk::CommandPool,
+        queue: vk::Queue,
+    ) -> RendererResult<Handle<Texture>> {
+        let texture = Texture::cubemap_from_files(
+            paths,
+            device,
+            allocator,
+            buffer_manager,
+            command_pool,
+            queue,
+        )?;
+        self.events |= FrameEvents::TEXTURE_UPLOADED;
+        let handle = self.textures.insert(texture);
+        let name = paths[0]
+            .as_ref()
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        self.set_debug_name(handle, &format!("cubemap {}", name));
+        Ok(handle)
+    }
+
+    pub fn new_storage_texture(
+        &mut self,
+        extent: vk::Extent2D,
+        cube: bool,
+        device: &Device,
+        allocator: &mut Allocator,
+    ) -> RendererResult<Handle<Texture>> {
+        let texture = Texture::new_storage(extent, cube, device, allocator)?;
+        self.events |= FrameEvents::TEXTURE_UPLOADED;
+        let handle = self.textures.insert(texture);
+        let kind = if cube { "cubemap" } else { "storage texture" };
+        self.set_debug_name(
+            handle,
+            &format!("{} {}x{}", kind, extent.width, extent.height),
+        );
+        Ok(handle)
+    }
+
+    pub fn new_storage_chain(
+        &mut self,
+        extent: vk::Extent2D,
+        format: vk::Format,
+        mip_levels: u32,
+        device: &Device,
+        allocator: &mut Allocator,
+    ) -> RendererResult<Handle<Texture>> {
+        let texture = Texture::new_storage_chain(extent, format, mip_levels, device, allocator)?;
+        let handle = self.textures.insert(texture);
+        self.set_debug_name(
+            handle,
+            &format!(
+                "storage chain {}x{}, {} levels",
+                extent.width, extent.height, mip_levels
+            ),
+        );
+        Ok(handle)
+    }
+
+    pub fn new_color_target(
+        &mut self,
+        extent: vk::Extent2D,
+        format: vk::Format,
+        queue_families: &[u32],
+        device: &Device,
+        allocator: &mut Allocator,
+    ) -> RendererResult<Handle<Texture>> {
+        let texture = Texture::new_color_target(extent, format, queue_families, device, allocator)?;
+        let handle = self.textures.insert(texture);
+        self.set_debug_name(
+            handle,
+            &format!("color target {}x{}", extent.width, extent.height),
+        );
+        Ok(handle)
+    }
+
+    // The caller has to make sure the texture is not used by any in-flight frame
+    pub fn remove_texture(
+        &mut self,
+        handle: Handle<Texture>,
+        device: &Device,
+        allocator: &mut Allocator,
+    ) -> RendererResult<()> {
+        let mut texture = self.take_texture(handle)?;
+        texture.destroy(device, allocator);
+        Ok(())
+    }
+
+    // Like remove_texture, but the texture is only destroyed once the GPU is done with
+    // last_submitted_frame
+    pub(crate) fn queue_destroy(
+        &mut self,
+        handle: Handle<Texture>,
+        last_submitted_frame: u64,
+    ) -> RendererResult<()> {
+        let texture = self.take_texture(handle)?;
+        self.to_destroy.push_back((texture, last_submitted_frame));
+        Ok(())
+    }
+
+    pub(crate) fn destroy_queued(
+        &mut self,
+        device: &Device,
+        allocator: &mut Allocator,
+        completed_frame: u64,
+    ) {
+        for mut texture in retired(&mut self.to_destroy, completed_frame) {
+            texture.destroy(device, allocator);
+        }
+    }
+
+    fn take_texture(&mut self, handle: Handle<Texture>) -> RendererResult<Texture> {
+        let texture = self.textures.remove(handle)?;
+        self.file_cache.retain(|_, h| *h != handle);
+        if self.fallback == Some(handle) {
+            self.fallback = None;
+        }
+        Ok(texture)
+    }
+
+    pub fn get_number_of_textures(&self) -> usize {
+        self.textures.len()
+    }
+
+    // Removed or replaced textures the GPU may still be using
+    pub fn get_number_of_queued_destructions(&self) -> usize {
+        self.to_destroy.len()
+    }
+
+    pub fn get_texture(&self, handle: Handle<Texture>) -> Option<&Texture> {
+        self.textures.get(handle)
+    }
+
+    pub fn get_texture_mut(&mut self, handle: Handle<Texture>) -> Option<&mut Texture> {
+        self.textures.get_mut(handle)
+    }
+
+    // Every texture Texture::is_movable, with the size of its allocation
+    #[cfg(feature = "defrag")]
+    pub(crate) fn movable_textures(&self) -> Vec<(Handle<Texture>, u64)> {
+        self.textures
+            .iter_with_handles()
+            .filter(|(_, texture)| texture.is_movable())
+            .map(|(handle, texture)| (handle, texture.allocation_size()))
+            .collect()
+    }
+
+    pub fn get_descriptor_image_info(&self) -> Vec<vk::DescriptorImageInfo> {
+        self.textures
+            .iter()
+            .map(|tex| vk::DescriptorImageInfo {
+                image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                image_view: tex.image_view,
+                sampler: tex.sampler,
+            })
+            .collect()
+    }
+
+    pub fn clean_up(&mut self, device: &Device, allocator: &mut Allocator) {
+        for texture in self.textures.iter_mut() {
+            texture.destroy(device, allocator);
+        }
+        for (mut texture, _) in self.to_destroy.drain(..) {
+            texture.destroy(device, allocator);
+        }
+        self.file_cache.clear();
+        self.fallback = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spellings_of_one_file_share_a_cache_key() {
+        let dir = std::env::temp_dir().join(format!("texture_cache_{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("materials")).unwrap();
+        std::fs::write(dir.join("albedo.png"), []).unwrap();
+        let direct = TextureStorage::cache_key(&dir.join("albedo.png"));
+        let through_sibling = TextureStorage::cache_key(&dir.join("materials/../albedo.png"));
+        let other = TextureStorage::cache_key(&dir.join("materials"));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(direct, through_sibling);
+        assert_ne!(direct, other);
     }
 }
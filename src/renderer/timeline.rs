@@ -0,0 +1,285 @@
+use std::collections::VecDeque;
+
+use ash::vk;
+
+use super::{debug_namer::DebugNamer, RendererResult};
+
+// Every submitted frame signals the next value of a counter, and anything waiting on
+// the GPU is expressed as "wait until the counter reaches N". With timeline semaphores
+// the counter is a single semaphore, otherwise there is one fence per frame in flight.
+pub(crate) struct FrameTimeline {
+    device: ash::Device,
+    semaphore: Option<vk::Semaphore>,
+    fences: Vec<vk::Fence>,
+    // The value of the last submission of each frame in flight and swapchain image
+    frame_values: Vec<u64>,
+    image_values: Vec<u64>,
+    last_submitted: u64,
+    completed: u64,
+}
+
+impl FrameTimeline {
+    pub fn new(
+        device: &ash::Device,
+        frames_in_flight: usize,
+        image_count: usize,
+        use_timeline_semaphore: bool,
+    ) -> RendererResult<Self> {
+        let (semaphore, fences) = if use_timeline_semaphore {
+            let mut type_info = vk::SemaphoreTypeCreateInfo::builder()
+                .semaphore_type(vk::SemaphoreType::TIMELINE)
+                .initial_value(0);
+            let semaphore_info = vk::SemaphoreCreateInfo::builder().push_next(&mut type_info);
+            let semaphore = unsafe { device.create_semaphore(&semaphore_info, None)? };
+            (Some(semaphore), vec![])
+        } else {
+            let fence_info = vk::FenceCreateInfo::builder().flags(vk::FenceCreateFlags::SIGNALED);
+            let fences = (0..frames_in_flight)
+                .map(|_| unsafe { device.create_fence(&fence_info, None) })
+                .collect::<Result<Vec<_>, _>>()?;
+            (None, fences)
+        };
+        Ok(Self {
+            device: device.clone(),
+            semaphore,
+            fences,
+            frame_values: vec![0; frames_in_flight],
+            image_values: vec![0; image_count],
+            last_submitted: 0,
+            completed: 0,
+        })
+    }
+
+    pub fn set_debug_names(&self, debug_namer: &DebugNamer) {
+        if let Some(semaphore) = self.semaphore {
+            debug_namer.name(semaphore, "frame timeline");
+        }
+        for (i, fence) in self.fences.iter().enumerate() {
+            debug_namer.name(*fence, &format!("frame {} fence", i));
+        }
+    }
+
+    // The swapchain came back with a different number of images, nothing may be in flight
+    pub fn set_image_count(&mut self, image_count: usize) {
+        self.image_values = vec![0; image_count];
+    }
+
+    pub fn uses_timeline_semaphore(&self) -> bool {
+        self.semaphore.is_some()
+    }
+
+    pub fn last_submitted(&self) -> u64 {
+        self.last_submitted
+    }
+
+    // Checks how far the GPU is without blocking
+    pub fn completed(&mut self) -> RendererResult<u64> {
+        if let Some(semaphore) = self.semaphore {
+            self.completed = unsafe { self.device.get_semaphore_counter_value(semaphore)? };
+        } else {
+            let device = &self.device;
+            let fences = &self.fences;
+            self.completed = highest_signalled(self.completed, &self.frame_values, |frame| {
+                Ok(unsafe { device.get_fence_status(fences[frame])? })
+            })?;
+        }
+        Ok(self.completed)
+    }
+
+    pub fn wait_for(&mut self, value: u64) -> RendererResult<()> {
+        if value <= self.completed {
+            return Ok(());
+        }
+        if let Some(semaphore) = self.semaphore {
+            let semaphores = [semaphore];
+            let values = [value];
+            let wait_info = vk::SemaphoreWaitInfo::builder()
+                .semaphores(&semaphores)
+                .values(&values);
+            unsafe { self.device.wait_semaphores(&wait_info, std::u64::MAX)? };
+        } else {
+            let fences = frames_to_wait_for(self.completed, &self.frame_values, value)
+                .into_iter()
+                .map(|frame| self.fences[frame])
+                .collect::<Vec<_>>();
+            if !fences.is_empty() {
+                unsafe { self.device.wait_for_fences(&fences, true, std::u64::MAX)? };
+            }
+        }
+        self.completed = value;
+        Ok(())
+    }
+
+    // Before reusing the semaphores of a frame in flight
+    pub fn wait_for_frame(&mut self, frame: usize) -> RendererResult<()> {
+        self.wait_for(self.frame_values[frame])
+    }
+
+    // Before recording into the command buffer of a swapchain image
+    pub fn wait_for_image(&mut self, image: usize) -> RendererResult<()> {
+        self.wait_for(self.image_values[image])
+    }
+
+    pub fn wait_idle(&mut self) -> RendererResult<()> {
+        self.wait_for(self.last_submitted)
+    }
+
+    // Submits the command buffers of a frame, signalling the next value once they are done.
+    // Returns that value.
+    pub fn submit(
+        &mut self,
+        queue: vk::Queue,
+        frame: usize,
+        image: usize,
+        submit_info: vk::SubmitInfoBuilder,
+        signal_semaphores: &[vk::Semaphore],
+    ) -> RendererResult<u64> {
+        let value = self.last_submitted + 1;
+        if let Some(semaphore) = self.semaphore {
+            let mut all_signal_semaphores = signal_semaphores.to_vec();
+            all_signal_semaphores.push(semaphore);
+            // Binary semaphores ignore their value
+            let mut signal_values = vec![0; signal_semaphores.len()];
+            signal_values.push(value);
+            let mut timeline_info =
+                vk::TimelineSemaphoreSubmitInfo::builder().signal_semaphore_values(&signal_values);
+            let submit_infos = [submit_info
+                .signal_semaphores(&all_signal_semaphores)
+                .push_next(&mut timeline_info)
+                .build()];
+            unsafe {
+                self.device
+                    .queue_submit(queue, &submit_infos, vk::Fence::null())?
+            };
+        } else {
+            let fence = self.fences[frame];
+            let submit_infos = [submit_info.signal_semaphores(signal_semaphores).build()];
+            unsafe {
+                self.device.reset_fences(&[fence])?;
+                self.device.queue_submit(queue, &submit_infos, fence)?;
+            }
+        }
+        self.last_submitted = value;
+        self.frame_values[frame] = value;
+        self.image_values[image] = value;
+        Ok(value)
+    }
+
+    // Has to be called before the device is destroyed
+    pub fn destroy(&mut self) {
+        unsafe {
+            if let Some(semaphore) = self.semaphore.take() {
+                self.device.destroy_semaphore(semaphore, None);
+            }
+            for fence in self.fences.drain(..) {
+                self.device.destroy_fence(fence, None);
+            }
+        }
+    }
+}
+
+// Without timeline semaphores: submissions to the queue finish in order, so the highest
+// value of a signalled fence is how far the GPU is
+fn highest_signalled<F>(
+    completed: u64,
+    frame_values: &[u64],
+    mut signalled: F,
+) -> RendererResult<u64>
+where
+    F: FnMut(usize) -> RendererResult<bool>,
+{
+    let mut highest = completed;
+    for (frame, value) in frame_values.iter().enumerate() {
+        if *value > highest && signalled(frame)? {
+            highest = *value;
+        }
+    }
+    Ok(highest)
+}
+
+// Without timeline semaphores: the frames whose fences have to be waited for to reach
+// value. A frame slot is only reused after waiting for it, so every value that isn't
+// completed yet still has its fence.
+fn frames_to_wait_for(completed: u64, frame_values: &[u64], value: u64) -> Vec<usize> {
+    frame_values
+        .iter()
+        .enumerate()
+        .filter(|(_, v)| **v > completed && **v <= value)
+        .map(|(frame, _)| frame)
+        .collect()
+}
+
+// Takes everything queued for a value at or before the completed one off the front of
+// the queue, which is in submission order
+pub(crate) fn retired<T>(queue: &mut VecDeque<(T, u64)>, completed: u64) -> Vec<T> {
+    let count = queue
+        .iter()
+        .take_while(|(_, value)| *value <= completed)
+        .count();
+    queue.drain(..count).map(|(item, _)| item).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The fences of 3 frames in flight, after submitting values 4, 5 and 6 to them
+    const FRAME_VALUES: [u64; 3] = [4, 5, 6];
+
+    #[test]
+    fn the_highest_signalled_fence_is_the_completed_value() {
+        let signalled = |count: usize| {
+            move |frame: usize| Ok(FRAME_VALUES[frame] <= FRAME_VALUES[0] + count as u64 - 1)
+        };
+        assert_eq!(
+            highest_signalled(3, &FRAME_VALUES, |_| Ok(false)).unwrap(),
+            3
+        );
+        assert_eq!(
+            highest_signalled(3, &FRAME_VALUES, signalled(1)).unwrap(),
+            4
+        );
+        assert_eq!(
+            highest_signalled(3, &FRAME_VALUES, signalled(3)).unwrap(),
+            6
+        );
+        // Fences at or below the completed value aren't checked again
+        let mut checked = vec![];
+        highest_signalled(5, &FRAME_VALUES, |frame| {
+            checked.push(frame);
+            Ok(false)
+        })
+        .unwrap();
+        assert_eq!(checked, [2]);
+    }
+
+    #[test]
+    fn waiting_only_takes_the_fences_up_to_the_value() {
+        assert_eq!(frames_to_wait_for(3, &FRAME_VALUES, 5), [0, 1]);
+        assert_eq!(frames_to_wait_for(4, &FRAME_VALUES, 6), [1, 2]);
+        assert!(frames_to_wait_for(6, &FRAME_VALUES, 6).is_empty());
+    }
+
+    // Resources are queued with the last submitted value when they are retired, and the
+    // mocked timeline completes one frame at a time
+    #[test]
+    fn resources_retire_once_the_timeline_reaches_their_value() {
+        let mut queue = VecDeque::from([("mesh", 2), ("texture", 2), ("buffer", 3)]);
+        assert!(retired(&mut queue, 1).is_empty());
+        assert_eq!(retired(&mut queue, 2), ["mesh", "texture"]);
+        queue.push_back(("shader", 5));
+        assert!(retired(&mut queue, 2).is_empty());
+        assert_eq!(retired(&mut queue, 4), ["buffer"]);
+        assert_eq!(retired(&mut queue, 7), ["shader"]);
+        assert!(queue.is_empty());
+    }
+
+    // Values only grow, so something queued behind a later value waits for it even if
+    // its own value is done
+    #[test]
+    fn retirement_keeps_the_submission_order() {
+        let mut queue = VecDeque::from([("later", 5), ("earlier", 3)]);
+        assert!(retired(&mut queue, 4).is_empty());
+        assert_eq!(retired(&mut queue, 5), ["later", "earlier"]);
+    }
+}
@@ -0,0 +1,120 @@
+use nalgebra_glm as glm;
+
+use super::{
+    camera::Camera, color::Color, culling::Aabb, material::Material, mesh::Mesh,
+    scene::SceneObject, utils::Handle,
+};
+
+// What Renderer::capture_turntable renders
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TurntableSubject {
+    // With its mesh, material and transform, but without its children
+    Object(Handle<SceneObject>),
+    // At the origin
+    Mesh(Handle<Mesh>, Handle<Material>),
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TurntableLighting {
+    // The fixed three point lighting of the material previews
+    #[default]
+    Preview,
+    // The lights of the scene as they were last updated
+    Scene,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TurntableConfig {
+    pub frames: u32,
+    // Width and height of every frame in pixels
+    pub size: u32,
+    pub background: Color,
+    pub lighting: TurntableLighting,
+    // How far above the subject the camera looks down from, in radians
+    pub elevation: f32,
+    pub fovy: f32,
+    // Space left around the bounds of the subject, 1 fills the frame with them
+    pub margin: f32,
+}
+
+impl Default for TurntableConfig {
+    fn default() -> Self {
+        Self {
+            frames: 36,
+            size: 256,
+            background: Color::from_srgb(0.48, 0.48, 0.48),
+            lighting: TurntableLighting::default(),
+            elevation: 0.35,
+            fovy: std::f32::consts::FRAC_PI_4,
+            margin: 1.1,
+        }
+    }
+}
+
+// The camera of frame out of config.frames, turning 360 / frames degrees further around
+// the vertical axis through the center of bounds for every frame. Far enough away for the
+// sphere around bounds to fit into the view from every side.
+pub fn turntable_camera(bounds: &Aabb, frame: u32, config: &TurntableConfig) -> Camera {
+    let center = bounds.center();
+    let radius = glm::length(&bounds.half_extents()).max(1e-3) * config.margin.max(1.0);
+    let distance = radius / (0.5 * config.fovy).sin();
+    let angle = 2.0 * std::f32::consts::PI * frame as f32 / config.frames.max(1) as f32;
+    // y points down
+    let offset = glm::vec3(
+        angle.sin() * config.elevation.cos(),
+        -config.elevation.sin(),
+        -angle.cos() * config.elevation.cos(),
+    );
+    Camera::builder()
+        .position(center + offset * distance)
+        .view_direction(-offset)
+        .down_direction(glm::vec3(0.0, 1.0, 0.0))
+        .fovy(config.fovy)
+        .aspect(1.0)
+        .near((distance - radius).max(distance * 0.01))
+        .far(distance + radius)
+        .build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bounds() -> Aabb {
+        Aabb {
+            min: glm::vec3(-1.0, -2.0, -1.0),
+            max: glm::vec3(3.0, 2.0, 1.0),
+        }
+    }
+
+    #[test]
+    fn a_full_rotation_returns_to_the_first_frame() {
+        let config = TurntableConfig {
+            frames: 8,
+            ..Default::default()
+        };
+        let first = turntable_camera(&bounds(), 0, &config);
+        let full = turntable_camera(&bounds(), 8, &config);
+        assert!(glm::distance(&first.get_position(), &full.get_position()) < 1e-4);
+        assert!(glm::distance(&first.get_view_direction(), &full.get_view_direction()) < 1e-4);
+    }
+
+    #[test]
+    fn frames_circle_the_center_at_the_same_distance() {
+        let config = TurntableConfig {
+            frames: 8,
+            ..Default::default()
+        };
+        let center = bounds().center();
+        let first = turntable_camera(&bounds(), 0, &config);
+        let distance = glm::distance(&first.get_position(), &center);
+        for frame in 1..8 {
+            let camera = turntable_camera(&bounds(), frame, &config);
+            let previous = turntable_camera(&bounds(), frame - 1, &config);
+            assert!((glm::distance(&camera.get_position(), &center) - distance).abs() < 1e-4);
+            assert!(glm::distance(&camera.get_position(), &previous.get_position()) > 0.1);
+            let to_center = glm::normalize(&(center - camera.get_position()));
+            assert!(glm::distance(&camera.get_view_direction(), &to_center) < 1e-4);
+        }
+    }
+}
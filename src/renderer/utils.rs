@@ -1,6 +1,9 @@
 mod handle_array;
 mod window;
 
+pub(crate) use handle_array::{fnv1a, fnv1a_start};
 pub use handle_array::{Handle, HandleArray};
 
-pub use window::{create_render_window, InternalWindow};
+#[cfg(feature = "winit")]
+pub use window::create_render_window;
+pub use window::InternalWindow;
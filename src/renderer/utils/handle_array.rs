@@ -6,13 +6,35 @@ use std::num::NonZeroUsize;
 use super::super::error::InvalidHandle;
 use super::super::RendererResult;
 
-pub struct Handle<T>(NonZeroUsize, PhantomData<*const T>);
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+// 64 bit FNV-1a, used to build hashes that are stable across processes
+pub(crate) fn fnv1a(mut hash: u64, bytes: &[u8]) -> u64 {
+    for b in bytes {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    hash
+}
+
+pub(crate) fn fnv1a_start() -> u64 {
+    FNV_OFFSET_BASIS
+}
+
+// fn() -> T keeps handles Send and Sync whatever T is, they don't own a T. Ids are never
+// handed out twice by the same generation of a HandleArray, clearing it starts a new
+// generation, so a handle of a removed or cleared element never refers to a new one.
+pub struct Handle<T>(NonZeroUsize, u32, PhantomData<fn() -> T>);
 
 // I Feel like there has got to be a better way to do this than
 // manually implementing all of these traits
 impl<T> fmt::Debug for Handle<T> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_tuple("Handle").field(&self.0).finish()
+        f.debug_tuple("Handle")
+            .field(&self.0)
+            .field(&self.1)
+            .finish()
     }
 }
 
@@ -24,7 +46,7 @@ impl<T: Ord> Ord for Handle<T> {
 
 impl<T> PartialOrd for Handle<T> {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        self.0.partial_cmp(&other.0)
+        (self.1, self.0).partial_cmp(&(other.1, other.0))
     }
 }
 
@@ -34,19 +56,43 @@ impl<T> Eq for Handle<T> {}
 
 impl<T> PartialEq for Handle<T> {
     fn eq(&self, other: &Self) -> bool {
-        self.0 == other.0
+        self.0 == other.0 && self.1 == other.1
     }
 }
 
 impl<T> std::hash::Hash for Handle<T> {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
         self.0.hash(state);
+        self.1.hash(state);
     }
 }
 
 impl<T> Clone for Handle<T> {
     fn clone(&self) -> Self {
-        Self(self.0, PhantomData)
+        Self(self.0, self.1, PhantomData)
+    }
+}
+
+impl<T> Handle<T> {
+    // Never 0, e.g. to tell objects apart from the background in an object id image
+    pub fn id(&self) -> usize {
+        self.0.get()
+    }
+
+    // Of the HandleArray that handed it out, bumped every time the array is cleared
+    pub fn generation(&self) -> u32 {
+        self.1
+    }
+
+    // For tests of code that only compares handles, like a HandleArray's first
+    // generation would hand out
+    #[cfg(test)]
+    pub(crate) fn for_test(id: usize) -> Self {
+        Self(
+            NonZeroUsize::new(id).expect("Handle ids start at 1"),
+            0,
+            PhantomData,
+        )
     }
 }
 
@@ -55,6 +101,10 @@ pub struct HandleArray<T> {
     handles: Vec<Handle<T>>,
     data: Vec<T>,
     next_handle: Handle<T>,
+    // Handles of earlier generations are stale, whatever their id
+    generation: u32,
+    // Rolling hash of every (type, handle) pair handed out
+    allocation_hash: u64,
 }
 
 impl<T: fmt::Debug> fmt::Debug for HandleArray<T> {
@@ -64,6 +114,8 @@ impl<T: fmt::Debug> fmt::Debug for HandleArray<T> {
             .field("handles", &self.handles)
             .field("data", &self.data)
             .field("next_handle", &self.next_handle)
+            .field("generation", &self.generation)
+            .field("allocation_hash", &self.allocation_hash)
             .finish()
     }
 }
@@ -74,7 +126,9 @@ impl<T> Default for HandleArray<T> {
             handle_to_index: HashMap::new(),
             handles: Vec::new(),
             data: Vec::new(),
-            next_handle: Handle(NonZeroUsize::new(1).expect("1 == 0??"), PhantomData),
+            next_handle: Handle(NonZeroUsize::new(1).expect("1 == 0??"), 0, PhantomData),
+            generation: 0,
+            allocation_hash: fnv1a_start(),
         }
     }
 }
@@ -96,11 +150,25 @@ impl<T> HandleArray<T> {
         self.handle_to_index.clear();
         self.handles.clear();
         self.data.clear();
-        self.next_handle = Handle(NonZeroUsize::new(1).expect("1 == 0 ??"), PhantomData);
+        // The ids start over, the handles handed out so far must not find the new elements
+        self.generation = self
+            .generation
+            .checked_add(1)
+            .expect("Generation count wrapped!");
+        self.next_handle = Handle(
+            NonZeroUsize::new(1).expect("1 == 0 ??"),
+            self.generation,
+            PhantomData,
+        );
+        self.allocation_hash = fnv1a_start();
+    }
+
+    pub fn allocation_hash(&self) -> u64 {
+        self.allocation_hash
     }
 
     pub fn get(&self, handle: Handle<T>) -> Option<&T> {
-        if let Some(&index) = self.handle_to_index.get(&handle) {
+        if let Some(index) = self.get_index(handle) {
             self.data.get(index)
         } else {
             None
@@ -108,14 +176,18 @@ impl<T> HandleArray<T> {
     }
 
     pub fn get_mut(&mut self, handle: Handle<T>) -> Option<&mut T> {
-        if let Some(&index) = self.handle_to_index.get(&handle) {
+        if let Some(index) = self.get_index(handle) {
             self.data.get_mut(index)
         } else {
             None
         }
     }
 
+    // None for stale handles, of removed elements or of an earlier generation
     pub fn get_index(&self, handle: Handle<T>) -> Option<usize> {
+        if handle.1 != self.generation {
+            return None;
+        }
         self.handle_to_index.get(&handle).copied()
     }
 
@@ -131,10 +203,7 @@ impl<T> HandleArray<T> {
         if handle1 == handle2 {
             return Ok(());
         }
-        if let (Some(&index1), Some(&index2)) = (
-            self.handle_to_index.get(&handle1),
-            self.handle_to_index.get(&handle2),
-        ) {
+        if let (Some(index1), Some(index2)) = (self.get_index(handle1), self.get_index(handle2)) {
             self.handles.swap(index1, index2);
             self.data.swap(index1, index2);
             self.handle_to_index.insert(handle2, index1);
@@ -164,6 +233,8 @@ impl<T> HandleArray<T> {
             .0
             .checked_add(1)
             .expect("Handle count wrapped!");
+        self.allocation_hash = fnv1a(self.allocation_hash, std::any::type_name::<T>().as_bytes());
+        self.allocation_hash = fnv1a(self.allocation_hash, &handle.0.get().to_le_bytes());
         let index = self.data.len();
         self.data.push(element);
         self.handles.push(handle);
@@ -172,7 +243,7 @@ impl<T> HandleArray<T> {
     }
 
     pub fn remove(&mut self, handle: Handle<T>) -> RendererResult<T> {
-        if let Some(&index) = self.handle_to_index.get(&handle) {
+        if let Some(index) = self.get_index(handle) {
             self.swap_by_index(index, self.data.len() - 1);
             self.handles.pop();
             self.handle_to_index.remove(&handle);
@@ -183,6 +254,14 @@ impl<T> HandleArray<T> {
         }
     }
 
+    pub fn iter_with_handles(&self) -> impl Iterator<Item = (Handle<T>, &T)> {
+        self.handles.iter().copied().zip(self.data.iter())
+    }
+
+    pub fn iter_mut_with_handles(&mut self) -> impl Iterator<Item = (Handle<T>, &mut T)> {
+        self.handles.iter().copied().zip(self.data.iter_mut())
+    }
+
     pub fn iter(&self) -> std::slice::Iter<'_, T> {
         self.data.iter()
     }
@@ -191,3 +270,48 @@ impl<T> HandleArray<T> {
         self.data.iter_mut()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Mesh;
+    struct Texture;
+
+    // Inserts a, b and c, removes b and inserts d, like a scene being built
+    fn build(array: &mut HandleArray<Mesh>) -> Vec<Handle<Mesh>> {
+        let mut handles: Vec<_> = (0..3).map(|_| array.insert(Mesh)).collect();
+        array.remove(handles[1]).unwrap();
+        handles.push(array.insert(Mesh));
+        handles
+    }
+
+    #[test]
+    fn same_operations_give_same_handles_and_hash() {
+        let (mut first, mut second) = (HandleArray::new(), HandleArray::new());
+        assert_eq!(build(&mut first), build(&mut second));
+        assert_eq!(first.allocation_hash(), second.allocation_hash());
+        assert_ne!(first.allocation_hash(), fnv1a_start());
+    }
+
+    #[test]
+    fn hash_depends_on_the_type_and_the_count() {
+        let mut meshes = HandleArray::new();
+        let mut textures = HandleArray::new();
+        meshes.insert(Mesh);
+        textures.insert(Texture);
+        assert_ne!(meshes.allocation_hash(), textures.allocation_hash());
+        let one = meshes.allocation_hash();
+        meshes.insert(Mesh);
+        assert_ne!(meshes.allocation_hash(), one);
+    }
+
+    #[test]
+    fn clearing_starts_the_hash_over() {
+        let mut array = HandleArray::new();
+        build(&mut array);
+        array.clear();
+        assert_eq!(array.allocation_hash(), fnv1a_start());
+        assert_eq!(array.insert(Mesh).id(), 1);
+    }
+}
@@ -1,16 +1,18 @@
 use std::ffi::c_void;
+use std::os::raw::c_ulong;
 
-#[cfg(target_os = "windows")]
-use raw_window_handle::{HasRawWindowHandle, RawWindowHandle, Win32WindowHandle};
+use raw_window_handle::{
+    HasRawDisplayHandle, HasRawWindowHandle, RawDisplayHandle, RawWindowHandle,
+};
 #[cfg(target_os = "macos")]
 use raw_window_metal::CAMetalLayer;
-#[cfg(target_os = "macos")]
-use winit::platform::macos::WindowExtMacOS;
-#[cfg(target_os = "linux")]
-use winit::platform::unix::WindowExtUnix;
 
+#[cfg(feature = "winit")]
 use winit::{dpi::PhysicalSize, error::OsError, event_loop::EventLoop, window::Window};
 
+use super::super::error::UnsupportedWindowHandle;
+use super::super::RendererResult;
+
 #[derive(Copy, Clone, Debug)]
 pub enum InternalWindow {
     WindowsWindow {
@@ -30,65 +32,83 @@ pub enum InternalWindow {
     },
 }
 
+// All of the raw constructors share the same contract: the handles have to be valid,
+// and the window they belong to has to outlive the Renderer created from them.
 impl InternalWindow {
-    #[cfg(target_os = "linux")]
-    fn new(window: &Window) -> Self {
-        let (display, window, is_wayland) = if let Some(display) = window.xlib_display() {
-            (
-                display,
-                window.xlib_window().expect("Got X11 Display but no window") as *mut c_void,
-                false,
-            )
-        } else if let Some(display) = window.wayland_display() {
-            (
-                display,
-                window
-                    .wayland_surface()
-                    .expect("Got Wayland Display but no surface"),
-                true,
-            )
-        } else {
-            panic!("No X11 or Wayland Display available!");
-        };
+    /// # Safety
+    /// `hinstance` and `hwnd` have to be a valid Win32 instance and window handle,
+    /// and the window has to outlive the Renderer
+    pub unsafe fn new_from_win32(hinstance: *const c_void, hwnd: *const c_void) -> Self {
+        Self::WindowsWindow { hinstance, hwnd }
+    }
+
+    /// # Safety
+    /// `display` has to be a valid Xlib `Display*` and `window` a window on it,
+    /// both have to outlive the Renderer
+    pub unsafe fn new_from_xlib(display: *mut c_void, window: c_ulong) -> Self {
         Self::LinuxWindow {
             display,
-            surface: window,
-            is_wayland,
+            surface: window as *mut c_void,
+            is_wayland: false,
         }
     }
 
-    #[cfg(target_os = "windows")]
-    fn new(window: &Window) -> Self {
-        let (hwnd, hinstance) = {
-            if let RawWindowHandle::Win32(Win32WindowHandle {
-                hwnd, hinstance, ..
-            }) = window.raw_window_handle()
-            {
-                (hwnd, hinstance)
-            } else {
-                panic!("Could not setup window");
-            }
-        };
-        Self::WindowsWindow { hinstance, hwnd }
+    /// # Safety
+    /// `display` has to be a valid `wl_display*` and `surface` a `wl_surface*` on it,
+    /// both have to outlive the Renderer
+    pub unsafe fn new_from_wayland(display: *mut c_void, surface: *mut c_void) -> Self {
+        Self::LinuxWindow {
+            display,
+            surface,
+            is_wayland: true,
+        }
     }
 
+    /// # Safety
+    /// `layer` has to be a valid CAMetalLayer that outlives the Renderer
     #[cfg(target_os = "macos")]
-    fn new(window: &Window) -> Self {
-        let ns_window = window.ns_window();
-        let ns_view = window.ns_view();
-        let mut handle = raw_window_handle::AppKitWindowHandle::empty();
-        handle.ns_window = ns_window;
-        handle.ns_view = ns_view;
-        let layer = unsafe { raw_window_metal::appkit::metal_layer_from_handle(handle) };
-        match layer {
-            raw_window_metal::Layer::Existing(layer) => Self::MacOsWindow { layer },
-            // TODO do we need to deallocate this??
-            raw_window_metal::Layer::Allocated(layer) => Self::MacOsWindow { layer },
-            raw_window_metal::Layer::None => panic!("Could not get CAMetalLayer!"),
+    pub unsafe fn new_from_metal_layer(layer: CAMetalLayer) -> Self {
+        Self::MacOsWindow { layer }
+    }
+
+    // Works for anything that exposes raw handles (winit, SDL2, glfw, ...).
+    // The window has to outlive the Renderer.
+    pub fn from_raw_handles<W: HasRawWindowHandle + HasRawDisplayHandle>(
+        window: &W,
+    ) -> RendererResult<Self> {
+        // The handle traits guarantee the handles are valid while `window` is alive
+        match (window.raw_window_handle(), window.raw_display_handle()) {
+            (RawWindowHandle::Win32(handle), _) => unsafe {
+                Ok(Self::new_from_win32(handle.hinstance, handle.hwnd))
+            },
+            (RawWindowHandle::Xlib(handle), RawDisplayHandle::Xlib(display)) => unsafe {
+                Ok(Self::new_from_xlib(display.display, handle.window))
+            },
+            (RawWindowHandle::Wayland(handle), RawDisplayHandle::Wayland(display)) => unsafe {
+                Ok(Self::new_from_wayland(display.display, handle.surface))
+            },
+            #[cfg(target_os = "macos")]
+            (RawWindowHandle::AppKit(handle), _) => {
+                let layer = unsafe { raw_window_metal::appkit::metal_layer_from_handle(handle) };
+                match layer {
+                    raw_window_metal::Layer::Existing(layer) => unsafe {
+                        Ok(Self::new_from_metal_layer(layer))
+                    },
+                    // TODO do we need to deallocate this??
+                    raw_window_metal::Layer::Allocated(layer) => unsafe {
+                        Ok(Self::new_from_metal_layer(layer))
+                    },
+                    raw_window_metal::Layer::None => {
+                        Err(UnsupportedWindowHandle("could not get CAMetalLayer").into())
+                    }
+                }
+            }
+            _ => Err(UnsupportedWindowHandle("unsupported window/display handle").into()),
         }
     }
 }
 
+#[cfg(feature = "winit")]
 pub fn create_render_window() -> Result<(EventLoop<()>, Window, InternalWindow), OsError> {
     // Create window
     let event_loop = winit::event_loop::EventLoop::new();
@@ -96,7 +116,8 @@ pub fn create_render_window() -> Result<(EventLoop<()>, Window, InternalWindow),
         .with_inner_size(PhysicalSize::new(1280, 720))
         .build(&event_loop)?;
 
-    let internal_window = InternalWindow::new(&window);
+    let internal_window =
+        InternalWindow::from_raw_handles(&window).expect("Could not get window handles");
 
     Ok((event_loop, window, internal_window))
 }
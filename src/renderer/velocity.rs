@@ -0,0 +1,271 @@
+use ash::vk;
+use nalgebra_glm as glm;
+
+use super::{
+    color,
+    material::{EffectTemplate, MaterialSystem},
+    render_target::RenderTarget,
+    shaders::ShaderCache,
+    texture::Texture,
+    utils::Handle,
+    RendererResult,
+};
+
+// Screen space motion of every pixel since the previous frame, in UV units, the current
+// position minus the previous one. Zero where nothing opaque was drawn.
+pub const VELOCITY_FORMAT: vk::Format = vk::Format::R16G16_SFLOAT;
+
+// A value as it was in the last rendered frame. Until one was rendered, the previous value
+// is the current one, so new objects and cameras don't move in their first frame.
+#[derive(Clone, Copy, Debug)]
+pub struct PreviousFrame<T: Clone> {
+    last_rendered: Option<T>,
+}
+
+impl<T: Clone> Default for PreviousFrame<T> {
+    fn default() -> Self {
+        Self {
+            last_rendered: None,
+        }
+    }
+}
+
+impl<T: Clone> PreviousFrame<T> {
+    pub fn previous(&self, current: T) -> T {
+        self.last_rendered.clone().unwrap_or(current)
+    }
+
+    // Called once per rendered frame with the value it is rendered with. Returns the
+    // previous value for this frame, current becomes the previous one of the next.
+    pub fn advance(&mut self, current: T) -> T {
+        self.last_rendered
+            .replace(current.clone())
+            .unwrap_or(current)
+    }
+
+    pub fn last_rendered(&self) -> Option<T> {
+        self.last_rendered.clone()
+    }
+
+    // The next frame starts without motion, like a new value
+    pub fn reset(&mut self) {
+        self.last_rendered = None;
+    }
+}
+
+// Rows from top to bottom, as read back by Renderer::capture_velocity
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct VelocityImage {
+    pub width: u32,
+    pub height: u32,
+    pub velocities: Vec<glm::Vec2>,
+}
+
+impl VelocityImage {
+    pub(crate) fn from_texels(width: u32, height: u32, texels: &[u8]) -> Self {
+        let half = |bytes: &[u8]| color::f16_to_f32(u16::from_le_bytes([bytes[0], bytes[1]]));
+        Self {
+            width,
+            height,
+            velocities: texels
+                .chunks_exact(4)
+                .map(|texel| glm::vec2(half(&texel[0..2]), half(&texel[2..4])))
+                .collect(),
+        }
+    }
+
+    pub fn velocity(&self, x: u32, y: u32) -> glm::Vec2 {
+        self.velocities[(y * self.width + x) as usize]
+    }
+}
+
+// Only with RendererConfig::velocity_target, kept until the renderer is dropped. Draws the
+// opaque objects again with their current and previous transforms, before the scene pass,
+// like the shadow pass. The forward subpass is shared with pipelines that have a single
+// color attachment, so the velocity can't be one of its attachments.
+pub(crate) struct VelocityPass {
+    pub render_pass: vk::RenderPass,
+    pub template: Handle<EffectTemplate>,
+}
+
+impl VelocityPass {
+    pub fn new(
+        device: &ash::Device,
+        material_system: &mut MaterialSystem,
+        shader_cache: &mut ShaderCache,
+    ) -> RendererResult<Self> {
+        let render_pass = Self::create_render_pass(device)?;
+        let template =
+            material_system.build_velocity_template(device, render_pass, shader_cache)?;
+        Ok(Self {
+            render_pass,
+            template,
+        })
+    }
+
+    // The velocity ends up ready to be sampled by the passes after it, or copied
+    fn create_render_pass(device: &ash::Device) -> RendererResult<vk::RenderPass> {
+        let attachments = [
+            vk::AttachmentDescription::builder()
+                .format(VELOCITY_FORMAT)
+                .load_op(vk::AttachmentLoadOp::CLEAR)
+                .store_op(vk::AttachmentStoreOp::STORE)
+                .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+                .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+                .initial_layout(vk::ImageLayout::UNDEFINED)
+                .final_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+                .samples(vk::SampleCountFlags::TYPE_1)
+                .build(),
+            vk::AttachmentDescription::builder()
+                .format(vk::Format::D32_SFLOAT)
+                .load_op(vk::AttachmentLoadOp::CLEAR)
+                .store_op(vk::AttachmentStoreOp::DONT_CARE)
+                .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+                .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+                .initial_layout(vk::ImageLayout::UNDEFINED)
+                .final_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
+                .samples(vk::SampleCountFlags::TYPE_1)
+                .build(),
+        ];
+        let color_attachment_references = [vk::AttachmentReference {
+            attachment: 0,
+            layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+        }];
+        let depth_attachment_reference = vk::AttachmentReference {
+            attachment: 1,
+            layout: vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+        };
+
+        let subpasses = [vk::SubpassDescription::builder()
+            .color_attachments(&color_attachment_references)
+            .depth_stencil_attachment(&depth_attachment_reference)
+            .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+            .build()];
+
+        // The last frame may still sample the velocity or test against the depth
+        let subpass_dependencies = [
+            vk::SubpassDependency::builder()
+                .src_subpass(vk::SUBPASS_EXTERNAL)
+                .src_stage_mask(
+                    vk::PipelineStageFlags::FRAGMENT_SHADER
+                        | vk::PipelineStageFlags::COMPUTE_SHADER
+                        | vk::PipelineStageFlags::TRANSFER
+                        | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
+                )
+                .src_access_mask(vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE)
+                .dst_subpass(0)
+                .dst_stage_mask(
+                    vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT
+                        | vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS,
+                )
+                .dst_access_mask(
+                    vk::AccessFlags::COLOR_ATTACHMENT_WRITE
+                        | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+                )
+                .build(),
+            vk::SubpassDependency::builder()
+                .src_subpass(0)
+                .src_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
+                .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+                .dst_subpass(vk::SUBPASS_EXTERNAL)
+                .dst_stage_mask(
+                    vk::PipelineStageFlags::FRAGMENT_SHADER
+                        | vk::PipelineStageFlags::COMPUTE_SHADER
+                        | vk::PipelineStageFlags::TRANSFER,
+                )
+                .dst_access_mask(vk::AccessFlags::SHADER_READ | vk::AccessFlags::TRANSFER_READ)
+                .build(),
+        ];
+
+        let renderpass_info = vk::RenderPassCreateInfo::builder()
+            .attachments(&attachments)
+            .subpasses(&subpasses)
+            .dependencies(&subpass_dependencies);
+        unsafe { Ok(device.create_render_pass(&renderpass_info, None)?) }
+    }
+
+    // The pipeline belongs to the template
+    pub fn destroy(&mut self, device: &ash::Device) {
+        unsafe {
+            device.destroy_render_pass(self.render_pass, None);
+        }
+    }
+}
+
+// At the render scale, recreated with the scaled target. The texture can be sampled by
+// materials like any other.
+pub(crate) struct VelocityTarget {
+    pub texture: Handle<Texture>,
+    pub target: RenderTarget,
+    // Its image is undefined until the first frame renders to it
+    pub rendered: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn new_values_have_no_motion() {
+        let history = PreviousFrame::<f32>::default();
+        assert_eq!(history.previous(3.0), 3.0);
+        assert_eq!(history.last_rendered(), None);
+    }
+
+    #[test]
+    fn advance_rolls_over_once_per_frame() {
+        let mut history = PreviousFrame::default();
+        assert_eq!(history.advance(1.0), 1.0);
+        assert_eq!(history.advance(2.0), 1.0);
+        assert_eq!(history.advance(5.0), 2.0);
+        assert_eq!(history.last_rendered(), Some(5.0));
+    }
+
+    #[test]
+    fn frames_without_rendering_keep_the_last_rendered_value() {
+        let mut history = PreviousFrame::default();
+        history.advance(1.0);
+        // Moved twice between two rendered frames
+        assert_eq!(history.previous(2.0), 1.0);
+        assert_eq!(history.previous(3.0), 1.0);
+        assert_eq!(history.advance(3.0), 1.0);
+    }
+
+    #[test]
+    fn reset_starts_without_motion() {
+        let mut history = PreviousFrame::default();
+        history.advance(1.0);
+        history.reset();
+        assert_eq!(history.advance(4.0), 4.0);
+        assert_eq!(history.advance(6.0), 4.0);
+    }
+
+    #[test]
+    fn matrices_roll_over_like_values() {
+        let first = glm::translation(&glm::vec3(1.0, 0.0, 0.0));
+        let second = glm::translation(&glm::vec3(2.0, 0.0, 0.0));
+        let mut history = PreviousFrame::default();
+        assert_eq!(history.advance(first), first);
+        assert_eq!(history.advance(second), first);
+        assert_eq!(history.advance(second), second);
+    }
+
+    #[test]
+    fn morph_weights_roll_over_like_values() {
+        let mut history = PreviousFrame::default();
+        let first = vec![("smile".to_string(), 0.25)];
+        let second = vec![("smile".to_string(), 0.75)];
+        assert_eq!(history.advance(first.clone()), first);
+        assert_eq!(history.advance(second.clone()), first);
+        assert_eq!(history.previous(vec![]), second);
+    }
+
+    #[test]
+    fn velocity_image_from_half_floats() {
+        // 0.5 and -0.25 as half floats
+        let texels = [0x00, 0x38, 0x00, 0xb4, 0x00, 0x00, 0x00, 0x00];
+        let image = VelocityImage::from_texels(2, 1, &texels);
+        assert_eq!(image.velocity(0, 0), glm::vec2(0.5, -0.25));
+        assert_eq!(image.velocity(1, 0), glm::vec2(0.0, 0.0));
+    }
+}
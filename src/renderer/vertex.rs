@@ -43,7 +43,7 @@ impl Vertex {
         ]
     }
 
-    pub fn get_attribute_descriptions() -> [vk::VertexInputAttributeDescription; 11] {
+    pub fn get_attribute_descriptions() -> [vk::VertexInputAttributeDescription; 17] {
         [
             vk::VertexInputAttributeDescription {
                 location: 0,
@@ -111,6 +111,42 @@ impl Vertex {
                 format: vk::Format::R32G32B32A32_SFLOAT,
                 offset: 112u32,
             },
+            vk::VertexInputAttributeDescription {
+                location: 11,
+                binding: 1,
+                format: vk::Format::R32G32B32A32_SFLOAT,
+                offset: 128u32,
+            },
+            vk::VertexInputAttributeDescription {
+                location: 12,
+                binding: 1,
+                format: vk::Format::R32G32B32A32_SFLOAT,
+                offset: 144u32,
+            },
+            vk::VertexInputAttributeDescription {
+                location: 13,
+                binding: 1,
+                format: vk::Format::R32G32B32A32_SFLOAT,
+                offset: 160u32,
+            },
+            vk::VertexInputAttributeDescription {
+                location: 14,
+                binding: 1,
+                format: vk::Format::R32G32B32A32_SFLOAT,
+                offset: 176u32,
+            },
+            vk::VertexInputAttributeDescription {
+                location: 15,
+                binding: 1,
+                format: vk::Format::R32G32B32A32_UINT,
+                offset: 192u32,
+            },
+            vk::VertexInputAttributeDescription {
+                location: 16,
+                binding: 1,
+                format: vk::Format::R32_UINT,
+                offset: 208u32,
+            },
         ]
     }
 
@@ -0,0 +1,170 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "hot-reload")]
+use log::warn;
+
+// Watches asset files and reports the ones that changed, once the writes to them have
+// settled. Only tracks paths, what a change means is up to the caller, so textures,
+// shaders and material files can share one watcher.
+pub struct AssetWatcher {
+    debounce: Duration,
+    watched: HashSet<PathBuf>,
+    // When each changed file is reported, pushed back by every further write to it
+    pending: HashMap<PathBuf, Instant>,
+    #[cfg(feature = "hot-reload")]
+    backend: Option<NotifyBackend>,
+}
+
+impl std::fmt::Debug for AssetWatcher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AssetWatcher")
+            .field("debounce", &self.debounce)
+            .field("watched", &self.watched.len())
+            .field("pending", &self.pending.len())
+            .finish()
+    }
+}
+
+impl AssetWatcher {
+    // Without the hot-reload feature, or with use_filesystem unset, changes are only
+    // picked up from mark_changed
+    pub fn new(debounce: Duration, use_filesystem: bool) -> Self {
+        #[cfg(not(feature = "hot-reload"))]
+        let _ = use_filesystem;
+        Self {
+            debounce,
+            watched: HashSet::new(),
+            pending: HashMap::new(),
+            #[cfg(feature = "hot-reload")]
+            backend: if use_filesystem {
+                NotifyBackend::new()
+            } else {
+                None
+            },
+        }
+    }
+
+    fn canonical(path: &Path) -> PathBuf {
+        std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+    }
+
+    // Watching a path twice does nothing
+    pub fn watch(&mut self, path: &Path) {
+        if self.watched.contains(path) {
+            return;
+        }
+        let path = Self::canonical(path);
+        #[cfg(feature = "hot-reload")]
+        if let Some(backend) = self.backend.as_mut() {
+            backend.watch(&path);
+        }
+        self.watched.insert(path);
+    }
+
+    pub fn unwatch(&mut self, path: &Path) {
+        let path = Self::canonical(path);
+        self.watched.remove(&path);
+        self.pending.remove(&path);
+    }
+
+    // Changes to paths that aren't watched are ignored
+    pub fn mark_changed(&mut self, path: &Path, now: Instant) {
+        let path = Self::canonical(path);
+        if self.watched.contains(&path) {
+            self.pending.insert(path, now + self.debounce);
+        }
+    }
+
+    // For files that could not be read yet, an editor may still be writing them. They
+    // are reported again after the debounce time, so a file that stays broken doesn't
+    // get retried every frame.
+    pub fn retry(&mut self, path: &Path, now: Instant) {
+        self.mark_changed(path, now);
+    }
+
+    // The files that changed and haven't been written to for the debounce time
+    pub fn poll(&mut self, now: Instant) -> Vec<PathBuf> {
+        #[cfg(feature = "hot-reload")]
+        if let Some(backend) = self.backend.as_mut() {
+            for path in backend.changed_paths() {
+                self.mark_changed(&path, now);
+            }
+        }
+        let ready = self
+            .pending
+            .iter()
+            .filter(|(_, due)| **due <= now)
+            .map(|(path, _)| path.clone())
+            .collect::<Vec<_>>();
+        for path in ready.iter() {
+            self.pending.remove(path);
+        }
+        ready
+    }
+}
+
+// Editors often save by writing a new file and renaming it over the old one, which
+// ends a watch on the file itself, so the directories are watched instead
+#[cfg(feature = "hot-reload")]
+struct NotifyBackend {
+    watcher: notify::RecommendedWatcher,
+    events: std::sync::mpsc::Receiver<notify::Result<notify::Event>>,
+    directories: HashSet<PathBuf>,
+}
+
+#[cfg(feature = "hot-reload")]
+impl NotifyBackend {
+    fn new() -> Option<Self> {
+        let (sender, events) = std::sync::mpsc::channel();
+        match notify::recommended_watcher(move |event| {
+            let _ = sender.send(event);
+        }) {
+            Ok(watcher) => Some(Self {
+                watcher,
+                events,
+                directories: HashSet::new(),
+            }),
+            Err(e) => {
+                warn!("Unable to watch asset files, hot reloading is off: {}", e);
+                None
+            }
+        }
+    }
+
+    fn watch(&mut self, path: &Path) {
+        use notify::Watcher;
+
+        let directory = match path.parent() {
+            Some(directory) => directory.to_path_buf(),
+            None => return,
+        };
+        if self.directories.contains(&directory) {
+            return;
+        }
+        match self
+            .watcher
+            .watch(&directory, notify::RecursiveMode::NonRecursive)
+        {
+            Ok(()) => {
+                self.directories.insert(directory);
+            }
+            Err(e) => warn!("Unable to watch {}: {}", directory.display(), e),
+        }
+    }
+
+    fn changed_paths(&mut self) -> Vec<PathBuf> {
+        let mut paths = vec![];
+        for event in self.events.try_iter() {
+            match event {
+                Ok(event) if event.kind.is_create() || event.kind.is_modify() => {
+                    paths.extend(event.paths)
+                }
+                Ok(_) => {}
+                Err(e) => warn!("Asset watcher error: {}", e),
+            }
+        }
+        paths
+    }
+}
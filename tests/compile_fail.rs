@@ -0,0 +1,12 @@
+// The contracts of Renderer::begin_frame and Renderer::resources are checked by the
+// borrow checker, these make sure the misuse they rule out keeps failing to compile.
+// After a compiler update changes the messages: TRYBUILD=overwrite cargo test --test compile_fail
+#[test]
+fn renderer_borrows() {
+    let t = trybuild::TestCases::new();
+    t.compile_fail("tests/ui/resources_during_frame.rs");
+    t.compile_fail("tests/ui/destroy_texture_during_frame.rs");
+    t.compile_fail("tests/ui/two_resource_contexts.rs");
+    t.compile_fail("tests/ui/object_guard_outlives_context.rs");
+    t.pass("tests/ui/resource_context_between_frames.rs");
+}
@@ -0,0 +1,1355 @@
+// Frames rendered by a headless renderer. They need a Vulkan device, without a loader or
+// a device every test passes after saying on stderr that it was skipped.
+use std::collections::{BTreeSet, HashMap};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use ash::vk;
+use nalgebra as na;
+use nalgebra_glm as glm;
+
+use vulkan_rust::renderer::aov::AOV_BACKGROUND_ID;
+use vulkan_rust::renderer::camera::Camera;
+use vulkan_rust::renderer::color::linear_to_srgb;
+use vulkan_rust::renderer::config::{OpaqueSort, RendererConfig, TransparencyTechnique};
+use vulkan_rust::renderer::culling::{Aabb, CullingMode};
+use vulkan_rust::renderer::error::RendererError;
+use vulkan_rust::renderer::exposure::AutoExposureConfig;
+use vulkan_rust::renderer::fog::{FogConfig, FogMode};
+use vulkan_rust::renderer::light::DirectionalLight;
+use vulkan_rust::renderer::material::{MaterialData, MaterialTextureSlot, ShaderParameters};
+use vulkan_rust::renderer::material_file::MaterialFile;
+use vulkan_rust::renderer::mesh::loaders::obj::load_obj_scene;
+use vulkan_rust::renderer::morph::MorphTarget;
+use vulkan_rust::renderer::profiler::{self, PassTiming};
+use vulkan_rust::renderer::scene::{ChunkEvent, ChunkPolicy, SceneObject, NO_DYNAMIC_LIGHTING};
+use vulkan_rust::renderer::turntable::TurntableSubject;
+use vulkan_rust::renderer::utils::Handle;
+use vulkan_rust::renderer::Renderer;
+
+const SIZE: u32 = 64;
+
+fn headless_renderer() -> Option<Renderer> {
+    headless_renderer_with_config(RendererConfig::default())
+}
+
+fn headless_renderer_with_config(config: RendererConfig) -> Option<Renderer> {
+    headless_renderer_sized(SIZE, config)
+}
+
+fn headless_renderer_sized(size: u32, config: RendererConfig) -> Option<Renderer> {
+    match Renderer::new_headless_with_config("headless test", size, size, config) {
+        Ok(renderer) => Some(renderer),
+        Err(
+            e @ (RendererError::LoadError { .. }
+            | RendererError::NoSuitableDevice { .. }
+            | RendererError::VulkanError {
+                source: vk::Result::ERROR_INCOMPATIBLE_DRIVER,
+                ..
+            }),
+        ) => {
+            eprintln!("Skipped, there is no Vulkan device: {}", e);
+            None
+        }
+        Err(e) => panic!("Unable to create a headless renderer: {:?}", e),
+    }
+}
+
+// A sphere without any light in front of the camera, 3 m away from its surface
+fn unlit_sphere_scene(renderer: &mut Renderer) -> Camera {
+    let sphere = renderer.resources().unwrap().new_sphere_mesh(3).unwrap();
+    let material = renderer
+        .build_material(
+            "sphere",
+            MaterialData {
+                textures: HashMap::new(),
+                buffers: vec![],
+                parameters: ShaderParameters::default(),
+                base_template: "default".to_string(),
+            },
+        )
+        .unwrap();
+    renderer
+        .resources()
+        .unwrap()
+        .new_object(sphere, material)
+        .unwrap();
+    let mut camera = Camera::builder()
+        .position(glm::vec3(0.0, 0.0, -4.0))
+        .aspect(1.0)
+        .build();
+    camera.look_at(&glm::Vec3::zeros(), &glm::vec3(0.0, 1.0, 0.0));
+    camera
+}
+
+// A lit sphere in front of the camera
+fn sphere_scene(renderer: &mut Renderer) -> Camera {
+    let camera = unlit_sphere_scene(renderer);
+    renderer.lights.add_light(DirectionalLight {
+        direction: na::Unit::new_normalize(glm::vec3(-1.0, 1.0, 1.0)),
+        illuminance: glm::vec3(8.0, 8.0, 8.0),
+    });
+    camera
+}
+
+fn is_all_black(image: &image::RgbaImage) -> bool {
+    image.pixels().all(|pixel| pixel.0[..3] == [0, 0, 0])
+}
+
+#[test]
+fn headless_frame_is_not_all_black() {
+    let mut renderer = match headless_renderer() {
+        Some(renderer) => renderer,
+        None => return,
+    };
+    let camera = sphere_scene(&mut renderer);
+    let image = renderer.render_to_image(&camera).unwrap();
+    assert_eq!(image.dimensions(), (SIZE, SIZE));
+    assert!(!is_all_black(&image));
+}
+
+#[test]
+fn overlays_do_not_record_the_scene_again() {
+    let mut renderer = match headless_renderer() {
+        Some(renderer) => renderer,
+        None => return,
+    };
+    let camera = sphere_scene(&mut renderer);
+    let overlays = Arc::new(AtomicUsize::new(0));
+    let recorded = overlays.clone();
+    renderer.set_overlay_recorder(move |_, _, _| {
+        recorded.fetch_add(1, Ordering::Relaxed);
+    });
+    // Every image records its scene command buffer once
+    for _ in 0..8 {
+        renderer.render_to_image(&camera).unwrap();
+    }
+    overlays.store(0, Ordering::Relaxed);
+    for _ in 0..8 {
+        renderer.render_to_image(&camera).unwrap();
+        assert_eq!(renderer.frame_stats().command_buffers_recorded, 0);
+    }
+    assert_eq!(overlays.load(Ordering::Relaxed), 8);
+}
+
+// Golden values of the center of the black sphere, fogged by exp(-density * 3 m), tone
+// mapped with x / (1 + x) and encoded to sRGB
+#[test]
+fn exponential_fog_matches_its_golden_values() {
+    let mut renderer = match headless_renderer() {
+        Some(renderer) => renderer,
+        None => return,
+    };
+    let camera = unlit_sphere_scene(&mut renderer);
+    let image = renderer.render_to_image(&camera).unwrap();
+    assert_eq!(image.get_pixel(SIZE / 2, SIZE / 2).0[..3], [0, 0, 0]);
+
+    let fog_color = glm::vec3(2.0, 1.0, 0.5);
+    for density in [0.1f32, 0.5] {
+        renderer.set_fog(FogConfig {
+            mode: FogMode::Exponential { density },
+            color: fog_color,
+            ..Default::default()
+        });
+        let image = renderer.render_to_image(&camera).unwrap();
+        let pixel = image.get_pixel(SIZE / 2, SIZE / 2);
+        let fogged = 1.0 - (-density * 3.0).exp();
+        for (channel, color) in pixel.0[..3].iter().zip(fog_color.iter()) {
+            let radiance = color * fogged;
+            let golden = (linear_to_srgb(radiance / (1.0 + radiance)) * 255.0).round();
+            assert!(
+                (*channel as f32 - golden).abs() <= 3.0,
+                "density {}: {:?} instead of {}",
+                density,
+                pixel,
+                golden
+            );
+        }
+    }
+}
+
+fn luminance(pixel: &image::Rgba<u8>) -> u32 {
+    pixel.0[..3].iter().map(|channel| *channel as u32).sum()
+}
+
+// The sphere lit by its lightmap alone, with the lights on top of it, and without any
+// light once dynamic lighting is off for the default template
+#[test]
+fn baked_objects_turn_dynamic_lighting_off_with_their_flags() {
+    let mut renderer = match headless_renderer() {
+        Some(renderer) => renderer,
+        None => return,
+    };
+    let camera = sphere_scene(&mut renderer);
+    let (object, default_material) = {
+        let (handle, object) = renderer.scene_tree.iter_with_handles().next().unwrap();
+        (handle, object.material)
+    };
+    let lightmaps = renderer.bake_lightmaps(&[object], 64).unwrap();
+    let baked = renderer
+        .build_material(
+            "baked",
+            MaterialData {
+                textures: HashMap::from([(MaterialTextureSlot::Lightmap, lightmaps[0])]),
+                buffers: vec![],
+                parameters: ShaderParameters::default(),
+                base_template: "lightmapped".to_string(),
+            },
+        )
+        .unwrap();
+    let mut render_with = |material, flags| {
+        {
+            let mut resources = renderer.resources().unwrap();
+            let guard = resources.get_object_mut(object).unwrap();
+            guard.object.material = material;
+            guard.object.flags = flags;
+        }
+        let image = renderer.render_to_image(&camera).unwrap();
+        luminance(image.get_pixel(SIZE / 2, SIZE / 2))
+    };
+
+    let lit = render_with(default_material, 0);
+    let baked_only = render_with(baked, NO_DYNAMIC_LIGHTING);
+    let baked_and_dynamic = render_with(baked, 0);
+    let unlit = render_with(default_material, NO_DYNAMIC_LIGHTING);
+    assert!(lit > 0);
+    assert!(baked_only > 0);
+    assert!(baked_and_dynamic > baked_only);
+    assert!(unlit < lit);
+}
+
+// Changing the weight of a target that shifts the sphere sideways moves its pixels, even
+// though its transform stays where it was
+#[test]
+fn morphing_leaves_motion_in_the_velocity_target() {
+    let mut renderer = match headless_renderer_with_config(RendererConfig {
+        velocity_target: true,
+        ..RendererConfig::default()
+    }) {
+        Some(renderer) => renderer,
+        None => return,
+    };
+    let camera = sphere_scene(&mut renderer);
+    let (object, mesh) = {
+        let (handle, object) = renderer.scene_tree.iter_with_handles().next().unwrap();
+        (handle, object.mesh)
+    };
+    let vertex_count = renderer.meshs.get_mesh(mesh).unwrap().vertices().len();
+    renderer
+        .set_morph_targets(
+            mesh,
+            vec![MorphTarget {
+                name: "shift".to_string(),
+                position_deltas: vec![glm::vec3(0.5, 0.0, 0.0); vertex_count],
+                normal_deltas: vec![],
+            }],
+        )
+        .unwrap();
+    renderer.render_to_image(&camera).unwrap();
+
+    renderer
+        .set_morph_weights(object, &[("shift", 1.0)])
+        .unwrap();
+    renderer.render_to_image(&camera).unwrap();
+    let velocity = renderer.capture_velocity().unwrap().unwrap();
+    assert!(velocity.velocity(SIZE / 2, SIZE / 2).x.abs() > 0.01);
+
+    // Held at the same weight, nothing moves any more
+    renderer.render_to_image(&camera).unwrap();
+    let velocity = renderer.capture_velocity().unwrap().unwrap();
+    assert_eq!(velocity.velocity(SIZE / 2, SIZE / 2), glm::vec2(0.0, 0.0));
+}
+
+// A sphere of the transparent template between the camera and the opaque sphere, blended
+// over it without hiding it
+#[test]
+fn transparent_objects_blend_over_the_opaque_ones() {
+    let mut renderer = match headless_renderer() {
+        Some(renderer) => renderer,
+        None => return,
+    };
+    let camera = sphere_scene(&mut renderer);
+    let opaque = renderer.render_to_image(&camera).unwrap();
+
+    let mesh = renderer.scene_tree.iter().next().unwrap().mesh;
+    let mut invisible = ShaderParameters::default();
+    invisible.set("opacity", 0.0);
+    // The invisible sphere stays in front of the camera while the glass one is drawn
+    for (name, parameters) in [
+        ("invisible", invisible),
+        ("glass", ShaderParameters::default()),
+    ] {
+        let material = renderer
+            .build_material(
+                name,
+                MaterialData {
+                    textures: HashMap::new(),
+                    buffers: vec![],
+                    parameters,
+                    base_template: "transparent".to_string(),
+                },
+            )
+            .unwrap();
+        let mut resources = renderer.resources().unwrap();
+        let object = resources.new_object(mesh, material).unwrap();
+        let guard = resources.get_object_mut(object).unwrap();
+        guard.object.position = glm::vec3(0.0, 0.0, -2.5);
+        guard.object.scaling = glm::vec3(0.5, 0.5, 0.5);
+        drop(guard);
+        drop(resources);
+
+        let image = renderer.render_to_image(&camera).unwrap();
+        if name == "invisible" {
+            assert_eq!(image, opaque);
+        } else {
+            assert_ne!(
+                image.get_pixel(SIZE / 2, SIZE / 2),
+                opaque.get_pixel(SIZE / 2, SIZE / 2)
+            );
+        }
+    }
+}
+
+// A red and a blue transparent quad crossing in an X at the origin, lit from both sides
+// so that their back faces match their front faces
+fn crossing_quads_scene(renderer: &mut Renderer) -> Camera {
+    let cube = renderer.resources().unwrap().new_cube_mesh().unwrap();
+    for (name, color, angle) in [
+        ("red", [255, 0, 0, 255], std::f32::consts::FRAC_PI_4),
+        ("blue", [0, 0, 255, 255], -std::f32::consts::FRAC_PI_4),
+    ] {
+        let texture = renderer
+            .new_texture_from_u8(&color.repeat(4), 2, 2, vk::Format::R8G8B8A8_UNORM)
+            .unwrap();
+        let material = renderer
+            .build_material(
+                name,
+                MaterialData {
+                    textures: HashMap::from([(MaterialTextureSlot::Albedo, texture)]),
+                    buffers: vec![],
+                    parameters: ShaderParameters::default(),
+                    base_template: "transparent".to_string(),
+                },
+            )
+            .unwrap();
+        let mut resources = renderer.resources().unwrap();
+        let object = resources.new_object(cube, material).unwrap();
+        let guard = resources.get_object_mut(object).unwrap();
+        guard.object.scaling = glm::vec3(1.2, 1.2, 0.01);
+        guard.object.rotation = glm::quat_angle_axis(angle, &glm::vec3(0.0, 1.0, 0.0));
+    }
+    for z in [-1.0, 1.0] {
+        renderer.lights.add_light(DirectionalLight {
+            direction: na::Unit::new_normalize(glm::vec3(0.0, 0.0, z)),
+            illuminance: glm::vec3(4.0, 4.0, 4.0),
+        });
+    }
+    let mut camera = Camera::builder()
+        .position(glm::vec3(0.0, 0.0, -4.0))
+        .aspect(1.0)
+        .build();
+    camera.look_at(&glm::Vec3::zeros(), &glm::vec3(0.0, 1.0, 0.0));
+    camera
+}
+
+// Left and right of the intersection, mirrored around the center of the image
+fn crossing_quads_samples(image: &image::RgbaImage) -> (image::Rgba<u8>, image::Rgba<u8>) {
+    let column = SIZE * 3 / 8;
+    (
+        *image.get_pixel(column, SIZE / 2),
+        *image.get_pixel(SIZE - 1 - column, SIZE / 2),
+    )
+}
+
+// Both quads are as far from the camera, so sorting draws one of them last on both sides
+// of the intersection
+#[test]
+fn sorted_quads_blend_in_one_order_across_their_intersection() {
+    let mut renderer = match headless_renderer() {
+        Some(renderer) => renderer,
+        None => return,
+    };
+    let camera = crossing_quads_scene(&mut renderer);
+    let (left, right) = crossing_quads_samples(&renderer.render_to_image(&camera).unwrap());
+    assert_eq!(left.0[0] > left.0[2], right.0[0] > right.0[2]);
+}
+
+// Golden image of weighted blended transparency: the quad in front weighs more, so each
+// side of the intersection shows the color of the quad that is in front there. The scene
+// is mirror symmetric with the colors swapped.
+#[test]
+fn weighted_blended_quads_blend_by_depth_across_their_intersection() {
+    let mut renderer = match headless_renderer_with_config(RendererConfig {
+        transparency_mode: TransparencyTechnique::WeightedBlended,
+        ..Default::default()
+    }) {
+        Some(renderer) => renderer,
+        None => return,
+    };
+    if renderer.get_capabilities().transparency != TransparencyTechnique::WeightedBlended {
+        eprintln!("Skipped, the device has no independent blending");
+        return;
+    }
+    let camera = crossing_quads_scene(&mut renderer);
+    let (left, right) = crossing_quads_samples(&renderer.render_to_image(&camera).unwrap());
+    assert_ne!(left.0[0] > left.0[2], right.0[0] > right.0[2]);
+    for (a, b) in [
+        (left.0[0], right.0[2]),
+        (left.0[2], right.0[0]),
+        (left.0[1], right.0[1]),
+    ] {
+        assert!(
+            a.abs_diff(b) <= 3,
+            "{:?} and {:?} are not mirrored",
+            left,
+            right
+        );
+    }
+}
+
+#[test]
+fn removing_a_texture_waits_for_its_materials() {
+    let mut renderer = match headless_renderer() {
+        Some(renderer) => renderer,
+        None => return,
+    };
+    let camera = sphere_scene(&mut renderer);
+    let texture = renderer
+        .new_texture_from_u8(&[255; 16], 2, 2, vk::Format::R8G8B8A8_UNORM)
+        .unwrap();
+    renderer
+        .build_material(
+            "textured",
+            MaterialData {
+                textures: HashMap::from([(MaterialTextureSlot::Albedo, texture)]),
+                buffers: vec![],
+                parameters: ShaderParameters::default(),
+                base_template: "default".to_string(),
+            },
+        )
+        .unwrap();
+    renderer.render_to_image(&camera).unwrap();
+    assert!(matches!(
+        renderer.remove_texture(texture),
+        Err(RendererError::TextureInUse { .. })
+    ));
+
+    renderer
+        .material_system
+        .remove_material("textured")
+        .unwrap();
+    renderer.remove_texture(texture).unwrap();
+    renderer.render_to_image(&camera).unwrap();
+    assert!(renderer.texture_storage.get_texture(texture).is_none());
+    let replacement = renderer
+        .new_texture_from_u8(&[0; 16], 2, 2, vk::Format::R8G8B8A8_UNORM)
+        .unwrap();
+    assert!(renderer.texture_storage.get_texture(replacement).is_some());
+    assert!(renderer.texture_storage.get_texture(texture).is_none());
+}
+
+// Dropping a frame without ending it hands its acquired image to the next begin_frame
+#[test]
+fn abandoned_frames_give_their_image_to_the_next_one() {
+    let mut renderer = match headless_renderer() {
+        Some(renderer) => renderer,
+        None => return,
+    };
+    let camera = sphere_scene(&mut renderer);
+    let frame = renderer.begin_frame().unwrap().unwrap();
+    let image_index = frame.image_index();
+    let frame_number = frame.frame_number();
+    drop(frame);
+
+    let frame = renderer.begin_frame().unwrap().unwrap();
+    assert_eq!(frame.image_index(), image_index);
+    assert_eq!(frame.frame_number(), frame_number);
+    frame.end_without_ui(&camera).unwrap();
+    assert!(!is_all_black(&renderer.render_to_image(&camera).unwrap()));
+}
+
+// Forgetting a frame neither ends nor abandons it, so it is still pending
+#[test]
+fn begin_frame_twice_without_end_frame_fails() {
+    let mut renderer = match headless_renderer() {
+        Some(renderer) => renderer,
+        None => return,
+    };
+    std::mem::forget(renderer.begin_frame().unwrap().unwrap());
+    assert!(matches!(
+        renderer.begin_frame(),
+        Err(RendererError::FrameError { .. })
+    ));
+}
+
+fn channel_sums(image: &image::RgbaImage) -> [u32; 3] {
+    image.pixels().fold([0; 3], |sums, pixel| {
+        [0, 1, 2].map(|channel| sums[channel] + pixel.0[channel] as u32)
+    })
+}
+
+// Simulates the watcher reporting a changed file by reloading its texture directly. The
+// descriptor sets of the material show the new image in the next frame, which also
+// destroys the old image, the reload waited for the frames using it.
+#[test]
+fn reloading_a_texture_rewrites_its_materials() {
+    let mut renderer = match headless_renderer() {
+        Some(renderer) => renderer,
+        None => return,
+    };
+    let camera = sphere_scene(&mut renderer);
+    let dir = std::env::temp_dir().join(format!("headless_reload_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("albedo.png");
+    image::RgbaImage::from_pixel(2, 2, image::Rgba([255, 0, 0, 255]))
+        .save(&path)
+        .unwrap();
+    let texture = renderer.new_texture_from_file(&path).unwrap();
+    let material = renderer
+        .build_material(
+            "reloaded",
+            MaterialData {
+                textures: HashMap::from([(MaterialTextureSlot::Albedo, texture)]),
+                buffers: vec![],
+                parameters: ShaderParameters::default(),
+                base_template: "default".to_string(),
+            },
+        )
+        .unwrap();
+    // In front of the untextured sphere, which adds as much red as green
+    let mesh = renderer.scene_tree.iter().next().unwrap().mesh;
+    let mut resources = renderer.resources().unwrap();
+    let object = resources.new_object(mesh, material).unwrap();
+    let guard = resources.get_object_mut(object).unwrap();
+    guard.object.position = glm::vec3(0.0, 0.0, -2.5);
+    guard.object.scaling = glm::vec3(0.5, 0.5, 0.5);
+    drop(guard);
+    drop(resources);
+    let [red, green, _] = channel_sums(&renderer.render_to_image(&camera).unwrap());
+    assert!(red > green);
+
+    let old_view = renderer
+        .texture_storage
+        .get_texture(texture)
+        .unwrap()
+        .image_view;
+    image::RgbaImage::from_pixel(2, 2, image::Rgba([0, 255, 0, 255]))
+        .save(&path)
+        .unwrap();
+    renderer.reload_texture(texture).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_ne!(
+        renderer
+            .texture_storage
+            .get_texture(texture)
+            .unwrap()
+            .image_view,
+        old_view
+    );
+    assert_eq!(
+        renderer.texture_storage.get_number_of_queued_destructions(),
+        1
+    );
+
+    let [red, green, _] = channel_sums(&renderer.render_to_image(&camera).unwrap());
+    assert!(green > red);
+    assert_eq!(
+        renderer.texture_storage.get_number_of_queued_destructions(),
+        0
+    );
+}
+
+// Every image records its command buffer once after the swapchain came back with another
+// image count, so the command buffers and the rest of the per image state follow it
+#[test]
+fn changing_the_image_count_resizes_the_per_image_resources() {
+    let mut renderer = match headless_renderer() {
+        Some(renderer) => renderer,
+        None => return,
+    };
+    let camera = sphere_scene(&mut renderer);
+    renderer.render_to_image(&camera).unwrap();
+    for image_count in [5, 1, 3] {
+        renderer.set_headless_image_count(image_count).unwrap();
+        let mut recorded = 0;
+        for _ in 0..image_count * 2 {
+            let image = renderer.render_to_image(&camera).unwrap();
+            assert!(!is_all_black(&image));
+            recorded += renderer.frame_stats().command_buffers_recorded;
+        }
+        assert_eq!(recorded, image_count);
+    }
+}
+
+#[test]
+fn deterministic_ids_match_across_renderers() {
+    let config = RendererConfig {
+        deterministic_ids: true,
+        ..Default::default()
+    };
+    let mut runs = vec![];
+    for _ in 0..2 {
+        let mut renderer = match headless_renderer_with_config(config.clone()) {
+            Some(renderer) => renderer,
+            None => return,
+        };
+        let mut resources = renderer.resources().unwrap();
+        let cube = resources.new_cube_mesh().unwrap();
+        let sphere = resources.new_sphere_mesh(2).unwrap();
+        drop(resources);
+        sphere_scene(&mut renderer);
+        let text = renderer
+            .add_text(
+                (0, 0),
+                Default::default(),
+                &[&fontdue::layout::TextStyle::new("handles", 12.0, 0)],
+                [1.0, 1.0, 1.0],
+            )
+            .unwrap();
+        runs.push((
+            format!("{:?} {:?}", cube, sphere),
+            text,
+            renderer.debug_handle_hash(),
+        ));
+    }
+    assert_eq!(runs[0], runs[1]);
+}
+
+#[test]
+fn material_files_round_trip() {
+    let mut renderer = match headless_renderer() {
+        Some(renderer) => renderer,
+        None => return,
+    };
+    let dir = std::env::temp_dir().join(format!("headless_material_{}", std::process::id()));
+    // b names the texture of a from another directory, saving it next to a gives a back
+    std::fs::create_dir_all(dir.join("sub")).unwrap();
+    image::RgbaImage::from_pixel(2, 2, image::Rgba([200, 100, 50, 255]))
+        .save(dir.join("albedo.png"))
+        .unwrap();
+    let material =
+        r#"(base_template: "default", textures: ["albedo.png"], parameters: {"roughness": 0.25})"#;
+    std::fs::write(dir.join("a.material"), material).unwrap();
+    std::fs::write(
+        dir.join("sub/b.material"),
+        material.replace("albedo.png", "../albedo.png"),
+    )
+    .unwrap();
+
+    renderer.load_material_file(dir.join("a.material")).unwrap();
+    let b = renderer
+        .load_material_file(dir.join("sub/b.material"))
+        .unwrap();
+    renderer
+        .save_material_file(b, dir.join("c.material"))
+        .unwrap();
+    let saved = MaterialFile::load(dir.join("c.material"));
+    let original = MaterialFile::load(dir.join("a.material"));
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(saved.unwrap(), original.unwrap());
+}
+
+#[test]
+fn writing_a_shared_material_splits_it() {
+    let mut renderer = match headless_renderer() {
+        Some(renderer) => renderer,
+        None => return,
+    };
+    let data = || MaterialData {
+        textures: HashMap::new(),
+        buffers: vec![],
+        parameters: ShaderParameters::default(),
+        base_template: "default".to_string(),
+    };
+    let brick = renderer.build_material("brick", data()).unwrap();
+    let wall = renderer.build_material("wall", data()).unwrap();
+    assert_eq!(brick, wall);
+    assert!(renderer.material_system.is_shared(brick));
+
+    let mut rough = ShaderParameters::default();
+    rough.set("roughness", 0.9);
+    let split = renderer
+        .set_material_parameters("brick", rough.clone())
+        .unwrap();
+    assert_ne!(split, wall);
+    assert_eq!(renderer.material_system.aliases(wall), vec!["wall"]);
+    assert_eq!(renderer.material_system.aliases(split), vec!["brick"]);
+
+    rough.set("roughness", 0.5);
+    let again = renderer.set_material_parameters("brick", rough).unwrap();
+    assert_eq!(again, split);
+}
+
+// Alternately frees small textures to leave holes, then moves everything that is left
+// a little at a time and checks that a large texture still fits and the materials still
+// draw
+#[cfg(feature = "defrag")]
+#[test]
+fn defragmenting_fragmented_memory_makes_room() {
+    let mut renderer = match headless_renderer() {
+        Some(renderer) => renderer,
+        None => return,
+    };
+    let camera = sphere_scene(&mut renderer);
+    let pixels = vec![128u8; 256 * 256 * 4];
+    let mut textures = vec![];
+    for round in 0..4 {
+        for i in 0..32 {
+            let texture = renderer
+                .new_texture_from_u8(&pixels, 256, 256, vk::Format::R8G8B8A8_UNORM)
+                .unwrap();
+            renderer
+                .build_material(
+                    &format!("fragment {} {}", round, i),
+                    MaterialData {
+                        textures: HashMap::from([(MaterialTextureSlot::Albedo, texture)]),
+                        buffers: vec![],
+                        parameters: ShaderParameters::default(),
+                        base_template: "default".to_string(),
+                    },
+                )
+                .unwrap();
+            textures.push(texture);
+        }
+        // Every other texture of the round goes, with its material's reference
+        for texture in textures
+            .drain(textures.len() - 32..)
+            .step_by(2)
+            .collect::<Vec<_>>()
+        {
+            renderer.destroy_texture(texture, true).unwrap();
+        }
+        renderer.render_to_image(&camera).unwrap();
+    }
+
+    let mut moved = 0;
+    let mut calls = 0;
+    loop {
+        let report = renderer.defragment(std::time::Duration::ZERO).unwrap();
+        moved += report.moved_textures + report.moved_buffers;
+        calls += 1;
+        if report.finished {
+            break;
+        }
+        assert!(calls < 1000, "defragmenting never finished");
+    }
+    assert!(moved > 0);
+    assert!(calls > 1, "a zero budget still moves one resource per call");
+
+    let large = vec![255u8; 4096 * 4096 * 4];
+    renderer
+        .new_texture_from_u8(&large, 4096, 4096, vk::Format::R8G8B8A8_UNORM)
+        .unwrap();
+    renderer.render_to_image(&camera).unwrap();
+}
+
+// With auto exposure the luminance pass goes on the compute queue, if the device has one
+fn async_compute_renderer(size: u32) -> Option<Renderer> {
+    let config = RendererConfig {
+        async_compute: true,
+        auto_exposure: Some(AutoExposureConfig::default()),
+        ..Default::default()
+    };
+    let renderer = headless_renderer_sized(size, config)?;
+    if !renderer.uses_async_compute() {
+        eprintln!("Skipped, the device has no separate compute queue");
+        return None;
+    }
+    Some(renderer)
+}
+
+#[test]
+fn async_compute_passes_are_timed() {
+    let mut renderer = match async_compute_renderer(SIZE) {
+        Some(renderer) => renderer,
+        None => return,
+    };
+    let camera = sphere_scene(&mut renderer);
+    // The timings lag behind by the frames in flight
+    for _ in 0..8 {
+        renderer.render_to_image(&camera).unwrap();
+    }
+    let timings = renderer.frame_timings();
+    if timings.is_empty() {
+        eprintln!("Skipped, the device has no timestamps on the graphics queue");
+        return;
+    }
+    let names = timings.iter().map(|timing| timing.name).collect::<Vec<_>>();
+    assert!(names.contains(&"forward"));
+    assert!(names.contains(&"upscale"));
+    if names.contains(&"luminance") {
+        assert!(profiler::overlap_ms(timings) >= 0.0);
+    } else {
+        eprintln!("The compute queue has other timestamps, its passes aren't timed");
+    }
+}
+
+// Not a check, a measurement of how much the luminance pass on the compute queue runs
+// next to the graphics passes after it. Prints the sum of the pass times, the time any
+// of them ran, and their difference averaged over the frames.
+// cargo test --test headless async_compute_overlap -- --ignored --nocapture
+#[test]
+#[ignore]
+fn async_compute_overlap() {
+    let mut renderer = match async_compute_renderer(1024) {
+        Some(renderer) => renderer,
+        None => return,
+    };
+    let camera = sphere_scene(&mut renderer);
+    let mut frames: Vec<Vec<PassTiming>> = vec![];
+    for _ in 0..64 {
+        renderer.render_to_image(&camera).unwrap();
+        if renderer
+            .frame_timings()
+            .iter()
+            .any(|timing| timing.name == "luminance")
+        {
+            frames.push(renderer.frame_timings().to_vec());
+        }
+    }
+    if frames.is_empty() {
+        eprintln!("Skipped, the passes on the compute queue aren't timed");
+        return;
+    }
+    let average = |f: &dyn Fn(&[PassTiming]) -> f32| {
+        frames.iter().map(|timings| f(timings)).sum::<f32>() / frames.len() as f32
+    };
+    let sum = average(&|timings| timings.iter().map(|timing| timing.gpu_ms).sum());
+    let overlap = average(&|timings| profiler::overlap_ms(timings));
+    println!(
+        "Over {} frames: {:.3} ms of passes, {:.3} ms busy, {:.3} ms overlapped",
+        frames.len(),
+        sum,
+        sum - overlap,
+        overlap
+    );
+}
+
+// Not a check, a measurement of what drawing front to back saves on a scene of large
+// spheres behind each other, which the scene has from back to front. Prints the average
+// GPU time of the forward pass with each sort.
+// cargo test --test headless opaque_sort_timings -- --ignored --nocapture
+#[test]
+#[ignore]
+fn opaque_sort_timings() {
+    for opaque_sort in [OpaqueSort::None, OpaqueSort::FrontToBack] {
+        let config = RendererConfig {
+            opaque_sort,
+            ..Default::default()
+        };
+        let mut renderer = match headless_renderer_sized(1024, config) {
+            Some(renderer) => renderer,
+            None => return,
+        };
+        let camera = sphere_scene(&mut renderer);
+        let (mesh, material) = renderer
+            .scene_tree
+            .iter()
+            .map(|object| (object.mesh, object.material))
+            .next()
+            .unwrap();
+        let mut resources = renderer.resources().unwrap();
+        for i in 0..64 {
+            let sphere = resources.new_object(mesh, material).unwrap();
+            let guard = resources.get_object_mut(sphere).unwrap();
+            guard.object.position = glm::vec3(0.0, 0.0, 64.0 - i as f32);
+            guard.object.scaling = glm::vec3(4.0, 4.0, 4.0);
+        }
+        drop(resources);
+
+        let mut forward = vec![];
+        for _ in 0..64 {
+            renderer.render_to_image(&camera).unwrap();
+            forward.extend(
+                renderer
+                    .frame_timings()
+                    .iter()
+                    .filter(|timing| timing.name == "forward")
+                    .map(|timing| timing.gpu_ms),
+            );
+        }
+        if forward.is_empty() {
+            eprintln!("Skipped, the device has no timestamps on the graphics queue");
+            return;
+        }
+        println!(
+            "{:?}: forward pass {:.3} ms on average over {} frames",
+            opaque_sort,
+            forward.iter().sum::<f32>() / forward.len() as f32,
+            forward.len()
+        );
+    }
+}
+
+#[test]
+fn material_previews_show_the_roughness() {
+    let mut renderer = match headless_renderer() {
+        Some(renderer) => renderer,
+        None => return,
+    };
+    let mut materials = vec![];
+    for (name, roughness) in [("smooth", 0.1f32), ("rough", 0.9)] {
+        let mut parameters = ShaderParameters::default();
+        parameters.set("roughness", roughness);
+        materials.push(
+            renderer
+                .build_material(
+                    name,
+                    MaterialData {
+                        textures: HashMap::new(),
+                        buffers: vec![],
+                        parameters,
+                        base_template: "default".to_string(),
+                    },
+                )
+                .unwrap(),
+        );
+    }
+    let previews = renderer.render_material_previews(&materials, SIZE).unwrap();
+    assert_eq!(previews.len(), 2);
+    assert!(!is_all_black(&previews[0]));
+    assert_ne!(previews[0], previews[1]);
+}
+
+// Two spheres side by side, every pixel they cover holds the id of one of their handles
+// and a normal of unit length
+#[test]
+fn aovs_hold_the_object_ids_and_unit_normals() {
+    let mut renderer = match headless_renderer() {
+        Some(renderer) => renderer,
+        None => return,
+    };
+    let camera = sphere_scene(&mut renderer);
+    let (first, mesh, material) = renderer
+        .scene_tree
+        .iter_with_handles()
+        .map(|(handle, object)| (handle, object.mesh, object.material))
+        .next()
+        .unwrap();
+    let mut resources = renderer.resources().unwrap();
+    let second = resources.new_object(mesh, material).unwrap();
+    let guard = resources.get_object_mut(first).unwrap();
+    guard.object.position = glm::vec3(-1.2, 0.0, 0.0);
+    drop(guard);
+    let guard = resources.get_object_mut(second).unwrap();
+    guard.object.position = glm::vec3(1.2, 0.0, 0.0);
+    drop(guard);
+    drop(resources);
+
+    let aovs = renderer.capture_aovs(&camera).unwrap();
+    assert_eq!((aovs.width, aovs.height), (SIZE, SIZE));
+    assert_eq!(
+        aovs.unique_object_ids(),
+        [first.id() as u32, second.id() as u32]
+            .into_iter()
+            .collect()
+    );
+    for (id, normal) in aovs.object_ids.iter().zip(&aovs.normals) {
+        if *id != AOV_BACKGROUND_ID {
+            assert!((glm::length(normal) - 1.0).abs() < 0.01, "{:?}", normal);
+        }
+    }
+}
+
+// A cube looks different from every one of 8 directions 45 degrees apart
+#[test]
+fn turntable_frames_turn_around_the_subject() {
+    let mut renderer = match headless_renderer() {
+        Some(renderer) => renderer,
+        None => return,
+    };
+    let cube = renderer.resources().unwrap().new_cube_mesh().unwrap();
+    let material = renderer
+        .build_material(
+            "cube",
+            MaterialData {
+                textures: HashMap::new(),
+                buffers: vec![],
+                parameters: ShaderParameters::default(),
+                base_template: "default".to_string(),
+            },
+        )
+        .unwrap();
+    let frames = renderer
+        .capture_turntable(TurntableSubject::Mesh(cube, material), 8, SIZE)
+        .unwrap();
+    assert_eq!(frames.len(), 8);
+    for (i, frame) in frames.iter().enumerate() {
+        assert_eq!(frame.dimensions(), (SIZE, SIZE));
+        assert_ne!(frame, &frames[(i + 1) % 8], "frame {}", i);
+    }
+    // Capturing again starts from the same direction
+    let again = renderer
+        .capture_turntable(TurntableSubject::Mesh(cube, material), 8, SIZE)
+        .unwrap();
+    assert_eq!(again[0], frames[0]);
+}
+
+// tests/fixtures/car.obj becomes a scene group with an object per group of the file
+#[test]
+fn obj_scenes_keep_their_groups() {
+    let mut renderer = match headless_renderer() {
+        Some(renderer) => renderer,
+        None => return,
+    };
+    let scene =
+        load_obj_scene(Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/car.obj"))
+            .unwrap();
+    assert_eq!(scene.materials.len(), 2);
+    let mut material = |name: &str| {
+        renderer
+            .build_material(
+                name,
+                MaterialData {
+                    textures: HashMap::new(),
+                    buffers: vec![],
+                    parameters: ShaderParameters::default(),
+                    base_template: "default".to_string(),
+                },
+            )
+            .unwrap()
+    };
+    let (paint, rubber, fallback) = (material("paint"), material("rubber"), material("fallback"));
+    let materials = HashMap::from([("paint".to_string(), paint), ("rubber".to_string(), rubber)]);
+    let instance = renderer
+        .resources()
+        .unwrap()
+        .new_obj_scene("car", scene, &materials, fallback)
+        .unwrap();
+
+    let group = renderer.scene_tree.get_group(instance.group).unwrap();
+    assert_eq!(group.get_name(), "car");
+    assert_eq!(
+        renderer
+            .scene_tree
+            .iter_group(instance.group)
+            .collect::<Vec<_>>(),
+        instance
+            .objects
+            .iter()
+            .map(|(_, object)| *object)
+            .collect::<Vec<_>>()
+    );
+    let created = instance
+        .objects
+        .iter()
+        .map(|(name, object)| {
+            let object = renderer.scene_tree.get_object(*object).unwrap();
+            let mesh = renderer.meshs.get_mesh(object.mesh).unwrap();
+            (name.as_str(), mesh.vertices().len(), object.material)
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        created,
+        [
+            ("body", 4, paint),
+            ("wheel", 3, rubber),
+            ("trim", 5, fallback)
+        ]
+    );
+}
+
+// Views with disjoint layer masks see disjoint parts of the scene, neither of them with
+// the hidden objects or the ones behind the camera
+#[test]
+fn disjoint_layer_masks_partition_the_scene() {
+    let mut renderer = match headless_renderer() {
+        Some(renderer) => renderer,
+        None => return,
+    };
+    let mut camera = sphere_scene(&mut renderer);
+    let (sphere, mesh, material) = renderer
+        .scene_tree
+        .iter_with_handles()
+        .map(|(handle, object)| (handle, object.mesh, object.material))
+        .next()
+        .unwrap();
+    let mut resources = renderer.resources().unwrap();
+    let mut objects = vec![];
+    for position in [
+        glm::vec3(-1.5, 0.0, 0.0),
+        glm::vec3(1.5, 0.0, 0.0),
+        glm::vec3(0.0, 1.5, 0.0),
+        glm::vec3(0.0, 0.0, -8.0),
+    ] {
+        let object = resources.new_object(mesh, material).unwrap();
+        let guard = resources.get_object_mut(object).unwrap();
+        guard.object.position = position;
+        guard.object.scaling = glm::vec3(0.5, 0.5, 0.5);
+        drop(guard);
+        objects.push(object);
+    }
+    drop(resources);
+    let [left, right, hidden, behind] = objects[..] else {
+        unreachable!()
+    };
+    let scene_tree = &mut renderer.scene_tree;
+    scene_tree.set_layer(sphere, 0b100).unwrap();
+    scene_tree.set_layer(left, 0b01).unwrap();
+    scene_tree.set_layer(right, 0b10).unwrap();
+    scene_tree.set_layer(hidden, 0b01).unwrap();
+    scene_tree.set_visible(hidden, false, false).unwrap();
+    scene_tree.set_layer(behind, 0b10).unwrap();
+
+    let mut seen = |mask: u32| {
+        camera.set_layer_mask(mask);
+        renderer.capture_aovs(&camera).unwrap().unique_object_ids()
+    };
+    let id = |handle: Handle<SceneObject>| handle.id() as u32;
+    assert_eq!(seen(0b01), BTreeSet::from([id(left)]));
+    assert_eq!(seen(0b10), BTreeSet::from([id(right)]));
+    assert_eq!(
+        seen(0b111),
+        BTreeSet::from([id(sphere), id(left), id(right)])
+    );
+}
+
+// The camera moves past three chunks in a row, and only the chunk in front of it is
+// active and drawn. The last chunk frees its buffers as soon as it is inactive and gets
+// them back when the camera reaches it.
+#[test]
+fn chunks_follow_the_camera() {
+    let mut renderer = match headless_renderer_with_config(RendererConfig {
+        chunk_activation_radius: 7.1,
+        ..Default::default()
+    }) {
+        Some(renderer) => renderer,
+        None => return,
+    };
+    unlit_sphere_scene(&mut renderer);
+    let (middle, mesh, material) = renderer
+        .scene_tree
+        .iter_with_handles()
+        .map(|(handle, object)| (handle, object.mesh, object.material))
+        .next()
+        .unwrap();
+    let mut resources = renderer.resources().unwrap();
+    let mut spheres = vec![];
+    for x in [-3.0, 3.0] {
+        let object = resources.new_object(mesh, material).unwrap();
+        resources.get_object_mut(object).unwrap().object.position = glm::vec3(x, 0.0, 0.0);
+        spheres.push(object);
+    }
+    drop(resources);
+    let spheres = [spheres[0], middle, spheres[1]];
+    let mut chunks = vec![];
+    for (i, sphere) in spheres.iter().enumerate() {
+        let x = 3.0 * i as f32 - 3.0;
+        let chunk = renderer.scene_tree.create_chunk(
+            format!("chunk {}", i),
+            Aabb {
+                min: glm::vec3(x - 1.0, -1.0, -1.0),
+                max: glm::vec3(x + 1.0, 1.0, 1.0),
+            },
+        );
+        renderer.scene_tree.assign_to_chunk(chunk, *sphere).unwrap();
+        chunks.push(chunk);
+    }
+    renderer
+        .scene_tree
+        .set_chunk_policy(chunks[2], ChunkPolicy::FreeBuffersAfter(Duration::ZERO))
+        .unwrap();
+    let events = Arc::new(Mutex::new(vec![]));
+    let recorded = events.clone();
+    renderer.set_chunk_callback(move |chunk, event| recorded.lock().unwrap().push((chunk, event)));
+
+    let expected = [
+        vec![
+            (chunks[1], ChunkEvent::Deactivated),
+            (chunks[2], ChunkEvent::Deactivated),
+            (chunks[2], ChunkEvent::BuffersFreed),
+        ],
+        vec![
+            (chunks[0], ChunkEvent::Deactivated),
+            (chunks[1], ChunkEvent::Activated),
+        ],
+        vec![
+            (chunks[1], ChunkEvent::Deactivated),
+            (chunks[2], ChunkEvent::BuffersRestored),
+            (chunks[2], ChunkEvent::Activated),
+        ],
+    ];
+    for (i, expected) in expected.into_iter().enumerate() {
+        // 7 away from the chunk in front of it, and more than 7.1 from the others
+        let x = 3.0 * i as f32 - 3.0;
+        let mut camera = Camera::builder()
+            .position(glm::vec3(x, 0.0, -8.0))
+            .aspect(1.0)
+            .build();
+        camera.look_at(&glm::vec3(x, 0.0, 0.0), &glm::vec3(0.0, 1.0, 0.0));
+        renderer.render_to_image(&camera).unwrap();
+        assert_eq!(std::mem::take(&mut *events.lock().unwrap()), expected);
+        for (j, chunk) in chunks.iter().enumerate() {
+            let chunk = renderer.scene_tree.get_chunk(*chunk).unwrap();
+            assert_eq!(chunk.is_active(), i == j);
+        }
+        assert_eq!(
+            renderer.capture_aovs(&camera).unwrap().unique_object_ids(),
+            BTreeSet::from([spheres[i].id() as u32])
+        );
+    }
+}
+
+// An object far to the side of the camera is only drawn with CullingMode::Never, and
+// counted apart from the culled ones
+#[test]
+fn never_culled_objects_are_drawn_outside_the_frustum() {
+    let mut renderer = match headless_renderer() {
+        Some(renderer) => renderer,
+        None => return,
+    };
+    let camera = sphere_scene(&mut renderer);
+    let (mesh, material) = renderer
+        .scene_tree
+        .iter()
+        .map(|object| (object.mesh, object.material))
+        .next()
+        .unwrap();
+    let mut resources = renderer.resources().unwrap();
+    let far_away = resources.new_object(mesh, material).unwrap();
+    resources.get_object_mut(far_away).unwrap().object.position = glm::vec3(100.0, 0.0, 0.0);
+    drop(resources);
+
+    renderer.render_to_image(&camera).unwrap();
+    let stats = renderer.get_culling_stats();
+    assert_eq!((stats.drawn, stats.culled, stats.never_culled), (1, 1, 0));
+    assert_eq!(renderer.frame_stats().objects_drawn, 1);
+
+    renderer
+        .resources()
+        .unwrap()
+        .get_object_mut(far_away)
+        .unwrap()
+        .object
+        .culling = CullingMode::Never;
+    renderer.render_to_image(&camera).unwrap();
+    let stats = renderer.get_culling_stats();
+    assert_eq!((stats.drawn, stats.culled, stats.never_culled), (2, 0, 1));
+    assert_eq!(renderer.frame_stats().objects_drawn, 2);
+}
+
+#[test]
+fn materials_of_a_rebuilt_template_are_skipped_until_migrated() {
+    let mut renderer = match headless_renderer() {
+        Some(renderer) => renderer,
+        None => return,
+    };
+    let camera = unlit_sphere_scene(&mut renderer);
+    let sphere = renderer.scene_tree.iter_with_handles().next().unwrap().0;
+    renderer.render_to_image(&camera).unwrap();
+    assert_eq!(renderer.frame_stats().objects_drawn, 1);
+    assert!(renderer.skipped_draws().is_empty());
+
+    // A rebuild the material of the sphere wasn't moved along with
+    let template = renderer
+        .material_system
+        .get_effect_template_handle("default")
+        .unwrap();
+    renderer
+        .material_system
+        .get_effect_template_by_handle_mut(template)
+        .unwrap()
+        .generation += 1;
+    renderer.render_to_image(&camera).unwrap();
+    assert_eq!(renderer.frame_stats().objects_drawn, 0);
+    let skipped = renderer.skipped_draws();
+    assert_eq!(skipped.len(), 1);
+    assert_eq!(skipped[0].0, sphere);
+    assert!(skipped[0].1.contains("generation 0"), "{}", skipped[0].1);
+}
+
+#[test]
+fn removing_half_of_a_hundred_objects_between_frames() {
+    let mut renderer = match headless_renderer() {
+        Some(renderer) => renderer,
+        None => return,
+    };
+    let camera = unlit_sphere_scene(&mut renderer);
+    let (first, mesh, material) = renderer
+        .scene_tree
+        .iter_with_handles()
+        .map(|(handle, object)| (handle, object.mesh, object.material))
+        .next()
+        .unwrap();
+    renderer.scene_tree.remove_object(first).unwrap();
+
+    // Ten rows of a parent with nine children each
+    let mut parents = vec![];
+    let mut children = vec![];
+    let mut resources = renderer.resources().unwrap();
+    for row in 0..10 {
+        let parent = resources.new_object(mesh, material).unwrap();
+        resources.get_object_mut(parent).unwrap().object.position =
+            glm::vec3(-2.0, row as f32 * 0.4 - 2.0, 0.0);
+        for column in 1..10 {
+            let child = resources.new_object(mesh, material).unwrap();
+            {
+                let mut guard = resources.get_object_mut(child).unwrap();
+                guard.object.position = glm::vec3(column as f32 * 0.4, 0.0, 0.0);
+                guard.object.scaling = glm::vec3(0.1, 0.1, 0.1);
+            }
+            resources
+                .get_object_mut(parent)
+                .unwrap()
+                .add_child(child)
+                .unwrap();
+            children.push((row, child));
+        }
+        parents.push(parent);
+    }
+    drop(resources);
+    renderer.render_to_image(&camera).unwrap();
+    assert_eq!(renderer.frame_stats().objects_total, 100);
+
+    for parent in parents.iter().step_by(2) {
+        renderer.scene_tree.remove_object(*parent).unwrap();
+    }
+    renderer.render_to_image(&camera).unwrap();
+    assert_eq!(renderer.frame_stats().objects_total, 50);
+    for (row, parent) in parents.iter().enumerate() {
+        assert_eq!(
+            renderer.scene_tree.get_object(*parent).is_some(),
+            row % 2 == 1
+        );
+    }
+    for (row, child) in children {
+        let mut resources = renderer.resources().unwrap();
+        assert_eq!(resources.get_object_mut(child).is_some(), row % 2 == 1);
+    }
+    assert!(renderer.skipped_draws().is_empty());
+}
+
+#[test]
+fn scrolling_uvs_allocates_no_descriptor_sets() {
+    let mut renderer = match headless_renderer() {
+        Some(renderer) => renderer,
+        None => return,
+    };
+    let camera = sphere_scene(&mut renderer);
+    let material = renderer.scene_tree.iter().next().unwrap().material;
+    for _ in 0..3 {
+        renderer.render_to_image(&camera).unwrap();
+    }
+    let allocated = renderer.descriptor_allocator.allocated_set_count();
+
+    for _ in 0..10 {
+        renderer
+            .animate_material_uv(material, glm::vec2(1000.0, -1000.0))
+            .unwrap();
+        renderer.render_to_image(&camera).unwrap();
+    }
+    assert_eq!(
+        renderer.descriptor_allocator.allocated_set_count(),
+        allocated
+    );
+    let offset = renderer
+        .material_system
+        .get_material_by_handle(material)
+        .unwrap()
+        .parameters
+        .uv_offset;
+    assert!((0.0..1.0).contains(&offset.x) && (0.0..1.0).contains(&offset.y));
+}
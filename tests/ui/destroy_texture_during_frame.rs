@@ -0,0 +1,13 @@
+use vulkan_rust::renderer::{Renderer, RendererResult};
+
+// The frame may sample the texture, it can't be destroyed while it is recorded
+fn record(renderer: &mut Renderer) -> RendererResult<()> {
+    let texture = renderer.new_texture_from_file("assets/albedo.png")?;
+    if let Some(frame) = renderer.begin_frame()? {
+        renderer.destroy_texture(texture, true)?;
+        println!("{}", frame.frame_number());
+    }
+    Ok(())
+}
+
+fn main() {}
@@ -0,0 +1,17 @@
+use vulkan_rust::renderer::scene::SceneObject;
+use vulkan_rust::renderer::utils::Handle;
+use vulkan_rust::renderer::{Renderer, RendererResult};
+
+// The guard writes the object data with the allocator the context locked
+fn attach(
+    renderer: &mut Renderer,
+    parent: Handle<SceneObject>,
+    child: Handle<SceneObject>,
+) -> RendererResult<()> {
+    let mut resources = renderer.resources()?;
+    let mut guard = resources.get_object_mut(parent).unwrap();
+    drop(resources);
+    guard.add_child(child)
+}
+
+fn main() {}
@@ -0,0 +1,17 @@
+use vulkan_rust::renderer::{Renderer, RendererResult};
+
+// Resources are created before begin_frame, and the context dropped before the frame
+fn load_then_record(renderer: &mut Renderer) -> RendererResult<()> {
+    let mesh = {
+        let mut resources = renderer.resources()?;
+        resources.new_cube_mesh()?
+    };
+    let texture = renderer.new_texture_from_file("assets/albedo.png")?;
+    if let Some(frame) = renderer.begin_frame()? {
+        println!("{} {:?}", frame.frame_number(), mesh);
+        drop(frame);
+    }
+    renderer.destroy_texture(texture, true)
+}
+
+fn main() {}
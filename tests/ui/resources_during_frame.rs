@@ -0,0 +1,12 @@
+use vulkan_rust::renderer::{Renderer, RendererResult};
+
+// Meshes can't be created or changed while a frame is recorded
+fn record(renderer: &mut Renderer) -> RendererResult<()> {
+    if let Some(frame) = renderer.begin_frame()? {
+        renderer.resources()?.new_cube_mesh()?;
+        println!("{}", frame.frame_number());
+    }
+    Ok(())
+}
+
+fn main() {}
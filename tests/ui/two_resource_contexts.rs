@@ -0,0 +1,12 @@
+use vulkan_rust::renderer::{Renderer, RendererResult};
+
+// Each context locks the allocator, a second one can't exist next to the first
+fn load(renderer: &mut Renderer) -> RendererResult<()> {
+    let mut meshes = renderer.resources()?;
+    let mut textures = renderer.resources()?;
+    meshes.new_cube_mesh()?;
+    textures.new_texture_from_file("assets/albedo.png")?;
+    Ok(())
+}
+
+fn main() {}